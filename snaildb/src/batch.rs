@@ -0,0 +1,70 @@
+use crate::utils::RecordKind;
+
+/// Bytes a write takes in a batch besides its key and value, as a record of the WAL holds for
+/// its length, checksum, kind, sequence number and field lengths, see `approximate_size`.
+const RECORD_OVERHEAD: usize = 24;
+
+/// Writes applied together by `SnailDb::write`: appended to the WAL as one batch, so recovery
+/// replays either all of them or none, then applied to the memtable in the order they were
+/// added. A key written twice in a batch reads as the last write.
+///
+/// A batch is kept after it is written, and can be written again or emptied with `clear`.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    records: Vec<(RecordKind, String, Vec<u8>)>,
+    /// the bytes of the keys and values, plus `RECORD_OVERHEAD` per write
+    size: usize,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a write of `value` to `key`.
+    pub fn put(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.push(RecordKind::Set, key.into(), value.into())
+    }
+
+    /// Adds a delete of `key`.
+    pub fn delete(&mut self, key: impl Into<String>) -> &mut Self {
+        self.push(RecordKind::Delete, key.into(), Vec::new())
+    }
+
+    /// Adds a merge operand for `key`, see `SnailDb::merge`. Writing the batch fails if the
+    /// database has no merge operator.
+    pub fn merge(&mut self, key: impl Into<String>, operand: impl Into<Vec<u8>>) -> &mut Self {
+        self.push(RecordKind::Merge, key.into(), operand.into())
+    }
+
+    fn push(&mut self, kind: RecordKind, key: String, value: Vec<u8>) -> &mut Self {
+        self.size += key.len() + value.len() + RECORD_OVERHEAD;
+        self.records.push((kind, key, value));
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns roughly the bytes the batch takes in the WAL.
+    pub fn approximate_size(&self) -> usize {
+        self.size
+    }
+
+    /// Removes the writes of the batch, keeping its buffer for reuse.
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.size = 0;
+    }
+
+    /// Returns the writes in the order they were added: their kind, key and value.
+    pub fn records(&self) -> &[(RecordKind, String, Vec<u8>)] {
+        &self.records
+    }
+}
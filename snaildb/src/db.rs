@@ -1,13 +1,27 @@
-use std::fs;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
-use crate::storage::{MemTable, SsTable};
-use crate::wal::Wal;
-use crate::utils::Value;
-use tracing::info;
+use crate::batch::WriteBatch;
+use crate::stats::{DbMetrics, DbStats};
+use crate::storage::{CompactionStrategy, LeveledOptions, MemTable, SizeTieredOptions, Snapshot, SnapshotList, SsTable};
+use crate::storage::scan::{Scan, Versions};
+use crate::storage::compactor::{CompactionJob, CompletedCompaction, Compactor};
+use crate::storage::manifest::{LiveFiles, Manifest, TableFile, Version, VersionEdit, file_name, is_manifest_file};
+use crate::storage::sstable::{
+    BlockCache, KeyRange, MergeOptions, RangeTombstone, SsTableOptions, SsTableWriter, Stats, system_clock,
+};
+use crate::wal::{RecoveryMode, RecoveryReport, Wal, WalEntry, WalPosition, file_checkpoint_path};
+use crate::wal::segment::sync_dir;
+use crate::utils::{MergeFn, MergeOperator, RecordKind, Value};
+use tracing::{info, warn};
 
 /// The default flush threshold is 64 MiB (same as RocksDB).
 /// This is a safe default for most containerized environments with 512MB-2GB RAM.
@@ -18,41 +32,946 @@ const DEFAULT_FLUSH_THRESHOLD_BYTES: usize = 64 * 1024 * 1024; // 64 MiB
 pub struct SnailDb {
     /// The memtable is a in-memory data structure that stores the data that has been written to the database but not yet flushed to disk.
     pub memtable: MemTable,
+    /// The memtable being flushed, see `freeze_memtable`, which holds the writes older than
+    /// those of `memtable`.
+    pub frozen_memtable: Option<FrozenMemTable>,
     /// The WAL is a file that stores the write-ahead log of the database.
     pub wal: Wal,
     /// The SSTables are the immutable on-disk data structures that store the data that has been flushed from the memtable to disk.
-    pub sstables: Vec<SsTable>,
+    /// These are the tables of level 0, newest first.
+    pub sstables: Vec<Arc<SsTable>>,
+    /// The tables of the levels from 1 on, `levels[0]` for level 1, written by leveled
+    /// compaction, see `LeveledOptions`. Each level is sorted by key range, and its tables
+    /// hold disjoint ranges of keys.
+    pub levels: Vec<Vec<Arc<SsTable>>>,
     /// The flush threshold is the size of the memtable that triggers a flush to disk, can be set by the user.
     pub flush_threshold_bytes: usize,
+    /// How the SSTables are compacted after a flush, see `compact`.
+    pub compaction: CompactionStrategy,
     /// The data directory is the directory that stores the database files.
     pub data_dir: PathBuf,
+    /// What opening the database recovered from the WAL and dropped from it and the SSTables.
+    pub recovery_report: RecoveryReport,
+    /// The files opening the database found left behind by a crash: SSTables out of the
+    /// manifest and unfinished flush and compaction outputs. They are removed, except under
+    /// `RecoveryMode::SkipCorrupt`, which leaves them on disk like the tables that fail to open.
+    pub orphaned_files: Vec<PathBuf>,
+    /// Combines merge operands with the value of their key, see `open_with_merge_operator`.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// The number of the next table a leveled compaction writes.
+    next_table_number: u64,
+    /// The sequence number of the last write, see `last_seqno`.
+    last_seqno: u64,
+    /// The log of the live set of tables, committed to by flushes and compactions.
+    manifest: Manifest,
+    /// The live snapshots, see `snapshot`, whose versions the memtables and compactions keep.
+    snapshots: Arc<SnapshotList>,
+    /// The time the expiries of `put_with_ttl` are compared with, see `with_clock`.
+    clock: fn() -> u64,
+    /// The column families created by `create_cf` by id, the default one left out: its
+    /// memtable and tables are the fields above.
+    column_families: BTreeMap<u32, ColumnFamily>,
+    /// The id the next column family created gets.
+    next_family_id: u32,
+    /// The cache the SSTables read their blocks through, see `with_block_cache`.
+    block_cache: Option<Arc<BlockCache>>,
+    /// The counters of what the database served, see `stats`.
+    metrics: DbMetrics,
+    /// The thread the compactions run on, see `compact`, and the round it runs, if any.
+    compactor: Compactor,
+    running_compaction: Option<RunningCompaction>,
+    /// The tables compactions and `drop_cf` took out of the database, whose files a scan of
+    /// `scan_pinned` may still read, see `purge_obsolete_files`.
+    obsolete: Vec<Arc<SsTable>>,
+    /// The tables `with_clock` and `with_block_cache` replaced with a copy holding the setting
+    /// as a scan of `scan_pinned` read them, kept until it is dropped so the files they share
+    /// with their copies are not purged under it.
+    superseded: Vec<Arc<SsTable>>,
+    /// When writes are held up, see `with_write_stall`, and whether the database is closing,
+    /// which makes them fail, see `close_handle`.
+    write_stall: WriteStallOptions,
+    closing: Arc<AtomicBool>,
+    /// The `LOCK` file of the data directory, locked for as long as the database is open so no
+    /// other instance opens it, see `destroy`.
+    _lock: File,
+}
+
+/// The name of the column family the writes without one go to, see `SnailDb::cf`.
+pub const DEFAULT_CF: &str = "default";
+
+/// A column family of the database, see `SnailDb::create_cf`: a keyspace of its own, with its
+/// own memtable and SSTables, sharing the WAL and the manifest with the others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CfHandle {
+    id: u32,
+    name: String,
+}
+
+impl CfHandle {
+    /// Returns the name the column family was created with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The memtables and tables of a column family other than the default one.
+#[derive(Debug)]
+struct ColumnFamily {
+    name: String,
+    memtable: MemTable,
+    /// frozen along with the default memtable, see `SnailDb::freeze_memtable`
+    frozen_memtable: Option<MemTable>,
+    /// the tables of level 0, newest first, and of the levels from 1 on, as in `SnailDb`
+    sstables: Vec<Arc<SsTable>>,
+    levels: Vec<Vec<Arc<SsTable>>>,
+}
+
+impl ColumnFamily {
+    /// Returns the memtables and tables of the family, read with `merge` and counted in `metrics`.
+    fn tree<'a>(&'a self, merge: Option<&'a Arc<dyn MergeOperator>>, metrics: &'a DbMetrics) -> Tree<'a> {
+        Tree {
+            memtable: &self.memtable,
+            frozen: self.frozen_memtable.as_ref(),
+            sstables: &self.sstables,
+            levels: &self.levels,
+            merge,
+            metrics,
+        }
+    }
+}
+
+/// The memtables and tables a read of a column family goes through, with the merge operator
+/// and the counters of the database it reads, shared by `SnailDb` and `ReadOnlyDb`.
+#[derive(Clone, Copy)]
+struct Tree<'a> {
+    memtable: &'a MemTable,
+    frozen: Option<&'a MemTable>,
+    sstables: &'a [Arc<SsTable>],
+    levels: &'a [Vec<Arc<SsTable>>],
+    merge: Option<&'a Arc<dyn MergeOperator>>,
+    metrics: &'a DbMetrics,
+}
+
+impl<'a> Tree<'a> {
+    /// Returns the memtables, newest first.
+    fn memtables(self) -> impl Iterator<Item = &'a MemTable> {
+        std::iter::once(self.memtable).chain(self.frozen)
+    }
+
+    /// Returns the tables, newest first: level 0, then the levels from 1 on.
+    fn tables(self) -> impl Iterator<Item = &'a Arc<SsTable>> {
+        self.sstables.iter().chain(self.levels.iter().flatten())
+    }
+
+    /// Returns the tables that may hold `key`, newest first, see `SnailDb::tables_for_key`.
+    fn tables_for_key(self, key: &'a str) -> impl Iterator<Item = &'a Arc<SsTable>> + 'a {
+        let levels = self.levels.iter().filter_map(move |level| table_for_key(level, key).map(|index| &level[index]));
+        self.sstables.iter().chain(levels)
+    }
+
+    /// Gets the values of several keys, see `SnailDb::multi_get`.
+    fn multi_get(self, keys: &[&str]) -> Vec<io::Result<Option<Vec<u8>>>> {
+        self.metrics.record_gets(keys.len() as u64);
+        let merge = self.merge.map(Arc::as_ref);
+        let mut values: Vec<io::Result<Option<Value>>> = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut value = Ok(None);
+            for memtable in self.memtables() {
+                value = value.and_then(|value| {
+                    if !is_pending(&value) {
+                        return Ok(value);
+                    }
+                    stack_version(key, value, memtable.get(key), memtable.is_range_deleted(key), merge)
+                });
+            }
+            values.push(value);
+        }
+
+        let pending = |values: &[io::Result<Option<Value>>]| -> Vec<usize> {
+            (0..keys.len()).filter(|&i| values[i].as_ref().is_ok_and(is_pending)).collect()
+        };
+        for table in self.sstables {
+            self.multi_get_from(table, keys, pending(&values), &mut values);
+        }
+        // A level from 1 on holds disjoint key ranges, so each key is looked up in one table of it
+        for level in self.levels {
+            let mut wanted: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+            for i in pending(&values) {
+                if let Some(index) = table_for_key(level, keys[i]) {
+                    wanted.entry(index).or_default().push(i);
+                }
+            }
+            for (index, wanted) in wanted {
+                self.multi_get_from(&level[index], keys, wanted, &mut values);
+            }
+        }
+        values
+            .into_iter()
+            .zip(keys)
+            .map(|(value, key)| match value? {
+                Some(value) => Ok(value.resolve(key.as_bytes(), merge)?.as_option()),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Stacks under the values of the keys of `wanted`, indexes into `keys` and `values`, the
+    /// versions `table` holds of them, see `SnailDb::multi_get`, reading it once for those of
+    /// them its bloom filter does not rule out.
+    fn multi_get_from(
+        self,
+        table: &SsTable,
+        keys: &[&str],
+        wanted: impl IntoIterator<Item = usize>,
+        values: &mut [io::Result<Option<Value>>],
+    ) {
+        let merge = self.merge.map(Arc::as_ref);
+        let wanted: Vec<usize> = wanted.into_iter().filter(|&i| self.bloom_check(table, keys[i])).collect();
+        if wanted.is_empty() {
+            return;
+        }
+        let wanted_keys: Vec<&str> = wanted.iter().map(|&i| keys[i]).collect();
+        let found: Vec<io::Result<Option<Value>>> = match table.multi_get(&wanted_keys) {
+            Ok(found) => found.into_iter().map(Ok).collect(),
+            // Read the keys one by one, so a damaged block fails the keys it holds only
+            Err(_) => wanted_keys.iter().map(|key| table.get(key)).collect(),
+        };
+        for (i, older) in wanted.into_iter().zip(found) {
+            let key = keys[i];
+            let value = std::mem::replace(&mut values[i], Ok(None));
+            values[i] = value.and_then(|value| {
+                let older = older.map_err(|err| {
+                    let message = format!("failed to read from sstable {}: {err}", table.path().display());
+                    io::Error::new(err.kind(), message)
+                })?;
+                stack_version(key, value, older, table.is_range_deleted(key), merge)
+            });
+        }
+    }
+
+    /// Returns whether `table` may hold `key`, see `SsTable::might_contain_key`, counting the
+    /// answer of its bloom filter.
+    fn bloom_check(self, table: &SsTable, key: &str) -> bool {
+        let hit = table.might_contain_key(key);
+        self.metrics.record_bloom(hit);
+        hit
+    }
+
+    /// Gets a value, see `SnailDb::get`.
+    fn get(self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.metrics.record_gets(1);
+        let merge = self.merge.map(Arc::as_ref);
+        let mut value = None;
+        for memtable in self.memtables() {
+            if !is_pending(&value) {
+                break;
+            }
+            value = stack_version(key, value, memtable.get(key), memtable.is_range_deleted(key), merge)?;
+        }
+
+        // Check each SSTable: key range -> bloom filter -> read the one block that can hold the key
+        for table in self.tables_for_key(key) {
+            if !is_pending(&value) {
+                break;
+            }
+            if self.bloom_check(table, key) {
+                let older = table.get(key)
+                    .with_context(|| format!("failed to read from sstable {}", table.path().display()))?;
+                value = stack_version(key, value, older, table.is_range_deleted(key), merge)?;
+            }
+        }
+        match value {
+            Some(value) => Ok(value.resolve(key.as_bytes(), merge)?.as_option()),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets a value as of sequence number `seqno`, see `SnailDb::get_with_snapshot`.
+    fn get_at(self, key: &str, seqno: u64) -> Result<Option<Vec<u8>>> {
+        self.metrics.record_gets(1);
+        let mut versions = Vec::new();
+        for memtable in self.memtables() {
+            versions.extend(memtable.version_at(key, seqno));
+        }
+        for table in self.tables_for_key(key).filter(|table| self.bloom_check(table, key)) {
+            let version = table
+                .get_at(key, seqno)
+                .with_context(|| format!("failed to read from sstable {}", table.path().display()))?;
+            versions.extend(version);
+        }
+        // Newest first, the tables holding the versions of a flush are in that order already
+        versions.sort_by_key(|(_, seqno)| std::cmp::Reverse(*seqno));
+        let merge = self.merge.map(Arc::as_ref);
+        let mut value: Option<Value> = None;
+        for (older, _) in versions {
+            value = Some(match value {
+                Some(operands @ Value::Merge(_)) => operands.stack_onto(key.as_bytes(), older, merge)?,
+                Some(_) => break,
+                None => older,
+            });
+        }
+        match value {
+            Some(value) => Ok(value.resolve(key.as_bytes(), merge)?.as_option()),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the time left at `now` before the value of `key` expires, see `SnailDb::ttl`.
+    fn ttl(self, key: &str, now: u64) -> Result<Option<Duration>> {
+        let remaining = |value: &Value, expires_at: Option<u64>| match value {
+            Value::Deleted | Value::Merge(_) => None,
+            _ => expires_at.map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now))),
+        };
+        // The newest version decides, merge operands onto a value make one that doesn't expire
+        for memtable in self.memtables() {
+            if let Some((value, expires_at)) = memtable.get_with_expiry(key) {
+                return Ok(remaining(&value, expires_at));
+            }
+            if memtable.is_range_deleted(key) {
+                return Ok(None);
+            }
+        }
+        for table in self.tables_for_key(key) {
+            if table.might_contain_key(key) {
+                let found = table
+                    .get_with_metadata(key)
+                    .with_context(|| format!("failed to read from sstable {}", table.path().display()))?;
+                if let Some((value, meta)) = found {
+                    return Ok(remaining(&value, meta.expires_at));
+                }
+            }
+            if table.is_range_deleted(key) {
+                return Ok(None);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Scans the keys from `start` to `end` of a range, see `SnailDb::scan`.
+    fn scan_range<'r>(self, range: impl RangeBounds<&'r str>, reverse: bool) -> Scan<'a> {
+        self.scan_range_at(range, reverse, u64::MAX)
+    }
+
+    /// Scans the keys of a range like `scan_range`, as of sequence number `seqno`, see
+    /// `SnailDb::scan_with_snapshot`.
+    fn scan_range_at<'r>(self, range: impl RangeBounds<&'r str>, reverse: bool, seqno: u64) -> Scan<'a> {
+        let start = range.start_bound().map(|key| key.to_string());
+        let end = range.end_bound().map(|key| key.to_string());
+        self.scan(start, end, reverse, seqno, |_| true)
+    }
+
+    /// Scans the keys starting with `prefix`, see `SnailDb::scan_prefix`.
+    fn scan_prefix(self, prefix: &str) -> Scan<'a> {
+        let start = Bound::Included(prefix.to_string());
+        let end = prefix_end(prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        self.scan(start, end, false, u64::MAX, |table| table.might_contain_prefix(prefix))
+    }
+
+    /// Scans the keys from `start` to `end` as of sequence number `seqno`, `u64::MAX` for the
+    /// latest writes, reading the tables that meet them and `keep`.
+    fn scan(
+        self,
+        start: Bound<String>,
+        end: Bound<String>,
+        reverse: bool,
+        seqno: u64,
+        keep: impl Fn(&SsTable) -> bool,
+    ) -> Scan<'a> {
+        let mut sources: Vec<(Versions<'_>, Vec<RangeTombstone>)> = Vec::new();
+        if is_empty_range(&start, &end) {
+            return Scan::new(sources, None, reverse);
+        }
+        // The range tombstones written after `seqno` delete nothing the scan reads
+        let written_by = |tombstone: &RangeTombstone| tombstone.seqno <= seqno;
+        for memtable in self.memtables() {
+            let versions: Versions<'_> = if seqno == u64::MAX {
+                let versions = memtable.range(start.clone(), end.clone()).map(|(key, value, _)| Ok((key, value)));
+                if reverse { Box::new(versions.rev()) } else { Box::new(versions) }
+            } else {
+                let versions = memtable.range_at(start.clone(), end.clone(), seqno);
+                let versions = versions.into_iter().map(|(key, value, _)| Ok((key, value)));
+                if reverse { Box::new(versions.rev()) } else { Box::new(versions) }
+            };
+            sources.push((versions, memtable.range_tombstones().into_iter().filter(written_by).collect()));
+        }
+        for table in self.tables().filter(|table| overlaps(table, &start, &end) && keep(table)) {
+            let (from, to) = (start.as_ref().map(String::as_str), end.as_ref().map(String::as_str));
+            let versions = table.range_at(from, to, seqno);
+            let versions: Versions<'_> = if reverse {
+                Box::new(versions.rev().map(utf8_version))
+            } else {
+                Box::new(versions.map(utf8_version))
+            };
+            sources.push((versions, table.range_tombstones().iter().filter(|t| written_by(t)).cloned().collect()));
+        }
+        Scan::new(sources, self.merge.cloned(), reverse)
+    }
+
+    /// Scans the keys of a range like `scan_range`, owning what it reads, see
+    /// `SnailDb::scan_pinned`.
+    fn scan_pinned<'r>(self, range: impl RangeBounds<&'r str>, reverse: bool) -> Scan<'static> {
+        let start = range.start_bound().map(|key| key.to_string());
+        let end = range.end_bound().map(|key| key.to_string());
+        let mut sources: Vec<(Versions<'static>, Vec<RangeTombstone>)> = Vec::new();
+        if is_empty_range(&start, &end) {
+            return Scan::new(sources, None, reverse);
+        }
+        for memtable in self.memtables() {
+            let versions: Vec<_> =
+                memtable.range(start.clone(), end.clone()).map(|(key, value, _)| Ok((key, value))).collect();
+            let versions: Versions<'static> =
+                if reverse { Box::new(versions.into_iter().rev()) } else { Box::new(versions.into_iter()) };
+            sources.push((versions, memtable.range_tombstones()));
+        }
+        for table in self.tables().filter(|table| overlaps(table, &start, &end)) {
+            let versions = PinnedRange::new(Arc::clone(table), &start, &end, reverse);
+            sources.push((Box::new(versions), table.range_tombstones().to_vec()));
+        }
+        Scan::new(sources, self.merge.cloned(), reverse)
+    }
+}
+
+/// Returns true if no key lies from `start` to `end`.
+fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// The versions of a range of an SSTable like `SsTable::range`, from an iterator that owns
+/// the table, see `SnailDb::scan_pinned`. As an iterator of the table borrows it, they are
+/// read in batches, each from a range starting after the last key read.
+struct PinnedRange {
+    table: Arc<SsTable>,
+    /// the keys left to read
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+    batch: std::vec::IntoIter<io::Result<(Vec<u8>, Value)>>,
+    /// set once the last batch is read
+    done: bool,
+}
+
+/// The versions a `PinnedRange` reads at a time.
+const PINNED_RANGE_BATCH: usize = 256;
+
+impl PinnedRange {
+    fn new(table: Arc<SsTable>, start: &Bound<String>, end: &Bound<String>, reverse: bool) -> Self {
+        let bytes = |bound: &Bound<String>| bound.as_ref().map(|key| key.as_bytes().to_vec());
+        Self { table, start: bytes(start), end: bytes(end), reverse, batch: Vec::new().into_iter(), done: false }
+    }
+
+    /// Reads the next batch and moves the bound it started from past it.
+    fn refill(&mut self) {
+        let range = self.table.range(self.start.as_ref(), self.end.as_ref());
+        let batch: Vec<_> = if self.reverse {
+            range.rev().take(PINNED_RANGE_BATCH).collect()
+        } else {
+            range.take(PINNED_RANGE_BATCH).collect()
+        };
+        self.done = batch.len() < PINNED_RANGE_BATCH;
+        match batch.last() {
+            Some(Ok((key, _))) if self.reverse => self.end = Bound::Excluded(key.clone()),
+            Some(Ok((key, _))) => self.start = Bound::Excluded(key.clone()),
+            // Reading on after an error could read it again, forever
+            Some(Err(_)) => self.done = true,
+            None => {}
+        }
+        self.batch = batch.into_iter();
+    }
+}
+
+impl Iterator for PinnedRange {
+    type Item = io::Result<(String, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(version) = self.batch.next() {
+                return Some(utf8_version(version));
+            }
+            if self.done {
+                return None;
+            }
+            self.refill();
+        }
+    }
+}
+
+/// A memtable frozen by `SnailDb::freeze_memtable`, no longer written to, until it is flushed.
+#[derive(Debug)]
+pub struct FrozenMemTable {
+    pub memtable: MemTable,
+    /// The end of the WAL records the memtable holds, checkpointed once it is flushed.
+    pub wal_position: WalPosition,
+    /// The sequence number of the last write the memtable holds.
+    pub last_seqno: u64,
+}
+
+/// The database, see `SnailDb`.
+pub type Db = SnailDb;
+
+/// A round of compaction running on the compaction thread, see `SnailDb::compact`, and where
+/// its outputs go once it is done.
+#[derive(Debug)]
+struct RunningCompaction {
+    /// the column family compacted
+    family: u32,
+    /// the level and path of each input, oldest first
+    inputs: Vec<(usize, PathBuf)>,
+    /// the level the outputs go to
+    to: usize,
+    /// the newest input, which the output of a size-tiered round is renamed over
+    replaces: Option<PathBuf>,
+}
+
+/// The outcome of `SnailDb::compare_and_swap`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CasResult {
+    /// the key held the expected value and the new one was written
+    Swapped,
+    /// the key held `current` instead, `None` if it was absent, and nothing was written
+    Mismatch { current: Option<Vec<u8>> },
+}
+
+/// What `SnailDb::checkpoint` wrote.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointSummary {
+    /// the files of the checkpoint: its SSTables, its manifest and `CURRENT`
+    pub files: usize,
+    /// the bytes of those files
+    pub bytes: u64,
+    /// the sequence number of the last write the checkpoint holds
+    pub last_seqno: u64,
+}
+
+/// What `SnailDb::compact_range` merged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactRangeSummary {
+    /// the tables merged and the tables they were merged into
+    pub input_tables: usize,
+    pub output_tables: usize,
+    /// the bytes of those tables on disk, before and after
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// A table written by `SnailDb::flush`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlushedTable {
+    /// the file name of the table and the column family it belongs to, 0 for the default one
+    pub name: String,
+    pub family: u32,
+    /// what the table held when it was written
+    pub stats: Stats,
+}
+
+/// Options of `SnailDb::restore`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RestoreOptions {
+    /// Whether every SSTable of the backup is read through to check its checksums, see
+    /// `SsTable::verify`, true by default.
+    pub verify_checksums: bool,
+    /// Whether a target directory that is not empty is replaced, false by default.
+    pub overwrite: bool,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self { verify_checksums: true, overwrite: false }
+    }
+}
+
+impl RestoreOptions {
+    /// Sets whether the SSTables of the backup are verified before they are restored.
+    pub fn with_verify_checksums(mut self, verify: bool) -> Self {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Sets whether a target directory that is not empty is replaced.
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+}
+
+/// When writes are held up because flushes or compactions fall behind, see
+/// `SnailDb::with_write_stall`: past a slowdown threshold each write is delayed by `slowdown`,
+/// at a stop threshold it waits until the count is back under it. Level 0 tables are counted
+/// in the column family that has the most, frozen memtables once for all of them, as they are
+/// frozen together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteStallOptions {
+    /// Number of level 0 tables from which writes are delayed and from which they wait for
+    /// compactions, 20 and 36 by default. A stopped write goes on once nothing is left to
+    /// compact, as when live snapshots pin the tables, see `SnailDb::snapshot`.
+    pub l0_slowdown_tables: usize,
+    pub l0_stop_tables: usize,
+    /// Number of frozen memtables not yet flushed from which writes are delayed and from which
+    /// they flush them first, see `freeze_memtable`, 1 and 1 by default, so the write after a
+    /// freeze flushes the frozen memtable. A freeze flushes the memtable frozen before it, so
+    /// one at most is pending: a threshold above 1 would never be reached, and 0 turns the
+    /// threshold off, leaving a frozen memtable to `flush_frozen_memtable`.
+    pub frozen_slowdown_memtables: usize,
+    pub frozen_stop_memtables: usize,
+    /// How long a delayed write sleeps, 1 ms by default.
+    pub slowdown: Duration,
+}
+
+impl Default for WriteStallOptions {
+    fn default() -> Self {
+        Self {
+            l0_slowdown_tables: 20,
+            l0_stop_tables: 36,
+            frozen_slowdown_memtables: 1,
+            frozen_stop_memtables: 1,
+            slowdown: Duration::from_millis(1),
+        }
+    }
+}
+
+impl WriteStallOptions {
+    /// Sets the numbers of level 0 tables from which writes are delayed and stopped.
+    pub fn with_l0_thresholds(mut self, slowdown: usize, stop: usize) -> Self {
+        self.l0_slowdown_tables = slowdown;
+        self.l0_stop_tables = stop;
+        self
+    }
+
+    /// Sets the numbers of frozen memtables from which writes are delayed and stopped.
+    ///
+    /// Panics if either is above 1, the most frozen memtables pending at once, see
+    /// `frozen_slowdown_memtables`.
+    pub fn with_frozen_thresholds(mut self, slowdown: usize, stop: usize) -> Self {
+        assert!(slowdown <= 1 && stop <= 1, "frozen memtable thresholds of {slowdown} and {stop} are never reached");
+        self.frozen_slowdown_memtables = slowdown;
+        self.frozen_stop_memtables = stop;
+        self
+    }
+
+    /// Sets how long a delayed write sleeps.
+    pub fn with_slowdown(mut self, delay: Duration) -> Self {
+        self.slowdown = delay;
+        self
+    }
+}
+
+/// Closes a database from another thread than the one writing to it, see `SnailDb::close_handle`.
+#[derive(Clone, Debug)]
+pub struct CloseHandle {
+    closing: Arc<AtomicBool>,
+}
+
+impl CloseHandle {
+    /// Marks the database as closing: a write stalled waiting for compactions, see
+    /// `WriteStallOptions`, gives up, and every write fails from then on, so whoever holds the
+    /// database can let go of it and `close` it.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+}
+
+/// What `SnailDb::destroy` removed and left in place.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DestroyReport {
+    /// the files of the database removed
+    pub removed: Vec<PathBuf>,
+    /// the entries of the directory that are not files of a database, left untouched
+    pub kept: Vec<PathBuf>,
+}
+
+/// A database opened for reading only, see `SnailDb::open_read_only`: it has no write methods,
+/// and never flushes or compacts.
+#[derive(Debug)]
+pub struct ReadOnlyDb {
+    /// the writes the WAL held when the database was opened
+    memtable: MemTable,
+    /// the tables of level 0, newest first, and of the levels from 1 on, as in `SnailDb`
+    sstables: Vec<Arc<SsTable>>,
+    levels: Vec<Vec<Arc<SsTable>>>,
+    column_families: BTreeMap<u32, ColumnFamily>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    last_seqno: u64,
+    snapshots: Arc<SnapshotList>,
+    metrics: DbMetrics,
+    /// The `READ_LOCK` file of the data directory, locked shared for as long as the database is
+    /// open so it is not destroyed, see `SnailDb::destroy`. A directory no writer opened since
+    /// it was created has none, and is then not locked.
+    _read_lock: Option<File>,
+}
+
+impl ReadOnlyDb {
+    /// Gets a value, see `SnailDb::get`.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.default_tree().get(key)
+    }
+
+    /// Gets a value from the column family of `cf`, see `SnailDb::get_cf`.
+    pub fn get_cf(&self, cf: &CfHandle, key: &str) -> Result<Option<Vec<u8>>> {
+        self.tree(cf)?.get(key)
+    }
+
+    /// Returns the column family named `name`, see `SnailDb::cf`.
+    pub fn cf(&self, name: &str) -> Option<CfHandle> {
+        find_cf(&self.column_families, name)
+    }
+
+    /// Returns the live keys of `range` in ascending order, see `SnailDb::scan`.
+    pub fn scan<'r>(&self, range: impl RangeBounds<&'r str>) -> Scan<'_> {
+        self.default_tree().scan_range(range, false)
+    }
+
+    /// Returns the live keys of `range` in descending order, see `SnailDb::scan_rev`.
+    pub fn scan_rev<'r>(&self, range: impl RangeBounds<&'r str>) -> Scan<'_> {
+        self.default_tree().scan_range(range, true)
+    }
+
+    /// Returns the live keys starting with `prefix`, see `SnailDb::scan_prefix`.
+    pub fn scan_prefix(&self, prefix: &str) -> Scan<'_> {
+        self.default_tree().scan_prefix(prefix)
+    }
+
+    /// Returns the live keys of `range` in the column family of `cf`, see `SnailDb::scan_cf`.
+    pub fn scan_cf<'r>(&self, cf: &CfHandle, range: impl RangeBounds<&'r str>) -> Result<Scan<'_>> {
+        Ok(self.tree(cf)?.scan_range(range, false))
+    }
+
+    /// Takes a snapshot as of the last write the database held when it was opened.
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshots.snapshot(self.last_seqno)
+    }
+
+    /// Gets a value as of `snapshot`, see `SnailDb::get_with_snapshot`.
+    pub fn get_with_snapshot(&self, key: &str, snapshot: &Snapshot) -> Result<Option<Vec<u8>>> {
+        self.default_tree().get_at(key, snapshot.seqno())
+    }
+
+    /// Returns the live keys of `range` in ascending order as of `snapshot`, see
+    /// `SnailDb::scan_with_snapshot`.
+    pub fn scan_with_snapshot<'r>(&self, range: impl RangeBounds<&'r str>, snapshot: &Snapshot) -> Scan<'_> {
+        self.default_tree().scan_range_at(range, false, snapshot.seqno())
+    }
+
+    /// Returns the sequence number of the last write the database held when it was opened.
+    pub fn last_seqno(&self) -> u64 {
+        self.last_seqno
+    }
+
+    fn default_tree(&self) -> Tree<'_> {
+        Tree {
+            memtable: &self.memtable,
+            frozen: None,
+            sstables: &self.sstables,
+            levels: &self.levels,
+            merge: self.merge_operator.as_ref(),
+            metrics: &self.metrics,
+        }
+    }
+
+    fn tree(&self, cf: &CfHandle) -> Result<Tree<'_>> {
+        if cf.id == 0 {
+            return Ok(self.default_tree());
+        }
+        let family = self.column_families.get(&cf.id);
+        let family = family.with_context(|| format!("column family {} was dropped", cf.name))?;
+        Ok(family.tree(self.merge_operator.as_ref(), &self.metrics))
+    }
 }
 
 impl SnailDb {
     /// Opens the database at the given path, creating it if it doesn't exist.
     pub fn open(base_path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_recovery_mode(base_path, RecoveryMode::default())
+    }
+
+    /// Opens the database at the given path like `open`, treating a damaged WAL or SSTable as
+    /// `mode` says. Under `RecoveryMode::SkipCorrupt` an SSTable that fails to open is left out
+    /// and left on disk, under the other modes it is an error. `recovery_report` tells what was
+    /// dropped.
+    pub fn open_with_recovery_mode(base_path: impl AsRef<Path>, mode: RecoveryMode) -> Result<Self> {
+        Self::open_with(base_path, mode, None)
+    }
+
+    /// Opens the database at the given path like `open`, with `merge` combining the operands
+    /// written by `merge` with the value of their key. It is needed to replay the operands the
+    /// WAL holds, so it is given here rather than set afterwards.
+    pub fn open_with_merge_function(base_path: impl AsRef<Path>, merge: MergeFn) -> Result<Self> {
+        Self::open_with_merge_operator(base_path, Arc::new(merge))
+    }
+
+    /// Opens the database at the given path like `open_with_merge_function`, with `operator`
+    /// combining merge operands, see `MergeOperator`.
+    pub fn open_with_merge_operator(base_path: impl AsRef<Path>, operator: Arc<dyn MergeOperator>) -> Result<Self> {
+        Self::open_with(base_path, RecoveryMode::default(), Some(operator))
+    }
+
+    /// Opens the database at the given path for reading only, see `ReadOnlyDb`, as of the last
+    /// write it holds: its tables and the writes of its WAL are read, and no file is created,
+    /// changed or removed. It only takes a shared lock on `READ_LOCK`, which keeps `destroy`
+    /// off, so it can be opened while another instance has the database open for writing, and
+    /// does not see the writes made after it was opened.
+    ///
+    /// The WAL is read before the manifest, so the writes a flush moves from one to the other
+    /// in between are read from both. A compaction in between may remove a table the manifest
+    /// still listed: opening then fails, and can be retried.
+    pub fn open_read_only(base_path: impl AsRef<Path>) -> Result<ReadOnlyDb> {
+        Self::open_read_only_with(base_path.as_ref(), None)
+    }
+
+    /// Opens the database at the given path for reading only like `open_read_only`, with
+    /// `operator` combining merge operands, see `open_with_merge_operator`.
+    pub fn open_read_only_with_merge_operator(
+        base_path: impl AsRef<Path>,
+        operator: Arc<dyn MergeOperator>,
+    ) -> Result<ReadOnlyDb> {
+        Self::open_read_only_with(base_path.as_ref(), Some(operator))
+    }
+
+    fn open_read_only_with(base_path: &Path, merge_operator: Option<Arc<dyn MergeOperator>>) -> Result<ReadOnlyDb> {
+        if !base_path.is_dir() {
+            bail!("database {} does not exist", base_path.display());
+        }
+        let read_lock =
+            lock_shared(base_path).with_context(|| format!("failed to lock database {}", base_path.display()))?;
+        let wal_path = base_path.join(WAL_FILE);
+        let (entries, _) = Wal::read_with_seqnos(&wal_path, RecoveryMode::default())
+            .with_context(|| format!("failed to read WAL {}", wal_path.display()))?;
+        let recovered = Manifest::recover(base_path).with_context(|| "failed to read the manifest")?;
+        let version = match recovered {
+            Some((_, version)) => version,
+            None => Version { files: adopt_existing_sstables(base_path)?, ..Version::default() },
+        };
+        let mut report = RecoveryReport::default();
+        let mut trees = load_existing_sstables(base_path, &version.files, RecoveryMode::default(), &mut report)?;
+        let (sstables, levels) = trees.remove(&0).unwrap_or_default();
+        let snapshots = Arc::new(SnapshotList::default());
+        let memtable = MemTable::with_snapshots(Arc::clone(&snapshots));
+        let column_families = column_families_of(&version, trees, &snapshots);
+        let wal_seqno = replay_wal(entries, &memtable, &column_families, merge_operator.as_deref())?;
+        let metrics = DbMetrics::default();
+        let families = column_families.values().flat_map(|family| family.tree(None, &metrics).tables());
+        let tables = sstables.iter().chain(levels.iter().flatten()).chain(families);
+        let (_, table_seqno) = seqno_range(tables);
+        let last_seqno = wal_seqno.max(version.last_seqno).max(table_seqno);
+        Ok(ReadOnlyDb {
+            memtable,
+            sstables,
+            levels,
+            column_families,
+            merge_operator,
+            last_seqno,
+            snapshots,
+            metrics,
+            _read_lock: read_lock,
+        })
+    }
+
+    fn open_with(
+        base_path: impl AsRef<Path>,
+        mode: RecoveryMode,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+    ) -> Result<Self> {
         let base_path = base_path.as_ref().to_path_buf();
         fs::create_dir_all(&base_path)?;
-        let wal_path = base_path.join("wal.log");
+        let lock = lock_dir(&base_path)?.with_context(|| format!("database {} is already open", base_path.display()))?;
+        // Left for the read-only instances to lock, as they create no file
+        OpenOptions::new().create(true).truncate(false).write(true).open(base_path.join(READ_LOCK_FILE))?;
+        let wal_path = base_path.join(WAL_FILE);
+        // Replayed before the WAL is opened for appending, as a torn tail is truncated first
+        let (entries, mut recovery_report) = Wal::recover_with_seqnos(&wal_path, mode)
+            .with_context(|| format!("failed to recover WAL {}", wal_path.display()))?;
         let wal = Wal::open(&wal_path)?;
-        let memtable = MemTable::new();
+        let snapshots = Arc::new(SnapshotList::default());
+        let memtable = MemTable::with_snapshots(Arc::clone(&snapshots));
 
-        for (key, value) in wal.replay()? {
-            memtable.insert(key, value);
+        let remove = mode != RecoveryMode::SkipCorrupt;
+        // A flush interrupted by a crash leaves a temporary file, its entries are still in the WAL
+        let mut orphaned_files = if remove {
+            SsTable::remove_temp_files(&base_path)
+        } else {
+            SsTable::temp_files(&base_path)
+        }
+        .with_context(|| "failed to remove temporary sstable files")?;
+        // and a compaction its output before the swap, its inputs are still in place
+        orphaned_files.extend(
+            remove_compaction_outputs(&base_path, remove)
+                .with_context(|| "failed to remove unfinished compaction outputs")?,
+        );
+
+        // A database without a manifest predates it, its tables are all of level 0
+        let recovered = Manifest::recover(&base_path).with_context(|| "failed to read the manifest")?;
+        let (number, mut version) = match recovered {
+            Some(recovered) => recovered,
+            None => (0, Version { files: adopt_existing_sstables(&base_path)?, ..Version::default() }),
+        };
+        // Open tables lazily, only metadata (bloom filter, min/max keys, index) is read
+        let mut trees = load_existing_sstables(&base_path, &version.files, mode, &mut recovery_report)?;
+        let (sstables, levels) = trees.remove(&0).unwrap_or_default();
+        let column_families = column_families_of(&version, trees, &snapshots);
+        let next_table_number = version
+            .files
+            .values()
+            .filter_map(|file| {
+                let name = file.name.strip_prefix(&table_prefix(file.family))?.strip_prefix(LEVEL_TABLE_PREFIX)?;
+                name.split('.').next()?.parse().ok()
+            })
+            .max()
+            .map_or(0, |number: u64| number + 1);
+        // A fresh manifest on each open keeps its replay short, tables skipped as corrupt stay in it
+        let families = column_families.iter().map(|(&id, family)| (id, &family.sstables, &family.levels));
+        for (family, sstables, levels) in std::iter::once((0, &sstables, &levels)).chain(families) {
+            let opened = sstables.iter().map(|table| (0, table));
+            let opened = opened.chain(
+                levels.iter().enumerate().flat_map(|(i, level)| level.iter().map(move |table| (i + 1, table))),
+            );
+            for (level, table) in opened {
+                version.files.insert(table_name(table), TableFile::of(level, table).in_family(family));
+            }
         }
 
-        // Load only metadata (bloom filter, min/max keys) for efficient startup
-        let mut sstables = load_existing_sstables(&base_path)?;
+        let mut last_seqno = replay_wal(entries, &memtable, &column_families, merge_operator.as_deref())?;
+        // Sequence numbers resume past the last one the WAL, the manifest or a table holds
+        let tables = version.files.values().map(|file| file.max_seqno);
+        last_seqno = tables.fold(last_seqno.max(version.last_seqno), u64::max);
+        version.last_seqno = last_seqno;
+        let next_family_id = version.next_family_id.max(1);
+        let manifest =
+            Manifest::create(&base_path, number + 1, version).with_context(|| "failed to write the manifest")?;
+        orphaned_files.extend(remove_obsolete_sstables(&base_path, manifest.files(), remove)?);
+        for path in &orphaned_files {
+            warn!(path = %path.display(), removed = remove, "found a file left behind by a crash");
+        }
 
         Ok(Self {
             memtable,
+            frozen_memtable: None,
             wal,
-            sstables: {
-                sstables.sort_by(|a, b| b.path().cmp(a.path()));
-                sstables
-            },
+            sstables,
+            levels,
             flush_threshold_bytes: DEFAULT_FLUSH_THRESHOLD_BYTES,
+            compaction: CompactionStrategy::default(),
             data_dir: base_path,
+            recovery_report,
+            orphaned_files,
+            merge_operator,
+            next_table_number,
+            last_seqno,
+            manifest,
+            snapshots,
+            clock: system_clock,
+            column_families,
+            next_family_id,
+            block_cache: None,
+            metrics: DbMetrics::default(),
+            compactor: Compactor::spawn(),
+            running_compaction: None,
+            obsolete: Vec::new(),
+            superseded: Vec::new(),
+            write_stall: WriteStallOptions::default(),
+            closing: Arc::new(AtomicBool::new(false)),
+            _lock: lock,
         })
     }
 
@@ -62,97 +981,1885 @@ impl SnailDb {
         self
     }
 
+    /// Sets how the SSTables are compacted, `SizeTieredOptions` or `LeveledOptions`, see `compact`.
+    pub fn with_compaction(mut self, strategy: impl Into<CompactionStrategy>) -> Self {
+        self.compaction = strategy.into();
+        self
+    }
+
+    /// Makes the expiries of `put_with_ttl` compare with the time `now` returns, in milliseconds
+    /// since the UNIX epoch, instead of the system clock, see `system_clock`: for the expiries
+    /// written afterwards, the reads, the scans and the compactions.
+    pub fn with_clock(mut self, now: fn() -> u64) -> Self {
+        self.clock = now;
+        self.memtable = std::mem::take(&mut self.memtable).with_clock(now);
+        if let Some(frozen) = &mut self.frozen_memtable {
+            frozen.memtable = std::mem::take(&mut frozen.memtable).with_clock(now);
+        }
+        let superseded = &mut self.superseded;
+        for tables in std::iter::once(&mut self.sstables).chain(&mut self.levels) {
+            let tables_now =
+                std::mem::take(tables).into_iter().map(|table| unshared(table, superseded, |t| t.with_clock(now)));
+            *tables = tables_now.collect();
+        }
+        for family in self.column_families.values_mut() {
+            family.memtable = std::mem::take(&mut family.memtable).with_clock(now);
+            for tables in std::iter::once(&mut family.sstables).chain(&mut family.levels) {
+                let tables_now =
+                    std::mem::take(tables).into_iter().map(|table| unshared(table, superseded, |t| t.with_clock(now)));
+                *tables = tables_now.collect();
+            }
+        }
+        self
+    }
+
+    /// Sets when writes are held up because flushes or compactions fall behind, see
+    /// `WriteStallOptions`. The time they are held up is counted by `stats`.
+    pub fn with_write_stall(mut self, options: WriteStallOptions) -> Self {
+        self.write_stall = options;
+        self
+    }
+
+    /// Returns a handle that closes the database from another thread, see `CloseHandle`.
+    pub fn close_handle(&self) -> CloseHandle {
+        CloseHandle { closing: Arc::clone(&self.closing) }
+    }
+
+    /// Makes the SSTables read their blocks through `cache`, see `SsTable::with_block_cache`,
+    /// those already open and those flushes and compactions write. The cache may be shared with
+    /// other databases, `stats` then reports the lookups of all of them.
+    pub fn with_block_cache(mut self, cache: Arc<BlockCache>) -> Self {
+        let families = self.column_families.values_mut().flat_map(|family| {
+            std::iter::once(&mut family.sstables).chain(&mut family.levels)
+        });
+        for tables in std::iter::once(&mut self.sstables).chain(&mut self.levels).chain(families) {
+            let cached = std::mem::take(tables)
+                .into_iter()
+                .map(|table| unshared(table, &mut self.superseded, |table| table.with_block_cache(Arc::clone(&cache))));
+            *tables = cached.collect();
+        }
+        self.block_cache = Some(cache);
+        self
+    }
+
+    /// Returns what the database holds, over every column family, and the counts of what it
+    /// served since it was opened, see `DbStats`.
+    pub fn stats(&self) -> DbStats {
+        let mut sstables_per_level = vec![0];
+        let mut sstable_bytes = 0;
+        let families = self.column_families.values();
+        let trees = families.map(|family| family.tree(None, &self.metrics));
+        let trees = std::iter::once(self.default_tree()).chain(trees);
+        let mut memtable_bytes = 0;
+        for tree in trees {
+            memtable_bytes += tree.memtables().map(|memtable| memtable.size_bytes() as u64).sum::<u64>();
+            sstables_per_level[0] += tree.sstables.len();
+            for (i, level) in tree.levels.iter().enumerate() {
+                if sstables_per_level.len() < i + 2 {
+                    sstables_per_level.push(0);
+                }
+                sstables_per_level[i + 1] += level.len();
+            }
+            sstable_bytes += tree.tables().map(|table| table.file_size()).sum::<u64>();
+        }
+        let (block_cache_hits, block_cache_misses) =
+            self.block_cache.as_ref().map_or((0, 0), |cache| (cache.hits(), cache.misses()));
+        DbStats {
+            sstables_per_level,
+            sstable_bytes,
+            memtable_bytes,
+            wal_bytes: fs::metadata(&self.wal.path).map_or(0, |metadata| metadata.len()),
+            block_cache_hits,
+            block_cache_misses,
+            last_seqno: self.last_seqno,
+            ..DbStats::default()
+        }
+        .with_counters(&self.metrics)
+    }
+
+    /// Estimates the bytes the keys from `start`, included, to `end`, excluded, take, unbounded
+    /// where `None`, from the indexes of the SSTables: no data block is read.
+    ///
+    /// Each table whose key range meets the range counts the bytes of its data blocks between
+    /// the blocks holding the two ends, see `SsTable::approximate_size_of`, so the estimate is
+    /// off by less than two blocks per table, and the index, filters and footer of the tables
+    /// are left out. The memtables add the bytes of the keys and values of the range they
+    /// hold, see `MemTable::range_bytes`. The estimates of adjacent ranges add up to that of
+    /// the range they cover. Overwritten and deleted versions count until a compaction drops
+    /// them.
+    pub fn approximate_size(&self, start: Option<&str>, end: Option<&str>) -> u64 {
+        let low = start.map_or(Bound::Unbounded, Bound::Included);
+        let high = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let (low_key, high_key) = (low.map(str::to_string), high.map(str::to_string));
+        if is_empty_range(&low_key, &high_key) {
+            return 0;
+        }
+        let tree = self.default_tree();
+        let (start, end) = (start.map(str::as_bytes), end.map(str::as_bytes));
+        let tables: u64 = tree
+            .tables()
+            .filter(|table| overlaps(table, &low_key, &high_key))
+            .map(|table| table.approximate_size_of(start, end))
+            .sum();
+        let memtables: usize = tree.memtables().map(|memtable| memtable.range_bytes(low, high)).sum();
+        tables + memtables as u64
+    }
+
     /// Writes a key-value pair into the database.
     pub fn put(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Result<()> {
         let key = key.into(); // into is to convert the key to a string
         let value_bytes = value.into();
+        self.stall_writes()?;
+        let seqno = self.last_seqno + 1;
         self.wal
-            .append_set(&key, &value_bytes)
+            .append_with_seqno(RecordKind::Set, &key, &value_bytes, seqno)
             .with_context(|| "failed to write to WAL")?;
-        self.memtable.insert(key, Value::from_bytes(value_bytes));
+        self.last_seqno = seqno;
+        self.metrics.record_puts(1);
+        self.memtable.insert_with_seqno(key, Value::from_bytes(value_bytes), seqno);
         if self.memtable.size_bytes() >= self.flush_threshold_bytes {
             self.flush_memtable()?;
         }
         Ok(())
     }
 
-    /// Deletes a key from the database.
-    pub fn delete(&mut self, key: impl Into<String>) -> Result<()> {
+    /// Writes a key-value pair like `put` that expires once `ttl` has passed, by the clock set with
+    /// `with_clock`. From then on the key reads as deleted, its older versions included, until it
+    /// is written again, and compactions drop the value. Expiry is checked when the key is read,
+    /// nothing is written when it happens: deleting the key still writes a tombstone.
+    ///
+    /// Merge operands stacked onto the value before it expires make a value that does not.
+    pub fn put_with_ttl(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
         let key = key.into();
+        let value_bytes = value.into();
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        self.stall_writes()?;
+        let expires_at = (self.clock)().saturating_add(ttl);
+        let seqno = self.last_seqno + 1;
         self.wal
-            .append_delete(&key)
-            .with_context(|| "failed to write tombstone to WAL")?;
-        self.memtable.insert(key, Value::tombstone());
+            .append_with_expiry(&key, &value_bytes, expires_at, seqno)
+            .with_context(|| "failed to write to WAL")?;
+        self.last_seqno = seqno;
+        self.metrics.record_puts(1);
+        self.memtable.insert_with_expiry(key, Value::from_bytes(value_bytes), seqno, expires_at);
         if self.memtable.size_bytes() >= self.flush_threshold_bytes {
             self.flush_memtable()?;
         }
         Ok(())
     }
 
-    /// Gets a value from the database.
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        if let Some(value) = self.memtable.get(key) {
-            return Ok(value.as_option());
-        }
+    /// Returns the time left before the value of `key` expires, `None` if the key has no value
+    /// or a value without a TTL, see `put_with_ttl`.
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        self.default_tree().ttl(key, (self.clock)())
+    }
 
-        // Check each SSTable: bloom filter -> key range -> load entries and search
-        // Entries are loaded lazily only when might_contain_key returns true
-        for table in &self.sstables {
-            if table.might_contain_key(key) {
-                if let Some(value) = table.get(key)
-                    .with_context(|| format!("failed to read from sstable {}", table.path().display()))? {
-                    return Ok(value.as_option());
-                }
-            }
+    /// Deletes a key from the database.
+    pub fn delete(&mut self, key: impl Into<String>) -> Result<()> {
+        let key = key.into();
+        self.stall_writes()?;
+        let seqno = self.last_seqno + 1;
+        self.wal
+            .append_with_seqno(RecordKind::Delete, &key, &[], seqno)
+            .with_context(|| "failed to write tombstone to WAL")?;
+        self.last_seqno = seqno;
+        self.metrics.record_deletes(1);
+        self.memtable.delete_with_seqno(key, seqno);
+        if self.memtable.size_bytes() >= self.flush_threshold_bytes {
+            self.flush_memtable()?;
         }
-        Ok(None)
+        Ok(())
     }
 
-    /// Flushes the memtable to an SSTable.
-    pub fn flush_memtable(&mut self) -> Result<()> {
-        if self.memtable.is_empty() {
+    /// Deletes every key from `start`, included, to `end`, excluded. Keys written afterwards
+    /// are not affected. A range whose end is not after its start deletes nothing and is not written.
+    ///
+    /// However many keys the range holds, it is written as one range tombstone, a single WAL
+    /// record, which `get`, `scan` and compactions apply to the keys of the older tables.
+    pub fn delete_range(&mut self, start: impl Into<String>, end: impl Into<String>) -> Result<()> {
+        let (start, end) = (start.into(), end.into());
+        if end <= start {
             return Ok(());
         }
-
-        let pending = self.memtable.len();
-        let file_name = format!("sst-{}.sst", unix_millis());
-        let path = self.data_dir.join(file_name);
-        info!(
-            entry_count = pending,
-            path = %path.display(),
-            "flushing memtable to SSTable"
-        );
-        let entries = self.memtable.drain_sorted();
-        let table = SsTable::create(&path, entries).with_context(|| "failed to create SSTable")?;
-        self.sstables.insert(0, table);
-        self.wal.reset().with_context(|| "failed to reset WAL")?;
+        self.stall_writes()?;
+        let seqno = self.last_seqno + 1;
+        self.wal
+            .append_with_seqno(RecordKind::RangeDelete, &start, end.as_bytes(), seqno)
+            .with_context(|| "failed to write range tombstone to WAL")?;
+        self.last_seqno = seqno;
+        self.metrics.record_deletes(1);
+        self.memtable.delete_range_with_seqno(&start, &end, seqno);
+        if self.memtable.size_bytes() >= self.flush_threshold_bytes {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Writes `new` to `key`, or deletes it if `new` is `None`, if the key holds `expected`,
+    /// `None` meaning the key must be absent, as a tombstone or a range delete leaves it.
+    /// Otherwise nothing is written, and the value the key holds is returned.
+    ///
+    /// The check and the write happen under the borrow that orders all writes, so no other
+    /// write can come between them: callers sharing the database behind a lock get an atomic
+    /// compare-and-swap as long as they hold it across the call.
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl Into<String>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasResult> {
+        let key = key.into();
+        let current = self.get(&key)?;
+        if current.as_deref() != expected {
+            return Ok(CasResult::Mismatch { current });
+        }
+        match new {
+            Some(value) => self.put(key, value)?,
+            None => self.delete(key)?,
+        }
+        Ok(CasResult::Swapped)
+    }
+
+    /// Writes a merge operand for a key, combined with the value of the key and the operands
+    /// written since by the merge operator when the key is read. Fails if the database was
+    /// not opened with `open_with_merge_operator` or `open_with_merge_function`.
+    pub fn merge(&mut self, key: impl Into<String>, operand: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let operand = operand.into();
+        self.stall_writes()?;
+        let merge = self
+            .merge_operator
+            .as_deref()
+            .with_context(|| "merging needs a database opened with a merge function")?;
+        let seqno = self.last_seqno + 1;
+        self.wal
+            .append_with_seqno(RecordKind::Merge, &key, &operand, seqno)
+            .with_context(|| "failed to write merge operand to WAL")?;
+        self.last_seqno = seqno;
+        self.memtable.merge_with_seqno(key, Value::merge_operand(operand), Some(merge), seqno)?;
+        if self.memtable.size_bytes() >= self.flush_threshold_bytes {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the writes of `batch` atomically: they are appended to the WAL as one batch, so
+    /// a crash leaves all of them or none, and applied to the memtable in order once appended,
+    /// each with the next sequence number. An empty batch writes nothing. Fails before writing
+    /// anything if the batch holds merge operands and the database has no merge operator.
+    ///
+    /// The batch writes to the default column family: writes to the others, see `put_cf`, are
+    /// not batched and each of them is atomic on its own.
+    pub fn write(&mut self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let merge = self.merge_operator.clone();
+        if batch.records().iter().any(|(kind, _, _)| *kind == RecordKind::Merge) {
+            merge.as_deref().with_context(|| "merging needs a database opened with a merge function")?;
+        }
+        self.stall_writes()?;
+        let first_seqno = self.last_seqno + 1;
+        self.wal
+            .append_batch_with_seqno(first_seqno, batch.records().to_vec())
+            .with_context(|| "failed to write batch to WAL")?;
+        self.last_seqno = first_seqno + batch.len() as u64 - 1;
+        let deletes = batch.records().iter().filter(|(kind, _, _)| *kind == RecordKind::Delete).count() as u64;
+        let merges = batch.records().iter().filter(|(kind, _, _)| *kind == RecordKind::Merge).count() as u64;
+        self.metrics.record_deletes(deletes);
+        self.metrics.record_puts(batch.len() as u64 - deletes - merges);
+        for (seqno, (kind, key, value)) in (first_seqno..).zip(batch.records()) {
+            let key = key.clone();
+            match kind {
+                RecordKind::Merge => {
+                    let operand = Value::merge_operand(value.clone());
+                    self.memtable.merge_with_seqno(key, operand, merge.as_deref(), seqno)?;
+                }
+                RecordKind::Delete => self.memtable.delete_with_seqno(key, seqno),
+                _ => self.memtable.insert_with_seqno(key, Value::from_bytes(value.clone()), seqno),
+            }
+        }
+        if self.memtable.size_bytes() >= self.flush_threshold_bytes {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Gets a value from the database: from the memtable, the memtable being flushed, then
+    /// the SSTables newest to oldest. Merge operands are combined with the older value of the
+    /// key, newest to oldest until a value, a tombstone or the oldest table is reached. A
+    /// range delete reads as a tombstone for the keys it holds that were written before it.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.default_tree().get(key)
+    }
+
+    /// Gets the values of several keys like `get`, in the order of `keys`, a key given more
+    /// than once getting an answer at each position. The keys the memtables answer are not
+    /// looked up further, and each SSTable is read once for the keys it may hold that are still
+    /// unanswered, see `SsTable::multi_get`. A damaged table fails the keys it holds only.
+    pub fn multi_get(&self, keys: &[&str]) -> Vec<io::Result<Option<Vec<u8>>>> {
+        self.default_tree().multi_get(keys)
+    }
+
+    /// Takes a snapshot of the database as of the last write: reads through it, see
+    /// `get_with_snapshot`, leave out the writes made after it. The versions it reads are kept
+    /// until it is dropped: the memtable keeps those overwritten, a flush writes them to older
+    /// tables, and a compaction that would merge them away is put off. With leveled
+    /// compaction, level 0 is then merged into itself, so a long-lived snapshot leaves it
+    /// bounded but lets the levels below grow past their budgets.
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshots.snapshot(self.last_seqno)
+    }
+
+    /// Gets a value like `get`, as of `snapshot`: the versions written after it are left out.
+    pub fn get_with_snapshot(&self, key: &str, snapshot: &Snapshot) -> Result<Option<Vec<u8>>> {
+        self.default_tree().get_at(key, snapshot.seqno())
+    }
+
+    /// Returns the live keys of `range` in ascending order with their values like `scan`, as of
+    /// `snapshot`, like `get_with_snapshot`: the writes and range deletes made after it are left
+    /// out, so the scans through one snapshot read the same keys and values.
+    pub fn scan_with_snapshot<'r>(&self, range: impl RangeBounds<&'r str>, snapshot: &Snapshot) -> Scan<'_> {
+        self.default_tree().scan_range_at(range, false, snapshot.seqno())
+    }
+
+    /// Returns the live keys of `range` in descending order with their values like `scan_rev`,
+    /// as of `snapshot`, like `scan_with_snapshot`.
+    pub fn scan_rev_with_snapshot<'r>(&self, range: impl RangeBounds<&'r str>, snapshot: &Snapshot) -> Scan<'_> {
+        self.default_tree().scan_range_at(range, true, snapshot.seqno())
+    }
+
+    /// Returns the live keys of `range` in ascending order with their values, read like `get`
+    /// from a merge of the memtables and the SSTables, see `Scan`. Only the tables whose key
+    /// range meets `range` are read, and only their blocks that hold keys of it.
+    ///
+    /// The scan borrows the database, so no flush or compaction runs until it is dropped, see
+    /// `scan_pinned` for one that does not, and it reads the tables through their open files:
+    /// dropping it early releases nothing more.
+    pub fn scan<'r>(&self, range: impl RangeBounds<&'r str>) -> Scan<'_> {
+        self.default_tree().scan_range(range, false)
+    }
+
+    /// Returns the live keys of `range` in descending order with their values, like `scan`:
+    /// an unbounded end starts from the largest key, and the reads stop with the iteration,
+    /// so `scan_rev(..).take(n)` reads the blocks of the last `n` keys only.
+    pub fn scan_rev<'r>(&self, range: impl RangeBounds<&'r str>) -> Scan<'_> {
+        self.default_tree().scan_range(range, true)
+    }
+
+    /// Returns the live keys starting with `prefix` in ascending order with their values,
+    /// like `scan` over the range of the keys starting with it, see `prefix_end`. The tables
+    /// that cannot hold such a key are skipped, see `SsTable::might_contain_prefix`. An empty
+    /// prefix scans every key.
+    pub fn scan_prefix(&self, prefix: &str) -> Scan<'_> {
+        self.default_tree().scan_prefix(prefix)
+    }
+
+    /// Returns the live keys of `range` in ascending order with their values, like `scan`, from
+    /// a scan that does not borrow the database: writes, flushes and compactions go on while it
+    /// runs, and it reads the database as of the call. It copies the keys of the range out of
+    /// the memtables and holds the tables it reads: a compaction that takes one out of the
+    /// database leaves its file on disk until the scan is dropped, see `purge_obsolete_files`.
+    pub fn scan_pinned<'r>(&self, range: impl RangeBounds<&'r str>) -> Scan<'static> {
+        self.default_tree().scan_pinned(range, false)
+    }
+
+    /// Removes the files of the tables compactions and `drop_cf` took out of the database that
+    /// no scan of `scan_pinned` reads anymore, and returns how many it removed. Flushes,
+    /// compactions and `close` purge them too, and those left when the database is dropped are
+    /// removed by the next `open`, being out of the manifest.
+    pub fn purge_obsolete_files(&mut self) -> Result<usize> {
+        self.superseded.retain(|table| Arc::strong_count(table) > 1);
+        let superseded = &self.superseded;
+        let unused = |table: &Arc<SsTable>| {
+            Arc::strong_count(table) == 1 && !superseded.iter().any(|other| other.path() == table.path())
+        };
+        let (unused, pinned): (Vec<_>, Vec<_>) = std::mem::take(&mut self.obsolete).into_iter().partition(unused);
+        self.obsolete = pinned;
+        let paths: Vec<PathBuf> = unused.into_iter().map(|table| table.path().to_path_buf()).collect();
+        for path in &paths {
+            fs::remove_file(path).with_context(|| format!("failed to remove sstable {}", path.display()))?;
+        }
+        if !paths.is_empty() {
+            sync_dir(&self.data_dir).with_context(|| "failed to sync the data directory")?;
+        }
+        Ok(paths.len())
+    }
+
+    /// Returns the sequence number of the last write, 0 before the first. Each write takes the
+    /// next one, and opening the database resumes after the largest the WAL and tables hold.
+    pub fn last_seqno(&self) -> u64 {
+        self.last_seqno
+    }
+
+    /// Returns the SSTables a read of `key` consults, newest first: the tables of level 0, then
+    /// in each level from 1 on the one table whose key range may hold it, if any.
+    pub fn tables_for_key<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a SsTable> + 'a {
+        self.default_tree().tables_for_key(key).map(Arc::as_ref)
+    }
+
+    /// Creates the column family `name`, empty: a keyspace of its own, written and read with
+    /// the methods ending in `_cf`, such as `put_cf` and `get_cf`, whose keys never collide
+    /// with those of another family. A `WriteBatch` writes to the default family only. The
+    /// family is flushed along with the default one and compacted on its own, and its tables
+    /// are named after its id. The name is recorded in the manifest, so `cf` finds the family
+    /// again after the database is reopened.
+    pub fn create_cf(&mut self, name: impl Into<String>) -> Result<CfHandle> {
+        let name = name.into();
+        if self.cf(&name).is_some() {
+            bail!("column family {name} already exists");
+        }
+        let id = self.next_family_id;
+        self.commit(vec![VersionEdit::AddFamily { id, name: name.clone() }])?;
+        self.next_family_id += 1;
+        let memtable = MemTable::with_snapshots(Arc::clone(&self.snapshots)).with_clock(self.clock);
+        let family = ColumnFamily {
+            name: name.clone(),
+            memtable,
+            frozen_memtable: None,
+            sstables: Vec::new(),
+            levels: Vec::new(),
+        };
+        self.column_families.insert(id, family);
+        Ok(CfHandle { id, name })
+    }
+
+    /// Returns the column family named `name`, `None` if there is none. `DEFAULT_CF` names the
+    /// family the writes without one go to.
+    pub fn cf(&self, name: &str) -> Option<CfHandle> {
+        find_cf(&self.column_families, name)
+    }
+
+    /// Drops the column family of `cf` with its data: its tables are removed from the manifest
+    /// and from disk, and its writes still in the WAL are skipped when it is replayed. The
+    /// default family cannot be dropped.
+    pub fn drop_cf(&mut self, cf: &CfHandle) -> Result<()> {
+        if cf.id == 0 {
+            bail!("the default column family cannot be dropped");
+        }
+        self.family(cf)?;
+        // The round running may be one of the family, whose tables are removed below
+        self.finish_compaction()?;
+        let family = self.family(cf)?;
+        let tables: Vec<&Arc<SsTable>> = family.sstables.iter().chain(family.levels.iter().flatten()).collect();
+        let mut edits: Vec<VersionEdit> =
+            tables.iter().map(|table| VersionEdit::RemoveFile { name: table_name(table) }).collect();
+        edits.push(VersionEdit::DropFamily { id: cf.id });
+        self.commit(edits)?;
+        let family = self.column_families.remove(&cf.id).expect("a family found above");
+        self.obsolete.extend(family.sstables.into_iter().chain(family.levels.into_iter().flatten()));
+        self.purge_obsolete_files()?;
+        Ok(())
+    }
+
+    /// Writes a key-value pair into the column family of `cf`, like `put`.
+    pub fn put_cf(&mut self, cf: &CfHandle, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        if cf.id == 0 {
+            return self.put(key, value);
+        }
+        self.write_to_family(cf, RecordKind::Set, key, value, None)
+    }
+
+    /// Writes a key-value pair into the column family of `cf` that expires once `ttl` has
+    /// passed, like `put_with_ttl`.
+    pub fn put_with_ttl_cf(
+        &mut self,
+        cf: &CfHandle,
+        key: impl Into<String>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        if cf.id == 0 {
+            return self.put_with_ttl(key, value, ttl);
+        }
+        self.write_to_family(cf, RecordKind::SetWithTtl, key, value, Some(ttl))
+    }
+
+    /// Deletes a key from the column family of `cf`, like `delete`.
+    pub fn delete_cf(&mut self, cf: &CfHandle, key: impl Into<String>) -> Result<()> {
+        let key = key.into();
+        if cf.id == 0 {
+            return self.delete(key);
+        }
+        self.write_to_family(cf, RecordKind::Delete, key, Vec::new(), None)
+    }
+
+    /// Deletes every key from `start`, included, to `end`, excluded, from the column family of
+    /// `cf`, like `delete_range`.
+    pub fn delete_range_cf(&mut self, cf: &CfHandle, start: impl Into<String>, end: impl Into<String>) -> Result<()> {
+        let (start, end) = (start.into(), end.into());
+        if cf.id == 0 {
+            return self.delete_range(start, end);
+        }
+        if end <= start {
+            return Ok(());
+        }
+        self.write_to_family(cf, RecordKind::RangeDelete, start, end.into_bytes(), None)
+    }
+
+    /// Writes a merge operand for a key of the column family of `cf`, like `merge`.
+    pub fn merge_cf(&mut self, cf: &CfHandle, key: impl Into<String>, operand: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, operand) = (key.into(), operand.into());
+        if cf.id == 0 {
+            return self.merge(key, operand);
+        }
+        self.merge_operator.as_deref().with_context(|| "merging needs a database opened with a merge function")?;
+        self.write_to_family(cf, RecordKind::Merge, key, operand, None)
+    }
+
+    /// Gets a value from the column family of `cf`, like `get`.
+    pub fn get_cf(&self, cf: &CfHandle, key: &str) -> Result<Option<Vec<u8>>> {
+        self.tree(cf)?.get(key)
+    }
+
+    /// Returns the time left before the value of `key` in the column family of `cf` expires,
+    /// like `ttl`.
+    pub fn ttl_cf(&self, cf: &CfHandle, key: &str) -> Result<Option<Duration>> {
+        self.tree(cf)?.ttl(key, (self.clock)())
+    }
+
+    /// Gets the values of several keys from the column family of `cf`, like `multi_get`.
+    pub fn multi_get_cf(&self, cf: &CfHandle, keys: &[&str]) -> Result<Vec<io::Result<Option<Vec<u8>>>>> {
+        Ok(self.tree(cf)?.multi_get(keys))
+    }
+
+    /// Returns the live keys of `range` in the column family of `cf`, like `scan`.
+    pub fn scan_cf<'r>(&self, cf: &CfHandle, range: impl RangeBounds<&'r str>) -> Result<Scan<'_>> {
+        Ok(self.tree(cf)?.scan_range(range, false))
+    }
+
+    /// Returns the live keys of `range` in the column family of `cf` in descending order, like
+    /// `scan_rev`.
+    pub fn scan_rev_cf<'r>(&self, cf: &CfHandle, range: impl RangeBounds<&'r str>) -> Result<Scan<'_>> {
+        Ok(self.tree(cf)?.scan_range(range, true))
+    }
+
+    /// Returns the live keys starting with `prefix` in the column family of `cf`, like
+    /// `scan_prefix`.
+    pub fn scan_prefix_cf(&self, cf: &CfHandle, prefix: &str) -> Result<Scan<'_>> {
+        Ok(self.tree(cf)?.scan_prefix(prefix))
+    }
+
+    /// Writes a record of `kind` to the column family of `cf`, other than the default one: to
+    /// the WAL as a write to the family, then to the memtable of the family. A set with a TTL
+    /// expires `ttl` after the write, by the clock set with `with_clock`, and the value of a
+    /// range delete is the end of its range.
+    fn write_to_family(
+        &mut self,
+        cf: &CfHandle,
+        kind: RecordKind,
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.family(cf)?;
+        self.stall_writes()?;
+        let seqno = self.last_seqno + 1;
+        let ttl = ttl.map(|ttl| u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX));
+        let expires_at = ttl.map(|ttl| (self.clock)().saturating_add(ttl));
+        match expires_at {
+            Some(expires_at) => self.wal.append_to_family_with_expiry(cf.id, &key, &value, expires_at, seqno),
+            None => self.wal.append_to_family(cf.id, kind, &key, &value, seqno),
+        }
+        .with_context(|| "failed to write to WAL")?;
+        self.last_seqno = seqno;
+        match kind {
+            RecordKind::Delete | RecordKind::RangeDelete => self.metrics.record_deletes(1),
+            RecordKind::Merge => {}
+            _ => self.metrics.record_puts(1),
+        }
+        let merge = self.merge_operator.as_deref();
+        let family = self.column_families.get_mut(&cf.id).expect("the family was checked above");
+        match (kind, expires_at) {
+            (RecordKind::Delete, _) => family.memtable.delete_with_seqno(key, seqno),
+            (RecordKind::RangeDelete, _) => {
+                let end = String::from_utf8(value).expect("the end of a range is a string");
+                family.memtable.delete_range_with_seqno(&key, &end, seqno);
+            }
+            (RecordKind::Merge, _) => {
+                family.memtable.merge_with_seqno(key, Value::merge_operand(value), merge, seqno)?;
+            }
+            (_, Some(expires_at)) => {
+                family.memtable.insert_with_expiry(key, Value::from_bytes(value), seqno, expires_at);
+            }
+            _ => family.memtable.insert_with_seqno(key, Value::from_bytes(value), seqno),
+        }
+        if family.memtable.size_bytes() >= self.flush_threshold_bytes {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the column family of `cf`, other than the default one, unless it was dropped.
+    fn family(&self, cf: &CfHandle) -> Result<&ColumnFamily> {
+        self.column_families.get(&cf.id).with_context(|| format!("column family {} was dropped", cf.name))
+    }
+
+    /// Returns the memtables and tables of the default column family.
+    fn default_tree(&self) -> Tree<'_> {
+        Tree {
+            memtable: &self.memtable,
+            frozen: self.frozen_memtable.as_ref().map(|frozen| &frozen.memtable),
+            sstables: &self.sstables,
+            levels: &self.levels,
+            merge: self.merge_operator.as_ref(),
+            metrics: &self.metrics,
+        }
+    }
+
+    /// Returns the memtables and tables of the column family of `cf`.
+    fn tree(&self, cf: &CfHandle) -> Result<Tree<'_>> {
+        if cf.id == 0 {
+            return Ok(self.default_tree());
+        }
+        Ok(self.family(cf)?.tree(self.merge_operator.as_ref(), &self.metrics))
+    }
+
+    /// Prepares a table written by a flush or a compaction to be read, with the clock and the
+    /// block cache of the database.
+    fn prepare(&self, table: SsTable) -> Arc<SsTable> {
+        let table = table.with_clock(self.clock);
+        Arc::new(match &self.block_cache {
+            Some(cache) => table.with_block_cache(Arc::clone(cache)),
+            None => table,
+        })
+    }
+
+    /// Closes the database: the WAL writer is stopped once everything written is synced, see
+    /// `Wal::close`. Dropping the database does the same, without reporting an error. The
+    /// memtable is not flushed, it is replayed from the WAL by the next `open`.
+    ///
+    /// The round of compaction running, if any, is waited for and installed first. Dropping the
+    /// database waits for it too but leaves its outputs out of the manifest, so the next
+    /// `open` removes them.
+    pub fn close(mut self) -> Result<()> {
+        self.closing.store(true, Ordering::SeqCst);
+        self.finish_compaction()?;
+        self.purge_obsolete_files()?;
+        self.wal.close().with_context(|| "failed to close WAL")
+    }
+
+    /// Flushes the memtable to an SSTable: freezes it, see `freeze_memtable`, then flushes
+    /// it, see `flush_frozen_memtable`.
+    pub fn flush_memtable(&mut self) -> Result<()> {
+        self.freeze_memtable()?;
+        self.flush_frozen_memtable()
+    }
+
+    /// Freezes the memtable for a flush: it becomes `frozen_memtable` and later writes go to a
+    /// new memtable. Reads consult both until `flush_frozen_memtable` writes the frozen one to
+    /// an SSTable. A memtable frozen before is flushed first. An empty memtable is not frozen.
+    ///
+    /// The memtables of the column families are frozen along, as they share the WAL: it is
+    /// checkpointed once they are all flushed.
+    pub fn freeze_memtable(&mut self) -> Result<()> {
+        if self.memtable.is_empty() && self.column_families.values().all(|family| family.memtable.is_empty()) {
+            return Ok(());
+        }
+        self.flush_frozen_memtable()?;
+        let wal_position = self.wal.position().with_context(|| "failed to read the WAL position")?;
+        let fresh = MemTable::with_snapshots(Arc::clone(&self.snapshots)).with_clock(self.clock);
+        let memtable = std::mem::replace(&mut self.memtable, fresh);
+        self.frozen_memtable = Some(FrozenMemTable { memtable, wal_position, last_seqno: self.last_seqno });
+        for family in self.column_families.values_mut() {
+            let fresh = MemTable::with_snapshots(Arc::clone(&self.snapshots)).with_clock(self.clock);
+            family.frozen_memtable = Some(std::mem::replace(&mut family.memtable, fresh));
+        }
+        Ok(())
+    }
+
+    /// Writes the frozen memtable, if any, to a new SSTable. Once the table is committed to the
+    /// manifest the frozen memtable is released and the WAL checkpointed past the writes it
+    /// held, so a crash before then replays them from the WAL and removes the table.
+    ///
+    /// The versions the memtable kept for a live snapshot go to older tables written along, as
+    /// a table holds one version of a key, see `flush_layers`.
+    ///
+    /// The frozen memtables of the column families are written along, and all the tables are
+    /// committed at once. The next round of compaction is then handed to the compaction
+    /// thread, see `compact`, once the round running, if any, is installed.
+    pub fn flush_frozen_memtable(&mut self) -> Result<()> {
+        self.flush_frozen().map(drop)
+    }
+
+    /// Flushes the memtable to SSTables like `flush_memtable`, a memtable frozen before first,
+    /// and returns the tables written, none if the memtables were empty. The WAL is
+    /// checkpointed past the writes they hold. The tables may be compacted away later, see
+    /// `flush_frozen_memtable`, which leaves what they held at the time.
+    ///
+    /// Taking the database mutably, a flush never runs along another one, so two flushes in a
+    /// row write the memtable once and the second writes nothing.
+    pub fn flush(&mut self) -> Result<Vec<FlushedTable>> {
+        let mut flushed = self.flush_frozen()?;
+        self.freeze_memtable()?;
+        flushed.extend(self.flush_frozen()?);
+        Ok(flushed)
+    }
+
+    /// Makes sure the writes sent to the WAL so far are written to its file, which then
+    /// survives a crash of the process, without writing an SSTable. With `sync` the file is
+    /// synced too, see `Wal::force_flush`, so they survive a crash of the machine.
+    pub fn flush_wal(&self, sync: bool) -> Result<()> {
+        if sync {
+            return self.wal.force_flush().with_context(|| "failed to sync WAL");
+        }
+        // The writer writes the records it holds before it answers
+        self.wal.position().map(drop).with_context(|| "failed to write WAL")
+    }
+
+    /// Writes the frozen memtables like `flush_frozen_memtable`, returning the tables written.
+    fn flush_frozen(&mut self) -> Result<Vec<FlushedTable>> {
+        let Some(frozen) = &self.frozen_memtable else {
+            return Ok(Vec::new());
+        };
+        let pending = frozen.memtable.len();
+        info!(entry_count = pending, "flushing memtable to SSTable");
+        let default_tables = self.write_memtable(&frozen.memtable, 0)?;
+        let mut edits: Vec<VersionEdit> =
+            default_tables.iter().map(|table| VersionEdit::AddFile(TableFile::of(0, table))).collect();
+        let mut family_tables = Vec::new();
+        for (&id, family) in &self.column_families {
+            if let Some(memtable) = &family.frozen_memtable {
+                let tables = self.write_memtable(memtable, id)?;
+                edits.extend(tables.iter().map(|table| VersionEdit::AddFile(TableFile::of(0, table).in_family(id))));
+                family_tables.push((id, tables));
+            }
+        }
+        let wal_position = frozen.wal_position;
+        // Kept by the manifest, as a compaction may drop the record of the last write
+        edits.push(VersionEdit::LastSeqno(frozen.last_seqno));
+        self.commit(edits)?;
+        let paths: Vec<String> = default_tables.iter().map(|table| table_name(table)).collect();
+        let flushed: Vec<FlushedTable> = std::iter::once((0, &default_tables))
+            .chain(family_tables.iter().map(|(id, tables)| (*id, tables)))
+            .flat_map(|(family, tables)| {
+                tables.iter().map(move |table| FlushedTable {
+                    name: table_name(table),
+                    family,
+                    stats: table.stats().clone(),
+                })
+            })
+            .collect();
+        for table in default_tables {
+            self.sstables.insert(0, table);
+        }
+        for (id, tables) in family_tables {
+            let family = self.column_families.get_mut(&id).expect("a family flushed above");
+            for table in tables {
+                family.sstables.insert(0, table);
+            }
+        }
+        self.frozen_memtable = None;
+        for family in self.column_families.values_mut() {
+            family.frozen_memtable = None;
+        }
+        self.wal.checkpoint(wal_position).with_context(|| "failed to checkpoint WAL")?;
         info!(
             entry_count = pending,
-            path = %path.display(),
+            tables = ?paths,
             "memtable flush complete"
         );
+        self.poll_compactions()?;
+        self.purge_obsolete_files()?;
+        Ok(flushed)
+    }
+
+    /// Writes `memtable`, frozen, to new tables of the column family `family`, oldest first,
+    /// so the table names sort like the versions they hold. An empty memtable writes none.
+    ///
+    /// The versions the memtable kept for a live snapshot go to older tables, as a table holds
+    /// one version of a key, see `flush_layers`.
+    fn write_memtable(&self, memtable: &MemTable, family: u32) -> Result<Vec<Arc<SsTable>>> {
+        if memtable.is_empty() {
+            return Ok(Vec::new());
+        }
+        let layers =
+            if memtable.has_history() { flush_layers(memtable.versions()) } else { vec![memtable.iter().collect()] };
+        let mut tables = Vec::with_capacity(layers.len());
+        for (i, layer) in layers.into_iter().enumerate().rev() {
+            // Named once the table written before it is on disk, so the names differ
+            let path = next_sstable_path(&self.data_dir, &table_prefix(family));
+            let mut writer = SsTableWriter::new(&path, &SsTableOptions::default())?;
+            for (key, value, seqno) in layer {
+                let expires_at = memtable.expires_at(&key, seqno);
+                writer.add_with_seqno_and_expiry(&key, &value, seqno, expires_at)?;
+            }
+            if i == 0 {
+                for tombstone in memtable.range_tombstones() {
+                    writer.add_range_delete(tombstone.start, tombstone.end, tombstone.seqno);
+                }
+            }
+            tables.push(self.prepare(writer.finish().with_context(|| "failed to create SSTable")?));
+        }
+        Ok(tables)
+    }
+
+    /// Runs a round of compaction following `compaction`, see `CompactionStrategy`, and waits
+    /// for it: the one running on the compaction thread if there is one, or else the next one.
+    /// Returns false if there was nothing to compact.
+    ///
+    /// Each column family is compacted on its own, the default one first: a round compacts
+    /// the first family that has something to compact.
+    pub fn compact(&mut self) -> Result<bool> {
+        if self.running_compaction.is_none() && !self.schedule_compaction()? {
+            return Ok(false);
+        }
+        self.finish_compaction()
+    }
+
+    /// Waits until there is nothing left to compact, installing the outputs of every round,
+    /// see `compact`. A flush only hands the next round to the compaction thread.
+    pub fn wait_for_compactions(&mut self) -> Result<()> {
+        while self.compact()? {}
+        Ok(())
+    }
+
+    /// Hands the next round of compaction to the compaction thread, unless a round is running.
+    /// Returns false if none was handed.
+    fn schedule_compaction(&mut self) -> Result<bool> {
+        if self.running_compaction.is_some() {
+            return Ok(false);
+        }
+        let ids: Vec<u32> = std::iter::once(0).chain(self.column_families.keys().copied()).collect();
+        for id in ids {
+            let picked = self.with_family_tables(id, |db, sstables, levels| match db.compaction {
+                CompactionStrategy::SizeTiered(options) => db.pick_size_tiered(&options, id, sstables),
+                CompactionStrategy::Leveled(options) => db.pick_leveled(&options, id, sstables, levels),
+            });
+            if let Some((running, job)) = picked {
+                info!(table_count = job.inputs.len(), path = %job.output.display(), "compacting SSTables");
+                self.compactor.submit(job).with_context(|| "failed to start a compaction")?;
+                self.running_compaction = Some(running);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Waits for the round of compaction running, if any, and installs its outputs. Returns
+    /// false if none was running.
+    fn finish_compaction(&mut self) -> Result<bool> {
+        let Some(running) = self.running_compaction.take() else {
+            return Ok(false);
+        };
+        let completed = self.compactor.wait_completed().with_context(|| "failed to finish a compaction")?;
+        self.install_compaction(running, completed)?;
+        Ok(true)
+    }
+
+    /// Installs the outputs of the round of compaction running if it is done, then hands the
+    /// compaction thread the next round, without waiting for either.
+    fn poll_compactions(&mut self) -> Result<()> {
+        if self.running_compaction.is_some() {
+            let Some(completed) = self.compactor.try_completed() else {
+                return Ok(());
+            };
+            let running = self.running_compaction.take().expect("a compaction running");
+            self.install_compaction(running, completed)?;
+        }
+        self.schedule_compaction()?;
+        Ok(())
+    }
+
+    /// Holds up a write while flushes or compactions fall behind, see `WriteStallOptions`, and
+    /// counts the time it was held up. Past a stop threshold of frozen memtables they are
+    /// flushed, past one of level 0 tables the rounds of compaction are waited for until the
+    /// count is back under it, or there is nothing left to compact. Fails once the database
+    /// is closing, see `close_handle`, a stalled write included.
+    fn stall_writes(&mut self) -> Result<()> {
+        let closing = || anyhow::anyhow!("the database is closing");
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(closing());
+        }
+        let stall = self.write_stall;
+        let started = Instant::now();
+        let mut stalled = false;
+        let frozen_past = |threshold: usize, frozen: usize| threshold > 0 && frozen >= threshold;
+        if frozen_past(stall.frozen_stop_memtables, self.frozen_memtables()) {
+            self.flush_frozen_memtable()?;
+            stalled = true;
+        }
+        // Nothing new to compact since the last flush unless a round finished
+        if self.running_compaction.is_some() {
+            self.poll_compactions()?;
+        }
+        while self.max_l0_tables() >= stall.l0_stop_tables {
+            if self.running_compaction.is_none() && !self.schedule_compaction()? {
+                warn!(l0_tables = self.max_l0_tables(), "nothing to compact past the stop threshold");
+                break;
+            }
+            stalled = true;
+            // Woken up now and then to see whether the database is closing
+            let completed = self.compactor.wait_completed_timeout(Duration::from_millis(10));
+            if self.closing.load(Ordering::SeqCst) {
+                self.metrics.record_write_stall(started.elapsed());
+                return Err(closing());
+            }
+            if let Some(completed) = completed.with_context(|| "failed to finish a compaction")? {
+                let running = self.running_compaction.take().expect("a compaction running");
+                self.install_compaction(running, completed)?;
+            }
+        }
+        if !stalled
+            && (self.max_l0_tables() >= stall.l0_slowdown_tables
+                || frozen_past(stall.frozen_slowdown_memtables, self.frozen_memtables()))
+        {
+            std::thread::sleep(stall.slowdown);
+            stalled = true;
+        }
+        if stalled {
+            self.metrics.record_write_stall(started.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Returns the number of level 0 tables of the column family that has the most.
+    fn max_l0_tables(&self) -> usize {
+        let families = self.column_families.values().map(|family| family.sstables.len());
+        std::iter::once(self.sstables.len()).chain(families).max().unwrap_or(0)
+    }
+
+    /// Returns the number of freezes not yet flushed. The memtables of the column families are
+    /// frozen along with the default one, so they count once, and a freeze flushes the memtable
+    /// frozen before it, so there is one at most.
+    fn frozen_memtables(&self) -> usize {
+        usize::from(self.frozen_memtable.is_some())
+    }
+
+    /// Merges every SSTable holding keys from `start` to `end`, both included and unbounded
+    /// where `None`, whatever their level, and returns what was merged: `compact_range(None,
+    /// None)` merges every table. Tombstones are dropped, as no table left out holds a key
+    /// they shadow, and so are the values they shadowed.
+    ///
+    /// The range is widened to the key ranges of the tables it touches, until no table left
+    /// out shares a key with them, so a table split by a bound is merged whole. The outputs go
+    /// to the deepest level of the inputs, level 1 at least under leveled compaction, split
+    /// at `target_file_size`. Nothing is merged while a live snapshot reads versions the merge
+    /// would drop, like an automatic compaction.
+    ///
+    /// The call returns once the outputs replace the inputs in the manifest. The round running
+    /// on the compaction thread, if any, is finished first, so a table is never merged twice.
+    pub fn compact_range(&mut self, start: Option<&str>, end: Option<&str>) -> Result<CompactRangeSummary> {
+        self.finish_compaction()?;
+        self.with_family_tables(0, |db, sstables, levels| db.compact_tables_in_range(0, start, end, sstables, levels))
+    }
+
+    /// Merges the SSTables of the column family `cf` holding keys from `start` to `end`, see
+    /// `compact_range`.
+    pub fn compact_range_cf(
+        &mut self,
+        cf: &CfHandle,
+        start: Option<&str>,
+        end: Option<&str>,
+    ) -> Result<CompactRangeSummary> {
+        self.family(cf)?;
+        self.finish_compaction()?;
+        self.with_family_tables(cf.id, |db, sstables, levels| {
+            db.compact_tables_in_range(cf.id, start, end, sstables, levels)
+        })
+    }
+
+    /// Runs `f` on the tables of the column family `family`, taken out of it meanwhile.
+    fn with_family_tables<T>(
+        &mut self,
+        family: u32,
+        f: impl FnOnce(&mut Self, &mut Vec<Arc<SsTable>>, &mut Vec<Vec<Arc<SsTable>>>) -> T,
+    ) -> T {
+        let (mut sstables, mut levels) = match self.column_families.get_mut(&family) {
+            Some(cf) => (std::mem::take(&mut cf.sstables), std::mem::take(&mut cf.levels)),
+            None => (std::mem::take(&mut self.sstables), std::mem::take(&mut self.levels)),
+        };
+        let result = f(self, &mut sstables, &mut levels);
+        match self.column_families.get_mut(&family) {
+            Some(cf) => (cf.sstables, cf.levels) = (sstables, levels),
+            None => (self.sstables, self.levels) = (sstables, levels),
+        }
+        result
+    }
+
+    /// Merges the tables of `sstables` and `levels`, those of the column family `family`,
+    /// holding keys from `start` to `end`, see `compact_range`.
+    fn compact_tables_in_range(
+        &mut self,
+        family: u32,
+        start: Option<&str>,
+        end: Option<&str>,
+        sstables: &mut Vec<Arc<SsTable>>,
+        levels: &mut Vec<Vec<Arc<SsTable>>>,
+    ) -> Result<CompactRangeSummary> {
+        let (mut low, mut high) = (start.map(|key| key.as_bytes().to_vec()), end.map(|key| key.as_bytes().to_vec()));
+        loop {
+            let mut widened = false;
+            for table in sstables.iter().chain(levels.iter().flatten()) {
+                let (min, max) = table.key_range();
+                if !within(&low, &high, table) {
+                    continue;
+                }
+                if low.as_deref().is_some_and(|low| min < low) {
+                    (low, widened) = (Some(min.to_vec()), true);
+                }
+                if high.as_deref().is_some_and(|high| max > high) {
+                    (high, widened) = (Some(max.to_vec()), true);
+                }
+            }
+            if !widened {
+                break;
+            }
+        }
+        let selected = |table: &Arc<SsTable>| within(&low, &high, table);
+        let picked: Vec<&Arc<SsTable>> =
+            sstables.iter().chain(levels.iter().flatten()).filter(|t| selected(t)).collect();
+        if picked.is_empty() {
+            return Ok(CompactRangeSummary::default());
+        }
+        let (min, max) = seqno_range(picked);
+        if self.snapshots.pins(min, max) {
+            return Ok(CompactRangeSummary::default());
+        }
+        let deepest = levels.iter().rposition(|level| level.iter().any(selected)).map_or(0, |i| i + 1);
+        let (to, target_file_size) = match self.compaction {
+            CompactionStrategy::Leveled(options) => (deepest.max(1), Some(options.target_file_size)),
+            CompactionStrategy::SizeTiered(_) => (deepest, None),
+        };
+        while levels.len() < to {
+            levels.push(Vec::new());
+        }
+
+        // Inputs oldest first: the deepest level first, then level 0 last, oldest first
+        let newest = sstables.iter().position(selected).unwrap_or(0);
+        let mut inputs = Vec::new();
+        let mut origins = Vec::new();
+        for (i, level) in levels.iter_mut().enumerate().rev() {
+            let (picked, kept): (Vec<_>, Vec<_>) = std::mem::take(level).into_iter().partition(selected);
+            *level = kept;
+            origins.extend(std::iter::repeat_n(i + 1, picked.len()));
+            inputs.extend(picked);
+        }
+        let (mut picked, kept): (Vec<_>, Vec<_>) = std::mem::take(sstables).into_iter().partition(selected);
+        *sstables = kept;
+        picked.reverse();
+        origins.extend(std::iter::repeat_n(0, picked.len()));
+        inputs.extend(picked);
+
+        let number = self.next_table_number;
+        self.next_table_number += 1;
+        let output = self.data_dir.join(format!("{}{LEVEL_TABLE_PREFIX}{number}.sst", table_prefix(family)));
+        let mut options = MergeOptions::default().with_drop_tombstones(true).with_clock(self.clock);
+        if let Some(bytes) = target_file_size {
+            options = options.with_target_file_size(bytes);
+        }
+        if let Some(operator) = &self.merge_operator {
+            options = options.with_merge_operator(Arc::clone(operator));
+        }
+        info!(to_level = to, table_count = inputs.len(), path = %output.display(), "compacting SSTable range");
+        let paths = inputs.iter().map(|input| input.path().to_path_buf()).collect();
+        let job = CompactionJob { inputs: paths, output: output.clone(), split: true, options };
+        let outputs = match job.run() {
+            Ok(outputs) => outputs,
+            Err(err) => {
+                // Put the inputs back where they were
+                let mut upper = Vec::new();
+                for (input, level) in inputs.into_iter().zip(origins) {
+                    match level {
+                        0 => upper.push(input),
+                        level => insert_sorted(&mut levels[level - 1], vec![input]),
+                    }
+                }
+                upper.reverse();
+                sstables.splice(newest..newest, upper);
+                return Err(err).with_context(|| format!("failed to compact into {}", output.display()));
+            }
+        };
+        let outputs: Vec<Arc<SsTable>> = outputs.into_iter().map(|table| self.prepare(table)).collect();
+        let summary = CompactRangeSummary {
+            input_tables: inputs.len(),
+            output_tables: outputs.len(),
+            bytes_before: inputs.iter().map(|table| table.file_size()).sum(),
+            bytes_after: outputs.iter().map(|table| table.file_size()).sum(),
+        };
+        let mut edits: Vec<VersionEdit> =
+            inputs.iter().map(|input| VersionEdit::RemoveFile { name: table_name(input) }).collect();
+        edits.extend(outputs.iter().map(|table| VersionEdit::AddFile(TableFile::of(to, table).in_family(family))));
+        if to == 0 {
+            // Sharing no key with the tables left, the outputs may go anywhere among them
+            sstables.splice(newest..newest, outputs);
+        } else {
+            insert_sorted(&mut levels[to - 1], outputs);
+        }
+        self.commit(edits)?;
+
+        // Only once the manifest no longer lists them
+        self.obsolete.extend(inputs);
+        self.purge_obsolete_files()?;
+        self.metrics.record_compaction(summary.bytes_before);
+        info!(to_level = to, table_count = summary.output_tables, "range compaction complete");
+        Ok(summary)
+    }
+
+    /// Picks a round of size-tiered compaction, see `SizeTieredOptions`: the level 0 tables of
+    /// the newest full bucket, merged into one table, which replaces the newest of them in a
+    /// single rename once it is installed.
+    ///
+    /// Tombstones are kept: a crash before the manifest drops the other inputs leaves them in
+    /// place, older than the merged table, so they must not hold values it no longer shadows.
+    fn pick_size_tiered(
+        &self,
+        tiers: &SizeTieredOptions,
+        family: u32,
+        sstables: &[Arc<SsTable>],
+    ) -> Option<(RunningCompaction, CompactionJob)> {
+        let sizes: Vec<u64> = sstables.iter().map(|table| table.file_size()).collect();
+        let range = tiers.pick(&sizes)?;
+        if self.pinned(&sstables[range.clone()]) {
+            return None;
+        }
+        Some(self.merge_into_newest(family, &sstables[range]))
+    }
+
+    /// Returns a round merging `tables`, a run of level 0 tables newest first, into one table
+    /// which replaces the newest of them in a single rename once it is installed.
+    fn merge_into_newest(&self, family: u32, tables: &[Arc<SsTable>]) -> (RunningCompaction, CompactionJob) {
+        let newest = tables[0].path().to_path_buf();
+        // The tables are newest first, a merge takes them oldest first
+        let inputs: Vec<PathBuf> = tables.iter().rev().map(|table| table.path().to_path_buf()).collect();
+        let job = CompactionJob {
+            inputs: inputs.clone(),
+            output: newest.with_extension(COMPACTION_OUTPUT_EXTENSION),
+            split: false,
+            options: self.merge_options(),
+        };
+        let running = RunningCompaction {
+            family,
+            inputs: inputs.into_iter().map(|path| (0, path)).collect(),
+            to: 0,
+            replaces: Some(newest),
+        };
+        (running, job)
+    }
+
+    /// Picks a round of leveled compaction, see `LeveledOptions`: the level picked by
+    /// `LeveledOptions::pick_level`, all of level 0 or the table `LeveledOptions::pick_table`
+    /// picks, with the tables of the next level it overlaps, merged into tables of the next
+    /// level split at `target_file_size`.
+    ///
+    /// Tombstones are dropped only when nothing below the output level can hold a value they
+    /// shadow. The levels below cannot change before the round is installed, as only one
+    /// round runs at a time.
+    ///
+    /// Nothing is merged into the next level while a live snapshot pins the round, see
+    /// `pinned`, as the merge keeps one version of a key. Level 0 is then merged into itself
+    /// instead, those of its newest tables the snapshot does not pin, so it stays bounded and
+    /// writes do not stall on it, while the levels below grow past their budgets until the
+    /// snapshot is dropped.
+    fn pick_leveled(
+        &mut self,
+        leveled: &LeveledOptions,
+        family: u32,
+        sstables: &[Arc<SsTable>],
+        levels: &mut Vec<Vec<Arc<SsTable>>>,
+    ) -> Option<(RunningCompaction, CompactionJob)> {
+        let level_sizes: Vec<u64> =
+            levels.iter().map(|level| level.iter().map(|table| table.file_size()).sum()).collect();
+        let from = leveled.pick_level(sstables.len(), &level_sizes)?;
+        let to = from + 1;
+        while levels.len() < to {
+            levels.push(Vec::new());
+        }
+        let picked = if from == 0 {
+            sstables
+        } else {
+            let index = leveled.pick_table(&levels[from - 1], &levels[to - 1]).expect("a level over budget");
+            &levels[from - 1][index..index + 1]
+        };
+        let range =
+            picked.iter().map(|table| KeyRange::from(&**table)).reduce(|a, b| a.cover(&b)).expect("tables to compact");
+        let overlapping: Vec<&Arc<SsTable>> =
+            levels[to - 1].iter().filter(|&table| range.overlaps(&KeyRange::from(&**table))).collect();
+        let (min, max) = seqno_range(picked.iter().chain(overlapping.iter().copied()));
+        if self.snapshots.pins(min, max) {
+            return if from == 0 { self.pick_unpinned_l0(family, sstables) } else { None };
+        }
+
+        // Inputs oldest first: the overlapping tables of the output level, then the newer ones
+        let mut inputs: Vec<(usize, PathBuf)> =
+            overlapping.iter().map(|table| (to, table.path().to_path_buf())).collect();
+        inputs.extend(picked.iter().rev().map(|table| (from, table.path().to_path_buf())));
+        let bottom = levels[to..].iter().all(Vec::is_empty);
+        let number = self.next_table_number;
+        self.next_table_number += 1;
+        let job = CompactionJob {
+            inputs: inputs.iter().map(|(_, path)| path.clone()).collect(),
+            output: self.data_dir.join(format!("{}{LEVEL_TABLE_PREFIX}{number}.sst", table_prefix(family))),
+            split: true,
+            options: self
+                .merge_options()
+                .with_drop_tombstones(bottom)
+                .with_target_file_size(leveled.target_file_size),
+        };
+        Some((RunningCompaction { family, inputs, to, replaces: None }, job))
+    }
+
+    /// Picks a round merging level 0 into itself, for when a live snapshot pins it to the next
+    /// level: the newest tables the snapshot does not pin, two at least, merged into one like
+    /// `pick_size_tiered` does. The tables older than the snapshot are left in place.
+    fn pick_unpinned_l0(&self, family: u32, sstables: &[Arc<SsTable>]) -> Option<(RunningCompaction, CompactionJob)> {
+        let count = (1..=sstables.len()).take_while(|&count| !self.pinned(&sstables[..count])).last()?;
+        (count >= 2).then(|| self.merge_into_newest(family, &sstables[..count]))
+    }
+
+    /// Returns the options of the merges of compactions.
+    fn merge_options(&self) -> MergeOptions {
+        let options = MergeOptions::default().with_clock(self.clock);
+        match &self.merge_operator {
+            Some(operator) => options.with_merge_operator(Arc::clone(operator)),
+            None => options,
+        }
+    }
+
+    /// Installs the outputs of a round of compaction the compaction thread finished: they
+    /// replace its inputs in one commit to the manifest, and the inputs are removed after it.
+    /// The inputs are still where the round found them, as the tables of a family only change
+    /// by flushes, which add tables to level 0, and by compactions, which run one at a time.
+    fn install_compaction(&mut self, running: RunningCompaction, completed: CompletedCompaction) -> Result<()> {
+        let output = completed.job.output;
+        let outputs = completed.outputs.with_context(|| format!("failed to compact into {}", output.display()))?;
+        let family = running.family;
+        self.with_family_tables(family, |db, sstables, levels| {
+            // Where the outputs go in level 0, the newest input of level 0 having been there
+            let newest = sstables.iter().position(|table| running.inputs.iter().any(|(_, path)| table.path() == path));
+            let mut inputs = Vec::with_capacity(running.inputs.len());
+            for (level, path) in &running.inputs {
+                let tables = if *level == 0 { &mut *sstables } else { &mut levels[level - 1] };
+                let index = tables.iter().position(|table| table.path() == path).expect("an input of the compaction");
+                inputs.push(tables.remove(index));
+            }
+            let newest = newest.unwrap_or(0);
+
+            let mut edits = Vec::new();
+            let mut output_count = 0;
+            match &running.replaces {
+                Some(replaced) => {
+                    if let Some(merged) = outputs.into_iter().next() {
+                        drop(merged);
+                        fs::rename(&output, replaced)
+                            .with_context(|| format!("failed to swap in {}", replaced.display()))?;
+                        sync_dir(&db.data_dir)?;
+                        let table =
+                            SsTable::open(replaced).with_context(|| format!("failed to open {}", replaced.display()))?;
+                        let table = db.prepare(table);
+                        edits.push(VersionEdit::AddFile(TableFile::of(0, &table).in_family(family)));
+                        sstables.insert(newest, table);
+                        output_count = 1;
+                    }
+                }
+                None => {
+                    let outputs: Vec<Arc<SsTable>> = outputs.into_iter().map(|table| db.prepare(table)).collect();
+                    output_count = outputs.len();
+                    let to = running.to;
+                    edits.extend(
+                        outputs.iter().map(|table| VersionEdit::AddFile(TableFile::of(to, table).in_family(family))),
+                    );
+                    if to == 0 {
+                        sstables.splice(newest..newest, outputs);
+                    } else {
+                        insert_sorted(&mut levels[to - 1], outputs);
+                    }
+                }
+            }
+            let bytes = inputs.iter().map(|table| table.file_size()).sum();
+            // The input the output was renamed over, if any, is replaced rather than removed
+            let replaced = |input: &Arc<SsTable>| output_count > 0 && running.replaces.as_deref() == Some(input.path());
+            let removed: Vec<Arc<SsTable>> = inputs.into_iter().filter(|input| !replaced(input)).collect();
+            edits.extend(removed.iter().map(|input| VersionEdit::RemoveFile { name: table_name(input) }));
+            db.commit(edits)?;
+
+            // Only once the manifest no longer lists them
+            db.obsolete.extend(removed);
+            db.purge_obsolete_files()?;
+            db.metrics.record_compaction(bytes);
+            info!(to_level = running.to, table_count = output_count, "compaction complete");
+            Ok(())
+        })
+    }
+
+    /// Returns true if a live snapshot may read versions that merging `tables` would drop: one
+    /// taken within the sequence numbers they hold.
+    fn pinned(&self, tables: &[Arc<SsTable>]) -> bool {
+        let (min, max) = seqno_range(tables);
+        self.snapshots.pins(min, max)
+    }
+
+    /// Writes a consistent copy of the database to `target_dir`, which is created if it does
+    /// not exist and must be empty: a database directory of its own, opened with `open`,
+    /// holding every write up to the returned sequence number and none after it.
+    ///
+    /// The memtables are flushed first, so the copy needs no WAL. Each live SSTable is then
+    /// hard linked into the target, or copied where the target is on another file system, and
+    /// a manifest naming them is written along. The call holds the mutable borrow throughout,
+    /// so no compaction can remove a table before it is linked, and writes made through a
+    /// shared lock wait for it. A linked table is safe to share, as tables are never changed
+    /// in place: the copy and the database each remove only their own name of it.
+    pub fn checkpoint(&mut self, target_dir: impl AsRef<Path>) -> Result<CheckpointSummary> {
+        let target = target_dir.as_ref();
+        fs::create_dir_all(target).with_context(|| format!("failed to create {}", target.display()))?;
+        if fs::read_dir(target)?.next().is_some() {
+            bail!("checkpoint directory {} is not empty", target.display());
+        }
+        self.flush_memtable()?;
+        for name in self.manifest.files().keys() {
+            let from = self.data_dir.join(name);
+            link_or_copy(&from, &target.join(name))
+                .with_context(|| format!("failed to copy {} into the checkpoint", from.display()))?;
+        }
+        self.manifest
+            .snapshot_to(target)
+            .with_context(|| format!("failed to write the manifest of checkpoint {}", target.display()))?;
+        sync_dir(target).with_context(|| "failed to sync the checkpoint directory")?;
+        let mut summary = CheckpointSummary { files: 0, bytes: 0, last_seqno: self.last_seqno };
+        for entry in fs::read_dir(target)? {
+            summary.files += 1;
+            summary.bytes += entry?.metadata()?.len();
+        }
+        info!(path = %target.display(), files = summary.files, last_seqno = summary.last_seqno, "checkpoint written");
+        Ok(summary)
+    }
+
+    /// Restores the backup in `backup_dir`, a checkpoint or a copy of a database directory,
+    /// into `target_dir`, ready for `open`. The backup is checked first: its manifest must
+    /// parse, every SSTable it lists must exist and, under `verify_checksums`, verify. A backup
+    /// that fails a check leaves the target untouched.
+    ///
+    /// The files are hard linked, or copied where linking fails, into a staging directory next
+    /// to the target, which is renamed into place once complete, so a crash halfway leaves no
+    /// directory `open` would take for a database. The WAL of the backup, if any, is copied
+    /// with its checkpoint, so the records the tables already hold are not replayed again.
+    /// A target that is not empty is refused unless `overwrite` is set.
+    pub fn restore(backup_dir: impl AsRef<Path>, target_dir: impl AsRef<Path>, options: &RestoreOptions) -> Result<()> {
+        let (backup, target) = (backup_dir.as_ref(), target_dir.as_ref());
+        let (number, version) = Manifest::recover(backup)
+            .with_context(|| format!("failed to read the manifest of backup {}", backup.display()))?
+            .with_context(|| format!("backup {} has no manifest", backup.display()))?;
+        for name in version.files.keys() {
+            let path = backup.join(name);
+            if !path.is_file() {
+                bail!("backup {} is missing sstable {name}", backup.display());
+            }
+            if options.verify_checksums {
+                let report =
+                    SsTable::verify(&path).with_context(|| format!("failed to verify sstable {}", path.display()))?;
+                if let Some(error) = report.errors.first() {
+                    bail!("sstable {} of the backup is damaged: {error}", path.display());
+                }
+            }
+        }
+        let occupied = match fs::read_dir(target) {
+            Ok(mut entries) => entries.next().is_some(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err).with_context(|| format!("failed to read {}", target.display())),
+        };
+        if occupied && !options.overwrite {
+            bail!("restore target {} is not empty", target.display());
+        }
+
+        let staging = target.with_file_name(format!("{}.restoring", file_name(target)));
+        if staging.exists() {
+            fs::remove_dir_all(&staging).with_context(|| format!("failed to remove {}", staging.display()))?;
+        }
+        fs::create_dir_all(&staging).with_context(|| format!("failed to create {}", staging.display()))?;
+        for name in version.files.keys() {
+            let from = backup.join(name);
+            link_or_copy(&from, &staging.join(name))
+                .with_context(|| format!("failed to restore {}", from.display()))?;
+        }
+        let wal = backup.join(WAL_FILE);
+        if wal.exists() {
+            // Copied, as the restored database appends to it, along with the checkpoint that skips
+            // the records already flushed to the tables
+            let checkpoint = file_checkpoint_path(&wal);
+            let checkpoint = checkpoint.exists().then_some((checkpoint, file_checkpoint_path(staging.join(WAL_FILE))));
+            for (from, to) in std::iter::once((wal.clone(), staging.join(WAL_FILE))).chain(checkpoint) {
+                fs::copy(&from, &to).with_context(|| format!("failed to restore {}", from.display()))?;
+            }
+        }
+        Manifest::create(&staging, number, version).with_context(|| "failed to write the restored manifest")?;
+        if target.exists() {
+            fs::remove_dir_all(target).with_context(|| format!("failed to remove {}", target.display()))?;
+        }
+        fs::rename(&staging, target).with_context(|| format!("failed to move the restore into {}", target.display()))?;
+        if let Some(parent) = target.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            sync_dir(parent).with_context(|| format!("failed to sync {}", parent.display()))?;
+        }
+        info!(backup = %backup.display(), path = %target.display(), "backup restored");
         Ok(())
     }
+
+    /// Removes the database in `dir`: the files the crate writes, its manifest and `CURRENT`,
+    /// its SSTables, WAL and temporary files and its `LOCK` and `READ_LOCK`, then the directory
+    /// if nothing else is left in it. Anything else is left untouched and listed in the report.
+    /// It works on a database a crash left half created or damaged, as nothing is read, and a
+    /// directory that does not exist is left alone. Fails with `WouldBlock` while the database
+    /// is open, for reading only included.
+    pub fn destroy(dir: impl AsRef<Path>) -> io::Result<DestroyReport> {
+        let dir = dir.as_ref();
+        let mut report = DestroyReport::default();
+        if !dir.is_dir() {
+            return Ok(report);
+        }
+        let open = || io::Error::new(io::ErrorKind::WouldBlock, format!("database {} is open", dir.display()));
+        let lock = if dir.join(LOCK_FILE).exists() { Some(lock_dir(dir)?.ok_or_else(open)?) } else { None };
+        let read_lock = lock_readers(dir)?;
+        let lock_files = [READ_LOCK_FILE, LOCK_FILE];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if lock_files.contains(&name.as_str()) {
+                continue;
+            }
+            if entry.file_type()?.is_file() && is_database_file(&name) {
+                fs::remove_file(entry.path())?;
+                report.removed.push(entry.path());
+            } else {
+                report.kept.push(entry.path());
+            }
+        }
+        // Last, so no instance opens the database while its files are being removed
+        for (name, lock) in lock_files.into_iter().zip([read_lock, lock]) {
+            if let Some(lock) = lock {
+                drop(lock);
+                fs::remove_file(dir.join(name))?;
+                report.removed.push(dir.join(name));
+            }
+        }
+        if report.kept.is_empty() {
+            fs::remove_dir(dir)?;
+        } else {
+            sync_dir(dir)?;
+        }
+        info!(path = %dir.display(), removed = report.removed.len(), kept = report.kept.len(), "database destroyed");
+        Ok(report)
+    }
+
+    /// Commits `edits` to the manifest, see `Manifest::commit`.
+    fn commit(&mut self, edits: Vec<VersionEdit>) -> Result<()> {
+        self.manifest.commit(edits).with_context(|| "failed to commit to the manifest")
+    }
+}
+
+/// Name of the WAL file in the data directory.
+const WAL_FILE: &str = "wal.log";
+
+/// Name of the file locked by an open database, see `SnailDb::destroy`.
+const LOCK_FILE: &str = "LOCK";
+
+/// Locks the `LOCK` file of `dir`, creating it if needed. Returns `None` if another instance
+/// holds it.
+fn lock_dir(dir: &Path) -> io::Result<Option<File>> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Ok(None),
+        Err(fs::TryLockError::Error(err)) => Err(err),
+    }
+}
+
+/// Name of the file read-only instances lock shared, see `ReadOnlyDb`. Writers create it, as
+/// they hold `LOCK` exclusively, which readers open alongside.
+const READ_LOCK_FILE: &str = "READ_LOCK";
+
+/// Takes a shared lock on the `READ_LOCK` of `dir` without creating it, none if it does not
+/// exist. Fails with `WouldBlock` while the database is being destroyed.
+fn lock_shared(dir: &Path) -> io::Result<Option<File>> {
+    let file = match File::open(dir.join(READ_LOCK_FILE)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    match file.try_lock_shared() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "database is being destroyed"))
+        }
+        Err(fs::TryLockError::Error(err)) => Err(err),
+    }
+}
+
+/// Takes an exclusive lock on the `READ_LOCK` of `dir`, none if it does not exist. Fails with
+/// `WouldBlock` while a read-only instance has the database open.
+fn lock_readers(dir: &Path) -> io::Result<Option<File>> {
+    let file = match OpenOptions::new().write(true).open(dir.join(READ_LOCK_FILE)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("database {} is open for reading", dir.display()),
+        )),
+        Err(fs::TryLockError::Error(err)) => Err(err),
+    }
+}
+
+/// Returns true if `name` is a file the crate writes in a data directory, see `SnailDb::destroy`:
+/// the WAL files and their checkpoints among them.
+fn is_database_file(name: &str) -> bool {
+    let extension = Path::new(name).extension().and_then(|ext| ext.to_str());
+    name == LOCK_FILE
+        || name == READ_LOCK_FILE
+        || is_manifest_file(name)
+        || matches!(extension, Some("sst" | "tmp" | "compacted"))
+        || is_wal_file(name)
+}
+
+/// Returns true if `name` is a file of a WAL: `wal.log`, a segment `wal-<number>.log`, see
+/// `segment_path`, or the checkpoint of either, `<name>.checkpoint`.
+fn is_wal_file(name: &str) -> bool {
+    let log = name.strip_suffix(".checkpoint").unwrap_or(name);
+    let segment = log.strip_prefix("wal-").and_then(|log| log.strip_suffix(".log"));
+    log == WAL_FILE || segment.is_some_and(|number| number.parse::<u64>().is_ok())
+}
+
+/// Hard links `from` to `to`, or copies it where linking fails, as across file systems.
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Prefix of the file names of the tables compactions write to the levels from 1 on, followed
+/// by a number, see `SnailDb::compact`.
+const LEVEL_TABLE_PREFIX: &str = "lvl-";
+
+/// Returns the column family named `name` among `families`, the default one for `DEFAULT_CF`.
+fn find_cf(families: &BTreeMap<u32, ColumnFamily>, name: &str) -> Option<CfHandle> {
+    if name == DEFAULT_CF {
+        return Some(CfHandle { id: 0, name: name.to_string() });
+    }
+    let (&id, family) = families.iter().find(|(_, family)| family.name == name)?;
+    Some(CfHandle { id, name: family.name.clone() })
+}
+
+/// Returns the column families of `version`, with their tables taken from `trees` and empty
+/// memtables.
+fn column_families_of(
+    version: &Version,
+    mut trees: BTreeMap<u32, FamilyTables>,
+    snapshots: &Arc<SnapshotList>,
+) -> BTreeMap<u32, ColumnFamily> {
+    let families = version.families.iter().map(|(&id, name)| {
+        let (sstables, levels) = trees.remove(&id).unwrap_or_default();
+        let memtable = MemTable::with_snapshots(Arc::clone(snapshots));
+        (id, ColumnFamily { name: name.clone(), memtable, frozen_memtable: None, sstables, levels })
+    });
+    families.collect()
+}
+
+/// Replays the WAL `entries` into `memtable` and the memtables of `families`, and returns the
+/// largest sequence number they hold. The writes to a dropped column family are left out.
+fn replay_wal(
+    entries: Vec<(u64, WalEntry)>,
+    memtable: &MemTable,
+    families: &BTreeMap<u32, ColumnFamily>,
+    merge: Option<&dyn MergeOperator>,
+) -> Result<u64> {
+    let mut last_seqno = 0;
+    for (seqno, entry) in entries {
+        last_seqno = last_seqno.max(seqno);
+        let (memtable, entry) = match entry {
+            WalEntry::Family { id, entry } => match families.get(&id) {
+                Some(family) => (&family.memtable, *entry),
+                None => continue,
+            },
+            entry => (memtable, entry),
+        };
+        replay_entry(memtable, entry, seqno, merge)?;
+    }
+    Ok(last_seqno)
+}
+
+/// Applies a write replayed from the WAL to `memtable`, see `SnailDb::open`.
+fn replay_entry(memtable: &MemTable, entry: WalEntry, seqno: u64, merge: Option<&dyn MergeOperator>) -> Result<()> {
+    match entry {
+        WalEntry::Write(key, value @ Value::Merge(_)) => memtable
+            .merge_with_seqno(key, value, merge, seqno)
+            .with_context(|| "failed to replay a merge operand from the WAL")?,
+        WalEntry::Write(key, value) => memtable.insert_with_seqno(key, value, seqno),
+        WalEntry::RangeDelete { start, end } => memtable.delete_range_with_seqno(&start, &end, seqno),
+        WalEntry::SetWithTtl { key, value, expires_at } => {
+            memtable.insert_with_expiry(key, Value::from_bytes(value), seqno, expires_at)
+        }
+        WalEntry::Family { .. } => unreachable!("a family entry is unwrapped before it is replayed"),
+    }
+    Ok(())
+}
+
+/// Returns the index of the table of `level`, a level from 1 on, whose key range holds `key`,
+/// the only one that may hold it as the ranges of the level are disjoint.
+fn table_for_key(level: &[Arc<SsTable>], key: &str) -> Option<usize> {
+    let index = level.partition_point(|table| table.min_key() <= key.as_bytes());
+    index.checked_sub(1).filter(|&index| key.as_bytes() <= level[index].max_key())
+}
+
+/// Returns the smallest and largest sequence numbers `tables` hold, their range tombstones
+/// included.
+fn seqno_range<'a>(tables: impl IntoIterator<Item = &'a Arc<SsTable>>) -> (u64, u64) {
+    let (mut min, mut max) = (u64::MAX, 0);
+    for table in tables {
+        let tombstones = table.range_tombstones().iter().map(|tombstone| tombstone.seqno);
+        min = tombstones.clone().fold(min.min(table.stats().min_seqno), u64::min);
+        max = tombstones.fold(max.max(table.stats().max_seqno), u64::max);
+    }
+    (min, max)
+}
+
+/// Splits the versions of `MemTable::versions` into the layers a flush writes, newest first:
+/// the newest version of each key goes to the first, and each older one to the layer after
+/// that of the version before it.
+fn flush_layers(versions: Vec<(String, Value, u64)>) -> Vec<Vec<(String, Value, u64)>> {
+    let mut layers: Vec<Vec<(String, Value, u64)>> = vec![Vec::new()];
+    let mut layer = 0;
+    for (i, (key, value, seqno)) in versions.iter().cloned().enumerate() {
+        layer = if i > 0 && versions[i - 1].0 == key { layer + 1 } else { 0 };
+        if layers.len() == layer {
+            layers.push(Vec::new());
+        }
+        layers[layer].push((key, value, seqno));
+    }
+    layers
+}
+
+/// Returns true if a read of a key must go on to the older sources: it found no version yet,
+/// or merge operands only.
+fn is_pending(value: &Option<Value>) -> bool {
+    matches!(value, None | Some(Value::Merge(_)))
+}
+
+/// Stacks the versions of `key` read so far from the newer sources, `value`, onto `older`, its
+/// version in the next source, see `Value::stack_onto`. If a range tombstone of that source
+/// holds the key, `range_deleted`, the older sources no longer do: a key still unread then
+/// reads as a tombstone, and merge operands are combined as of a missing key.
+fn stack_version(
+    key: &str,
+    value: Option<Value>,
+    older: Option<Value>,
+    range_deleted: bool,
+    merge: Option<&dyn MergeOperator>,
+) -> io::Result<Option<Value>> {
+    let value = match (value, older) {
+        (Some(operands), Some(older)) => Some(operands.stack_onto(key.as_bytes(), older, merge)?),
+        (value, older) => value.or(older),
+    };
+    Ok(match value {
+        Some(operands @ Value::Merge(_)) if range_deleted => Some(operands.resolve(key.as_bytes(), merge)?),
+        None if range_deleted => Some(Value::Deleted),
+        value => value,
+    })
+}
+
+/// Returns the smallest key after every key starting with `prefix`: the prefix with its last
+/// character replaced by the next one. Characters that have no next one, `char::MAX`, are
+/// dropped first, and `None` is returned if only those are left, as no key then sorts after
+/// the ones starting with the prefix. Keys sort by their UTF-8 bytes, as characters do.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.trim_end_matches(char::MAX).to_string();
+    let last = end.pop()?;
+    // The surrogates are skipped, they are not characters
+    let next = (u32::from(last) + 1..=u32::from(char::MAX)).find_map(char::from_u32);
+    end.push(next.expect("a character after the last"));
+    Some(end)
+}
+
+/// Turns a version read from an SSTable into one of a `Scan`, whose keys are strings.
+fn utf8_version(version: io::Result<(Vec<u8>, Value)>) -> io::Result<(String, Value)> {
+    let (key, value) = version?;
+    let key = String::from_utf8(key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "SSTable key is not valid UTF-8"))?;
+    Ok((key, value))
+}
+
+/// Returns true if the key range of `table` meets the keys from `start` to `end`.
+fn overlaps(table: &SsTable, start: &Bound<String>, end: &Bound<String>) -> bool {
+    let before_end = match end {
+        Bound::Included(end) => table.min_key() <= end.as_bytes(),
+        Bound::Excluded(end) => table.min_key() < end.as_bytes(),
+        Bound::Unbounded => true,
+    };
+    let after_start = match start {
+        Bound::Included(start) => table.max_key() >= start.as_bytes(),
+        Bound::Excluded(start) => table.max_key() > start.as_bytes(),
+        Bound::Unbounded => true,
+    };
+    before_end && after_start
+}
+
+/// Returns the file name of a table.
+fn table_name(table: &SsTable) -> String {
+    file_name(table.path())
+}
+
+/// Returns whether `table` holds keys from `low` to `high`, both included and unbounded where
+/// `None`.
+fn within(low: &Option<Vec<u8>>, high: &Option<Vec<u8>>, table: &SsTable) -> bool {
+    let (min, max) = table.key_range();
+    low.as_deref().is_none_or(|low| max >= low) && high.as_deref().is_none_or(|high| min <= high)
+}
+
+/// Applies `f` to `table`, or to a copy of it if a scan of `SnailDb::scan_pinned` shares it,
+/// see `SsTable::try_clone`. The scan keeps reading the table as it was, which goes to
+/// `superseded`. A shared table that fails to be copied is returned as it was.
+fn unshared(
+    table: Arc<SsTable>,
+    superseded: &mut Vec<Arc<SsTable>>,
+    f: impl FnOnce(SsTable) -> SsTable,
+) -> Arc<SsTable> {
+    let shared = match Arc::try_unwrap(table) {
+        Ok(table) => return Arc::new(f(table)),
+        Err(shared) => shared,
+    };
+    match shared.try_clone() {
+        Ok(copy) => {
+            superseded.push(shared);
+            Arc::new(f(copy))
+        }
+        Err(err) => {
+            warn!(path = %shared.path().display(), error = %err, "failed to copy an sstable a scan reads");
+            shared
+        }
+    }
+}
+
+/// Adds `tables` to a level from 1 on, keeping it sorted by key range.
+fn insert_sorted(level: &mut Vec<Arc<SsTable>>, tables: Vec<Arc<SsTable>>) {
+    level.extend(tables);
+    level.sort_by_cached_key(|table| KeyRange::from(&**table));
+}
+
+/// Extension of the output of a compaction until it replaces the newest of its inputs.
+const COMPACTION_OUTPUT_EXTENSION: &str = "compacted";
+
+/// Returns the outputs of compactions a crash stopped before their swap, see `compact`, and
+/// removes them if `remove` is set.
+fn remove_compaction_outputs(dir: &Path, remove: bool) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == COMPACTION_OUTPUT_EXTENSION) {
+            if remove {
+                fs::remove_file(&path)?;
+            }
+            paths.push(path);
+        }
+    }
+    Ok(paths)
 }
 
-/// Loads the existing SSTables from the given directory.
-/// Only loads metadata (bloom filter, min/max keys) for efficient startup.
-/// Entries are loaded lazily when needed.
-fn load_existing_sstables(dir: &Path) -> Result<Vec<SsTable>> {
-    let mut tables = Vec::new();
+/// Lists the SSTables of a directory without a manifest as the live set, all of level 0.
+fn adopt_existing_sstables(dir: &Path) -> Result<LiveFiles> {
+    let mut live = LiveFiles::new();
     for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if let Some(ext) = path.extension() {
-            if ext == "sst" {
-                tables.push(
-                    SsTable::load_metadata(&path)
-                        .with_context(|| format!("failed to load sstable metadata {}", path.display()))?,
-                );
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "sst") {
+            let name = file_name(&path);
+            // The keys and seqnos are filled in once the table is opened
+            let file = TableFile {
+                level: 0,
+                name: name.clone(),
+                min_key: Vec::new(),
+                max_key: Vec::new(),
+                min_seqno: 0,
+                max_seqno: 0,
+                family: 0,
+            };
+            live.insert(name, file);
+        }
+    }
+    Ok(live)
+}
+
+/// The tables of a column family: those of level 0, newest first, and of the levels from 1 on.
+type FamilyTables = (Vec<Arc<SsTable>>, Vec<Vec<Arc<SsTable>>>);
+
+/// Loads the SSTables of the live set from the given directory, the tables of level 0 and
+/// those of the levels from 1 on of each column family, by id.
+/// Tables are opened lazily: only metadata (bloom filter, min/max keys, index) is read
+/// for efficient startup, and point reads seek into the file. Under `RecoveryMode::SkipCorrupt`
+/// a table that fails to open is skipped and noted in `report`.
+fn load_existing_sstables(
+    dir: &Path,
+    live: &LiveFiles,
+    mode: RecoveryMode,
+    report: &mut RecoveryReport,
+) -> Result<BTreeMap<u32, FamilyTables>> {
+    let mut families: BTreeMap<u32, FamilyTables> = BTreeMap::new();
+    for file in live.values() {
+        let path = dir.join(&file.name);
+        let (tables, levels) = families.entry(file.family).or_default();
+        match SsTable::open(&path) {
+            Ok(table) if file.level == 0 => tables.push(Arc::new(table)),
+            Ok(table) => {
+                while levels.len() < file.level {
+                    levels.push(Vec::new());
+                }
+                levels[file.level - 1].push(Arc::new(table));
+            }
+            Err(err) if mode == RecoveryMode::SkipCorrupt => {
+                warn!(path = %path.display(), error = %err, "skipping sstable that fails to open");
+                report.skipped(&path, fs::metadata(&path).map_or(0, |metadata| metadata.len()));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to open sstable {}", path.display()));
             }
         }
     }
-    Ok(tables)
+    for (tables, levels) in families.values_mut() {
+        tables.sort_by(|a, b| b.path().cmp(a.path()));
+        for level in levels {
+            level.sort_by_cached_key(|table| KeyRange::from(&**table));
+        }
+    }
+    Ok(families)
+}
+
+/// Returns the SSTables out of the live set, the outputs of a flush or compaction a crash
+/// stopped before it committed or the inputs of a compaction it stopped after, and removes
+/// them if `remove` is set.
+fn remove_obsolete_sstables(dir: &Path, live: &LiveFiles, remove: bool) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "sst") && !live.contains_key(&file_name(&path)) {
+            if remove {
+                fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+            }
+            paths.push(path);
+        }
+    }
+    sync_dir(dir)?;
+    Ok(paths)
+}
+
+/// Returns the prefix of the file names of the tables of the column family `family`, none for
+/// the default one.
+fn table_prefix(family: u32) -> String {
+    if family == 0 { String::new() } else { format!("cf{family}-") }
+}
+
+/// Returns the path of the next SSTable, named after `prefix` and the current time so tables
+/// sort newest first, see `open`, and after the time of the last table if it was flushed in
+/// the same millisecond.
+fn next_sstable_path(dir: &Path, prefix: &str) -> PathBuf {
+    let mut millis = unix_millis();
+    loop {
+        let path = dir.join(format!("{prefix}sst-{millis}.sst"));
+        if !path.exists() {
+            return path;
+        }
+        millis += 1;
+    }
 }
 
 /// Returns the current time in milliseconds since the UNIX epoch.
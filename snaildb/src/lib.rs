@@ -3,5 +3,12 @@ pub mod utils;
 pub mod wal;
 pub mod worker;
 pub mod db;
+pub mod batch;
+pub mod stats;
 
-pub use db::SnailDb;
\ No newline at end of file
+pub use batch::WriteBatch;
+pub use stats::DbStats;
+pub use db::{
+    CasResult, CfHandle, CheckpointSummary, CloseHandle, CompactRangeSummary, Db, FlushedTable, ReadOnlyDb,
+    RestoreOptions, SnailDb, WriteStallOptions, DEFAULT_CF,
+};
\ No newline at end of file
@@ -0,0 +1,147 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters of the reads and writes a database served, bumped on its hot paths, see
+/// `SnailDb::stats`.
+///
+/// Every counter is an atomic updated with relaxed ordering, like those of `WalMetrics`, so
+/// reads through a shared borrow count what they serve without a lock.
+#[derive(Debug, Default)]
+pub(crate) struct DbMetrics {
+    gets: AtomicU64,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    bloom_hits: AtomicU64,
+    bloom_skips: AtomicU64,
+    compactions: AtomicU64,
+    compacted_bytes: AtomicU64,
+    write_stall_micros: AtomicU64,
+}
+
+impl DbMetrics {
+    /// Counts `keys` keys looked up.
+    pub(crate) fn record_gets(&self, keys: u64) {
+        self.gets.fetch_add(keys, Ordering::Relaxed);
+    }
+
+    /// Counts `writes` values written.
+    pub(crate) fn record_puts(&self, writes: u64) {
+        self.puts.fetch_add(writes, Ordering::Relaxed);
+    }
+
+    /// Counts `deletes` keys or ranges deleted.
+    pub(crate) fn record_deletes(&self, deletes: u64) {
+        self.deletes.fetch_add(deletes, Ordering::Relaxed);
+    }
+
+    /// Counts a table whose bloom filter was asked about a key: read if `hit`, skipped otherwise.
+    pub(crate) fn record_bloom(&self, hit: bool) {
+        let counter = if hit { &self.bloom_hits } else { &self.bloom_skips };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts `stalled`, the time a write was held up by `SnailDb::with_write_stall`.
+    pub(crate) fn record_write_stall(&self, stalled: Duration) {
+        let micros = u64::try_from(stalled.as_micros()).unwrap_or(u64::MAX);
+        self.write_stall_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Counts a compaction that merged tables of `bytes` bytes.
+    pub(crate) fn record_compaction(&self, bytes: u64) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compacted_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// What a database holds and what it served since it was opened, see `SnailDb::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DbStats {
+    /// the number of live SSTables in each level, level 0 first, of every column family
+    pub sstables_per_level: Vec<usize>,
+    /// bytes the live SSTables take on disk
+    pub sstable_bytes: u64,
+    /// bytes the memtables hold, the frozen ones included
+    pub memtable_bytes: u64,
+    /// bytes the WAL file takes on disk
+    pub wal_bytes: u64,
+    /// keys looked up by `get`, `get_cf`, `multi_get` and `get_with_snapshot`
+    pub gets: u64,
+    /// values written, by `put` and the other writes of a value, a batch counting each of its writes
+    pub puts: u64,
+    /// deletes of a key or of a range written
+    pub deletes: u64,
+    /// tables a point read went on to read after their bloom filter said they may hold the key
+    pub bloom_hits: u64,
+    /// tables a point read skipped because their bloom filter ruled the key out
+    pub bloom_skips: u64,
+    /// lookups of the block cache that found their block and that read it from the file, 0
+    /// without a cache, see `SnailDb::with_block_cache`
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    /// compactions run and the bytes of the tables they merged
+    pub compactions: u64,
+    pub compacted_bytes: u64,
+    /// microseconds writes were held up while flushes or compactions fell behind, see
+    /// `SnailDb::with_write_stall`
+    pub write_stall_micros: u64,
+    /// the sequence number of the last write
+    pub last_seqno: u64,
+}
+
+impl DbStats {
+    /// Fills in the counters of `metrics`.
+    pub(crate) fn with_counters(mut self, metrics: &DbMetrics) -> Self {
+        self.gets = metrics.gets.load(Ordering::Relaxed);
+        self.puts = metrics.puts.load(Ordering::Relaxed);
+        self.deletes = metrics.deletes.load(Ordering::Relaxed);
+        self.bloom_hits = metrics.bloom_hits.load(Ordering::Relaxed);
+        self.bloom_skips = metrics.bloom_skips.load(Ordering::Relaxed);
+        self.compactions = metrics.compactions.load(Ordering::Relaxed);
+        self.compacted_bytes = metrics.compacted_bytes.load(Ordering::Relaxed);
+        self.write_stall_micros = metrics.write_stall_micros.load(Ordering::Relaxed);
+        self
+    }
+
+    /// Returns the number of live SSTables, every level included.
+    pub fn sstable_count(&self) -> usize {
+        self.sstables_per_level.iter().sum()
+    }
+
+    /// Returns the bytes the database takes on disk, its SSTables and WAL.
+    pub fn disk_bytes(&self) -> u64 {
+        self.sstable_bytes + self.wal_bytes
+    }
+
+    /// Returns the share of the block cache lookups that found their block, `None` before the first.
+    pub fn block_cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.block_cache_hits + self.block_cache_misses;
+        (lookups > 0).then(|| self.block_cache_hits as f64 / lookups as f64)
+    }
+}
+
+impl fmt::Display for DbStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "last seqno: {}", self.last_seqno)?;
+        let levels: Vec<String> =
+            self.sstables_per_level.iter().enumerate().map(|(level, count)| format!("L{level}: {count}")).collect();
+        writeln!(f, "sstables: {} ({}), {} bytes", self.sstable_count(), levels.join(", "), self.sstable_bytes)?;
+        writeln!(f, "memtables: {} bytes", self.memtable_bytes)?;
+        writeln!(f, "wal: {} bytes", self.wal_bytes)?;
+        writeln!(f, "disk: {} bytes", self.disk_bytes())?;
+        writeln!(f, "operations: {} gets, {} puts, {} deletes", self.gets, self.puts, self.deletes)?;
+        writeln!(f, "bloom filters: {} hits, {} skips", self.bloom_hits, self.bloom_skips)?;
+        match self.block_cache_hit_rate() {
+            Some(rate) => writeln!(
+                f,
+                "block cache: {:.1}% hit rate ({} hits, {} misses)",
+                rate * 100.0,
+                self.block_cache_hits,
+                self.block_cache_misses
+            )?,
+            None => writeln!(f, "block cache: no lookups")?,
+        }
+        writeln!(f, "compactions: {}, {} bytes compacted", self.compactions, self.compacted_bytes)?;
+        write!(f, "write stalls: {} us", self.write_stall_micros)
+    }
+}
@@ -1,6 +1,6 @@
 use xxhash_rust::xxh3::xxh3_64;
 
-/// The number of hash functions to use.
+/// The number of hash functions used with the default bits per key.
 pub const NUM_HASH_FUNCTIONS: usize = 7;
 /// The number of bits per key to use for the bloom filter.
 pub const BITS_PER_KEY: usize = 10;
@@ -18,35 +18,66 @@ pub const DEFAULT_ERROR_RATE: f64 = 0.01; // 1% error rate
 #[derive(Clone, Debug)]
 pub struct BloomFilter {
     pub bits: Vec<u8>,
+    /// The number of hash functions used to set and probe bits.
+    pub num_hashes: usize,
 }
 
 impl BloomFilter {
     pub fn new(num_keys: usize) -> Self{
-        Self::with_bits_per_key(num_keys)
+        Self::with_bits_per_key(num_keys, BITS_PER_KEY)
     }
 
-    pub fn with_bits_per_key(num_keys: usize) -> Self {
-        let bits_per_key = num_keys * BITS_PER_KEY;
-        let bits = (bits_per_key + 7) / 8; // round up to nearest byte
+    /// Creates a filter sized for `num_keys` keys using `bits_per_key` bits each.
+    /// The number of hash functions is derived from the bits per key (ln 2 * bits_per_key),
+    /// which is 7 for the default of 10 bits per key.
+    pub fn with_bits_per_key(num_keys: usize, bits_per_key: usize) -> Self {
+        let total_bits = num_keys * bits_per_key;
+        let bits = total_bits.div_ceil(8); // round up to nearest byte
         let bits = vec![0u8; bits];
-        Self { bits }
+        let num_hashes = ((bits_per_key as f64 * std::f64::consts::LN_2).round() as usize).clamp(1, 30);
+        Self { bits, num_hashes }
     }
 
-    /// Hash function that simulates multiple hash functions by combining the key with a seed, which returns a u64 value which is the bit index of the key.
-    fn hash(&self, key: &str, seed: usize) -> u64 {
-        // Hash the key once
-        let h = xxh3_64(key.as_bytes());
-        // Use double hashing: combine the hash with seed using a large prime multiplier
-        // This ensures different seeds produce well-distributed hash values (basically h1 + seed * h2)
-        h.wrapping_add(seed as u64).wrapping_mul(0x9e3779b97f4a7c15)
+    /// Returns an empty filter, which treats every key as possibly present.
+    pub fn empty() -> Self {
+        Self { bits: Vec::new(), num_hashes: NUM_HASH_FUNCTIONS }
+    }
+
+    /// Returns true if the filter has no bits, i.e. the table was written without a filter.
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Hashes a key once, the result can be fed to `insert_hash`/`may_contain_hash`.
+    /// This lets a streaming writer remember 8 bytes per key and build the filter at the end.
+    pub fn key_hash(key: &[u8]) -> u64 {
+        xxh3_64(key)
+    }
+
+    /// Hash function that simulates multiple hash functions by combining the key hash with a seed, which returns a u64 value which is the bit index of the key.
+    fn hash(h: u64, seed: usize) -> u64 {
+        // Use double hashing (h1 + seed * h2), where h2 comes from the other half of the hash.
+        // h2 must depend on the key, otherwise every key probes the same stride and the
+        // false positive rate is far above what the bits per key promise.
+        let h1 = h.wrapping_mul(0x9e3779b97f4a7c15);
+        let h2 = h.rotate_left(32) | 1;
+        h1.wrapping_add((seed as u64).wrapping_mul(h2))
     }
 
     /// Add a key to the filter
-    pub fn insert(&mut self, key: &str) {
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(Self::key_hash(key));
+    }
+
+    /// Add a key hash produced by `key_hash` to the filter
+    pub fn insert_hash(&mut self, h: u64) {
         let num_bits = self.bits.len() * 8;
-        
-        for i in 0..NUM_HASH_FUNCTIONS {
-            let bit_index = self.hash(key, i) % (num_bits as u64);
+        if num_bits == 0 {
+            return;
+        }
+
+        for i in 0..self.num_hashes {
+            let bit_index = Self::hash(h, i) % (num_bits as u64);
             let byte_index = (bit_index / 8) as usize; // get the index of the byte in the vector
             let bit_offset = (bit_index % 8) as u8; // get the offset of the bit in the byte
             self.bits[byte_index] |= 1 << bit_offset; // set the bit to 1
@@ -56,18 +87,45 @@ impl BloomFilter {
     /// Check if key might be in the set.
     /// Returns false = DEFINITELY NOT present
     /// Returns true = MAYBE present (check SSTable to confirm)
-    pub fn may_contain(&self, key: &str) -> bool {
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.may_contain_hash(Self::key_hash(key))
+    }
+
+    /// Same as `may_contain` for a key hash produced by `key_hash`.
+    pub fn may_contain_hash(&self, h: u64) -> bool {
         let num_bits = self.bits.len() * 8;
-        
-        for i in 0..NUM_HASH_FUNCTIONS {
-            let bit_index = self.hash(key, i) % (num_bits as u64);
+        if num_bits == 0 {
+            return true; // No filter, cannot rule anything out
+        }
+
+        for i in 0..self.num_hashes {
+            let bit_index = Self::hash(h, i) % (num_bits as u64);
             let byte_index = (bit_index / 8) as usize;
             let bit_offset = (bit_index % 8) as u8;
-            
+
             if (self.bits[byte_index] & (1 << bit_offset)) == 0 {
                 return false; // Bit not set = key definitely not present
             }
         }
         true // All bits set = key probably present
     }
-}
\ No newline at end of file
+
+    /// Serializes the filter as [num_hashes:1][bits:var].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(1 + self.bits.len());
+        buffer.push(self.num_hashes as u8);
+        buffer.extend_from_slice(&self.bits);
+        buffer
+    }
+
+    /// Deserializes a filter written by `encode`. An empty buffer yields an empty filter.
+    pub fn decode(buffer: &[u8]) -> Self {
+        match buffer.split_first() {
+            Some((&num_hashes, bits)) => Self {
+                bits: bits.to_vec(),
+                num_hashes: num_hashes as usize,
+            },
+            None => Self::empty(),
+        }
+    }
+}
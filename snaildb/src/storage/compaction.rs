@@ -0,0 +1,222 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::storage::sstable::{KeyRange, SsTable};
+
+/// Options of the size-tiered compaction strategy, see `SnailDb::compact`: tables of similar
+/// size are grouped into buckets, and a bucket of `min_threshold` tables is merged into one.
+///
+/// Only tables next to each other in age share a bucket, so the merged table takes their
+/// place in the order of the tables and the newest version of a key still wins.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeTieredOptions {
+    /// Number of tables a bucket needs to be compacted, 4 by default. Below 2 compaction is off.
+    pub min_threshold: usize,
+    /// Largest number of tables merged at once, the newest of a bucket, 32 by default.
+    pub max_threshold: usize,
+    /// A table joins a bucket if its size is between `bucket_low` and `bucket_high` times the
+    /// average size of the bucket, 0.5 and 1.5 by default.
+    pub bucket_low: f64,
+    pub bucket_high: f64,
+    /// Tables smaller than this share a bucket whatever their sizes, 50 MiB by default.
+    pub min_table_size: u64,
+}
+
+impl Default for SizeTieredOptions {
+    fn default() -> Self {
+        Self {
+            min_threshold: 4,
+            max_threshold: 32,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+            min_table_size: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl SizeTieredOptions {
+    /// Sets the number of tables of a bucket that triggers a compaction.
+    pub fn with_min_threshold(mut self, tables: usize) -> Self {
+        self.min_threshold = tables;
+        self
+    }
+
+    /// Sets the largest number of tables merged at once.
+    pub fn with_max_threshold(mut self, tables: usize) -> Self {
+        self.max_threshold = tables;
+        self
+    }
+
+    /// Sets the bounds of the sizes joining a bucket, as factors of its average size.
+    pub fn with_bucket_bounds(mut self, low: f64, high: f64) -> Self {
+        self.bucket_low = low;
+        self.bucket_high = high;
+        self
+    }
+
+    /// Sets the size below which tables share a bucket.
+    pub fn with_min_table_size(mut self, bytes: u64) -> Self {
+        self.min_table_size = bytes;
+        self
+    }
+
+    /// Picks the tables to compact among tables of `sizes` bytes, newest first: the newest
+    /// bucket of at least `min_threshold` tables, up to `max_threshold` of them. Returns their
+    /// indexes, or `None` if no bucket is full.
+    pub fn pick(&self, sizes: &[u64]) -> Option<Range<usize>> {
+        if self.min_threshold < 2 {
+            return None;
+        }
+        let max = self.max_threshold.max(self.min_threshold);
+        let (mut start, mut end) = (0, 0);
+        let mut total = 0u64;
+        for &size in sizes {
+            let len = (end - start) as u64;
+            if len > 0 && !self.joins(size, total / len) {
+                if end - start >= self.min_threshold {
+                    break;
+                }
+                (start, total) = (end, 0);
+            }
+            total += size;
+            end += 1;
+        }
+        let end = end.min(start + max);
+        (end - start >= self.min_threshold).then_some(start..end)
+    }
+
+    /// Returns whether a table of `size` bytes joins a bucket of `average` bytes.
+    fn joins(&self, size: u64, average: u64) -> bool {
+        if size < self.min_table_size && average < self.min_table_size {
+            return true;
+        }
+        let (size, average) = (size as f64, average as f64);
+        size >= average * self.bucket_low && size <= average * self.bucket_high
+    }
+}
+
+/// Options of the leveled compaction strategy, see `SnailDb::compact`: flushed tables pile up
+/// in level 0, where their key ranges may overlap, and are merged into level 1 once there are
+/// `l0_compaction_trigger` of them. Each level from 1 on holds tables of disjoint key ranges,
+/// up to a size budget growing by `level_size_multiplier` from level to level. A level over
+/// its budget has one table merged into the tables of the next level it overlaps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeveledOptions {
+    /// Number of level 0 tables that triggers their compaction into level 1, 4 by default.
+    pub l0_compaction_trigger: usize,
+    /// Size budget of level 1 in bytes, 10 MiB by default.
+    pub base_level_size: u64,
+    /// Factor between the budgets of a level and of the next one, 10 by default.
+    pub level_size_multiplier: u64,
+    /// Size at which the output of a compaction is split into another table, 2 MiB by default.
+    pub target_file_size: u64,
+    /// Number of levels, level 0 included, 7 by default. The last level has no budget.
+    pub max_levels: usize,
+}
+
+impl Default for LeveledOptions {
+    fn default() -> Self {
+        Self {
+            l0_compaction_trigger: 4,
+            base_level_size: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+            target_file_size: 2 * 1024 * 1024,
+            max_levels: 7,
+        }
+    }
+}
+
+impl LeveledOptions {
+    /// Sets the number of level 0 tables that triggers their compaction.
+    pub fn with_l0_compaction_trigger(mut self, tables: usize) -> Self {
+        self.l0_compaction_trigger = tables;
+        self
+    }
+
+    /// Sets the size budget of level 1.
+    pub fn with_base_level_size(mut self, bytes: u64) -> Self {
+        self.base_level_size = bytes;
+        self
+    }
+
+    /// Sets the factor between the budgets of consecutive levels.
+    pub fn with_level_size_multiplier(mut self, multiplier: u64) -> Self {
+        self.level_size_multiplier = multiplier;
+        self
+    }
+
+    /// Sets the size of the tables compactions write.
+    pub fn with_target_file_size(mut self, bytes: u64) -> Self {
+        self.target_file_size = bytes;
+        self
+    }
+
+    /// Sets the number of levels, level 0 included.
+    pub fn with_max_levels(mut self, levels: usize) -> Self {
+        self.max_levels = levels;
+        self
+    }
+
+    /// Returns the size budget of `level`, from 1 on.
+    pub fn level_budget(&self, level: usize) -> u64 {
+        let exponent = u32::try_from(level.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.base_level_size.saturating_mul(self.level_size_multiplier.saturating_pow(exponent))
+    }
+
+    /// Picks the level to compact into the next one, given the number of level 0 tables and
+    /// the sizes of the levels from 1 on: the level furthest over its budget, or over the
+    /// trigger for level 0. Returns `None` if none is over. The last level is never picked.
+    pub fn pick_level(&self, l0_tables: usize, level_sizes: &[u64]) -> Option<usize> {
+        let l0_score = l0_tables as f64 / self.l0_compaction_trigger.max(1) as f64;
+        let scores = level_sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| (i + 1, size as f64 / self.level_budget(i + 1).max(1) as f64));
+        std::iter::once((0, l0_score))
+            .chain(scores)
+            .filter(|&(level, score)| level + 1 < self.max_levels && score >= 1.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(level, _)| level)
+    }
+
+    /// Picks the table of `level` to merge into `next`, the tables of the next level: the one
+    /// overlapping the fewest bytes of `next` for its own size, so compacting it rewrites the least.
+    pub fn pick_table(&self, level: &[Arc<SsTable>], next: &[Arc<SsTable>]) -> Option<usize> {
+        let ratio = |table: &SsTable| {
+            let range = KeyRange::from(table);
+            let overlapping: u64 = next
+                .iter()
+                .map(Arc::as_ref)
+                .filter(|other| range.overlaps(&KeyRange::from(*other)))
+                .map(SsTable::file_size)
+                .sum();
+            overlapping as f64 / table.file_size().max(1) as f64
+        };
+        (0..level.len()).min_by(|&a, &b| ratio(&level[a]).total_cmp(&ratio(&level[b])))
+    }
+}
+
+/// How the SSTables of a database are compacted, size-tiered by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionStrategy {
+    SizeTiered(SizeTieredOptions),
+    Leveled(LeveledOptions),
+}
+
+impl Default for CompactionStrategy {
+    fn default() -> Self {
+        CompactionStrategy::SizeTiered(SizeTieredOptions::default())
+    }
+}
+
+impl From<SizeTieredOptions> for CompactionStrategy {
+    fn from(options: SizeTieredOptions) -> Self {
+        CompactionStrategy::SizeTiered(options)
+    }
+}
+
+impl From<LeveledOptions> for CompactionStrategy {
+    fn from(options: LeveledOptions) -> Self {
+        CompactionStrategy::Leveled(options)
+    }
+}
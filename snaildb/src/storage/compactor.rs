@@ -0,0 +1,131 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use crate::storage::sstable::{MergeOptions, SsTable};
+use crate::worker::handler::WorkerManager;
+
+/// A merge of SSTables run by the compaction thread, see `Compactor`. The inputs are opened
+/// from their files, which the database keeps until it installs the outputs.
+#[derive(Debug)]
+pub(crate) struct CompactionJob {
+    /// the paths of the tables to merge, oldest first
+    pub(crate) inputs: Vec<PathBuf>,
+    /// the path of the output, named after it with a number if `split`, see `SsTable::merge_split`
+    pub(crate) output: PathBuf,
+    pub(crate) split: bool,
+    pub(crate) options: MergeOptions,
+}
+
+impl CompactionJob {
+    /// Opens the inputs and merges them, returning the outputs, none if nothing survives.
+    pub(crate) fn run(&self) -> io::Result<Vec<SsTable>> {
+        let inputs = self.inputs.iter().map(SsTable::open).collect::<io::Result<Vec<_>>>()?;
+        if self.split {
+            SsTable::merge_split(&self.output, &inputs, &self.options)
+        } else {
+            Ok(SsTable::merge_with_options(&self.output, &inputs, &self.options)?.into_iter().collect())
+        }
+    }
+}
+
+/// A job the compaction thread finished, with its outputs or the error that failed it.
+#[derive(Debug)]
+pub(crate) struct CompletedCompaction {
+    pub(crate) job: CompactionJob,
+    pub(crate) outputs: io::Result<Vec<SsTable>>,
+}
+
+/// Commands sent to the compaction thread.
+#[derive(Debug)]
+pub(crate) enum CompactionCommand {
+    /// Runs a job and sends it back completed.
+    Run(Box<CompactionJob>),
+    /// Stops the thread, then sends back once it is stopped.
+    Shutdown { done: mpsc::Sender<()> },
+}
+
+/// The thread a database runs its compactions on, so writes do not wait for a merge. Jobs run
+/// one after the other and come back completed in the order they were submitted. Installing
+/// the outputs is left to the database, the only one to change its tables and manifest.
+#[derive(Debug)]
+pub(crate) struct Compactor {
+    worker: WorkerManager<CompactionCommand>,
+    completed: mpsc::Receiver<CompletedCompaction>,
+    /// Set once the compactor is dropped, so the thread skips the jobs it has not started.
+    stopping: Arc<AtomicBool>,
+}
+
+impl Compactor {
+    /// Spawns the compaction thread.
+    pub(crate) fn spawn() -> Self {
+        let (done, completed) = mpsc::channel();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopping);
+        let worker = WorkerManager::spawn(move |receiver, _| compaction_handler(receiver, done, stop), Duration::ZERO);
+        Self { worker, completed, stopping }
+    }
+
+    /// Hands `job` to the compaction thread.
+    pub(crate) fn submit(&self, job: CompactionJob) -> io::Result<()> {
+        self.worker
+            .send(CompactionCommand::Run(Box::new(job)))
+            .map_err(|e| io::Error::other(format!("compaction submit error: {}", e)))
+    }
+
+    /// Returns the next job the thread finished, if it is finished, without waiting.
+    pub(crate) fn try_completed(&self) -> Option<CompletedCompaction> {
+        self.completed.try_recv().ok()
+    }
+
+    /// Waits for the thread to finish the next job and returns it.
+    pub(crate) fn wait_completed(&self) -> io::Result<CompletedCompaction> {
+        self.completed.recv().map_err(|e| io::Error::other(format!("compaction thread error: {}", e)))
+    }
+
+    /// Waits at most `timeout` for the thread to finish the next job, and returns it if it did.
+    pub(crate) fn wait_completed_timeout(&self, timeout: Duration) -> io::Result<Option<CompletedCompaction>> {
+        match self.completed.recv_timeout(timeout) {
+            Ok(completed) => Ok(Some(completed)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(e) => Err(io::Error::other(format!("compaction thread error: {}", e))),
+        }
+    }
+}
+
+impl Drop for Compactor {
+    /// Stops the compaction thread once the merge it runs, if any, is done, and waits for it.
+    /// The jobs it has not started are dropped. Outputs that were never installed are not in
+    /// the manifest, so the next `open` removes them.
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let (done, stopped) = mpsc::channel();
+        if self.worker.send(CompactionCommand::Shutdown { done }).is_ok() {
+            let _ = stopped.recv();
+        }
+    }
+}
+
+/// The compaction thread handler: runs the jobs it receives until it is shut down.
+fn compaction_handler(
+    receiver: mpsc::Receiver<CompactionCommand>,
+    completed: mpsc::Sender<CompletedCompaction>,
+    stopping: Arc<AtomicBool>,
+) {
+    while let Ok(command) = receiver.recv() {
+        match command {
+            CompactionCommand::Run(_) if stopping.load(Ordering::SeqCst) => {}
+            CompactionCommand::Run(job) => {
+                let outputs = job.run();
+                // The database may be gone, waiting for the shutdown queued behind the job
+                let _ = completed.send(CompletedCompaction { job: *job, outputs });
+            }
+            CompactionCommand::Shutdown { done } => {
+                let _ = done.send(());
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,368 @@
+//! The manifest of a database: the log of the changes to its live set of SSTables, so that
+//! after a crash the live tables are known apart from the outputs of an unfinished flush or
+//! compaction and from the inputs of a finished one.
+//!
+//! Each change is a batch of `VersionEdit`s appended to a `MANIFEST-<number>` file with the
+//! record framing of the WAL, see `encode_write_batch`, and synced before the change takes
+//! effect. Replaying the batches from the last snapshot gives the live set. The manifest in
+//! use is named by the `CURRENT` file, which is switched by a rename once a new manifest
+//! holding a snapshot of the live set is written, on open and after `ROTATE_EDITS` batches.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::storage::SsTable;
+use crate::utils::record::{RecordKind, encode_write_batch, read_batch};
+use crate::wal::segment::sync_dir;
+
+/// Name of the file naming the manifest in use.
+const CURRENT_FILE: &str = "CURRENT";
+
+/// Prefix of the manifest file names, followed by their number.
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+
+/// Number of batches after which the manifest is rewritten as a snapshot of the live set.
+const ROTATE_EDITS: usize = 1024;
+
+/// Tags of the edits, the first byte of the key of their record.
+const ADD_FILE_TAG: u8 = b'A';
+const REMOVE_FILE_TAG: u8 = b'R';
+const SNAPSHOT_TAG: u8 = b'S';
+const LAST_SEQNO_TAG: u8 = b'N';
+const ADD_FAMILY_TAG: u8 = b'F';
+const DROP_FAMILY_TAG: u8 = b'D';
+const NEXT_FAMILY_TAG: u8 = b'I';
+
+/// A live table as the manifest records it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TableFile {
+    /// 0 for the flushed tables, from 1 on for the levels of leveled compaction
+    pub(crate) level: usize,
+    /// the file name of the table in the data directory
+    pub(crate) name: String,
+    pub(crate) min_key: Vec<u8>,
+    pub(crate) max_key: Vec<u8>,
+    pub(crate) min_seqno: u64,
+    pub(crate) max_seqno: u64,
+    /// the id of the column family of the table, 0 for the default one
+    pub(crate) family: u32,
+}
+
+impl TableFile {
+    /// Describes `table`, in `level`. The seqnos of its range tombstones count.
+    pub(crate) fn of(level: usize, table: &SsTable) -> Self {
+        let (min_key, max_key) = table.key_range();
+        let tombstones = table.range_tombstones().iter().map(|tombstone| tombstone.seqno);
+        Self {
+            level,
+            name: file_name(table.path()),
+            min_key: min_key.to_vec(),
+            max_key: max_key.to_vec(),
+            min_seqno: table.stats().min_seqno,
+            max_seqno: tombstones.fold(table.stats().max_seqno, u64::max),
+            family: 0,
+        }
+    }
+
+    /// Puts the table in the column family `family`.
+    pub(crate) fn in_family(mut self, family: u32) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Encodes the fields but the name: `[level:u32][min_key_len:u32][min_key][max_key_len:u32]
+    /// [max_key][min_seqno:u64][max_seqno:u64][family:u32]`, little endian. The family is left
+    /// out for the default one, so those entries read as before column families.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(32 + self.min_key.len() + self.max_key.len());
+        bytes.extend_from_slice(&(self.level as u32).to_le_bytes());
+        for key in [&self.min_key, &self.max_key] {
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key);
+        }
+        bytes.extend_from_slice(&self.min_seqno.to_le_bytes());
+        bytes.extend_from_slice(&self.max_seqno.to_le_bytes());
+        if self.family != 0 {
+            bytes.extend_from_slice(&self.family.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn decode(name: String, bytes: &[u8]) -> io::Result<Self> {
+        let mut rest = bytes;
+        let short = || invalid(format!("manifest entry of table {name} cut short"));
+        let level = take_u32(&mut rest).ok_or_else(short)? as usize;
+        let mut keys = [Vec::new(), Vec::new()];
+        for key in &mut keys {
+            let len = take_u32(&mut rest).ok_or_else(short)? as usize;
+            *key = take(&mut rest, len).ok_or_else(short)?.to_vec();
+        }
+        let mut seqnos = [0u64; 2];
+        for seqno in &mut seqnos {
+            *seqno = u64::from_le_bytes(take(&mut rest, 8).ok_or_else(short)?.try_into().expect("8 bytes"));
+        }
+        let [min_key, max_key] = keys;
+        let [min_seqno, max_seqno] = seqnos;
+        let family = if rest.is_empty() { 0 } else { take_u32(&mut rest).ok_or_else(short)? };
+        Ok(Self { level, name, min_key, max_key, min_seqno, max_seqno, family })
+    }
+}
+
+/// Splits `len` bytes off the front of `rest`, `None` if it is shorter.
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (taken, tail) = rest.split_at(len);
+    *rest = tail;
+    Some(taken)
+}
+
+fn take_u32(rest: &mut &[u8]) -> Option<u32> {
+    take(rest, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().expect("4 bytes")))
+}
+
+/// A change to the live set of tables.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum VersionEdit {
+    /// Adds a table, or replaces the one of the same name
+    AddFile(TableFile),
+    RemoveFile { name: String },
+    /// Empties the live set, the `AddFile` edits after it list it from scratch
+    Snapshot,
+    /// Raises the sequence number of the last write the tables may hold, which a compaction
+    /// that drops records does not lower
+    LastSeqno(u64),
+    /// Creates the column family `id`, see `SnailDb::create_cf`
+    AddFamily { id: u32, name: String },
+    /// Drops the column family `id`, whose tables are removed by the same batch
+    DropFamily { id: u32 },
+    /// Raises the id the next column family takes, so the ids of dropped families, which the
+    /// WAL may still hold records of, are not taken again
+    NextFamilyId(u32),
+}
+
+impl VersionEdit {
+    /// Returns the key and value of the record of the edit.
+    fn to_record(&self) -> (Vec<u8>, Vec<u8>) {
+        let key = |tag: u8, name: &str| [&[tag], name.as_bytes()].concat();
+        match self {
+            VersionEdit::AddFile(file) => (key(ADD_FILE_TAG, &file.name), file.encode()),
+            VersionEdit::RemoveFile { name } => (key(REMOVE_FILE_TAG, name), Vec::new()),
+            VersionEdit::Snapshot => (vec![SNAPSHOT_TAG], Vec::new()),
+            VersionEdit::LastSeqno(seqno) => (vec![LAST_SEQNO_TAG], seqno.to_le_bytes().to_vec()),
+            VersionEdit::AddFamily { id, name } => (key(ADD_FAMILY_TAG, name), id.to_le_bytes().to_vec()),
+            VersionEdit::DropFamily { id } => (vec![DROP_FAMILY_TAG], id.to_le_bytes().to_vec()),
+            VersionEdit::NextFamilyId(id) => (vec![NEXT_FAMILY_TAG], id.to_le_bytes().to_vec()),
+        }
+    }
+
+    fn from_record(key: &[u8], value: &[u8]) -> io::Result<Self> {
+        let (&tag, name) = key.split_first().ok_or_else(|| invalid("empty manifest edit".to_string()))?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| invalid("table name is not UTF-8".to_string()))?;
+        match tag {
+            ADD_FILE_TAG => Ok(VersionEdit::AddFile(TableFile::decode(name, value)?)),
+            REMOVE_FILE_TAG => Ok(VersionEdit::RemoveFile { name }),
+            SNAPSHOT_TAG => Ok(VersionEdit::Snapshot),
+            LAST_SEQNO_TAG => {
+                let seqno = value.try_into().map_err(|_| invalid("last seqno is not 8 bytes".to_string()))?;
+                Ok(VersionEdit::LastSeqno(u64::from_le_bytes(seqno)))
+            }
+            ADD_FAMILY_TAG | DROP_FAMILY_TAG | NEXT_FAMILY_TAG => {
+                let id = value.try_into().map_err(|_| invalid("column family id is not 4 bytes".to_string()))?;
+                let id = u32::from_le_bytes(id);
+                Ok(match tag {
+                    ADD_FAMILY_TAG => VersionEdit::AddFamily { id, name },
+                    DROP_FAMILY_TAG => VersionEdit::DropFamily { id },
+                    _ => VersionEdit::NextFamilyId(id),
+                })
+            }
+            _ => Err(invalid(format!("unknown manifest edit tag {tag}"))),
+        }
+    }
+}
+
+/// The live set of tables, by file name.
+pub(crate) type LiveFiles = BTreeMap<String, TableFile>;
+
+/// The state the edits of a manifest add up to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Version {
+    pub(crate) files: LiveFiles,
+    /// the sequence number of the last write the tables may hold, see `VersionEdit::LastSeqno`
+    pub(crate) last_seqno: u64,
+    /// the names of the column families but the default one, by id
+    pub(crate) families: BTreeMap<u32, String>,
+    /// the id the next column family takes, past those of every family created so far
+    pub(crate) next_family_id: u32,
+}
+
+impl Version {
+    fn apply(&mut self, edit: VersionEdit) {
+        match edit {
+            VersionEdit::AddFile(file) => {
+                self.files.insert(file.name.clone(), file);
+            }
+            VersionEdit::RemoveFile { name } => {
+                self.files.remove(&name);
+            }
+            VersionEdit::Snapshot => *self = Version::default(),
+            VersionEdit::LastSeqno(seqno) => self.last_seqno = self.last_seqno.max(seqno),
+            VersionEdit::AddFamily { id, name } => {
+                self.families.insert(id, name);
+                self.next_family_id = self.next_family_id.max(id + 1);
+            }
+            VersionEdit::DropFamily { id } => {
+                self.families.remove(&id);
+            }
+            VersionEdit::NextFamilyId(id) => self.next_family_id = self.next_family_id.max(id),
+        }
+    }
+}
+
+/// The manifest in use, appended to by `commit`.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    dir: PathBuf,
+    number: u64,
+    file: File,
+    version: Version,
+    /// batches appended since the snapshot the manifest starts with
+    edits: usize,
+    /// whether a commit failed, possibly leaving part of its batch in the file
+    poisoned: bool,
+}
+
+impl Manifest {
+    /// Replays the manifest `CURRENT` names in `dir` and returns its number and its version,
+    /// or `None` if there is no `CURRENT`, as in a new database or one written before
+    /// manifests. A batch cut short at the end of the manifest, by a crash while it was
+    /// appended, was never committed and is ignored. A damaged batch fails with `InvalidData`.
+    pub(crate) fn recover(dir: &Path) -> io::Result<Option<(u64, Version)>> {
+        let current = match fs::read_to_string(dir.join(CURRENT_FILE)) {
+            Ok(current) => current,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let name = current.trim_end();
+        let number = manifest_number(name).ok_or_else(|| invalid(format!("CURRENT names {name:?}")))?;
+        let mut reader = BufReader::new(File::open(dir.join(name))?);
+        let mut version = Version::default();
+        loop {
+            let batch = match read_batch(&mut reader) {
+                Ok(Some(batch)) => batch,
+                Ok(None) => break,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            for record in batch {
+                version.apply(VersionEdit::from_record(&record.key, &record.value)?);
+            }
+        }
+        Ok(Some((number, version)))
+    }
+
+    /// Writes manifest `number` holding a snapshot of `version`, switches `CURRENT` to it and
+    /// removes the other manifests. A crash before the switch leaves the former manifest in
+    /// use, and the new one is removed by the next `create`.
+    pub(crate) fn create(dir: &Path, number: u64, version: Version) -> io::Result<Self> {
+        let name = format!("{MANIFEST_PREFIX}{number:06}");
+        let path = dir.join(&name);
+        let mut edits = vec![VersionEdit::Snapshot, VersionEdit::LastSeqno(version.last_seqno)];
+        // Left out without column families, so the manifest reads as before them
+        if version.next_family_id > 0 {
+            edits.push(VersionEdit::NextFamilyId(version.next_family_id));
+        }
+        let families = version.families.iter().map(|(&id, name)| VersionEdit::AddFamily { id, name: name.clone() });
+        edits.extend(families);
+        edits.extend(version.files.values().cloned().map(VersionEdit::AddFile));
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&path)?;
+        file.write_all(&encode_edits(&edits)?)?;
+        file.sync_all()?;
+
+        let temp_path = dir.join(format!("{CURRENT_FILE}.tmp"));
+        let mut current = File::create(&temp_path)?;
+        current.write_all(format!("{name}\n").as_bytes())?;
+        current.sync_all()?;
+        fs::rename(&temp_path, dir.join(CURRENT_FILE))?;
+        sync_dir(dir)?;
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let other = entry.file_name().to_string_lossy().into_owned();
+            if manifest_number(&other).is_some_and(|other| other != number) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        sync_dir(dir)?;
+        Ok(Self { dir: dir.to_path_buf(), number, file, version, edits: 0, poisoned: false })
+    }
+
+    /// Writes a manifest holding a snapshot of the live set to `dir`, named by a `CURRENT` of
+    /// its own, for a copy of the database, see `SnailDb::checkpoint`.
+    pub(crate) fn snapshot_to(&self, dir: &Path) -> io::Result<()> {
+        Self::create(dir, self.number, self.version.clone()).map(drop)
+    }
+
+    /// Returns the live set of tables.
+    pub(crate) fn files(&self) -> &LiveFiles {
+        &self.version.files
+    }
+
+    /// Appends `edits` as one batch and syncs it, then applies them to the live set. The
+    /// manifest is rotated once it holds `ROTATE_EDITS` batches.
+    ///
+    /// A failed commit applies none of `edits`, but may leave part of its batch in the file,
+    /// which a batch appended after it would follow into the middle of the manifest, failing
+    /// `recover`. The next commit rotates the manifest first instead.
+    pub(crate) fn commit(&mut self, edits: Vec<VersionEdit>) -> io::Result<()> {
+        if self.poisoned {
+            *self = Self::create(&self.dir, self.number + 1, self.version.clone())?;
+        }
+        let batch = encode_edits(&edits)?;
+        // Until the batch is synced whole
+        self.poisoned = true;
+        self.file.write_all(&batch)?;
+        self.file.sync_data()?;
+        self.poisoned = false;
+        for edit in edits {
+            self.version.apply(edit);
+        }
+        self.edits += 1;
+        if self.edits >= ROTATE_EDITS {
+            *self = Self::create(&self.dir, self.number + 1, std::mem::take(&mut self.version))?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes `edits` as one batch of records.
+fn encode_edits(edits: &[VersionEdit]) -> io::Result<Vec<u8>> {
+    let records: Vec<(Vec<u8>, Vec<u8>)> = edits.iter().map(VersionEdit::to_record).collect();
+    let mut buffer = Vec::new();
+    let records = records.iter().map(|(key, value)| (RecordKind::Set, key.as_slice(), value.as_slice()));
+    encode_write_batch(&mut buffer, records)?;
+    Ok(buffer)
+}
+
+/// Returns true if `name` is a file of the manifest: `CURRENT`, its temporary file or a
+/// manifest, see `SnailDb::destroy`.
+pub(crate) fn is_manifest_file(name: &str) -> bool {
+    name == CURRENT_FILE || name == format!("{CURRENT_FILE}.tmp") || manifest_number(name).is_some()
+}
+
+/// Returns the number of the manifest file `name`, `None` if it is not one.
+fn manifest_number(name: &str) -> Option<u64> {
+    name.strip_prefix(MANIFEST_PREFIX)?.parse().ok()
+}
+
+/// Returns the file name in `path`.
+pub(crate) fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -1,40 +1,84 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 use crossbeam_skiplist::SkipMap;
 
-use crate::utils::value::Value;
+use crate::storage::snapshot::SnapshotList;
+use crate::storage::sstable::{BytewiseComparator, RangeTombstone, system_clock};
+use crate::utils::value::{MergeOperator, Value};
 
 #[derive(Debug)]
 pub struct MemTable {
-    entries: SkipMap<String, Value>,
+    /// The value of each key with the sequence number of the write that set it, 0 for none.
+    entries: SkipMap<String, (Value, u64)>,
+    /// Range deletes written since the last flush. The entries they hold were removed when
+    /// they were written, so they only hide the keys of older tables.
+    range_tombstones: RefCell<Vec<RangeTombstone>>,
+    /// The versions replaced or range deleted while a snapshot reads them, newest first for
+    /// each key, see `version_at`.
+    history: RefCell<BTreeMap<(String, Reverse<u64>), Value>>,
+    /// For the versions holding merge operands stacked onto a version of the history, the
+    /// operands written since that version, which a flush writes instead, see `versions`.
+    deltas: RefCell<BTreeMap<(String, Reverse<u64>), Value>>,
+    /// When the versions written with a TTL expire, in milliseconds since the UNIX epoch, see
+    /// `insert_with_expiry`.
+    expiries: RefCell<BTreeMap<(String, Reverse<u64>), u64>>,
+    /// The time expiries are compared with, see `with_clock`.
+    clock: fn() -> u64,
+    snapshots: Arc<SnapshotList>,
     size_bytes: Cell<usize>,
 }
 
 impl MemTable {
     pub fn new() -> Self {
+        Self::with_snapshots(Arc::default())
+    }
+
+    /// Creates a memtable keeping the versions the snapshots of `snapshots` read.
+    pub fn with_snapshots(snapshots: Arc<SnapshotList>) -> Self {
         Self {
             entries: SkipMap::new(),
+            range_tombstones: RefCell::new(Vec::new()),
+            history: RefCell::new(BTreeMap::new()),
+            deltas: RefCell::new(BTreeMap::new()),
+            expiries: RefCell::new(BTreeMap::new()),
+            clock: system_clock,
+            snapshots,
             size_bytes: Cell::new(0),
         }
     }
 
+    /// Makes reads compare the expiries of the entries with `now` instead of the system clock,
+    /// like `SsTable::with_clock`.
+    pub fn with_clock(mut self, now: fn() -> u64) -> Self {
+        self.clock = now;
+        self
+    }
+
     pub fn insert(&self, key: String, value: Value) {
+        self.insert_with_seqno(key, value, 0);
+    }
+
+    /// Inserts `value` for `key` like `insert`, written with sequence number `seqno`.
+    pub fn insert_with_seqno(&self, key: String, value: Value, seqno: u64) {
         // Calculate size: key length + value size + overhead
         let key_size = key.len();
-        let value_size = match &value {
-            crate::utils::value::Value::Present(bytes) => bytes.len(),
-            crate::utils::value::Value::Deleted => 0, // Tombstone has no value bytes
-        };
+        let value_size = value.byte_len(); // Tombstone has no value bytes
         // Approximate overhead: 8 bytes for String pointer + 8 bytes for Vec pointer + 24 bytes for Value enum
         let new_entry_size = key_size + value_size + 40;
         
         // Calculate the size delta: if replacing, calculate net change; if new, use full size
         let size_delta = if let Some(old_entry) = self.entries.get(&key) {
+            let (old_value, old_seqno) = old_entry.value();
+            if self.keep_version(&key, old_value, *old_seqno, seqno) {
+                return self.add_entry(key, value, seqno, new_entry_size as i64);
+            }
+            self.forget_version(&key, *old_seqno);
             // Updating existing entry: calculate net change (new - old)
-            let old_value = old_entry.value();
-            let old_value_size = match old_value {
-                crate::utils::value::Value::Present(bytes) => bytes.len(),
-                crate::utils::value::Value::Deleted => 0,
-            };
+            let old_value_size = old_value.byte_len();
             let old_entry_size = key_size + old_value_size + 40;
             new_entry_size as i64 - old_entry_size as i64
         } else {
@@ -42,25 +86,267 @@ impl MemTable {
             new_entry_size as i64
         };
         
+        self.add_entry(key, value, seqno, size_delta);
+    }
+
+    /// Inserts `value` for `key` like `insert_with_seqno`, expiring at `expires_at`, in
+    /// milliseconds since the UNIX epoch. Once the clock reaches it, reads return the entry as a
+    /// tombstone, which still hides the key in older tables.
+    pub fn insert_with_expiry(&self, key: String, value: Value, seqno: u64, expires_at: u64) {
+        self.insert_with_seqno(key.clone(), value, seqno);
+        self.expiries.borrow_mut().insert((key, Reverse(seqno)), expires_at);
+    }
+
+    fn add_entry(&self, key: String, value: Value, seqno: u64, size_delta: i64) {
         // SkipMap::insert takes &self, so we can use &self here
-        self.entries.insert(key, value);
+        self.entries.insert(key, (value, seqno));
         
         // Apply the size delta in one operation
         let current_size = self.size_bytes.get() as i64;
         self.size_bytes.set((current_size + size_delta).max(0) as usize);
     }
 
+    /// Moves the version of `key` written at `seqno` and replaced at `replaced_at` to the
+    /// history if a snapshot reads it. Returns true if it did, its size then still counts.
+    fn keep_version(&self, key: &str, value: &Value, seqno: u64, replaced_at: u64) -> bool {
+        if !self.snapshots.pins(seqno, replaced_at) {
+            return false;
+        }
+        self.history.borrow_mut().insert((key.to_string(), Reverse(seqno)), value.clone());
+        true
+    }
+
+    /// Drops what is kept alongside the version of `key` written at `seqno` once it is gone.
+    fn forget_version(&self, key: &str, seqno: u64) {
+        self.deltas.borrow_mut().remove(&(key.to_string(), Reverse(seqno)));
+        self.expiries.borrow_mut().remove(&(key.to_string(), Reverse(seqno)));
+    }
+
+    /// Returns when the version of `key` written at `seqno` expires, if it was written with a TTL.
+    pub fn expires_at(&self, key: &str, seqno: u64) -> Option<u64> {
+        let expiries = self.expiries.borrow();
+        if expiries.is_empty() {
+            return None;
+        }
+        expiries.get(&(key.to_string(), Reverse(seqno))).copied()
+    }
+
+    /// Returns the version of `key` written at `seqno` as read at `now`: a tombstone once it expired.
+    fn value_at(&self, key: &str, value: &Value, seqno: u64, now: u64) -> Value {
+        match self.expires_at(key, seqno) {
+            Some(expires_at) if expires_at <= now => Value::Deleted,
+            _ => value.clone(),
+        }
+    }
+
+    /// Deletes `key`: inserts a tombstone, which hides the key in older tables.
+    pub fn delete(&self, key: String) {
+        self.delete_with_seqno(key, 0);
+    }
+
+    /// Deletes `key` like `delete`, written with sequence number `seqno`.
+    pub fn delete_with_seqno(&self, key: String, seqno: u64) {
+        self.insert_with_seqno(key, Value::tombstone(), seqno);
+    }
+
+    /// Inserts merge operands for `key`, stacked onto the value the memtable holds for it, see
+    /// `Value::stack_onto`. Without a value they are kept as they are, to be stacked onto the
+    /// value of an SSTable when read.
+    pub fn merge(&self, key: String, operands: Value, merge: Option<&dyn MergeOperator>) -> io::Result<()> {
+        self.merge_with_seqno(key, operands, merge, 0)
+    }
+
+    /// Inserts merge operands for `key` like `merge`, written with sequence number `seqno`,
+    /// which the stacked value takes.
+    pub fn merge_with_seqno(
+        &self,
+        key: String,
+        operands: Value,
+        merge: Option<&dyn MergeOperator>,
+        seqno: u64,
+    ) -> io::Result<()> {
+        // An expired value is stacked onto as a tombstone, and a value stacked onto doesn't expire
+        let now = (self.clock)();
+        let older = self.entries.get(&key).map(|entry| {
+            let (value, seqno) = entry.value();
+            (self.value_at(&key, value, *seqno, now), *seqno)
+        });
+        let value = match older.clone() {
+            Some((older, _)) => operands.clone().stack_onto(key.as_bytes(), older, merge)?,
+            None if self.is_range_deleted(&key) => operands.clone().resolve(key.as_bytes(), merge)?,
+            None => operands.clone(),
+        };
+        // Operands stacked onto a version kept for a snapshot are flushed on their own
+        let delta = match older {
+            Some((_, older_seqno)) if matches!(value, Value::Merge(_)) => {
+                if self.snapshots.pins(older_seqno, seqno) {
+                    Some(operands)
+                } else {
+                    let older_delta = self.deltas.borrow().get(&(key.clone(), Reverse(older_seqno))).cloned();
+                    older_delta.map(|older| operands.stack_onto(key.as_bytes(), older, merge)).transpose()?
+                }
+            }
+            _ => None,
+        };
+        self.insert_with_seqno(key.clone(), value, seqno);
+        if let Some(delta) = delta {
+            self.deltas.borrow_mut().insert((key, Reverse(seqno)), delta);
+        }
+        Ok(())
+    }
+
+    /// Deletes the keys from `start`, included, to `end`, excluded: removes their entries and
+    /// keeps a range tombstone to hide them in older tables. An empty range deletes nothing.
+    pub fn delete_range(&self, start: &str, end: &str) {
+        self.delete_range_with_seqno(start, end, 0);
+    }
+
+    /// Deletes the keys from `start` to `end` like `delete_range`, written with sequence
+    /// number `seqno`, which the range tombstone takes.
+    pub fn delete_range_with_seqno(&self, start: &str, end: &str, seqno: u64) {
+        if end <= start {
+            return;
+        }
+        let mut removed = 0;
+        for entry in self.entries.range::<str, _>((Bound::Included(start), Bound::Excluded(end))) {
+            let (value, version) = entry.value();
+            if !self.keep_version(entry.key(), value, *version, seqno) {
+                removed += entry.key().len() + value.byte_len() + 40;
+                self.forget_version(entry.key(), *version);
+            }
+            entry.remove();
+        }
+        let added = start.len() + end.len() + 40;
+        self.size_bytes.set((self.size_bytes.get() + added).saturating_sub(removed));
+        self.range_tombstones.borrow_mut().push(RangeTombstone::new(start, end, seqno));
+    }
+
+    /// Returns true if a range delete written since the last flush holds `key`.
+    pub fn is_range_deleted(&self, key: &str) -> bool {
+        self.range_tombstones
+            .borrow()
+            .iter()
+            .any(|tombstone| tombstone.contains(key.as_bytes(), &BytewiseComparator))
+    }
+
     pub fn len(&self) -> usize {
         self.entries.len()
     }
 
+    /// Returns true if the memtable holds neither entries nor range tombstones.
     pub fn is_empty(&self) -> bool {
-        self.entries.is_empty()
+        self.entries.is_empty() && self.range_tombstones.borrow().is_empty()
     }
 
+    /// Returns the range tombstones written since the last flush, in the order they were written.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.borrow().clone()
+    }
+
+    /// Looks up `key`. An entry past its expiry reads as a tombstone, see `insert_with_expiry`.
     pub fn get(&self, key: &str) -> Option<Value> {
-        // SkipMap::get returns an EntryRef, we need to clone the value
-        self.entries.get(key).map(|entry| entry.value().clone())
+        self.get_with_expiry(key).map(|(value, _)| value)
+    }
+
+    /// Looks up `key` like `get` and also returns when its value expires, if it was written
+    /// with a TTL and has not expired yet.
+    pub fn get_with_expiry(&self, key: &str) -> Option<(Value, Option<u64>)> {
+        let entry = self.entries.get(key)?;
+        let (value, seqno) = entry.value();
+        match self.expires_at(key, *seqno) {
+            Some(expires_at) if expires_at <= (self.clock)() => Some((Value::Deleted, None)),
+            expires_at => Some((value.clone(), expires_at)),
+        }
+    }
+
+    /// Looks up `key` as of sequence number `seqno`: returns the newest version written at or
+    /// before it with its sequence number, or a tombstone with that of the newest range delete
+    /// written at or before it holding the key, if that is newer. `None` if there is neither.
+    /// A version past its expiry reads as a tombstone.
+    pub fn version_at(&self, key: &str, seqno: u64) -> Option<(Value, u64)> {
+        let now = (self.clock)();
+        let newest = self.entries.get(key).map(|entry| entry.value().clone());
+        let newest = newest.filter(|(_, version)| *version <= seqno);
+        let found = newest.or_else(|| {
+            let history = self.history.borrow();
+            let mut older = history.range((key.to_string(), Reverse(seqno))..);
+            older.next().filter(|((other, _), _)| other == key).map(|((_, Reverse(version)), value)| {
+                (value.clone(), *version)
+            })
+        });
+        let found = found.map(|(value, version)| (self.value_at(key, &value, version, now), version));
+        let deleted = self
+            .range_tombstones
+            .borrow()
+            .iter()
+            .filter(|tombstone| tombstone.seqno <= seqno)
+            .filter(|tombstone| tombstone.contains(key.as_bytes(), &BytewiseComparator))
+            .map(|tombstone| tombstone.seqno)
+            .max();
+        match (deleted, found) {
+            (Some(deleted), None) => Some((Value::Deleted, deleted)),
+            (Some(deleted), Some((_, version))) if deleted > version => Some((Value::Deleted, deleted)),
+            (_, found) => found,
+        }
+    }
+
+    /// Returns true if versions replaced or range deleted are kept for a snapshot.
+    pub fn has_history(&self) -> bool {
+        !self.history.borrow().is_empty()
+    }
+
+    /// Returns every version the memtable holds in key order, newest first for each key: the
+    /// entries, and the versions kept for snapshots, with their sequence numbers. The merge
+    /// operands of a version stacked onto the next one are left out of it, so each version
+    /// reads as it did once stacked onto those after it, like the tables a flush writes.
+    pub fn versions(&self) -> Vec<(String, Value, u64)> {
+        let history = self.history.borrow();
+        let deltas = self.deltas.borrow();
+        let mut versions: Vec<_> = self.iter().collect();
+        versions.extend(history.iter().map(|((key, Reverse(seqno)), value)| (key.clone(), value.clone(), *seqno)));
+        versions.sort_by(|a, b| a.0.cmp(&b.0).then(b.2.cmp(&a.2)));
+        for (key, value, seqno) in &mut versions {
+            if let Some(delta) = deltas.get(&(key.clone(), Reverse(*seqno))) {
+                *value = delta.clone();
+            }
+        }
+        versions
+    }
+
+    /// Returns the entries in key order with their sequence numbers, tombstones and merge
+    /// operands included, ignoring the clock: expired entries keep their values, see `expires_at`.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Value, u64)> + '_ {
+        self.entries.iter().map(|entry| {
+            let (value, seqno) = entry.value();
+            (entry.key().clone(), value.clone(), *seqno)
+        })
+    }
+
+    /// Returns the entries whose keys fall inside the bounds in key order, like `iter`. It is
+    /// double ended, so `range(..).rev()` walks them from the largest key downward. Entries that
+    /// expired by the time it was created are returned as tombstones, like `get` does.
+    pub fn range(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> impl DoubleEndedIterator<Item = (String, Value, u64)> + '_ {
+        let now = (self.clock)();
+        self.entries.range::<String, _>((start, end)).map(move |entry| {
+            let (value, seqno) = entry.value();
+            (entry.key().clone(), self.value_at(entry.key(), value, *seqno, now), *seqno)
+        })
+    }
+
+    /// Returns the keys inside the bounds in key order like `range`, as of sequence number
+    /// `seqno`: each with its version read by `version_at`, the keys it finds none for left out.
+    pub fn range_at(&self, start: Bound<String>, end: Bound<String>, seqno: u64) -> Vec<(String, Value, u64)> {
+        let mut keys: BTreeSet<String> =
+            self.entries.range::<String, _>((start.clone(), end.clone())).map(|entry| entry.key().clone()).collect();
+        let bounds = (start, end);
+        keys.extend(self.history.borrow().keys().map(|(key, _)| key).filter(|key| bounds.contains(*key)).cloned());
+        keys.into_iter()
+            .filter_map(|key| self.version_at(&key, seqno).map(|(value, version)| (key, value, version)))
+            .collect()
     }
 
     pub fn drain_sorted(&self) -> Vec<(String, Value)> {
@@ -69,10 +355,14 @@ impl MemTable {
         // Note: crossbeam-skiplist uses epoch-based reclamation, so we need to collect
         // all entries first before clearing
         for entry in self.entries.iter() {
-            drained.push((entry.key().clone(), entry.value().clone()));
+            drained.push((entry.key().clone(), entry.value().0.clone()));
         }
-        // Clear all entries after collecting
+        // Clear all entries after collecting, and the range tombstones with them
         self.entries.clear();
+        self.range_tombstones.borrow_mut().clear();
+        self.history.borrow_mut().clear();
+        self.deltas.borrow_mut().clear();
+        self.expiries.borrow_mut().clear();
         self.size_bytes.set(0);
         drained
     }
@@ -81,4 +371,19 @@ impl MemTable {
     pub fn size_bytes(&self) -> usize {
         self.size_bytes.get()
     }
+
+    /// Returns the bytes of the keys and values of the entries from `start` to `end`, tombstones
+    /// included, walked without copying them. The versions kept for snapshots are left out.
+    pub fn range_bytes(&self, start: Bound<&str>, end: Bound<&str>) -> usize {
+        self.entries
+            .range::<str, _>((start, end))
+            .map(|entry| entry.key().len() + entry.value().0.byte_len())
+            .sum()
+    }
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,7 +1,15 @@
+pub mod compaction;
+pub(crate) mod compactor;
+pub(crate) mod manifest;
 pub mod memtable;
+pub mod scan;
+pub mod snapshot;
 pub mod sstable;
 pub mod bloom_filter;
 
+pub use compaction::{CompactionStrategy, LeveledOptions, SizeTieredOptions};
 pub use memtable::MemTable;
+pub use scan::Scan;
+pub use snapshot::{Snapshot, SnapshotList};
 pub use sstable::SsTable;
 pub use bloom_filter::BloomFilter;
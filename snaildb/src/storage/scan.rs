@@ -0,0 +1,156 @@
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use std::io;
+use std::sync::Arc;
+
+use crate::storage::sstable::{BytewiseComparator, RangeTombstone};
+use crate::utils::value::{MergeOperator, Value};
+
+/// The versions one source of a `Scan` holds in the order of the scan: a memtable or an SSTable.
+pub(crate) type Versions<'a> = Box<dyn Iterator<Item = io::Result<(String, Value)>> + 'a>;
+
+/// K-way merge over the memtables and SSTables of a database, yielding the live keys of a
+/// range once each in ascending order with their value, see `SnailDb::scan`, or in descending
+/// order, see `SnailDb::scan_rev`.
+///
+/// Sources are ordered newest to oldest, and the version of a key from the newest source wins,
+/// like a read with `SnailDb::get`: merge operands are stacked onto the older versions, and a
+/// tombstone hides the key. A range tombstone of a source deletes the versions of the older
+/// sources, its own are deleted by the source itself.
+pub struct Scan<'a> {
+    sources: Vec<Versions<'a>>,
+    /// the range tombstones of the sources, with the index of their source
+    range_tombstones: Vec<(RangeTombstone, usize)>,
+    merge: Option<Arc<dyn MergeOperator>>,
+    /// set when the keys are yielded in descending order
+    reverse: bool,
+    heap: BinaryHeap<Head>,
+    /// an error hit while refilling the heap, reported on the next call
+    pending_error: Option<io::Error>,
+}
+
+/// The current head of one source. The heap is a max-heap, so the ordering is reversed to pop
+/// the smallest key first, or the largest in a reverse scan, and among equal keys the newest
+/// source first.
+struct Head {
+    key: String,
+    value: Value,
+    source: usize,
+    reverse: bool,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let keys = if self.reverse { self.key.cmp(&other.key) } else { other.key.cmp(&self.key) };
+        keys.then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl<'a> Scan<'a> {
+    /// Merges `sources`, newest first, each with its range tombstones, combining merge operands
+    /// with `merge`. The sources yield their keys in descending order if `reverse` is set.
+    pub(crate) fn new(
+        sources: Vec<(Versions<'a>, Vec<RangeTombstone>)>,
+        merge: Option<Arc<dyn MergeOperator>>,
+        reverse: bool,
+    ) -> Self {
+        let mut range_tombstones = Vec::new();
+        let mut versions = Vec::with_capacity(sources.len());
+        for (source, (source_versions, tombstones)) in sources.into_iter().enumerate() {
+            range_tombstones.extend(tombstones.into_iter().map(|tombstone| (tombstone, source)));
+            versions.push(source_versions);
+        }
+        let mut scan = Self {
+            heap: BinaryHeap::with_capacity(versions.len()),
+            sources: versions,
+            range_tombstones,
+            merge,
+            reverse,
+            pending_error: None,
+        };
+        for source in 0..scan.sources.len() {
+            scan.advance(source);
+        }
+        scan
+    }
+
+    /// Pushes the next version of `source` onto the heap, if any.
+    fn advance(&mut self, source: usize) {
+        match self.sources[source].next() {
+            Some(Ok((key, value))) => self.heap.push(Head { key, value, source, reverse: self.reverse }),
+            Some(Err(err)) if self.pending_error.is_none() => self.pending_error = Some(err),
+            Some(Err(_)) | None => {}
+        }
+    }
+
+    /// Returns the newest source with a range tombstone holding `key`, whose older sources
+    /// no longer hold it, if any.
+    fn deleted_below(&self, key: &str) -> Option<usize> {
+        self.range_tombstones
+            .iter()
+            .filter(|(tombstone, _)| tombstone.contains(key.as_bytes(), &BytewiseComparator))
+            .map(|&(_, source)| source)
+            .min()
+    }
+
+    /// Returns the value of `key` from its newest version, of `source`, skipping the older
+    /// versions and stacking merge operands onto them.
+    fn next_value(&mut self, key: &str, value: Value, source: usize) -> io::Result<Value> {
+        let deleted_below = self.deleted_below(key);
+        let read = |value: Value, source: usize| match deleted_below {
+            Some(deleted) if deleted < source => Value::Deleted,
+            _ => value,
+        };
+        let mut value = read(value, source);
+        while self.heap.peek().is_some_and(|head| head.key == key) {
+            let older = self.heap.pop().expect("peeked head");
+            self.advance(older.source);
+            if matches!(value, Value::Merge(_)) {
+                value = value.stack_onto(key.as_bytes(), read(older.value, older.source), self.merge.as_deref())?;
+            }
+        }
+        if let Some(err) = self.pending_error.take() {
+            return Err(err);
+        }
+        value.resolve(key.as_bytes(), self.merge.as_deref())
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = io::Result<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(err) = self.pending_error.take() {
+                self.heap.clear();
+                return Some(Err(err));
+            }
+            let newest = self.heap.pop()?;
+            self.advance(newest.source);
+            match self.next_value(&newest.key, newest.value, newest.source) {
+                Ok(value) => match value.as_option() {
+                    Some(bytes) => return Some(Ok((newest.key, bytes))),
+                    None => continue,
+                },
+                Err(err) => {
+                    self.heap.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
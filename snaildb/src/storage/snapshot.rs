@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The sequence numbers of the live snapshots of a database, shared with its memtables, which
+/// keep the versions a snapshot still reads, and consulted before a compaction drops versions.
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    /// the number of live snapshots at each sequence number
+    seqnos: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    /// Takes a snapshot at `seqno`, registered until it is dropped.
+    pub fn snapshot(self: &Arc<Self>, seqno: u64) -> Snapshot {
+        *self.lock().entry(seqno).or_insert(0) += 1;
+        Snapshot { seqno, list: Arc::clone(self) }
+    }
+
+    /// Returns true if a live snapshot reads a version written at `from` and replaced at `to`:
+    /// one taken at a sequence number from `from`, included, to `to`, excluded.
+    pub fn pins(&self, from: u64, to: u64) -> bool {
+        from < to && self.lock().range(from..to).next().is_some()
+    }
+
+    /// Returns true if there is no live snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn release(&self, seqno: u64) {
+        let mut seqnos = self.lock();
+        if let Some(count) = seqnos.get_mut(&seqno) {
+            *count -= 1;
+            if *count == 0 {
+                seqnos.remove(&seqno);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u64, usize>> {
+        // The map is left consistent whatever panicked while it was held
+        self.seqnos.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A view of the database as of the last write before it was taken, see `SnailDb::snapshot`:
+/// reads through it leave out the writes with a larger sequence number. The versions it reads
+/// are kept by the memtable and compactions until it is dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    seqno: u64,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    /// Returns the sequence number of the last write the snapshot sees.
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seqno);
+    }
+}
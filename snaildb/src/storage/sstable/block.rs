@@ -0,0 +1,524 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::storage::sstable::{Comparator, Entry, compression};
+use crate::utils::{
+    record::{
+        DecodedRecord, DecodedRecordRef, RecordKind, RecordRefs, ValueCompression, decode_record_ref, decode_var_u32,
+        encode_record_to_buffer, encode_var_u32,
+    },
+    value::Value,
+};
+
+/// Target size of a data block. A block is closed once it reaches this size,
+/// so a single block can be slightly larger when the last record overflows it.
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024; // 4 KiB
+
+/// Number of entries between restart points in a prefix compressed block.
+pub const RESTART_INTERVAL: usize = 16;
+
+/// Set in the block tag when the keys of the block are prefix compressed. The low bits of
+/// the tag hold the compression codec.
+pub(crate) const PREFIX_KEYS_FLAG: u8 = 0x80;
+
+/// Accumulates the records of one data block.
+///
+/// A plain block is the concatenation of the records. With prefix compression every
+/// entry is `[shared_len:varint][record]`, where the record stores only the part of the
+/// key after the prefix shared with the previous key. Every `RESTART_INTERVAL` entries a
+/// restart point stores the full key, and the block ends with the restart offsets,
+/// `[restart_offset:4]*[restart_count:4]`, so lookups can binary search those.
+pub(crate) struct BlockBuilder {
+    buffer: Vec<u8>,
+    prefix_keys: bool,
+    value_compression: Option<ValueCompression>,
+    restarts: Vec<u32>,
+    last_key: Vec<u8>,
+    entries: usize,
+}
+
+impl BlockBuilder {
+    pub(crate) fn new(prefix_keys: bool, value_compression: Option<ValueCompression>) -> Self {
+        Self {
+            buffer: Vec::with_capacity(DEFAULT_BLOCK_SIZE),
+            prefix_keys,
+            value_compression,
+            restarts: Vec::new(),
+            last_key: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    /// Appends an entry. `expires_at` is ignored for tombstones and merge operands, and only raw
+    /// bytes may expire. A `written_at` of 0 writes the entry without a timestamp.
+    pub(crate) fn add(
+        &mut self,
+        key: &[u8],
+        value: &Value,
+        expires_at: Option<u64>,
+        seqno: u64,
+        written_at: u64,
+    ) -> io::Result<()> {
+        let encoded;
+        let (kind, value, expires_at) = match (value, expires_at) {
+            (Value::Bytes(bytes), None) => (RecordKind::Set, bytes.as_slice(), None),
+            (Value::Bytes(bytes), Some(at)) => (RecordKind::SetWithTtl, bytes.as_slice(), Some(at)),
+            (Value::Deleted, _) => (RecordKind::Delete, &[][..], None),
+            (Value::Merge(list), _) => {
+                encoded = encode_operands(list)?;
+                (RecordKind::Merge, encoded.as_slice(), None)
+            }
+            (typed, None) => {
+                encoded = typed.encode()?;
+                (RecordKind::Typed, encoded.as_slice(), None)
+            }
+            (_, Some(_)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "only raw bytes values may expire"));
+            }
+        };
+        let compression = self.value_compression;
+        if !self.prefix_keys {
+            let encoded = encode_record_to_buffer(kind, key, value, expires_at, seqno, written_at, compression)?;
+            self.buffer.extend_from_slice(&encoded);
+            return Ok(());
+        }
+        let shared = if self.entries.is_multiple_of(RESTART_INTERVAL) {
+            let offset = u32::try_from(self.buffer.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "block too large"))?;
+            self.restarts.push(offset);
+            0
+        } else {
+            shared_prefix_len(&self.last_key, key)
+        };
+        self.buffer.extend_from_slice(&encode_var_u32(shared as u32));
+        let encoded = encode_record_to_buffer(kind, &key[shared..], value, expires_at, seqno, written_at, compression)?;
+        self.buffer.extend_from_slice(&encoded);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Returns the size of the entries added so far.
+    pub(crate) fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub(crate) fn prefix_keys(&self) -> bool {
+        self.prefix_keys
+    }
+
+    /// Appends the restart points, if any, and returns the raw block. The builder is
+    /// empty again afterwards, ready for the next block.
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        if self.prefix_keys {
+            for restart in &self.restarts {
+                self.buffer.extend_from_slice(&restart.to_le_bytes());
+            }
+            self.buffer.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        }
+        self.restarts.clear();
+        self.last_key.clear();
+        self.entries = 0;
+        std::mem::replace(&mut self.buffer, Vec::with_capacity(DEFAULT_BLOCK_SIZE))
+    }
+}
+
+/// Returns the length of the common prefix of `a` and `b`.
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// A decompressed prefix compressed block, see `BlockBuilder`.
+struct PrefixBlock<'a> {
+    entries: &'a [u8],
+    restarts: Vec<usize>,
+}
+
+impl<'a> PrefixBlock<'a> {
+    fn parse(payload: &'a [u8]) -> io::Result<Self> {
+        let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "prefix compressed block is corrupt");
+        let count_pos = payload.len().checked_sub(4).ok_or_else(corrupt)?;
+        let count = u32::from_le_bytes(payload[count_pos..].try_into().expect("4-byte slice")) as usize;
+        let restarts_pos = count
+            .checked_mul(4)
+            .and_then(|len| count_pos.checked_sub(len))
+            .ok_or_else(corrupt)?;
+        let restarts = payload[restarts_pos..count_pos]
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().expect("4-byte chunk")) as usize)
+            .collect::<Vec<_>>();
+        if restarts.iter().any(|&offset| offset >= restarts_pos) {
+            return Err(corrupt());
+        }
+        Ok(Self {
+            entries: &payload[..restarts_pos],
+            restarts,
+        })
+    }
+
+    /// Decodes the entry at `offset`, rebuilding its key from `prev_key`, and advances `offset`.
+    fn read_entry(&self, offset: &mut usize, prev_key: &[u8]) -> io::Result<(Vec<u8>, DecodedRecordRef<'a>)> {
+        let shared = decode_var_u32(self.entries, offset)? as usize;
+        let (record, len) = decode_record_ref(&self.entries[*offset..])?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "prefix compressed block truncated")
+        })?;
+        *offset += len;
+        let prefix = prev_key
+            .get(..shared)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid shared key prefix length"))?;
+        let mut key = Vec::with_capacity(shared + record.key.len());
+        key.extend_from_slice(prefix);
+        key.extend_from_slice(record.key);
+        Ok((key, record))
+    }
+
+    fn decode(&self) -> io::Result<Vec<Entry>> {
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut offset = 0;
+        while offset < self.entries.len() {
+            let prev_key = entries.last().map_or(&[][..], |entry| entry.key.as_slice());
+            let (key, record) = self.read_entry(&mut offset, prev_key)?;
+            entries.push(Entry { key, ..Entry::try_from(record)? });
+        }
+        Ok(entries)
+    }
+
+    /// Binary searches the restart points for the last one at or before `key`, then scans forward.
+    fn search(&self, key: &[u8], comparator: &dyn Comparator) -> io::Result<Option<Entry>> {
+        let (mut lo, mut hi) = (0, self.restarts.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let mut offset = self.restarts[mid];
+            let (restart_key, _) = self.read_entry(&mut offset, &[])?;
+            if comparator.cmp(&restart_key, key) != Ordering::Greater {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let Some(start) = lo.checked_sub(1) else {
+            return Ok(None);
+        };
+
+        let mut offset = self.restarts[start];
+        let mut prev_key = Vec::new();
+        while offset < self.entries.len() {
+            let (entry_key, record) = self.read_entry(&mut offset, &prev_key)?;
+            match comparator.cmp(&entry_key, key) {
+                Ordering::Less => prev_key = entry_key,
+                Ordering::Equal => return Ok(Some(Entry { key: entry_key, ..Entry::try_from(record)? })),
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Location of a data block in the SSTable file, as recorded in the sparse index.
+#[derive(Clone, Debug)]
+pub struct BlockHandle {
+    /// the first key stored in the block
+    pub first_key: Vec<u8>,
+    /// the file offset where the block starts
+    pub offset: u64,
+    /// the length of the block in bytes
+    pub len: u64,
+}
+
+/// Encodes the sparse index as a sequence of `[key_len:4][first_key][offset:8][len:8]`.
+pub fn encode_index(handles: &[BlockHandle]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for handle in handles {
+        buffer.extend_from_slice(&(handle.first_key.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&handle.first_key);
+        buffer.extend_from_slice(&handle.offset.to_le_bytes());
+        buffer.extend_from_slice(&handle.len.to_le_bytes());
+    }
+    buffer
+}
+
+/// Decodes the sparse index written by `encode_index`.
+pub fn decode_index(mut buffer: &[u8]) -> io::Result<Vec<BlockHandle>> {
+    let mut handles = Vec::new();
+    while !buffer.is_empty() {
+        let mut len_buf = [0u8; 4];
+        buffer.read_exact(&mut len_buf).map_err(truncated_index)?;
+        let key_len = u32::from_le_bytes(len_buf) as usize;
+        if key_len > buffer.len() {
+            return Err(truncated_index(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let (key_bytes, rest) = buffer.split_at(key_len);
+        let first_key = key_bytes.to_vec();
+        buffer = rest;
+
+        let mut u64_buf = [0u8; 8];
+        buffer.read_exact(&mut u64_buf).map_err(truncated_index)?;
+        let offset = u64::from_le_bytes(u64_buf);
+        buffer.read_exact(&mut u64_buf).map_err(truncated_index)?;
+        let len = u64::from_le_bytes(u64_buf);
+
+        handles.push(BlockHandle { first_key, offset, len });
+    }
+    Ok(handles)
+}
+
+/// Returns the index of the only block that can contain `key`: the last block
+/// whose first key sorts at or before it.
+pub fn find_block(handles: &[BlockHandle], key: &[u8], comparator: &dyn Comparator) -> Option<usize> {
+    let pos = handles.partition_point(|handle| comparator.cmp(&handle.first_key, key) != Ordering::Greater);
+    pos.checked_sub(1)
+}
+
+/// Reads the raw bytes of a single block from the reader.
+pub fn read_block<R: Read + Seek>(reader: &mut R, handle: &BlockHandle) -> io::Result<Vec<u8>> {
+    let len: usize = handle
+        .len
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "block too large"))?;
+    reader.seek(SeekFrom::Start(handle.offset))?;
+    let mut buffer = vec![0u8; len];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Returns the bytes of a block inside a memory mapped file or a buffer holding the start of
+/// the file, failing if the index points past its end.
+pub fn block_in<'a>(map: &'a [u8], handle: &BlockHandle) -> io::Result<&'a [u8]> {
+    usize::try_from(handle.offset)
+        .ok()
+        .zip(usize::try_from(handle.len).ok())
+        .and_then(|(offset, len)| map.get(offset..offset.checked_add(len)?))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "sstable block extends past the end of the file"))
+}
+
+/// Looks up `key` in a block, parsing records in order and stopping at the first key past it.
+pub fn search_block(bytes: &[u8], key: &[u8], comparator: &dyn Comparator) -> io::Result<Option<Entry>> {
+    let payload = compression::decompress_block(bytes)?;
+    if is_prefix_block(bytes) {
+        return PrefixBlock::parse(&payload)?.search(key, comparator);
+    }
+    // Records are decoded in place, so only the one found is copied
+    for record in RecordRefs::new(&payload) {
+        let record = record?;
+        match comparator.cmp(record.key, key) {
+            Ordering::Less => continue,
+            Ordering::Equal => return Ok(Some(Entry::try_from(record)?)),
+            Ordering::Greater => break,
+        }
+    }
+    Ok(None)
+}
+
+/// Decodes every record stored in a block, decompressing it first if needed.
+pub fn decode_block(bytes: &[u8]) -> io::Result<Vec<Entry>> {
+    let payload = compression::decompress_block(bytes)?;
+    if is_prefix_block(bytes) {
+        return PrefixBlock::parse(&payload)?.decode();
+    }
+    RecordRefs::new(&payload).map(|record| Entry::try_from(record?)).collect()
+}
+
+/// The entries of a block, copied out one at a time from either end as they are taken, see
+/// `SsTable::iter`.
+pub(crate) enum BlockEntries {
+    /// A plain block: the buffer holding its records and the offsets in it of those left,
+    /// which are decoded in place again when taken
+    Plain { buffer: Vec<u8>, offsets: VecDeque<usize> },
+    /// A prefix compressed block, whose keys are rebuilt in order up front
+    Decoded(VecDeque<Entry>),
+}
+
+impl Default for BlockEntries {
+    fn default() -> Self {
+        BlockEntries::Decoded(VecDeque::new())
+    }
+}
+
+impl BlockEntries {
+    /// Decodes the block `bytes`, keeping the entries whose key `keep` accepts. Every record is
+    /// checked, but those of a plain block are not copied.
+    pub(crate) fn new(bytes: Cow<'_, [u8]>, keep: impl Fn(&[u8]) -> bool) -> io::Result<Self> {
+        if is_prefix_block(&bytes) {
+            let mut entries = decode_block(&bytes)?;
+            entries.retain(|entry| keep(&entry.key));
+            return Ok(BlockEntries::Decoded(entries.into()));
+        }
+        // An uncompressed block is kept as read, its records start after the codec tag
+        let (buffer, start) = match compression::decompress_block(&bytes)? {
+            Cow::Owned(payload) => (payload, 0),
+            Cow::Borrowed(_) => (bytes.into_owned(), 1),
+        };
+        let mut offsets = VecDeque::new();
+        let mut offset = start;
+        while let Some((record, len)) = decode_record_ref(&buffer[offset..])? {
+            if keep(record.key) {
+                offsets.push_back(offset);
+            }
+            offset += len;
+        }
+        Ok(BlockEntries::Plain { buffer, offsets })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            BlockEntries::Plain { offsets, .. } => offsets.is_empty(),
+            BlockEntries::Decoded(entries) => entries.is_empty(),
+        }
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<io::Result<Entry>> {
+        match self {
+            BlockEntries::Plain { buffer, offsets } => offsets.pop_front().map(|offset| entry_at(buffer, offset)),
+            BlockEntries::Decoded(entries) => entries.pop_front().map(Ok),
+        }
+    }
+
+    pub(crate) fn pop_back(&mut self) -> Option<io::Result<Entry>> {
+        match self {
+            BlockEntries::Plain { buffer, offsets } => offsets.pop_back().map(|offset| entry_at(buffer, offset)),
+            BlockEntries::Decoded(entries) => entries.pop_back().map(Ok),
+        }
+    }
+}
+
+/// Copies out the entry of the record at `offset` in `buffer`.
+fn entry_at(buffer: &[u8], offset: usize) -> io::Result<Entry> {
+    let (record, _) = decode_record_ref(&buffer[offset..])?.expect("offset of a decoded record");
+    Entry::try_from(record)
+}
+
+/// A record of a decoded block and where it sits in the decompressed payload, see `block_records`.
+pub(crate) struct RecordSpan {
+    pub(crate) offset: usize,
+    pub(crate) size: usize,
+    pub(crate) entry: Entry,
+}
+
+/// Decodes a block like `decode_block`, also returning the offset and size of each record in
+/// the decompressed payload. When a record is corrupt the ones before it are returned with the error.
+pub(crate) fn block_records(bytes: &[u8]) -> (Vec<RecordSpan>, Option<io::Error>) {
+    let payload = match compression::decompress_block(bytes) {
+        Ok(payload) => payload,
+        Err(err) => return (Vec::new(), Some(err)),
+    };
+    let mut spans: Vec<RecordSpan> = Vec::new();
+    if is_prefix_block(bytes) {
+        let block = match PrefixBlock::parse(&payload) {
+            Ok(block) => block,
+            Err(err) => return (spans, Some(err)),
+        };
+        let mut offset = 0;
+        while offset < block.entries.len() {
+            let start = offset;
+            let prev_key = spans.last().map_or(&[][..], |span| span.entry.key.as_slice());
+            let entry = block.read_entry(&mut offset, prev_key);
+            match entry.and_then(|(key, record)| Ok((key, Entry::try_from(record)?))) {
+                Ok((key, entry)) => spans.push(RecordSpan {
+                    offset: start,
+                    size: offset - start,
+                    entry: Entry { key, ..entry },
+                }),
+                Err(err) => return (spans, Some(err)),
+            }
+        }
+    } else {
+        let mut start = 0;
+        loop {
+            let decoded = decode_record_ref(&payload[start..])
+                .and_then(|record| record.map(|(record, size)| Ok((Entry::try_from(record)?, size))).transpose());
+            match decoded {
+                Ok(Some((entry, size))) => {
+                    spans.push(RecordSpan { offset: start, size, entry });
+                    start += size;
+                }
+                Ok(None) => break,
+                Err(err) => return (spans, Some(err)),
+            }
+        }
+    }
+    (spans, None)
+}
+
+pub(crate) fn is_prefix_block(bytes: &[u8]) -> bool {
+    bytes.first().is_some_and(|tag| tag & PREFIX_KEYS_FLAG != 0)
+}
+
+impl TryFrom<DecodedRecord> for Entry {
+    type Error = io::Error;
+
+    fn try_from(record: DecodedRecord) -> io::Result<Self> {
+        Ok(Entry {
+            value: record_value(record.kind, record.value)?,
+            key: record.key,
+            expires_at: record.expires_at,
+            seqno: record.seqno,
+            written_at: record.timestamp,
+        })
+    }
+}
+
+impl TryFrom<DecodedRecordRef<'_>> for Entry {
+    type Error = io::Error;
+
+    fn try_from(record: DecodedRecordRef<'_>) -> io::Result<Self> {
+        Ok(Entry {
+            key: record.key.to_vec(),
+            value: record_value(record.kind, record.decompressed_value()?)?,
+            expires_at: record.expires_at,
+            seqno: record.seqno,
+            written_at: record.timestamp,
+        })
+    }
+}
+
+/// Returns the value of a data block record of kind `kind` holding `value`.
+fn record_value<V: AsRef<[u8]> + Into<Vec<u8>>>(kind: RecordKind, value: V) -> io::Result<Value> {
+    match kind {
+        RecordKind::Set | RecordKind::SetWithTtl => Ok(Value::from_bytes(value.into())),
+        RecordKind::Delete => Ok(Value::Deleted),
+        RecordKind::Merge => Ok(Value::Merge(decode_operands(value.as_ref())?)),
+        RecordKind::Typed => Value::decode(value.as_ref()),
+        // Range tombstones have a block of their own, see `range_del`
+        RecordKind::RangeDelete => Err(io::Error::new(io::ErrorKind::InvalidData, "range tombstone in a data block")),
+    }
+}
+
+/// Encodes the operands of a merge entry as its value: `[operand_len:varint][operand]*`, oldest
+/// first.
+fn encode_operands(operands: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    for operand in operands {
+        let len = u32::try_from(operand.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "merge operand too large"))?;
+        buffer.extend_from_slice(&encode_var_u32(len));
+        buffer.extend_from_slice(operand);
+    }
+    Ok(buffer)
+}
+
+/// Decodes the operands encoded by `encode_operands`.
+fn decode_operands(bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut operands = Vec::new();
+    let mut cursor = 0;
+    while cursor < bytes.len() {
+        let len = decode_var_u32(bytes, &mut cursor)? as usize;
+        let operand = cursor
+            .checked_add(len)
+            .and_then(|end| bytes.get(cursor..end))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "merge operand truncated"))?;
+        operands.push(operand.to_vec());
+        cursor += len;
+    }
+    Ok(operands)
+}
+
+fn truncated_index(err: io::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("sstable index truncated: {err}"))
+}
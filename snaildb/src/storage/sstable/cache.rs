@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::storage::sstable::{BlockHandle, Entry};
+
+/// An LRU cache of decoded data blocks, shared by any number of SSTables through an `Arc`.
+///
+/// Blocks are keyed by the id handed to each table in `SsTable::with_block_cache` and the
+/// block offset in its file, and charged for the bytes of their keys and values. Once the
+/// charged bytes exceed the capacity the least recently used blocks are evicted. The index
+/// partitions of tables with a partitioned index are cached alongside the data blocks.
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    next_table_id: AtomicU64,
+}
+
+/// (table id, block offset)
+type BlockKey = (u64, u64);
+
+#[derive(Debug, Default)]
+struct LruState {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    /// block keys by last use, the first one is evicted next
+    order: BTreeMap<u64, BlockKey>,
+    next_tick: u64,
+    usage: usize,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    contents: Contents,
+    charge: usize,
+    tick: u64,
+}
+
+#[derive(Clone, Debug)]
+enum Contents {
+    Data(Arc<[Entry]>),
+    IndexPartition(Arc<[BlockHandle]>),
+}
+
+impl BlockCache {
+    /// Creates a cache holding up to `capacity` bytes of decoded blocks.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(LruState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            next_table_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the capacity in bytes.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the bytes charged for the blocks currently cached.
+    pub fn usage(&self) -> usize {
+        self.lock().usage
+    }
+
+    /// Returns the number of blocks currently cached.
+    pub fn len(&self) -> usize {
+        self.lock().blocks.len()
+    }
+
+    /// Returns true if no block is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns how many lookups found their block in the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many lookups had to read their block from the file.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns a new id for a table, so blocks of different tables never collide.
+    pub(crate) fn next_table_id(&self) -> u64 {
+        self.next_table_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the cached data block and marks it as most recently used, counting a hit or a miss.
+    pub(crate) fn get(&self, table_id: u64, offset: u64) -> Option<Arc<[Entry]>> {
+        match self.lookup(table_id, offset)? {
+            Contents::Data(entries) => Some(entries),
+            Contents::IndexPartition(_) => None,
+        }
+    }
+
+    /// Returns the cached index partition like `get`.
+    pub(crate) fn get_index_partition(&self, table_id: u64, offset: u64) -> Option<Arc<[BlockHandle]>> {
+        match self.lookup(table_id, offset)? {
+            Contents::IndexPartition(handles) => Some(handles),
+            Contents::Data(_) => None,
+        }
+    }
+
+    /// Caches a decoded block and evicts the least recently used blocks beyond the capacity.
+    /// A block larger than the whole cache is not cached.
+    pub(crate) fn insert(&self, table_id: u64, offset: u64, entries: Arc<[Entry]>) {
+        self.store(table_id, offset, charge(&entries), Contents::Data(entries));
+    }
+
+    /// Caches a decoded index partition like `insert`.
+    pub(crate) fn insert_index_partition(&self, table_id: u64, offset: u64, handles: Arc<[BlockHandle]>) {
+        let charge = handles
+            .iter()
+            .map(|handle| size_of::<BlockHandle>() + handle.first_key.len())
+            .sum();
+        self.store(table_id, offset, charge, Contents::IndexPartition(handles));
+    }
+
+    fn lookup(&self, table_id: u64, offset: u64) -> Option<Contents> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let tick = state.next_tick;
+        let Some(block) = state.blocks.get_mut(&(table_id, offset)) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        state.order.remove(&block.tick);
+        state.order.insert(tick, (table_id, offset));
+        state.next_tick += 1;
+        block.tick = tick;
+        Some(block.contents.clone())
+    }
+
+    fn store(&self, table_id: u64, offset: u64, charge: usize, contents: Contents) {
+        if charge > self.capacity {
+            return;
+        }
+        let mut state = self.lock();
+        let tick = state.next_tick;
+        state.next_tick += 1;
+        state.order.insert(tick, (table_id, offset));
+        state.usage += charge;
+        if let Some(old) = state.blocks.insert((table_id, offset), CachedBlock { contents, charge, tick }) {
+            state.order.remove(&old.tick);
+            state.usage -= old.charge;
+        }
+        while state.usage > self.capacity {
+            let (_, key) = state.order.pop_first().expect("usage is charged to cached blocks");
+            let evicted = state.blocks.remove(&key).expect("ordered block is cached");
+            state.usage -= evicted.charge;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LruState> {
+        // The state stays consistent between statements, so a panic elsewhere cannot poison it
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Returns the bytes charged for a block: its keys, its values and the entries holding them.
+fn charge(entries: &[Entry]) -> usize {
+    entries
+        .iter()
+        .map(|entry| {
+            size_of::<Entry>() + entry.key.len() + entry.value.byte_len()
+        })
+        .sum()
+}
@@ -0,0 +1,25 @@
+use crate::utils::value::Value;
+
+/// What a `CompactionFilter` does with an entry that survives a merge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Writes the entry unchanged.
+    Keep,
+    /// Drops the value. It is written as a tombstone, or left out entirely when the merge
+    /// drops tombstones, so an older value of the key cannot come back.
+    Remove,
+    /// Writes the given bytes as the value instead, keeping the expiry and sequence number.
+    ChangeValue(Vec<u8>),
+}
+
+/// Domain logic applied to the output of `SsTable::merge_with_filter`, such as stripping
+/// soft-deleted entries or rewriting values to a new schema.
+///
+/// The filter is called once for every key that survives the merge, with the value that won
+/// over the older versions of the key. Keys whose winner is a tombstone are passed as
+/// `Value::Deleted` unless the merge drops tombstones, in which case they are not passed at all,
+/// and merge operands the merge could not combine into a value are passed as `Value::Merge`.
+pub trait CompactionFilter {
+    /// Decides what to write for `key`.
+    fn filter(&mut self, key: &[u8], value: &Value) -> FilterDecision;
+}
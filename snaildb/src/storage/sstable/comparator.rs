@@ -0,0 +1,69 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use crate::storage::sstable::properties;
+
+/// The order keys are stored in, see `SsTableOptions::with_comparator`.
+///
+/// The writer requires keys strictly ascending by `cmp`, and lookups, range scans and merges
+/// rely on that order, so a table must always be read with the comparator it was written with.
+/// The `name` is recorded in the table and checked when it is opened: it must be unique and
+/// change whenever the order does. Names starting with `snaildb.` are used by the built-ins.
+pub trait Comparator: fmt::Debug + Send + Sync {
+    /// Returns the name recorded in the tables written with this comparator.
+    fn name(&self) -> &str;
+
+    /// Orders two keys. Keys comparing `Equal` are the same key, so it must only return
+    /// `Equal` for identical bytes.
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders keys byte-wise, like `Ord` on `[u8]`. The default, and the order of tables written
+/// before comparators were recorded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "snaildb.bytewise"
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Orders keys byte-wise from the largest to the smallest, so iterating a table walks the
+/// keys in descending byte order.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "snaildb.reverse_bytewise"
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// Fails with `InvalidInput` unless the table with these properties was written with
+/// `comparator`. Tables without the `snaildb.comparator` property are byte-wise.
+pub(crate) fn check(properties: &BTreeMap<String, String>, comparator: &dyn Comparator) -> io::Result<()> {
+    let recorded = properties
+        .get(properties::COMPARATOR)
+        .map_or(BytewiseComparator.name(), String::as_str);
+    if recorded != comparator.name() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "sstable was written with comparator {recorded:?} but is read with {:?}",
+                comparator.name()
+            ),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use std::borrow::Cow;
+use std::io;
+
+/// Compression codec applied to SSTable data blocks.
+///
+/// Every block on disk starts with a one-byte codec tag followed by the (possibly
+/// compressed) records, so a table can mix codecs: a block that does not shrink is
+/// stored uncompressed even when a codec was requested.
+///
+/// The codecs are behind the `lz4` and `zstd` cargo features. Reading a block written
+/// with a codec whose feature is disabled fails with `InvalidData` naming the codec.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    fn as_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        let codec = byte & CODEC_MASK;
+        match codec {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown block compression codec {codec}"),
+            )),
+        }
+    }
+
+    /// Returns the codec name, which is also the name of the cargo feature enabling it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Returns true if this build can read and write blocks with the codec.
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    fn unavailable(self, kind: io::ErrorKind) -> io::Error {
+        io::Error::new(
+            kind,
+            format!(
+                "block compressed with {name}, but snaildb was built without the `{name}` feature",
+                name = self.name()
+            ),
+        )
+    }
+}
+
+/// Bits of the block tag holding the codec, the others describe the block layout.
+const CODEC_MASK: u8 = 0x0F;
+
+/// Appends `[codec:1][payload]` for a raw block to `out`. Falls back to an uncompressed
+/// block when the codec does not make it smaller.
+pub(crate) fn compress_block(codec: Compression, raw: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    if !codec.is_available() {
+        return Err(codec.unavailable(io::ErrorKind::InvalidInput));
+    }
+    let compressed = match codec {
+        Compression::None => None,
+        Compression::Lz4 => lz4_compress(raw),
+        Compression::Zstd => zstd_compress(raw)?,
+    };
+    match compressed {
+        Some(payload) if payload.len() < raw.len() => {
+            out.push(codec.as_byte());
+            out.extend_from_slice(&payload);
+        }
+        _ => {
+            out.push(Compression::None.as_byte());
+            out.extend_from_slice(raw);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the codec named by the tag of an on-disk block.
+pub(crate) fn block_codec(bytes: &[u8]) -> io::Result<Compression> {
+    let tag = bytes
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty sstable block"))?;
+    Compression::from_byte(*tag)
+}
+
+/// Strips the codec tag from an on-disk block and decompresses it if needed.
+pub(crate) fn decompress_block(bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let (&tag, payload) = bytes
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty sstable block"))?;
+    let codec = Compression::from_byte(tag)?;
+    if !codec.is_available() {
+        return Err(codec.unavailable(io::ErrorKind::InvalidData));
+    }
+    match codec {
+        Compression::None => Ok(Cow::Borrowed(payload)),
+        Compression::Lz4 => lz4_decompress(payload).map(Cow::Owned),
+        Compression::Zstd => zstd_decompress(payload).map(Cow::Owned),
+    }
+}
+
+#[cfg(feature = "lz4")]
+pub(crate) fn lz4_compress(raw: &[u8]) -> Option<Vec<u8>> {
+    Some(lz4_flex::compress_prepend_size(raw))
+}
+
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn lz4_compress(_raw: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "lz4")]
+pub(crate) fn lz4_decompress(payload: &[u8]) -> io::Result<Vec<u8>> {
+    lz4_flex::decompress_size_prepended(payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("lz4 block is corrupt: {e}")))
+}
+
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn lz4_decompress(_payload: &[u8]) -> io::Result<Vec<u8>> {
+    Err(Compression::Lz4.unavailable(io::ErrorKind::InvalidData))
+}
+
+/// Compression level used for zstd blocks, the library default.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[cfg(feature = "zstd")]
+pub(crate) fn zstd_compress(raw: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    zstd::bulk::compress(raw, ZSTD_LEVEL).map(Some)
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn zstd_compress(_raw: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(feature = "zstd")]
+pub(crate) fn zstd_decompress(payload: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::stream::decode_all(payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("zstd block is corrupt: {e}")))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn zstd_decompress(_payload: &[u8]) -> io::Result<Vec<u8>> {
+    Err(Compression::Zstd.unavailable(io::ErrorKind::InvalidData))
+}
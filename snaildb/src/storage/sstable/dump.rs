@@ -0,0 +1,186 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::storage::sstable::{SsTable, block, compression};
+use crate::utils::{base64, value::Value};
+
+/// Output format of `SsTable::dump`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per entry, `{"key":..,"kind":"set","value":..}` or
+    /// `{"key":..,"kind":"delete","value":null}`, followed by a summary object
+    /// `{"summary":true,"entries":..,"min_key":..,"max_key":..}`. Keys and values that are
+    /// not UTF-8 are written as base64, flagged by an extra `"<field>_base64":true`. A value
+    /// with an expiry also has `"expires_at":..`, and is written whether or not it expired.
+    /// Entries written with a sequence number also have `"seqno":..`, and entries written with
+    /// a timestamp `"written_at":..`. A typed value other than raw bytes has its type in
+    /// `"type"`, one of `"int"`, `"float"`, `"str"` and `"bool"`, and is written as a JSON
+    /// number, string or boolean. Floats that are not finite are written as the strings
+    /// `"NaN"`, `"inf"` and `"-inf"`.
+    Json,
+    /// One line per block with its file offset, length and codec, then one line per record
+    /// with its offset and size in the decompressed block. A block that fails to decode is
+    /// reported and skipped, so the lines show where a corrupt file goes wrong.
+    Raw,
+}
+
+pub(crate) fn dump<W: Write>(table: &SsTable, out: W, format: DumpFormat) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    match format {
+        DumpFormat::Json => dump_json(table, &mut out)?,
+        DumpFormat::Raw => dump_raw(table, &mut out)?,
+    }
+    out.flush()
+}
+
+fn dump_json<W: Write>(table: &SsTable, out: &mut W) -> io::Result<()> {
+    let mut entries = 0u64;
+    for item in table.entries() {
+        let entry = item?;
+        out.write_all(b"{")?;
+        write_bytes_field(out, "key", &entry.key)?;
+        match &entry.value {
+            Value::Bytes(bytes) => {
+                out.write_all(br#","kind":"set","#)?;
+                write_bytes_field(out, "value", bytes)?;
+                if let Some(expires_at) = entry.expires_at {
+                    write!(out, r#","expires_at":{expires_at}"#)?;
+                }
+            }
+            Value::Int(int) => write!(out, r#","kind":"set","type":"int","value":{int}"#)?,
+            Value::Float(float) if float.is_nan() => out.write_all(br#","kind":"set","type":"float","value":"NaN""#)?,
+            Value::Float(float) if float.is_infinite() => {
+                let text = if *float > 0.0 { "inf" } else { "-inf" };
+                write!(out, r#","kind":"set","type":"float","value":"{text}""#)?;
+            }
+            Value::Float(float) => write!(out, r#","kind":"set","type":"float","value":{float:?}"#)?,
+            Value::Str(text) => {
+                out.write_all(br#","kind":"set","type":"str","value":"#)?;
+                write_json_string(out, text)?;
+            }
+            Value::Bool(flag) => write!(out, r#","kind":"set","type":"bool","value":{flag}"#)?,
+            Value::Deleted => out.write_all(br#","kind":"delete","value":null"#)?,
+            Value::Merge(operands) => {
+                out.write_all(br#","kind":"merge","operands":["#)?;
+                for (i, operand) in operands.iter().enumerate() {
+                    out.write_all(if i == 0 { b"{" } else { b",{" })?;
+                    write_bytes_field(out, "value", operand)?;
+                    out.write_all(b"}")?;
+                }
+                out.write_all(b"]")?;
+            }
+        }
+        if entry.seqno != 0 {
+            write!(out, r#","seqno":{}"#, entry.seqno)?;
+        }
+        if let Some(written_at) = entry.written_at() {
+            write!(out, r#","written_at":{written_at}"#)?;
+        }
+        out.write_all(b"}\n")?;
+        entries += 1;
+    }
+    write!(out, r#"{{"summary":true,"entries":{entries},"#)?;
+    write_bytes_field(out, "min_key", &table.metadata.min_key)?;
+    out.write_all(b",")?;
+    write_bytes_field(out, "max_key", &table.metadata.max_key)?;
+    out.write_all(b"}\n")
+}
+
+fn dump_raw<W: Write>(table: &SsTable, out: &mut W) -> io::Result<()> {
+    // Read the blocks from the file itself, so a loaded table shows what is on disk
+    let mut file = File::open(table.path())?;
+    let handles = table.block_handles()?;
+    for (idx, handle) in handles.iter().enumerate() {
+        write!(out, "block {idx} offset=0x{:x} len={}", handle.offset, handle.len)?;
+        let bytes = match block::read_block(&mut file, handle) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                writeln!(out, " error: {err}")?;
+                continue;
+            }
+        };
+        match compression::block_codec(&bytes) {
+            Ok(codec) => write!(out, " codec={}", codec.name())?,
+            Err(err) => write!(out, " codec=? ({err})")?,
+        }
+        writeln!(
+            out,
+            " prefix_keys={} first_key=\"{}\"",
+            block::is_prefix_block(&bytes),
+            handle.first_key.escape_ascii()
+        )?;
+        let (records, error) = block::block_records(&bytes);
+        for record in &records {
+            let (kind, value_len) = match &record.entry.value {
+                Value::Bytes(value) => ("set", value.len()),
+                Value::Deleted => ("delete", 0),
+                value @ (Value::Int(_) | Value::Float(_) | Value::Str(_) | Value::Bool(_)) => {
+                    ("typed", value.byte_len())
+                }
+                value @ Value::Merge(_) => ("merge", value.byte_len()),
+            };
+            write!(
+                out,
+                "  0x{:06x} size={} {kind} key=\"{}\" value_len={value_len}",
+                record.offset,
+                record.size,
+                record.entry.key.escape_ascii()
+            )?;
+            if let Some(expires_at) = record.entry.expires_at {
+                write!(out, " expires_at={expires_at}")?;
+            }
+            if record.entry.seqno != 0 {
+                write!(out, " seqno={}", record.entry.seqno)?;
+            }
+            if let Some(written_at) = record.entry.written_at() {
+                write!(out, " written_at={written_at}")?;
+            }
+            writeln!(out)?;
+        }
+        if let Some(err) = error {
+            writeln!(out, "  error after {} records: {err}", records.len())?;
+        }
+    }
+    writeln!(
+        out,
+        "{} blocks, {} entries, keys \"{}\"..=\"{}\"",
+        handles.len(),
+        table.stats().entries,
+        table.metadata.min_key.escape_ascii(),
+        table.metadata.max_key.escape_ascii()
+    )
+}
+
+/// Writes `"name":"text"`, or `"name":"base64","name_base64":true` for bytes that are not UTF-8.
+fn write_bytes_field<W: Write>(out: &mut W, name: &str, bytes: &[u8]) -> io::Result<()> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => {
+            write!(out, r#""{name}":"#)?;
+            write_json_string(out, text)
+        }
+        Err(_) => write!(out, r#""{name}":"{}","{name}_base64":true"#, base64::encode(bytes)),
+    }
+}
+
+fn write_json_string<W: Write>(out: &mut W, text: &str) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    let mut start = 0;
+    for (pos, c) in text.char_indices() {
+        if c >= ' ' && c != '"' && c != '\\' {
+            continue;
+        }
+        out.write_all(&text.as_bytes()[start..pos])?;
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            _ => write!(out, "\\u{:04x}", c as u32)?,
+        }
+        // every escaped character is a single byte
+        start = pos + 1;
+    }
+    out.write_all(&text.as_bytes()[start..])?;
+    out.write_all(b"\"")
+}
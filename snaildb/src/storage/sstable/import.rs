@@ -0,0 +1,413 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::storage::sstable::{Comparator, Entry, MergeOptions, SsTable, SsTableOptions, SsTableWriter, writer};
+use crate::utils::{base64, value::Value};
+
+/// Options controlling `SsTable::import_ndjson` and `SsTable::import_csv`.
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    /// Options for the imported table.
+    pub table_options: SsTableOptions,
+    /// Sorts the input with an external merge sort that buffers up to about this many bytes
+    /// of keys and values before spilling a sorted run to a temporary table next to the
+    /// output. Without it the input must already be sorted by key.
+    pub sort_memory_budget: Option<usize>,
+}
+
+impl ImportOptions {
+    /// Sets the options used to write the imported table.
+    pub fn with_table_options(mut self, table_options: SsTableOptions) -> Self {
+        self.table_options = table_options;
+        self
+    }
+
+    /// Sorts the input instead of requiring it sorted, buffering up to `bytes` bytes in memory.
+    pub fn with_sort_memory_budget(mut self, bytes: usize) -> Self {
+        self.sort_memory_budget = Some(bytes);
+        self
+    }
+}
+
+/// A parsed input record with the 1-based line it starts on.
+type Record = (usize, Entry);
+
+pub(crate) fn import_ndjson<R: Read>(reader: R, path: &Path, options: &ImportOptions) -> io::Result<SsTable> {
+    let mut lines = BufReader::new(reader).lines().enumerate();
+    let records = std::iter::from_fn(move || {
+        for (idx, line) in lines.by_ref() {
+            let line_no = idx + 1;
+            let parsed = line
+                .map_err(|err| at_line(line_no, err))
+                .and_then(|line| parse_json_line(&line).map_err(|reason| line_error(line_no, reason)));
+            match parsed {
+                // Blank lines and the summary `SsTable::dump` ends with are skipped
+                Ok(None) => continue,
+                Ok(Some(entry)) => return Some(Ok((line_no, entry))),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        None
+    });
+    import(records, path, options)
+}
+
+pub(crate) fn import_csv<R: Read>(reader: R, path: &Path, options: &ImportOptions) -> io::Result<SsTable> {
+    let mut csv = CsvReader { reader: BufReader::new(reader), line: 0 };
+    let header = match csv.next_record() {
+        Some(header) => header?.1,
+        None => return Err(line_error(1, "missing CSV header")),
+    };
+    let column = |name: &str| header.iter().position(|field| field == name);
+    let (Some(key_col), Some(value_col)) = (column("key"), column("value")) else {
+        return Err(line_error(1, "CSV header must name a key and a value column"));
+    };
+    let deleted_col = column("deleted");
+    let expires_col = column("expires_at");
+    let seqno_col = column("seqno");
+    let written_col = column("written_at");
+
+    let records = std::iter::from_fn(move || {
+        let (line_no, fields) = match csv.next_record()? {
+            Ok(record) => record,
+            Err(err) => return Some(Err(err)),
+        };
+        if fields.len() != header.len() {
+            let reason = format!("expected {} fields, found {}", header.len(), fields.len());
+            return Some(Err(line_error(line_no, reason)));
+        }
+        let deleted = match deleted_col.map(|col| fields[col].as_str()) {
+            None | Some("" | "false" | "0") => false,
+            Some("true" | "1") => true,
+            Some(other) => {
+                let reason = format!("invalid deleted flag \"{}\"", other.escape_default());
+                return Some(Err(line_error(line_no, reason)));
+            }
+        };
+        let number = |col: Option<usize>, name: &str| match col.map(|col| fields[col].as_str()) {
+            None | Some("") => Ok(None),
+            Some(text) => text
+                .parse()
+                .map(Some)
+                .map_err(|_| line_error(line_no, format!("invalid {name} \"{}\"", text.escape_default()))),
+        };
+        let numbers = (
+            number(expires_col, "expires_at"),
+            number(seqno_col, "seqno"),
+            number(written_col, "written_at"),
+        );
+        let (expires_at, seqno, written_at) = match numbers {
+            (Ok(expires_at), Ok(seqno), Ok(written_at)) => (expires_at, seqno.unwrap_or(0), written_at.unwrap_or(0)),
+            (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return Some(Err(err)),
+        };
+        let key = fields[key_col].as_bytes().to_vec();
+        let entry = if deleted {
+            Entry { key, value: Value::Deleted, expires_at: None, seqno, written_at }
+        } else {
+            Entry { key, value: Value::Bytes(fields[value_col].as_bytes().to_vec()), expires_at, seqno, written_at }
+        };
+        Some(Ok((line_no, entry)))
+    });
+    import(records, path, options)
+}
+
+fn import(
+    records: impl Iterator<Item = io::Result<Record>>,
+    path: &Path,
+    options: &ImportOptions,
+) -> io::Result<SsTable> {
+    let Some(budget) = options.sort_memory_budget else {
+        let mut writer = SsTableWriter::new(path, &options.table_options)?;
+        for record in records {
+            let (line_no, entry) = record?;
+            writer.add_entry(&entry).map_err(|err| at_line(line_no, err))?;
+        }
+        return writer.finish();
+    };
+
+    let mut sorter = RunSorter {
+        path: path.to_path_buf(),
+        budget,
+        buffer: Vec::new(),
+        buffered: 0,
+        runs: Vec::new(),
+        comparator: Arc::clone(&options.table_options.comparator),
+    };
+    for record in records {
+        sorter.push(record?.1)?;
+    }
+    sorter.finish(&options.table_options)
+}
+
+/// Buffers records and spills them as sorted runs, written as temporary tables that are
+/// merged into the output at the end. The runs are removed when the sorter is dropped.
+struct RunSorter {
+    path: PathBuf,
+    budget: usize,
+    buffer: Vec<Entry>,
+    buffered: usize,
+    runs: Vec<SsTable>,
+    /// the order of the output, which the runs are sorted by as well
+    comparator: Arc<dyn Comparator>,
+}
+
+impl RunSorter {
+    fn push(&mut self, entry: Entry) -> io::Result<()> {
+        self.buffered += size_of::<Entry>() + entry.key.len() + entry.value.byte_len();
+        self.buffer.push(entry);
+        if self.buffered >= self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Sorts the buffer, keeping the record with the highest sequence number for a repeated
+    /// key and the one read last among equal sequence numbers, like the merge of the runs.
+    fn sorted_buffer(&mut self) -> Vec<Entry> {
+        self.buffered = 0;
+        let mut buffer = std::mem::take(&mut self.buffer);
+        // The sort is stable, so equal keys stay in input order
+        buffer.sort_by(|a, b| self.comparator.cmp(&a.key, &b.key));
+        let mut sorted: Vec<Entry> = Vec::with_capacity(buffer.len());
+        for entry in buffer {
+            match sorted.last_mut() {
+                Some(last) if last.key == entry.key => {
+                    if entry.seqno >= last.seqno {
+                        *last = entry;
+                    }
+                }
+                _ => sorted.push(entry),
+            }
+        }
+        sorted
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let entries = self.sorted_buffer();
+        // Named with the temporary suffix, so runs left by a crash are found by `SsTable::temp_files`
+        let mut run_path = self.path.as_os_str().to_os_string();
+        run_path.push(format!(".run{}{}", self.runs.len(), writer::TEMP_SUFFIX));
+        // Runs are read back once, a filter would only slow the spill down
+        let run_options = SsTableOptions::default()
+            .with_bloom_bits_per_key(0)
+            .with_comparator(Arc::clone(&self.comparator));
+        let run = write_entries(PathBuf::from(run_path), &entries, &run_options)?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    fn finish(mut self, table_options: &SsTableOptions) -> io::Result<SsTable> {
+        if self.runs.is_empty() {
+            let entries = self.sorted_buffer();
+            return write_entries(&self.path, &entries, table_options);
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        // Later runs hold later records, and the merge lets the latest input win. Its clock
+        // stands still at 0 so expiries are copied as they are, like without sorting
+        let options = MergeOptions::default()
+            .with_table_options(table_options.clone())
+            .with_clock(|| 0);
+        SsTable::merge_with_options(&self.path, &self.runs, &options)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cannot create an sstable without entries"))
+    }
+}
+
+impl Drop for RunSorter {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = std::fs::remove_file(run.path());
+        }
+    }
+}
+
+/// Writes sorted entries to a new table, keeping their expiries.
+fn write_entries(path: impl AsRef<Path>, entries: &[Entry], options: &SsTableOptions) -> io::Result<SsTable> {
+    let mut writer = SsTableWriter::new(path, options)?;
+    entries.iter().try_for_each(|entry| writer.add_entry(entry))?;
+    writer.finish()
+}
+
+/// Parses one line of NDJSON into an entry, or `None` for a line without one.
+fn parse_json_line(line: &str) -> Result<Option<Entry>, String> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let record: serde_json::Value = serde_json::from_str(line).map_err(|err| format!("invalid JSON: {err}"))?;
+    let Some(record) = record.as_object() else {
+        return Err("expected a JSON object".to_string());
+    };
+    if record.get("summary").and_then(serde_json::Value::as_bool) == Some(true) {
+        return Ok(None);
+    }
+    let number = |name: &str| match record.get(name) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(number) => number
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("\"{name}\" must be a non-negative integer")),
+    };
+    let flag = |name: &str| match record.get(name) {
+        None | Some(serde_json::Value::Null) => Ok(false),
+        Some(serde_json::Value::Bool(flag)) => Ok(*flag),
+        Some(_) => Err(format!("\"{name}\" must be a boolean")),
+    };
+    let bytes = |name: &str| -> Result<Option<Vec<u8>>, String> {
+        let text = match record.get(name) {
+            None | Some(serde_json::Value::Null) => return Ok(None),
+            Some(serde_json::Value::String(text)) => text,
+            Some(_) => return Err(format!("\"{name}\" must be a string")),
+        };
+        if flag(&format!("{name}_base64"))? {
+            base64::decode(text)
+                .map(Some)
+                .ok_or_else(|| format!("\"{name}\" is not valid base64"))
+        } else {
+            Ok(Some(text.as_bytes().to_vec()))
+        }
+    };
+
+    let key = bytes("key")?.ok_or_else(|| "missing \"key\"".to_string())?;
+    let deleted = match record.get("kind").map(|kind| kind.as_str()) {
+        None => flag("deleted")?,
+        Some(Some("set")) => false,
+        Some(Some("delete")) => true,
+        Some(_) => return Err("\"kind\" must be \"set\" or \"delete\"".to_string()),
+    };
+    let seqno = number("seqno")?.unwrap_or(0);
+    let written_at = number("written_at")?.unwrap_or(0);
+    if deleted {
+        return Ok(Some(Entry { key, value: Value::Deleted, expires_at: None, seqno, written_at }));
+    }
+    let expires_at = number("expires_at")?;
+    let value = match record.get("type") {
+        None | Some(serde_json::Value::Null) => {
+            Value::Bytes(bytes("value")?.ok_or_else(|| "missing \"value\"".to_string())?)
+        }
+        Some(_) if expires_at.is_some() => return Err("only raw bytes values may expire".to_string()),
+        Some(kind) => parse_typed_value(kind, record.get("value"))?,
+    };
+    Ok(Some(Entry { key, value, expires_at, seqno, written_at }))
+}
+
+/// Parses the value of a record with a `"type"`, as `SsTable::dump` writes it.
+fn parse_typed_value(kind: &serde_json::Value, value: Option<&serde_json::Value>) -> Result<Value, String> {
+    use serde_json::Value as Json;
+
+    let value = value.ok_or_else(|| "missing \"value\"".to_string())?;
+    let invalid = |name: &str| format!("\"value\" must be {name} for \"type\" {kind}");
+    match kind.as_str() {
+        Some("int") => value.as_i64().map(Value::Int).ok_or_else(|| invalid("an integer")),
+        Some("float") => match value {
+            Json::String(text) if text == "NaN" => Ok(Value::Float(f64::NAN)),
+            Json::String(text) if text == "inf" => Ok(Value::Float(f64::INFINITY)),
+            Json::String(text) if text == "-inf" => Ok(Value::Float(f64::NEG_INFINITY)),
+            value => value.as_f64().map(Value::Float).ok_or_else(|| invalid("a number")),
+        },
+        Some("str") => value.as_str().map(|text| Value::Str(text.to_string())).ok_or_else(|| invalid("a string")),
+        Some("bool") => value.as_bool().map(Value::Bool).ok_or_else(|| invalid("a boolean")),
+        _ => Err("\"type\" must be \"int\", \"float\", \"str\" or \"bool\"".to_string()),
+    }
+}
+
+/// Reads RFC 4180 style records: comma separated fields, optionally quoted, where a quoted
+/// field may hold commas, newlines and quotes doubled as `""`.
+struct CsvReader<R> {
+    reader: R,
+    /// Lines read so far
+    line: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    /// Returns the next record with the line it starts on, skipping blank lines.
+    fn next_record(&mut self) -> Option<io::Result<(usize, Vec<String>)>> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            let start = self.line + 1;
+            match self.read_line(&mut text) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => return Some(Err(err)),
+            }
+            if text.trim_end_matches(['\r', '\n']).is_empty() {
+                continue;
+            }
+            // A quoted field may span lines, so read on until its closing quote
+            while text.bytes().filter(|&byte| byte == b'"').count() % 2 == 1 {
+                match self.read_line(&mut text) {
+                    Ok(0) => return Some(Err(line_error(start, "unterminated quoted field"))),
+                    Ok(_) => {}
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            let fields = parse_csv_record(&text).map_err(|reason| line_error(start, reason));
+            return Some(fields.map(|fields| (start, fields)));
+        }
+    }
+
+    fn read_line(&mut self, text: &mut String) -> io::Result<usize> {
+        let read = self.reader.read_line(text).map_err(|err| at_line(self.line + 1, err))?;
+        if read > 0 {
+            self.line += 1;
+        }
+        Ok(read)
+    }
+}
+
+fn parse_csv_record(text: &str) -> Result<Vec<String>, String> {
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    let text = text.strip_suffix('\r').unwrap_or(text);
+    let mut fields = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("unterminated quoted field".to_string()),
+                }
+            }
+            match chars.next() {
+                None => {
+                    fields.push(field);
+                    return Ok(fields);
+                }
+                Some(',') => fields.push(field),
+                Some(c) => return Err(format!("unexpected {c:?} after a quoted field")),
+            }
+        } else {
+            loop {
+                match chars.next() {
+                    None => {
+                        fields.push(field);
+                        return Ok(fields);
+                    }
+                    Some(',') => break,
+                    Some('"') => return Err("unexpected quote in an unquoted field".to_string()),
+                    Some(c) => field.push(c),
+                }
+            }
+            fields.push(field);
+        }
+    }
+}
+
+/// Returns an `InvalidData` error for malformed input on line `line_no`.
+fn line_error(line_no: usize, reason: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {line_no}: {reason}"))
+}
+
+/// Prefixes `err` with the line it was raised for, keeping its kind.
+fn at_line(line_no: usize, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("line {line_no}: {err}"))
+}
@@ -0,0 +1,152 @@
+use std::io::{self, Read};
+
+use crate::storage::sstable::DATA_START;
+use crate::storage::sstable::block::{self, BlockHandle};
+
+/// Value of the `snaildb.index_type` property of tables with a partitioned index.
+pub(crate) const PARTITIONED: &str = "partitioned";
+
+/// The index of a table, locating every data block by its first key.
+///
+/// A flat index is a single list of block handles held in memory. A partitioned index splits
+/// that list into index partitions stored after the data blocks, and only keeps the top-level
+/// index over the partitions in memory, so a lookup reads one partition and then one block.
+/// The `snaildb.index_type` property tells the two apart, tables without it have a flat index.
+#[derive(Clone, Debug)]
+pub(crate) enum Index {
+    Flat(Vec<BlockHandle>),
+    Partitioned(Vec<Partition>),
+}
+
+/// An entry of the top-level index of a partitioned index.
+#[derive(Clone, Debug)]
+pub(crate) struct Partition {
+    /// the first key of the first block the partition covers, and where the partition is stored
+    pub(crate) handle: BlockHandle,
+    /// the number of the first block the partition covers
+    pub(crate) first_block: usize,
+    /// how many blocks the partition covers
+    pub(crate) block_count: usize,
+}
+
+impl Index {
+    /// Returns the number of data blocks.
+    pub(crate) fn block_count(&self) -> usize {
+        match self {
+            Index::Flat(handles) => handles.len(),
+            Index::Partitioned(partitions) => partitions.last().map_or(0, |last| last.first_block + last.block_count),
+        }
+    }
+
+    /// Returns the offset right after the last data block. Index partitions follow the blocks.
+    pub(crate) fn data_end(&self) -> u64 {
+        match self {
+            Index::Flat(handles) => handles
+                .last()
+                .map_or(DATA_START, |handle| handle.offset.saturating_add(handle.len)),
+            Index::Partitioned(partitions) => partitions.first().map_or(DATA_START, |first| first.handle.offset),
+        }
+    }
+
+    /// Encodes the index section: the flat index as written by `block::encode_index`, or the
+    /// top-level index as a sequence of `[key_len:4][first_key][offset:8][len:8][block_count:4]`.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        match self {
+            Index::Flat(handles) => block::encode_index(handles),
+            Index::Partitioned(partitions) => {
+                let mut buffer = Vec::new();
+                for partition in partitions {
+                    buffer.extend_from_slice(&block::encode_index(std::slice::from_ref(&partition.handle)));
+                    buffer.extend_from_slice(&(partition.block_count as u32).to_le_bytes());
+                }
+                buffer
+            }
+        }
+    }
+
+    /// Decodes the index section written by `encode`.
+    pub(crate) fn decode(buffer: &[u8], partitioned: bool) -> io::Result<Self> {
+        if !partitioned {
+            return block::decode_index(buffer).map(Index::Flat);
+        }
+        let mut buffer = buffer;
+        let mut partitions = Vec::new();
+        let mut first_block = 0;
+        while !buffer.is_empty() {
+            let key_len = read_u32(&mut buffer)? as usize;
+            if key_len > buffer.len() {
+                return Err(truncated());
+            }
+            let (first_key, rest) = buffer.split_at(key_len);
+            buffer = rest;
+            let handle = BlockHandle {
+                first_key: first_key.to_vec(),
+                offset: read_u64(&mut buffer)?,
+                len: read_u64(&mut buffer)?,
+            };
+            let block_count = read_u32(&mut buffer)? as usize;
+            if block_count == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "sstable index partition covers no blocks"));
+            }
+            partitions.push(Partition { handle, first_block, block_count });
+            first_block += block_count;
+        }
+        Ok(Index::Partitioned(partitions))
+    }
+}
+
+/// Returns the partition holding the handle of block `block`.
+pub(crate) fn partition_of(partitions: &[Partition], block: usize) -> usize {
+    partitions.partition_point(|partition| partition.first_block <= block).saturating_sub(1)
+}
+
+/// Decodes an index partition, which must hold the handles of the blocks it covers.
+pub(crate) fn decode_partition(buffer: &[u8], partition: &Partition) -> io::Result<Vec<BlockHandle>> {
+    let handles = block::decode_index(buffer)?;
+    if handles.len() != partition.block_count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "sstable index partition at {} holds {} blocks instead of {}",
+                partition.handle.offset,
+                handles.len(),
+                partition.block_count
+            ),
+        ));
+    }
+    Ok(handles)
+}
+
+/// Splits the handles into groups whose encoded size reaches `partition_size` bytes, the last
+/// one possibly smaller. Each group becomes an index partition.
+pub(crate) fn split(handles: &[BlockHandle], partition_size: usize) -> Vec<&[BlockHandle]> {
+    let mut groups = Vec::new();
+    let (mut start, mut size) = (0, 0);
+    for (i, handle) in handles.iter().enumerate() {
+        size += 4 + handle.first_key.len() + 16;
+        if size >= partition_size {
+            groups.push(&handles[start..=i]);
+            (start, size) = (i + 1, 0);
+        }
+    }
+    if start < handles.len() {
+        groups.push(&handles[start..]);
+    }
+    groups
+}
+
+fn read_u32(buffer: &mut &[u8]) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    buffer.read_exact(&mut buf).map_err(|_| truncated())?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(buffer: &mut &[u8]) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    buffer.read_exact(&mut buf).map_err(|_| truncated())?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "sstable top-level index truncated")
+}
@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+use std::io;
+use std::ops::Bound;
+use std::slice;
+
+use crate::storage::sstable::block::BlockEntries;
+use crate::storage::sstable::{Comparator, Entry, RangeTombstone, SsTable, TableData};
+use crate::utils::value::Value;
+
+/// Iterator over the entries of an SSTable in ascending key order, tombstones included.
+/// Keys are ordered by the comparator the table was written with.
+/// Created by `SsTable::iter` and `SsTable::range`.
+///
+/// Loaded tables are walked in memory. Lazily opened tables decode one block at a
+/// time from whichever end is being consumed, so memory stays bounded by a couple of blocks.
+/// The records of a block are decoded in place, and the key and value of an entry are only
+/// copied out of the block when the entry is yielded.
+/// Entries expired at the time the iterator was created are yielded as tombstones, and
+/// entries deleted by a range tombstone of the table are skipped.
+/// Created by `SsTable::range_at`, it reads the table as of a sequence number instead.
+pub struct Iter<'a> {
+    entries: Entries<'a>,
+    /// the time expiries are compared with, read from the table clock once
+    now: u64,
+    /// the sequence number read as of: the entries and range tombstones written after it are
+    /// left out
+    seqno: u64,
+    range_tombstones: &'a [RangeTombstone],
+    comparator: &'a dyn Comparator,
+}
+
+/// The raw entries behind an `Iter`, with their expiries. Created by `SsTable::entries`.
+pub struct Entries<'a> {
+    source: Source<'a>,
+}
+
+enum Source<'a> {
+    Memory(slice::Iter<'a, Entry>),
+    Disk(DiskIter<'a>),
+}
+
+/// Walks the blocks of an on-disk table from both ends. Blocks in
+/// `next_block..end_block` have not been read yet, and every entry buffered in
+/// `front` sorts before them while every entry in `back` sorts after them.
+/// Entries of each decoded block outside `start..end` are dropped, which only
+/// trims the first and last block of a range.
+struct DiskIter<'a> {
+    table: &'a SsTable,
+    next_block: usize,
+    end_block: usize,
+    front: BlockEntries,
+    back: BlockEntries,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    /// an error finding the blocks of the range, returned by the first call to `next`
+    error: Option<io::Error>,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(table: &'a SsTable) -> Self {
+        Self::range(table, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    pub(crate) fn range(table: &'a SsTable, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self {
+        Self::range_at(table, start, end, u64::MAX)
+    }
+
+    pub(crate) fn range_at(table: &'a SsTable, start: Bound<&[u8]>, end: Bound<&[u8]>, seqno: u64) -> Self {
+        Self {
+            entries: Entries::range(table, start, end),
+            now: (table.clock)(),
+            seqno,
+            range_tombstones: table.range_tombstones(),
+            comparator: table.comparator(),
+        }
+    }
+
+    /// Returns true if `entry` is left out: written after the sequence number read as of, or
+    /// deleted by a range tombstone of the table written before it.
+    fn is_skipped(&self, entry: &Entry) -> bool {
+        entry.seqno > self.seqno
+            || self.range_tombstones.iter().any(|tombstone| {
+                tombstone.seqno <= self.seqno && tombstone.covers(&entry.key, entry.seqno, self.comparator)
+            })
+    }
+}
+
+impl<'a> Entries<'a> {
+    pub(crate) fn range(table: &'a SsTable, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self {
+        let empty = Self { source: Source::Memory([].iter()) };
+        if !overlaps_table(table, start, end) {
+            return empty;
+        }
+
+        let comparator = table.comparator();
+        let source = match &table.data {
+            TableData::Loaded(entries) => {
+                let lo = entries.partition_point(|entry| before_start(&entry.key, start, comparator));
+                let hi = entries.partition_point(|entry| !after_end(&entry.key, end, comparator));
+                if lo >= hi {
+                    return empty;
+                }
+                Source::Memory(entries[lo..hi].iter())
+            }
+            _ => {
+                // An index partition that cannot be read is reported by the first `next`
+                let (next_block, end_block, error) = match block_range(table, start, end) {
+                    Ok(Some((next_block, end_block))) => (next_block, end_block, None),
+                    Ok(None) => return empty,
+                    Err(err) => (0, 0, Some(err)),
+                };
+                Source::Disk(DiskIter {
+                    table,
+                    next_block,
+                    end_block,
+                    error,
+                    front: BlockEntries::default(),
+                    back: BlockEntries::default(),
+                    start: start.map(<[u8]>::to_vec),
+                    end: end.map(<[u8]>::to_vec),
+                })
+            }
+        };
+        Self { source }
+    }
+}
+
+/// Returns the numbers of the blocks that can hold keys inside the bounds, as `next_block..end_block`,
+/// or `None` if the end bound sorts before every block.
+fn block_range(table: &SsTable, start: Bound<&[u8]>, end: Bound<&[u8]>) -> io::Result<Option<(usize, usize)>> {
+    let find_block = |key| table.find_block(key).map(|found| found.map(|(idx, _)| idx));
+    let next_block = match start {
+        Bound::Included(key) | Bound::Excluded(key) => find_block(key)?.unwrap_or(0),
+        Bound::Unbounded => 0,
+    };
+    let end_block = match end {
+        Bound::Included(key) | Bound::Excluded(key) => match find_block(key)? {
+            Some(idx) => idx + 1,
+            None => return Ok(None),
+        },
+        Bound::Unbounded => table.block_count(),
+    };
+    Ok(Some((next_block, end_block)))
+}
+
+/// Returns true if `key` sorts before the start bound.
+fn before_start(key: &[u8], start: Bound<&[u8]>, comparator: &dyn Comparator) -> bool {
+    match start {
+        Bound::Included(start) => comparator.cmp(key, start) == Ordering::Less,
+        Bound::Excluded(start) => comparator.cmp(key, start) != Ordering::Greater,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Returns true if `key` sorts after the end bound.
+fn after_end(key: &[u8], end: Bound<&[u8]>, comparator: &dyn Comparator) -> bool {
+    match end {
+        Bound::Included(end) => comparator.cmp(key, end) == Ordering::Greater,
+        Bound::Excluded(end) => comparator.cmp(key, end) != Ordering::Less,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Returns false when the bounds cannot match any key in `[min_key, max_key]`.
+fn overlaps_table(table: &SsTable, start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    let comparator = table.comparator();
+    !after_end(&table.metadata.min_key, end, comparator) && !before_start(&table.metadata.max_key, start, comparator)
+}
+
+impl DiskIter<'_> {
+    fn read_block(&mut self, idx: usize) -> io::Result<BlockEntries> {
+        let bytes = self.table.read_block_bytes(&self.table.block_handle(idx)?)?;
+        let (start, end) = (self.start.as_ref().map(Vec::as_slice), self.end.as_ref().map(Vec::as_slice));
+        let comparator = self.table.comparator();
+        BlockEntries::new(bytes, |key| !before_start(key, start, comparator) && !after_end(key, end, comparator))
+    }
+
+    /// Stops the iteration after an error so a broken block, or entry, is reported once.
+    fn fail(&mut self, err: io::Error) -> Option<io::Result<Entry>> {
+        self.next_block = self.end_block;
+        self.front = BlockEntries::default();
+        self.back = BlockEntries::default();
+        Some(Err(err))
+    }
+
+    fn next(&mut self) -> Option<io::Result<Entry>> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        while self.front.is_empty() && self.next_block < self.end_block {
+            match self.read_block(self.next_block) {
+                Ok(entries) => self.front = entries,
+                Err(err) => return self.fail(err),
+            }
+            self.next_block += 1;
+        }
+        match self.front.pop_front().or_else(|| self.back.pop_front())? {
+            Ok(entry) => Some(Ok(entry)),
+            Err(err) => self.fail(err),
+        }
+    }
+
+    fn next_back(&mut self) -> Option<io::Result<Entry>> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        while self.back.is_empty() && self.next_block < self.end_block {
+            match self.read_block(self.end_block - 1) {
+                Ok(entries) => self.back = entries,
+                Err(err) => return self.fail(err),
+            }
+            self.end_block -= 1;
+        }
+        match self.back.pop_back().or_else(|| self.front.pop_back())? {
+            Ok(entry) => Some(Ok(entry)),
+            Err(err) => self.fail(err),
+        }
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Memory(entries) => entries.next().cloned().map(Ok),
+            Source::Disk(disk) => disk.next(),
+        }
+    }
+}
+
+impl DoubleEndedIterator for Entries<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Memory(entries) => entries.next_back().cloned().map(Ok),
+            Source::Disk(disk) => disk.next_back(),
+        }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = io::Result<(Vec<u8>, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = self.now;
+        loop {
+            match self.entries.next()? {
+                Ok(entry) if self.is_skipped(&entry) => continue,
+                item => return Some(item.map(|entry| entry.into_pair_at(now))),
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let now = self.now;
+        loop {
+            match self.entries.next_back()? {
+                Ok(entry) if self.is_skipped(&entry) => continue,
+                item => return Some(item.map(|entry| entry.into_pair_at(now))),
+            }
+        }
+    }
+}
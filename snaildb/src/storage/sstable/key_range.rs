@@ -0,0 +1,48 @@
+use crate::storage::sstable::SsTable;
+
+/// The inclusive `[min_key, max_key]` range of a table, for keeping a level of tables sorted.
+///
+/// Ranges order by `min_key` and then by `max_key`, comparing keys byte-wise whatever the
+/// comparator of the tables.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyRange {
+    min_key: Vec<u8>,
+    max_key: Vec<u8>,
+}
+
+impl KeyRange {
+    /// Returns the smallest key, inclusive.
+    pub fn min_key(&self) -> &[u8] {
+        &self.min_key
+    }
+
+    /// Returns the largest key, inclusive.
+    pub fn max_key(&self) -> &[u8] {
+        &self.max_key
+    }
+
+    /// Returns whether `key` is within the range.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.min_key.as_slice() <= key && key <= self.max_key.as_slice()
+    }
+
+    /// Returns whether the two ranges intersect, sharing a single boundary key included.
+    pub fn overlaps(&self, other: &KeyRange) -> bool {
+        self.min_key <= other.max_key && other.min_key <= self.max_key
+    }
+
+    /// Returns the smallest range holding both ranges.
+    pub fn cover(&self, other: &KeyRange) -> KeyRange {
+        KeyRange {
+            min_key: self.min_key.clone().min(other.min_key.clone()),
+            max_key: self.max_key.clone().max(other.max_key.clone()),
+        }
+    }
+}
+
+impl From<&SsTable> for KeyRange {
+    fn from(table: &SsTable) -> Self {
+        let (min_key, max_key) = table.key_range();
+        Self { min_key: min_key.to_vec(), max_key: max_key.to_vec() }
+    }
+}
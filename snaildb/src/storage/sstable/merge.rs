@@ -0,0 +1,168 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+
+use crate::storage::sstable::{Comparator, Entry, RangeTombstone, SsTable, iter::Entries};
+use crate::utils::value::{MergeOperator, Value};
+
+/// K-way merge over several table iterators, yielding each key once in ascending order of
+/// the comparator the tables were written with.
+///
+/// When the same key appears in several sources, the entry with the highest sequence number
+/// wins and the others are skipped. Sources are ordered oldest to newest, so among entries
+/// with the same sequence number the one from the source with the highest index wins.
+/// Tombstones are yielded like any other value, and entries keep their expiries.
+///
+/// Merge operands are stacked onto the older versions of their key instead of hiding them,
+/// see `Value::stack_onto`: the operands of several sources are collected into one entry, and
+/// combined with the merge function into a value once they reach a value or a tombstone.
+///
+/// Entries deleted by a range tombstone of the sources are skipped, and merge operands reaching
+/// one are stacked onto it as onto a tombstone. A range tombstone deletes the entries with a
+/// lower sequence number and, among equal ones, those from older sources, like a tombstone
+/// written with its sequence number in its source would. The range tombstones themselves are
+/// not yielded.
+pub(crate) struct MergeIter<'a> {
+    sources: Vec<Entries<'a>>,
+    /// the range tombstones of the sources, with the index of their source
+    range_tombstones: Vec<(&'a RangeTombstone, usize)>,
+    comparator: &'a dyn Comparator,
+    merge: Option<&'a dyn MergeOperator>,
+    /// the time entry expiries are compared with, an expired value is stacked onto as a tombstone
+    now: u64,
+    heap: BinaryHeap<HeapEntry<'a>>,
+    /// an error hit while refilling the heap, reported on the next call
+    pending_error: Option<io::Error>,
+}
+
+/// The current head of one source. The heap is a max-heap, so the key ordering is reversed
+/// to pop the smallest key first and, among equal keys, the highest sequence number and then
+/// the newest source first.
+struct HeapEntry<'a> {
+    entry: Entry,
+    source: usize,
+    comparator: &'a dyn Comparator,
+}
+
+impl Ord for HeapEntry<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator
+            .cmp(&other.entry.key, &self.entry.key)
+            .then(self.entry.seqno.cmp(&other.entry.seqno))
+            .then(self.source.cmp(&other.source))
+    }
+}
+
+impl PartialOrd for HeapEntry<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry<'_> {}
+
+impl<'a> MergeIter<'a> {
+    /// Merges `tables`, which must all be ordered by `comparator`, combining merge operands
+    /// with `merge`.
+    pub(crate) fn new(
+        tables: &'a [SsTable],
+        comparator: &'a dyn Comparator,
+        merge: Option<&'a dyn MergeOperator>,
+        now: u64,
+    ) -> Self {
+        let mut merge = Self {
+            comparator,
+            merge,
+            now,
+            sources: tables.iter().map(SsTable::entries).collect(),
+            range_tombstones: tables
+                .iter()
+                .enumerate()
+                .flat_map(|(source, table)| table.range_tombstones().iter().map(move |tombstone| (tombstone, source)))
+                .collect(),
+            heap: BinaryHeap::with_capacity(tables.len()),
+            pending_error: None,
+        };
+        for source in 0..merge.sources.len() {
+            merge.advance(source);
+        }
+        merge
+    }
+
+    /// Returns true if a range tombstone deletes `entry` of `source`.
+    fn is_range_deleted(&self, entry: &Entry, source: usize) -> bool {
+        self.range_tombstones.iter().any(|&(tombstone, tombstone_source)| {
+            (tombstone.seqno, tombstone_source) > (entry.seqno, source)
+                && tombstone.contains(&entry.key, self.comparator)
+        })
+    }
+
+    /// Pushes the next entry of `source` onto the heap, if any.
+    fn advance(&mut self, source: usize) {
+        match self.sources[source].next() {
+            Some(Ok(entry)) => self.heap.push(HeapEntry { entry, source, comparator: self.comparator }),
+            Some(Err(err)) if self.pending_error.is_none() => self.pending_error = Some(err),
+            Some(Err(_)) | None => {}
+        }
+    }
+
+    /// Skips the older versions of the key of `entry`, the newest version, stacking merge
+    /// operands onto them, and returns the entry they make.
+    fn next_version(&mut self, mut entry: Entry) -> io::Result<Entry> {
+        // Skip the older versions of the same key, stacking merge operands onto them
+        while self.heap.peek().is_some_and(|head| head.entry.key == entry.key) {
+            let shadowed = self.heap.pop().expect("peeked entry");
+            self.advance(shadowed.source);
+            if matches!(entry.value, Value::Merge(_)) {
+                let older = if self.is_range_deleted(&shadowed.entry, shadowed.source) {
+                    Value::Deleted
+                } else {
+                    shadowed.entry.value_at(self.now)
+                };
+                match std::mem::replace(&mut entry.value, Value::Deleted).stack_onto(&entry.key, older, self.merge) {
+                    Ok(value) => entry.value = value,
+                    Err(err) => {
+                        self.heap.clear();
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        if let Some(err) = self.pending_error.take() {
+            self.heap.clear();
+            return Err(err);
+        }
+        Ok(entry)
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(err) = self.pending_error.take() {
+                self.heap.clear();
+                return Some(Err(err));
+            }
+            let newest = self.heap.pop()?;
+            self.advance(newest.source);
+            // The older versions sort after the newest one, so a range tombstone deleting it deletes them too
+            let mut entry = newest.entry;
+            let deleted = self.is_range_deleted(&entry, newest.source);
+            if deleted {
+                entry.value = Value::Deleted;
+            }
+            match self.next_version(entry) {
+                Ok(_) if deleted => continue,
+                item => return Some(item),
+            }
+        }
+    }
+}
@@ -0,0 +1,1698 @@
+pub mod block;
+pub mod cache;
+pub mod compaction_filter;
+pub mod comparator;
+pub mod compression;
+pub mod dump;
+#[cfg(feature = "import")]
+pub mod import;
+pub(crate) mod index;
+pub mod iter;
+pub mod key_range;
+pub mod merge;
+pub mod options;
+mod positioned;
+pub mod prefix;
+pub mod properties;
+pub mod range_del;
+pub mod stats;
+pub mod verify;
+pub mod writer;
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crc32fast::Hasher;
+
+use crate::storage::bloom_filter::BloomFilter;
+use crate::storage::sstable::index::Index;
+use crate::storage::sstable::positioned::PositionedReader;
+use crate::utils::record::{RecordKind, encode_record_into};
+use crate::utils::stream::{ValueReader, read_record_header};
+use crate::utils::value::Value;
+
+pub use block::{BlockHandle, DEFAULT_BLOCK_SIZE};
+pub use cache::BlockCache;
+pub use compaction_filter::{CompactionFilter, FilterDecision};
+pub use comparator::{BytewiseComparator, Comparator, ReverseBytewiseComparator};
+pub use compression::Compression;
+pub use dump::DumpFormat;
+#[cfg(feature = "import")]
+pub use import::ImportOptions;
+pub use iter::{Entries, Iter};
+pub use key_range::KeyRange;
+pub use options::{MergeOptions, SsTableOptions};
+pub use positioned::ValueSource;
+pub use prefix::PrefixExtractor;
+pub use range_del::RangeTombstone;
+pub use stats::Stats;
+pub use verify::{VerifyError, VerifyOptions, VerifyReport};
+pub use writer::SsTableWriter;
+
+/// An entry as stored in a table, yielded by `SsTable::entries`.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// the key of the entry
+    key: Vec<u8>,
+    /// the value of the entry
+    value: Value,
+    /// when the entry expires, in milliseconds since the UNIX epoch, see `SsTableWriter::add_with_expiry`
+    expires_at: Option<u64>,
+    /// the sequence number of the write, 0 for entries written without one
+    seqno: u64,
+    /// when the entry was written, in milliseconds since the UNIX epoch, 0 if unknown, see
+    /// `SsTableOptions::with_write_clock`
+    written_at: u64,
+}
+
+impl Entry {
+    /// Returns the key.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the value as written, a tombstone for a delete. Expiry is not applied.
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Returns when the entry expires, in milliseconds since the UNIX epoch, if it does.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Returns the sequence number of the write, 0 for entries written without one.
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    /// Returns when the entry was written, in milliseconds since the UNIX epoch, `None` for
+    /// entries written without a timestamp.
+    pub fn written_at(&self) -> Option<u64> {
+        (self.written_at != 0).then_some(self.written_at)
+    }
+
+    /// Returns the metadata of the entry, see `SsTable::get_with_metadata`.
+    fn meta(&self) -> RecordMeta {
+        RecordMeta { seqno: self.seqno, written_at: self.written_at(), expires_at: self.expires_at }
+    }
+
+    /// Returns true if the entry expired at or before `now`.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Returns the value as read at `now`. An expired entry reads as a tombstone, so it keeps
+    /// shadowing the older versions of its key in older tables.
+    fn value_at(&self, now: u64) -> Value {
+        if self.is_expired(now) { Value::Deleted } else { self.value.clone() }
+    }
+
+    /// Splits the entry into its key and its value at `now`, see `value_at`.
+    fn into_pair_at(self, now: u64) -> (Vec<u8>, Value) {
+        let value = if self.is_expired(now) { Value::Deleted } else { self.value };
+        (self.key, value)
+    }
+}
+
+/// What a table records about the version of a key besides its value, returned by
+/// `SsTable::get_with_metadata`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// the sequence number of the write, 0 if it has none
+    pub seqno: u64,
+    /// when the version was written, in milliseconds since the UNIX epoch, `None` if the table
+    /// was written without a write clock or before timestamps existed
+    pub written_at: Option<u64>,
+    /// when the value expires, in milliseconds since the UNIX epoch, if it does
+    pub expires_at: Option<u64>,
+}
+
+/// An entry accepted by `SsTable::create`: a `(key, value)` pair, written with sequence
+/// number 0, or a `(key, value, seqno)` triple.
+pub trait TableEntry {
+    fn key(&self) -> &[u8];
+    fn value(&self) -> &Value;
+    fn seqno(&self) -> u64 {
+        0
+    }
+}
+
+impl<K: AsRef<[u8]>> TableEntry for (K, Value) {
+    fn key(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    fn value(&self) -> &Value {
+        &self.1
+    }
+}
+
+impl<K: AsRef<[u8]>> TableEntry for (K, Value, u64) {
+    fn key(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    fn value(&self) -> &Value {
+        &self.1
+    }
+
+    fn seqno(&self) -> u64 {
+        self.2
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SsTableMetadata {
+    /// the path to the sstable file
+    path: PathBuf,
+    /// the minimum key in the sstable
+    min_key: Vec<u8>,
+    /// the maximum key in the sstable
+    max_key: Vec<u8>,
+    /// the bloom filter for the sstable
+    pub bloom_filter: BloomFilter,
+    /// the sparse index mapping the first key of each data block to its location
+    index: Index,
+    /// counters describing the contents, see `SsTable::stats`
+    stats: Stats,
+    /// the format version the file was written with
+    format_version: u16,
+    /// built-in and user-defined properties, see `SsTable::properties`
+    properties: BTreeMap<String, String>,
+    /// the prefix extractor and the bloom filter over the prefixes it returned, if one was built
+    prefix_filter: Option<(PrefixExtractor, BloomFilter)>,
+    /// the range tombstones of the table, ordered by start key
+    range_tombstones: Vec<RangeTombstone>,
+    /// the order of the keys, checked against the `snaildb.comparator` property on open
+    comparator: Arc<dyn Comparator>,
+}
+
+#[derive(Debug)]
+pub struct SsTable {
+    pub metadata: SsTableMetadata,
+    /// Where reads are served from, see `TableData`.
+    data: TableData,
+    /// The shared cache consulted by `get` and the id of this table in it.
+    block_cache: Option<(Arc<BlockCache>, u64)>,
+    /// The current time in milliseconds since the UNIX epoch, compared with entry expiries.
+    clock: fn() -> u64,
+    /// The index partition read last and its number, so walking the blocks of a table with a
+    /// partitioned index reads each partition once.
+    last_partition: Mutex<Option<(usize, Arc<[BlockHandle]>)>>,
+}
+
+/// The ways a table can serve reads. All are backed by the same file format.
+#[derive(Debug)]
+enum TableData {
+    /// Every entry is in memory, produced by `load`.
+    Loaded(Vec<Entry>),
+    /// Only the metadata is in memory, reads go into the file through the sparse index. They
+    /// are positioned reads, so concurrent readers do not share a cursor.
+    OnDisk { file: File },
+    /// The file is memory mapped and blocks are parsed straight from the mapping, produced by `open_mmap`.
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+/// The footer at the end of the file:
+/// [min_key_len:4][min_key:var][max_key_len:4][max_key:var]
+/// [filter_offset:8][filter_len:8][index_offset:8][index_len:8][stats_offset:8][stats_len:8]
+/// [properties_offset:8][properties_len:8][prefix_filter_offset:8][prefix_filter_len:8]
+/// [range_del_offset:8][range_del_len:8][footer_crc:4][footer_offset:8][version:2][magic:8]
+///
+/// Each version added a section: version 1 files stop after index_len, version 2 files
+/// after stats_len, version 3 files after properties_len and files before version 9 after
+/// prefix_filter_len. The footer_crc was added in version 6 and covers the footer up to it
+/// together with footer_offset and version.
+struct Footer {
+    version: u16,
+    file_size: u64,
+    min_key: Vec<u8>,
+    max_key: Vec<u8>,
+    filter_offset: u64,
+    filter_len: u64,
+    index_offset: u64,
+    index_len: u64,
+    /// offset and length of the stats block, absent in version 1 files
+    stats: Option<(u64, u64)>,
+    /// offset and length of the properties block, absent before version 3
+    properties: Option<(u64, u64)>,
+    /// offset and length of the prefix filter block, absent before version 4
+    prefix_filter: Option<(u64, u64)>,
+    /// offset and length of the range deletion block, absent before version 9
+    range_deletions: Option<(u64, u64)>,
+}
+
+/// Returns how many `[offset:8][len:8]` section handles the footer of a format version holds.
+fn footer_section_count(version: u16) -> usize {
+    match version {
+        1 => 2,
+        2 => 3,
+        3 => 4,
+        4..=8 => 5,
+        _ => 6,
+    }
+}
+
+/// Identifies a file as a snailDB SSTable, stored in the last 8 bytes.
+const MAGIC: [u8; 8] = *b"SNAILSST";
+
+/// The format version written by this build. Files claiming a newer version are rejected.
+/// Version 5 records may carry sequence numbers, which older builds cannot decode, version 6
+/// footers end in a checksum, version 7 indexes may be partitioned, version 8 records may
+/// be merge operands, version 9 files have a range deletion block, version 10 records may
+/// carry the time they were written, version 11 records may hold typed values and version 12
+/// values may be compressed record by record, see `SsTableOptions::value_compression`.
+pub const FORMAT_VERSION: u16 = 12;
+
+/// Returns the current time in milliseconds since the UNIX epoch, the default clock entry
+/// expiries are compared with. See `SsTable::with_clock`.
+pub fn system_clock() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns the tables whose `[min_key, max_key]` range intersects the inclusive range
+/// `[start, end]`, in the order they appear in `tables`. A table touching the range at a
+/// single boundary key counts. Returns nothing when `start` is greater than `end`.
+pub fn overlapping_tables(tables: &[SsTable], start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Vec<&SsTable> {
+    let (start, end) = (start.as_ref(), end.as_ref());
+    tables.iter().filter(|table| table.overlaps_range(start, end)).collect()
+}
+
+/// Offset of the first data block, right after the [entry_count:4] header.
+const DATA_START: u64 = 4;
+
+/// Length of the trailer at the very end of the file: [footer_offset:8][version:2][magic:8].
+const TRAILER_LEN: u64 = 8 + 2 + MAGIC.len() as u64;
+
+/// Length of the [footer_crc:4] right before the trailer, present from version 6 on.
+const FOOTER_CRC_LEN: u64 = 4;
+
+/// The footer at the end of a file is damaged: the file is truncated, is not an sstable,
+/// fails the footer checksum, or records section locations that do not add up.
+///
+/// Returned wrapped in an `io::Error` of kind `InvalidData` by `open`, `load` and the other
+/// readers, where `io::Error::get_ref` and `downcast_ref` recover it.
+#[derive(Debug)]
+pub struct CorruptFooter {
+    reason: String,
+}
+
+impl CorruptFooter {
+    fn error(reason: impl Into<String>) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, CorruptFooter { reason: reason.into() })
+    }
+
+    /// Describes what is wrong with the footer.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl fmt::Display for CorruptFooter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupt sstable footer: {}", self.reason)
+    }
+}
+
+impl std::error::Error for CorruptFooter {}
+
+impl SsTable {
+    /// Writes the entries (sorted by key) to a new SSTable at `path` using the default options.
+    /// Entries are `(key, value)` pairs, or `(key, value, seqno)` triples, see `TableEntry`.
+    pub fn create<E: TableEntry>(
+        path: impl AsRef<Path>,
+        entries: impl IntoIterator<Item = E>,
+    ) -> io::Result<Self> {
+        Self::create_with_options(path, entries, &SsTableOptions::default())
+    }
+
+    /// Writes the entries (sorted by key) to a new SSTable at `path`.
+    /// This is a thin wrapper over `SsTableWriter`, the returned table is opened lazily.
+    ///
+    /// The table is written to `<path>.tmp` and renamed into place once complete, see
+    /// `SsTableWriter`. Fails with `InvalidInput` if there are no entries, the keys are not
+    /// strictly ascending or a key or value is over `options.record_limits`, in which case
+    /// `path` is left untouched and the temporary file removed.
+    pub fn create_with_options<E: TableEntry>(
+        path: impl AsRef<Path>,
+        entries: impl IntoIterator<Item = E>,
+        options: &SsTableOptions,
+    ) -> io::Result<Self> {
+        let mut writer = SsTableWriter::new(path, options)?;
+        entries
+            .into_iter()
+            .try_for_each(|entry| writer.add_with_seqno(entry.key(), entry.value(), entry.seqno()))?;
+        writer.finish()
+    }
+
+    /// Merges `inputs` into a new table at `output_path` using the default options, which
+    /// keep tombstones. See `merge_with_options`. Fails with `InvalidInput` without inputs.
+    /// The output is ordered by the comparator of the inputs.
+    pub fn merge(output_path: impl AsRef<Path>, inputs: &[SsTable]) -> io::Result<Self> {
+        let mut options = MergeOptions::default();
+        if let Some(first) = inputs.first() {
+            options.table_options.comparator = Arc::clone(&first.metadata.comparator);
+        }
+        Self::merge_with_options(output_path, inputs, &options)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no sstables to merge"))
+    }
+
+    /// Merges `inputs` into a new table at `output_path` with a k-way merge.
+    ///
+    /// For a key present in several tables the entry with the highest sequence number wins,
+    /// including tombstones shadowing older values. Inputs are ordered oldest to newest, so
+    /// between entries with the same sequence number, as in tables written without them, the
+    /// one from the table latest in the slice wins. When `drop_tombstones` is set, a winning
+    /// tombstone is left out of the output together with the older values it shadows. Entries
+    /// are streamed through `SsTableWriter`, so memory stays bounded by a block per input.
+    ///
+    /// A winning entry that expired by `MergeOptions::clock` loses its value: it is written as a
+    /// tombstone, or dropped entirely with `drop_tombstones`. Entries still alive keep their expiry.
+    ///
+    /// Merge operands do not hide the older versions of their key but are stacked onto them,
+    /// so a key written as operands in several inputs gets a single entry holding all of them.
+    /// Operands that reach a value or a tombstone are combined with it into a value by
+    /// `MergeOptions::merge_operator`, and so are the operands of a key with nothing older
+    /// when `drop_tombstones` is set. Without a merge function the former fails with
+    /// `InvalidInput` and the latter keeps the operands.
+    ///
+    /// Entries deleted by a range tombstone of the inputs are left out, see `range_tombstones`,
+    /// and merge operands reaching one are stacked onto it as onto a tombstone. The range
+    /// tombstones are written to the output to keep deleting the keys of older tables, unless
+    /// `drop_tombstones` is set, nothing being older then.
+    ///
+    /// Returns `Ok(None)` and writes no file when no entry or range tombstone survives the merge. Fails with
+    /// `InvalidInput` if the output is one of the inputs, or if an input is ordered by another
+    /// comparator than `MergeOptions::table_options`.
+    pub fn merge_with_options(
+        output_path: impl AsRef<Path>,
+        inputs: &[SsTable],
+        options: &MergeOptions,
+    ) -> io::Result<Option<Self>> {
+        Self::merge_with_filter(output_path, inputs, options, None)
+    }
+
+    /// Merges `inputs` like `merge_with_options` and, with a `filter`, passes every entry that
+    /// survives the merge through it before it is written. See `CompactionFilter`.
+    pub fn merge_with_filter(
+        output_path: impl AsRef<Path>,
+        inputs: &[SsTable],
+        options: &MergeOptions,
+        filter: Option<&mut dyn CompactionFilter>,
+    ) -> io::Result<Option<Self>> {
+        let output_path = output_path.as_ref();
+        check_merge_output(output_path, inputs)?;
+        check_merge_comparators(inputs, &options.table_options)?;
+
+        // A writer that is not finished removes its temporary file, so no partial or empty
+        // table is left behind
+        let mut writer = SsTableWriter::new(output_path, &options.table_options)?;
+        // the cast shortens the lifetime of the filter object to that of the inputs
+        merge_entries(inputs, options, filter.map(|filter| filter as _)).try_for_each(|item| writer.add_entry(&item?))?;
+        for tombstone in merged_range_tombstones(inputs, options) {
+            writer.add_range_tombstone(tombstone.clone());
+        }
+        if writer.is_empty() { Ok(None) } else { writer.finish().map(Some) }
+    }
+
+    /// Merges `inputs` like `merge_with_options`, but rolls over to a new output table once
+    /// the current one reaches `MergeOptions::target_file_size`. The outputs are named after
+    /// `output_path` with a sequence number before the extension, so `foo.sst` becomes
+    /// `foo.0.sst`, `foo.1.sst` and so on. Without a target size a single `foo.0.sst` is written.
+    ///
+    /// A table only ever ends between two entries, so the outputs hold disjoint key ranges
+    /// and are returned in key order. A range tombstone kept by the merge is written to every
+    /// output but those whose keys all sort at or after its end, so the ranges of the outputs
+    /// may overlap where they hold the same tombstone. Returns an empty `Vec` when no entry
+    /// or range tombstone survives the merge. On error every output written so far is removed.
+    pub fn merge_split(
+        output_path: impl AsRef<Path>,
+        inputs: &[SsTable],
+        options: &MergeOptions,
+    ) -> io::Result<Vec<Self>> {
+        let output_path = output_path.as_ref();
+        check_merge_comparators(inputs, &options.table_options)?;
+        let mut finished = Vec::new();
+        let result = write_split(output_path, inputs, options, &mut finished);
+        if result.is_err() {
+            for path in &finished {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        result
+    }
+
+    /// Builds a table at `path` from newline-delimited JSON, one object per line:
+    /// `{"key":"k","value":"v"}` for a value and `{"key":"k","deleted":true}` for a tombstone.
+    /// A value may carry an `"expires_at"` in milliseconds since the UNIX epoch, see
+    /// `SsTableWriter::add_with_expiry`, and any record a `"seqno"` and a `"written_at"`, see
+    /// `SsTable::get_with_metadata`. A typed value has a `"type"` as `DumpFormat::Json`
+    /// describes it, and cannot expire. The output of
+    /// `SsTable::dump` in `DumpFormat::Json` is accepted as well, including its `"kind"` field
+    /// and base64 flags, so a dump can be imported again. Blank lines are skipped.
+    ///
+    /// Without `ImportOptions::sort_memory_budget` the keys must be strictly ascending and the
+    /// import fails on the first one that is not. With it the input is sorted externally, and
+    /// for a repeated key the record with the highest sequence number wins, or the last one
+    /// among equal sequence numbers. Malformed records fail with `InvalidData`
+    /// naming their line number.
+    #[cfg(feature = "import")]
+    pub fn import_ndjson<R: Read>(reader: R, path: impl AsRef<Path>, options: &ImportOptions) -> io::Result<Self> {
+        import::import_ndjson(reader, path.as_ref(), options)
+    }
+
+    /// Builds a table at `path` from CSV like `import_ndjson`. The first row is a header that
+    /// must name a `key` and a `value` column, and may name a `deleted` column where `true`
+    /// or `1` writes a tombstone, an `expires_at` column, a `seqno` column and a `written_at`
+    /// column. Other columns are ignored.
+    #[cfg(feature = "import")]
+    pub fn import_csv<R: Read>(reader: R, path: impl AsRef<Path>, options: &ImportOptions) -> io::Result<Self> {
+        import::import_csv(reader, path.as_ref(), options)
+    }
+
+    /// Returns the temporary files left in `dir` by writers that never finished, for example
+    /// because the process crashed while writing a table. See `SsTableWriter`.
+    pub fn temp_files(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let is_temp = entry.file_name().to_string_lossy().ends_with(writer::TEMP_SUFFIX);
+            if is_temp && entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Removes the files `temp_files` returns and returns their paths. Must not run while a
+    /// writer in `dir` is still working.
+    pub fn remove_temp_files(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+        let paths = Self::temp_files(dir)?;
+        for path in &paths {
+            std::fs::remove_file(path)?;
+        }
+        Ok(paths)
+    }
+
+    /// Opens the SSTable lazily: only the footer, filter and sparse index are read,
+    /// and the file handle is kept so `get` can read straight from the block holding a key.
+    /// Memory usage is proportional to the index rather than the whole file.
+    ///
+    /// Blocks are read with positioned reads that leave the file cursor alone, so the table is
+    /// `Sync` and any number of threads can call `get` on it at once.
+    ///
+    /// Fails with `InvalidInput` if the table was written with another comparator than the
+    /// byte-wise one, see `open_with_comparator`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_comparator(path, Arc::new(BytewiseComparator))
+    }
+
+    /// Opens the SSTable lazily like `open`, for a table whose keys are ordered by `comparator`.
+    /// Fails with `InvalidInput` if the table records another comparator name.
+    pub fn open_with_comparator(path: impl AsRef<Path>, comparator: Arc<dyn Comparator>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+        let metadata = read_metadata(&mut file, path, comparator)?;
+
+        Ok(Self {
+            metadata,
+            data: TableData::OnDisk { file },
+            block_cache: None,
+            clock: system_clock,
+            last_partition: Mutex::new(None),
+        })
+    }
+
+    /// Opens the SSTable by memory mapping the whole file. Lookups and iteration parse blocks
+    /// directly from the mapping, leaving caching to the kernel page cache instead of
+    /// seeking and copying through a buffer for every block.
+    ///
+    /// The file must not be modified while it is mapped. snaildb never rewrites a finished
+    /// table, but truncating it from another process makes reads fault.
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_mmap_with_comparator(path, Arc::new(BytewiseComparator))
+    }
+
+    /// Memory maps the SSTable like `open_mmap`, for a table whose keys are ordered by `comparator`.
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap_with_comparator(path: impl AsRef<Path>, comparator: Arc<dyn Comparator>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        // SAFETY: finished SSTables are immutable, see the warning above
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let metadata = read_metadata(&mut io::Cursor::new(&map[..]), path, comparator)?;
+
+        Ok(Self {
+            metadata,
+            data: TableData::Mapped(map),
+            block_cache: None,
+            clock: system_clock,
+            last_partition: Mutex::new(None),
+        })
+    }
+
+    /// Loads only metadata (bloom filter, min/max keys, sparse index) without loading entries into memory.
+    /// This is the same as `open`.
+    pub fn load_metadata(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open(path)
+    }
+
+    /// Loads the full SSTable including all entries into memory.
+    /// Use this when you need to access entries directly.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_with_comparator(path, Arc::new(BytewiseComparator))
+    }
+
+    /// Loads the full SSTable like `load`, for a table whose keys are ordered by `comparator`.
+    pub fn load_with_comparator(path: impl AsRef<Path>, comparator: Arc<dyn Comparator>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        // Buffered so the many small reads of the footer do not each cost a syscall
+        let mut file = BufReader::new(File::open(&path)?);
+        let metadata = read_metadata(&mut file, path, comparator)?;
+
+        // Read the data section, and the index partitions after it, with a single read and
+        // decode every block listed in the index. The index is kept flat in memory.
+        let mut metadata = metadata;
+        let data_end = match &metadata.index {
+            Index::Flat(_) => metadata.index.data_end(),
+            Index::Partitioned(partitions) => partitions
+                .last()
+                .map_or(DATA_START, |last| last.handle.offset.saturating_add(last.handle.len)),
+        };
+        let data = read_section(file.get_mut(), 0, data_end, "data")?;
+        if let Index::Partitioned(partitions) = &metadata.index {
+            let mut handles = Vec::with_capacity(metadata.index.block_count());
+            for partition in partitions {
+                handles.extend(index::decode_partition(block::block_in(&data, &partition.handle)?, partition)?);
+            }
+            metadata.index = Index::Flat(handles);
+        }
+        let Index::Flat(handles) = &metadata.index else {
+            unreachable!("the index was flattened above");
+        };
+        let mut entries: Vec<Entry> = Vec::new();
+        for handle in handles {
+            entries.extend(block::decode_block(block::block_in(&data, handle)?)?);
+        }
+        // `get` binary searches the entries, so a file that is not sorted is rejected here
+        let comparator = &*metadata.comparator;
+        if let Some(pair) = entries
+            .windows(2)
+            .find(|pair| comparator.cmp(&pair[0].key, &pair[1].key) != Ordering::Less)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "sstable keys out of order: \"{}\" after \"{}\"",
+                    pair[1].key.escape_ascii(),
+                    pair[0].key.escape_ascii()
+                ),
+            ));
+        }
+
+        Ok(Self {
+            metadata,
+            data: TableData::Loaded(entries),
+            block_cache: None,
+            clock: system_clock,
+            last_partition: Mutex::new(None),
+        })
+    }
+
+    /// Walks the whole file at `path` and reports every integrity problem found.
+    /// See `verify_with_options`.
+    pub fn verify(path: impl AsRef<Path>) -> io::Result<VerifyReport> {
+        Self::verify_with_options(path, &VerifyOptions::default())
+    }
+
+    /// Walks the whole file at `path`, decoding every block so record checksums are checked,
+    /// and reports every problem instead of stopping at the first one, unless `fail_fast` is set.
+    ///
+    /// Besides the checksums it checks that the index describes contiguous blocks, that keys are
+    /// strictly ascending, inside the footer range and present in the bloom filter, and that the
+    /// entry count in the header and the min and max keys in the footer match the data.
+    /// Only failing to open the file is returned as an error, an unreadable footer is reported.
+    pub fn verify_with_options(path: impl AsRef<Path>, options: &VerifyOptions) -> io::Result<VerifyReport> {
+        verify::verify(path.as_ref(), options)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.metadata.path
+    }
+
+    /// Returns the size and entry counters of the table. They are read from the file when it is
+    /// opened, so this never touches the disk.
+    pub fn stats(&self) -> &Stats {
+        &self.metadata.stats
+    }
+
+    /// Returns the properties stored in the table: the user-defined ones passed through
+    /// `SsTableOptions::with_property` and the built-ins, whose names start with `snaildb.`.
+    /// Tables written before properties existed have none.
+    pub fn properties(&self) -> &BTreeMap<String, String> {
+        &self.metadata.properties
+    }
+
+    /// Returns when the table was written, if the file records it.
+    pub fn created_at(&self) -> Option<SystemTime> {
+        let millis = self.metadata.properties.get(properties::CREATED_AT)?.parse().ok()?;
+        UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+    }
+
+    /// Returns the format version the file was written with.
+    pub fn format_version(&self) -> u16 {
+        self.metadata.format_version
+    }
+
+    /// Returns the range tombstones of the table ordered by start key, see
+    /// `SsTableWriter::add_range_delete`. Tables written before format version 9 have none.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.metadata.range_tombstones
+    }
+
+    /// Returns the comparator the keys are ordered by.
+    pub fn comparator(&self) -> &dyn Comparator {
+        &*self.metadata.comparator
+    }
+
+    /// Returns the smallest and largest key in the table, both inclusive. See also `KeyRange`.
+    /// The range tombstones of the table count with their start and their end, so the range
+    /// covers the keys they delete.
+    pub fn key_range(&self) -> (&[u8], &[u8]) {
+        (&self.metadata.min_key, &self.metadata.max_key)
+    }
+
+    /// Returns the smallest key in the table.
+    pub fn min_key(&self) -> &[u8] {
+        &self.metadata.min_key
+    }
+
+    /// Returns the largest key in the table.
+    pub fn max_key(&self) -> &[u8] {
+        &self.metadata.max_key
+    }
+
+    /// Returns the number of entries, tombstones included, from the stats.
+    pub fn len(&self) -> u64 {
+        self.metadata.stats.entries
+    }
+
+    /// Returns true if the table holds no entries. Tables are never written empty, so this
+    /// is only true of a table holding nothing but range tombstones.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the size of the file in bytes, as found when the table was written or opened.
+    pub fn file_size(&self) -> u64 {
+        self.metadata.stats.file_size
+    }
+
+    /// Returns whether the key ranges of the two tables intersect, sharing a single boundary
+    /// key included. It says nothing about whether any key is present in both.
+    pub fn overlaps(&self, other: &SsTable) -> bool {
+        let (min_key, max_key) = other.key_range();
+        self.overlaps_range(min_key, max_key)
+    }
+
+    fn overlaps_range(&self, start: &[u8], end: &[u8]) -> bool {
+        let at_or_before = |a: &[u8], b: &[u8]| self.comparator().cmp(a, b) != Ordering::Greater;
+        at_or_before(start, end)
+            && at_or_before(&self.metadata.min_key, end)
+            && at_or_before(start, &self.metadata.max_key)
+    }
+
+    /// Writes the contents of the table to `out` for inspection, see `DumpFormat`. Entries are
+    /// streamed block by block, so a lazily opened table is never loaded whole.
+    pub fn dump<W: Write>(&self, out: W, format: DumpFormat) -> io::Result<()> {
+        dump::dump(self, out, format)
+    }
+
+    /// Returns the number of data blocks in the table.
+    pub fn block_count(&self) -> usize {
+        self.metadata.index.block_count()
+    }
+
+    /// Returns the number of index partitions, 0 for tables with a flat index.
+    pub fn index_partition_count(&self) -> usize {
+        match &self.metadata.index {
+            Index::Flat(_) => 0,
+            Index::Partitioned(partitions) => partitions.len(),
+        }
+    }
+
+    /// Returns a key roughly `fraction` of the way through the table, for picking split points.
+    /// Fractions are clamped to `[0, 1]`, so 0 and below give `min_key` and 1 and above give
+    /// `max_key`. Returns `None` for NaN.
+    ///
+    /// Loaded tables pick the entry at that position by count. Lazily opened tables pick the
+    /// first key of the block at that byte offset in the data section, so no block is read.
+    /// Tables with a partitioned index pick the block at that position by count instead and
+    /// read the index partition holding it, returning `None` if it cannot be read.
+    /// Either way the key never decreases as the fraction grows.
+    pub fn approximate_key_at(&self, fraction: f64) -> Option<Vec<u8>> {
+        if fraction.is_nan() {
+            return None;
+        }
+        if fraction <= 0.0 {
+            return Some(self.metadata.min_key.clone());
+        }
+        if fraction >= 1.0 {
+            return Some(self.metadata.max_key.clone());
+        }
+        if let TableData::Loaded(entries) = &self.data {
+            let idx = (fraction * entries.len().saturating_sub(1) as f64) as usize;
+            return entries.get(idx).map(|entry| entry.key.clone());
+        }
+        match &self.metadata.index {
+            Index::Flat(handles) => {
+                let target = DATA_START + (fraction * (self.data_end() - DATA_START) as f64) as u64;
+                let idx = handles.partition_point(|handle| handle.offset <= target).checked_sub(1)?;
+                Some(handles[idx].first_key.clone())
+            }
+            Index::Partitioned(_) => {
+                let idx = (fraction * self.block_count().saturating_sub(1) as f64) as usize;
+                self.block_handle(idx).ok().map(|handle| handle.first_key)
+            }
+        }
+    }
+
+    /// Returns the approximate byte offset in the file where `key` is or would be stored: the
+    /// start of the block that can hold it, the start of the data section for keys before
+    /// `min_key` and its end for keys after `max_key`. The difference between the offsets of
+    /// two keys estimates the size of the range between them. Only the index is consulted,
+    /// for a partitioned index the partition covering the key. If it cannot be read the offset
+    /// is the start of the data section.
+    pub fn approximate_offset_of(&self, key: impl AsRef<[u8]>) -> u64 {
+        let key = key.as_ref();
+        if self.comparator().cmp(key, &self.metadata.max_key) == Ordering::Greater {
+            return self.data_end();
+        }
+        match self.find_block(key) {
+            Ok(Some((_, handle))) => handle.offset,
+            Ok(None) | Err(_) => DATA_START,
+        }
+    }
+
+    /// Returns the approximate bytes of the data blocks holding the keys from `start`, included,
+    /// to `end`, excluded, unbounded where `None`: the difference of their offsets, see
+    /// `approximate_offset_of`. Each bounded end is rounded down to the start of its block, so
+    /// the estimate is off by less than a block per end, and the sizes of adjacent ranges add up
+    /// to that of the range they cover.
+    pub fn approximate_size_of(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> u64 {
+        let start = start.map_or(DATA_START, |key| self.approximate_offset_of(key));
+        let end = end.map_or_else(|| self.data_end(), |key| self.approximate_offset_of(key));
+        end.saturating_sub(start)
+    }
+
+    /// Returns the offset right after the last data block.
+    fn data_end(&self) -> u64 {
+        self.metadata.index.data_end()
+    }
+
+    /// Returns the index partition `partition` of a partitioned index, from the last one read,
+    /// the block cache or the file.
+    fn index_partition(&self, partitions: &[index::Partition], partition: usize) -> io::Result<Arc<[BlockHandle]>> {
+        if let Some((last, handles)) = &*self.lock_last_partition() {
+            if *last == partition {
+                return Ok(Arc::clone(handles));
+            }
+        }
+        let entry = &partitions[partition];
+        let offset = entry.handle.offset;
+        let cached = self
+            .block_cache
+            .as_ref()
+            .and_then(|(cache, table_id)| cache.get_index_partition(*table_id, offset));
+        let handles = match cached {
+            Some(handles) => handles,
+            None => {
+                let handles: Arc<[BlockHandle]> =
+                    index::decode_partition(&self.read_block_bytes(&entry.handle)?, entry)?.into();
+                if let Some((cache, table_id)) = &self.block_cache {
+                    cache.insert_index_partition(*table_id, offset, Arc::clone(&handles));
+                }
+                handles
+            }
+        };
+        *self.lock_last_partition() = Some((partition, Arc::clone(&handles)));
+        Ok(handles)
+    }
+
+    fn lock_last_partition(&self) -> MutexGuard<'_, Option<(usize, Arc<[BlockHandle]>)>> {
+        // Only ever replaced whole, so a panic elsewhere cannot leave it inconsistent
+        self.last_partition.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns the handle of block `idx`, reading its index partition if the index is partitioned.
+    fn block_handle(&self, idx: usize) -> io::Result<BlockHandle> {
+        match &self.metadata.index {
+            Index::Flat(handles) => Ok(handles[idx].clone()),
+            Index::Partitioned(partitions) => {
+                let partition = index::partition_of(partitions, idx);
+                let handles = self.index_partition(partitions, partition)?;
+                Ok(handles[idx - partitions[partition].first_block].clone())
+            }
+        }
+    }
+
+    /// Returns the number and handle of the only block that can contain `key`, see `block::find_block`.
+    fn find_block(&self, key: &[u8]) -> io::Result<Option<(usize, BlockHandle)>> {
+        match &self.metadata.index {
+            Index::Flat(handles) => {
+                Ok(block::find_block(handles, key, self.comparator()).map(|idx| (idx, handles[idx].clone())))
+            }
+            Index::Partitioned(partitions) => {
+                let Some(partition) = partitions
+                    .partition_point(|partition| {
+                        self.comparator().cmp(&partition.handle.first_key, key) != Ordering::Greater
+                    })
+                    .checked_sub(1)
+                else {
+                    return Ok(None);
+                };
+                let handles = self.index_partition(partitions, partition)?;
+                let first_block = partitions[partition].first_block;
+                let found = block::find_block(&handles, key, self.comparator());
+                Ok(found.map(|idx| (first_block + idx, handles[idx].clone())))
+            }
+        }
+    }
+
+    /// Returns the handles of every data block in order.
+    pub(crate) fn block_handles(&self) -> io::Result<Vec<BlockHandle>> {
+        (0..self.block_count()).map(|idx| self.block_handle(idx)).collect()
+    }
+
+    /// Looks up a key. Keys are compared with the comparator of the table, byte-wise by default.
+    ///
+    /// An entry past its expiry reads as `Value::Deleted`, like a tombstone, so it still
+    /// shadows older tables while the database returns no value for it. Expiries are compared
+    /// with the clock set by `with_clock`. Merge operands read as `Value::Merge`, to be stacked
+    /// onto the value of the key in older tables, see `Value::stack_onto`. A key deleted by a
+    /// range tombstone of the table written after its entry, or by any if it has none, reads
+    /// as `Value::Deleted` too, see `range_tombstones`.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> io::Result<Option<Value>> {
+        Ok(self.get_with_metadata(key)?.map(|(value, _)| value))
+    }
+
+    /// Looks up a key like `get` and also returns the sequence number the entry was written
+    /// with, 0 if it has none, or that of the range tombstone that deleted it.
+    pub fn get_with_seqno(&self, key: impl AsRef<[u8]>) -> io::Result<Option<(Value, u64)>> {
+        Ok(self.get_with_metadata(key)?.map(|(value, meta)| (value, meta.seqno)))
+    }
+
+    /// Looks up a key like `get` and also returns the sequence number, timestamp and expiry the
+    /// entry was written with. A key deleted by a range tombstone gets the sequence number of
+    /// the tombstone and neither timestamp nor expiry.
+    pub fn get_with_metadata(&self, key: impl AsRef<[u8]>) -> io::Result<Option<(Value, RecordMeta)>> {
+        let key = key.as_ref();
+        let found = self.find_with_metadata(key)?;
+        Ok(self.mask_range_deleted(key, found))
+    }
+
+    /// Looks up a key like `get_with_seqno` as of sequence number `seqno`, for a snapshot: the
+    /// entry is left out if it was written after `seqno`, and so are the range tombstones.
+    pub fn get_at(&self, key: impl AsRef<[u8]>, seqno: u64) -> io::Result<Option<(Value, u64)>> {
+        let key = key.as_ref();
+        let found = self.find_with_metadata(key)?.map(|(value, meta)| (value, meta.seqno));
+        let found = found.filter(|(_, version)| *version <= seqno);
+        let deleted = self
+            .range_tombstones()
+            .iter()
+            .filter(|tombstone| tombstone.seqno <= seqno && tombstone.contains(key, self.comparator()))
+            .map(|tombstone| tombstone.seqno)
+            .max();
+        Ok(match (deleted, found) {
+            (Some(deleted), None) => Some((Value::Deleted, deleted)),
+            (Some(deleted), Some((_, version))) if deleted > version => Some((Value::Deleted, deleted)),
+            (_, found) => found,
+        })
+    }
+
+    /// Looks up the entry of a key, leaving range tombstones aside.
+    fn find_with_metadata(&self, key: &[u8]) -> io::Result<Option<(Value, RecordMeta)>> {
+        if !self.metadata.bloom_filter.may_contain(key) {
+            return Ok(None);
+        }
+        match &self.data {
+            TableData::Loaded(entries) => Ok(find_entry(entries, key, (self.clock)(), self.comparator())),
+            TableData::OnDisk { file } => self.lookup_from(&mut PositionedReader::new(file), key),
+            #[cfg(feature = "mmap")]
+            TableData::Mapped(_) => {
+                let Some((_, handle)) = self.find_block(key)? else {
+                    return Ok(None);
+                };
+                let entry = block::search_block(&self.read_block_bytes(&handle)?, key, self.comparator())?;
+                Ok(entry.map(|entry| (entry.value_at((self.clock)()), entry.meta())))
+            }
+        }
+    }
+
+    /// Returns an iterator over all entries in ascending key order, tombstones included.
+    /// It is double ended, so `iter().rev()` walks the table from `max_key` downward.
+    /// Entries that expired by the time the iterator was created are yielded as tombstones,
+    /// and entries deleted by a range tombstone of the table are skipped.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self)
+    }
+
+    /// Returns the entries as stored, in ascending key order: tombstones and entries deleted by
+    /// range tombstones are included and entries keep their expiries and sequence numbers,
+    /// ignoring the clock.
+    pub fn entries(&self) -> Entries<'_> {
+        iter::Entries::range(self, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Returns an iterator over the entries whose keys fall inside the bounds, in ascending
+    /// key order and including tombstones, skipping entries deleted by range tombstones like
+    /// `iter`. Bounds work like `BTreeMap::range`, except that a start bound past the end bound
+    /// yields nothing instead of panicking.
+    ///
+    /// The start position is found by binary search, either over the in-memory entries or
+    /// over the sparse index for lazily opened tables, so only blocks overlapping the range are read.
+    /// `range(..).rev()` starts from the block holding the end bound and walks backward.
+    pub fn range<K: AsRef<[u8]> + ?Sized>(&self, start: Bound<&K>, end: Bound<&K>) -> Iter<'_> {
+        Iter::range(self, start.map(AsRef::as_ref), end.map(AsRef::as_ref))
+    }
+
+    /// Returns an iterator over the entries inside the bounds like `range`, as of sequence
+    /// number `seqno`, for a snapshot: the entries written after it are left out, and so are
+    /// the range tombstones, like `get_at`.
+    pub fn range_at<K: AsRef<[u8]> + ?Sized>(&self, start: Bound<&K>, end: Bound<&K>, seqno: u64) -> Iter<'_> {
+        Iter::range_at(self, start.map(AsRef::as_ref), end.map(AsRef::as_ref), seqno)
+    }
+
+    /// Returns true if a range tombstone of the table holds `key`, whatever the sequence number
+    /// of the key. The versions of the key in older tables are then deleted.
+    pub fn is_range_deleted(&self, key: impl AsRef<[u8]>) -> bool {
+        range_del::covering_seqno(&self.metadata.range_tombstones, key.as_ref(), self.comparator()).is_some()
+    }
+
+    /// Returns true if all entries are held in memory, false for lazily opened tables.
+    pub fn is_loaded(&self) -> bool {
+        matches!(self.data, TableData::Loaded(_))
+    }
+
+    /// Looks up a key by reading only the block that can contain it from `reader`, unless the
+    /// block cache set by `with_block_cache` already holds it.
+    ///
+    /// The reader must be positioned over the same file this table was loaded from. The index
+    /// partitions of a partitioned index are read through the table itself.
+    pub fn get_from<R: Read + Seek>(&self, reader: &mut R, key: impl AsRef<[u8]>) -> io::Result<Option<Value>> {
+        let key = key.as_ref();
+        let found = self.lookup_from(reader, key)?;
+        Ok(self.mask_range_deleted(key, found).map(|(value, _)| value))
+    }
+
+    /// Looks up a key like `get` and returns a reader over its value rather than the value, so
+    /// a value too large to hold in memory can be copied to a file or a socket as it is read.
+    /// Returns `None` if the key has no value in the table: it is missing, deleted, expired or
+    /// deleted by a range tombstone. Merge operands fail with `InvalidInput`, they are stacked
+    /// onto older tables by `get`.
+    ///
+    /// In a lazily opened table, the value of a block that is neither compressed nor prefix
+    /// compressed is read from the file as the reader is read, and the values of the records
+    /// before it in the block are skipped with seeks. Other values are read into memory first:
+    /// those of loaded and memory mapped tables, of compressed blocks, compressed values and
+    /// typed values, which read as their bytes, see `Value::as_option`.
+    pub fn get_reader(&self, key: impl AsRef<[u8]>) -> io::Result<Option<ValueReader<ValueSource<'_>>>> {
+        let key = key.as_ref();
+        if let TableData::OnDisk { file } = &self.data {
+            if !self.metadata.bloom_filter.may_contain(key) {
+                return Ok(None);
+            }
+            let Some((_, handle)) = self.find_block(key)? else {
+                return Ok(None);
+            };
+            let mut reader = PositionedReader::new(file);
+            reader.seek(SeekFrom::Start(handle.offset))?;
+            let mut tag = [0u8; 1];
+            reader.read_exact(&mut tag)?;
+            if compression::block_codec(&tag)? == Compression::None && !block::is_prefix_block(&tag) {
+                if let Some(found) = self.stream_from_block(reader, &handle, key)? {
+                    return Ok(found);
+                }
+            }
+        }
+        let value = match self.get_with_metadata(key)? {
+            None | Some((Value::Deleted, _)) => return Ok(None),
+            Some((Value::Merge(_), _)) => return Err(merge_operands_unreadable(key)),
+            Some((value, _)) => value.as_option().expect("typed values have bytes"),
+        };
+        let mut record = Vec::new();
+        encode_record_into(&mut record, RecordKind::Set, key, &value, None, 0, 0)?;
+        let mut source = ValueSource::memory(record);
+        let header = read_record_header(&mut source)?.expect("an encoded record");
+        Ok(Some(ValueReader::new(source, header)))
+    }
+
+    /// Looks for `key` in the plain block at `handle`, reading the records from `reader` just
+    /// after the tag of the block and seeking past their values. Returns `Some(None)` if the key
+    /// has no value in the table, and `None` if its value has to be read into memory, see
+    /// `get_reader`.
+    fn stream_from_block<'a>(
+        &self,
+        mut reader: PositionedReader<'a>,
+        handle: &BlockHandle,
+        key: &[u8],
+    ) -> io::Result<Option<Option<ValueReader<ValueSource<'a>>>>> {
+        let end = handle.offset + handle.len;
+        while reader.stream_position()? < end {
+            let header = read_record_header(&mut reader)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "sstable block truncated")
+            })?;
+            match self.comparator().cmp(&header.key, key) {
+                Ordering::Less => (_, reader) = ValueReader::new(reader, header).skip()?,
+                Ordering::Greater => break,
+                Ordering::Equal => {
+                    if header.compressed || header.kind == RecordKind::Typed {
+                        return Ok(None);
+                    }
+                    let streamable = matches!(header.kind, RecordKind::Set | RecordKind::SetWithTtl);
+                    let value_start = reader;
+                    let (trailer, _) = ValueReader::new(reader, header.clone()).skip()?;
+                    let expired = trailer.expires_at.is_some_and(|expires_at| expires_at <= (self.clock)());
+                    let tombstones = &self.metadata.range_tombstones;
+                    let range_deleted = range_del::covering_seqno(tombstones, key, self.comparator())
+                        .is_some_and(|seqno| seqno > trailer.seqno);
+                    return match header.kind {
+                        RecordKind::Merge if !range_deleted => Err(merge_operands_unreadable(key)),
+                        _ if !streamable || expired || range_deleted => Ok(Some(None)),
+                        _ => Ok(Some(Some(ValueReader::new(ValueSource::file(value_start), header)))),
+                    };
+                }
+            }
+        }
+        Ok(Some(None))
+    }
+
+    /// Applies the range tombstones of the table to `found`, the entry of `key` in it: the key
+    /// reads as `Value::Deleted`, with the sequence number of the newest tombstone holding it,
+    /// if that tombstone was written after the entry or the key has no entry.
+    fn mask_range_deleted(&self, key: &[u8], found: Option<(Value, RecordMeta)>) -> Option<(Value, RecordMeta)> {
+        let deleted = |seqno| Some((Value::Deleted, RecordMeta { seqno, ..RecordMeta::default() }));
+        match (range_del::covering_seqno(&self.metadata.range_tombstones, key, self.comparator()), found) {
+            (Some(seqno), None) => deleted(seqno),
+            (Some(seqno), Some((_, meta))) if seqno > meta.seqno => deleted(seqno),
+            (_, found) => found,
+        }
+    }
+
+    /// Does the work of `get_from`, also returning the metadata.
+    fn lookup_from<R: Read + Seek>(&self, reader: &mut R, key: &[u8]) -> io::Result<Option<(Value, RecordMeta)>> {
+        let Some((_, handle)) = self.find_block(key)? else {
+            return Ok(None);
+        };
+        let now = (self.clock)();
+        let Some((cache, table_id)) = &self.block_cache else {
+            let entry = block::search_block(&block::read_block(reader, &handle)?, key, self.comparator())?;
+            return Ok(entry.map(|entry| (entry.value_at(now), entry.meta())));
+        };
+        let entries = match cache.get(*table_id, handle.offset) {
+            Some(entries) => entries,
+            None => {
+                let entries: Arc<[Entry]> = block::decode_block(&block::read_block(reader, &handle)?)?.into();
+                cache.insert(*table_id, handle.offset, Arc::clone(&entries));
+                entries
+            }
+        };
+        Ok(find_entry(&entries, key, now, self.comparator()))
+    }
+
+    /// Looks up several keys at once and returns their values in the order of `keys`. A key
+    /// given more than once gets an answer at each position.
+    ///
+    /// The keys are sorted and grouped by the block that can hold them, so each block is read
+    /// and decoded once however many keys it answers. For lazily opened tables the blocks that
+    /// are not in the block cache and sit next to each other in the file are fetched with a
+    /// single read.
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> io::Result<Vec<Option<Value>>> {
+        let results = self.find_many(keys)?;
+        Ok(results
+            .into_iter()
+            .zip(keys)
+            .map(|(found, key)| self.mask_range_deleted(key.as_ref(), found).map(|(value, _)| value))
+            .collect())
+    }
+
+    /// Does the work of `multi_get`, leaving range tombstones aside and keeping the metadata.
+    fn find_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> io::Result<Vec<Option<(Value, RecordMeta)>>> {
+        let mut results = vec![None; keys.len()];
+        let now = (self.clock)();
+        let mut order: Vec<usize> = (0..keys.len())
+            .filter(|&i| self.metadata.bloom_filter.may_contain(keys[i].as_ref()))
+            .collect();
+        let comparator = self.comparator();
+        order.sort_by(|&a, &b| comparator.cmp(keys[a].as_ref(), keys[b].as_ref()));
+        if let TableData::Loaded(entries) = &self.data {
+            for i in order {
+                results[i] = find_entry(entries, keys[i].as_ref(), now, comparator);
+            }
+            return Ok(results);
+        }
+
+        // Group the positions of the sorted keys by the block that can hold them
+        let mut groups: Vec<(usize, BlockHandle, Vec<usize>)> = Vec::new();
+        for i in order {
+            let Some((block, handle)) = self.find_block(keys[i].as_ref())? else {
+                continue;
+            };
+            match groups.last_mut() {
+                Some((last, _, positions)) if *last == block => positions.push(i),
+                _ => groups.push((block, handle, vec![i])),
+            }
+        }
+        let mut answer = |entries: &[Entry], positions: &[usize]| {
+            for &i in positions {
+                results[i] = find_entry(entries, keys[i].as_ref(), now, comparator);
+            }
+        };
+
+        match &self.data {
+            TableData::Loaded(_) => unreachable!("loaded tables are answered above"),
+            TableData::OnDisk { file } => {
+                let mut pending = Vec::new();
+                for (block, handle, positions) in groups {
+                    match &self.block_cache {
+                        Some((cache, table_id)) => match cache.get(*table_id, handle.offset) {
+                            Some(entries) => answer(&entries, &positions),
+                            None => pending.push((block, handle, positions)),
+                        },
+                        None => pending.push((block, handle, positions)),
+                    }
+                }
+                // Blocks tile the file, so consecutive blocks are fetched with one read
+                let mut file = PositionedReader::new(file);
+                for run in pending.chunk_by(|a, b| b.0 == a.0 + 1) {
+                    let first = &run[0].1;
+                    let last = &run[run.len() - 1].1;
+                    let run_len = (last.offset + last.len).saturating_sub(first.offset);
+                    let bytes = read_section(&mut file, first.offset, run_len, "blocks")?;
+                    for (_, handle, positions) in run {
+                        let start = (handle.offset - first.offset) as usize;
+                        let raw = bytes.get(start..start + handle.len as usize).ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidData, "sstable blocks are not contiguous")
+                        })?;
+                        let entries: Arc<[Entry]> = block::decode_block(raw)?.into();
+                        if let Some((cache, table_id)) = &self.block_cache {
+                            cache.insert(*table_id, handle.offset, Arc::clone(&entries));
+                        }
+                        answer(&entries, positions);
+                    }
+                }
+            }
+            #[cfg(feature = "mmap")]
+            TableData::Mapped(_) => {
+                for (_, handle, positions) in groups {
+                    answer(&block::decode_block(&self.read_block_bytes(&handle)?)?, &positions);
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Returns another handle of the table, like `File::try_clone`, with its block cache and
+    /// clock: a lazily opened table shares the file, a memory mapped one maps it again and a
+    /// loaded one copies its entries.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let data = match &self.data {
+            TableData::Loaded(entries) => TableData::Loaded(entries.clone()),
+            TableData::OnDisk { file } => TableData::OnDisk { file: file.try_clone()? },
+            #[cfg(feature = "mmap")]
+            TableData::Mapped(_) => {
+                let file = File::open(self.path())?;
+                // SAFETY: finished SSTables are immutable, see `open_mmap`
+                TableData::Mapped(unsafe { memmap2::Mmap::map(&file)? })
+            }
+        };
+        Ok(Self {
+            metadata: self.metadata.clone(),
+            data,
+            block_cache: self.block_cache.clone(),
+            clock: self.clock,
+            last_partition: Mutex::new(None),
+        })
+    }
+
+    /// Makes `get` on this lazily opened table go through the shared `cache`: blocks are looked
+    /// up there first, and decoded blocks read from the file are added to it. Loaded and memory
+    /// mapped tables do not read blocks from the file, so they ignore the cache.
+    pub fn with_block_cache(mut self, cache: Arc<BlockCache>) -> Self {
+        let table_id = cache.next_table_id();
+        self.block_cache = Some((cache, table_id));
+        self
+    }
+
+    /// Makes `get`, `multi_get` and the iterators compare entry expiries with `now` instead of
+    /// the system clock. `now` returns milliseconds since the UNIX epoch, see `system_clock`.
+    pub fn with_clock(mut self, now: fn() -> u64) -> Self {
+        self.clock = now;
+        self
+    }
+
+    /// Returns the raw bytes of a block or index partition, borrowed from the mapping or read
+    /// from the file.
+    fn read_block_bytes(&self, handle: &BlockHandle) -> io::Result<Cow<'_, [u8]>> {
+        match &self.data {
+            TableData::Loaded(_) => unreachable!("block read from an in-memory table"),
+            TableData::OnDisk { file } => block::read_block(&mut PositionedReader::new(file), handle).map(Cow::Owned),
+            #[cfg(feature = "mmap")]
+            TableData::Mapped(map) => block::block_in(map, handle).map(Cow::Borrowed),
+        }
+    }
+
+    pub fn might_contain_key(&self, key: impl AsRef<[u8]>) -> bool {
+        let key = key.as_ref();
+        // First check key range, it is the cheapest test
+        let comparator = self.comparator();
+        if comparator.cmp(key, &self.metadata.min_key) == Ordering::Less
+            || comparator.cmp(key, &self.metadata.max_key) == Ordering::Greater
+        {
+            return false;
+        }
+        // Then the bloom filter, tables written without one fall back to the range check alone.
+        // The keys of range tombstones are not in it.
+        self.metadata.bloom_filter.may_contain(key)
+            || range_del::covering_seqno(&self.metadata.range_tombstones, key, comparator).is_some()
+    }
+
+    /// Returns false if no key in the table can start with `prefix`, so a prefix scan can skip it.
+    /// Like `might_contain_key`, tombstones count as keys so they can shadow older tables.
+    ///
+    /// The `[min_key, max_key]` range is checked first, for byte-wise ordered tables only. If the
+    /// table was written with a prefix extractor and `prefix` is long enough to have a prefix of
+    /// its own, the prefix bloom filter is checked too: every key starting with `prefix` was added
+    /// to it under that same prefix.
+    pub fn might_contain_prefix(&self, prefix: impl AsRef<[u8]>) -> bool {
+        let prefix = prefix.as_ref();
+        // Keys starting with the prefix sort at or after it, and share it up to the first one that
+        // does not. Other comparators need not keep them together, so their range says nothing.
+        let (min_key, max_key) = (self.metadata.min_key.as_slice(), self.metadata.max_key.as_slice());
+        let bytewise = self.comparator().name() == BytewiseComparator.name();
+        if bytewise && (max_key < prefix || (min_key > prefix && !min_key.starts_with(prefix))) {
+            return false;
+        }
+        // Range tombstones can hold keys with any prefix, and delete them in older tables
+        if !self.metadata.range_tombstones.is_empty() {
+            return true;
+        }
+        match &self.metadata.prefix_filter {
+            Some((extractor, filter)) => extractor
+                .extract(prefix)
+                .is_none_or(|extracted| filter.may_contain(extracted)),
+            None => true,
+        }
+    }
+}
+
+/// Binary searches the sorted entries of a table or block for `key` and returns its value at
+/// `now` and its metadata.
+/// The error of `SsTable::get_reader` for a key holding merge operands.
+fn merge_operands_unreadable(key: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("key {:?} holds merge operands, which have no value to read", String::from_utf8_lossy(key)),
+    )
+}
+
+fn find_entry(entries: &[Entry], key: &[u8], now: u64, comparator: &dyn Comparator) -> Option<(Value, RecordMeta)> {
+    entries
+        .binary_search_by(|entry| comparator.cmp(&entry.key, key))
+        .ok()
+        .map(|idx| (entries[idx].value_at(now), entries[idx].meta()))
+}
+
+/// Rejects a merge that would overwrite one of its inputs.
+fn check_merge_output(output_path: &Path, inputs: &[SsTable]) -> io::Result<()> {
+    if inputs.iter().any(|input| input.path() == output_path) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot merge into input table {}", output_path.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects a merge of tables ordered by another comparator than the output.
+fn check_merge_comparators(inputs: &[SsTable], table_options: &SsTableOptions) -> io::Result<()> {
+    let name = table_options.comparator.name();
+    match inputs.iter().find(|input| input.comparator().name() != name) {
+        Some(input) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "cannot merge {} ordered by comparator {:?} into a table ordered by {name:?}",
+                input.path().display(),
+                input.comparator().name()
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Returns the merged entries of `inputs`. Entries that expired by the clock of the options
+/// become tombstones, the survivors go through `filter`, and tombstones are left out if the
+/// options drop them. Merge operands are stacked onto the older versions of their key, and
+/// when the options drop tombstones, there being nothing older, the operands left are
+/// combined into a value if the options have a merge function.
+fn merge_entries<'a>(
+    inputs: &'a [SsTable],
+    options: &'a MergeOptions,
+    mut filter: Option<&'a mut dyn CompactionFilter>,
+) -> impl Iterator<Item = io::Result<Entry>> + 'a {
+    let now = (options.clock)();
+    let is_dropped = |entry: &Entry| options.drop_tombstones && matches!(entry.value, Value::Deleted);
+    let comparator = &*options.table_options.comparator;
+    let merge = options.merge_operator.as_deref();
+    merge::MergeIter::new(inputs, comparator, merge, now).filter_map(move |item| {
+        let mut entry = match item {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err)),
+        };
+        if entry.is_expired(now) {
+            entry = Entry { value: Value::Deleted, expires_at: None, ..entry };
+        }
+        if let (true, Value::Merge(_), Some(merge)) = (options.drop_tombstones, &entry.value, merge) {
+            match std::mem::replace(&mut entry.value, Value::Deleted).resolve(&entry.key, Some(merge)) {
+                Ok(value) => entry.value = value,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        if is_dropped(&entry) {
+            return None;
+        }
+        if let Some(filter) = filter.as_deref_mut() {
+            match filter.filter(&entry.key, &entry.value) {
+                FilterDecision::Keep => {}
+                FilterDecision::Remove => entry = Entry { value: Value::Deleted, expires_at: None, ..entry },
+                FilterDecision::ChangeValue(value) => entry.value = Value::Bytes(value),
+            }
+        }
+        (!is_dropped(&entry)).then_some(Ok(entry))
+    })
+}
+
+/// Returns the path of output number `n` of `SsTable::merge_split`.
+fn split_output_path(output_path: &Path, n: usize) -> PathBuf {
+    let mut name = output_path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{n}"));
+    if let Some(extension) = output_path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output_path.with_file_name(name)
+}
+
+/// Does the work of `SsTable::merge_split`, recording the path of every finished output in `finished`.
+fn write_split(
+    output_path: &Path,
+    inputs: &[SsTable],
+    options: &MergeOptions,
+    finished: &mut Vec<PathBuf>,
+) -> io::Result<Vec<SsTable>> {
+    let range_tombstones = merged_range_tombstones(inputs, options);
+    let comparator = &*options.table_options.comparator;
+    let mut outputs = Vec::new();
+    // the writer of the current output and its first key
+    let mut writer: Option<(SsTableWriter, Vec<u8>)> = None;
+    let mut finish = |(mut writer, first_key): (SsTableWriter, Vec<u8>), outputs: &mut Vec<SsTable>| {
+        for &tombstone in &range_tombstones {
+            if outputs.is_empty() || comparator.cmp(&tombstone.end, &first_key) == Ordering::Greater {
+                writer.add_range_tombstone(tombstone.clone());
+            }
+        }
+        let table = writer.finish()?;
+        finished.push(table.path().to_path_buf());
+        outputs.push(table);
+        io::Result::Ok(())
+    };
+    for item in merge_entries(inputs, options, None) {
+        let entry = item?;
+        let (current, _) = match &mut writer {
+            Some(current) => current,
+            None => {
+                let path = split_output_path(output_path, outputs.len());
+                check_merge_output(&path, inputs)?;
+                writer.insert((SsTableWriter::new(&path, &options.table_options)?, entry.key.clone()))
+            }
+        };
+        current.add_entry(&entry)?;
+        if options.target_file_size.is_some_and(|target| current.file_size() >= target) {
+            finish(writer.take().expect("writer was just used"), &mut outputs)?;
+        }
+    }
+    // Range tombstones left without an entry get an output of their own
+    if writer.is_none() && outputs.is_empty() && !range_tombstones.is_empty() {
+        let path = split_output_path(output_path, 0);
+        check_merge_output(&path, inputs)?;
+        writer = Some((SsTableWriter::new(&path, &options.table_options)?, Vec::new()));
+    }
+    if let Some(writer) = writer {
+        finish(writer, &mut outputs)?;
+    }
+    Ok(outputs)
+}
+
+/// Returns the range tombstones of `inputs` a merge writes to its output: all of them, or none
+/// if the options drop tombstones.
+fn merged_range_tombstones<'a>(inputs: &'a [SsTable], options: &MergeOptions) -> Vec<&'a RangeTombstone> {
+    if options.drop_tombstones {
+        return Vec::new();
+    }
+    inputs.iter().flat_map(SsTable::range_tombstones).collect()
+}
+
+/// Reads the header, footer and sparse index of an SSTable file, which must be ordered by `comparator`.
+fn read_metadata<R: Read + Seek>(
+    file: &mut R,
+    path: PathBuf,
+    comparator: Arc<dyn Comparator>,
+) -> io::Result<SsTableMetadata> {
+    // The footer is checked first so that a file that is not an sstable is reported as such
+    let footer = read_footer(file)?;
+
+    // Read header: [entry_count:4]
+    file.seek(SeekFrom::Start(0))?;
+    let _entry_count = read_entry_count(file)?;
+
+    // Read the filter block, an empty one means the table was written without a filter
+    let filter_bytes = read_section(file, footer.filter_offset, footer.filter_len, "filter")?;
+    let bloom_filter = BloomFilter::decode(&filter_bytes);
+
+    // Read the properties block, older files have none
+    let properties = match footer.properties {
+        Some((offset, len)) => properties::decode(&read_section(file, offset, len, "properties")?)?,
+        None => BTreeMap::new(),
+    };
+    comparator::check(&properties, &*comparator)?;
+
+    // Read the sparse index, or the top-level index of a partitioned one
+    let index = read_index(file, &footer, &properties)?;
+
+    // Read the stats block, tables written before it existed are scanned once instead. Those
+    // predate partitioned indexes.
+    let mut stats = match (footer.stats, &index) {
+        (Some((offset, len)), _) => Stats::decode(&read_section(file, offset, len, "stats")?)?,
+        (None, Index::Flat(handles)) => {
+            let mut stats = Stats::default();
+            for handle in handles {
+                for entry in block::decode_block(&block::read_block(file, handle)?)? {
+                    stats.add(&entry.key, &entry.value, entry.seqno);
+                }
+            }
+            stats
+        }
+        (None, Index::Partitioned(_)) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "sstable with a partitioned index has no stats"));
+        }
+    };
+    stats.file_size = footer.file_size;
+    stats.min_key = footer.min_key.clone();
+    stats.max_key = footer.max_key.clone();
+
+    // Read the prefix filter block, it is empty or absent when the table has no prefix filter
+    let prefix_filter = match footer.prefix_filter {
+        Some((offset, len)) => prefix::decode(&read_section(file, offset, len, "prefix filter")?)?,
+        None => None,
+    };
+
+    // Read the range deletion block, older files have none
+    let range_tombstones = match footer.range_deletions {
+        Some((offset, len)) => range_del::decode(&read_section(file, offset, len, "range deletions")?)?,
+        None => Vec::new(),
+    };
+
+    Ok(SsTableMetadata {
+        path,
+        min_key: footer.min_key,
+        max_key: footer.max_key,
+        bloom_filter,
+        index,
+        stats,
+        format_version: footer.version,
+        properties,
+        prefix_filter,
+        range_tombstones,
+        comparator,
+    })
+}
+
+/// Reads the index section, which the `snaildb.index_type` property marks as partitioned.
+fn read_index<R: Read + Seek>(
+    file: &mut R,
+    footer: &Footer,
+    properties: &BTreeMap<String, String>,
+) -> io::Result<Index> {
+    let partitioned = match properties.get(properties::INDEX_TYPE).map(String::as_str) {
+        None => false,
+        Some(index::PARTITIONED) => true,
+        Some(other) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported sstable index type {other:?}"),
+            ));
+        }
+    };
+    Index::decode(&read_section(file, footer.index_offset, footer.index_len, "index")?, partitioned)
+}
+
+/// Reads `len` bytes starting at `offset`.
+fn read_section<R: Read + Seek>(reader: &mut R, offset: u64, len: u64, label: &str) -> io::Result<Vec<u8>> {
+    let len: usize = len
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("sstable {label} too large")))?;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0u8; len];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn read_entry_count<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_footer<R: Read + Seek>(reader: &mut R) -> io::Result<Footer> {
+    // 1. Check the trailer: [footer_offset:8][version:2][magic:8]
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < DATA_START + TRAILER_LEN {
+        return Err(CorruptFooter::error(format!("file is only {file_len} bytes long, not an sstable")));
+    }
+    let trailer_pos = reader.seek(SeekFrom::Start(file_len - TRAILER_LEN))?;
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    reader.read_exact(&mut trailer)?;
+    let (offset_buf, rest) = trailer.split_at(8);
+    let (version_buf, magic) = rest.split_at(2);
+    if magic != MAGIC {
+        return Err(CorruptFooter::error("bad magic number, not an sstable or truncated"));
+    }
+    let version = u16::from_le_bytes([version_buf[0], version_buf[1]]);
+    if version == 0 {
+        return Err(CorruptFooter::error("format version 0"));
+    }
+    if version > FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("sstable format version {version} is newer than the supported version {FORMAT_VERSION}"),
+        ));
+    }
+    let footer_offset = u64::from_le_bytes(offset_buf.try_into().expect("8-byte slice"));
+    let footer_end = if version >= 6 { trailer_pos - FOOTER_CRC_LEN } else { trailer_pos };
+    if footer_offset <= DATA_START || footer_offset >= footer_end {
+        return Err(CorruptFooter::error(format!(
+            "footer offset {footer_offset} is outside the file of {file_len} bytes"
+        )));
+    }
+
+    // 2. Read the whole footer and, from version 6 on, check it against the crc that
+    // covers it together with the footer_offset and version of the trailer
+    reader.seek(SeekFrom::Start(footer_offset))?;
+    let mut footer = vec![0u8; (trailer_pos - footer_offset) as usize];
+    reader.read_exact(&mut footer)?;
+    if version >= 6 {
+        let (body, crc_buf) = footer.split_at(footer.len() - FOOTER_CRC_LEN as usize);
+        let mut hasher = Hasher::new();
+        hasher.update(body);
+        hasher.update(&trailer[..10]);
+        let expected = u32::from_le_bytes(crc_buf.try_into().expect("4-byte slice"));
+        let computed = hasher.finalize();
+        if computed != expected {
+            return Err(CorruptFooter::error(format!(
+                "crc mismatch: expected {expected:#010x}, computed {computed:#010x}"
+            )));
+        }
+        footer.truncate(body.len());
+    }
+
+    // 3. Read the length prefixed min_key and max_key
+    let mut footer = io::Cursor::new(footer.as_slice());
+    let mut read_key = |label: &str| -> io::Result<Vec<u8>> {
+        let runs_past = || CorruptFooter::error(format!("{label} runs past the footer"));
+        let mut len_buf = [0u8; 4];
+        footer.read_exact(&mut len_buf).map_err(|_| runs_past())?;
+        let len = u64::from(u32::from_le_bytes(len_buf));
+        if footer.position() + len > footer.get_ref().len() as u64 {
+            return Err(runs_past());
+        }
+        let mut key = vec![0u8; len as usize];
+        footer.read_exact(&mut key)?;
+        Ok(key)
+    };
+    let min_key = read_key("min_key")?;
+    let max_key = read_key("max_key")?;
+
+    // 4. Read the section locations, which must end right at the end of the footer. The
+    // sections follow each other in the order they are listed and the last one ends at the footer.
+    let no_index = || CorruptFooter::error("footer has no block index");
+    let section_count = footer_section_count(version);
+    if footer.position() + 16 * section_count as u64 != footer.get_ref().len() as u64 {
+        return Err(no_index());
+    }
+    let mut sections = Vec::with_capacity(section_count);
+    let mut u64_buf = [0u8; 8];
+    for _ in 0..section_count {
+        footer.read_exact(&mut u64_buf)?;
+        let offset = u64::from_le_bytes(u64_buf);
+        footer.read_exact(&mut u64_buf)?;
+        let len = u64::from_le_bytes(u64_buf);
+        sections.push((offset, len));
+    }
+    let mut end = sections[0].0;
+    for &(offset, len) in &sections {
+        if offset != end {
+            return Err(no_index());
+        }
+        end = offset.checked_add(len).ok_or_else(no_index)?;
+    }
+    if end != footer_offset {
+        return Err(no_index());
+    }
+
+    Ok(Footer {
+        version,
+        file_size: file_len,
+        min_key,
+        max_key,
+        filter_offset: sections[0].0,
+        filter_len: sections[0].1,
+        index_offset: sections[1].0,
+        index_len: sections[1].1,
+        stats: sections.get(2).copied(),
+        properties: sections.get(3).copied(),
+        prefix_filter: sections.get(4).copied(),
+        range_deletions: sections.get(5).copied(),
+    })
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::storage::bloom_filter::BITS_PER_KEY;
+use crate::storage::sstable::{BytewiseComparator, Comparator, Compression, PrefixExtractor, system_clock};
+use crate::utils::record::{RecordLimits, ValueCompression};
+use crate::utils::value::{MergeFn, MergeOperator};
+
+/// Options controlling how an SSTable is written.
+#[derive(Clone, Debug)]
+pub struct SsTableOptions {
+    /// Bits per key for the bloom filter, 0 writes the table without a filter.
+    pub bloom_bits_per_key: usize,
+    /// Codec used to compress data blocks.
+    pub compression: Compression,
+    /// Compresses the values of entries one by one, each record saying how its value was
+    /// compressed, see `ValueCompression`. Unlike block compression this leaves the records of
+    /// a block readable in place, and small values uncompressed. `None` by default.
+    pub value_compression: Option<ValueCompression>,
+    /// Stores each key as the suffix after the prefix it shares with the previous key,
+    /// which shrinks tables whose keys share long prefixes.
+    pub prefix_compression: bool,
+    /// Builds a second bloom filter over key prefixes, see `SsTable::might_contain_prefix`.
+    /// It uses the same bits per key as the key filter, counted per distinct prefix.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    /// User-defined properties stored in the table, see `SsTable::properties`.
+    /// Names starting with `snaildb.` are reserved.
+    pub properties: BTreeMap<String, String>,
+    /// Splits the index into partitions of about this many bytes, stored next to the data
+    /// blocks, and keeps only the top-level index over them in memory. `None` writes a flat
+    /// index that is read whole when the table is opened.
+    pub index_partition_size: Option<usize>,
+    /// The order of the keys, recorded in the table. Byte-wise by default.
+    pub comparator: Arc<dyn Comparator>,
+    /// Stamps the entries added to the writer with the time it returns, in milliseconds since
+    /// the UNIX epoch, see `SsTable::get_with_metadata`. Entries copied from other tables by a
+    /// merge or an import keep the time they were written, if any. `None`, the default, stamps
+    /// nothing.
+    pub write_clock: Option<fn() -> u64>,
+    /// The longest keys and values the writer takes, see `RecordLimits`. Adding a longer one
+    /// fails with a `RecordError`.
+    pub record_limits: RecordLimits,
+}
+
+impl SsTableOptions {
+    /// Sets the bloom filter bits per key, 0 disables the filter.
+    pub fn with_bloom_bits_per_key(mut self, bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bits_per_key;
+        self
+    }
+
+    /// Sets the codec used to compress data blocks.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Compresses the values longer than `compression.threshold` bytes with `compression.codec`.
+    pub fn with_value_compression(mut self, compression: ValueCompression) -> Self {
+        self.value_compression = Some(compression);
+        self
+    }
+
+    /// Enables prefix compression of keys inside data blocks.
+    pub fn with_prefix_compression(mut self, prefix_compression: bool) -> Self {
+        self.prefix_compression = prefix_compression;
+        self
+    }
+
+    /// Builds a prefix bloom filter over the prefixes `extractor` returns.
+    pub fn with_prefix_extractor(mut self, extractor: PrefixExtractor) -> Self {
+        self.prefix_extractor = Some(extractor);
+        self
+    }
+
+    /// Adds a user-defined property to store in the table.
+    pub fn with_property(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.insert(name.into(), value.into());
+        self
+    }
+
+    /// Writes a partitioned index with partitions of about `partition_size` bytes. Lookups in
+    /// a lazily opened table then read an index partition before the data block, and tables
+    /// with millions of blocks open without reading their whole index.
+    pub fn with_partitioned_index(mut self, partition_size: usize) -> Self {
+        self.index_partition_size = Some(partition_size);
+        self
+    }
+
+    /// Orders the keys with `comparator` instead of byte-wise. The table must then be opened
+    /// with the same comparator, see `SsTable::open_with_comparator`.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    /// Stamps added entries with the time `now` returns, `system_clock` for the actual time.
+    pub fn with_write_clock(mut self, now: fn() -> u64) -> Self {
+        self.write_clock = Some(now);
+        self
+    }
+
+    /// Takes keys and values up to `limits`.
+    pub fn with_record_limits(mut self, limits: RecordLimits) -> Self {
+        self.record_limits = limits;
+        self
+    }
+}
+
+impl Default for SsTableOptions {
+    fn default() -> Self {
+        Self {
+            bloom_bits_per_key: BITS_PER_KEY,
+            compression: Compression::None,
+            value_compression: None,
+            prefix_compression: false,
+            prefix_extractor: None,
+            properties: BTreeMap::new(),
+            index_partition_size: None,
+            comparator: Arc::new(BytewiseComparator),
+            write_clock: None,
+            record_limits: RecordLimits::default(),
+        }
+    }
+}
+
+/// Options controlling `SsTable::merge_with_options`.
+#[derive(Clone, Debug)]
+pub struct MergeOptions {
+    /// Options for the merged output table.
+    pub table_options: SsTableOptions,
+    /// Drops tombstones from the output. Only safe when no older table below the
+    /// inputs can still hold a value the tombstones need to shadow.
+    pub drop_tombstones: bool,
+    /// Size at which `SsTable::merge_split` finishes the current output and starts the next one.
+    /// `merge_with_options` always writes a single table and ignores it.
+    pub target_file_size: Option<u64>,
+    /// The current time in milliseconds since the UNIX epoch. Entries that expired by then
+    /// lose their value in the output, see `SsTable::merge_with_options`.
+    pub clock: fn() -> u64,
+    /// Combines the merge operands of a key with its older value. Without it a merge fails on
+    /// operands over a value or a tombstone, and keeps the operands of keys with nothing older,
+    /// which it combines into one where the operator can, see `MergeOperator::partial_merge`.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            table_options: SsTableOptions::default(),
+            drop_tombstones: false,
+            target_file_size: None,
+            clock: system_clock,
+            merge_operator: None,
+        }
+    }
+}
+
+impl MergeOptions {
+    /// Sets the options used to write the merged table.
+    pub fn with_table_options(mut self, table_options: SsTableOptions) -> Self {
+        self.table_options = table_options;
+        self
+    }
+
+    /// Drops tombstones from the output instead of carrying them over.
+    pub fn with_drop_tombstones(mut self, drop_tombstones: bool) -> Self {
+        self.drop_tombstones = drop_tombstones;
+        self
+    }
+
+    /// Splits the output of `SsTable::merge_split` into tables of about `bytes` bytes each.
+    pub fn with_target_file_size(mut self, bytes: u64) -> Self {
+        self.target_file_size = Some(bytes);
+        self
+    }
+
+    /// Compares entry expiries with `now` instead of the system clock.
+    pub fn with_clock(mut self, now: fn() -> u64) -> Self {
+        self.clock = now;
+        self
+    }
+
+    /// Combines merge operands with `operator`, collapsing the operands of a key into one value.
+    pub fn with_merge_operator(mut self, operator: Arc<dyn MergeOperator>) -> Self {
+        self.merge_operator = Some(operator);
+        self
+    }
+
+    /// Combines merge operands with `merge`, see `with_merge_operator`.
+    pub fn with_merge_function(self, merge: MergeFn) -> Self {
+        self.with_merge_operator(Arc::new(merge))
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Reads a file with positioned reads, `pread` on Unix and `seek_read` on Windows, keeping
+/// its own position instead of moving the cursor of the file. Any number of them can read
+/// one `File` from different threads at once.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PositionedReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> PositionedReader<'a> {
+    pub(crate) fn new(file: &'a File) -> Self {
+        Self { file, pos: 0 }
+    }
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let read = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for PositionedReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.file.metadata()?.len(), offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// What the `ValueReader` returned by `SsTable::get_reader` reads from: the file of the table,
+/// or a copy of the record in memory.
+#[derive(Debug)]
+pub struct ValueSource<'a>(Source<'a>);
+
+#[derive(Debug)]
+enum Source<'a> {
+    File(PositionedReader<'a>),
+    Memory(io::Cursor<Vec<u8>>),
+}
+
+impl<'a> ValueSource<'a> {
+    pub(crate) fn file(reader: PositionedReader<'a>) -> Self {
+        Self(Source::File(reader))
+    }
+
+    pub(crate) fn memory(bytes: Vec<u8>) -> Self {
+        Self(Source::Memory(io::Cursor::new(bytes)))
+    }
+}
+
+impl Read for ValueSource<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Source::File(reader) => reader.read(buf),
+            Source::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for ValueSource<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.0 {
+            Source::File(reader) => reader.seek(pos),
+            Source::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::io;
+
+use crate::storage::bloom_filter::BloomFilter;
+
+/// Maps a key to the prefix stored in the prefix bloom filter, see
+/// `SsTableOptions::with_prefix_extractor` and `SsTable::might_contain_prefix`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefixExtractor {
+    /// The first `n` bytes of the key. Keys shorter than that have no prefix.
+    FixedLength(usize),
+    /// Everything up to and including the first occurrence of the byte, so `Delimiter(b'/')`
+    /// maps `tenant123/orders/7` to `tenant123/`. Keys without the byte have no prefix.
+    Delimiter(u8),
+}
+
+const FIXED_LENGTH: u8 = 1;
+const DELIMITER: u8 = 2;
+
+impl PrefixExtractor {
+    /// Returns the prefix of `key`, or `None` if the key has none.
+    pub fn extract<'k>(&self, key: &'k [u8]) -> Option<&'k [u8]> {
+        match *self {
+            PrefixExtractor::FixedLength(len) => key.get(..len),
+            PrefixExtractor::Delimiter(delimiter) => {
+                let end = key.iter().position(|&byte| byte == delimiter)?;
+                Some(&key[..=end])
+            }
+        }
+    }
+}
+
+/// Rejects extractors that cannot be stored in the file.
+pub(crate) fn validate(extractor: &PrefixExtractor) -> io::Result<()> {
+    match *extractor {
+        PrefixExtractor::FixedLength(0) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "prefix extractor length must not be 0",
+        )),
+        PrefixExtractor::FixedLength(len) if u32::try_from(len).is_err() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("prefix extractor length {len} is too large"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Serializes the prefix filter block as [extractor_kind:1][extractor_param:4][filter:var].
+pub(crate) fn encode(extractor: &PrefixExtractor, filter: &BloomFilter) -> Vec<u8> {
+    let (kind, param) = match *extractor {
+        PrefixExtractor::FixedLength(len) => (FIXED_LENGTH, len as u32),
+        PrefixExtractor::Delimiter(delimiter) => (DELIMITER, u32::from(delimiter)),
+    };
+    let filter = filter.encode();
+    let mut buffer = Vec::with_capacity(5 + filter.len());
+    buffer.push(kind);
+    buffer.extend_from_slice(&param.to_le_bytes());
+    buffer.extend_from_slice(&filter);
+    buffer
+}
+
+/// Deserializes a block written by `encode`. An empty block means the table has no prefix filter.
+pub(crate) fn decode(buffer: &[u8]) -> io::Result<Option<(PrefixExtractor, BloomFilter)>> {
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, format!("sstable prefix filter {reason}"));
+    let (header, filter) = buffer
+        .split_at_checked(5)
+        .ok_or_else(|| invalid("is truncated".to_string()))?;
+    let param = u32::from_le_bytes(header[1..5].try_into().expect("4-byte slice"));
+    let extractor = match header[0] {
+        FIXED_LENGTH => PrefixExtractor::FixedLength(param as usize),
+        DELIMITER => PrefixExtractor::Delimiter(
+            u8::try_from(param).map_err(|_| invalid(format!("has an invalid delimiter {param}")))?,
+        ),
+        kind => return Err(invalid(format!("has an unknown extractor kind {kind}"))),
+    };
+    Ok(Some((extractor, BloomFilter::decode(filter))))
+}
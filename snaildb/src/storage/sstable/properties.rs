@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+
+/// Prefix of the property names reserved for the properties snaildb writes itself.
+pub const RESERVED_PREFIX: &str = "snaildb.";
+
+/// Creation time of the table, in milliseconds since the Unix epoch.
+pub const CREATED_AT: &str = "snaildb.created_at";
+
+/// Layout of the index, `partitioned` for a two-level index, see
+/// `SsTableOptions::with_partitioned_index`. Tables without it have a flat index.
+pub const INDEX_TYPE: &str = "snaildb.index_type";
+
+/// Name of the comparator the keys are ordered by, see `Comparator::name`. Tables without it
+/// are ordered byte-wise.
+pub const COMPARATOR: &str = "snaildb.comparator";
+
+/// Checks that user supplied property names do not use the reserved prefix.
+pub(crate) fn validate(properties: &BTreeMap<String, String>) -> io::Result<()> {
+    match properties.keys().find(|name| name.starts_with(RESERVED_PREFIX)) {
+        Some(name) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("property name {name:?} uses the reserved `{RESERVED_PREFIX}` prefix"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Encodes the properties as `[count:4]` followed by `[name_len:4][name][value_len:4][value]` per property.
+pub(crate) fn encode(properties: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&(properties.len() as u32).to_le_bytes());
+    for (name, value) in properties {
+        for part in [name, value] {
+            buffer.extend_from_slice(&(part.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(part.as_bytes());
+        }
+    }
+    buffer
+}
+
+/// Decodes the properties written by `encode`.
+pub(crate) fn decode(mut buffer: &[u8]) -> io::Result<BTreeMap<String, String>> {
+    let count = read_u32(&mut buffer)?;
+    let mut properties = BTreeMap::new();
+    for _ in 0..count {
+        let name = read_string(&mut buffer)?;
+        let value = read_string(&mut buffer)?;
+        properties.insert(name, value);
+    }
+    Ok(properties)
+}
+
+fn read_string(buffer: &mut &[u8]) -> io::Result<String> {
+    let len = read_u32(buffer)? as usize;
+    if len > buffer.len() {
+        return Err(truncated());
+    }
+    let (bytes, rest) = buffer.split_at(len);
+    *buffer = rest;
+    String::from_utf8(bytes.to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid sstable property: {e}")))
+}
+
+fn read_u32(buffer: &mut &[u8]) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    buffer.read_exact(&mut buf).map_err(|_| truncated())?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "sstable properties block truncated")
+}
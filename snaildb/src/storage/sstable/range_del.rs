@@ -0,0 +1,79 @@
+use std::cmp::Ordering;
+use std::io;
+
+use crate::storage::sstable::Comparator;
+use crate::utils::record::{RecordBuf, RecordKind, encode_record_into, read_record_into};
+
+/// Deletes every key from `start`, included, to `end`, excluded, hiding the versions of those
+/// keys written before it, see `covers`. Written by `SsTableWriter::add_range_delete` and
+/// `SnailDb::delete_range`. A range whose end does not sort after its start holds no key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    /// the sequence number of the delete, 0 if it has none
+    pub seqno: u64,
+}
+
+impl RangeTombstone {
+    pub fn new(start: impl Into<Vec<u8>>, end: impl Into<Vec<u8>>, seqno: u64) -> Self {
+        Self { start: start.into(), end: end.into(), seqno }
+    }
+
+    /// Returns true if the range holds no key: its end sorts at or before its start.
+    pub fn is_empty(&self, comparator: &dyn Comparator) -> bool {
+        comparator.cmp(&self.end, &self.start) != Ordering::Greater
+    }
+
+    /// Returns true if `key` sorts at or after `start` and before `end`.
+    pub fn contains(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        comparator.cmp(key, &self.start) != Ordering::Less && comparator.cmp(key, &self.end) == Ordering::Less
+    }
+
+    /// Returns true if the tombstone hides the version of `key` written with sequence number
+    /// `seqno`: the key is in the range and the tombstone has a higher sequence number.
+    pub fn covers(&self, key: &[u8], seqno: u64, comparator: &dyn Comparator) -> bool {
+        self.seqno > seqno && self.contains(key, comparator)
+    }
+}
+
+/// Returns the highest sequence number of the tombstones whose range holds `key`.
+pub(crate) fn covering_seqno(tombstones: &[RangeTombstone], key: &[u8], comparator: &dyn Comparator) -> Option<u64> {
+    tombstones
+        .iter()
+        .filter(|tombstone| tombstone.contains(key, comparator))
+        .map(|tombstone| tombstone.seqno)
+        .max()
+}
+
+/// Encodes the range deletion block of a table: a `RangeDelete` record per tombstone, keyed by
+/// its start with its end as the value, in the order given.
+pub(crate) fn encode(tombstones: &[RangeTombstone]) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    for tombstone in tombstones {
+        let RangeTombstone { start, end, seqno } = tombstone;
+        encode_record_into(&mut buffer, RecordKind::RangeDelete, start, end, None, *seqno, 0)?;
+    }
+    Ok(buffer)
+}
+
+/// Decodes a range deletion block written by `encode`.
+pub(crate) fn decode(bytes: &[u8]) -> io::Result<Vec<RangeTombstone>> {
+    let mut reader = io::Cursor::new(bytes);
+    let mut tombstones = Vec::new();
+    let mut record = RecordBuf::default();
+    while read_record_into(&mut reader, &mut record)?.is_some() {
+        if record.kind() != RecordKind::RangeDelete {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} record in the range deletion block", record.kind()),
+            ));
+        }
+        tombstones.push(RangeTombstone {
+            start: record.key().to_vec(),
+            end: record.value().to_vec(),
+            seqno: record.seqno(),
+        });
+    }
+    Ok(tombstones)
+}
@@ -0,0 +1,84 @@
+use std::io;
+
+use crate::utils::value::Value;
+
+/// Summary of the contents of an SSTable, returned by `SsTable::stats`.
+///
+/// The counters are gathered by the writer and stored in a stats block next to the index,
+/// so opening a table lazily does not need to scan its data blocks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// size of the file in bytes
+    pub file_size: u64,
+    /// number of entries, tombstones included
+    pub entries: u64,
+    /// number of entries that are tombstones
+    pub tombstones: u64,
+    /// total length of all keys
+    pub key_bytes: u64,
+    /// total length of all values, merge operands summed, tombstones count as empty
+    pub value_bytes: u64,
+    /// the smallest key in the table
+    pub min_key: Vec<u8>,
+    /// the largest key in the table
+    pub max_key: Vec<u8>,
+    /// the smallest sequence number of an entry, 0 for tables written without sequence numbers
+    pub min_seqno: u64,
+    /// the largest sequence number of an entry, 0 for tables written without sequence numbers
+    pub max_seqno: u64,
+}
+
+/// Length of the encoded counters: [entries:8][tombstones:8][key_bytes:8][value_bytes:8].
+const ENCODED_LEN: usize = 4 * 8;
+
+/// Length of the sequence number range following the counters: [min_seqno:8][max_seqno:8].
+/// Stats blocks written before sequence numbers existed end after the counters.
+const SEQNO_RANGE_LEN: usize = 2 * 8;
+
+impl Stats {
+    /// Counts one entry.
+    pub(crate) fn add(&mut self, key: &[u8], value: &Value, seqno: u64) {
+        if self.entries == 0 {
+            (self.min_seqno, self.max_seqno) = (seqno, seqno);
+        } else {
+            self.min_seqno = self.min_seqno.min(seqno);
+            self.max_seqno = self.max_seqno.max(seqno);
+        }
+        self.entries += 1;
+        self.key_bytes += key.len() as u64;
+        match value {
+            Value::Deleted => self.tombstones += 1,
+            value => self.value_bytes += value.byte_len() as u64,
+        }
+    }
+
+    /// Encodes the counters and the sequence number range. The key range and file size are
+    /// already known from the footer.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(ENCODED_LEN + SEQNO_RANGE_LEN);
+        let counters = [self.entries, self.tombstones, self.key_bytes, self.value_bytes];
+        for counter in counters.into_iter().chain([self.min_seqno, self.max_seqno]) {
+            buffer.extend_from_slice(&counter.to_le_bytes());
+        }
+        buffer
+    }
+
+    /// Decodes the counters written by `encode`, ignoring any trailing bytes. Blocks without the
+    /// sequence number range leave it at 0.
+    pub(crate) fn decode(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < ENCODED_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "sstable stats block truncated"));
+        }
+        let counter = |i: usize| u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().expect("8-byte slice"));
+        let has_seqnos = bytes.len() >= ENCODED_LEN + SEQNO_RANGE_LEN;
+        Ok(Self {
+            entries: counter(0),
+            tombstones: counter(1),
+            key_bytes: counter(2),
+            value_bytes: counter(3),
+            min_seqno: if has_seqnos { counter(4) } else { 0 },
+            max_seqno: if has_seqnos { counter(5) } else { 0 },
+            ..Self::default()
+        })
+    }
+}
@@ -0,0 +1,333 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Seek};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::storage::bloom_filter::BloomFilter;
+use crate::storage::sstable::index::{self, Index};
+use crate::storage::sstable::{
+    BytewiseComparator, Comparator, block, comparator, prefix, properties, range_del, read_entry_count, read_footer,
+    read_index, read_section,
+};
+
+/// Options controlling `SsTable::verify_with_options`.
+#[derive(Clone, Debug)]
+pub struct VerifyOptions {
+    /// Stops at the first problem instead of collecting all of them.
+    pub fail_fast: bool,
+    /// The comparator the table must be ordered by, byte-wise by default. A table recording
+    /// another one is reported as a metadata problem.
+    pub comparator: Arc<dyn Comparator>,
+}
+
+impl VerifyOptions {
+    /// Stops the verification at the first problem found.
+    pub fn with_fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Checks the key order with `comparator` instead of byte-wise.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            fail_fast: false,
+            comparator: Arc::new(BytewiseComparator),
+        }
+    }
+}
+
+/// The result of walking an SSTable file with `SsTable::verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// number of entries decoded from the data blocks
+    pub entries_checked: u64,
+    /// every problem found, in file order
+    pub errors: Vec<VerifyError>,
+}
+
+impl VerifyReport {
+    /// Returns true if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A problem found by `SsTable::verify`.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The header, footer, filter or index could not be read, so the data blocks were not checked.
+    Metadata(io::Error),
+    /// The sparse index does not describe contiguous blocks between the header and the filter.
+    IndexLayout { block: usize, reason: String },
+    /// A data block could not be read or decoded, for example because a record checksum failed.
+    Block { block: usize, error: io::Error },
+    /// The first key of a block differs from the one recorded in the index.
+    BlockFirstKey { block: usize, index: Vec<u8>, actual: Vec<u8> },
+    /// A key is not strictly greater than the key before it.
+    OutOfOrder { previous: Vec<u8>, key: Vec<u8> },
+    /// A key lies outside the `[min_key, max_key]` range recorded in the footer.
+    OutOfRange { key: Vec<u8> },
+    /// A key is missing from the bloom filter, so lookups for it would wrongly miss.
+    FilterMiss { key: Vec<u8> },
+    /// The prefix of a key is missing from the prefix bloom filter, so prefix checks would wrongly skip it.
+    PrefixFilterMiss { key: Vec<u8> },
+    /// The number of entries differs from the count in the header.
+    EntryCount { header: u32, actual: u64 },
+    /// The smallest key differs from the footer. The start of a range tombstone counts as a key.
+    MinKey { footer: Vec<u8>, actual: Vec<u8> },
+    /// The largest key differs from the footer. The end of a range tombstone counts as a key.
+    MaxKey { footer: Vec<u8>, actual: Vec<u8> },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Metadata(err) => write!(f, "cannot read sstable metadata: {err}"),
+            VerifyError::IndexLayout { block, reason } => write!(f, "block {block}: {reason}"),
+            VerifyError::Block { block, error } => write!(f, "block {block}: {error}"),
+            VerifyError::BlockFirstKey { block, index, actual } => {
+                write!(
+                    f,
+                    "block {block} starts with \"{}\" but the index says \"{}\"",
+                    actual.escape_ascii(),
+                    index.escape_ascii()
+                )
+            }
+            VerifyError::OutOfOrder { previous, key } => {
+                write!(f, "key \"{}\" is not after \"{}\"", key.escape_ascii(), previous.escape_ascii())
+            }
+            VerifyError::OutOfRange { key } => write!(f, "key \"{}\" is outside the footer key range", key.escape_ascii()),
+            VerifyError::FilterMiss { key } => write!(f, "key \"{}\" is missing from the bloom filter", key.escape_ascii()),
+            VerifyError::PrefixFilterMiss { key } => {
+                write!(f, "the prefix of key \"{}\" is missing from the prefix bloom filter", key.escape_ascii())
+            }
+            VerifyError::EntryCount { header, actual } => {
+                write!(f, "header counts {header} entries but the blocks hold {actual}")
+            }
+            VerifyError::MinKey { footer, actual } => {
+                write!(
+                    f,
+                    "footer min_key is \"{}\" but the first key is \"{}\"",
+                    footer.escape_ascii(),
+                    actual.escape_ascii()
+                )
+            }
+            VerifyError::MaxKey { footer, actual } => {
+                write!(
+                    f,
+                    "footer max_key is \"{}\" but the last key is \"{}\"",
+                    footer.escape_ascii(),
+                    actual.escape_ascii()
+                )
+            }
+        }
+    }
+}
+
+/// Collects errors, and tells the caller to stop after the first one in fail-fast mode.
+struct Checker {
+    report: VerifyReport,
+    fail_fast: bool,
+}
+
+impl Checker {
+    /// Records the error, returns true if verification should stop.
+    fn fail(&mut self, error: VerifyError) -> bool {
+        self.report.errors.push(error);
+        self.fail_fast
+    }
+}
+
+pub(crate) fn verify(path: &Path, options: &VerifyOptions) -> io::Result<VerifyReport> {
+    let mut file = File::open(path)?;
+    let mut checker = Checker {
+        report: VerifyReport::default(),
+        fail_fast: options.fail_fast,
+    };
+
+    let metadata = read_footer(&mut file).and_then(|footer| {
+        let filter = read_section(&mut file, footer.filter_offset, footer.filter_len, "filter")?;
+        let properties = match footer.properties {
+            Some((offset, len)) => properties::decode(&read_section(&mut file, offset, len, "properties")?)?,
+            None => Default::default(),
+        };
+        comparator::check(&properties, &*options.comparator)?;
+        // The handles of a partitioned index are gathered from its partitions
+        let (index, partitions) = match read_index(&mut file, &footer, &properties)? {
+            Index::Flat(handles) => (handles, Vec::new()),
+            Index::Partitioned(partitions) => {
+                let mut handles = Vec::new();
+                for partition in &partitions {
+                    let bytes = block::read_block(&mut file, &partition.handle)?;
+                    handles.extend(index::decode_partition(&bytes, partition)?);
+                }
+                (handles, partitions)
+            }
+        };
+        let prefix_filter = match footer.prefix_filter {
+            Some((offset, len)) => prefix::decode(&read_section(&mut file, offset, len, "prefix filter")?)?,
+            None => None,
+        };
+        let range_tombstones = match footer.range_deletions {
+            Some((offset, len)) => range_del::decode(&read_section(&mut file, offset, len, "range deletions")?)?,
+            None => Vec::new(),
+        };
+        file.rewind()?;
+        let header_count = read_entry_count(&mut file)?;
+        let filter = BloomFilter::decode(&filter);
+        Ok((footer, filter, index, partitions, prefix_filter, range_tombstones, header_count))
+    });
+    let (footer, bloom_filter, index, partitions, prefix_filter, range_tombstones, header_count) = match metadata {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            checker.fail(VerifyError::Metadata(err));
+            return Ok(checker.report);
+        }
+    };
+
+    // Blocks must tile the file between the header and the filter block
+    let mut expected_offset = 4u64;
+    for (idx, handle) in index.iter().enumerate() {
+        let reason = if handle.offset != expected_offset {
+            Some(format!("starts at {} instead of {expected_offset}", handle.offset))
+        } else if handle.len == 0 {
+            Some("is empty".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            if checker.fail(VerifyError::IndexLayout { block: idx, reason }) {
+                return Ok(checker.report);
+            }
+        }
+        expected_offset = handle.offset.saturating_add(handle.len);
+    }
+    // followed by the index partitions of a partitioned index
+    for (n, partition) in partitions.iter().enumerate() {
+        if partition.handle.offset != expected_offset
+            && checker.fail(VerifyError::IndexLayout {
+                block: index.len(),
+                reason: format!(
+                    "index partition {n} starts at {} instead of {expected_offset}",
+                    partition.handle.offset
+                ),
+            })
+        {
+            return Ok(checker.report);
+        }
+        expected_offset = partition.handle.offset.saturating_add(partition.handle.len);
+    }
+    if expected_offset != footer.filter_offset
+        && checker.fail(VerifyError::IndexLayout {
+            block: index.len(),
+            reason: format!("blocks end at {expected_offset} but the filter starts at {}", footer.filter_offset),
+        })
+    {
+        return Ok(checker.report);
+    }
+
+    let comparator = &*options.comparator;
+    let mut first_key: Option<Vec<u8>> = None;
+    let mut last_key: Option<Vec<u8>> = None;
+    for (idx, handle) in index.iter().enumerate() {
+        let entries = match block::read_block(&mut file, handle).and_then(|bytes| block::decode_block(&bytes)) {
+            Ok(entries) => entries,
+            Err(error) => {
+                if checker.fail(VerifyError::Block { block: idx, error }) {
+                    return Ok(checker.report);
+                }
+                continue;
+            }
+        };
+        let actual_first = entries.first().map_or(&[][..], |entry| entry.key.as_slice());
+        if actual_first != handle.first_key
+            && checker.fail(VerifyError::BlockFirstKey {
+                block: idx,
+                index: handle.first_key.clone(),
+                actual: actual_first.to_vec(),
+            })
+        {
+            return Ok(checker.report);
+        }
+
+        for entry in entries {
+            checker.report.entries_checked += 1;
+            let key = entry.key;
+            let mut errors = Vec::new();
+            match &last_key {
+                Some(previous) if comparator.cmp(&key, previous) != Ordering::Greater => {
+                    errors.push(VerifyError::OutOfOrder { previous: previous.clone(), key: key.clone() })
+                }
+                _ => {}
+            }
+            if comparator.cmp(&key, &footer.min_key) == Ordering::Less
+                || comparator.cmp(&key, &footer.max_key) == Ordering::Greater
+            {
+                errors.push(VerifyError::OutOfRange { key: key.clone() });
+            }
+            if !bloom_filter.may_contain(&key) {
+                errors.push(VerifyError::FilterMiss { key: key.clone() });
+            }
+            if let Some((extractor, filter)) = &prefix_filter {
+                if extractor.extract(&key).is_some_and(|prefix| !filter.may_contain(prefix)) {
+                    errors.push(VerifyError::PrefixFilterMiss { key: key.clone() });
+                }
+            }
+            for error in errors {
+                if checker.fail(error) {
+                    return Ok(checker.report);
+                }
+            }
+            if first_key.is_none() {
+                first_key = Some(key.clone());
+            }
+            last_key = Some(key);
+        }
+    }
+
+    let mut errors = Vec::new();
+    if u64::from(header_count) != checker.report.entries_checked {
+        errors.push(VerifyError::EntryCount {
+            header: header_count,
+            actual: checker.report.entries_checked,
+        });
+    }
+    // The footer range takes in the range tombstones
+    for tombstone in range_tombstones {
+        if first_key.as_ref().is_none_or(|key| comparator.cmp(&tombstone.start, key) == Ordering::Less) {
+            first_key = Some(tombstone.start);
+        }
+        if last_key.as_ref().is_none_or(|key| comparator.cmp(&tombstone.end, key) == Ordering::Greater) {
+            last_key = Some(tombstone.end);
+        }
+    }
+    let first_key = first_key.unwrap_or_default();
+    if first_key != footer.min_key {
+        errors.push(VerifyError::MinKey {
+            footer: footer.min_key,
+            actual: first_key,
+        });
+    }
+    let last_key = last_key.unwrap_or_default();
+    if last_key != footer.max_key {
+        errors.push(VerifyError::MaxKey {
+            footer: footer.max_key,
+            actual: last_key,
+        });
+    }
+    for error in errors {
+        if checker.fail(error) {
+            break;
+        }
+    }
+    Ok(checker.report)
+}
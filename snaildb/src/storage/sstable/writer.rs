@@ -0,0 +1,502 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crc32fast::Hasher;
+
+use crate::storage::bloom_filter::BloomFilter;
+use crate::storage::sstable::{
+    BlockHandle, Comparator, Compression, DEFAULT_BLOCK_SIZE, Entry, FORMAT_VERSION, MAGIC, PrefixExtractor, SsTable,
+    RangeTombstone, SsTableMetadata, SsTableOptions, Stats, TableData, block, compression, prefix, properties,
+    range_del, system_clock,
+};
+use crate::storage::sstable::block::BlockBuilder;
+use crate::storage::sstable::index::{self, Index, Partition};
+use crate::utils::record::RecordLimits;
+use crate::utils::value::Value;
+
+/// Writes an SSTable incrementally, one entry at a time, so the caller never has to hold
+/// every entry in memory. Entries must be added in strictly ascending key order, as defined by
+/// `SsTableOptions::comparator`.
+///
+/// The table is written to `<path>.tmp` and only renamed to `path` once it is complete and
+/// synced, so `path` either does not exist or holds a whole table. A writer dropped before
+/// `finish` removes its temporary file; one left behind by a crash can be cleaned up with
+/// `SsTable::remove_temp_files`.
+///
+/// Records are packed into blocks as they arrive and each finished block is written out
+/// immediately. Only the sparse index and one 8-byte hash per key (for the bloom filter)
+/// and per distinct prefix (for the prefix filter) are kept until `finish`, which writes
+/// the filter, index, stats, properties, prefix filter, range tombstones and footer, and
+/// backpatches the entry count into the header.
+pub struct SsTableWriter {
+    path: PathBuf,
+    /// where the table is written until `finish` renames it to `path`
+    temp_path: PathBuf,
+    /// set once the temporary file was renamed, so dropping the writer keeps the table
+    finished: bool,
+    /// buffered so the footer's small writes do not each cost a syscall
+    file: BufWriter<File>,
+    bloom_bits_per_key: usize,
+    compression: Compression,
+    /// buffer holding the encoded (possibly compressed) block before it is written
+    encoded_block: Vec<u8>,
+    /// offset where the next block starts
+    offset: u64,
+    index: Vec<BlockHandle>,
+    /// target size of the index partitions, `None` for a flat index
+    index_partition_size: Option<usize>,
+    block: BlockBuilder,
+    block_first_key: Vec<u8>,
+    key_hashes: Vec<u64>,
+    prefix_extractor: Option<PrefixExtractor>,
+    prefix_hashes: Vec<u64>,
+    stats: Stats,
+    properties: BTreeMap<String, String>,
+    min_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    entry_count: u32,
+    /// the range tombstones added so far, in the order they were added
+    range_tombstones: Vec<RangeTombstone>,
+    comparator: Arc<dyn Comparator>,
+    /// stamps the entries added without a timestamp, see `SsTableOptions::write_clock`
+    write_clock: Option<fn() -> u64>,
+    record_limits: RecordLimits,
+}
+
+impl SsTableWriter {
+    /// Creates the temporary file for the table at `path` and writes the header.
+    pub fn new(path: impl AsRef<Path>, options: &SsTableOptions) -> io::Result<Self> {
+        if !options.compression.is_available() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("snaildb was built without the `{}` feature", options.compression.name()),
+            ));
+        }
+        if let Some(compression) = &options.value_compression {
+            compression.check_available()?;
+        }
+        properties::validate(&options.properties)?;
+        if let Some(extractor) = &options.prefix_extractor {
+            prefix::validate(extractor)?;
+        }
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp_path = temp_path(&path);
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, File::create(&temp_path)?);
+
+        // Write header: [entry_count:4], the count is backpatched by `finish`
+        file.write_all(&0u32.to_le_bytes())?;
+
+        let mut properties = options.properties.clone();
+        properties.insert(properties::COMPARATOR.to_string(), options.comparator.name().to_string());
+
+        Ok(Self {
+            path,
+            temp_path,
+            finished: false,
+            file,
+            bloom_bits_per_key: options.bloom_bits_per_key,
+            compression: options.compression,
+            encoded_block: Vec::with_capacity(DEFAULT_BLOCK_SIZE + 1),
+            offset: 4,
+            index: Vec::new(),
+            index_partition_size: options.index_partition_size,
+            block: BlockBuilder::new(options.prefix_compression, options.value_compression),
+            block_first_key: Vec::new(),
+            key_hashes: Vec::new(),
+            prefix_extractor: options.prefix_extractor,
+            prefix_hashes: Vec::new(),
+            stats: Stats::default(),
+            properties,
+            min_key: None,
+            last_key: Vec::new(),
+            entry_count: 0,
+            range_tombstones: Vec::new(),
+            comparator: Arc::clone(&options.comparator),
+            write_clock: options.write_clock,
+            record_limits: options.record_limits,
+        })
+    }
+
+    /// Appends an entry. Returns `InvalidInput` if the key does not sort strictly after the
+    /// previously added key, and a `RecordError` if the key or value is over the limits, see
+    /// `SsTableOptions::record_limits`.
+    pub fn add(&mut self, key: impl AsRef<[u8]>, value: &Value) -> io::Result<()> {
+        self.push(key.as_ref(), value, None, 0, self.now())
+    }
+
+    /// Appends an entry written with sequence number `seqno`, see `SsTable::get_with_seqno`.
+    /// Orders keys like `add`.
+    pub fn add_with_seqno(&mut self, key: impl AsRef<[u8]>, value: &Value, seqno: u64) -> io::Result<()> {
+        self.push(key.as_ref(), value, None, seqno, self.now())
+    }
+
+    /// Appends a value that expires at `expires_at`, in milliseconds since the UNIX epoch. Once
+    /// the table clock reaches it, reads return the entry as a tombstone and merges drop its
+    /// value, see `SsTable::with_clock` and `MergeOptions::with_clock`. Orders keys like `add`.
+    pub fn add_with_expiry(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        expires_at: u64,
+    ) -> io::Result<()> {
+        self.push(key.as_ref(), &Value::Bytes(value.as_ref().to_vec()), Some(expires_at), 0, self.now())
+    }
+
+    /// Appends an entry written with sequence number `seqno` like `add_with_seqno`, expiring at
+    /// `expires_at` like `add_with_expiry` if it is set. Only raw bytes values may expire.
+    pub fn add_with_seqno_and_expiry(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: &Value,
+        seqno: u64,
+        expires_at: Option<u64>,
+    ) -> io::Result<()> {
+        self.push(key.as_ref(), value, expires_at, seqno, self.now())
+    }
+
+    /// Adds a range tombstone deleting the keys from `start`, included, to `end`, excluded,
+    /// that were written with a lower sequence number than `seqno`, in this table and in older
+    /// ones. Range tombstones may be added in any order and at any point before `finish`, and
+    /// may hold the keys of entries of the table. A range whose end does not sort after its
+    /// start deletes nothing and is left out.
+    pub fn add_range_delete(&mut self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>, seqno: u64) {
+        self.add_range_tombstone(RangeTombstone::new(start.as_ref(), end.as_ref(), seqno));
+    }
+
+    /// Adds a range tombstone read from another table, see `add_range_delete`.
+    pub(crate) fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        if !tombstone.is_empty(&*self.comparator) {
+            self.range_tombstones.push(tombstone);
+        }
+    }
+
+    /// Appends an entry read from another table, keeping its expiry, sequence number and
+    /// timestamp, or lack of one: the write clock does not stamp it.
+    pub(crate) fn add_entry(&mut self, entry: &Entry) -> io::Result<()> {
+        self.push(&entry.key, &entry.value, entry.expires_at, entry.seqno, entry.written_at)
+    }
+
+    /// Returns the time the write clock stamps entries with, 0 without one.
+    fn now(&self) -> u64 {
+        self.write_clock.map_or(0, |now| now())
+    }
+
+    fn push(
+        &mut self,
+        key: &[u8],
+        value: &Value,
+        expires_at: Option<u64>,
+        seqno: u64,
+        written_at: u64,
+    ) -> io::Result<()> {
+        self.record_limits.check(key.len(), value.byte_len())?;
+        let order = self.comparator.cmp(key, &self.last_key);
+        if self.min_key.is_some() && order != Ordering::Greater {
+            let reason = if order == Ordering::Equal { "duplicate key" } else { "out-of-order key" };
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{reason} \"{}\" after \"{}\"", key.escape_ascii(), self.last_key.escape_ascii()),
+            ));
+        }
+        self.entry_count = self
+            .entry_count
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many entries"))?;
+
+        if self.block.is_empty() {
+            self.block_first_key.clear();
+            self.block_first_key.extend_from_slice(key);
+        }
+        self.block.add(key, value, expires_at, seqno, written_at)?;
+        // Tombstones are included in the filter so a delete can shadow older tables
+        self.key_hashes.push(BloomFilter::key_hash(key));
+        if let Some(extractor) = self.prefix_extractor {
+            // Keys sharing a prefix are adjacent, so comparing with the previous key deduplicates them
+            if let Some(prefix) = extractor.extract(key) {
+                if self.min_key.is_none() || extractor.extract(&self.last_key) != Some(prefix) {
+                    self.prefix_hashes.push(BloomFilter::key_hash(prefix));
+                }
+            }
+        }
+        self.stats.add(key, value, seqno);
+        if self.min_key.is_none() {
+            self.min_key = Some(key.to_vec());
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+
+        if self.block.len() >= DEFAULT_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of entries added so far.
+    pub fn len(&self) -> usize {
+        self.entry_count as usize
+    }
+
+    /// Returns true if no entries and no range tombstones were added yet.
+    pub fn is_empty(&self) -> bool {
+        self.entry_count == 0 && self.range_tombstones.is_empty()
+    }
+
+    /// Returns the size of the data written so far, counting the block being built before
+    /// compression. The filter, index and footer that `finish` adds are not included.
+    pub fn file_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Writes the remaining block, the filter, the index, the stats, the properties, the prefix
+    /// filter, the range tombstones and the footer, syncs the file, renames it to its final
+    /// path, syncs the directory and returns the table opened lazily.
+    ///
+    /// The min and max keys of the footer take in the start and end of every range tombstone.
+    /// Fails with `InvalidInput` if neither entries nor range tombstones were added.
+    pub fn finish(mut self) -> io::Result<SsTable> {
+        let comparator = Arc::clone(&self.comparator);
+        self.range_tombstones
+            .sort_by(|a, b| comparator.cmp(&a.start, &b.start).then_with(|| comparator.cmp(&a.end, &b.end)));
+        let mut bounds = self.min_key.take().map(|min_key| (min_key, std::mem::take(&mut self.last_key)));
+        for tombstone in &self.range_tombstones {
+            let (min_key, max_key) = bounds.get_or_insert_with(|| (tombstone.start.clone(), tombstone.end.clone()));
+            if comparator.cmp(&tombstone.start, min_key) == Ordering::Less {
+                min_key.clone_from(&tombstone.start);
+            }
+            if comparator.cmp(&tombstone.end, max_key) == Ordering::Greater {
+                max_key.clone_from(&tombstone.end);
+            }
+        }
+        let Some((min_key, max_key)) = bounds else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot create an sstable without entries",
+            ));
+        };
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+
+        // A partitioned index stores its partitions right after the data blocks, the index
+        // section then holds the top-level index over them
+        let index = match self.index_partition_size {
+            Some(partition_size) => {
+                let mut partitions = Vec::new();
+                let mut first_block = 0;
+                for handles in index::split(&self.index, partition_size) {
+                    let bytes = block::encode_index(handles);
+                    self.file.write_all(&bytes)?;
+                    let handle = BlockHandle {
+                        first_key: handles[0].first_key.clone(),
+                        offset: self.offset,
+                        len: bytes.len() as u64,
+                    };
+                    partitions.push(Partition { handle, first_block, block_count: handles.len() });
+                    first_block += handles.len();
+                    self.offset += bytes.len() as u64;
+                }
+                self.properties
+                    .insert(properties::INDEX_TYPE.to_string(), index::PARTITIONED.to_string());
+                Index::Partitioned(partitions)
+            }
+            None => Index::Flat(std::mem::take(&mut self.index)),
+        };
+
+        // Write the filter block after the data blocks, it is left empty when the filter is disabled
+        let mut bloom_filter = BloomFilter::with_bits_per_key(self.key_hashes.len(), self.bloom_bits_per_key);
+        for h in &self.key_hashes {
+            bloom_filter.insert_hash(*h);
+        }
+        let filter_offset = self.offset;
+        let filter_bytes = if bloom_filter.is_empty() { Vec::new() } else { bloom_filter.encode() };
+        self.file.write_all(&filter_bytes)?;
+        let filter_len = filter_bytes.len() as u64;
+
+        // Write the sparse index right after the filter block
+        let index_offset = filter_offset + filter_len;
+        let index_bytes = index.encode();
+        self.file.write_all(&index_bytes)?;
+        let index_len = index_bytes.len() as u64;
+
+        // Write the stats block after the index
+        let stats_offset = index_offset + index_len;
+        let stats_bytes = self.stats.encode();
+        self.file.write_all(&stats_bytes)?;
+        let stats_len = stats_bytes.len() as u64;
+
+        // Write the properties block after the stats
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.properties
+            .insert(properties::CREATED_AT.to_string(), created_at.as_millis().to_string());
+        let properties_offset = stats_offset + stats_len;
+        let properties_bytes = properties::encode(&self.properties);
+        self.file.write_all(&properties_bytes)?;
+        let properties_len = properties_bytes.len() as u64;
+
+        // Write the prefix filter block after the properties, it is left empty without an extractor
+        let prefix_filter = match self.prefix_extractor {
+            Some(extractor) if self.bloom_bits_per_key > 0 => {
+                let mut filter = BloomFilter::with_bits_per_key(self.prefix_hashes.len(), self.bloom_bits_per_key);
+                for h in &self.prefix_hashes {
+                    filter.insert_hash(*h);
+                }
+                Some((extractor, filter))
+            }
+            _ => None,
+        };
+        let prefix_filter_offset = properties_offset + properties_len;
+        let prefix_filter_bytes = match &prefix_filter {
+            Some((extractor, filter)) => prefix::encode(extractor, filter),
+            None => Vec::new(),
+        };
+        self.file.write_all(&prefix_filter_bytes)?;
+        let prefix_filter_len = prefix_filter_bytes.len() as u64;
+
+        // Write the range deletion block after the prefix filter, it is left empty without range tombstones
+        let range_del_offset = prefix_filter_offset + prefix_filter_len;
+        let range_del_bytes = range_del::encode(&self.range_tombstones)?;
+        self.file.write_all(&range_del_bytes)?;
+        let range_del_len = range_del_bytes.len() as u64;
+
+        // Write footer: [min_key_len:4][min_key:var][max_key_len:4][max_key:var]
+        // [filter_offset:8][filter_len:8][index_offset:8][index_len:8][stats_offset:8][stats_len:8]
+        // [properties_offset:8][properties_len:8][prefix_filter_offset:8][prefix_filter_len:8]
+        // [range_del_offset:8][range_del_len:8][footer_crc:4][footer_offset:8][version:2][magic:8]
+        let footer_offset = range_del_offset + range_del_len;
+        let mut footer = Vec::with_capacity(min_key.len() + max_key.len() + 128);
+        footer.extend_from_slice(&(min_key.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&min_key);
+        footer.extend_from_slice(&(max_key.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&max_key);
+        for value in [
+            filter_offset,
+            filter_len,
+            index_offset,
+            index_len,
+            stats_offset,
+            stats_len,
+            properties_offset,
+            properties_len,
+            prefix_filter_offset,
+            prefix_filter_len,
+            range_del_offset,
+            range_del_len,
+        ] {
+            footer.extend_from_slice(&value.to_le_bytes());
+        }
+        // The crc covers the footer and the trailer fields after it, but not the magic
+        let mut trailer = [0u8; 10];
+        trailer[..8].copy_from_slice(&footer_offset.to_le_bytes());
+        trailer[8..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        let mut hasher = Hasher::new();
+        hasher.update(&footer);
+        hasher.update(&trailer);
+        footer.extend_from_slice(&hasher.finalize().to_le_bytes());
+        footer.extend_from_slice(&trailer);
+        footer.extend_from_slice(&MAGIC); // always last
+        let file = &mut self.file;
+        file.write_all(&footer)?;
+
+        // Seeking flushes the buffer, so the position is the real file size
+        let file_size = file.stream_position()?;
+
+        // Backpatch the header now that the entry count is known
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.entry_count.to_le_bytes())?;
+
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        std::fs::rename(&self.temp_path, &self.path)?;
+        self.finished = true;
+        sync_parent_dir(&self.path)?;
+
+        let mut stats = std::mem::take(&mut self.stats);
+        stats.file_size = file_size;
+        stats.min_key = min_key.clone();
+        stats.max_key = max_key.clone();
+        let metadata = SsTableMetadata {
+            path: std::mem::take(&mut self.path),
+            min_key,
+            max_key,
+            bloom_filter,
+            index,
+            stats,
+            format_version: FORMAT_VERSION,
+            properties: std::mem::take(&mut self.properties),
+            prefix_filter,
+            range_tombstones: std::mem::take(&mut self.range_tombstones),
+            comparator,
+        };
+        let file = File::open(&metadata.path)?;
+        Ok(SsTable {
+            metadata,
+            data: TableData::OnDisk { file },
+            block_cache: None,
+            clock: system_clock,
+            last_partition: Mutex::new(None),
+        })
+    }
+
+    /// Compresses the current block, writes it to the file and records its handle in the index.
+    fn write_block(&mut self) -> io::Result<()> {
+        let raw = self.block.finish();
+        self.encoded_block.clear();
+        compression::compress_block(self.compression, &raw, &mut self.encoded_block)?;
+        if self.block.prefix_keys() {
+            self.encoded_block[0] |= block::PREFIX_KEYS_FLAG;
+        }
+        self.file.write_all(&self.encoded_block)?;
+        let len = self.encoded_block.len() as u64;
+        self.index.push(BlockHandle {
+            first_key: self.block_first_key.clone(),
+            offset: self.offset,
+            len,
+        });
+        self.offset += len;
+        Ok(())
+    }
+}
+
+impl Drop for SsTableWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Returns the temporary path a table at `path` is written to: `path` with `.tmp` appended.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(TEMP_SUFFIX);
+    PathBuf::from(temp)
+}
+
+/// Capacity of the buffer in front of the file, a few data blocks.
+const WRITE_BUFFER_SIZE: usize = 16 * DEFAULT_BLOCK_SIZE;
+
+/// Suffix of the temporary file a table is written to before it is renamed into place.
+pub(crate) const TEMP_SUFFIX: &str = ".tmp";
+
+/// Syncs the directory holding `path`, so a rename into it survives a crash.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories cannot be opened for syncing on this platform, the rename is left to the OS.
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::io;
+
+/// A 256-bit key for the AES-256-GCM encryption of WAL records, see
+/// `WalWriterOptions::with_encryption_key`.
+///
+/// Encryption is behind the `encryption` cargo feature. Without it a key can still be built,
+/// but writing with it fails with `InvalidInput`, and reading an encrypted record fails with
+/// `Unsupported`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Wraps the 32 bytes of a key.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns true if this build can encrypt and decrypt with a key.
+    pub fn is_available() -> bool {
+        cfg!(feature = "encryption")
+    }
+}
+
+/// Leaves the key bytes out, so they do not end up in logs.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Length of the authentication tag that follows the ciphertext.
+pub(crate) const TAG_LEN: usize = 16;
+
+/// Returns the nonce of the record with sequence number `seqno`: a key never encrypts two
+/// records with the same sequence number, bar the same record written again.
+#[cfg(feature = "encryption")]
+fn nonce(seqno: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&seqno.to_le_bytes());
+    nonce
+}
+
+/// Encrypts `plaintext` under `key` as the record with sequence number `seqno`, authenticating
+/// `aad` along with it. Returns the ciphertext followed by the tag.
+#[cfg(feature = "encryption")]
+pub(crate) fn seal(key: &EncryptionKey, seqno: u64, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    let cipher = aes_gcm::Aes256Gcm::new((&key.0).into());
+    cipher
+        .encrypt((&nonce(seqno)).into(), Payload { msg: plaintext, aad })
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record encryption failed"))
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn seal(_key: &EncryptionKey, _seqno: u64, _aad: &[u8], _plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unavailable(io::ErrorKind::InvalidInput))
+}
+
+/// Decrypts the ciphertext and tag `sealed` by `seal`. Fails with `PermissionDenied` if they do
+/// not authenticate under `key`, as with the wrong key: the checksum of the frame already
+/// passed, so this is not damage.
+#[cfg(feature = "encryption")]
+pub(crate) fn open(key: &EncryptionKey, seqno: u64, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    let cipher = aes_gcm::Aes256Gcm::new((&key.0).into());
+    cipher.decrypt((&nonce(seqno)).into(), Payload { msg: sealed, aad }).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("record with sequence number {seqno} fails authentication, the encryption key is wrong"),
+        )
+    })
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn open(_key: &EncryptionKey, _seqno: u64, _aad: &[u8], _sealed: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unavailable(io::ErrorKind::Unsupported))
+}
+
+#[cfg(not(feature = "encryption"))]
+fn unavailable(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "record encryption needs snaildb built with the `encryption` feature")
+}
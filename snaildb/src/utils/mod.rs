@@ -1,5 +1,15 @@
+pub(crate) mod base64;
+pub mod encryption;
 pub mod record;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod stream;
 pub mod value;
 
-pub use record::{DecodedRecord, RecordKind, read_record, write_record, encode_batch_records};
-pub use value::Value;
+pub use record::{DecodedRecord, DecodedRecordRef, FormatVersion, RecordBuf, RecordError, RecordKind, RecordLimits,
+    RecordRefs, ValueCompression, decode_record_ref, read_record, read_record_into, read_record_with_limits,
+    write_record, write_record_compressed, write_record_with_clock, write_record_with_expiry,
+    decode_batch_records, encode_batch_records, encode_numbered_write_batch, encode_write_batch, read_batch};
+pub use encryption::EncryptionKey;
+pub use stream::{RecordHeader, RecordTrailer, ValueReader, read_record_header};
+pub use value::{AppendOperator, MergeFn, MergeOperator, U64AddOperator, Value};
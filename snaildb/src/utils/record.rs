@@ -1,21 +1,62 @@
 use crc32fast::Hasher;
+use std::borrow::Cow;
+use std::fmt;
 use std::io::{self, Read, Write};
 
-#[derive(Debug, Clone, Copy)]
+use crate::storage::sstable::compression::{Compression, lz4_compress, lz4_decompress, zstd_compress, zstd_decompress};
+use crate::utils::encryption::{self, EncryptionKey, TAG_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
 pub enum RecordKind {
     Set = 1,
     Delete = 2,
+    /// A set that expires, its payload ends with the expiry timestamp
+    SetWithTtl = 3,
+    /// A merge operand, combined with the older value of the key when it is read, see
+    /// `SnailDb::merge`. Builds from before it reject the record as of an unknown kind
+    Merge = 4,
+    /// Deletes every key from the key of the record, included, to its value, excluded. Builds
+    /// from before it reject the record as of an unknown kind
+    RangeDelete = 5,
+    /// A set of a typed value, encoded by `Value::encode`. Sets of raw bytes stay `Set` records,
+    /// so they read the same as before. Builds from before it reject the record as of an
+    /// unknown kind
+    Typed = 6,
 }
 
+/// Set in the kind byte when the payload ends with a sequence number. Records with
+/// sequence number 0 leave it out, so they are encoded as before sequence numbers existed.
+pub(crate) const SEQNO_FLAG: u8 = 0x80;
+
+/// Set in the kind byte when the value is compressed, with lz4 unless the zstd flag is set
+/// too, see `ValueCompression`. The value length then is that of the compressed value. Kind
+/// bytes 0x40 and up without the sequence number flag head other frames, so this takes the bit
+/// below.
+pub(crate) const COMPRESSED_FLAG: u8 = 0x20;
+
+/// Set in the kind byte, together with the compressed flag, when the value is compressed with
+/// zstd rather than lz4. Builds from before it reject the record as of an unknown kind.
+pub(crate) const ZSTD_FLAG: u8 = 0x08;
+
+/// Set in the kind byte when the payload ends with the time the record was written, see
+/// `write_record_with_clock`. Records without a timestamp leave it out, so they are encoded as
+/// before timestamps existed and records written before then decode with timestamp 0.
+pub(crate) const TIMESTAMP_FLAG: u8 = 0x10;
+
 impl RecordKind {
     fn as_byte(self) -> u8 {
         self as u8
     }
 
-    fn from_byte(byte: u8) -> io::Result<Self> {
+    pub(crate) fn from_byte(byte: u8) -> io::Result<Self> {
         match byte {
             1 => Ok(RecordKind::Set),
             2 => Ok(RecordKind::Delete),
+            3 => Ok(RecordKind::SetWithTtl),
+            4 => Ok(RecordKind::Merge),
+            5 => Ok(RecordKind::RangeDelete),
+            6 => Ok(RecordKind::Typed),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("unknown record kind {byte}"),
@@ -24,27 +65,355 @@ impl RecordKind {
     }
 }
 
+/// Kind byte of the frame heading a batch of records written atomically by the WAL. Its payload
+/// is [kind][count:u32][body_length:u32][body_crc32:u32], and the `count` records of the
+/// batch follow it, `body_length` bytes in total with the checksum `body_crc32`.
+const BATCH_HEADER_KIND: u8 = 0x40;
+
+/// Length of the payload of a batch header.
+const BATCH_HEADER_PAYLOAD_LEN: usize = 13;
+
+/// Kind byte of the frame the WAL leaves where it emptied the log, carrying the last sequence
+/// number it gave out so a restart continues after it. Its payload is [kind][seqno:u64].
+const SEQNO_MARKER_KIND: u8 = 0x41;
+
+/// Length of the payload of a sequence number marker.
+pub(crate) const SEQNO_MARKER_PAYLOAD_LEN: usize = 9;
+
+/// Kind byte of the frame of an encrypted record. Its payload is
+/// [kind][seqno:u64][ciphertext_length:u32][ciphertext][tag:16], the ciphertext being the
+/// payload of the record encrypted with AES-256-GCM, with a nonce made of the sequence number
+/// and the three fields before it as associated data.
+const ENCRYPTED_RECORD_KIND: u8 = 0x42;
+
+/// Length of the fields of an encrypted record in front of the ciphertext.
+const ENCRYPTED_HEADER_LEN: usize = 13;
+
+/// Kind byte of the frame a WAL file starts with from format version 2 on, see `FormatVersion`.
+/// Its payload is [kind][version:u8].
+pub(crate) const FILE_HEADER_KIND: u8 = 0x43;
+
+/// Length of the payload of a file header.
+pub(crate) const FILE_HEADER_PAYLOAD_LEN: usize = 2;
+
+/// The format version of a WAL file, recorded in a header frame at its start.
+///
+/// Version 1 files are frames back to back, records, batches and sequence number markers, as
+/// written before files had a version: a file without a header is of version 1. From version
+/// 2 on the file starts with the header, written with its first records so an empty file
+/// stays empty, and the frames after it are those of version 1.
+/// Writers write `FormatVersion::LATEST` unless told otherwise, see
+/// `WalWriterOptions::with_format_version`. Readers read every version up to it and refuse a
+/// file of a newer one with `Unsupported`, naming both versions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FormatVersion {
+    /// Frames without a file header
+    V1 = 1,
+    /// A file header, then the frames of version 1
+    #[default]
+    V2 = 2,
+}
+
+impl FormatVersion {
+    /// The version written by this build.
+    pub const LATEST: FormatVersion = FormatVersion::V2;
+
+    pub fn as_byte(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the version numbered `version`. Fails with `Unsupported` if it is newer than
+    /// `LATEST`, and with `InvalidData` for 0, which no file has.
+    pub fn from_byte(version: u8) -> io::Result<Self> {
+        match version {
+            1 => Ok(FormatVersion::V1),
+            2 => Ok(FormatVersion::V2),
+            0 => Err(io::Error::new(io::ErrorKind::InvalidData, "WAL format version 0")),
+            // Not `InvalidData`, which a replay could take for a torn tail and drop
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "WAL format version {version} is newer than the supported version {}",
+                    Self::LATEST.as_byte()
+                ),
+            )),
+        }
+    }
+}
+
 // a record decoded from the binary format
 // the on-disk binary format is (little endian unless noted):
 // [length:u32][crc32:u32][kind:u8][key_length:varint][key][value_length:varint][value]
+// followed by [expires_at:u64] for SetWithTtl records, [seqno:u64] when the kind byte
+// has the sequence number flag and [timestamp:u64] when it has the timestamp flag. The value
+// is compressed when the kind byte has the compressed flag, and decompressed when decoded.
+// The lengths are varints, see `encode_var_u32`, and have been since the first version of the format
+// With the `serde` feature the key and value are written as strings when they are UTF-8, see
+// `utils::serialize`
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DecodedRecord {
-    pub kind: RecordKind, // 1 for set, 2 for delete
-    pub key: String,
+    pub kind: RecordKind, // 1 for set, 2 for delete, 3 for set with ttl, 4 for merge, 5 for range delete
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::utils::serialize::serialize_bytes"),
+        serde(deserialize_with = "crate::utils::serialize::deserialize_bytes")
+    )]
+    pub key: Vec<u8>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::utils::serialize::serialize_bytes"),
+        serde(deserialize_with = "crate::utils::serialize::deserialize_bytes")
+    )]
     pub value: Vec<u8>,
     pub crc32: u32,        // checksum of each record
     pub length: u32,       // length of the record payload
     pub key_length: u32,   // length of the key portion
-    pub value_length: u32, // length of the value portion
+    pub value_length: u32, // length of the value portion, compressed if the value is
+    pub timestamp: u64,          // when the record was written, in milliseconds since the UNIX epoch, 0 if unknown
+    pub expires_at: Option<u64>, // expiry of a SetWithTtl record, in milliseconds since the UNIX epoch
+    pub seqno: u64,              // sequence number of the write, 0 for records written without one
+}
+
+/// A record decoded in place, borrowing its key and value from the bytes it was decoded from,
+/// see `decode_record_ref`. The fields are those of `DecodedRecord`, but for the value, which
+/// is borrowed as stored: compressed with `compression` unless that is `Compression::None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodedRecordRef<'a> {
+    pub kind: RecordKind,
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+    pub compression: Compression,
+    pub crc32: u32,
+    pub length: u32,
     pub timestamp: u64,
+    pub expires_at: Option<u64>,
+    pub seqno: u64,
+}
+
+impl<'a> DecodedRecordRef<'a> {
+    /// Returns the value, borrowed unless it has to be decompressed. Fails with `Unsupported`
+    /// if this build lacks the codec it was compressed with.
+    pub fn decompressed_value(&self) -> io::Result<Cow<'a, [u8]>> {
+        match self.compression {
+            Compression::None => Ok(Cow::Borrowed(self.value)),
+            codec => decompress_value(codec, self.value).map(Cow::Owned),
+        }
+    }
+
+    /// Copies the key and value out, decompressing the value, as `read_record` would have
+    /// returned the record.
+    pub fn into_owned(self) -> io::Result<DecodedRecord> {
+        Ok(DecodedRecord {
+            kind: self.kind,
+            key: self.key.to_vec(),
+            value: self.decompressed_value()?.into_owned(),
+            crc32: self.crc32,
+            length: self.length,
+            key_length: self.key.len() as u32,
+            value_length: self.value.len() as u32,
+            timestamp: self.timestamp,
+            expires_at: self.expires_at,
+            seqno: self.seqno,
+        })
+    }
+}
+
+/// Compression of the values of records: a value longer than `threshold` bytes is compressed
+/// with `codec`, and stored as it is if that does not make it smaller. The record says which
+/// codec it was compressed with, and `read_record` decompresses it. See `write_record_compressed`,
+/// `WalWriterOptions::with_compression_codec` and `SsTableOptions::with_value_compression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueCompression {
+    pub codec: Compression,
+    pub threshold: usize,
+}
+
+impl ValueCompression {
+    pub fn new(codec: Compression, threshold: usize) -> Self {
+        Self { codec, threshold }
+    }
+
+    /// Fails with `InvalidInput` if this build lacks the codec.
+    pub(crate) fn check_available(&self) -> io::Result<()> {
+        if self.codec.is_available() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("value compression needs snaildb built with the `{}` feature", self.codec.name()),
+        ))
+    }
+
+    /// Returns `value` compressed, with the flags saying how, if it is worth compressing.
+    fn compress(&self, value: &[u8]) -> io::Result<Option<(Vec<u8>, u8)>> {
+        if value.len() <= self.threshold {
+            return Ok(None);
+        }
+        let compressed = match self.codec {
+            Compression::None => None,
+            Compression::Lz4 => lz4_compress(value).map(|compressed| (compressed, COMPRESSED_FLAG)),
+            Compression::Zstd => zstd_compress(value)?.map(|compressed| (compressed, COMPRESSED_FLAG | ZSTD_FLAG)),
+        };
+        Ok(compressed.filter(|(compressed, _)| compressed.len() < value.len()))
+    }
+}
+
+/// Decompresses a record value compressed with `codec`.
+fn decompress_value(codec: Compression, value: &[u8]) -> io::Result<Vec<u8>> {
+    if !codec.is_available() {
+        // Not `InvalidData`, which a replay could take for a torn tail and drop
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "record compressed with {name}, but snaildb was built without the `{name}` feature",
+                name = codec.name()
+            ),
+        ));
+    }
+    match codec {
+        Compression::None => Ok(value.to_vec()),
+        Compression::Lz4 => lz4_decompress(value),
+        Compression::Zstd => zstd_decompress(value),
+    }
+}
+
+/// The longest keys and values of records, 64 KiB and 256 MiB by default. Writers refuse
+/// longer ones with `RecordError::KeyTooLarge` and `RecordError::ValueTooLarge`, and readers
+/// refuse a record whose length header claims more than a record within the limits takes,
+/// with `RecordError::RecordTooLarge`, rather than allocate what a damaged length asks for. A
+/// log written with higher limits must be read with them, see `ReplayOptions::record_limits`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordLimits {
+    pub max_key_len: usize,
+    /// the longest value before compression
+    pub max_value_len: usize,
+}
+
+impl RecordLimits {
+    pub const DEFAULT_MAX_KEY_LEN: usize = 64 * 1024;
+    pub const DEFAULT_MAX_VALUE_LEN: usize = 256 * 1024 * 1024;
+
+    /// Sets the longest key, in bytes.
+    pub fn with_max_key_len(mut self, max_key_len: usize) -> Self {
+        self.max_key_len = max_key_len;
+        self
+    }
+
+    /// Sets the longest value, in bytes.
+    pub fn with_max_value_len(mut self, max_value_len: usize) -> Self {
+        self.max_value_len = max_value_len;
+        self
+    }
+
+    /// Fails if a key of `key_len` bytes or a value of `value_len` bytes is over the limits.
+    pub fn check(&self, key_len: usize, value_len: usize) -> Result<(), RecordError> {
+        if key_len > self.max_key_len {
+            return Err(RecordError::KeyTooLarge { len: key_len, max: self.max_key_len });
+        }
+        if value_len > self.max_value_len {
+            return Err(RecordError::ValueTooLarge { len: value_len, max: self.max_value_len });
+        }
+        Ok(())
+    }
+
+    /// Returns the longest payload of a frame holding a record within the limits: the key, the
+    /// value, their lengths, the kind byte, the expiry, sequence number and timestamp, and the
+    /// header and tag of an encrypted record.
+    fn max_payload_len(&self) -> usize {
+        const OVERHEAD: usize = 1 + 2 * 5 + 3 * 8 + ENCRYPTED_HEADER_LEN + TAG_LEN;
+        self.max_key_len.saturating_add(self.max_value_len).saturating_add(OVERHEAD)
+    }
+
+    /// Fails if a frame whose length header says `len` bytes cannot hold a record within the limits.
+    fn check_payload_len(&self, len: usize) -> Result<(), RecordError> {
+        let max = self.max_payload_len();
+        if len > max {
+            return Err(RecordError::RecordTooLarge { len, max });
+        }
+        Ok(())
+    }
+}
+
+impl Default for RecordLimits {
+    fn default() -> Self {
+        Self { max_key_len: Self::DEFAULT_MAX_KEY_LEN, max_value_len: Self::DEFAULT_MAX_VALUE_LEN }
+    }
+}
+
+/// A record over the `RecordLimits`. Returned wrapped in an `io::Error`, of kind `InvalidInput`
+/// when writing and `InvalidData` when reading, where `io::Error::get_ref` and `downcast_ref`
+/// recover it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordError {
+    /// A key of `len` bytes was written, longer than the `max` allowed.
+    KeyTooLarge { len: usize, max: usize },
+    /// A value of `len` bytes was written, longer than the `max` allowed.
+    ValueTooLarge { len: usize, max: usize },
+    /// A record read claims a payload of `len` bytes, longer than the `max` a record within the
+    /// limits takes, so its length header is most likely damaged.
+    RecordTooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::KeyTooLarge { len, max } => write!(f, "key of {len} bytes is longer than the {max} allowed"),
+            RecordError::ValueTooLarge { len, max } => {
+                write!(f, "value of {len} bytes is longer than the {max} allowed")
+            }
+            RecordError::RecordTooLarge { len, max } => {
+                write!(f, "record length {len} is longer than the {max} bytes a record can take")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl From<RecordError> for io::Error {
+    fn from(err: RecordError) -> Self {
+        let kind = match err {
+            RecordError::KeyTooLarge { .. } | RecordError::ValueTooLarge { .. } => io::ErrorKind::InvalidInput,
+            RecordError::RecordTooLarge { .. } => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
 }
 
 /// Encodes a record into a buffer in the format: [length:u32][crc32:u32][payload]
-/// where payload is: [kind:u8][key_len_varint][key][value_len_varint][value]
-fn encode_record_to_buffer(
+/// where payload is: [kind:u8][key_len_varint][key][value_len_varint][value], followed by
+/// [expires_at:u64] for SetWithTtl records, by [seqno:u64] unless `seqno` is 0 and by
+/// [timestamp:u64] unless `timestamp` is 0. Only SetWithTtl records take an expiry. The value
+/// is compressed as `compression` says, see `ValueCompression`.
+pub(crate) fn encode_record_to_buffer(
     kind: RecordKind,
-    key: &str,
+    key: &[u8],
     value: &[u8],
+    expires_at: Option<u64>,
+    seqno: u64,
+    timestamp: u64,
+    compression: Option<ValueCompression>,
 ) -> io::Result<Vec<u8>> {
+    let expiry = match (kind, expires_at) {
+        (RecordKind::SetWithTtl, Some(expires_at)) => Some(expires_at.to_le_bytes()),
+        (RecordKind::Set | RecordKind::Delete | RecordKind::Merge | RecordKind::RangeDelete, None) => None,
+        (RecordKind::Typed, None) => None,
+        (RecordKind::SetWithTtl, None) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "SetWithTtl record without an expiry"));
+        }
+        (_, Some(_)) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only SetWithTtl records take an expiry"));
+        }
+    };
+    let compressed = match compression {
+        Some(compression) => compression.compress(value)?,
+        None => None,
+    };
+    let (value, compressed_flag) = match &compressed {
+        Some((compressed, flags)) => (compressed.as_slice(), *flags),
+        None => (value, 0),
+    };
     let key_len: u32 = key
         .len()
         .try_into()
@@ -59,14 +428,28 @@ fn encode_record_to_buffer(
     let key_len_encoded = encode_var_u32(key_len);
     let value_len_encoded = encode_var_u32(value_len);
 
-    let payload_len = 1 + key_len_encoded.len() + key.len() + value_len_encoded.len() + value.len();
+    let payload_len = 1 + key_len_encoded.len() + key.len() + value_len_encoded.len() + value.len()
+        + expiry.map_or(0, |bytes| bytes.len())
+        + if seqno == 0 { 0 } else { 8 }
+        + if timestamp == 0 { 0 } else { 8 };
 
     let mut payload = Vec::with_capacity(payload_len);
-    payload.push(kind.as_byte());
+    let seqno_flag = if seqno == 0 { 0 } else { SEQNO_FLAG };
+    let timestamp_flag = if timestamp == 0 { 0 } else { TIMESTAMP_FLAG };
+    payload.push(kind.as_byte() | seqno_flag | compressed_flag | timestamp_flag);
     payload.extend_from_slice(&key_len_encoded);
-    payload.extend_from_slice(key.as_bytes());
+    payload.extend_from_slice(key);
     payload.extend_from_slice(&value_len_encoded);
     payload.extend_from_slice(value);
+    if let Some(bytes) = expiry {
+        payload.extend_from_slice(&bytes);
+    }
+    if seqno != 0 {
+        payload.extend_from_slice(&seqno.to_le_bytes());
+    }
+    if timestamp != 0 {
+        payload.extend_from_slice(&timestamp.to_le_bytes());
+    }
 
     let length: u32 = payload
         .len()
@@ -86,18 +469,301 @@ fn encode_record_to_buffer(
     Ok(buffer)
 }
 
+/// Writes a record. `seqno` is the sequence number of the write, 0 if it has none. Fails with
+/// a `RecordError` if the key or value is over the default `RecordLimits`.
 pub fn write_record<W: Write>(
     writer: &mut W,
     kind: RecordKind,
-    key: &str,
+    key: &[u8],
+    value: &[u8],
+    seqno: u64,
+) -> io::Result<()> {
+    RecordLimits::default().check(key.len(), value.len())?;
+    let buffer = encode_record_to_buffer(kind, key, value, None, seqno, 0, None)?;
+    writer.write_all(&buffer)?;
+    Ok(())
+}
+
+/// Writes a record like `write_record`, stamped with the time `clock` returns, in milliseconds
+/// since the UNIX epoch, see `DecodedRecord::timestamp`. A clock returning 0 stamps nothing.
+pub fn write_record_with_clock<W: Write>(
+    writer: &mut W,
+    kind: RecordKind,
+    key: &[u8],
+    value: &[u8],
+    seqno: u64,
+    clock: fn() -> u64,
+) -> io::Result<()> {
+    RecordLimits::default().check(key.len(), value.len())?;
+    let buffer = encode_record_to_buffer(kind, key, value, None, seqno, clock(), None)?;
+    writer.write_all(&buffer)?;
+    Ok(())
+}
+
+/// Writes a SetWithTtl record that expires at `expires_at`, in milliseconds since the UNIX epoch.
+pub fn write_record_with_expiry<W: Write>(
+    writer: &mut W,
+    key: &[u8],
     value: &[u8],
+    expires_at: u64,
+    seqno: u64,
 ) -> io::Result<()> {
-    let buffer = encode_record_to_buffer(kind, key, value)?;
+    RecordLimits::default().check(key.len(), value.len())?;
+    let buffer = encode_record_to_buffer(RecordKind::SetWithTtl, key, value, Some(expires_at), seqno, 0, None)?;
     writer.write_all(&buffer)?;
     Ok(())
 }
 
+/// Writes a record like `write_record`, its value compressed as `compression` says. Fails with
+/// `InvalidInput` if this build lacks the codec. `read_record` decompresses the value.
+pub fn write_record_compressed<W: Write>(
+    writer: &mut W,
+    kind: RecordKind,
+    key: &[u8],
+    value: &[u8],
+    seqno: u64,
+    compression: ValueCompression,
+) -> io::Result<()> {
+    compression.check_available()?;
+    RecordLimits::default().check(key.len(), value.len())?;
+    let buffer = encode_record_to_buffer(kind, key, value, None, seqno, 0, Some(compression))?;
+    writer.write_all(&buffer)?;
+    Ok(())
+}
+
+/// Reads the next record, or returns `None` at the end of the input. Fails with `InvalidData`
+/// if the record is damaged, and with `RecordError::RecordTooLarge` if its length is over what
+/// a record within the default `RecordLimits` takes, see `read_record_with_limits`. The header
+/// a WAL file starts with is skipped, and a file of a newer format fails, see `FormatVersion`.
 pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<DecodedRecord>> {
+    read_record_with_limits(reader, RecordLimits::default())
+}
+
+/// Reads the next record like `read_record`, of a log written with keys and values up to `limits`.
+pub fn read_record_with_limits<R: Read>(reader: &mut R, limits: RecordLimits) -> io::Result<Option<DecodedRecord>> {
+    let mut buf = RecordBuf::default().with_limits(limits);
+    Ok(read_record_into(reader, &mut buf)?.map(|()| buf.to_record()))
+}
+
+/// Buffers a record is read into by `read_record_into`, reused from one record to the next: a
+/// scan allocates only while they grow to the size of the largest record, and for the values
+/// it decompresses.
+#[derive(Clone, Debug)]
+pub struct RecordBuf {
+    limits: RecordLimits,
+    payload: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    kind: RecordKind,
+    crc32: u32,
+    length: u32,
+    /// the length of the value as stored, compressed or not
+    value_length: u32,
+    timestamp: u64,
+    expires_at: Option<u64>,
+    seqno: u64,
+}
+
+impl Default for RecordBuf {
+    fn default() -> Self {
+        Self {
+            limits: RecordLimits::default(),
+            payload: Vec::new(),
+            key: Vec::new(),
+            value: Vec::new(),
+            kind: RecordKind::Set,
+            crc32: 0,
+            length: 0,
+            value_length: 0,
+            timestamp: 0,
+            expires_at: None,
+            seqno: 0,
+        }
+    }
+}
+
+impl RecordBuf {
+    /// Reads records with keys and values up to `limits`, see `read_record_with_limits`.
+    pub fn with_limits(mut self, limits: RecordLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Returns the kind of the record last read.
+    pub fn kind(&self) -> RecordKind {
+        self.kind
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the value, decompressed.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Returns when the record was written, 0 if unknown.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Copies the record out as `read_record` returns it.
+    pub fn to_record(&self) -> DecodedRecord {
+        DecodedRecord {
+            kind: self.kind,
+            key: self.key.clone(),
+            value: self.value.clone(),
+            crc32: self.crc32,
+            length: self.length,
+            key_length: self.key.len() as u32,
+            value_length: self.value_length,
+            timestamp: self.timestamp,
+            expires_at: self.expires_at,
+            seqno: self.seqno,
+        }
+    }
+}
+
+/// Reads the next record like `read_record`, into `buf` rather than into a new record: its
+/// buffers are cleared and refilled, keeping their capacity. Returns `None` at the end of the
+/// input, leaving `buf` as it was.
+pub fn read_record_into<R: Read>(reader: &mut R, buf: &mut RecordBuf) -> io::Result<Option<()>> {
+    loop {
+        let Some((length, crc32)) = read_payload_into(reader, buf.limits, &mut buf.payload)? else {
+            return Ok(None);
+        };
+        if buf.payload.first() == Some(&FILE_HEADER_KIND) {
+            decode_file_header(&buf.payload)?;
+            continue;
+        }
+        let record = decode_payload_ref(length, crc32, &buf.payload)?;
+        buf.key.clear();
+        buf.key.extend_from_slice(record.key);
+        match record.compression {
+            Compression::None => {
+                buf.value.clear();
+                buf.value.extend_from_slice(record.value);
+            }
+            codec => buf.value = decompress_value(codec, record.value)?,
+        }
+        buf.kind = record.kind;
+        buf.crc32 = record.crc32;
+        buf.length = record.length;
+        buf.value_length = record.value.len() as u32;
+        buf.timestamp = record.timestamp;
+        buf.expires_at = record.expires_at;
+        buf.seqno = record.seqno;
+        return Ok(Some(()));
+    }
+}
+
+/// Decodes the record at the start of `bytes` without copying it, returning it with the
+/// number of bytes it takes, or `None` if `bytes` is empty. Fails like `read_record` on a
+/// damaged or truncated record. A compressed value is borrowed as stored, see
+/// `DecodedRecordRef::decompressed_value`.
+pub fn decode_record_ref(bytes: &[u8]) -> io::Result<Option<(DecodedRecordRef<'_>, usize)>> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let header = |pos: usize, what: &str| {
+        bytes
+            .get(pos..pos + 4)
+            .map(|word| u32::from_le_bytes(word.try_into().expect("4-byte slice")))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, format!("truncated record {what}")))
+    };
+    let length = header(0, "length header")?;
+    let crc32 = header(4, "crc32")?;
+    let end = usize::try_from(length)
+        .ok()
+        .and_then(|len| len.checked_add(8))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "record length too large"))?;
+    let payload = bytes
+        .get(8..end)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "record truncated while reading payload"))?;
+    check_crc(crc32, payload)?;
+    Ok(Some((decode_payload_ref(length, crc32, payload)?, end)))
+}
+
+/// Iterates over the records of a buffer of back to back records, such as a data block of a
+/// table, decoding each in place with `decode_record_ref`. Stops after the first error.
+pub struct RecordRefs<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> RecordRefs<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for RecordRefs<'a> {
+    type Item = io::Result<DecodedRecordRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match decode_record_ref(self.bytes) {
+            Ok(Some((record, len))) => {
+                self.bytes = &self.bytes[len..];
+                Some(Ok(record))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                self.bytes = &[];
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Reads the next frame of a WAL: a record, the header of a batch of records, a sequence
+/// number marker or the header of the file. An encrypted record is decrypted with `key`, see `decode_encrypted_record`.
+/// A frame longer than a record within `limits` fails, see `RecordError::RecordTooLarge`.
+pub(crate) fn read_frame<R: Read>(
+    reader: &mut R,
+    key: Option<&EncryptionKey>,
+    limits: RecordLimits,
+) -> io::Result<Option<Frame>> {
+    let Some((length, crc32, payload)) = read_payload(reader, limits)? else {
+        return Ok(None);
+    };
+    if length == 0 {
+        // No frame is empty, zeros are the unwritten end of a preallocated segment
+        return Ok(None);
+    }
+    match payload.first() {
+        Some(&BATCH_HEADER_KIND) => decode_batch_header(&payload).map(|header| Some(Frame::Batch(header))),
+        Some(&SEQNO_MARKER_KIND) => decode_seqno_marker(&payload).map(|seqno| Some(Frame::SeqnoMarker(seqno))),
+        Some(&FILE_HEADER_KIND) => decode_file_header(&payload).map(|_| Some(Frame::FileHeader)),
+        Some(&ENCRYPTED_RECORD_KIND) => {
+            decode_encrypted_record(length, crc32, &payload, key).map(|record| Some(Frame::Record(record)))
+        }
+        _ => decode_payload(length, crc32, &payload).map(|record| Some(Frame::Record(record))),
+    }
+}
+
+/// Reads the `[length:u32][crc32:u32]` header of a frame and its payload, checking the checksum.
+/// Returns `None` at the end of the input. The length is checked against `limits` before the
+/// payload is allocated.
+fn read_payload<R: Read>(reader: &mut R, limits: RecordLimits) -> io::Result<Option<(u32, u32, Vec<u8>)>> {
+    let mut payload = Vec::new();
+    let header = read_payload_into(reader, limits, &mut payload)?;
+    Ok(header.map(|(length, crc32)| (length, crc32, payload)))
+}
+
+/// Reads a frame like `read_payload`, into `payload`, which is cleared first.
+fn read_payload_into<R: Read>(
+    reader: &mut R,
+    limits: RecordLimits,
+    payload: &mut Vec<u8>,
+) -> io::Result<Option<(u32, u32)>> {
     let length = match read_u32_or_eof(reader)? {
         Some(len) => len,
         None => return Ok(None),
@@ -107,19 +773,38 @@ pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<DecodedRecord>>
     let payload_len: usize = length
         .try_into()
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "record length too large"))?;
-    let mut payload = vec![0u8; payload_len];
-    reader.read_exact(&mut payload)?;
+    limits.check_payload_len(payload_len)?;
+    payload.clear();
+    payload.resize(payload_len, 0);
+    reader.read_exact(payload)?;
+    check_crc(crc32, payload)?;
+    Ok(Some((length, crc32)))
+}
 
+/// Checks the payload of a frame against the checksum of its header.
+pub(crate) fn check_crc(crc32: u32, payload: &[u8]) -> io::Result<()> {
     let mut hasher = Hasher::new();
-    hasher.update(&payload);
+    hasher.update(payload);
     let computed_crc = hasher.finalize();
     if computed_crc != crc32 {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            "crc mismatch while reading record",
+            format!(
+                "crc mismatch while reading record for key \"{}\": expected {crc32:#010x}, computed {computed_crc:#010x}",
+                corrupt_record_key(payload).escape_ascii()
+            ),
         ));
     }
+    Ok(())
+}
+
+/// Decodes the payload of a record, see `DecodedRecord` for its layout.
+fn decode_payload(length: u32, crc32: u32, payload: &[u8]) -> io::Result<DecodedRecord> {
+    decode_payload_ref(length, crc32, payload)?.into_owned()
+}
 
+/// Decodes the payload of a record in place. The value is returned as stored.
+fn decode_payload_ref(length: u32, crc32: u32, payload: &[u8]) -> io::Result<DecodedRecordRef<'_>> {
     let mut cursor = 0usize;
 
     let kind_byte = *payload.get(cursor).ok_or_else(|| {
@@ -129,9 +814,17 @@ pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<DecodedRecord>>
         )
     })?;
     cursor += 1;
-    let kind = RecordKind::from_byte(kind_byte)?;
+    let kind = RecordKind::from_byte(kind_byte & !(SEQNO_FLAG | COMPRESSED_FLAG | TIMESTAMP_FLAG | ZSTD_FLAG))?;
+    let compression = match (kind_byte & COMPRESSED_FLAG != 0, kind_byte & ZSTD_FLAG != 0) {
+        (false, false) => Compression::None,
+        (true, false) => Compression::Lz4,
+        (true, true) => Compression::Zstd,
+        (false, true) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown record kind {kind_byte}")));
+        }
+    };
 
-    let key_len = decode_var_u32(&payload, &mut cursor)?;
+    let key_len = decode_var_u32(payload, &mut cursor)?;
     let key_len_usize: usize = key_len
         .try_into()
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "key too large in record"))?;
@@ -144,13 +837,10 @@ pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<DecodedRecord>>
             "record truncated while reading key",
         ));
     }
-    let key_bytes = &payload[cursor..key_end];
+    let key = &payload[cursor..key_end];
     cursor = key_end;
 
-    let key = String::from_utf8(key_bytes.to_vec())
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "key bytes are not valid UTF-8"))?;
-
-    let value_len = decode_var_u32(&payload, &mut cursor)?;
+    let value_len = decode_var_u32(payload, &mut cursor)?;
     let value_len_usize: usize = value_len
         .try_into()
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "value too large in record"))?;
@@ -163,9 +853,49 @@ pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<DecodedRecord>>
             "record truncated while reading value",
         ));
     }
-    let value = payload[cursor..value_end].to_vec();
+    let value = &payload[cursor..value_end];
     cursor = value_end;
 
+    let expires_at = match kind {
+        RecordKind::SetWithTtl => {
+            let bytes = payload.get(cursor..cursor + 8).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "record truncated while reading expiry",
+                )
+            })?;
+            cursor += 8;
+            Some(u64::from_le_bytes(bytes.try_into().expect("8-byte slice")))
+        }
+        RecordKind::Set | RecordKind::Delete | RecordKind::Merge | RecordKind::RangeDelete | RecordKind::Typed => None,
+    };
+
+    let seqno = if kind_byte & SEQNO_FLAG == 0 {
+        0
+    } else {
+        let bytes = payload.get(cursor..cursor + 8).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record truncated while reading sequence number",
+            )
+        })?;
+        cursor += 8;
+        u64::from_le_bytes(bytes.try_into().expect("8-byte slice"))
+    };
+
+    let timestamp = if kind_byte & TIMESTAMP_FLAG == 0 {
+        0
+    } else {
+        let bytes = payload.get(cursor..cursor + 8).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record truncated while reading timestamp",
+            )
+        })?;
+        cursor += 8;
+        u64::from_le_bytes(bytes.try_into().expect("8-byte slice"))
+    };
+
     if cursor != payload.len() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
@@ -173,19 +903,60 @@ pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<DecodedRecord>>
         ));
     }
 
-    Ok(Some(DecodedRecord {
-        kind,
-        key,
-        value,
-        crc32,
-        length,
-        key_length: key_len,
-        value_length: value_len,
-        timestamp: 0,
-    }))
+    Ok(DecodedRecordRef { kind, key, value, compression, crc32, length, timestamp, expires_at, seqno })
+}
+
+/// Decrypts and decodes the payload of an encrypted record. Fails with `PermissionDenied`
+/// without a key or with the wrong one, and with `Unsupported` without the `encryption`
+/// feature: neither is damage, so a replay does not drop the record as a torn tail.
+fn decode_encrypted_record(
+    length: u32,
+    crc32: u32,
+    payload: &[u8],
+    key: Option<&EncryptionKey>,
+) -> io::Result<DecodedRecord> {
+    if payload.len() < ENCRYPTED_HEADER_LEN + TAG_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "encrypted record truncated"));
+    }
+    let seqno = u64::from_le_bytes(payload[1..9].try_into().expect("8-byte slice"));
+    let ciphertext_len = u32::from_le_bytes(payload[9..13].try_into().expect("4-byte slice"));
+    if ENCRYPTED_HEADER_LEN as u64 + u64::from(ciphertext_len) + TAG_LEN as u64 != payload.len() as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("encrypted record of {} bytes holds a ciphertext of {ciphertext_len}", payload.len()),
+        ));
+    }
+    let Some(key) = key else {
+        let kind = match EncryptionKey::is_available() {
+            true => io::ErrorKind::PermissionDenied,
+            false => io::ErrorKind::Unsupported,
+        };
+        return Err(io::Error::new(kind, "WAL record is encrypted, but no encryption key was given"));
+    };
+    let (header, sealed) = payload.split_at(ENCRYPTED_HEADER_LEN);
+    let plaintext = encryption::open(key, seqno, header, sealed)?;
+    let mut record = decode_payload(length, crc32, &plaintext)?;
+    if record.seqno != seqno {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("encrypted record {seqno} holds a record with sequence number {}", record.seqno),
+        ));
+    }
+    record.length = length;
+    Ok(record)
+}
+
+/// Best-effort extraction of the key from a payload that failed its checksum, for error messages.
+/// The key length itself may be the corrupted part, so it is clamped to the payload.
+fn corrupt_record_key(payload: &[u8]) -> &[u8] {
+    let mut cursor = 1usize; // skip the kind byte
+    let key_len = decode_var_u32(payload, &mut cursor).unwrap_or(0) as usize;
+    let start = cursor.min(payload.len());
+    let end = start.saturating_add(key_len).min(payload.len());
+    &payload[start..end]
 }
 
-fn read_u32<R: Read>(reader: &mut R, label: &str) -> io::Result<u32> {
+pub(crate) fn read_u32<R: Read>(reader: &mut R, label: &str) -> io::Result<u32> {
     let mut buf = [0u8; 4];
     reader.read_exact(&mut buf).map_err(|err| {
         io::Error::new(err.kind(), format!("unable to read {label} length: {err}"))
@@ -193,7 +964,7 @@ fn read_u32<R: Read>(reader: &mut R, label: &str) -> io::Result<u32> {
     Ok(u32::from_le_bytes(buf))
 }
 
-fn read_u32_or_eof<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+pub(crate) fn read_u32_or_eof<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
     let mut buf = [0u8; 4];
     let mut read = 0;
     while read < 4 {
@@ -211,7 +982,10 @@ fn read_u32_or_eof<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
     Ok(Some(u32::from_le_bytes(buf)))
 }
 
-fn encode_var_u32(mut value: u32) -> Vec<u8> {
+/// Encodes `value` as a LEB128 varint, seven bits per byte from the lowest, with the top bit set
+/// on every byte but the last: 1 byte below 128, at most 5. The key and value lengths of a
+/// record are encoded this way, so the usual small ones take a byte each.
+pub fn encode_var_u32(mut value: u32) -> Vec<u8> {
     let mut encoded = Vec::new();
     loop {
         let mut byte = (value & 0x7F) as u8;
@@ -227,7 +1001,10 @@ fn encode_var_u32(mut value: u32) -> Vec<u8> {
     encoded
 }
 
-fn decode_var_u32(buffer: &[u8], cursor: &mut usize) -> io::Result<u32> {
+/// Decodes a varint encoded by `encode_var_u32` from `buffer` at `cursor`, moving the cursor past
+/// it. Fails with `UnexpectedEof` if the buffer ends inside the varint, and with `InvalidData` if
+/// it is longer than 5 bytes or its fifth byte carries bits beyond those of a u32.
+pub fn decode_var_u32(buffer: &[u8], cursor: &mut usize) -> io::Result<u32> {
     let mut value = 0u32;
     let mut shift = 0;
     for _ in 0..5 {
@@ -238,6 +1015,13 @@ fn decode_var_u32(buffer: &[u8], cursor: &mut usize) -> io::Result<u32> {
             )
         })?;
         *cursor += 1;
+        // The fifth byte holds the top 4 bits
+        if shift == 28 && byte & 0x7F > 0x0F {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "varint overflows a u32 while decoding record",
+            ));
+        }
         value |= ((byte & 0x7F) as u32) << shift;
         if byte & 0x80 == 0 {
             return Ok(value);
@@ -250,15 +1034,327 @@ fn decode_var_u32(buffer: &[u8], cursor: &mut usize) -> io::Result<u32> {
     ))
 }
 
-/// Encodes a record into the provided buffer for batching.
-/// Uses the shared encoding logic to avoid code duplication.
+/// Encodes a record into the provided buffer as a batch of its own, see `encode_write_batch`:
+/// the batch header frames the record, so a reader knows where it ends and that it is intact.
+/// Batches appended to one buffer decode with `decode_batch_records`. Fails with a `RecordError`
+/// if the key or value is over the default `RecordLimits`.
 pub fn encode_batch_records(
     buffer: &mut Vec<u8>,
     kind: RecordKind,
-    key: &str,
+    key: &[u8],
     value: &[u8],
+    seqno: u64,
 ) -> io::Result<()> {
-    let encoded = encode_record_to_buffer(kind, key, value)?;
+    encode_write_batch_with(buffer, seqno, &RecordEncoding::default(), [(kind, key, value)])
+}
+
+/// Encodes a record with an optional expiry and timestamp into the provided buffer, see
+/// `encode_record_to_buffer`.
+pub(crate) fn encode_record_into(
+    buffer: &mut Vec<u8>,
+    kind: RecordKind,
+    key: &[u8],
+    value: &[u8],
+    expires_at: Option<u64>,
+    seqno: u64,
+    timestamp: u64,
+) -> io::Result<()> {
+    let encoded = encode_record_to_buffer(kind, key, value, expires_at, seqno, timestamp, None)?;
     buffer.extend_from_slice(&encoded);
     Ok(())
 }
+
+/// How the WAL encodes the records it writes, see `encode_record_with`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RecordEncoding {
+    /// how values are compressed, `None` stores them as they are
+    pub compression: Option<ValueCompression>,
+    /// records are encrypted with this key
+    pub encryption_key: Option<EncryptionKey>,
+    /// records with a longer key or value are refused
+    pub limits: RecordLimits,
+    /// the format of the files of the log, see `encode_file_header`
+    pub format_version: FormatVersion,
+}
+
+/// Encodes a record like `encode_record_into`, refusing it if it is over `encoding.limits`,
+/// compressing its value as `encoding.compression` says and then encrypting the record if
+/// `encoding` has a key. Without the feature of the
+/// codec values are stored as they are. Encryption needs the `encryption` feature and a
+/// sequence number, which the nonce is made of.
+pub(crate) fn encode_record_with(
+    buffer: &mut Vec<u8>,
+    kind: RecordKind,
+    key: &[u8],
+    value: &[u8],
+    expires_at: Option<u64>,
+    seqno: u64,
+    encoding: &RecordEncoding,
+) -> io::Result<()> {
+    encoding.limits.check(key.len(), value.len())?;
+    let encoded = encode_record_to_buffer(kind, key, value, expires_at, seqno, 0, encoding.compression)?;
+    match &encoding.encryption_key {
+        Some(encryption_key) => encode_encrypted_record(buffer, encryption_key, seqno, &encoded[8..]),
+        None => {
+            buffer.extend_from_slice(&encoded);
+            Ok(())
+        }
+    }
+}
+
+/// Encrypts the record `payload` with sequence number `seqno` into an encrypted record frame.
+fn encode_encrypted_record(buffer: &mut Vec<u8>, key: &EncryptionKey, seqno: u64, payload: &[u8]) -> io::Result<()> {
+    if seqno == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "only records with a sequence number are encrypted"));
+    }
+    let mut frame = Vec::with_capacity(ENCRYPTED_HEADER_LEN + payload.len() + TAG_LEN);
+    frame.push(ENCRYPTED_RECORD_KIND);
+    frame.extend_from_slice(&seqno.to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    let sealed = encryption::seal(key, seqno, &frame, payload)?;
+    frame.extend_from_slice(&sealed);
+    let length: u32 = frame
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+    buffer.extend_from_slice(&length.to_le_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(&frame).to_le_bytes());
+    buffer.extend_from_slice(&frame);
+    Ok(())
+}
+
+/// The header of a batch of records read by `read_frame`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BatchHeader {
+    /// number of records in the batch
+    pub count: u32,
+    /// length in bytes of the encoded records following the header
+    pub body_length: u32,
+    /// checksum of the encoded records
+    pub body_crc32: u32,
+}
+
+impl BatchHeader {
+    /// Returns the number of bytes the header and its records take.
+    pub fn batch_length(&self) -> u64 {
+        8 + BATCH_HEADER_PAYLOAD_LEN as u64 + u64::from(self.body_length)
+    }
+}
+
+/// Decodes the records of the batches encoded one after the other in `bytes`, see
+/// `encode_write_batch` and `encode_batch_records`. Fails if a batch is damaged or cut short.
+pub fn decode_batch_records(bytes: &[u8]) -> io::Result<Vec<DecodedRecord>> {
+    let mut rest = bytes;
+    let mut records = Vec::new();
+    while let Some(batch) = read_batch(&mut rest)? {
+        records.extend(batch);
+    }
+    Ok(records)
+}
+
+/// Reads the next batch from `reader` and returns its records, or `None` at the end of the
+/// input. The whole batch is read and checked against its header before any record is
+/// decoded: a batch cut short fails with `UnexpectedEof`, saying how many bytes it should
+/// have had and how many there were, and a damaged one with `InvalidData`.
+pub fn read_batch<R: Read>(reader: &mut R) -> io::Result<Option<Vec<DecodedRecord>>> {
+    let header_len = 8 + BATCH_HEADER_PAYLOAD_LEN;
+    let mut header = Vec::with_capacity(header_len);
+    reader.take(header_len as u64).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(None);
+    }
+    if header.len() < header_len {
+        return Err(batch_truncated(header_len as u64, header.len() as u64));
+    }
+    let length = u32::from_le_bytes(header[0..4].try_into().expect("4-byte slice"));
+    let crc32 = u32::from_le_bytes(header[4..8].try_into().expect("4-byte slice"));
+    let payload = &header[8..];
+    if length as usize != BATCH_HEADER_PAYLOAD_LEN || payload[0] != BATCH_HEADER_KIND {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a batch header"));
+    }
+    check_crc(crc32, payload)?;
+    let header = decode_batch_header(payload)?;
+    read_batch_body(reader, &header, None, RecordLimits::default()).map(Some)
+}
+
+/// Reads the records of the batch with this header, checking them against it and `limits` and
+/// decrypting encrypted ones with `key`.
+pub(crate) fn read_batch_body<R: Read>(
+    reader: &mut R,
+    header: &BatchHeader,
+    key: Option<&EncryptionKey>,
+    limits: RecordLimits,
+) -> io::Result<Vec<DecodedRecord>> {
+    let mut body = Vec::new();
+    reader.take(u64::from(header.body_length)).read_to_end(&mut body)?;
+    if body.len() != header.body_length as usize {
+        let header_len = header.batch_length() - u64::from(header.body_length);
+        return Err(batch_truncated(header.batch_length(), header_len + body.len() as u64));
+    }
+    let computed_crc = crc32fast::hash(&body);
+    if computed_crc != header.body_crc32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "crc mismatch while reading batch of {} records: expected {:#010x}, computed {computed_crc:#010x}",
+                header.count, header.body_crc32
+            ),
+        ));
+    }
+    let mut rest = body.as_slice();
+    let mut records = Vec::with_capacity(header.count as usize);
+    while let Some(frame) = read_frame(&mut rest, key, limits)? {
+        let Frame::Record(record) = frame else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "batch nested in a batch"));
+        };
+        records.push(record);
+    }
+    if records.len() != header.count as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("batch holds {} records, its header says {}", records.len(), header.count),
+        ));
+    }
+    Ok(records)
+}
+
+fn batch_truncated(expected: u64, available: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("batch truncated: expected {expected} bytes, {available} available"),
+    )
+}
+
+/// A frame of a WAL, see `read_frame`.
+pub(crate) enum Frame {
+    Record(DecodedRecord),
+    Batch(BatchHeader),
+    /// the last sequence number given out when the log was emptied
+    SeqnoMarker(u64),
+    /// the header of a file of a supported format version, see `FormatVersion`
+    FileHeader,
+}
+
+fn decode_batch_header(payload: &[u8]) -> io::Result<BatchHeader> {
+    if payload.len() != BATCH_HEADER_PAYLOAD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("batch header payload is {} bytes", payload.len()),
+        ));
+    }
+    let field = |at: usize| u32::from_le_bytes(payload[at..at + 4].try_into().expect("4-byte slice"));
+    Ok(BatchHeader {
+        count: field(1),
+        body_length: field(5),
+        body_crc32: field(9),
+    })
+}
+
+fn decode_seqno_marker(payload: &[u8]) -> io::Result<u64> {
+    if payload.len() != SEQNO_MARKER_PAYLOAD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("sequence number marker payload is {} bytes", payload.len()),
+        ));
+    }
+    Ok(u64::from_le_bytes(payload[1..].try_into().expect("8-byte slice")))
+}
+
+pub(crate) fn decode_file_header(payload: &[u8]) -> io::Result<FormatVersion> {
+    match payload {
+        [_, version] => FormatVersion::from_byte(*version),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("file header payload is {} bytes", payload.len()),
+        )),
+    }
+}
+
+/// Encodes the header a WAL file of format `version` starts with, nothing for version 1.
+pub(crate) fn encode_file_header(buffer: &mut Vec<u8>, version: FormatVersion) {
+    if version == FormatVersion::V1 {
+        return;
+    }
+    let payload = [FILE_HEADER_KIND, version.as_byte()];
+    buffer.extend_from_slice(&(FILE_HEADER_PAYLOAD_LEN as u32).to_le_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buffer.extend_from_slice(&payload);
+}
+
+/// Encodes the marker the WAL leaves where it emptied the log, recording that `seqno` was the
+/// last sequence number given out. Replays skip it.
+pub(crate) fn encode_seqno_marker(buffer: &mut Vec<u8>, seqno: u64) {
+    let mut payload = [0u8; SEQNO_MARKER_PAYLOAD_LEN];
+    payload[0] = SEQNO_MARKER_KIND;
+    payload[1..].copy_from_slice(&seqno.to_le_bytes());
+    buffer.extend_from_slice(&(SEQNO_MARKER_PAYLOAD_LEN as u32).to_le_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buffer.extend_from_slice(&payload);
+}
+
+/// Encodes records as one batch into the provided buffer: a batch header followed by the
+/// records, each encoded as by `write_record`. The header is a frame of its own holding a batch
+/// kind byte, the number of records, their length in bytes and their checksum. A reader checks
+/// the header against the records, so it sees either the whole batch or none of it, see
+/// `read_batch`. On error the buffer is left as it was. An empty batch encodes nothing.
+pub fn encode_write_batch<'a, I>(buffer: &mut Vec<u8>, records: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = (RecordKind, &'a [u8], &'a [u8])>,
+{
+    encode_numbered_write_batch(buffer, 0, records)
+}
+
+/// Encodes records as one batch like `encode_write_batch`, numbering them from `first_seqno`
+/// on. With `first_seqno` 0 the records have no sequence number.
+pub fn encode_numbered_write_batch<'a, I>(buffer: &mut Vec<u8>, first_seqno: u64, records: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = (RecordKind, &'a [u8], &'a [u8])>,
+{
+    encode_write_batch_with(buffer, first_seqno, &RecordEncoding::default(), records)
+}
+
+/// Encodes records as one batch like `encode_numbered_write_batch`, each one as `encoding` says,
+/// see `encode_record_with`.
+pub(crate) fn encode_write_batch_with<'a, I>(
+    buffer: &mut Vec<u8>,
+    first_seqno: u64,
+    encoding: &RecordEncoding,
+    records: I,
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = (RecordKind, &'a [u8], &'a [u8])>,
+{
+    let start = buffer.len();
+    let header_len = 8 + BATCH_HEADER_PAYLOAD_LEN;
+    buffer.resize(start + header_len, 0);
+    let mut count = 0usize;
+    for (kind, key, value) in records {
+        let seqno = if first_seqno == 0 { 0 } else { first_seqno + count as u64 };
+        if let Err(err) = encode_record_with(buffer, kind, key, value, None, seqno, encoding) {
+            buffer.truncate(start);
+            return Err(err);
+        }
+        count += 1;
+    }
+    if count == 0 {
+        buffer.truncate(start);
+        return Ok(());
+    }
+    let body = &buffer[start + header_len..];
+    let (Ok(count), Ok(body_length)) = (u32::try_from(count), u32::try_from(body.len())) else {
+        buffer.truncate(start);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "batch too large"));
+    };
+    let body_crc32 = crc32fast::hash(body);
+
+    let mut payload = [0u8; BATCH_HEADER_PAYLOAD_LEN];
+    payload[0] = BATCH_HEADER_KIND;
+    payload[1..5].copy_from_slice(&count.to_le_bytes());
+    payload[5..9].copy_from_slice(&body_length.to_le_bytes());
+    payload[9..13].copy_from_slice(&body_crc32.to_le_bytes());
+    let header = &mut buffer[start..start + header_len];
+    header[0..4].copy_from_slice(&(BATCH_HEADER_PAYLOAD_LEN as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    header[8..].copy_from_slice(&payload);
+    Ok(())
+}
@@ -0,0 +1,232 @@
+//! Serde support for values and records, behind the `serde` feature.
+//!
+//! Bytes are written as a string when they are UTF-8 and as `{"base64":"..."}` otherwise. A
+//! value of raw bytes is written as its bytes, a tombstone as `null`, and the other variants
+//! as a map with a single entry naming the variant: `{"int":1}`, `{"float":0.5}`, `{"str":".."}`,
+//! `{"bool":true}` and `{"merge":[..]}` with the operands as bytes. Floats that are not finite
+//! are written as the strings `"NaN"`, `"inf"` and `"-inf"`, like `SsTable::dump` does. Every
+//! form reads back as the value it was written from.
+
+use std::fmt;
+
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::base64;
+use crate::utils::value::Value;
+
+/// Key of the map bytes that are not UTF-8 are written as.
+const BASE64: &str = "base64";
+
+/// Serializes bytes as a string, or as `{"base64":"..."}` if they are not UTF-8.
+pub(crate) fn serialize_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => serializer.serialize_str(text),
+        Err(_) => single_entry(serializer, BASE64, &base64::encode(bytes)),
+    }
+}
+
+/// Deserializes bytes written by `serialize_bytes`.
+pub(crate) fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_any(BytesVisitor)
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(r#"a string or {"base64": "..."}"#)
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Vec<u8>, E> {
+        Ok(text.as_bytes().to_vec())
+    }
+
+    fn visit_string<E: de::Error>(self, text: String) -> Result<Vec<u8>, E> {
+        Ok(text.into_bytes())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<u8>, A::Error> {
+        match map.next_key::<String>()?.as_deref() {
+            Some(BASE64) => {
+                let bytes = decode_base64(map.next_value()?)?;
+                end_of_single_entry(map)?;
+                Ok(bytes)
+            }
+            Some(other) => Err(de::Error::unknown_field(other, &[BASE64])),
+            None => Err(de::Error::missing_field(BASE64)),
+        }
+    }
+}
+
+fn decode_base64<E: de::Error>(text: String) -> Result<Vec<u8>, E> {
+    base64::decode(&text).ok_or_else(|| E::custom("invalid base64"))
+}
+
+/// Serializes `{name: value}`.
+fn single_entry<S: Serializer, T: Serialize + ?Sized>(serializer: S, name: &str, value: &T) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(name, value)?;
+    map.end()
+}
+
+/// Fails if the map holds another entry after the one read.
+fn end_of_single_entry<'de, A: MapAccess<'de>>(mut map: A) -> Result<(), A::Error> {
+    match map.next_key::<String>()? {
+        Some(extra) => Err(de::Error::custom(format!("unexpected field `{extra}` after the first"))),
+        None => Ok(()),
+    }
+}
+
+/// Bytes serialized by `serialize_bytes`, for the operands of merge values.
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_bytes(self.0, serializer)
+    }
+}
+
+struct OwnedBytes(Vec<u8>);
+
+impl<'de> Deserialize<'de> for OwnedBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_bytes(deserializer).map(OwnedBytes)
+    }
+}
+
+/// A float, written as a number or, if not finite, as `"NaN"`, `"inf"` or `"-inf"`.
+struct Float(f64);
+
+impl Serialize for Float {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            float if float.is_nan() => serializer.serialize_str("NaN"),
+            float if float.is_infinite() => serializer.serialize_str(if float > 0.0 { "inf" } else { "-inf" }),
+            float => serializer.serialize_f64(float),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Float {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FloatVisitor;
+
+        impl Visitor<'_> for FloatVisitor {
+            type Value = Float;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str(r#"a number, "NaN", "inf" or "-inf""#)
+            }
+
+            fn visit_f64<E: de::Error>(self, float: f64) -> Result<Float, E> {
+                Ok(Float(float))
+            }
+
+            fn visit_i64<E: de::Error>(self, int: i64) -> Result<Float, E> {
+                Ok(Float(int as f64))
+            }
+
+            fn visit_u64<E: de::Error>(self, int: u64) -> Result<Float, E> {
+                Ok(Float(int as f64))
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<Float, E> {
+                match text {
+                    "NaN" => Ok(Float(f64::NAN)),
+                    "inf" => Ok(Float(f64::INFINITY)),
+                    "-inf" => Ok(Float(f64::NEG_INFINITY)),
+                    _ => Err(E::invalid_value(de::Unexpected::Str(text), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(FloatVisitor)
+    }
+}
+
+/// The operands of a merge value, serialized as a sequence of bytes.
+struct Operands<'a>(&'a [Vec<u8>]);
+
+impl Serialize for Operands<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for operand in self.0 {
+            seq.serialize_element(&Bytes(operand))?;
+        }
+        seq.end()
+    }
+}
+
+/// Names of the variants written as a single entry map.
+const VARIANTS: &[&str] = &[BASE64, "int", "float", "str", "bool", "merge"];
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Bytes(bytes) => serialize_bytes(bytes, serializer),
+            Value::Deleted => serializer.serialize_none(),
+            Value::Int(int) => single_entry(serializer, "int", int),
+            Value::Float(float) => single_entry(serializer, "float", &Float(*float)),
+            Value::Str(text) => single_entry(serializer, "str", text),
+            Value::Bool(flag) => single_entry(serializer, "bool", flag),
+            Value::Merge(operands) => single_entry(serializer, "merge", &Operands(operands)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("null, a string or a map with a single entry naming the type of the value")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Deleted)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Deleted)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Value, E> {
+        Ok(Value::Bytes(text.as_bytes().to_vec()))
+    }
+
+    fn visit_string<E: de::Error>(self, text: String) -> Result<Value, E> {
+        Ok(Value::Bytes(text.into_bytes()))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let Some(name) = map.next_key::<String>()? else {
+            return Err(de::Error::invalid_length(0, &self));
+        };
+        let value = match name.as_str() {
+            BASE64 => Value::Bytes(decode_base64(map.next_value()?)?),
+            "int" => Value::Int(map.next_value()?),
+            "float" => Value::Float(map.next_value::<Float>()?.0),
+            "str" => Value::Str(map.next_value()?),
+            "bool" => Value::Bool(map.next_value()?),
+            "merge" => Value::Merge(map.next_value::<Vec<OwnedBytes>>()?.into_iter().map(|bytes| bytes.0).collect()),
+            other => return Err(de::Error::unknown_field(other, VARIANTS)),
+        };
+        end_of_single_entry(map)?;
+        Ok(value)
+    }
+}
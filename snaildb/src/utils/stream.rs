@@ -0,0 +1,300 @@
+//! Streaming reads of records whose values are too large to hold in memory at once: a record
+//! is read up to its value by `read_record_header`, and its value is read from the stream by a
+//! `ValueReader`, or skipped.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crc32fast::Hasher;
+
+use crate::utils::record::{
+    COMPRESSED_FLAG, FILE_HEADER_KIND, FILE_HEADER_PAYLOAD_LEN, RecordError, RecordKind, RecordLimits, SEQNO_FLAG,
+    TIMESTAMP_FLAG, ZSTD_FLAG, check_crc, decode_file_header, read_u32, read_u32_or_eof,
+};
+
+/// The fields of a record in front of its value, read by `read_record_header`.
+#[derive(Clone, Debug)]
+pub struct RecordHeader {
+    pub kind: RecordKind,
+    pub key: Vec<u8>,
+    /// the length of the value as stored
+    pub value_len: u64,
+    /// set when the value is stored compressed, see `ValueCompression`: a `ValueReader` then
+    /// reads the compressed bytes, `read_record` decompresses them
+    pub compressed: bool,
+    kind_byte: u8,
+    crc32: u32,
+    /// checksum of the payload up to the value
+    hasher: Hasher,
+}
+
+impl RecordHeader {
+    /// Returns the length of the fields stored after the value, see `RecordTrailer`.
+    fn trailer_len(&self) -> u64 {
+        let expiry = u64::from(self.kind == RecordKind::SetWithTtl);
+        let seqno = u64::from(self.kind_byte & SEQNO_FLAG != 0);
+        let timestamp = u64::from(self.kind_byte & TIMESTAMP_FLAG != 0);
+        8 * (expiry + seqno + timestamp)
+    }
+}
+
+/// The fields of a record stored after its value, returned by `ValueReader::finish` and
+/// `ValueReader::skip` once the value is behind the stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordTrailer {
+    /// when the value expires, in milliseconds since the UNIX epoch, for `SetWithTtl` records
+    pub expires_at: Option<u64>,
+    /// the sequence number of the record, 0 if it has none
+    pub seqno: u64,
+    /// when the record was written, in milliseconds since the UNIX epoch, 0 if unknown
+    pub timestamp: u64,
+}
+
+/// Reads the next record of `reader` up to its value, which is left unread: read it with a
+/// `ValueReader` made from the header, or skip it with one, before reading the next record.
+/// Returns `None` at the end of the input. A file header is skipped, like `read_record` does.
+///
+/// The key is checked against the default `RecordLimits`, the value is not as it is not held
+/// in memory. The checksum of the record is checked once the whole value is read, see
+/// `ValueReader`. Encrypted records cannot be read in parts and fail as of an unknown kind.
+pub fn read_record_header<R: Read>(reader: &mut R) -> io::Result<Option<RecordHeader>> {
+    let limits = RecordLimits::default();
+    loop {
+        let Some(length) = read_u32_or_eof(reader)? else {
+            return Ok(None);
+        };
+        let crc32 = read_u32(reader, "crc32")?;
+        let mut kind_byte = [0u8; 1];
+        reader.read_exact(&mut kind_byte)?;
+        let kind_byte = kind_byte[0];
+        if kind_byte == FILE_HEADER_KIND {
+            if length as usize != FILE_HEADER_PAYLOAD_LEN {
+                let message = format!("file header payload is {length} bytes");
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            let mut payload = [kind_byte; FILE_HEADER_PAYLOAD_LEN];
+            reader.read_exact(&mut payload[1..])?;
+            check_crc(crc32, &payload)?;
+            decode_file_header(&payload)?;
+            continue;
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&[kind_byte]);
+        let kind = RecordKind::from_byte(kind_byte & !(SEQNO_FLAG | COMPRESSED_FLAG | TIMESTAMP_FLAG | ZSTD_FLAG))?;
+        if kind_byte & ZSTD_FLAG != 0 && kind_byte & COMPRESSED_FLAG == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown record kind {kind_byte}")));
+        }
+
+        let (key_len, key_len_bytes) = read_var_u32(reader, &mut hasher)?;
+        if key_len as usize > limits.max_key_len {
+            return Err(RecordError::KeyTooLarge { len: key_len as usize, max: limits.max_key_len }.into());
+        }
+        let mut key = vec![0u8; key_len as usize];
+        reader.read_exact(&mut key)?;
+        hasher.update(&key);
+        let (value_len, value_len_bytes) = read_var_u32(reader, &mut hasher)?;
+
+        let header = RecordHeader {
+            kind,
+            key,
+            value_len: u64::from(value_len),
+            compressed: kind_byte & COMPRESSED_FLAG != 0,
+            kind_byte,
+            crc32,
+            hasher,
+        };
+        let fields = 1 + key_len_bytes + u64::from(key_len) + value_len_bytes + header.value_len + header.trailer_len();
+        if fields != u64::from(length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {length} bytes holds {fields} bytes of fields"),
+            ));
+        }
+        return Ok(Some(header));
+    }
+}
+
+/// Reads a varint like `decode_var_u32` one byte at a time, adding its bytes to `hasher`.
+/// Returns the value and the number of bytes it took.
+fn read_var_u32<R: Read>(reader: &mut R, hasher: &mut Hasher) -> io::Result<(u32, u64)> {
+    let mut value = 0u32;
+    for (i, shift) in (0..35).step_by(7).enumerate() {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        hasher.update(&byte);
+        let bits = u32::from(byte[0] & 0x7F);
+        if shift == 28 && bits > 0x0F {
+            break;
+        }
+        value |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok((value, i as u64 + 1));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "varint length overflows 32 bits"))
+}
+
+/// Reads the value of the record whose header was just read from `inner`, see
+/// `read_record_header`, through `io::Read`, and through `io::Seek` within the value when
+/// `inner` can seek. A read ends at the end of the value.
+///
+/// Reading the value in order to its end also reads the fields after it and checks the
+/// checksum of the record, so a damaged record fails the read that finds the end of the value.
+/// Once the reader has seeked the checksum can no longer be computed and is not checked.
+///
+/// `finish` or `skip` leave `inner` at the start of the next record.
+#[derive(Debug)]
+pub struct ValueReader<R> {
+    inner: R,
+    header: RecordHeader,
+    /// position in the value
+    pos: u64,
+    /// bytes of the value and trailer read from `inner`, so its position less that of the value
+    consumed: u64,
+    /// checksum of the payload, until a seek skips part of it
+    hasher: Option<Hasher>,
+    /// the fields after the value, once read
+    trailer: Option<RecordTrailer>,
+}
+
+impl<R> ValueReader<R> {
+    /// Reads the value of `header` from `inner`, which must be positioned right after it.
+    pub fn new(inner: R, header: RecordHeader) -> Self {
+        Self {
+            inner,
+            hasher: Some(header.hasher.clone()),
+            header,
+            pos: 0,
+            consumed: 0,
+            trailer: None,
+        }
+    }
+
+    /// Returns the header of the record.
+    pub fn header(&self) -> &RecordHeader {
+        &self.header
+    }
+
+    /// Returns the length of the value.
+    pub fn len(&self) -> u64 {
+        self.header.value_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.value_len == 0
+    }
+
+    /// Returns the bytes of the value not read yet.
+    pub fn remaining(&self) -> u64 {
+        self.header.value_len - self.pos
+    }
+}
+
+impl<R: Read> ValueReader<R> {
+    /// Reads the rest of the value and the fields after it, checking the checksum unless the
+    /// reader seeked, and returns them with `inner` at the start of the next record.
+    pub fn finish(mut self) -> io::Result<(RecordTrailer, R)> {
+        io::copy(&mut self, &mut io::sink())?;
+        let trailer = self.trailer.expect("the fields after a value read to its end");
+        // The fields were read before a seek back into the value
+        let behind = self.header.value_len + self.header.trailer_len() - self.consumed;
+        io::copy(&mut (&mut self.inner).take(behind), &mut io::sink())?;
+        Ok((trailer, self.inner))
+    }
+
+    /// Reads the fields after the value, with `inner` at the end of the value, and checks the
+    /// checksum if it was computed over the whole value.
+    fn read_trailer(&mut self) -> io::Result<RecordTrailer> {
+        let mut bytes = vec![0u8; self.header.trailer_len() as usize];
+        self.inner.read_exact(&mut bytes)?;
+        self.consumed += bytes.len() as u64;
+        if let Some(mut hasher) = self.hasher.take() {
+            hasher.update(&bytes);
+            let computed = hasher.finalize();
+            if computed != self.header.crc32 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "crc mismatch while reading record for key \"{}\": expected {:#010x}, computed {:#010x}",
+                        self.header.key.escape_ascii(),
+                        self.header.crc32,
+                        computed
+                    ),
+                ));
+            }
+        }
+        let mut fields = bytes.chunks_exact(8).map(|field| u64::from_le_bytes(field.try_into().expect("8-byte chunk")));
+        let expires_at = if self.header.kind == RecordKind::SetWithTtl { fields.next() } else { None };
+        let seqno = if self.header.kind_byte & SEQNO_FLAG != 0 { fields.next().unwrap_or(0) } else { 0 };
+        let timestamp = if self.header.kind_byte & TIMESTAMP_FLAG != 0 { fields.next().unwrap_or(0) } else { 0 };
+        let trailer = RecordTrailer { expires_at, seqno, timestamp };
+        self.trailer = Some(trailer);
+        Ok(trailer)
+    }
+}
+
+impl<R: Read + Seek> ValueReader<R> {
+    /// Seeks past the rest of the value without reading it and returns the fields after it,
+    /// with `inner` at the start of the next record. The checksum is not checked.
+    pub fn skip(mut self) -> io::Result<(RecordTrailer, R)> {
+        if let Some(trailer) = self.trailer {
+            let end = self.header.value_len + self.header.trailer_len();
+            self.inner.seek(SeekFrom::Current((end - self.consumed) as i64))?;
+            return Ok((trailer, self.inner));
+        }
+        if self.pos < self.header.value_len {
+            self.hasher = None;
+            self.inner.seek(SeekFrom::Current((self.header.value_len - self.consumed) as i64))?;
+            self.consumed = self.header.value_len;
+        }
+        let trailer = self.read_trailer()?;
+        Ok((trailer, self.inner))
+    }
+}
+
+impl<R: Read> Read for ValueReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.remaining();
+        if remaining == 0 {
+            if self.trailer.is_none() {
+                self.read_trailer()?;
+            }
+            return Ok(0);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record truncated while reading value"));
+        }
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..read]);
+        }
+        self.pos += read as u64;
+        self.consumed += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for ValueReader<R> {
+    /// Moves within the value: a position past its end fails with `InvalidInput`.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.header.value_len;
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+        };
+        let target = target.filter(|&target| target <= len).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("seek outside of a value of {len} bytes"))
+        })?;
+        if target != self.pos {
+            self.hasher = None;
+        }
+        self.inner.seek(SeekFrom::Current(target as i64 - self.consumed as i64))?;
+        self.pos = target;
+        self.consumed = target;
+        Ok(target)
+    }
+}
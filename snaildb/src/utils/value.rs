@@ -1,22 +1,263 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+
+/// Combines the merge operands of a key with the value they apply to, see `SnailDb::merge`: it
+/// is called with the key, the value written before the operands, `None` if the key had none
+/// or was deleted, and the operands oldest first, and returns the resulting value. A merge
+/// operator of its own, see `MergeOperator`, that combines no operands without a value.
+pub type MergeFn = fn(key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8>;
+
+/// Combines the merge operands of a key with the value they apply to, see `SnailDb::merge` and
+/// `MergeOptions::with_merge_operator`. Keys are bytes, as tables hold binary keys.
+pub trait MergeOperator: fmt::Debug + Send + Sync {
+    /// Returns the value the operands of `key`, oldest first, make of `existing`, the value
+    /// written before them, `None` if the key had none or was deleted. Returns `None` if they
+    /// cannot be combined, say an operand is malformed, which fails the read or merge.
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>>;
+
+    /// Combines consecutive operands of `key`, oldest first, into one operand with the same
+    /// effect on any value, so the operands of a key with nothing older take less room. Returns
+    /// `None` if they cannot be combined without the value, and they are kept as they are. The
+    /// default combines nothing.
+    fn partial_merge(&self, _key: &[u8], _operands: &[&[u8]]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl MergeOperator for MergeFn {
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        let operands: Vec<Vec<u8>> = operands.iter().map(|operand| operand.to_vec()).collect();
+        Some(self(key, existing, &operands))
+    }
+}
+
+/// Adds up 64-bit counters: values and operands are little endian `u64`s, the operands deltas
+/// added to the value, 0 for a missing key, wrapping on overflow. Fails on a value or operand
+/// that is not 8 bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct U64AddOperator;
+
+impl U64AddOperator {
+    fn sum<'a>(start: u64, operands: impl IntoIterator<Item = &'a [u8]>) -> Option<Vec<u8>> {
+        let mut sum = start;
+        for operand in operands {
+            sum = sum.wrapping_add(u64::from_le_bytes(operand.try_into().ok()?));
+        }
+        Some(sum.to_le_bytes().to_vec())
+    }
+}
+
+impl MergeOperator for U64AddOperator {
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        let start = match existing {
+            Some(existing) => u64::from_le_bytes(existing.try_into().ok()?),
+            None => 0,
+        };
+        Self::sum(start, operands.iter().copied())
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Option<Vec<u8>> {
+        Self::sum(0, operands.iter().copied())
+    }
+}
+
+/// Appends the operands to the value, oldest first, a missing key being empty.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AppendOperator;
+
+impl MergeOperator for AppendOperator {
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        Some(existing.into_iter().chain(operands.iter().copied()).flatten().copied().collect())
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Option<Vec<u8>> {
+        Some(operands.concat())
+    }
+}
+
+/// The version of a key: a value of one of the typed variants, a tombstone or merge operands.
+///
+/// Values of different variants are never equal and have no order, see `partial_cmp`. Floats
+/// compare by `f64::total_cmp`, so a NaN equals itself and reads back equal to what was written.
 #[derive(Clone, Debug)]
 pub enum Value {
-    Present(Vec<u8>),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// Raw bytes, the value of everything written before values were typed, see `from_bytes`
+    Bytes(Vec<u8>),
+    Bool(bool),
     Deleted,
+    /// Merge operands, oldest first, not yet combined with the older versions of the key
+    Merge(Vec<Vec<u8>>),
 }
 
+/// Type tags of the encoding of a value, see `Value::encode`.
+const INT_TAG: u8 = 1;
+const FLOAT_TAG: u8 = 2;
+const STR_TAG: u8 = 3;
+const BYTES_TAG: u8 = 4;
+const BOOL_TAG: u8 = 5;
+
 impl Value {
     pub fn from_bytes(bytes: Vec<u8>) -> Self {
-        Value::Present(bytes)
+        Value::Bytes(bytes)
     }
 
     pub fn tombstone() -> Self {
         Value::Deleted
     }
 
+    pub fn merge_operand(operand: Vec<u8>) -> Self {
+        Value::Merge(vec![operand])
+    }
+
+    /// Returns true for the variants holding a value of a type, `Bytes` included.
+    pub fn is_typed(&self) -> bool {
+        !matches!(self, Value::Deleted | Value::Merge(_))
+    }
+
+    /// Encodes a value as `[tag:u8][payload]`, the payload being the bytes `as_option` returns:
+    /// little endian for integers and floats, UTF-8 for strings and a 0 or 1 byte for booleans.
+    /// Tombstones and merge operands are record kinds of their own and fail with `InvalidInput`.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let tag = match self {
+            Value::Int(_) => INT_TAG,
+            Value::Float(_) => FLOAT_TAG,
+            Value::Str(_) => STR_TAG,
+            Value::Bytes(_) => BYTES_TAG,
+            Value::Bool(_) => BOOL_TAG,
+            Value::Deleted | Value::Merge(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "only typed values have an encoding"));
+            }
+        };
+        let payload = self.as_option().expect("typed values have bytes");
+        let mut buffer = Vec::with_capacity(1 + payload.len());
+        buffer.push(tag);
+        buffer.extend_from_slice(&payload);
+        Ok(buffer)
+    }
+
+    /// Decodes a value encoded by `encode`. Fails with `InvalidData` on an unknown tag, a
+    /// payload of the wrong length for its type, a string that is not UTF-8 or a boolean that
+    /// is neither 0 nor 1.
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+        let (&tag, payload) = bytes.split_first().ok_or_else(|| invalid("typed value without a tag"))?;
+        let eight_bytes = || -> io::Result<[u8; 8]> {
+            payload.try_into().map_err(|_| invalid("numeric value is not 8 bytes"))
+        };
+        match tag {
+            INT_TAG => Ok(Value::Int(i64::from_le_bytes(eight_bytes()?))),
+            FLOAT_TAG => Ok(Value::Float(f64::from_bits(u64::from_le_bytes(eight_bytes()?)))),
+            STR_TAG => String::from_utf8(payload.to_vec())
+                .map(Value::Str)
+                .map_err(|_| invalid("string value is not valid UTF-8")),
+            BYTES_TAG => Ok(Value::Bytes(payload.to_vec())),
+            BOOL_TAG => match payload {
+                [0] => Ok(Value::Bool(false)),
+                [1] => Ok(Value::Bool(true)),
+                _ => Err(invalid("boolean value is not a 0 or 1 byte")),
+            },
+            _ => Err(invalid(&format!("unknown value type tag {tag}"))),
+        }
+    }
+
+    /// Returns the bytes of a value: the raw bytes of `Bytes`, the UTF-8 of `Str` and, for the
+    /// other typed variants, the little endian bytes `encode` writes after the tag. Tombstones
+    /// and merge operands left unresolved have none, see `resolve`.
     pub fn as_option(&self) -> Option<Vec<u8>> {
         match self {
-            Value::Present(bytes) => Some(bytes.clone()),
-            Value::Deleted => None,
+            Value::Int(int) => Some(int.to_le_bytes().to_vec()),
+            Value::Float(float) => Some(float.to_bits().to_le_bytes().to_vec()),
+            Value::Str(text) => Some(text.as_bytes().to_vec()),
+            Value::Bytes(bytes) => Some(bytes.clone()),
+            Value::Bool(flag) => Some(vec![u8::from(*flag)]),
+            Value::Deleted | Value::Merge(_) => None,
+        }
+    }
+
+    /// Returns the number of bytes the value holds, see `as_option`: 0 for a tombstone, all the
+    /// operands for merge operands.
+    pub fn byte_len(&self) -> usize {
+        match self {
+            Value::Int(_) | Value::Float(_) => 8,
+            Value::Str(text) => text.len(),
+            Value::Bytes(bytes) => bytes.len(),
+            Value::Bool(_) => 1,
+            Value::Deleted => 0,
+            Value::Merge(operands) => operands.iter().map(Vec::len).sum(),
+        }
+    }
+
+    /// Places `self`, a version of `key`, on top of `older`, the version before it. A value or
+    /// a tombstone hides the older version. Merge operands are appended to older operands, and
+    /// combined into one with `MergeOperator::partial_merge` if `merge` can, or combined with
+    /// `merge` into the value they make of an older value or tombstone, which fails without
+    /// `merge` or if it cannot combine them. A typed older value is passed to `merge` as its
+    /// bytes, see `as_option`, and the result is raw bytes.
+    pub fn stack_onto(self, key: &[u8], older: Value, merge: Option<&dyn MergeOperator>) -> io::Result<Value> {
+        let operands = match self {
+            Value::Merge(operands) => operands,
+            value => return Ok(value),
+        };
+        let existing = match older {
+            Value::Merge(mut older) => {
+                older.extend(operands);
+                let combined = merge.and_then(|merge| merge.partial_merge(key, &operand_refs(&older)));
+                return Ok(Value::Merge(combined.map_or(older, |operand| vec![operand])));
+            }
+            older => older.as_option(),
+        };
+        let merge = merge.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("merge operands of key {:?} need a merge function", String::from_utf8_lossy(key)),
+            )
+        })?;
+        let value = merge.full_merge(key, existing.as_deref(), &operand_refs(&operands)).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("merge operator failed to combine the operands of key {:?}", String::from_utf8_lossy(key)),
+            )
+        })?;
+        Ok(Value::Bytes(value))
+    }
+
+    /// Returns the value `self` makes of `key` when there is no older version: merge operands
+    /// are combined with `merge` as applied to a missing key, other values are left as they
+    /// are. Fails on merge operands without `merge`.
+    pub fn resolve(self, key: &[u8], merge: Option<&dyn MergeOperator>) -> io::Result<Value> {
+        self.stack_onto(key, Value::Deleted, merge)
+    }
+}
+
+fn operand_refs(operands: &[Vec<u8>]) -> Vec<&[u8]> {
+    operands.iter().map(Vec::as_slice).collect()
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    /// Orders two values of the same variant, floats by `f64::total_cmp`. Values of different
+    /// variants have no order.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::Float(a), Value::Float(b)) => Some(a.total_cmp(b)),
+            (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+            (Value::Bytes(a), Value::Bytes(b)) => Some(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            (Value::Deleted, Value::Deleted) => Some(Ordering::Equal),
+            (Value::Merge(a), Value::Merge(b)) => Some(a.cmp(b)),
+            _ => None,
         }
     }
 }
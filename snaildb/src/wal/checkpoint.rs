@@ -0,0 +1,79 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::wal::segment::sync_dir;
+
+/// Length of the checkpoint file: [segment:u64][offset:u64][crc32:u32], little endian.
+const CHECKPOINT_LEN: usize = 20;
+
+/// A position in a WAL: a byte offset in the segment with this sequence number. The segment is
+/// 0 for a WAL that is a single file. Positions order like the records they point at.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WalPosition {
+    /// sequence number of the segment, 0 for a single file
+    pub segment: u64,
+    /// offset in bytes from the start of the segment
+    pub offset: u64,
+}
+
+/// Returns the path of the checkpoint of the WAL file at `path`: `<path>.checkpoint`.
+pub fn file_checkpoint_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_os_string();
+    name.push(".checkpoint");
+    PathBuf::from(name)
+}
+
+/// Returns the path of the checkpoint of the segmented WAL in `dir`: `checkpoint` in it.
+pub fn dir_checkpoint_path(dir: impl AsRef<Path>) -> PathBuf {
+    dir.as_ref().join("checkpoint")
+}
+
+/// Reads the checkpoint at `path`, see `WalHandle::checkpoint`. Returns `None` if there is
+/// no checkpoint.
+pub fn read_checkpoint(path: impl AsRef<Path>) -> io::Result<Option<WalPosition>> {
+    let bytes = match fs::read(path.as_ref()) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if bytes.len() != CHECKPOINT_LEN || crc32fast::hash(&bytes[..16]) != u32_at(&bytes, 16) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("WAL checkpoint {} is corrupt", path.as_ref().display()),
+        ));
+    }
+    Ok(Some(WalPosition {
+        segment: u64_at(&bytes, 0),
+        offset: u64_at(&bytes, 8),
+    }))
+}
+
+/// Replaces the checkpoint at `path` with `position`. The new checkpoint is written next to
+/// it and renamed over it, so a crash leaves either the old or the new one.
+pub(crate) fn write_checkpoint(path: &Path, position: WalPosition) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(CHECKPOINT_LEN);
+    bytes.extend_from_slice(&position.segment.to_le_bytes());
+    bytes.extend_from_slice(&position.offset.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = File::create(&temp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => Ok(()),
+    }
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().expect("8-byte slice"))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4-byte slice"))
+}
@@ -1,23 +1,95 @@
+use std::fmt;
+use std::fs::File;
 use std::io::{self, Write};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::wal::metrics::WalMetrics;
+use crate::wal::subscribe::{SlowSubscriberPolicy, Subscribers};
 
 /// Configuration constant for flush interval
 pub const FLUSH_INTERVAL_MS: u64 = 10; // 10 ms
 
+/// Makes the data written to a log file durable, see `SyncManager::with_syncer`.
+///
+/// Every sync of the writer goes through it, so wrapping `FileSync` tells how often the log is
+/// synced, or simulates a slow disk.
+pub trait LogSync: fmt::Debug + Send + Sync {
+    /// Syncs the data written to `file`.
+    fn sync(&self, file: &File) -> io::Result<()>;
+}
+
+/// Syncs with `File::sync_data`, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileSync;
+
+impl LogSync for FileSync {
+    fn sync(&self, file: &File) -> io::Result<()> {
+        file.sync_data()
+    }
+}
+
+/// When the WAL writer syncs the log on its own, trading durability for throughput. Whatever
+/// the policy, `Flush` commands sync everything written before them and `Shutdown` syncs
+/// before the writer exits, so only records written since the last of those are at stake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Syncs after every record or batch is written, which then survives a crash of the
+    /// machine as soon as the writer takes the next command. The slowest.
+    Always,
+    /// Syncs once this many records are written since the last sync, so a crash of the
+    /// machine loses fewer than that many records.
+    EveryN(u32),
+    /// Syncs writes at most this long after they are made, on a timer, so a crash of the
+    /// machine loses the writes of about that long. The default, every `FLUSH_INTERVAL_MS`.
+    Interval(Duration),
+    /// Leaves writes to the OS, which syncs them in its own time. Survives a crash of the
+    /// process, a crash of the machine can lose anything not explicitly flushed.
+    Never,
+}
+
+impl SyncPolicy {
+    /// Returns the period of the timer of an `Interval` policy.
+    pub fn interval(&self) -> Option<Duration> {
+        match self {
+            SyncPolicy::Interval(interval) => Some(*interval),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Interval(Duration::from_millis(FLUSH_INTERVAL_MS))
+    }
+}
+
 /// Manages the sync/flush state and operations for WAL durability.
 /// 
 /// This struct encapsulates:
 /// - Tracking whether there are pending writes that need to be flushed
 /// - Performing flush and sync operations
-/// - Managing the flush interval timing
+/// - Deciding when writes are due for a sync, following the `SyncPolicy`
 /// 
 /// The sync manager ensures that writes are periodically flushed to disk
 /// for durability, while avoiding excessive syscalls by batching flushes.
 pub struct SyncManager {
     /// Whether there are unflushed writes that need to be synced to disk
     pending_flush: bool,
-    /// The interval at which periodic flushes should occur
-    flush_interval: Duration,
+    /// When writes are synced without being asked to
+    policy: SyncPolicy,
+    /// Records written since the last sync
+    unsynced_records: u64,
+    /// When the file was last synced
+    last_sync: Instant,
+    /// Performs the syncs
+    syncer: Arc<dyn LogSync>,
+    /// The first error of a background write or sync not reported yet
+    error: Option<io::Error>,
+    /// Counts the syncs and the writes
+    metrics: Arc<WalMetrics>,
+    /// Get the records once a sync made them durable
+    subscribers: Subscribers,
 }
 
 impl SyncManager {
@@ -25,21 +97,63 @@ impl SyncManager {
     pub fn new() -> Self {
         Self {
             pending_flush: false,
-            flush_interval: Duration::from_millis(FLUSH_INTERVAL_MS),
+            policy: SyncPolicy::default(),
+            unsynced_records: 0,
+            last_sync: Instant::now(),
+            syncer: Arc::new(FileSync),
+            error: None,
+            metrics: Arc::default(),
+            subscribers: Subscribers::default(),
         }
     }
 
     /// Creates a new SyncManager with a custom flush interval
     pub fn with_interval(interval_ms: u64) -> Self {
-        Self {
-            pending_flush: false,
-            flush_interval: Duration::from_millis(interval_ms),
-        }
+        Self::new().with_policy(SyncPolicy::Interval(Duration::from_millis(interval_ms)))
+    }
+
+    /// Syncs following `policy` rather than every `FLUSH_INTERVAL_MS`.
+    pub fn with_policy(mut self, policy: SyncPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Performs the syncs with `syncer` rather than `FileSync`.
+    pub fn with_syncer(mut self, syncer: Arc<dyn LogSync>) -> Self {
+        self.syncer = syncer;
+        self
+    }
+
+    /// Counts the syncs and the writes of the log in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<WalMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Treats subscribers that do not keep up with the writes as `policy` says.
+    pub fn with_slow_subscriber_policy(mut self, policy: SlowSubscriberPolicy) -> Self {
+        self.subscribers = self.subscribers.with_policy(policy);
+        self
     }
 
-    /// Returns the flush interval duration
-    pub fn flush_interval(&self) -> Duration {
-        self.flush_interval
+    /// Returns the subscribers that get the records synced, see `WalHandle::subscribe`.
+    pub(crate) fn subscribers(&mut self) -> &mut Subscribers {
+        &mut self.subscribers
+    }
+
+    /// Returns the metrics the syncs and the writes are counted in.
+    pub fn metrics(&self) -> &WalMetrics {
+        &self.metrics
+    }
+
+    /// Returns the sync policy
+    pub fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    /// Returns the flush interval duration, if the policy syncs on a timer
+    pub fn flush_interval(&self) -> Option<Duration> {
+        self.policy.interval()
     }
 
     /// Marks that there are pending writes that need to be flushed
@@ -48,6 +162,37 @@ impl SyncManager {
         self.pending_flush = true;
     }
 
+    /// Marks that `records` records were written and need to be synced.
+    pub fn mark_written(&mut self, records: u64) {
+        if records > 0 {
+            self.pending_flush = true;
+            self.unsynced_records += records;
+        }
+    }
+
+    /// Returns true if the policy wants the writes made so far synced now.
+    pub fn sync_due(&self) -> bool {
+        if !self.pending_flush {
+            return false;
+        }
+        match self.policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced_records >= u64::from(n),
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        }
+    }
+
+    /// Returns true if a group of `records` records about to be written reaches the point
+    /// where the policy syncs, so the group should be written before taking more records.
+    pub fn group_full(&self, records: u64) -> bool {
+        match self.policy {
+            SyncPolicy::Always => records > 0,
+            SyncPolicy::EveryN(n) => records > 0 && self.unsynced_records + records >= u64::from(n),
+            SyncPolicy::Interval(_) | SyncPolicy::Never => false,
+        }
+    }
+
     /// Checks if there are pending writes that need flushing
     pub fn has_pending(&self) -> bool {
         self.pending_flush
@@ -57,7 +202,7 @@ impl SyncManager {
     /// 
     /// This performs:
     /// 1. `flush()` - Flushes the OS buffer to the file system
-    /// 2. `sync_all()` - Ensures data is written to disk (durability guarantee)
+    /// 2. A sync through the `LogSync` - Ensures data is written to disk (durability guarantee)
     /// 
     /// If there are no pending writes, this is a no-op.
     /// After flushing, the pending state is cleared.
@@ -72,22 +217,23 @@ impl SyncManager {
         file.flush()?;
         // Note: sync_all() is only available on File, not generic Write
         // We'll handle this differently - see flush_if_pending_file below
-        self.pending_flush = false;
+        self.mark_synced();
         Ok(())
     }
 
     /// Flushes and syncs a file if there are pending writes.
     /// 
     /// This is a specialized version for `std::fs::File` that includes
-    /// `sync_all()` for full durability guarantees.
+    /// a sync through the `LogSync` for full durability guarantees.
     pub fn flush_if_pending_file(&mut self, file: &mut std::fs::File) -> io::Result<()> {
         if !self.pending_flush {
             return Ok(());
         }
 
         file.flush()?;
-        file.sync_all()?;
-        self.pending_flush = false;
+        self.sync(file)?;
+        self.subscribers.synced();
+        self.mark_synced();
         Ok(())
     }
 
@@ -95,16 +241,44 @@ impl SyncManager {
     /// Useful for explicit durability requirements (e.g., before shutdown).
     pub fn force_flush(&mut self, file: &mut std::fs::File) -> io::Result<()> {
         file.flush()?;
-        file.sync_all()?;
-        self.pending_flush = false;
+        self.sync(file)?;
+        self.subscribers.synced();
+        self.mark_synced();
         Ok(())
     }
 
+    /// Syncs `file` through the syncer, counting the sync and its latency.
+    fn sync(&self, file: &File) -> io::Result<()> {
+        let start = Instant::now();
+        let synced = self.syncer.sync(file);
+        self.metrics.record_fsync(start.elapsed());
+        synced
+    }
+
     /// Clears the pending flush state without actually flushing.
     /// Use with caution - this should only be used when you're certain
-    /// the data doesn't need to be flushed (e.g., after a reset operation).
+    /// the data doesn't need to be flushed (e.g., after a reset operation). Subscribers do not
+    /// get the records written since the last sync.
     pub fn clear_pending(&mut self) {
         self.pending_flush = false;
+        self.unsynced_records = 0;
+        self.subscribers.discard_unsynced();
+    }
+
+    /// Keeps the error of a write or sync nobody waited on, to report it later. Only the first
+    /// error is kept until it is taken.
+    pub fn record_error(&mut self, err: io::Error) {
+        self.error.get_or_insert(err);
+    }
+
+    /// Takes the error kept by `record_error`.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn mark_synced(&mut self) {
+        self.clear_pending();
+        self.last_sync = Instant::now();
     }
 }
 
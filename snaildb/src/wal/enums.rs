@@ -1,14 +1,66 @@
+use std::io;
+use std::sync::mpsc;
+
 use crate::utils::record::RecordKind;
+use crate::wal::checkpoint::WalPosition;
+use crate::wal::subscribe::WalEvent;
 
 #[derive(Debug)]
 pub enum WriteCommand {
+    /// A record appended with sequence number `seqno`, 0 for none, the expiry of a `SetWithTtl`
+    /// record and the column family of the write, 0 for the default one.
     WriteRecord {
         kind: RecordKind,
         key: String,
         value: Vec<u8>,
+        seqno: u64,
+        expires_at: Option<u64>,
+        family: u32,
+    },
+    /// Records appended as one batch, which recovery replays either whole or not at all. They
+    /// are numbered from `first_seqno` on, or have no sequence number if it is 0.
+    WriteBatch {
+        first_seqno: u64,
+        records: Vec<(RecordKind, String, Vec<u8>)>,
+    },
+    /// Writes and syncs the records received so far, then sends back the outcome once they
+    /// are durable, or the error of a write that failed since the last flush. Flushes
+    /// requested together are coalesced into one sync.
+    Flush {
+        done: mpsc::Sender<io::Result<()>>,
+    },
+    /// A durability barrier: writes and syncs the records received so far, then sends back the
+    /// outcome like `Flush`, before anything received after it is written. Unlike a `Flush`,
+    /// it ends a group commit rather than joining the writes behind it to its sync.
+    Sync {
+        done: mpsc::Sender<io::Result<()>>,
+    },
+    /// Empties the log, keeping the records with a sequence number above `up_to` if it is set.
+    /// The log is replaced by a new file rather than truncated, so a crash leaves either the
+    /// old log or the new one.
+    Reset {
+        up_to: Option<u64>,
+    },
+    /// Sends back the position at the end of the records received so far.
+    Position(mpsc::Sender<io::Result<WalPosition>>),
+    /// Records that the records before `position` are durable elsewhere and drops them from
+    /// the log, then sends back the outcome.
+    Checkpoint {
+        position: WalPosition,
+        done: mpsc::Sender<io::Result<()>>,
+    },
+    /// Sends the records with a sequence number from `from_seqno` on to `events` once they are
+    /// durable, see `WalHandle::subscribe`.
+    Subscribe {
+        events: mpsc::SyncSender<WalEvent>,
+        from_seqno: u64,
+    },
+    /// Removes the segments of a segmented log below this sequence number, keeping the active one.
+    DeleteSegmentsBefore(u64),
+    /// Writes and syncs the records received so far, then sends back the outcome like `Flush`
+    /// and stops the writer.
+    Shutdown {
+        done: mpsc::Sender<io::Result<()>>,
     },
-    Flush,
-    Reset,
-    Shutdown,
 }
 
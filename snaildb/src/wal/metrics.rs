@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters of a WAL writer, shared by its handles and its thread, see `WalHandle::metrics`.
+///
+/// Every counter is an atomic updated with relaxed ordering, so the writer never waits on a
+/// reader. A snapshot is therefore not taken at a single instant, but each counter in it is
+/// at least as recent as the last command the caller saw acknowledged.
+#[derive(Debug, Default)]
+pub struct WalMetrics {
+    records_appended: AtomicU64,
+    batches_appended: AtomicU64,
+    bytes_written: AtomicU64,
+    fsyncs: AtomicU64,
+    fsync_nanos: AtomicU64,
+    fsync_max_nanos: AtomicU64,
+    /// commands sent to the writer and not taken yet
+    queue_depth: AtomicU64,
+    queue_depth_max: AtomicU64,
+    log_size: AtomicU64,
+}
+
+/// The counters of a `WalMetrics` at some point, see `WalMetrics::snapshot`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalMetricsSnapshot {
+    /// records appended to the log, the records of a batch included
+    pub records_appended: u64,
+    /// writes of a group of records to the log, see `WalWriter`: `records_appended` divided by
+    /// this is the average size of a group commit
+    pub batches_appended: u64,
+    /// bytes appended to the log, framing included
+    pub bytes_written: u64,
+    /// syncs of the log to make records durable
+    pub fsyncs: u64,
+    /// time spent in those syncs
+    pub fsync_time: Duration,
+    /// the longest of those syncs
+    pub fsync_time_max: Duration,
+    /// the most commands queued for the writer at once
+    pub queue_depth_max: u64,
+    /// bytes the log takes: the records of the active file, and the files of the segments
+    /// before it
+    pub log_size: u64,
+}
+
+impl WalMetrics {
+    /// Returns the current value of every counter.
+    pub fn snapshot(&self) -> WalMetricsSnapshot {
+        WalMetricsSnapshot {
+            records_appended: self.records_appended.load(Ordering::Relaxed),
+            batches_appended: self.batches_appended.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            fsyncs: self.fsyncs.load(Ordering::Relaxed),
+            fsync_time: Duration::from_nanos(self.fsync_nanos.load(Ordering::Relaxed)),
+            fsync_time_max: Duration::from_nanos(self.fsync_max_nanos.load(Ordering::Relaxed)),
+            queue_depth_max: self.queue_depth_max.load(Ordering::Relaxed),
+            log_size: self.log_size.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts a group of `records` records taking `bytes` bytes appended in one write.
+    pub(crate) fn record_append(&self, records: u64, bytes: u64) {
+        self.records_appended.fetch_add(records, Ordering::Relaxed);
+        self.batches_appended.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a sync that took `elapsed`.
+    pub(crate) fn record_fsync(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        self.fsync_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.fsync_max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Counts a command sent to the writer.
+    pub(crate) fn enqueue(&self) {
+        let depth = self.queue_depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.queue_depth_max.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    /// Counts a command taken by the writer. Commands sent without `enqueue`, as by `Wal`,
+    /// leave the depth at 0.
+    pub(crate) fn dequeue(&self) {
+        let _ = self
+            .queue_depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| Some(depth.saturating_sub(1)));
+    }
+
+    /// Notes that the log now takes `bytes` bytes.
+    pub(crate) fn set_log_size(&self, bytes: u64) {
+        self.log_size.store(bytes, Ordering::Relaxed);
+    }
+}
@@ -1,6 +1,26 @@
+#[allow(clippy::module_inception)]
 pub mod wal;
+pub mod checkpoint;
 pub mod enums;
 pub mod db_sync;
+pub mod metrics;
+pub mod reader;
+pub mod repair;
+pub mod replay;
+pub mod segment;
+pub mod subscribe;
+pub mod writer;
 
-pub use wal::Wal;
-pub use db_sync::{FLUSH_INTERVAL_MS, SyncManager};
+pub use wal::{Wal, WalEntry};
+pub use checkpoint::{WalPosition, dir_checkpoint_path, file_checkpoint_path, read_checkpoint};
+pub use metrics::{WalMetrics, WalMetricsSnapshot};
+pub use reader::WalReader;
+pub use repair::{RepairOptions, RepairReport, repair};
+pub use replay::{
+    RecoveryMode, RecoveryReport, ReplayOptions, WalReplay, replay, replay_archived, replay_dir,
+    replay_dir_with_options, replay_with_options,
+};
+pub use segment::{ArchivePolicy, SegmentOptions, segment_path, segments};
+pub use subscribe::{SlowSubscriberPolicy, WalEvent};
+pub use writer::{WalHandle, WalWriter, WalWriterOptions};
+pub use db_sync::{FLUSH_INTERVAL_MS, FileSync, LogSync, SyncManager, SyncPolicy};
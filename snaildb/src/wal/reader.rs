@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::utils::{DecodedRecord, EncryptionKey, RecordLimits};
+use crate::wal::replay::{RECORD_HEADER_LEN, read_records};
+
+/// Reads the records of a single WAL file along with the offset each one starts at, for tools
+/// that walk a log without recovering from it, such as inspecting it or shipping it elsewhere.
+///
+/// A consumer can remember the offset of the last record it processed and later resume from
+/// there with `seek_to`. The records of a batch all carry the offset of the batch, as a batch is
+/// only ever read whole. Unlike `WalReplay` there is no torn tail: a record that is cut short or
+/// fails its checksum is returned as an error that ends the iteration.
+#[derive(Debug)]
+pub struct WalReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+    /// offset of the record or batch after the ones read so far
+    offset: u64,
+    /// the records of the last batch read that are not yielded yet, with its offset
+    batch: VecDeque<(u64, DecodedRecord)>,
+    encryption_key: Option<EncryptionKey>,
+    record_limits: RecordLimits,
+    done: bool,
+}
+
+impl WalReader {
+    /// Opens the WAL file at `path`, positioned at its first record.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let reader = BufReader::new(File::open(&path)?);
+        Ok(Self {
+            path,
+            reader,
+            offset: 0,
+            batch: VecDeque::new(),
+            encryption_key: None,
+            record_limits: RecordLimits::default(),
+            done: false,
+        })
+    }
+
+    /// Decrypts encrypted records with `key`, see `ReplayOptions::encryption_key`.
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Reads records with keys and values up to `limits`, see `ReplayOptions::record_limits`.
+    pub fn with_record_limits(mut self, limits: RecordLimits) -> Self {
+        self.record_limits = limits;
+        self
+    }
+
+    /// Returns the offset of the next record to be read, the end of the records read so far.
+    pub fn offset(&self) -> u64 {
+        self.batch.front().map_or(self.offset, |(offset, _)| *offset)
+    }
+
+    /// Moves to the record or batch starting at `offset`, or to the end of the file. Fails with
+    /// `InvalidInput` if `offset` is past the end, and with `InvalidData` if no whole, valid
+    /// record starts there, leaving the reader where it was.
+    pub fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        let len = self.reader.get_ref().metadata()?.len();
+        if offset > len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {offset} is past the end of WAL {}, {len} bytes long", self.path.display()),
+            ));
+        }
+        if offset < len {
+            if let Err(err) = self.check_record_at(offset, len - offset) {
+                self.reader.seek(SeekFrom::Start(self.offset))?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no record starts at offset {offset} of WAL {}: {err}", self.path.display()),
+                ));
+            }
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        self.batch.clear();
+        self.done = false;
+        Ok(())
+    }
+
+    /// Reads the frame at `offset`, with `remaining` bytes of the file left from there, to check
+    /// that it is a whole, valid record or batch.
+    fn check_record_at(&mut self, offset: u64, remaining: u64) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut length = [0u8; 4];
+        self.reader.read_exact(&mut length)?;
+        // Checked before reading, so a stray length does not make it read the rest of the file
+        if RECORD_HEADER_LEN + u64::from(u32::from_le_bytes(length)) > remaining {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record runs past the end of the file"));
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        read_records(&mut self.reader, self.encryption_key.as_ref(), self.record_limits).map(|_| ())
+    }
+}
+
+impl Iterator for WalReader {
+    type Item = io::Result<(u64, DecodedRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.batch.pop_front() {
+            return Some(Ok(entry));
+        }
+        while !self.done {
+            match read_records(&mut self.reader, self.encryption_key.as_ref(), self.record_limits) {
+                Ok(Some(read)) => {
+                    let offset = self.offset;
+                    self.offset += read.len;
+                    self.batch.extend(read.records.into_iter().map(|record| (offset, record)));
+                    if let Some(entry) = self.batch.pop_front() {
+                        return Some(Ok(entry));
+                    }
+                }
+                Ok(None) => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    let err = io::Error::new(
+                        err.kind(),
+                        format!("WAL {} is damaged at offset {}: {err}", self.path.display(), self.offset),
+                    );
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
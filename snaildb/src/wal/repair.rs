@@ -0,0 +1,139 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::wal::checkpoint::{WalPosition, file_checkpoint_path, read_checkpoint, write_checkpoint};
+use crate::utils::{EncryptionKey, RecordLimits};
+use crate::wal::replay::{frame_at, is_undecodable, resync};
+use crate::wal::segment::sync_dir;
+
+/// Options controlling `repair`.
+#[derive(Clone, Debug, Default)]
+pub struct RepairOptions {
+    /// Drops everything from the first damaged record on, rather than looking for the next
+    /// valid record after it and keeping the records from there.
+    pub stop_at_first_corruption: bool,
+    /// Decrypts the records of an encrypted log to check them, see
+    /// `ReplayOptions::encryption_key`. Repairing an encrypted log without it fails.
+    pub encryption_key: Option<EncryptionKey>,
+    /// The limits the log was written with, see `ReplayOptions::record_limits`. A record over
+    /// them is dropped as damaged.
+    pub record_limits: RecordLimits,
+}
+
+impl RepairOptions {
+    /// Stops at the first damaged record instead of skipping over it.
+    pub fn with_stop_at_first_corruption(mut self, stop_at_first_corruption: bool) -> Self {
+        self.stop_at_first_corruption = stop_at_first_corruption;
+        self
+    }
+
+    /// Decrypts encrypted records with `key`.
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Checks records against `limits`.
+    pub fn with_record_limits(mut self, limits: RecordLimits) -> Self {
+        self.record_limits = limits;
+        self
+    }
+}
+
+/// What `repair` kept of a log and what it dropped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// records copied to the repaired log, the records of a batch included
+    pub records_kept: u64,
+    /// damaged records dropped. A region whose framing is damaged counts as one record, as
+    /// how many it held is unknown, so this is a lower bound
+    pub records_dropped: u64,
+    /// the byte ranges of the original log that were dropped, in order
+    pub skipped: Vec<Range<u64>>,
+    /// where the original log was kept, `None` if it was intact and left alone
+    pub backup: Option<PathBuf>,
+}
+
+/// Salvages the valid records of the WAL file at `path` when a damaged record in the middle
+/// of it makes recovery fail.
+///
+/// Every record, batch and sequence number marker that passes its checksum is copied to a new
+/// file, which is then renamed over the log. After a damaged record the scan resumes at the
+/// next offset where a valid record starts: right after the damaged one if its length still
+/// leads to a valid record, else at the first such offset found further on. With
+/// `RepairOptions::stop_at_first_corruption` everything from the damaged record on is dropped
+/// instead. Either way the original log is kept as `<path>.bak`, and a checkpoint of the log
+/// is moved to the same record of the repaired one. A log with nothing damaged, the zeros at
+/// the end of a preallocated file aside, is left as it is.
+///
+/// The log must not be open in a `WalWriter` meanwhile. The segments of a segmented log are
+/// repaired one at a time, keeping in mind that the checkpoint of the directory is not moved.
+pub fn repair(path: impl AsRef<Path>, options: &RepairOptions) -> io::Result<RepairReport> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    let len = bytes.len() as u64;
+    let mut report = RepairReport::default();
+    let mut repaired = Vec::with_capacity(bytes.len());
+    // (offset in the original log, offset in the repaired one) of every frame kept
+    let mut kept_at = Vec::new();
+    let mut offset = 0u64;
+    while offset < len {
+        match frame_at(&bytes, offset, options.encryption_key.as_ref(), options.record_limits) {
+            Some(Ok((frame_len, records))) => {
+                kept_at.push((offset, repaired.len() as u64));
+                repaired.extend_from_slice(&bytes[offset as usize..(offset + frame_len) as usize]);
+                report.records_kept += records;
+                offset += frame_len;
+            }
+            None => break,
+            // Not damage: a record compressed with a codec this build lacks, or encrypted with another key
+            Some(Err(err)) if is_undecodable(&err) => return Err(err),
+            Some(Err(_)) => {
+                report.records_dropped += 1;
+                let resume = if options.stop_at_first_corruption {
+                    len
+                } else {
+                    resync(&bytes, offset, options.encryption_key.as_ref(), options.record_limits)
+                };
+                report.skipped.push(offset..resume);
+                offset = resume;
+            }
+        }
+    }
+    if report.skipped.is_empty() {
+        return Ok(report);
+    }
+
+    let backup = with_suffix(path, ".bak");
+    fs::copy(path, &backup)?;
+    File::open(&backup)?.sync_all()?;
+    let temp = with_suffix(path, ".repair");
+    let mut file = File::create(&temp)?;
+    file.write_all(&repaired)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir)?,
+        _ => {}
+    }
+
+    let checkpoint_path = file_checkpoint_path(path);
+    if let Some(checkpoint) = read_checkpoint(&checkpoint_path)? {
+        // The first record kept at or after the checkpoint, or the end
+        let offset = kept_at
+            .iter()
+            .find(|(original, _)| *original >= checkpoint.offset)
+            .map_or(repaired.len() as u64, |(_, offset)| *offset);
+        write_checkpoint(&checkpoint_path, WalPosition { offset, ..checkpoint })?;
+    }
+    report.backup = Some(backup);
+    Ok(report)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,490 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::utils::{DecodedRecord, EncryptionKey};
+use crate::utils::record::{
+    FILE_HEADER_PAYLOAD_LEN, Frame, RecordLimits, SEQNO_MARKER_PAYLOAD_LEN, read_batch_body, read_frame,
+};
+use crate::wal::checkpoint::{dir_checkpoint_path, file_checkpoint_path, read_checkpoint};
+use crate::wal::segment;
+
+/// Length of the `[length:4][crc32:4]` header in front of every record payload.
+pub(crate) const RECORD_HEADER_LEN: u64 = 8;
+
+/// How a recovery treats damaged records, see `WalReplay`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Any damage is an error, a torn tail included: for data that must not lose a record
+    /// without someone looking at it first.
+    Strict,
+    /// Drops a torn tail left by a crash in the middle of a write, any other damage is an error.
+    #[default]
+    TolerateTornTail,
+    /// Skips every damaged record and keeps going from the next valid one, for data that can
+    /// afford to lose records. `RecoveryReport` tells what was skipped.
+    SkipCorrupt,
+}
+
+/// What a recovery found, see `WalReplay::report`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// records recovered, the records of a batch included
+    pub records_replayed: u64,
+    /// bytes of damaged records dropped, a torn tail included
+    pub bytes_skipped: u64,
+    /// the files that had records dropped, in the order they were read
+    pub segments_affected: Vec<PathBuf>,
+}
+
+impl RecoveryReport {
+    /// Notes that `bytes` bytes of `path` were dropped.
+    pub(crate) fn skipped(&mut self, path: &Path, bytes: u64) {
+        self.bytes_skipped += bytes;
+        if self.segments_affected.last().is_none_or(|last| last != path) {
+            self.segments_affected.push(path.to_path_buf());
+        }
+    }
+}
+
+/// Options controlling `replay_with_options` and `replay_dir_with_options`.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayOptions {
+    /// Truncates the log to its last whole record when the replay stops at a torn tail, so
+    /// appending to it afterwards does not leave garbage between the old and new records.
+    pub truncate_torn_tail: bool,
+    /// Starts the replay at the checkpoint of the log, skipping the records persisted
+    /// elsewhere, see `WalHandle::checkpoint`. A log without a checkpoint replays whole.
+    pub from_checkpoint: bool,
+    /// How damaged records are treated, `RecoveryMode::TolerateTornTail` by default.
+    pub recovery_mode: RecoveryMode,
+    /// Decrypts the records of a log written with `WalWriterOptions::with_encryption_key`.
+    /// Reading an encrypted record without it, or with another key, fails with
+    /// `PermissionDenied`.
+    pub encryption_key: Option<EncryptionKey>,
+    /// The limits the log was written with, see `WalWriterOptions::record_limits`. A record
+    /// longer than they allow counts as damaged, the default limits by default.
+    pub record_limits: RecordLimits,
+}
+
+impl ReplayOptions {
+    /// Truncates a torn tail off the log once the replay reaches it.
+    pub fn with_truncate_torn_tail(mut self, truncate_torn_tail: bool) -> Self {
+        self.truncate_torn_tail = truncate_torn_tail;
+        self
+    }
+
+    /// Starts the replay at the checkpoint of the log.
+    pub fn with_from_checkpoint(mut self, from_checkpoint: bool) -> Self {
+        self.from_checkpoint = from_checkpoint;
+        self
+    }
+
+    /// Sets how damaged records are treated.
+    pub fn with_recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    /// Decrypts encrypted records with `key`.
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Reads records with keys and values up to `limits`.
+    pub fn with_record_limits(mut self, limits: RecordLimits) -> Self {
+        self.record_limits = limits;
+        self
+    }
+}
+
+/// Opens the WAL file at `path` for recovery with the default options, see `WalReplay`.
+pub fn replay(path: impl AsRef<Path>) -> io::Result<WalReplay> {
+    replay_with_options(path, &ReplayOptions::default())
+}
+
+/// Opens the WAL file at `path` for recovery, see `WalReplay`.
+pub fn replay_with_options(path: impl AsRef<Path>, options: &ReplayOptions) -> io::Result<WalReplay> {
+    let path = path.as_ref();
+    let mut start = 0;
+    if options.from_checkpoint {
+        start = read_checkpoint(file_checkpoint_path(path))?.map_or(0, |checkpoint| checkpoint.offset);
+    }
+    WalReplay::new(VecDeque::from([path.to_path_buf()]), options, start)
+}
+
+/// Opens every segment of the WAL in `dir` for recovery with the default options, see
+/// `replay_dir_with_options`.
+pub fn replay_dir(dir: impl AsRef<Path>) -> io::Result<WalReplay> {
+    replay_dir_with_options(dir, &ReplayOptions::default())
+}
+
+/// Opens every segment of the WAL in `dir` for recovery, in sequence number order, see
+/// `WalReplay`. A directory without segments replays nothing.
+pub fn replay_dir_with_options(dir: impl AsRef<Path>, options: &ReplayOptions) -> io::Result<WalReplay> {
+    let dir = dir.as_ref();
+    let mut segments = segment::segments(dir)?;
+    let mut start = 0;
+    if options.from_checkpoint {
+        if let Some(checkpoint) = read_checkpoint(dir_checkpoint_path(dir))? {
+            segments.retain(|(seq, _)| *seq >= checkpoint.segment);
+            if segments.first().is_some_and(|(seq, _)| *seq == checkpoint.segment) {
+                start = checkpoint.offset;
+            }
+        }
+    }
+    WalReplay::new(segments.into_iter().map(|(_, path)| path).collect(), options, start)
+}
+
+/// Opens the segments retired from a segmented WAL, for forensics: every segment of an archive
+/// directory of `ArchivePolicy::MoveTo`, or the segments of a WAL directory that its
+/// checkpoint moved past under `ArchivePolicy::Keep`. They are replayed in sequence number
+/// order like `replay_dir`.
+pub fn replay_archived(dir: impl AsRef<Path>) -> io::Result<WalReplay> {
+    let dir = dir.as_ref();
+    let mut segments = segment::segments(dir)?;
+    if let Some(checkpoint) = read_checkpoint(dir_checkpoint_path(dir))? {
+        segments.retain(|(seq, _)| *seq < checkpoint.segment);
+    }
+    WalReplay::new(segments.into_iter().map(|(_, path)| path).collect(), &ReplayOptions::default(), 0)
+}
+
+/// Iterates the records of a WAL file, or of the segments of a WAL directory one after the
+/// other, in append order. Created by `replay` and `replay_dir`.
+///
+/// Every record carries a checksum of its payload, and the records of a batch are yielded
+/// only once the whole batch is read and matches the checksum in its header. The iteration
+/// ends cleanly at the end of the last file, and also at a torn tail left by a crash in the
+/// middle of a write: a record or batch at the end of the last file that is cut short or
+/// fails its checksum with no valid record right after it. A torn batch is dropped whole.
+/// `valid_len` then tells where the last whole record of that file ends, and
+/// `ReplayOptions::truncate_torn_tail` truncates the file there.
+///
+/// A record failing its checksum that is followed by a valid one is real corruption rather
+/// than a torn write, and is returned as an error that ends the iteration. So is any damaged
+/// record in a segment that is not the last one.
+///
+/// That is the default `RecoveryMode::TolerateTornTail`. Under `RecoveryMode::Strict` a torn
+/// tail is an error too, while `RecoveryMode::SkipCorrupt` skips a damaged record anywhere,
+/// resuming at the next offset where a valid record starts, or at the next segment if none
+/// does. Damage with no valid record after it in the last file is still a torn tail there.
+/// `report` tells what was recovered and what was dropped.
+#[derive(Debug)]
+pub struct WalReplay {
+    /// the segments not opened yet
+    pending: VecDeque<PathBuf>,
+    /// the file being read and its path
+    current: Option<(PathBuf, BufReader<File>)>,
+    /// the records of the last batch read that are not yielded yet
+    batch: VecDeque<DecodedRecord>,
+    /// bytes taken by the records read so far from the current file
+    valid_len: u64,
+    last_seqno: u64,
+    torn_tail: bool,
+    truncate_torn_tail: bool,
+    recovery_mode: RecoveryMode,
+    encryption_key: Option<EncryptionKey>,
+    record_limits: RecordLimits,
+    report: RecoveryReport,
+    done: bool,
+}
+
+impl WalReplay {
+    /// Replays the files in `pending`, the first one from offset `start`.
+    fn new(mut pending: VecDeque<PathBuf>, options: &ReplayOptions, start: u64) -> io::Result<Self> {
+        let mut valid_len = 0;
+        let current = match pending.pop_front() {
+            Some(path) => {
+                let (path, mut reader) = open(path)?;
+                // A checkpoint past the end is left by a crash while the log was emptied at it
+                if start <= reader.get_ref().metadata()?.len() {
+                    reader.seek(SeekFrom::Start(start))?;
+                    valid_len = start;
+                }
+                Some((path, reader))
+            }
+            None => None,
+        };
+        Ok(Self {
+            pending,
+            current,
+            batch: VecDeque::new(),
+            valid_len,
+            last_seqno: 0,
+            torn_tail: false,
+            truncate_torn_tail: options.truncate_torn_tail,
+            recovery_mode: options.recovery_mode,
+            encryption_key: options.encryption_key.clone(),
+            record_limits: options.record_limits,
+            report: RecoveryReport::default(),
+            done: false,
+        })
+    }
+
+    /// Returns the path of the file being read, the last one once the iteration ended.
+    pub fn path(&self) -> Option<&Path> {
+        self.current.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    /// Returns the number of bytes of whole, valid records read so far from the file `path`
+    /// returns. Once the iteration ended this is where that file should be truncated to drop
+    /// a partial trailing record.
+    pub fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    /// Returns true if the iteration stopped at a torn tail.
+    pub fn has_torn_tail(&self) -> bool {
+        self.torn_tail
+    }
+
+    /// Returns the largest sequence number read so far, counting the one the WAL records where
+    /// it empties the log, or 0 if none was. Once the iteration ended the writer continues after
+    /// it, see `WalHandle::write`.
+    pub fn last_seqno(&self) -> u64 {
+        self.last_seqno
+    }
+
+    /// Returns what the replay recovered and dropped so far, all of it once the iteration ended.
+    pub fn report(&self) -> &RecoveryReport {
+        &self.report
+    }
+
+    /// Moves on to the next segment, returns false after the last one.
+    fn next_segment(&mut self) -> io::Result<bool> {
+        let Some(path) = self.pending.pop_front() else {
+            return Ok(false);
+        };
+        self.current = Some(open(path)?);
+        self.valid_len = 0;
+        Ok(true)
+    }
+
+    /// Tells a torn tail from corruption after `err` was hit reading the record at `valid_len`,
+    /// and truncates the tail if asked to.
+    fn end_at_damaged_record(&mut self, err: io::Error) -> Option<io::Result<DecodedRecord>> {
+        self.done = true;
+        let (path, reader) = self.current.as_mut()?;
+        let last = self.pending.is_empty();
+        // `SkipCorrupt` only ends here when no valid record follows in the last file
+        let torn = last
+            && match (self.recovery_mode, err.kind()) {
+                (RecoveryMode::SkipCorrupt, _) | (_, io::ErrorKind::UnexpectedEof) => true,
+                _ => matches!(record_follows(reader, self.valid_len, self.record_limits), Ok(false)),
+            };
+        if !torn || self.recovery_mode == RecoveryMode::Strict {
+            let damage = if torn { "has a torn tail" } else { "is corrupt" };
+            let err = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("WAL {} {damage} at offset {}: {err}", path.display(), self.valid_len),
+            );
+            return Some(Err(err));
+        }
+        self.torn_tail = true;
+        let len = match reader.get_ref().metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(err) => return Some(Err(err)),
+        };
+        self.report.skipped(path, len.saturating_sub(self.valid_len));
+        if self.truncate_torn_tail {
+            let truncated = OpenOptions::new()
+                .write(true)
+                .open(&*path)
+                .and_then(|file| file.set_len(self.valid_len).and_then(|_| file.sync_all()));
+            if let Err(err) = truncated {
+                return Some(Err(err));
+            }
+        }
+        None
+    }
+
+    /// Skips the damaged record at `valid_len` under `RecoveryMode::SkipCorrupt`, up to the
+    /// next offset where a valid record starts, or to the next segment if none does. Returns
+    /// false if there is nowhere to resume, the damage is then the torn tail of the last file.
+    fn skip_damaged_record(&mut self) -> io::Result<bool> {
+        let Some((path, reader)) = self.current.as_mut() else {
+            return Ok(false);
+        };
+        reader.seek(SeekFrom::Start(self.valid_len))?;
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        let resume = resync(&rest, 0, self.encryption_key.as_ref(), self.record_limits);
+        if resume < rest.len() as u64 {
+            reader.seek(SeekFrom::Start(self.valid_len + resume))?;
+            self.report.skipped(path, resume);
+            self.valid_len += resume;
+            return Ok(true);
+        }
+        if self.pending.is_empty() {
+            return Ok(false);
+        }
+        self.report.skipped(path, resume);
+        self.next_segment()
+    }
+}
+
+fn open(path: PathBuf) -> io::Result<(PathBuf, BufReader<File>)> {
+    let file = File::open(&path)?;
+    Ok((path, BufReader::new(file)))
+}
+
+/// What `read_records` read: a record, a batch of records or a sequence number marker.
+pub(crate) struct Records {
+    /// number of bytes taken by the frames read
+    pub len: u64,
+    /// the record or the records of the batch, none for a marker
+    pub records: Vec<DecodedRecord>,
+    /// the largest sequence number of the records, or the one the marker recorded
+    pub last_seqno: u64,
+}
+
+/// Reads the next record, the records of the next batch, or the next sequence number marker,
+/// decrypting encrypted records with `key` and refusing those over `limits`. Returns `None` at
+/// the end of the log.
+pub(crate) fn read_records<R: Read>(
+    reader: &mut R,
+    key: Option<&EncryptionKey>,
+    limits: RecordLimits,
+) -> io::Result<Option<Records>> {
+    let (len, records, marker) = match read_frame(reader, key, limits)? {
+        Some(Frame::Batch(header)) => (header.batch_length(), read_batch_body(reader, &header, key, limits)?, 0),
+        Some(Frame::Record(record)) => (RECORD_HEADER_LEN + u64::from(record.length), vec![record], 0),
+        Some(Frame::SeqnoMarker(seqno)) => (RECORD_HEADER_LEN + SEQNO_MARKER_PAYLOAD_LEN as u64, Vec::new(), seqno),
+        Some(Frame::FileHeader) => (RECORD_HEADER_LEN + FILE_HEADER_PAYLOAD_LEN as u64, Vec::new(), 0),
+        None => return Ok(None),
+    };
+    let last_seqno = records.iter().map(|record| record.seqno).fold(marker, u64::max);
+    Ok(Some(Records { len, records, last_seqno }))
+}
+
+/// Reads the frame at `offset`: a record, a batch or a marker, returning its length and how
+/// many records it holds. Returns `None` at the end of the log, where only zeros are left,
+/// and an error if the frame is damaged. Encrypted records are decrypted with `key`, records
+/// over `limits` are damaged.
+pub(crate) fn frame_at(
+    bytes: &[u8],
+    offset: u64,
+    key: Option<&EncryptionKey>,
+    limits: RecordLimits,
+) -> Option<io::Result<(u64, u64)>> {
+    let rest = &bytes[offset as usize..];
+    if rest.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    let Some(length) = rest.get(..4) else {
+        return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record header cut short")));
+    };
+    // Checked before reading, so a damaged length does not ask for more than the file holds
+    let length = u32::from_le_bytes(length.try_into().expect("4-byte slice"));
+    if RECORD_HEADER_LEN + u64::from(length) > rest.len() as u64 {
+        return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record runs past the end of the log")));
+    }
+    let mut reader = rest;
+    Some(match read_records(&mut reader, key, limits) {
+        Ok(Some(read)) => Ok((read.len, read.records.len() as u64)),
+        // zeros in the middle of the log
+        Ok(None) => Err(io::Error::new(io::ErrorKind::InvalidData, "empty record")),
+        Err(err) => Err(err),
+    })
+}
+
+/// Returns the offset to resume at after the damaged frame at `offset`: the end of the frame if
+/// a valid one follows, else the next offset where a valid frame starts, or the end of the log.
+pub(crate) fn resync(bytes: &[u8], offset: u64, key: Option<&EncryptionKey>, limits: RecordLimits) -> u64 {
+    // A record that cannot be decoded here counts, so reading it fails rather than skips it
+    let is_valid = |at: u64| {
+        at < bytes.len() as u64
+            && match frame_at(bytes, at, key, limits) {
+                Some(Ok(_)) => true,
+                Some(Err(err)) => is_undecodable(&err),
+                None => false,
+            }
+    };
+    let length = bytes.get(offset as usize..offset as usize + 4).map(|length| {
+        u32::from_le_bytes(length.try_into().expect("4-byte slice"))
+    });
+    if let Some(length) = length {
+        let next = offset + RECORD_HEADER_LEN + u64::from(length);
+        if is_valid(next) {
+            return next;
+        }
+    }
+    (offset + 1..bytes.len() as u64).find(|at| is_valid(*at)).unwrap_or(bytes.len() as u64)
+}
+
+/// Returns true if `err` says a whole record could not be decoded for want of a feature or of
+/// the right encryption key, rather than that it is damaged.
+pub(crate) fn is_undecodable(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied)
+}
+
+/// Returns true if a valid record follows the damaged record or batch at `offset`, going by
+/// the length in its header.
+fn record_follows(reader: &mut BufReader<File>, offset: u64, limits: RecordLimits) -> io::Result<bool> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    reader.seek(SeekFrom::Start(offset))?;
+    let next = match read_frame(reader, None, limits) {
+        Ok(Some(Frame::Batch(header))) => offset + header.batch_length(),
+        _ => offset + RECORD_HEADER_LEN + u64::from(u32::from_le_bytes(length)),
+    };
+    reader.seek(SeekFrom::Start(next))?;
+    // A record this build or the key at hand cannot decode still starts there
+    Ok(match read_frame(reader, None, limits) {
+        Ok(frame) => frame.is_some(),
+        Err(err) => is_undecodable(&err),
+    })
+}
+
+impl Iterator for WalReplay {
+    type Item = io::Result<DecodedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(record) = self.batch.pop_front() {
+            return Some(Ok(record));
+        }
+        while !self.done {
+            let (_, reader) = self.current.as_mut()?;
+            match read_records(reader, self.encryption_key.as_ref(), self.record_limits) {
+                Ok(Some(read)) => {
+                    self.valid_len += read.len;
+                    self.last_seqno = self.last_seqno.max(read.last_seqno);
+                    self.report.records_replayed += read.records.len() as u64;
+                    self.batch.extend(read.records);
+                    if let Some(record) = self.batch.pop_front() {
+                        return Some(Ok(record));
+                    }
+                }
+                Ok(None) => match self.next_segment() {
+                    Ok(true) => {}
+                    Ok(false) => self.done = true,
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                },
+                Err(err) if matches!(err.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) => {
+                    if self.recovery_mode == RecoveryMode::SkipCorrupt {
+                        match self.skip_damaged_record() {
+                            Ok(true) => continue,
+                            Ok(false) => {}
+                            Err(err) => {
+                                self.done = true;
+                                return Some(Err(err));
+                            }
+                        }
+                    }
+                    return self.end_at_damaged_record(err);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
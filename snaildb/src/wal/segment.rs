@@ -0,0 +1,597 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::utils::EncryptionKey;
+use crate::utils::record::{RecordEncoding, encode_file_header, encode_record_with, encode_seqno_marker};
+use crate::wal::checkpoint::{WalPosition, dir_checkpoint_path, file_checkpoint_path, read_checkpoint, write_checkpoint};
+use crate::wal::replay::{self, ReplayOptions};
+use crate::wal::wal::open_log_file;
+
+/// Default size at which the active segment is closed and the next one opened.
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024; // 64 MiB
+
+/// Most retired segments kept for reuse when `SegmentOptions::recycle_segments` is set, the
+/// others are deleted.
+const MAX_RECYCLED_SEGMENTS: usize = 4;
+
+/// Options of a WAL split into segments, see `WalWriter::open_dir`.
+#[derive(Clone, Debug)]
+pub struct SegmentOptions {
+    /// Size in bytes past which the active segment is closed and `wal-<seq>.log` with the next
+    /// sequence number is opened. A batch of records always goes into one segment, so a segment
+    /// only grows past this size when a single batch does.
+    pub max_segment_size: u64,
+    /// What becomes of the segments retired by a reset, a checkpoint or
+    /// `WalHandle::delete_segments_before`.
+    pub archive_policy: ArchivePolicy,
+    /// Extends every segment to `max_segment_size` when it is created, so appending to it
+    /// does not change its size and a sync does not have to update the file metadata. The
+    /// end of the records is then marked by the zeros that follow them.
+    pub preallocate: bool,
+    /// Keeps segments that would be deleted as `recycle-<seq>.log` files, up to a few, and
+    /// reuses them for the next segments instead of creating new files. A recycled file is
+    /// zeroed before reuse, so its old records never show up in a replay.
+    pub recycle_segments: bool,
+}
+
+impl SegmentOptions {
+    /// Sets the size at which segments rotate.
+    pub fn with_max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Sets what becomes of retired segments.
+    pub fn with_archive_policy(mut self, archive_policy: ArchivePolicy) -> Self {
+        self.archive_policy = archive_policy;
+        self
+    }
+
+    /// Preallocates segments to their maximum size.
+    pub fn with_preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Reuses the files of deleted segments.
+    pub fn with_recycle_segments(mut self, recycle_segments: bool) -> Self {
+        self.recycle_segments = recycle_segments;
+        self
+    }
+}
+
+impl Default for SegmentOptions {
+    fn default() -> Self {
+        Self {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            archive_policy: ArchivePolicy::Delete,
+            preallocate: false,
+            recycle_segments: false,
+        }
+    }
+}
+
+/// What becomes of the segments of a segmented WAL once their records are no longer needed
+/// for recovery. Segments kept one way or the other can be read with `replay_archived`.
+///
+/// Unless segments are deleted, a reset does not truncate the active segment either: it is
+/// retired like the others and the next one becomes active.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ArchivePolicy {
+    /// Removes retired segments.
+    #[default]
+    Delete,
+    /// Renames retired segments into this directory, keeping their `wal-<seq>.log` names. It
+    /// must be on the same file system as the WAL.
+    MoveTo(PathBuf),
+    /// Leaves retired segments in the WAL directory and moves the checkpoint past them, so
+    /// `ReplayOptions::from_checkpoint` skips them.
+    Keep,
+}
+
+/// Returns the path of segment `seq` in `dir`: `wal-<seq>.log`.
+pub fn segment_path(dir: impl AsRef<Path>, seq: u64) -> PathBuf {
+    dir.as_ref().join(format!("wal-{}.log", seq))
+}
+
+/// Returns the sequence number and path of every segment in `dir`, ordered by sequence number.
+/// Other files are ignored.
+pub fn segments(dir: impl AsRef<Path>) -> io::Result<Vec<(u64, PathBuf)>> {
+    numbered_files(dir.as_ref(), "wal-")
+}
+
+/// Returns the files of `dir` named `<prefix><seq>.log`, ordered by sequence number.
+fn numbered_files(dir: &Path, prefix: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let seq = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(seq) = seq {
+            if entry.file_type()?.is_file() {
+                segments.push((seq, entry.path()));
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// The file the WAL writer appends to: a single log file, or the active segment of a
+/// directory of segments that is rotated by size.
+pub(crate) struct LogFile {
+    file: File,
+    /// path of the single log file, or of the directory of segments
+    path: PathBuf,
+    segments: Option<Segments>,
+    /// path of the checkpoint of the log, see `checkpoint`
+    checkpoint: PathBuf,
+    /// the last sequence number given to a record of the log, see `rewrite`
+    last_seqno: u64,
+    /// how records are compressed and encrypted, see `WalWriterOptions`
+    encoding: RecordEncoding,
+    /// set while the active file is empty, so the next batch is written after the file header
+    needs_header: bool,
+}
+
+/// The state of a segmented log.
+struct Segments {
+    dir: PathBuf,
+    /// sequence number of the active segment
+    seq: u64,
+    /// current length of the records in the active segment, less than the length of the file
+    /// when it is preallocated
+    len: u64,
+    /// total length of the files of the segments before the active one
+    closed_size: u64,
+    max_size: u64,
+    archive: ArchivePolicy,
+    preallocate: bool,
+    recycle: bool,
+}
+
+impl LogFile {
+    /// Appends to the log `file` opened at `path`, encoding records as `encoding` says.
+    pub(crate) fn single(path: &Path, file: File, encoding: RecordEncoding) -> io::Result<Self> {
+        remove_stale_rewrite(path)?;
+        let needs_header = file.metadata()?.len() == 0;
+        let log = Self {
+            file,
+            path: path.to_path_buf(),
+            segments: None,
+            checkpoint: file_checkpoint_path(path),
+            last_seqno: 0,
+            encoding,
+            needs_header,
+        };
+        log.repair_checkpoint()?;
+        Ok(log)
+    }
+
+    /// Opens the segments in `dir`, appending to the newest one, or creates `wal-1.log`.
+    pub(crate) fn segmented(dir: &Path, options: &SegmentOptions, encoding: RecordEncoding) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let seq = segments(dir)?.last().map_or(1, |(seq, _)| *seq);
+        let path = segment_path(dir, seq);
+        remove_stale_rewrite(&path)?;
+        remove_stale_rewrite(&segment_path(dir, seq + 1))?;
+        let (file, len) = if options.preallocate || options.recycle_segments {
+            // The file may be longer than its records, appending starts after the last one
+            let len = if path.exists() { logical_len(&path, encoding.encryption_key.as_ref())? } else { 0 };
+            let mut file = open_segment_file(&path)?;
+            if options.preallocate {
+                preallocate(&mut file, options.max_segment_size)?;
+            }
+            file.seek(SeekFrom::Start(len))?;
+            (file, len)
+        } else {
+            let file = open_log_file(&path)?;
+            let len = file.metadata()?.len();
+            (file, len)
+        };
+        let log = Self {
+            file,
+            path: dir.to_path_buf(),
+            segments: Some(Segments {
+                dir: dir.to_path_buf(),
+                seq,
+                len,
+                closed_size: closed_segments_size(dir, seq)?,
+                max_size: options.max_segment_size,
+                archive: options.archive_policy.clone(),
+                preallocate: options.preallocate,
+                recycle: options.recycle_segments,
+            }),
+            checkpoint: dir_checkpoint_path(dir),
+            last_seqno: 0,
+            encoding,
+            needs_header: len == 0,
+        };
+        log.repair_checkpoint()?;
+        Ok(log)
+    }
+
+    /// A crash while the log was emptied at a checkpoint can leave the checkpoint past the end
+    /// of the active file, where it would skip the records appended next. Moves it back to the
+    /// start of the file.
+    fn repair_checkpoint(&self) -> io::Result<()> {
+        let Some(checkpoint) = read_checkpoint(&self.checkpoint)? else {
+            return Ok(());
+        };
+        let end = self.position()?;
+        if checkpoint.segment == end.segment && checkpoint.offset > end.offset {
+            write_checkpoint(&self.checkpoint, WalPosition { offset: 0, ..end })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes the log takes: the records of the active file and the files of the
+    /// segments before it.
+    pub(crate) fn size(&self) -> io::Result<u64> {
+        match &self.segments {
+            Some(segments) => Ok(segments.closed_size + segments.len),
+            None => Ok(self.file.metadata()?.len()),
+        }
+    }
+
+    /// Returns the position at the end of the records written so far.
+    pub(crate) fn position(&self) -> io::Result<WalPosition> {
+        match &self.segments {
+            Some(segments) => Ok(WalPosition { segment: segments.seq, offset: segments.len }),
+            None => Ok(WalPosition { segment: 0, offset: self.file.metadata()?.len() }),
+        }
+    }
+
+    /// Records that the records before `position` are durable elsewhere, so replay skips them,
+    /// and removes them where it can: the log is emptied if `position` is its end, else the
+    /// segments holding only records before it are removed.
+    ///
+    /// The checkpoint is recorded before anything is removed, so a crash in between leaves
+    /// records that replay skips.
+    pub(crate) fn checkpoint(&mut self, position: WalPosition) -> io::Result<()> {
+        let end = self.position()?;
+        if position > end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("checkpoint {position:?} is past the end of the log at {end:?}"),
+            ));
+        }
+        if position == end {
+            return self.rewrite(&[], true);
+        }
+        write_checkpoint(&self.checkpoint, position)?;
+        let Some(segments) = &self.segments else {
+            return Ok(());
+        };
+        // A checkpoint at the end of a closed segment covers all of it
+        let mut before = position.segment;
+        if position.segment < end.segment {
+            let path = segment_path(&segments.dir, position.segment);
+            let len = match path.exists() {
+                true if segments.preallocate || segments.recycle => {
+                    logical_len(&path, self.encoding.encryption_key.as_ref())?
+                }
+                true => std::fs::metadata(&path)?.len(),
+                false => 0,
+            };
+            if position.offset >= len {
+                before += 1;
+            }
+        }
+        self.delete_segments_before(before)
+    }
+
+    pub(crate) fn file_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Notes that the records up to sequence number `seqno` were written.
+    pub(crate) fn advance_seqno(&mut self, seqno: u64) {
+        self.last_seqno = self.last_seqno.max(seqno);
+    }
+
+    /// Returns how records are encoded.
+    pub(crate) fn encoding(&self) -> &RecordEncoding {
+        &self.encoding
+    }
+
+    /// Appends a batch of whole records, first rotating to a new segment if the batch would
+    /// take the active one past its maximum size. A batch larger than that goes into a segment
+    /// of its own. The first batch of a file is written after the file header, in one write.
+    /// Returns the bytes written, the header included.
+    pub(crate) fn write_batch(&mut self, batch: &[u8]) -> io::Result<u64> {
+        if let Some(segments) = &self.segments {
+            if segments.len > 0 && segments.len + batch.len() as u64 > segments.max_size {
+                self.rotate()?;
+            }
+        }
+        let written = match self.needs_header {
+            true => {
+                let mut contents = file_header(&self.encoding);
+                contents.extend_from_slice(batch);
+                self.file.write_all(&contents)?;
+                self.needs_header = false;
+                contents.len()
+            }
+            false => {
+                self.file.write_all(batch)?;
+                batch.len()
+            }
+        };
+        if let Some(segments) = &mut self.segments {
+            segments.len += written as u64;
+        }
+        Ok(written as u64)
+    }
+
+    /// Syncs the active segment and opens the next one, reusing a recycled file if there is one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let Some(segments) = &mut self.segments else {
+            return Ok(());
+        };
+        self.file.flush()?;
+        self.file.sync_all()?;
+        let path = segment_path(&segments.dir, segments.seq + 1);
+        let next = if segments.preallocate || segments.recycle {
+            let recycled = numbered_files(&segments.dir, "recycle-")?.into_iter().next();
+            let mut file = match recycled {
+                Some((_, recycled)) => {
+                    std::fs::rename(recycled, &path)?;
+                    let mut file = open_segment_file(&path)?;
+                    let len = file.metadata()?.len();
+                    zero_fill(&mut file, 0, len)?;
+                    file
+                }
+                None => open_segment_file(&path)?,
+            };
+            if segments.preallocate {
+                preallocate(&mut file, segments.max_size)?;
+            }
+            file.sync_all()?;
+            file.seek(SeekFrom::Start(0))?;
+            file
+        } else {
+            open_log_file(&path)?
+        };
+        sync_dir(&segments.dir)?;
+        self.file = next;
+        self.needs_header = true;
+        segments.seq += 1;
+        segments.closed_size = closed_segments_size(&segments.dir, segments.seq)?;
+        segments.len = 0;
+        Ok(())
+    }
+
+    /// Empties the log, keeping only the records with a sequence number above `up_to` if it is
+    /// set, see `rewrite`. A checkpoint is moved to the start of the new log.
+    pub(crate) fn reset(&mut self, up_to: Option<u64>) -> io::Result<()> {
+        let mut carried = Vec::new();
+        if let Some(up_to) = up_to {
+            let options = ReplayOptions { encryption_key: self.encoding.encryption_key.clone(), ..Default::default() };
+            let replay = match &self.segments {
+                Some(_) => replay::replay_dir_with_options(&self.path, &options)?,
+                None => replay::replay_with_options(&self.path, &options)?,
+            };
+            for record in replay {
+                let record = record?;
+                if record.seqno > up_to {
+                    let (key, value) = (&record.key, &record.value);
+                    let (kind, expires_at, seqno) = (record.kind, record.expires_at, record.seqno);
+                    encode_record_with(&mut carried, kind, key, value, expires_at, seqno, &self.encoding)?;
+                }
+            }
+        }
+        self.rewrite(&carried, false)
+    }
+
+    /// Replaces the log with a new one holding the `carried` records, and retires the older
+    /// segments. Also records a checkpoint at the start of the new log if `checkpoint` is set
+    /// or the log had one.
+    ///
+    /// The new log is written and synced next to the file it replaces as `<name>.new`, then
+    /// renamed over it, so a crash or a reader opening the log meanwhile sees either the whole
+    /// old log or the whole new one. It starts with a marker of the last sequence number given
+    /// out, so a writer opening it later continues after it instead of numbering records from 1
+    /// again; replays skip the marker. Segments that are archived rather than deleted are not
+    /// replaced: the new log goes into the next segment and the active one is retired with
+    /// the older ones.
+    fn rewrite(&mut self, carried: &[u8], checkpoint: bool) -> io::Result<()> {
+        let (target, segment) = match &self.segments {
+            Some(segments) if segments.archive != ArchivePolicy::Delete && segments.len > 0 => {
+                (segment_path(&segments.dir, segments.seq + 1), segments.seq + 1)
+            }
+            Some(segments) => (segment_path(&segments.dir, segments.seq), segments.seq),
+            None => (self.path.clone(), 0),
+        };
+        let start = WalPosition { segment, offset: 0 };
+        // A checkpoint into the file replaced would land amid the new records
+        if read_checkpoint(&self.checkpoint)?.is_some_and(|old| old.segment == segment && old.offset > 0) {
+            write_checkpoint(&self.checkpoint, start)?;
+        }
+
+        let mut contents = Vec::new();
+        if self.last_seqno != 0 {
+            encode_seqno_marker(&mut contents, self.last_seqno);
+        }
+        contents.extend_from_slice(carried);
+        if !contents.is_empty() {
+            contents.splice(0..0, file_header(&self.encoding));
+        }
+        let temp = rewrite_path(&target);
+        let mut file = OpenOptions::new().create(true).truncate(true).write(true).open(&temp)?;
+        file.write_all(&contents)?;
+        if let Some(segments) = self.segments.as_ref().filter(|segments| segments.preallocate) {
+            preallocate(&mut file, segments.max_size)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, &target)?;
+        match target.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir)?,
+            _ => {}
+        }
+
+        self.file = match &mut self.segments {
+            Some(segments) => {
+                segments.seq = segment;
+                segments.len = contents.len() as u64;
+                segments.closed_size = closed_segments_size(&segments.dir, segment)?;
+                let mut file = open_segment_file(&target)?;
+                file.seek(SeekFrom::Start(segments.len))?;
+                file
+            }
+            None => open_log_file(&target)?,
+        };
+        self.needs_header = contents.is_empty();
+        if checkpoint || self.checkpoint.exists() {
+            write_checkpoint(&self.checkpoint, start)?;
+        }
+        self.delete_segments_before(segment)
+    }
+
+    /// Retires the segments with a sequence number below `seq` following the archive policy.
+    /// The active segment is kept.
+    pub(crate) fn delete_segments_before(&mut self, seq: u64) -> io::Result<()> {
+        let Some(segments) = &self.segments else {
+            return Ok(());
+        };
+        let before = seq.min(segments.seq);
+        let retired = self::segments(&segments.dir)?.into_iter().filter(|(n, _)| *n < before);
+        match &segments.archive {
+            ArchivePolicy::Delete if segments.recycle => {
+                let mut spare = numbered_files(&segments.dir, "recycle-")?.len();
+                for (seq, path) in retired {
+                    if spare < MAX_RECYCLED_SEGMENTS {
+                        std::fs::rename(path, segments.dir.join(format!("recycle-{}.log", seq)))?;
+                        spare += 1;
+                    } else {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+            }
+            ArchivePolicy::Delete => {
+                for (_, path) in retired {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            ArchivePolicy::MoveTo(archive) => {
+                std::fs::create_dir_all(archive)?;
+                for (seq, path) in retired {
+                    std::fs::rename(path, segment_path(archive, seq))?;
+                }
+                sync_dir(archive)?;
+            }
+            ArchivePolicy::Keep => {
+                let start = WalPosition { segment: before, offset: 0 };
+                if read_checkpoint(&self.checkpoint)?.is_none_or(|checkpoint| checkpoint < start) {
+                    write_checkpoint(&self.checkpoint, start)?;
+                }
+                return Ok(());
+            }
+        }
+        sync_dir(&segments.dir)?;
+        let closed_size = closed_segments_size(&segments.dir, segments.seq)?;
+        if let Some(segments) = &mut self.segments {
+            segments.closed_size = closed_size;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the total length of the files of the segments in `dir` numbered below `active`.
+fn closed_segments_size(dir: &Path, active: u64) -> io::Result<u64> {
+    let mut size = 0;
+    for (_, path) in segments(dir)?.into_iter().filter(|(seq, _)| *seq < active) {
+        size += std::fs::metadata(path)?.len();
+    }
+    Ok(size)
+}
+
+/// Returns the path a new log replacing the one at `path` is written to, see `LogFile::rewrite`.
+fn rewrite_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(".new");
+    PathBuf::from(temp)
+}
+
+/// Removes the new log a crash left before it replaced the one at `path`, which is still whole.
+fn remove_stale_rewrite(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(rewrite_path(path)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the header a file of a log encoded as `encoding` starts with, see `FormatVersion`.
+fn file_header(encoding: &RecordEncoding) -> Vec<u8> {
+    let mut header = Vec::new();
+    encode_file_header(&mut header, encoding.format_version);
+    header
+}
+
+/// Opens a segment for writing at its start rather than in append mode, as its file can be
+/// longer than its records.
+fn open_segment_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)
+}
+
+/// Returns the length of the records in the segment at `path`, up to the zeros or the torn
+/// record that follow them. Encrypted records are read with `key`.
+fn logical_len(path: &Path, key: Option<&EncryptionKey>) -> io::Result<u64> {
+    let options = ReplayOptions { encryption_key: key.cloned(), ..Default::default() };
+    let mut replay = replay::replay_with_options(path, &options)?;
+    for record in replay.by_ref() {
+        record?;
+    }
+    Ok(replay.valid_len())
+}
+
+/// Extends `file` to `len` bytes of zeros, unless it is already that long.
+#[cfg(unix)]
+fn preallocate(file: &mut File, len: u64) -> io::Result<()> {
+    let current = file.metadata()?.len();
+    if current < len {
+        // ftruncate, the file system hands out the zeroed blocks
+        file.set_len(len)?;
+    }
+    Ok(())
+}
+
+/// Extends `file` to `len` bytes of zeros written out, unless it is already that long.
+#[cfg(not(unix))]
+fn preallocate(file: &mut File, len: u64) -> io::Result<()> {
+    let current = file.metadata()?.len();
+    if current < len {
+        zero_fill(file, current, len)?;
+    }
+    Ok(())
+}
+
+/// Overwrites the bytes of `file` from `start` to `end` with zeros.
+fn zero_fill(file: &mut File, start: u64, end: u64) -> io::Result<()> {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+    file.seek(SeekFrom::Start(start))?;
+    let mut remaining = end.saturating_sub(start);
+    while remaining > 0 {
+        let chunk = remaining.min(ZEROS.len() as u64) as usize;
+        file.write_all(&ZEROS[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    Ok(())
+}
+
+/// Syncs a directory, so segments created in or removed from it survive a crash.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened for syncing on this platform, the change is left to the OS.
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::sync::mpsc::{self, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::utils::RecordKind;
+
+/// A record of the log, sent to the subscribers of a WAL writer once it is durable, see
+/// `WalHandle::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalEvent {
+    pub seqno: u64,
+    pub kind: RecordKind,
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+/// What the WAL writer does with a subscriber whose buffer of events is full, see
+/// `WalWriterOptions::with_slow_subscriber_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowSubscriberPolicy {
+    /// Waits for the subscriber to make room, holding up the writer and so every write, for at
+    /// most this long per event, then disconnects it. The default, for one second.
+    Backpressure(Duration),
+    /// Disconnects the subscriber right away, never holding up the writer.
+    Disconnect,
+}
+
+impl Default for SlowSubscriberPolicy {
+    fn default() -> Self {
+        SlowSubscriberPolicy::Backpressure(Duration::from_secs(1))
+    }
+}
+
+/// How long the writer sleeps between attempts to hand an event to a full subscriber.
+const BACKPRESSURE_POLL: Duration = Duration::from_micros(100);
+
+/// The subscribers of a WAL writer and the events on their way to them: encoded, then
+/// written, then sent once a sync made them durable.
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    subscribers: Vec<Subscriber>,
+    policy: SlowSubscriberPolicy,
+    /// events of the records encoded but not written yet
+    staged: Vec<WalEvent>,
+    /// events of the records written but not synced yet
+    unsynced: Vec<WalEvent>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    events: mpsc::SyncSender<WalEvent>,
+    /// the first sequence number the subscriber gets an event for
+    from_seqno: u64,
+}
+
+impl Subscribers {
+    pub(crate) fn with_policy(mut self, policy: SlowSubscriberPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sends the events of the records from sequence number `from_seqno` on to `events`.
+    pub(crate) fn add(&mut self, events: mpsc::SyncSender<WalEvent>, from_seqno: u64) {
+        self.subscribers.push(Subscriber { events, from_seqno });
+    }
+
+    /// Notes a record encoded for the next write. Records without a sequence number, and any
+    /// record while nobody subscribes, have no event.
+    pub(crate) fn stage(&mut self, seqno: u64, kind: RecordKind, key: &str, value: &[u8]) {
+        if seqno != 0 && self.subscribers.iter().any(|subscriber| seqno >= subscriber.from_seqno) {
+            self.staged.push(WalEvent { seqno, kind, key: key.to_string(), value: value.to_vec() });
+        }
+    }
+
+    /// Notes that the records staged so far were written, or drops their events if the write failed.
+    pub(crate) fn written(&mut self, ok: bool) {
+        if ok {
+            self.unsynced.append(&mut self.staged);
+        } else {
+            self.staged.clear();
+        }
+    }
+
+    /// Drops the events of the records written since the last sync, which did not make them durable.
+    pub(crate) fn discard_unsynced(&mut self) {
+        self.unsynced.clear();
+    }
+
+    /// Sends the events of the records written so far, which a sync just made durable, in order.
+    /// A subscriber that dropped its receiver is removed, and so is a slow one as the policy says.
+    pub(crate) fn synced(&mut self) {
+        let policy = self.policy;
+        for event in self.unsynced.drain(..) {
+            self.subscribers
+                .retain(|subscriber| event.seqno < subscriber.from_seqno || send(subscriber, &event, policy));
+        }
+    }
+}
+
+/// Sends `event` to `subscriber`, returns false if the subscriber is disconnected.
+fn send(subscriber: &Subscriber, event: &WalEvent, policy: SlowSubscriberPolicy) -> bool {
+    let mut event = match subscriber.events.try_send(event.clone()) {
+        Ok(()) => return true,
+        Err(TrySendError::Disconnected(_)) => return false,
+        Err(TrySendError::Full(event)) => event,
+    };
+    let SlowSubscriberPolicy::Backpressure(timeout) = policy else {
+        return false;
+    };
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        thread::sleep(BACKPRESSURE_POLL);
+        event = match subscriber.events.try_send(event) {
+            Ok(()) => return true,
+            Err(TrySendError::Disconnected(_)) => return false,
+            Err(TrySendError::Full(event)) => event,
+        };
+    }
+    false
+}
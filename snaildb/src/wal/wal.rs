@@ -1,14 +1,40 @@
 use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 
+use crate::wal::checkpoint::WalPosition;
 use crate::wal::enums::WriteCommand;
-use crate::wal::{FLUSH_INTERVAL_MS, SyncManager};
+use crate::wal::replay::{self, RecoveryMode, RecoveryReport, ReplayOptions};
+use crate::wal::segment::LogFile;
+use crate::wal::subscribe::Subscribers;
+use crate::wal::{FLUSH_INTERVAL_MS, SyncManager, SyncPolicy};
+
+/// First byte of the key of a record written to a column family other than the default one,
+/// followed by the id of the family, 4 bytes little endian, then the key of the write. Keys are
+/// UTF-8, in which the byte never appears, so builds from before column families reject it.
+const FAMILY_KEY_TAG: u8 = 0xFF;
+
+/// Largest group of records a group commit collects before writing and syncing it.
+const GROUP_COMMIT_MAX_BYTES: usize = 1024 * 1024; // 1 MiB
 use crate::worker::handler::WorkerManager;
 
-use crate::utils::{RecordKind, read_record, encode_batch_records, Value};
+use crate::utils::record::{RecordEncoding, RecordLimits, encode_record_with, encode_write_batch_with};
+use crate::utils::{DecodedRecord, RecordKind, read_record, Value};
+
+/// A write replayed from the WAL by `Wal::recover`.
+#[derive(Clone, Debug)]
+pub enum WalEntry {
+    /// sets the key to a value, a tombstone or merge operands
+    Write(String, Value),
+    /// sets the key to a value that expires at `expires_at`, in milliseconds since the UNIX epoch
+    SetWithTtl { key: String, value: Vec<u8>, expires_at: u64 },
+    /// makes `entry` in the column family `id`, other than the default one, see `Wal::append_to_family`
+    Family { id: u32, entry: Box<WalEntry> },
+    /// deletes the keys from `start`, included, to `end`, excluded
+    RangeDelete { start: String, end: String },
+}
 
 /// WAL (Write-Ahead Log) provides durable write operations.
 /// 
@@ -28,17 +54,9 @@ impl Wal {
     /// This spawns a background worker thread that handles all file I/O operations.
     pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
         let path = path.as_ref().to_path_buf();
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
         
         // Open the file handle - this will be moved into the worker thread
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .append(true) // append mode automatically moves the cursor to end of file, eliminating seek overhead costing write performance everytime we write a record to the file.
-            .open(&path)?;
+        let log = LogFile::single(&path, open_log_file(&path)?, RecordEncoding::default())?;
         
         let wal_path = path.clone();
         let flush_interval = Duration::from_millis(crate::wal::db_sync::FLUSH_INTERVAL_MS);
@@ -46,7 +64,8 @@ impl Wal {
         // Spawn the worker thread using WorkerManager
         let worker = WorkerManager::spawn(
             move |receiver, timeout| {
-                wal_handler(receiver, timeout, file);
+                let sync_manager = SyncManager::new().with_policy(SyncPolicy::Interval(timeout));
+                wal_handler(receiver, log, sync_manager);
             },
             flush_interval,
         );
@@ -59,28 +78,118 @@ impl Wal {
 
     /// Appends a SET record to the WAL.
     pub fn append_set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
-        self.write_record_internal(RecordKind::Set, key, value)
+        self.write_record_internal(RecordKind::Set, key, value, 0, None, 0)
     }
     
+    /// Appends the record setting `key` to `value`: a SET record for raw bytes, so it reads the
+    /// same as with `append_set`, a TYPED record for the other typed values and a DELETE record
+    /// for a tombstone. Merge operands fail with `InvalidInput`, see `append_merge`.
+    pub fn append_value(&mut self, key: &str, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Bytes(bytes) => self.append_set(key, bytes),
+            Value::Deleted => self.append_delete(key),
+            Value::Merge(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "merge operands are appended one at a time with append_merge",
+            )),
+            typed => self.write_record_internal(RecordKind::Typed, key, &typed.encode()?, 0, None, 0),
+        }
+    }
+
     /// Appends a DELETE record (tombstone) to the WAL.
     pub fn append_delete(&mut self, key: &str) -> io::Result<()> {
-        self.write_record_internal(RecordKind::Delete, key, &[])
+        self.write_record_internal(RecordKind::Delete, key, &[], 0, None, 0)
+    }
+
+    /// Appends a MERGE record, an operand for the value of `key`, to the WAL.
+    pub fn append_merge(&mut self, key: &str, operand: &[u8]) -> io::Result<()> {
+        self.write_record_internal(RecordKind::Merge, key, operand, 0, None, 0)
+    }
+
+    /// Appends a RANGE DELETE record, deleting the keys from `start`, included, to `end`,
+    /// excluded, to the WAL.
+    pub fn append_range_delete(&mut self, start: &str, end: &str) -> io::Result<()> {
+        self.write_record_internal(RecordKind::RangeDelete, start, end.as_bytes(), 0, None, 0)
+    }
+
+    /// Appends a record of `kind` with sequence number `seqno`, which `recover_with_seqnos`
+    /// returns with its entry. The value is laid out as for the `append_*` method of the kind:
+    /// the operand of a MERGE record, the end of the range of a RANGE DELETE record.
+    pub fn append_with_seqno(&mut self, kind: RecordKind, key: &str, value: &[u8], seqno: u64) -> io::Result<()> {
+        self.write_record_internal(kind, key, value, seqno, None, 0)
+    }
+
+    /// Appends a SET WITH TTL record with sequence number `seqno`, a value that expires at
+    /// `expires_at`, in milliseconds since the UNIX epoch, see `SnailDb::put_with_ttl`.
+    pub fn append_with_expiry(&mut self, key: &str, value: &[u8], expires_at: u64, seqno: u64) -> io::Result<()> {
+        self.write_record_internal(RecordKind::SetWithTtl, key, value, seqno, Some(expires_at), 0)
+    }
+
+    /// Appends a record of `kind` with sequence number `seqno` like `append_with_seqno`, for a
+    /// write to the column family `family`, which `recover_with_seqnos` returns as a
+    /// `WalEntry::Family`. Family 0, the default one, appends the record as `append_with_seqno`.
+    pub fn append_to_family(
+        &mut self,
+        family: u32,
+        kind: RecordKind,
+        key: &str,
+        value: &[u8],
+        seqno: u64,
+    ) -> io::Result<()> {
+        self.write_record_internal(kind, key, value, seqno, None, family)
+    }
+
+    /// Appends a SET WITH TTL record like `append_with_expiry`, for a write to the column
+    /// family `family`, see `append_to_family`.
+    pub fn append_to_family_with_expiry(
+        &mut self,
+        family: u32,
+        key: &str,
+        value: &[u8],
+        expires_at: u64,
+        seqno: u64,
+    ) -> io::Result<()> {
+        self.write_record_internal(RecordKind::SetWithTtl, key, value, seqno, Some(expires_at), family)
+    }
+
+    /// Appends `records` as one batch, numbered from `first_seqno` on, which recovery replays
+    /// either whole or, if a crash tore it, not at all. Fails with a `RecordError`, appending
+    /// none of them, if a key or value is over the default `RecordLimits`.
+    pub fn append_batch_with_seqno(
+        &mut self,
+        first_seqno: u64,
+        records: Vec<(RecordKind, String, Vec<u8>)>,
+    ) -> io::Result<()> {
+        let limits = RecordLimits::default();
+        for (_, key, value) in &records {
+            limits.check(key.len(), value.len())?;
+        }
+        self.worker
+            .send(WriteCommand::WriteBatch { first_seqno, records })
+            .map_err(|e| io::Error::other(format!("WAL channel error: {}", e)))?;
+        Ok(())
     }
 
     /// Replays all records from the WAL file.
     /// 
-    /// Opens a separate read handle to avoid conflicts with the writer thread.
+    /// Opens a separate read handle to avoid conflicts with the writer thread. Fails with
+    /// `InvalidData` on a range delete, which is not a key and value, on a value with an
+    /// expiry, which a `Value` does not carry, and on a write to a column family, see `recover`.
     pub fn replay(&self) -> io::Result<Vec<(String, Value)>> {
         let mut file = File::open(&self.path)?;
         let mut entries = Vec::new();
         
         while let Some(record) = read_record(&mut file)? {
-            match record.kind {
-                RecordKind::Set => {
-                    entries.push((record.key, Value::from_bytes(record.value)));
+            match decode_entry(record)? {
+                WalEntry::Write(key, value) => entries.push((key, value)),
+                WalEntry::RangeDelete { .. } => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "WAL holds a range delete"));
+                }
+                WalEntry::SetWithTtl { .. } => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "WAL holds a value with an expiry"));
                 }
-                RecordKind::Delete => {
-                    entries.push((record.key, Value::tombstone()));
+                WalEntry::Family { .. } => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "WAL holds a write to a column family"));
                 }
             }
         }
@@ -88,107 +197,501 @@ impl Wal {
         Ok(entries)
     }
 
-    /// Forces an immediate flush and sync of the WAL file.
+    /// Replays the WAL file at `path` before it is opened, treating damaged records as `mode`
+    /// says, and truncates a torn tail so appending to the file afterwards is safe. A missing
+    /// file replays nothing. The records before the checkpoint, see `checkpoint`, are skipped.
+    pub fn recover(path: impl AsRef<Path>, mode: RecoveryMode) -> io::Result<(Vec<WalEntry>, RecoveryReport)> {
+        let (entries, report) = Self::recover_with_seqnos(path, mode)?;
+        Ok((entries.into_iter().map(|(_, entry)| entry).collect(), report))
+    }
+
+    /// Replays the WAL file at `path` like `recover`, with the sequence number of each entry,
+    /// 0 for a record appended without one.
+    pub fn recover_with_seqnos(
+        path: impl AsRef<Path>,
+        mode: RecoveryMode,
+    ) -> io::Result<(Vec<(u64, WalEntry)>, RecoveryReport)> {
+        Self::replay_entries(path.as_ref(), mode, true)
+    }
+
+    /// Replays the WAL file at `path` like `recover_with_seqnos`, leaving the file as it is, so
+    /// it can be read while a writer appends to it: a torn tail, as a record being appended
+    /// leaves, ends the replay. See `SnailDb::open_read_only`.
+    pub fn read_with_seqnos(
+        path: impl AsRef<Path>,
+        mode: RecoveryMode,
+    ) -> io::Result<(Vec<(u64, WalEntry)>, RecoveryReport)> {
+        Self::replay_entries(path.as_ref(), mode, false)
+    }
+
+    fn replay_entries(
+        path: &Path,
+        mode: RecoveryMode,
+        truncate_torn_tail: bool,
+    ) -> io::Result<(Vec<(u64, WalEntry)>, RecoveryReport)> {
+        if !path.exists() {
+            return Ok((Vec::new(), RecoveryReport::default()));
+        }
+        let options = ReplayOptions::default()
+            .with_truncate_torn_tail(truncate_torn_tail)
+            .with_recovery_mode(mode)
+            .with_from_checkpoint(true);
+        let mut replay = replay::replay_with_options(path, &options)?;
+        let mut entries = Vec::new();
+        for record in replay.by_ref() {
+            let record = record?;
+            entries.push((record.seqno, decode_entry(record)?));
+        }
+        Ok((entries, replay.report().clone()))
+    }
+
+    /// Forces an immediate flush and sync of the WAL file, and waits for it.
     /// 
-    /// This is useful for critical operations that require durability guarantees.
+    /// This is useful for critical operations that require durability guarantees. Returns the
+    /// error of the flush, or of a write that failed in the background since the last flush.
     pub fn force_flush(&self) -> io::Result<()> {
+        let (done, flushed) = mpsc::channel();
         self.worker
-            .send(WriteCommand::Flush)
-            .map_err(|e| io::Error::new(
-                io::ErrorKind::Other,
-                format!("WAL force_flush error: {}", e)
-            ))?;
-        Ok(())
+            .send(WriteCommand::Flush { done })
+            .map_err(|e| io::Error::other(format!("WAL force_flush error: {}", e)))?;
+        flushed
+            .recv()
+            .map_err(|e| io::Error::other(format!("WAL force_flush error: {}", e)))?
     }
 
-    /// Resets the WAL file (truncates to zero length).
+    /// Returns the position at the end of the records appended so far, see `checkpoint`.
+    pub fn position(&self) -> io::Result<WalPosition> {
+        let (done, position) = mpsc::channel();
+        self.worker
+            .send(WriteCommand::Position(done))
+            .map_err(|e| io::Error::other(format!("WAL position error: {}", e)))?;
+        position
+            .recv()
+            .map_err(|e| io::Error::other(format!("WAL position error: {}", e)))?
+    }
+
+    /// Records that the records before `position` are persisted elsewhere, say by a memtable
+    /// flush, and waits until they are dropped from the log, see `WalHandle::checkpoint`.
+    /// `recover` starts at the checkpoint.
+    pub fn checkpoint(&self, position: WalPosition) -> io::Result<()> {
+        let (done, checkpointed) = mpsc::channel();
+        self.worker
+            .send(WriteCommand::Checkpoint { position, done })
+            .map_err(|e| io::Error::other(format!("WAL checkpoint error: {}", e)))?;
+        checkpointed
+            .recv()
+            .map_err(|e| io::Error::other(format!("WAL checkpoint error: {}", e)))?
+    }
+
+    /// Stops the writer thread once the records sent so far are written and synced, and waits
+    /// for it. Returns the error of the flush, or of a write that failed in the background,
+    /// like `force_flush`. Dropping the WAL does the same and ignores the error.
+    pub fn close(self) -> io::Result<()> {
+        let (done, stopped) = mpsc::channel();
+        self.worker
+            .send(WriteCommand::Shutdown { done })
+            .map_err(|e| io::Error::other(format!("WAL close error: {}", e)))?;
+        stopped
+            .recv()
+            .map_err(|e| io::Error::other(format!("WAL close error: {}", e)))?
+    }
+
+    /// Resets the WAL file (replaces it by an empty one).
     /// 
     /// This is typically called after flushing the memtable to SSTable.
     pub fn reset(&mut self) -> io::Result<()> {
         self.worker
-            .send(WriteCommand::Reset)
-            .map_err(|e| io::Error::new(
-                io::ErrorKind::Other,
-                format!("WAL reset error: {}", e)
-            ))?;
+            .send(WriteCommand::Reset { up_to: None })
+            .map_err(|e| io::Error::other(format!("WAL reset error: {}", e)))?;
         Ok(())
     }
 
-    /// Writes a record to the WAL file, internal function.
+    /// Writes a record with sequence number `seqno`, 0 for none, the expiry of a `SetWithTtl`
+    /// record and the column family of the write to the WAL file, internal function. Fails with
+    /// a `RecordError` if the key or value is over the default `RecordLimits`.
     fn write_record_internal(
         &mut self,
         kind: RecordKind,
         key: &str,
         value: &[u8],
+        seqno: u64,
+        expires_at: Option<u64>,
+        family: u32,
     ) -> io::Result<()> {
+        RecordLimits::default().check(key.len(), value.len())?;
         self.worker
             .send(WriteCommand::WriteRecord {
                 kind,
                 key: key.to_string(),
                 value: value.to_vec(),
+                seqno,
+                expires_at,
+                family,
             })
-            .map_err(|e| io::Error::new(
-                io::ErrorKind::Other,
-                format!("WAL channel error: {}", e)
-            ))?;
+            .map_err(|e| io::Error::other(format!("WAL channel error: {}", e)))?;
         Ok(())
     }
 }
 
-/// Writes the batch buffer to file if it's not empty, marks dirty, and clears it.
-fn write_batch_if_needed(
-    file: &mut File,
-    sync_manager: &mut SyncManager,
-    batch_buffer: &mut Vec<u8>,
-) {
-    if !batch_buffer.is_empty() {
-        if let Err(e) = file.write_all(batch_buffer) {
-            eprintln!("WAL write error: {}", e);
-        } else {
-            sync_manager.mark_dirty();
-        }
-        batch_buffer.clear();
+/// Turns a record of the WAL into the write it makes.
+fn decode_entry(mut record: DecodedRecord) -> io::Result<WalEntry> {
+    if record.key.first() == Some(&FAMILY_KEY_TAG) && record.key.len() >= 5 {
+        let id = u32::from_le_bytes(record.key[1..5].try_into().expect("4 bytes"));
+        record.key.drain(..5);
+        return Ok(WalEntry::Family { id, entry: Box::new(decode_entry(record)?) });
+    }
+    let utf8 = |bytes| {
+        String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "WAL key is not valid UTF-8"))
+    };
+    let key = utf8(record.key)?;
+    match record.kind {
+        RecordKind::Set => Ok(WalEntry::Write(key, Value::from_bytes(record.value))),
+        RecordKind::Delete => Ok(WalEntry::Write(key, Value::tombstone())),
+        RecordKind::Merge => Ok(WalEntry::Write(key, Value::merge_operand(record.value))),
+        RecordKind::Typed => Ok(WalEntry::Write(key, Value::decode(&record.value)?)),
+        RecordKind::RangeDelete => Ok(WalEntry::RangeDelete { start: key, end: utf8(record.value)? }),
+        RecordKind::SetWithTtl => match record.expires_at {
+            Some(expires_at) => Ok(WalEntry::SetWithTtl { key, value: record.value, expires_at }),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "WAL record with a TTL has no expiry")),
+        },
     }
 }
 
-/// Handles a flush command: writes any pending batch and flushes to disk.
-fn handle_flush(
-    file: &mut File,
+/// Opens the log file for appending, creating it and its parent directories if they don't exist.
+pub(crate) fn open_log_file(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true) // append mode automatically moves the cursor to end of file, eliminating seek overhead costing write performance everytime we write a record to the file.
+        .open(path)
+}
+
+/// Writes the batch buffer holding `records` records to file if it's not empty, marks them
+/// written and counts them, and clears it, returning the outcome of the write.
+fn write_pending(
+    log: &mut LogFile,
     sync_manager: &mut SyncManager,
     batch_buffer: &mut Vec<u8>,
-) {
-    write_batch_if_needed(file, sync_manager, batch_buffer);
-    if let Err(e) = sync_manager.flush_if_pending_file(file) {
-        eprintln!("WAL flush error: {}", e);
+    records: u64,
+) -> io::Result<()> {
+    if batch_buffer.is_empty() {
+        return Ok(());
     }
+    let written = log.write_batch(batch_buffer);
+    sync_manager.subscribers().written(written.is_ok());
+    if let Ok(bytes) = written {
+        sync_manager.mark_written(records);
+        sync_manager.metrics().record_append(records, bytes);
+        update_log_size(log, sync_manager);
+    }
+    batch_buffer.clear();
+    written.map(|_| ())
 }
 
-/// Handles a reset command: writes batch, flushes, truncates file, and clears state.
+/// Notes the size of the log in the metrics, after records are written or removed.
+fn update_log_size(log: &LogFile, sync_manager: &SyncManager) {
+    match log.size() {
+        Ok(size) => sync_manager.metrics().set_log_size(size),
+        Err(e) => eprintln!("WAL size error: {}", e),
+    }
+}
+
+/// Handles a reset command: flushes, replaces the file by one holding the records above
+/// `up_to`, and clears state. A segmented log also loses its older segments.
 fn handle_reset(
-    file: &mut File,
+    log: &mut LogFile,
     sync_manager: &mut SyncManager,
-    batch_buffer: &mut Vec<u8>,
+    up_to: Option<u64>,
 ) {
-    write_batch_if_needed(file, sync_manager, batch_buffer);
-    
     // Flush before reset to ensure all data is persisted
-    if let Err(e) = sync_manager.flush_if_pending_file(file) {
+    if let Err(e) = sync_manager.flush_if_pending_file(log.file_mut()) {
         eprintln!("WAL flush error: {}", e);
+        sync_manager.record_error(e);
     }
     
-    // Reset the file (truncate to zero)
-    if let Err(e) = file.set_len(0) {
+    // Reset the file (replace it by an empty one)
+    if let Err(e) = log.reset(up_to) {
         eprintln!("WAL reset error: {}", e);
-    }
-    if let Err(e) = file.sync_all() {
-        eprintln!("WAL sync error: {}", e);
-    }
-    if let Err(e) = file.seek(SeekFrom::Start(0)) {
-        eprintln!("WAL seek error: {}", e);
+        sync_manager.record_error(e);
     }
     
     // Clear pending state after reset since file is empty
     sync_manager.clear_pending();
+    update_log_size(log, sync_manager);
+}
+
+/// Handles a command asking for the end of the log: sends back its position.
+fn handle_position(log: &mut LogFile, done: mpsc::Sender<io::Result<WalPosition>>) {
+    // The caller may have stopped waiting, which is fine
+    let _ = done.send(log.position());
+}
+
+/// Handles a checkpoint: syncs what was written, then records the checkpoint and drops the
+/// records before it, reporting the outcome.
+fn handle_checkpoint(
+    log: &mut LogFile,
+    sync_manager: &mut SyncManager,
+    position: WalPosition,
+    done: mpsc::Sender<io::Result<()>>,
+) {
+    let result = sync_manager
+        .flush_if_pending_file(log.file_mut())
+        .and_then(|()| log.checkpoint(position));
+    if let Err(e) = &result {
+        eprintln!("WAL checkpoint error: {}", e);
+    }
+    update_log_size(log, sync_manager);
+    let _ = done.send(result);
+}
+
+/// Handles a command deleting old segments: removes the segments below `seq`.
+fn handle_delete_segments(log: &mut LogFile, sync_manager: &mut SyncManager, seq: u64) {
+    if let Err(e) = log.delete_segments_before(seq) {
+        eprintln!("WAL segment removal error: {}", e);
+        sync_manager.record_error(e);
+    }
+    update_log_size(log, sync_manager);
+}
+
+/// Handles a shutdown: syncs everything written, then reports the outcome, or the error of a
+/// write that failed in the background since the last flush.
+fn handle_shutdown(
+    log: &mut LogFile,
+    sync_manager: &mut SyncManager,
+    done: Option<mpsc::Sender<io::Result<()>>>,
+) {
+    // Force flush on shutdown to ensure all data is persisted
+    let mut result = sync_manager.force_flush(log.file_mut());
+    if let Err(e) = &result {
+        eprintln!("WAL flush error: {}", e);
+    }
+    if let Some(e) = sync_manager.take_error() {
+        result = Err(e);
+    }
+    if let Some(done) = done {
+        // The caller may have stopped waiting, which is fine
+        let _ = done.send(result);
+    }
+}
+
+/// Encodes the record of a `WriteRecord` command, noting its sequence number in the log and its
+/// event for the subscribers.
+#[allow(clippy::too_many_arguments)]
+fn encode_record(
+    buffer: &mut Vec<u8>,
+    log: &mut LogFile,
+    subscribers: &mut Subscribers,
+    kind: RecordKind,
+    key: &str,
+    value: &[u8],
+    seqno: u64,
+    expires_at: Option<u64>,
+    family: u32,
+) -> io::Result<()> {
+    let mut tagged = Vec::new();
+    let key_bytes = if family == 0 {
+        key.as_bytes()
+    } else {
+        tagged.push(FAMILY_KEY_TAG);
+        tagged.extend_from_slice(&family.to_le_bytes());
+        tagged.extend_from_slice(key.as_bytes());
+        &tagged
+    };
+    encode_record_with(buffer, kind, key_bytes, value, expires_at, seqno, log.encoding())?;
+    log.advance_seqno(seqno);
+    subscribers.stage(seqno, kind, key, value);
+    Ok(())
+}
+
+/// Encodes the records of a `WriteBatch` command as one batch, see `encode_write_batch_with`,
+/// noting their sequence numbers in the log and their events for the subscribers.
+fn encode_records_as_batch(
+    buffer: &mut Vec<u8>,
+    log: &mut LogFile,
+    subscribers: &mut Subscribers,
+    first_seqno: u64,
+    records: &[(RecordKind, String, Vec<u8>)],
+) -> io::Result<()> {
+    encode_write_batch_with(
+        buffer,
+        first_seqno,
+        log.encoding(),
+        records.iter().map(|(kind, key, value)| (*kind, key.as_bytes(), value.as_slice())),
+    )?;
+    if first_seqno != 0 && !records.is_empty() {
+        log.advance_seqno(first_seqno + records.len() as u64 - 1);
+        for (seqno, (kind, key, value)) in (first_seqno..).zip(records) {
+            subscribers.stage(seqno, *kind, key, value);
+        }
+    }
+    Ok(())
+}
+
+/// The flushes and syncs requested while a group of writes is collected, see `drain_writes`.
+#[derive(Default)]
+struct GroupCommit {
+    /// number of records in the group
+    records: u64,
+    /// whether the group is synced once written
+    sync: bool,
+    /// the callers waiting for the group to be durable
+    acks: Vec<mpsc::Sender<io::Result<()>>>,
+}
+
+/// Appends the writes queued right after the one encoded into `batch_buffer`, so they reach the
+/// file in one syscall, and executes the first other command found.
+///
+/// This is the group commit: the `Flush` commands found along the way are collected
+/// instead of ending the batch, and once nothing more is queued the whole group is written and
+/// synced once, then every `Flush` is acknowledged. A `Sync` ends the group instead, so the
+/// writes queued behind it go to the next one. The group is closed after
+/// `FLUSH_INTERVAL_MS` or `GROUP_COMMIT_MAX_BYTES` of records, so a long backlog does not hold
+/// back the first waiters, and when it reaches a record the `SyncPolicy` syncs after. Without
+/// a `Flush` the group is synced only if the policy says so. Returns false if a
+/// command stops the handler.
+fn drain_writes(
+    receiver: &mpsc::Receiver<WriteCommand>,
+    log: &mut LogFile,
+    sync_manager: &mut SyncManager,
+    batch_buffer: &mut Vec<u8>,
+    mut group: GroupCommit,
+) -> bool {
+    let batch_start_time = Instant::now();
+    
+    // Try to drain more commands (non-blocking)
+    loop {
+        // Close the group once the flush interval has elapsed since batch start or it is large
+        if batch_start_time.elapsed() >= Duration::from_millis(FLUSH_INTERVAL_MS)
+            || batch_buffer.len() >= GROUP_COMMIT_MAX_BYTES
+            || sync_manager.group_full(group.records)
+        {
+            break;
+        }
+        
+        let command = receiver.try_recv();
+        if command.is_ok() {
+            sync_manager.metrics().dequeue();
+        }
+        match command {
+            Ok(WriteCommand::WriteRecord { kind, key, value, seqno, expires_at, family }) => {
+                // Encode this record into the batch buffer
+                let subscribers = sync_manager.subscribers();
+                let encoded =
+                    encode_record(batch_buffer, log, subscribers, kind, &key, &value, seqno, expires_at, family);
+                if let Err(e) = encoded {
+                    eprintln!("WAL encode error: {}", e);
+                    break; // Write what we have so far
+                }
+                group.records += 1;
+            }
+            Ok(WriteCommand::WriteBatch { first_seqno, records }) => {
+                // Encode the whole batch, or nothing of it
+                let subscribers = sync_manager.subscribers();
+                if let Err(e) = encode_records_as_batch(batch_buffer, log, subscribers, first_seqno, &records) {
+                    eprintln!("WAL encode error: {}", e);
+                    break; // Write what we have so far
+                }
+                group.records += records.len() as u64;
+            }
+            Ok(WriteCommand::Flush { done }) => {
+                // Synced with the rest of the group
+                group.sync = true;
+                group.acks.push(done);
+            }
+            Ok(WriteCommand::Sync { done }) => {
+                // The group ends at the barrier, durable before the writes behind it
+                group.sync = true;
+                group.acks.push(done);
+                commit_group(log, sync_manager, batch_buffer, &mut group);
+                return true;
+            }
+            Ok(WriteCommand::Subscribe { events, from_seqno }) => {
+                // Gets the records after those of the group, which were sequenced before it subscribed
+                sync_manager.subscribers().add(events, from_seqno);
+            }
+            Ok(WriteCommand::Reset { up_to }) => {
+                commit_group(log, sync_manager, batch_buffer, &mut group);
+                handle_reset(log, sync_manager, up_to);
+                return true;
+            }
+            Ok(WriteCommand::DeleteSegmentsBefore(seq)) => {
+                commit_group(log, sync_manager, batch_buffer, &mut group);
+                handle_delete_segments(log, sync_manager, seq);
+                return true;
+            }
+            Ok(WriteCommand::Position(done)) => {
+                commit_group(log, sync_manager, batch_buffer, &mut group);
+                handle_position(log, done);
+                return true;
+            }
+            Ok(WriteCommand::Checkpoint { position, done }) => {
+                commit_group(log, sync_manager, batch_buffer, &mut group);
+                handle_checkpoint(log, sync_manager, position, done);
+                return true;
+            }
+            Ok(WriteCommand::Shutdown { done }) => {
+                // Write the group, then sync and exit
+                commit_group(log, sync_manager, batch_buffer, &mut group);
+                handle_shutdown(log, sync_manager, Some(done));
+                return false; // Exit the handler loop
+            }
+            Err(mpsc::TryRecvError::Disconnected) => {
+                // Channel closed, write the group and exit
+                commit_group(log, sync_manager, batch_buffer, &mut group);
+                handle_shutdown(log, sync_manager, None);
+                return false; // Exit the handler loop
+            }
+            Err(mpsc::TryRecvError::Empty) => {
+                // No more commands available, exit batching loop
+                break;
+            }
+        }
+    }
+    
+    // Write the entire batch in ONE syscall and sync it once for the whole group
+    commit_group(log, sync_manager, batch_buffer, &mut group);
+    true
+}
+
+/// Writes the batch buffer and, if the group or the sync policy asks for it, syncs the file,
+/// then reports the outcome to every `Flush` of the group. Without a `Flush` to report it to,
+/// an error is kept for the next one.
+fn commit_group(
+    log: &mut LogFile,
+    sync_manager: &mut SyncManager,
+    batch_buffer: &mut Vec<u8>,
+    group: &mut GroupCommit,
+) {
+    let mut result = write_pending(log, sync_manager, batch_buffer, group.records);
+    if group.sync || sync_manager.sync_due() {
+        result = result.and_then(|()| sync_manager.flush_if_pending_file(log.file_mut()));
+    }
+    if let Err(e) = &result {
+        eprintln!("WAL write error: {}", e);
+    }
+    if group.acks.is_empty() {
+        if let Err(e) = result {
+            sync_manager.record_error(e);
+        }
+        group.records = 0;
+        group.sync = false;
+        return;
+    }
+    if let Some(e) = sync_manager.take_error() {
+        result = Err(e);
+    }
+    for ack in group.acks.drain(..) {
+        // The caller may have stopped waiting, which is fine
+        let _ = ack.send(match &result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        });
+    }
+    group.records = 0;
+    group.sync = false;
 }
 
 /// The worker thread handler that processes WAL commands.
@@ -197,114 +700,117 @@ fn handle_reset(
 /// - Writing records to the WAL file
 /// - Flushing and syncing for durability
 /// - Resetting the file when needed
-/// - Periodic automatic flushes
-fn wal_handler(
+/// - Automatic syncs following the `SyncPolicy` of the sync manager, on a timer for
+///   an `Interval` policy
+pub(crate) fn wal_handler(
     receiver: mpsc::Receiver<WriteCommand>,
-    timeout: Duration,
-    mut file: File,
+    mut log: LogFile,
+    mut sync_manager: SyncManager,
 ) {
-    let mut sync_manager = SyncManager::new();
     let mut batch_buffer = Vec::with_capacity(8192);
 
     loop {
-        match receiver.recv_timeout(timeout) {
-            Ok(WriteCommand::WriteRecord { kind, key, value }) => {
+        let command = match sync_manager.flush_interval() {
+            Some(interval) => receiver.recv_timeout(interval),
+            // No timer, wait for the next command
+            None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        if command.is_ok() {
+            sync_manager.metrics().dequeue();
+        }
+        match command {
+            Ok(WriteCommand::WriteRecord { kind, key, value, seqno, expires_at, family }) => {
                 // Batch writes to avoid syscall overhead.
                 // Clear buffer but keep capacity to avoid reallocations
                 batch_buffer.clear();
 
                 // Encode first record into buffer
-                if let Err(e) = encode_batch_records(&mut batch_buffer, kind, &key, &value) {
+                let subscribers = sync_manager.subscribers();
+                let encoded = encode_record(
+                    &mut batch_buffer,
+                    &mut log,
+                    subscribers,
+                    kind,
+                    &key,
+                    &value,
+                    seqno,
+                    expires_at,
+                    family,
+                );
+                if let Err(e) = encoded {
                     eprintln!("WAL encode error: {}", e);
                     continue;
                 }
-                
-                let mut should_write_batch = true;
-                let batch_start_time = Instant::now();
-                
-                // Try to drain more WriteRecord commands (non-blocking)
-                loop {
-                    // Check if flush interval has elapsed since batch start
-                    if batch_start_time.elapsed() >= Duration::from_millis(FLUSH_INTERVAL_MS) {
-                        break;
-                    }
-                    
-                    match receiver.try_recv() {
-                        Ok(WriteCommand::WriteRecord { kind, key, value }) => {
-                            // Encode this record into the batch buffer
-                            if let Err(e) = encode_batch_records(&mut batch_buffer, kind, &key, &value) {
-                                eprintln!("WAL encode error: {}", e);
-                                break; // Write what we have so far
-                            }
-                        }
-                        Ok(WriteCommand::Flush) => {
-                            handle_flush(&mut file, &mut sync_manager, &mut batch_buffer);
-                            should_write_batch = false; // Already wrote and flushed
-                            break;
-                        }
-                        Ok(WriteCommand::Reset) => {
-                            handle_reset(&mut file, &mut sync_manager, &mut batch_buffer);
-                            should_write_batch = false; // Already handled reset
-                            break;
-                        }
-                        Ok(WriteCommand::Shutdown) => {
-                            write_batch_if_needed(&mut file, &mut sync_manager, &mut batch_buffer);
-                            // Force flush on shutdown
-                            if let Err(e) = sync_manager.force_flush(&mut file) {
-                                eprintln!("WAL flush error: {}", e);
-                            }
-                            return; // Exit the handler loop
-                        }
-                        Err(mpsc::TryRecvError::Empty) => {
-                            // No more commands available, exit batching loop
-                            break;
-                        }
-                        Err(mpsc::TryRecvError::Disconnected) => {
-                            // Channel closed, write batch and exit
-                            write_batch_if_needed(&mut file, &mut sync_manager, &mut batch_buffer);
-                            if let Err(e) = sync_manager.force_flush(&mut file) {
-                                eprintln!("WAL flush error: {}", e);
-                            }
-                            return; // Exit the handler loop
-                        }
-                    }
+                let group = GroupCommit { records: 1, ..GroupCommit::default() };
+                if !drain_writes(&receiver, &mut log, &mut sync_manager, &mut batch_buffer, group) {
+                    return;
                 }
-                
-                // Write the entire batch in ONE syscall (if not already written)
-                if should_write_batch {
-                    write_batch_if_needed(&mut file, &mut sync_manager, &mut batch_buffer);
+            }
+
+            Ok(WriteCommand::WriteBatch { first_seqno, records }) => {
+                batch_buffer.clear();
+                let subscribers = sync_manager.subscribers();
+                let encoded = encode_records_as_batch(&mut batch_buffer, &mut log, subscribers, first_seqno, &records);
+                if let Err(e) = encoded {
+                    eprintln!("WAL encode error: {}", e);
+                    continue;
+                }
+                let group = GroupCommit { records: records.len() as u64, ..GroupCommit::default() };
+                if !drain_writes(&receiver, &mut log, &mut sync_manager, &mut batch_buffer, group) {
+                    return;
                 }
             }
             
-            Ok(WriteCommand::Flush) => {
-                handle_flush(&mut file, &mut sync_manager, &mut batch_buffer);
+            Ok(WriteCommand::Flush { done }) => {
+                // Collect the writes queued behind the flush into the same sync
+                let group = GroupCommit { records: 0, sync: true, acks: vec![done] };
+                if !drain_writes(&receiver, &mut log, &mut sync_manager, &mut batch_buffer, group) {
+                    return;
+                }
             }
             
-            Ok(WriteCommand::Reset) => {
-                handle_reset(&mut file, &mut sync_manager, &mut batch_buffer);
+            Ok(WriteCommand::Sync { done }) => {
+                // Nothing is pending but what earlier groups left unsynced
+                let mut group = GroupCommit { records: 0, sync: true, acks: vec![done] };
+                commit_group(&mut log, &mut sync_manager, &mut batch_buffer, &mut group);
+            }
+
+            Ok(WriteCommand::Reset { up_to }) => {
+                handle_reset(&mut log, &mut sync_manager, up_to);
+            }
+
+            Ok(WriteCommand::Subscribe { events, from_seqno }) => {
+                sync_manager.subscribers().add(events, from_seqno);
+            }
+
+            Ok(WriteCommand::DeleteSegmentsBefore(seq)) => {
+                handle_delete_segments(&mut log, &mut sync_manager, seq);
+            }
+
+            Ok(WriteCommand::Position(done)) => {
+                handle_position(&mut log, done);
+            }
+
+            Ok(WriteCommand::Checkpoint { position, done }) => {
+                handle_checkpoint(&mut log, &mut sync_manager, position, done);
             }
             
-            Ok(WriteCommand::Shutdown) => {
-                write_batch_if_needed(&mut file, &mut sync_manager, &mut batch_buffer);
-                // Force flush on shutdown to ensure all data is persisted
-                if let Err(e) = sync_manager.force_flush(&mut file) {
-                    eprintln!("WAL flush error: {}", e);
-                }
+            Ok(WriteCommand::Shutdown { done }) => {
+                handle_shutdown(&mut log, &mut sync_manager, Some(done));
                 break;
             }
             
             Err(mpsc::RecvTimeoutError::Timeout) => {
-                // Periodic flush interval reached - flush if there are pending writes
-                if let Err(e) = sync_manager.flush_if_pending_file(&mut file) {
+                // Sync interval reached - flush if there are pending writes
+                if let Err(e) = sync_manager.flush_if_pending_file(log.file_mut()) {
                     eprintln!("WAL flush error: {}", e);
+                    sync_manager.record_error(e);
                 }
             }
             
             Err(mpsc::RecvTimeoutError::Disconnected) => {
                 // Channel closed (sender dropped), flush and exit
-                if let Err(e) = sync_manager.force_flush(&mut file) {
-                    eprintln!("WAL flush error: {}", e);
-                }
+                handle_shutdown(&mut log, &mut sync_manager, None);
                 break;
             }
         }
@@ -312,9 +818,13 @@ fn wal_handler(
 }
 
 impl Drop for Wal {
+    /// Shuts the writer down like `close`, waiting for the records to be synced, so the file
+    /// can be opened again as soon as the WAL is dropped.
     fn drop(&mut self) {
-        // Send shutdown command to ensure clean exit
-        // Ignore errors since we're dropping anyway
-        let _ = self.worker.send(WriteCommand::Shutdown);
+        // Ignore errors since we're dropping anyway, see `close` for the outcome
+        let (done, stopped) = mpsc::channel();
+        if self.worker.send(WriteCommand::Shutdown { done }).is_ok() {
+            let _ = stopped.recv();
+        }
     }
 }
@@ -0,0 +1,450 @@
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::thread;
+
+use crate::storage::sstable::Compression;
+use crate::utils::record::{FormatVersion, RecordEncoding, RecordLimits, ValueCompression};
+use crate::utils::{EncryptionKey, RecordKind};
+use crate::wal::checkpoint::WalPosition;
+use crate::wal::enums::WriteCommand;
+use crate::wal::metrics::{WalMetrics, WalMetricsSnapshot};
+use crate::wal::replay::{self, RecoveryMode, ReplayOptions, WalReplay};
+use crate::wal::segment::{LogFile, SegmentOptions};
+use crate::wal::subscribe::{SlowSubscriberPolicy, WalEvent};
+use crate::wal::db_sync::{FileSync, LogSync};
+use crate::wal::{SyncManager, SyncPolicy};
+use crate::wal::wal::{open_log_file, wal_handler};
+
+/// Events a subscriber can have waiting by default, see `WalWriterOptions::subscriber_capacity`.
+const DEFAULT_SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Options of a `WalWriter`, see `WalWriter::open_with_options`.
+#[derive(Clone, Debug)]
+pub struct WalWriterOptions {
+    /// Performs the syncs of the log, `FileSync` by default.
+    pub syncer: Arc<dyn LogSync>,
+    /// When the writer syncs the log besides `Flush` and `Shutdown`, see `SyncPolicy`.
+    pub sync_policy: SyncPolicy,
+    /// How the replay that recovers the last sequence number on open treats damaged records,
+    /// see `RecoveryMode`. The log is not changed either way.
+    pub recovery_mode: RecoveryMode,
+    /// Values longer than this many bytes are compressed in the log, `None`, the default,
+    /// compresses nothing. Smaller values are not worth the time. Needs the feature of the
+    /// codec, and so does reading the log back.
+    pub compression_threshold: Option<usize>,
+    /// The codec values over the compression threshold are compressed with, lz4 by default.
+    pub compression_codec: Compression,
+    /// Encrypts every record with AES-256-GCM under this key, `None` by default. The log can
+    /// only be read back with the same key, see `ReplayOptions::encryption_key`. Needs the
+    /// `encryption` feature.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Events a subscriber can have waiting before it counts as slow, see `WalHandle::subscribe`.
+    /// 1024 by default.
+    pub subscriber_capacity: usize,
+    /// What happens to a slow subscriber, see `SlowSubscriberPolicy`.
+    pub slow_subscriber_policy: SlowSubscriberPolicy,
+    /// The longest keys and values the writer takes, see `RecordLimits`. A longer one fails its
+    /// write with a `RecordError`. Reading the log back needs the same limits, see
+    /// `ReplayOptions::record_limits`.
+    pub record_limits: RecordLimits,
+    /// The format of the files the writer starts, `FormatVersion::LATEST` by default. Files
+    /// written before keep their format, as the records are the same in every version.
+    pub format_version: FormatVersion,
+}
+
+impl WalWriterOptions {
+    /// Syncs the log with `syncer`.
+    pub fn with_syncer(mut self, syncer: Arc<dyn LogSync>) -> Self {
+        self.syncer = syncer;
+        self
+    }
+
+    /// Sets when the writer syncs the log on its own.
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets how damaged records are treated when the log is opened.
+    pub fn with_recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    /// Compresses the values longer than `bytes` bytes.
+    pub fn with_compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = Some(bytes);
+        self
+    }
+
+    /// Compresses values over the compression threshold with `codec`.
+    pub fn with_compression_codec(mut self, codec: Compression) -> Self {
+        self.compression_codec = codec;
+        self
+    }
+
+    /// Encrypts the records with `key`.
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Lets every subscriber have up to `capacity` events waiting.
+    pub fn with_subscriber_capacity(mut self, capacity: usize) -> Self {
+        self.subscriber_capacity = capacity;
+        self
+    }
+
+    /// Sets what happens to a subscriber that does not keep up.
+    pub fn with_slow_subscriber_policy(mut self, policy: SlowSubscriberPolicy) -> Self {
+        self.slow_subscriber_policy = policy;
+        self
+    }
+
+    /// Takes keys and values up to `limits`.
+    pub fn with_record_limits(mut self, limits: RecordLimits) -> Self {
+        self.record_limits = limits;
+        self
+    }
+
+    /// Starts files in format `version`, say for readers built before it.
+    pub fn with_format_version(mut self, version: FormatVersion) -> Self {
+        self.format_version = version;
+        self
+    }
+
+    /// Returns how the writer encodes records, failing if this build lacks a feature it needs.
+    fn record_encoding(&self) -> io::Result<RecordEncoding> {
+        let codec = self.compression_codec;
+        let compression = self.compression_threshold.map(|threshold| ValueCompression::new(codec, threshold));
+        if compression.is_some() && !codec.is_available() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("WAL compression needs snaildb built with the `{}` feature", codec.name()),
+            ));
+        }
+        if self.encryption_key.is_some() && !EncryptionKey::is_available() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WAL encryption needs snaildb built with the `encryption` feature",
+            ));
+        }
+        Ok(RecordEncoding {
+            compression,
+            encryption_key: self.encryption_key.clone(),
+            limits: self.record_limits,
+            format_version: self.format_version,
+        })
+    }
+
+    /// Returns the options of the replay recovering the last sequence number on open.
+    fn replay_options(&self) -> ReplayOptions {
+        let options = ReplayOptions::default().with_recovery_mode(self.recovery_mode);
+        ReplayOptions { encryption_key: self.encryption_key.clone(), record_limits: self.record_limits, ..options }
+    }
+}
+
+impl Default for WalWriterOptions {
+    fn default() -> Self {
+        Self {
+            syncer: Arc::new(FileSync),
+            sync_policy: SyncPolicy::default(),
+            recovery_mode: RecoveryMode::default(),
+            compression_threshold: None,
+            compression_codec: Compression::Lz4,
+            encryption_key: None,
+            subscriber_capacity: DEFAULT_SUBSCRIBER_CAPACITY,
+            slow_subscriber_policy: SlowSubscriberPolicy::default(),
+            record_limits: RecordLimits::default(),
+            format_version: FormatVersion::LATEST,
+        }
+    }
+}
+
+/// Owns the log file on a dedicated thread that executes the `WriteCommand`s sent through
+/// its `WalHandle`s.
+///
+/// Commands are executed in the order they are received: records are appended in batches,
+/// `Flush` writes and syncs them, `Reset` empties the log and `Shutdown` writes and syncs
+/// everything queued before it, then stops the thread. Dropping the last handle stops the
+/// thread the same way. A write failing in the background is reported by the next `flush`
+/// or `shutdown`.
+///
+/// Every record gets a sequence number, strictly increasing in the order the records are
+/// appended across all handles. Opening a log continues after the largest number found in
+/// it, so numbers never repeat; an emptied log keeps the last one in a marker for that.
+#[derive(Debug)]
+pub struct WalWriter {
+    /// The path to the WAL file, or to the directory of a segmented WAL.
+    path: PathBuf,
+    /// The writer thread, joined by `join`.
+    thread: thread::JoinHandle<()>,
+}
+
+/// A cheap, cloneable handle sending commands to a `WalWriter`.
+#[derive(Clone, Debug)]
+pub struct WalHandle {
+    sender: mpsc::Sender<WriteCommand>,
+    /// the last sequence number given out, shared by every handle of the writer and held
+    /// while a write is sent so the records reach the writer in sequence number order
+    last_seqno: Arc<Mutex<u64>>,
+    metrics: Arc<WalMetrics>,
+    subscriber_capacity: usize,
+    /// checked before a record is queued, so an oversized one fails its write rather than a flush
+    record_limits: RecordLimits,
+}
+
+impl WalWriter {
+    /// Opens the WAL file at `path` for appending, creating it if it doesn't exist, and starts
+    /// the writer thread. Returns the writer and the first handle to it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, WalHandle)> {
+        Self::open_with_options(path, &WalWriterOptions::default())
+    }
+
+    /// Opens the WAL file at `path` like `open`, with the given options.
+    pub fn open_with_options(path: impl AsRef<Path>, options: &WalWriterOptions) -> io::Result<(Self, WalHandle)> {
+        let path = path.as_ref().to_path_buf();
+        let log = LogFile::single(&path, open_log_file(&path)?, options.record_encoding()?)?;
+        let replay_options = options.replay_options();
+        let last_seqno = recover_last_seqno(replay::replay_with_options(&path, &replay_options)?)?;
+        Self::spawn(path, log, last_seqno, options)
+    }
+
+    /// Opens a WAL split into segments named `wal-<seq>.log` in `dir`, see `SegmentOptions`.
+    /// Appends to the segment with the highest sequence number, creating `wal-1.log` in an
+    /// empty directory. Segments are replayed with `replay_dir`.
+    pub fn open_dir(dir: impl AsRef<Path>, options: &SegmentOptions) -> io::Result<(Self, WalHandle)> {
+        Self::open_dir_with_options(dir, options, &WalWriterOptions::default())
+    }
+
+    /// Opens a WAL split into segments in `dir` like `open_dir`, with the given writer options.
+    pub fn open_dir_with_options(
+        dir: impl AsRef<Path>,
+        segment_options: &SegmentOptions,
+        options: &WalWriterOptions,
+    ) -> io::Result<(Self, WalHandle)> {
+        let dir = dir.as_ref().to_path_buf();
+        let log = LogFile::segmented(&dir, segment_options, options.record_encoding()?)?;
+        let replay_options = options.replay_options();
+        let last_seqno = recover_last_seqno(replay::replay_dir_with_options(&dir, &replay_options)?)?;
+        Self::spawn(dir, log, last_seqno, options)
+    }
+
+    fn spawn(
+        path: PathBuf,
+        mut log: LogFile,
+        last_seqno: u64,
+        options: &WalWriterOptions,
+    ) -> io::Result<(Self, WalHandle)> {
+        log.advance_seqno(last_seqno);
+        let metrics = Arc::new(WalMetrics::default());
+        metrics.set_log_size(log.size()?);
+        let (sender, receiver) = mpsc::channel();
+        let sync_manager = SyncManager::new()
+            .with_policy(options.sync_policy)
+            .with_syncer(Arc::clone(&options.syncer))
+            .with_metrics(Arc::clone(&metrics))
+            .with_slow_subscriber_policy(options.slow_subscriber_policy);
+        let thread = thread::Builder::new()
+            .name("snaildb-wal".to_string())
+            .spawn(move || wal_handler(receiver, log, sync_manager))?;
+        let handle = WalHandle {
+            sender,
+            last_seqno: Arc::new(Mutex::new(last_seqno)),
+            metrics,
+            subscriber_capacity: options.subscriber_capacity,
+            record_limits: options.record_limits,
+        };
+        Ok((Self { path, thread }, handle))
+    }
+
+    /// Returns the path to the WAL file, or to the directory of a segmented WAL.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits for the writer thread to stop, after a `Shutdown` or once every handle is dropped.
+    /// Every record sent before then is written and synced when this returns.
+    pub fn join(self) -> io::Result<()> {
+        self.thread
+            .join()
+            .map_err(|_| io::Error::other("WAL writer thread panicked"))
+    }
+}
+
+impl WalHandle {
+    /// Queues a record to be appended to the log, and returns the sequence number it is
+    /// written with, see `DecodedRecord::seqno`. Fails with a `RecordError` if the key or value
+    /// is over `WalWriterOptions::record_limits`.
+    pub fn write(&self, kind: RecordKind, key: &str, value: &[u8]) -> io::Result<u64> {
+        self.record_limits.check(key.len(), value.len())?;
+        let mut last_seqno = self.lock_seqno();
+        let seqno = *last_seqno + 1;
+        self.send(WriteCommand::WriteRecord {
+            kind,
+            key: key.to_string(),
+            value: value.to_vec(),
+            seqno,
+            expires_at: None,
+            family: 0,
+        })?;
+        *last_seqno = seqno;
+        Ok(seqno)
+    }
+
+    /// Queues records to be appended to the log as one batch. Replaying the log yields either
+    /// all of them or, if a crash tore the batch, none of them.
+    ///
+    /// Returns the sequence numbers of the records, consecutive and in the order given. Fails
+    /// like `write`, queueing none of the records, if one is over the limits.
+    pub fn write_batch(&self, records: Vec<(RecordKind, String, Vec<u8>)>) -> io::Result<Range<u64>> {
+        for (_, key, value) in &records {
+            self.record_limits.check(key.len(), value.len())?;
+        }
+        let mut last_seqno = self.lock_seqno();
+        let seqnos = *last_seqno + 1..*last_seqno + 1 + records.len() as u64;
+        self.send(WriteCommand::WriteBatch { first_seqno: seqnos.start, records })?;
+        *last_seqno = seqnos.end - 1;
+        Ok(seqnos)
+    }
+
+    /// Subscribes to the records sent from any handle after this call: each one is sent to the
+    /// receiver as a `WalEvent` once a sync made it durable, so a subscriber never sees a record
+    /// that recovery could lose. Events come in sequence number order, and every subscriber
+    /// gets all of them, the records of a batch one by one.
+    ///
+    /// A subscriber that lets `WalWriterOptions::subscriber_capacity` events pile up holds up
+    /// the writer or is disconnected, see `SlowSubscriberPolicy`: its receiver then yields the
+    /// events it has and ends, and so does the receiver of a writer that stopped. Records are
+    /// synced as the `SyncPolicy` says, so under `SyncPolicy::Never` events wait for a flush.
+    pub fn subscribe(&self) -> mpsc::Receiver<WalEvent> {
+        let (events, receiver) = mpsc::sync_channel(self.subscriber_capacity);
+        // Held so the records sent after the subscription get the numbers from here on
+        let last_seqno = self.lock_seqno();
+        // A writer that stopped drops the sender, which ends the receiver
+        let _ = self.send(WriteCommand::Subscribe { events, from_seqno: *last_seqno + 1 });
+        receiver
+    }
+
+    /// Returns the sequence number of the last record sent from any handle, or the last one
+    /// found in the log when it was opened. 0 if there is none yet.
+    pub fn last_sequence(&self) -> u64 {
+        *self.lock_seqno()
+    }
+
+    /// Writes and syncs the records sent so far from any handle, and waits until they are
+    /// durable. Flushes requested by concurrent callers are coalesced into a single sync, see
+    /// `WriteCommand::Flush`.
+    ///
+    /// Returns the error of the sync, or of a write that failed in the background since the
+    /// last flush, say because the disk is full.
+    pub fn flush(&self) -> io::Result<()> {
+        let (done, flushed) = mpsc::channel();
+        self.send(WriteCommand::Flush { done })?;
+        flushed.recv().map_err(|_| shut_down())?
+    }
+
+    /// Inserts a durability barrier into the log and waits until it is reached: every record
+    /// sent before the call from any handle is written and synced before any record sent after
+    /// it is written, see `WriteCommand::Sync`.
+    ///
+    /// Records are ordered by their sequence numbers, which are given out in the order the
+    /// records reach the writer, so the barrier falls right after the record numbered
+    /// `last_sequence()` at the call. A write racing with it on another handle lands on one
+    /// side or the other, whole. When this returns, the records up to the barrier are durable,
+    /// while those after it may already be written but are synced only later. The errors are
+    /// those of `flush`.
+    pub fn barrier(&self) -> io::Result<()> {
+        let (done, synced) = mpsc::channel();
+        {
+            // Held so no record is numbered before the barrier and queued after it
+            let _last_seqno = self.lock_seqno();
+            self.send(WriteCommand::Sync { done })?;
+        }
+        synced.recv().map_err(|_| shut_down())?
+    }
+
+    /// Returns the position at the end of the records sent so far from any handle. Once they
+    /// are persisted elsewhere, say by a memtable flush, `checkpoint` with it drops them.
+    pub fn position(&self) -> io::Result<WalPosition> {
+        let (done, position) = mpsc::channel();
+        self.send(WriteCommand::Position(done))?;
+        position.recv().map_err(|_| shut_down())?
+    }
+
+    /// Records that the records before `position` are durable elsewhere, and waits until they
+    /// are dropped from the log.
+    ///
+    /// The checkpoint is kept in a small file next to the log, `file_checkpoint_path` or
+    /// `dir_checkpoint_path`, that `ReplayOptions::from_checkpoint` starts the replay at. A
+    /// checkpoint at the end of the log empties it, otherwise the segments holding only
+    /// records before it are removed. A crash before the checkpoint leaves the records to be
+    /// replayed again over the data they were persisted to, which is harmless as long as the
+    /// replay is applied in order with the last write winning.
+    pub fn checkpoint(&self, position: WalPosition) -> io::Result<()> {
+        let (done, checkpointed) = mpsc::channel();
+        self.send(WriteCommand::Checkpoint { position, done })?;
+        checkpointed.recv().map_err(|_| shut_down())?
+    }
+
+    /// Queues emptying the log, after the records sent so far are written. A segmented log
+    /// also removes every segment but the active one. See `WriteCommand::Reset`.
+    pub fn reset(&self) -> io::Result<()> {
+        self.send(WriteCommand::Reset { up_to: None })
+    }
+
+    /// Queues emptying the log like `reset`, but for the records with a sequence number above
+    /// `seqno`, say those written after the memtable flush that persisted the others began.
+    pub fn reset_up_to(&self, seqno: u64) -> io::Result<()> {
+        self.send(WriteCommand::Reset { up_to: Some(seqno) })
+    }
+
+    /// Queues the removal of the segments numbered below `seq`, once the data they hold is
+    /// persisted elsewhere. The active segment is always kept. Does nothing for a single file.
+    /// See `segments` for the sequence numbers in use.
+    pub fn delete_segments_before(&self, seq: u64) -> io::Result<()> {
+        self.send(WriteCommand::DeleteSegmentsBefore(seq))
+    }
+
+    /// Returns the counters of the writer: the records, bytes and syncs of the log so far and its
+    /// current size, see `WalMetricsSnapshot`.
+    pub fn metrics(&self) -> WalMetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Stops the writer once the commands sent so far are executed, and waits until the
+    /// records are written and synced. Returns an error like `flush`. Later commands from any
+    /// handle fail. See `WalWriter::join` to wait for the thread to exit.
+    pub fn shutdown(&self) -> io::Result<()> {
+        let (done, stopped) = mpsc::channel();
+        self.send(WriteCommand::Shutdown { done })?;
+        stopped.recv().map_err(|_| shut_down())?
+    }
+
+    fn send(&self, command: WriteCommand) -> io::Result<()> {
+        // Counted before the writer can take it
+        self.metrics.enqueue();
+        self.sender.send(command).map_err(|_| {
+            self.metrics.dequeue();
+            shut_down()
+        })
+    }
+
+    fn lock_seqno(&self) -> MutexGuard<'_, u64> {
+        self.last_seqno.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Reads a whole log for the last sequence number given out, see `WalReplay::last_seqno`.
+fn recover_last_seqno(mut replay: WalReplay) -> io::Result<u64> {
+    for record in replay.by_ref() {
+        record?;
+    }
+    Ok(replay.last_seqno())
+}
+
+fn shut_down() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "WAL writer has shut down")
+}
@@ -0,0 +1,1085 @@
+use snaildb::storage::SsTable;
+use snaildb::storage::sstable::Compression;
+use snaildb::utils::{
+    AppendOperator, MergeOperator, RecordBuf, RecordError, RecordKind, RecordLimits, RecordRefs, RecordTrailer,
+    U64AddOperator, Value, ValueCompression, ValueReader, decode_batch_records, decode_record_ref, encode_batch_records,
+    encode_write_batch, read_batch, read_record, read_record_header, read_record_into, record, write_record,
+    write_record_compressed, write_record_with_clock, write_record_with_expiry,
+};
+use snaildb::wal::{RecoveryMode, Wal, WalEntry};
+use anyhow::Result;
+use tempfile::TempDir;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::thread;
+use std::time::Duration;
+
+/// Counts the allocations of each thread, see `allocations_during`.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the result of `f` and how many allocations it made on this thread.
+fn allocations_during<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
+
+#[test]
+fn test_record_roundtrip() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, b"user:1", b"Hrushi", 0)?;
+    write_record(&mut buffer, RecordKind::Delete, b"user:2", &[], 0)?;
+
+    let mut cursor = Cursor::new(buffer);
+    let first = read_record(&mut cursor)?.expect("first record");
+    assert!(matches!(first.kind, RecordKind::Set));
+    assert_eq!(first.key, b"user:1");
+    assert_eq!(first.value, b"Hrushi");
+    let second = read_record(&mut cursor)?.expect("second record");
+    assert!(matches!(second.kind, RecordKind::Delete));
+    assert_eq!(second.key, b"user:2");
+    assert_eq!(second.seqno, 0);
+    assert!(read_record(&mut cursor)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_record_seqno_roundtrip() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, b"user:1", b"Hrushi", 7)?;
+    write_record(&mut buffer, RecordKind::Delete, b"user:1", &[], u64::MAX)?;
+    write_record_with_expiry(&mut buffer, b"user:2", b"token", 5_000, 9)?;
+
+    let mut cursor = Cursor::new(buffer);
+    let first = read_record(&mut cursor)?.expect("first record");
+    assert!(matches!(first.kind, RecordKind::Set));
+    assert_eq!((first.key.as_slice(), first.value.as_slice(), first.seqno), (&b"user:1"[..], &b"Hrushi"[..], 7));
+    let second = read_record(&mut cursor)?.expect("second record");
+    assert!(matches!(second.kind, RecordKind::Delete));
+    assert_eq!(second.seqno, u64::MAX);
+    let third = read_record(&mut cursor)?.expect("third record");
+    assert!(matches!(third.kind, RecordKind::SetWithTtl));
+    assert_eq!((third.expires_at, third.seqno), (Some(5_000), 9));
+
+    // sequence number 0 is left out, so such records encode exactly as before
+    let mut with_seqno = Vec::new();
+    write_record(&mut with_seqno, RecordKind::Set, b"key", b"value", 1)?;
+    let mut without = Vec::new();
+    write_record(&mut without, RecordKind::Set, b"key", b"value", 0)?;
+    assert_eq!(with_seqno.len(), without.len() + 8);
+    assert_eq!(&without[8..], &[0x01, 0x03, b'k', b'e', b'y', 0x05, b'v', b'a', b'l', b'u', b'e']);
+    Ok(())
+}
+
+#[test]
+fn test_record_timestamp_roundtrip() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record_with_clock(&mut buffer, RecordKind::Set, b"user:1", b"Hrushi", 7, || 1_700_000_000_000)?;
+    write_record_with_clock(&mut buffer, RecordKind::Delete, b"user:2", &[], 0, || 42)?;
+    write_record(&mut buffer, RecordKind::Set, b"user:3", b"Hrushi", 8)?;
+
+    let mut cursor = Cursor::new(buffer);
+    let first = read_record(&mut cursor)?.expect("first record");
+    assert!(matches!(first.kind, RecordKind::Set));
+    assert_eq!((first.value.as_slice(), first.seqno, first.timestamp), (&b"Hrushi"[..], 7, 1_700_000_000_000));
+    let second = read_record(&mut cursor)?.expect("second record");
+    assert!(matches!(second.kind, RecordKind::Delete));
+    assert_eq!((second.seqno, second.timestamp), (0, 42));
+    // records written without a clock, like those written before timestamps existed, have none
+    let third = read_record(&mut cursor)?.expect("third record");
+    assert_eq!((third.seqno, third.timestamp), (8, 0));
+    assert!(read_record(&mut cursor)?.is_none());
+
+    // a clock returning 0 stamps nothing, so the record encodes as if written without one
+    let mut stamped = Vec::new();
+    write_record_with_clock(&mut stamped, RecordKind::Set, b"key", b"value", 1, || 5)?;
+    let mut unstamped = Vec::new();
+    write_record_with_clock(&mut unstamped, RecordKind::Set, b"key", b"value", 1, || 0)?;
+    let mut plain = Vec::new();
+    write_record(&mut plain, RecordKind::Set, b"key", b"value", 1)?;
+    assert_eq!(unstamped, plain);
+    assert_eq!(stamped.len(), plain.len() + 8);
+    Ok(())
+}
+
+/// The length of the header framing a batch: `[length:4][crc32:4]` and a 13-byte payload.
+const BATCH_HEADER_LEN: usize = 21;
+
+#[test]
+fn test_record_batch_encoding_matches_write_record() -> Result<()> {
+    let mut written = Vec::new();
+    write_record(&mut written, RecordKind::Set, b"key", b"value", 0)?;
+    let mut batched = Vec::new();
+    encode_batch_records(&mut batched, RecordKind::Set, b"key", b"value", 0)?;
+    // the record follows the header of its batch
+    assert_eq!(&batched[BATCH_HEADER_LEN..], written.as_slice());
+    let record = read_record(&mut Cursor::new(written))?.expect("record");
+    assert_eq!(decode_batch_records(&batched)?, vec![record]);
+    Ok(())
+}
+
+#[test]
+fn test_batch_framing_roundtrip() -> Result<()> {
+    // an empty batch encodes nothing and decodes to no record
+    let mut buffer = Vec::new();
+    encode_write_batch(&mut buffer, [])?;
+    assert!(buffer.is_empty());
+    assert!(decode_batch_records(&buffer)?.is_empty());
+    assert!(read_batch(&mut buffer.as_slice())?.is_none());
+
+    // a single record
+    encode_batch_records(&mut buffer, RecordKind::Delete, b"gone", b"", 7)?;
+    let records = decode_batch_records(&buffer)?;
+    assert_eq!(records.len(), 1);
+    assert_eq!((records[0].kind, records[0].key.as_slice(), records[0].seqno), (RecordKind::Delete, &b"gone"[..], 7));
+
+    // many records, as one batch and as batches of one, read one batch at a time
+    let keys: Vec<String> = (0..500).map(|i| format!("key{i:03}")).collect();
+    let mut buffer = Vec::new();
+    encode_write_batch(&mut buffer, keys.iter().map(|key| (RecordKind::Set, key.as_bytes(), b"value".as_slice())))?;
+    for key in &keys {
+        encode_batch_records(&mut buffer, RecordKind::Set, key.as_bytes(), b"single", 0)?;
+    }
+    let mut reader = buffer.as_slice();
+    let first = read_batch(&mut reader)?.expect("the large batch");
+    assert_eq!(first.len(), keys.len());
+    let mut singles = 0;
+    while let Some(batch) = read_batch(&mut reader)? {
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].value, b"single");
+        singles += 1;
+    }
+    assert_eq!(singles, keys.len());
+    let records = decode_batch_records(&buffer)?;
+    assert_eq!(records.len(), 2 * keys.len());
+    for (record, key) in records.iter().zip(keys.iter().chain(keys.iter())) {
+        assert_eq!(record.key, key.as_bytes());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_batch_framing_detects_torn_and_corrupt_batches() -> Result<()> {
+    let mut buffer = Vec::new();
+    let records = [(RecordKind::Set, &b"alpha"[..], &b"first value"[..]), (RecordKind::Set, b"beta", b"second")];
+    encode_write_batch(&mut buffer, records)?;
+    let len = buffer.len();
+
+    // a torn frame names the bytes it needed and the bytes it had, in the header or the records
+    for cut in [5, BATCH_HEADER_LEN + 3, len - 1] {
+        let err = decode_batch_records(&buffer[..cut]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "cut at {cut}");
+        let expected = if cut < BATCH_HEADER_LEN { BATCH_HEADER_LEN } else { len };
+        assert!(err.to_string().contains(&format!("expected {expected} bytes, {cut} available")), "{err}");
+        assert!(read_batch(&mut &buffer[..cut]).is_err());
+    }
+    // after a valid batch too
+    let mut two = buffer.clone();
+    two.extend_from_slice(&buffer[..len - 4]);
+    let err = decode_batch_records(&two).unwrap_err();
+    assert!(err.to_string().contains(&format!("expected {len} bytes, {} available", len - 4)), "{err}");
+
+    // a flipped bit in a value fails the checksum of the batch before any record is returned
+    let at = buffer.windows(6).position(|window| window == b"second").expect("value in the batch");
+    for bit in 0..8 {
+        let mut corrupted = buffer.clone();
+        corrupted[at + 2] ^= 1 << bit;
+        let err = read_batch(&mut corrupted.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("crc mismatch while reading batch"), "{err}");
+        assert!(decode_batch_records(&corrupted).is_err());
+    }
+
+    // and so does one in the header, as does a plain record where a batch should be
+    let mut corrupted = buffer.clone();
+    corrupted[10] ^= 0x01;
+    assert_eq!(decode_batch_records(&corrupted).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let mut record = Vec::new();
+    write_record(&mut record, RecordKind::Set, b"key", b"a value long enough for a header", 0)?;
+    assert_eq!(decode_batch_records(&record).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn test_read_record_into_reuses_buffers() -> Result<()> {
+    let mut bytes = Vec::new();
+    for i in 0..2_000u64 {
+        let key = format!("key{i:05}");
+        let value = "v".repeat(i as usize % 300);
+        match i % 5 {
+            0 => write_record(&mut bytes, RecordKind::Delete, key.as_bytes(), b"", i)?,
+            1 => write_record_with_expiry(&mut bytes, key.as_bytes(), value.as_bytes(), 1_700_000_000_000 + i, i)?,
+            2 => write_record_with_clock(&mut bytes, RecordKind::Set, key.as_bytes(), value.as_bytes(), i, || 42)?,
+            3 => write_record(&mut bytes, RecordKind::RangeDelete, key.as_bytes(), b"zzz", i)?,
+            _ => write_record(&mut bytes, RecordKind::Set, key.as_bytes(), value.as_bytes(), 0)?,
+        }
+    }
+    let plain_len = bytes.len();
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    for codec in available_codecs() {
+        let compression = ValueCompression::new(codec, 16);
+        let value = "snail".repeat(100).into_bytes();
+        write_record_compressed(&mut bytes, RecordKind::Set, format!("{codec:?}").as_bytes(), &value, 9, compression)?;
+    }
+
+    // both paths read the same records
+    let (mut owned, mut reused) = (Cursor::new(&bytes), Cursor::new(&bytes));
+    let mut buf = RecordBuf::default();
+    let mut count = 0;
+    while let Some(record) = read_record(&mut owned)? {
+        assert!(read_record_into(&mut reused, &mut buf)?.is_some());
+        assert_eq!(buf.kind(), record.kind);
+        assert_eq!(buf.key(), record.key);
+        assert_eq!(buf.value(), record.value);
+        assert_eq!(buf.seqno(), record.seqno);
+        assert_eq!(buf.expires_at(), record.expires_at);
+        assert_eq!(buf.timestamp(), record.timestamp);
+        assert_eq!(buf.to_record(), record);
+        count += 1;
+    }
+    assert!(count >= 2_000);
+    assert!(read_record_into(&mut reused, &mut buf)?.is_none());
+    assert_eq!(reused.position(), owned.position());
+
+    // once the buffers have grown, a scan of plain records allocates nothing
+    let (result, allocations) = allocations_during(|| -> io::Result<usize> {
+        let mut reader = Cursor::new(&bytes[..plain_len]);
+        let mut key_bytes = 0;
+        while read_record_into(&mut reader, &mut buf)?.is_some() {
+            key_bytes += buf.key().len();
+        }
+        Ok(key_bytes)
+    });
+    assert!(result? > 0);
+    assert_eq!(allocations, 0);
+    Ok(())
+}
+
+#[test]
+fn test_record_ref_decoding_borrows() -> Result<()> {
+    let mut block = Vec::new();
+    for i in 0..10_000u64 {
+        let kind = if i % 7 == 0 { RecordKind::Delete } else { RecordKind::Set };
+        write_record(&mut block, kind, format!("key{i:05}").as_bytes(), format!("v{i}").as_bytes(), i)?;
+    }
+
+    // the borrowed path decodes every record of the block without allocating
+    let ((count, key_bytes, value_bytes), allocations) = allocations_during(|| {
+        let (mut count, mut key_bytes, mut value_bytes) = (0, 0, 0);
+        for record in RecordRefs::new(&block) {
+            let record = record.expect("valid record");
+            count += 1;
+            key_bytes += record.key.len();
+            value_bytes += record.value.len();
+        }
+        (count, key_bytes, value_bytes)
+    });
+    assert_eq!(allocations, 0);
+    assert_eq!(count, 10_000);
+    assert_eq!(key_bytes, 10_000 * 8);
+    assert!(value_bytes > 0);
+
+    // and matches the owned path, record for record
+    let mut reader = Cursor::new(&block);
+    let mut offset = 0;
+    for (i, record) in RecordRefs::new(&block).enumerate() {
+        let record = record?;
+        let owned = read_record(&mut reader)?.expect("as many owned records");
+        assert_eq!(record.key, format!("key{i:05}").as_bytes());
+        assert_eq!(record.seqno, i as u64);
+        let (at_offset, len) = decode_record_ref(&block[offset..])?.expect("record at offset");
+        assert_eq!(at_offset, record);
+        offset += len;
+        assert_eq!(record.into_owned()?, owned);
+    }
+    assert_eq!(offset, block.len());
+    assert!(read_record(&mut reader)?.is_none());
+    assert!(decode_record_ref(&block[offset..])?.is_none());
+
+    // a damaged or truncated record fails, and ends the iteration
+    let mut corrupted = block.clone();
+    corrupted[10] ^= 0xFF;
+    let mut records = RecordRefs::new(&corrupted);
+    assert_eq!(records.next().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!(records.next().is_none());
+    let mut records = RecordRefs::new(&block[..block.len() - 1]);
+    let err = records.by_ref().find_map(Result::err).expect("truncated record");
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(records.next().is_none());
+    Ok(())
+}
+
+/// The codecs this build has.
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn available_codecs() -> Vec<Compression> {
+    [Compression::Lz4, Compression::Zstd].into_iter().filter(|codec| codec.is_available()).collect()
+}
+
+/// Returns `len` bytes no codec can shrink.
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn incompressible(len: usize) -> Vec<u8> {
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+#[test]
+fn test_record_value_compression_threshold() -> Result<()> {
+    let threshold = 64;
+    for codec in available_codecs() {
+        let compression = ValueCompression::new(codec, threshold);
+
+        // a value of exactly the threshold is stored as it is
+        let at_threshold = vec![b'a'; threshold];
+        let (mut plain, mut compressed) = (Vec::new(), Vec::new());
+        write_record(&mut plain, RecordKind::Set, b"key", &at_threshold, 3)?;
+        write_record_compressed(&mut compressed, RecordKind::Set, b"key", &at_threshold, 3, compression)?;
+        assert_eq!(compressed, plain, "{codec:?}");
+
+        // one byte more is compressed, and read back as written
+        let over = vec![b'a'; threshold + 1];
+        let mut compressed = Vec::new();
+        write_record_compressed(&mut compressed, RecordKind::Set, b"key", &over, 3, compression)?;
+        assert!(compressed.len() < plain.len(), "{codec:?}");
+        let record = read_record(&mut Cursor::new(&compressed))?.expect("record");
+        assert_eq!((record.value, record.seqno), (over.clone(), 3));
+        assert!((record.value_length as usize) < over.len());
+        let (borrowed, _) = decode_record_ref(&compressed)?.expect("record");
+        assert_eq!(borrowed.compression, codec);
+        assert_ne!(borrowed.value, over.as_slice());
+        assert_eq!(borrowed.decompressed_value()?.as_ref(), over.as_slice());
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+#[test]
+fn test_record_value_compression_skips_incompressible_values() -> Result<()> {
+    let random = incompressible(4096);
+    for codec in available_codecs() {
+        let (mut plain, mut compressed) = (Vec::new(), Vec::new());
+        write_record(&mut plain, RecordKind::Set, b"random", &random, 0)?;
+        let compression = ValueCompression::new(codec, 16);
+        write_record_compressed(&mut compressed, RecordKind::Set, b"random", &random, 0, compression)?;
+        assert_eq!(compressed, plain, "{codec:?}");
+        let (record, _) = decode_record_ref(&compressed)?.expect("record");
+        assert_eq!(record.compression, Compression::None);
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+#[test]
+fn test_record_value_compression_mixed_file() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("mixed.log");
+    let large = "snail".repeat(2_000).into_bytes();
+    let random = incompressible(1024);
+
+    // records stored as they are, compressed with each codec and too random to compress
+    let mut file = std::fs::File::create(&path)?;
+    let mut expected = Vec::new();
+    write_record(&mut file, RecordKind::Set, b"plain", &large, 1)?;
+    expected.push((b"plain".to_vec(), large.clone()));
+    for codec in available_codecs() {
+        let compression = ValueCompression::new(codec, 128);
+        let key = format!("{codec:?}").into_bytes();
+        write_record_compressed(&mut file, RecordKind::Set, &key, &large, 2, compression)?;
+        write_record_compressed(&mut file, RecordKind::Set, b"random", &random, 3, compression)?;
+        write_record_compressed(&mut file, RecordKind::Delete, b"deleted", b"", 4, compression)?;
+        expected.push((key, large.clone()));
+        expected.push((b"random".to_vec(), random.clone()));
+        expected.push((b"deleted".to_vec(), Vec::new()));
+    }
+    drop(file);
+    let bytes = std::fs::read(&path)?;
+    assert!(bytes.len() < large.len() * 2, "{} bytes", bytes.len());
+
+    let mut reader = Cursor::new(&bytes);
+    let mut read = Vec::new();
+    while let Some(record) = read_record(&mut reader)? {
+        read.push((record.key, record.value));
+    }
+    assert_eq!(read, expected);
+    let borrowed = RecordRefs::new(&bytes)
+        .map(|record| record.and_then(|record| Ok((record.key.to_vec(), record.decompressed_value()?.into_owned()))))
+        .collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(borrowed, expected);
+    Ok(())
+}
+
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn test_record_value_compression_missing_codec_feature() -> Result<()> {
+    let compression = ValueCompression::new(Compression::Zstd, 0);
+    let err = write_record_compressed(&mut Vec::new(), RecordKind::Set, b"key", b"value", 0, compression).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("zstd"), "{err}");
+
+    // A record flagged as compressed with zstd, 0x20 | 0x08 in the kind byte, with a valid checksum
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, b"key", b"value", 0)?;
+    buffer[8] |= 0x28;
+    let crc = crc32fast::hash(&buffer[8..]);
+    buffer[4..8].copy_from_slice(&crc.to_le_bytes());
+    let err = read_record(&mut Cursor::new(&buffer)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    assert!(err.to_string().contains("built without the `zstd` feature"), "{err}");
+    let (record, _) = decode_record_ref(&buffer)?.expect("record");
+    assert_eq!(record.compression, Compression::Zstd);
+    assert_eq!(record.decompressed_value().unwrap_err().kind(), io::ErrorKind::Unsupported);
+    Ok(())
+}
+
+/// Returns every way of cutting `operands` into consecutive groups, as the lengths of the groups.
+fn groupings(len: usize) -> Vec<Vec<usize>> {
+    if len == 0 {
+        return vec![Vec::new()];
+    }
+    (1..=len)
+        .flat_map(|first| {
+            groupings(len - first).into_iter().map(move |mut rest| {
+                rest.insert(0, first);
+                rest
+            })
+        })
+        .collect()
+}
+
+/// Checks that combining `operands` group by group with `partial_merge`, in every grouping and
+/// in groups of groups, makes the same value of `existing` as all of them at once.
+fn assert_partial_merge_is_associative(operator: &dyn MergeOperator, existing: Option<&[u8]>, operands: &[&[u8]]) {
+    let expected = operator.full_merge(b"key", existing, operands).expect("valid operands");
+    for grouping in groupings(operands.len()) {
+        let mut rest = operands;
+        let mut partials = Vec::new();
+        for len in &grouping {
+            let (group, tail) = rest.split_at(*len);
+            partials.push(operator.partial_merge(b"key", group).expect("operands combine"));
+            rest = tail;
+        }
+        let partials: Vec<&[u8]> = partials.iter().map(Vec::as_slice).collect();
+        assert_eq!(operator.full_merge(b"key", existing, &partials).as_ref(), Some(&expected), "{grouping:?}");
+        // and the partial results combine again, in pairs from either end
+        let (left, right) = partials.split_at(partials.len() / 2);
+        let nested: Vec<Vec<u8>> = [left, right]
+            .iter()
+            .filter(|half| !half.is_empty())
+            .map(|half| operator.partial_merge(b"key", half).expect("operands combine"))
+            .collect();
+        let nested: Vec<&[u8]> = nested.iter().map(Vec::as_slice).collect();
+        assert_eq!(operator.full_merge(b"key", existing, &nested).as_ref(), Some(&expected), "{grouping:?}");
+    }
+}
+
+#[test]
+fn test_merge_operators_partial_merge_is_associative() {
+    let deltas: Vec<[u8; 8]> = [1u64, 20, 300, u64::MAX, 4000].map(u64::to_le_bytes).to_vec();
+    let deltas: Vec<&[u8]> = deltas.iter().map(|delta| delta.as_slice()).collect();
+    let base = 7u64.to_le_bytes();
+    assert_partial_merge_is_associative(&U64AddOperator, None, &deltas);
+    assert_partial_merge_is_associative(&U64AddOperator, Some(&base), &deltas);
+    let total = U64AddOperator.full_merge(b"key", Some(&base), &deltas).unwrap();
+    assert_eq!(total, 7u64.wrapping_add(4321).wrapping_add(u64::MAX).to_le_bytes());
+    // an operand or a value that is not a counter fails the merge
+    assert_eq!(U64AddOperator.full_merge(b"key", Some(b"seven"), &deltas), None);
+    assert_eq!(U64AddOperator.partial_merge(b"key", &[b"1"]), None);
+
+    let pieces: Vec<&[u8]> = vec![b"a", b"", b"bc", b"def", b"g"];
+    assert_partial_merge_is_associative(&AppendOperator, None, &pieces);
+    assert_partial_merge_is_associative(&AppendOperator, Some(b"base:"), &pieces);
+    assert_eq!(AppendOperator.full_merge(b"key", Some(b"base:"), &pieces).unwrap(), b"base:abcdefg");
+
+    // operands stacked onto operands are combined into one
+    let stacked = Value::Merge(vec![b"c".to_vec()])
+        .stack_onto(b"key", Value::Merge(vec![b"a".to_vec(), b"b".to_vec()]), Some(&AppendOperator))
+        .unwrap();
+    assert_eq!(stacked, Value::Merge(vec![b"abc".to_vec()]));
+    let err = Value::merge_operand(b"1".to_vec()).resolve(b"key", Some(&U64AddOperator)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+/// One value of every variant, with bytes that are and are not UTF-8 and floats that are not finite.
+#[cfg(feature = "serde")]
+fn serde_values() -> Vec<Value> {
+    vec![
+        Value::Bytes(b"plain text".to_vec()),
+        Value::Bytes(vec![0xFF, 0x00, 0xFE]),
+        Value::Bytes(Vec::new()),
+        Value::Deleted,
+        Value::Int(-42),
+        Value::Int(i64::MAX),
+        Value::Float(0.1),
+        Value::Float(-2.5e300),
+        Value::Float(f64::NAN),
+        Value::Float(f64::INFINITY),
+        Value::Float(f64::NEG_INFINITY),
+        Value::Str("snail \u{1F40C}".to_string()),
+        Value::Bool(true),
+        Value::Merge(vec![b"+1".to_vec(), vec![0x80, 0x81]]),
+    ]
+}
+
+/// One record of every kind, read back from the bytes `write_record` and friends encode.
+#[cfg(feature = "serde")]
+fn serde_records() -> Result<Vec<record::DecodedRecord>> {
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, b"user:1", b"alice", 0)?;
+    write_record(&mut buffer, RecordKind::Set, &[0xC3, 0x28], &[0x00, 0xFF], 2)?;
+    write_record(&mut buffer, RecordKind::Delete, b"user:2", b"", 3)?;
+    write_record_with_expiry(&mut buffer, b"session", b"token", 1_700_000_000_000, 4)?;
+    write_record(&mut buffer, RecordKind::Merge, b"hits", &1u64.to_le_bytes(), 5)?;
+    write_record(&mut buffer, RecordKind::RangeDelete, b"a", b"m", 6)?;
+    write_record(&mut buffer, RecordKind::Typed, b"count", &Value::Int(7).encode()?, 7)?;
+    write_record_with_clock(&mut buffer, RecordKind::Set, b"stamped", b"now", 8, || 1_700_000_000_123)?;
+    let mut reader = Cursor::new(buffer);
+    let mut records = Vec::new();
+    while let Some(record) = read_record(&mut reader)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_values_and_records() -> Result<()> {
+    let values = serde_values();
+    let forms: Vec<String> = values.iter().map(serde_json::to_string).collect::<serde_json::Result<_>>()?;
+    assert_eq!(
+        forms,
+        [
+            r#""plain text""#,
+            r#"{"base64":"/wD+"}"#,
+            r#""""#,
+            "null",
+            r#"{"int":-42}"#,
+            r#"{"int":9223372036854775807}"#,
+            r#"{"float":0.1}"#,
+            r#"{"float":-2.5e300}"#,
+            r#"{"float":"NaN"}"#,
+            r#"{"float":"inf"}"#,
+            r#"{"float":"-inf"}"#,
+            "{\"str\":\"snail \u{1F40C}\"}",
+            r#"{"bool":true}"#,
+            r#"{"merge":["+1",{"base64":"gIE="}]}"#,
+        ]
+    );
+    let read: Vec<Value> = serde_json::from_str(&serde_json::to_string(&values)?)?;
+    assert_eq!(read, values);
+
+    let records = serde_records()?;
+    let json = serde_json::to_string(&records)?;
+    assert!(json.contains(r#""kind":"set_with_ttl""#) && json.contains(r#""kind":"range_delete""#), "{json}");
+    assert!(json.contains(r#""key":{"base64":"wyg="}"#), "{json}");
+    let read: Vec<record::DecodedRecord> = serde_json::from_str(&json)?;
+    assert_eq!(read, records);
+
+    // The fixture reads back as the values and records, and they write as the fixture
+    let fixture: serde_json::Value = serde_json::from_str(include_str!("fixtures/records.json"))?;
+    let read: Vec<Value> = serde_json::from_value(fixture["values"].clone())?;
+    assert_eq!(read, values);
+    let read: Vec<record::DecodedRecord> = serde_json::from_value(fixture["records"].clone())?;
+    assert_eq!(read, records);
+    assert_eq!(serde_json::json!({ "values": values, "records": records }), fixture);
+
+    // Malformed forms are rejected
+    let malformed = [
+        r#"{"base64":"not base64!"}"#,
+        r#"{"nope":1}"#,
+        r#"{"int":1,"str":"x"}"#,
+        "{}",
+        "[1]",
+        r#"{"float":"nan"}"#,
+    ];
+    for bad in malformed {
+        assert!(serde_json::from_str::<Value>(bad).is_err(), "{bad}");
+    }
+    Ok(())
+}
+
+/// Returns the `RecordError` an error wraps, if it does.
+fn record_error(err: &io::Error) -> Option<RecordError> {
+    err.get_ref().and_then(|err| err.downcast_ref::<RecordError>()).copied()
+}
+
+#[test]
+fn test_streaming_record_values() -> Result<()> {
+    let large: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let mut log = Vec::new();
+    write_record(&mut log, RecordKind::Set, b"small", b"value", 1)?;
+    write_record(&mut log, RecordKind::Set, b"large", &large, 2)?;
+    write_record_with_expiry(&mut log, b"session", b"token", 1_700_000_000_000, 3)?;
+    write_record(&mut log, RecordKind::Delete, b"small", b"", 4)?;
+
+    // Reading whole values, each one leaving the stream at the next record
+    let mut cursor = Cursor::new(log.as_slice());
+    let mut read = Vec::new();
+    while let Some(header) = read_record_header(&mut cursor)? {
+        let key = header.key.clone();
+        let mut value = ValueReader::new(&mut cursor, header);
+        let mut bytes = Vec::new();
+        value.read_to_end(&mut bytes)?;
+        let (trailer, _) = value.finish()?;
+        read.push((key, bytes, trailer));
+    }
+    let trailer = |seqno, expires_at| RecordTrailer { expires_at, seqno, timestamp: 0 };
+    assert_eq!(
+        read,
+        [
+            (b"small".to_vec(), b"value".to_vec(), trailer(1, None)),
+            (b"large".to_vec(), large.clone(), trailer(2, None)),
+            (b"session".to_vec(), b"token".to_vec(), trailer(3, Some(1_700_000_000_000))),
+            (b"small".to_vec(), Vec::new(), trailer(4, None)),
+        ]
+    );
+
+    // Partial reads and seeks within the value, then skipping the rest
+    let mut cursor = Cursor::new(log.as_slice());
+    let header = read_record_header(&mut cursor)?.expect("a record");
+    ValueReader::new(&mut cursor, header).skip()?;
+    let header = read_record_header(&mut cursor)?.expect("a record");
+    assert_eq!((header.kind, header.key.as_slice(), header.value_len), (RecordKind::Set, &b"large"[..], 100_000));
+    let mut value = ValueReader::new(&mut cursor, header);
+    let mut part = [0u8; 10];
+    value.read_exact(&mut part)?;
+    assert_eq!(part, large[..10]);
+    assert_eq!(value.remaining(), 99_990);
+    value.seek(SeekFrom::Start(50_000))?;
+    value.read_exact(&mut part)?;
+    assert_eq!(part, large[50_000..50_010]);
+    value.seek(SeekFrom::End(-5))?;
+    let mut tail = Vec::new();
+    value.read_to_end(&mut tail)?;
+    assert_eq!(tail, large[99_995..]);
+    value.seek(SeekFrom::Current(-100))?;
+    assert_eq!(value.seek(SeekFrom::End(1)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    let (trailer, cursor) = value.skip()?;
+    assert_eq!(trailer.seqno, 2);
+    let header = read_record_header(cursor)?.expect("a record");
+    assert_eq!(header.key, b"session");
+
+    // Only the keys, skipping every value with a seek, or by reading it without one
+    let keys = |seek: bool| -> Result<Vec<Vec<u8>>> {
+        let mut cursor = Cursor::new(log.as_slice());
+        let mut keys = Vec::new();
+        while let Some(header) = read_record_header(&mut cursor)? {
+            keys.push(header.key.clone());
+            let value = ValueReader::new(&mut cursor, header);
+            if seek {
+                value.skip()?;
+            } else {
+                value.finish()?;
+            }
+        }
+        Ok(keys)
+    };
+    let expected = [&b"small"[..], b"large", b"session", b"small"];
+    assert_eq!(keys(true)?, expected);
+    assert_eq!(keys(false)?, expected);
+
+    // A damaged value fails the read that reaches its end
+    let mut damaged = log.clone();
+    let at = damaged.len() / 2;
+    damaged[at] ^= 0xFF;
+    let mut cursor = Cursor::new(damaged.as_slice());
+    let header = read_record_header(&mut cursor)?.expect("a record");
+    ValueReader::new(&mut cursor, header).finish()?;
+    let header = read_record_header(&mut cursor)?.expect("a record");
+    let mut value = ValueReader::new(&mut cursor, header);
+    let err = value.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("crc mismatch"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn test_record_limits() -> Result<()> {
+    let key_limit = RecordLimits::DEFAULT_MAX_KEY_LEN;
+    let value_limit = RecordLimits::DEFAULT_MAX_VALUE_LEN;
+
+    // A key exactly at the limit is written and read back, batched or not
+    let key = vec![b'k'; key_limit];
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, &key, b"value", 1)?;
+    assert_eq!(read_record(&mut Cursor::new(&buffer))?.map(|record| record.key), Some(key.clone()));
+    let mut batch = Vec::new();
+    encode_batch_records(&mut batch, RecordKind::Set, &key, b"value", 2)?;
+    assert_eq!(decode_batch_records(&batch)?[0].key, key);
+    assert!(RecordLimits::default().check(key_limit, value_limit).is_ok());
+
+    // One byte over fails before anything is written
+    let over_key = vec![b'k'; key_limit + 1];
+    let over_value = vec![0u8; value_limit + 1];
+    let key_error = RecordError::KeyTooLarge { len: key_limit + 1, max: key_limit };
+    let value_error = RecordError::ValueTooLarge { len: value_limit + 1, max: value_limit };
+    for (key, value, expected) in [(&over_key[..], &b"value"[..], key_error), (b"key", &over_value[..], value_error)] {
+        let mut buffer = Vec::new();
+        let err = write_record(&mut buffer, RecordKind::Set, key, value, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(record_error(&err), Some(expected));
+        let err = write_record_with_expiry(&mut buffer, key, value, 1_000, 1).unwrap_err();
+        assert_eq!(record_error(&err), Some(expected));
+        let err = encode_batch_records(&mut buffer, RecordKind::Set, key, value, 1).unwrap_err();
+        assert_eq!(record_error(&err), Some(expected));
+        assert!(buffer.is_empty());
+    }
+    assert_eq!(key_error.to_string(), "key of 65537 bytes is longer than the 65536 allowed");
+
+    // Lower limits refuse records on read that the default ones take
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, b"key", &[7; 100], 1)?;
+    let limits = RecordLimits::default().with_max_key_len(16).with_max_value_len(16);
+    let err = record::read_record_with_limits(&mut Cursor::new(&buffer), limits).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(matches!(record_error(&err), Some(RecordError::RecordTooLarge { len, .. }) if len == buffer.len() - 8));
+    assert!(read_record(&mut Cursor::new(&buffer))?.is_some());
+    Ok(())
+}
+
+#[test]
+fn test_read_record_refuses_corrupt_length() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, b"key", b"value", 1)?;
+
+    // A length header claiming close to 4 GiB fails without allocating for it, not at the end of the input
+    for length in [u32::MAX, 0x8000_0000, (RecordLimits::DEFAULT_MAX_VALUE_LEN + 65536 + 1024) as u32] {
+        let mut corrupt = buffer.clone();
+        corrupt[..4].copy_from_slice(&length.to_le_bytes());
+        let err = read_record(&mut Cursor::new(&corrupt)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{length}");
+        assert!(matches!(record_error(&err), Some(RecordError::RecordTooLarge { len, .. }) if len == length as usize));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_record_crc_detects_flipped_bit() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, b"user:42", b"some value", 0)?;
+
+    // flip every bit of the payload (after [length:4][crc32:4]) one at a time
+    for byte in 8..buffer.len() {
+        for bit in 0..8 {
+            let mut corrupted = buffer.clone();
+            corrupted[byte] ^= 1 << bit;
+            let err = match read_record(&mut Cursor::new(corrupted)) {
+                Err(err) => err,
+                Ok(_) => panic!("flipped bit {bit} of byte {byte} went undetected"),
+            };
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("crc mismatch"));
+        }
+    }
+
+    // when the key survives, the error names it
+    let mut corrupted = buffer.clone();
+    *corrupted.last_mut().unwrap() ^= 0xFF;
+    let err = read_record(&mut Cursor::new(corrupted)).err().unwrap();
+    assert!(err.to_string().contains("\"user:42\""), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_record_crc_detects_corrupt_sstable() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries: Vec<_> = (0..100)
+        .map(|i| (format!("key:{:03}", i), Value::from_bytes(format!("value:{}", i).into_bytes())))
+        .collect();
+    SsTable::create(&path, entries)?;
+
+    // corrupt a byte of the value of the first record: header(4) + block codec(1) + record header(8) + payload
+    let mut bytes = std::fs::read(&path)?;
+    let value_offset = 4 + 1 + 8 + 1 + 1 + "key:000".len() + 1;
+    bytes[value_offset] ^= 0x01;
+    std::fs::write(&path, bytes)?;
+
+    let table = SsTable::open(&path)?;
+    let err = table.get("key:000").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("key:000"), "{err}");
+    assert!(SsTable::load(&path).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_record_crc_detects_corrupt_wal() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("wal.log");
+    let mut wal = Wal::open(&path)?;
+    wal.append_set("key1", b"value1")?;
+    wal.append_set("key2", b"value2")?;
+    wal.force_flush()?;
+    thread::sleep(Duration::from_millis(100));
+    drop(wal);
+    thread::sleep(Duration::from_millis(50));
+
+    let mut bytes = std::fs::read(&path)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x40;
+    std::fs::write(&path, bytes)?;
+
+    let wal = Wal::open(&path)?;
+    let err = wal.replay().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("key2"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_record_with_expiry_roundtrip() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record_with_expiry(&mut buffer, b"session:1", b"token", 1_700_000_000_000, 0)?;
+    write_record(&mut buffer, RecordKind::Set, b"session:2", b"token", 0)?;
+
+    let mut cursor = Cursor::new(buffer);
+    let first = read_record(&mut cursor)?.expect("first record");
+    assert!(matches!(first.kind, RecordKind::SetWithTtl));
+    assert_eq!(first.key, b"session:1");
+    assert_eq!(first.value, b"token");
+    assert_eq!(first.expires_at, Some(1_700_000_000_000));
+    let second = read_record(&mut cursor)?.expect("second record");
+    assert!(matches!(second.kind, RecordKind::Set));
+    assert_eq!(second.expires_at, None);
+
+    // the expiry belongs to SetWithTtl records only
+    let err = write_record(&mut Vec::new(), RecordKind::SetWithTtl, b"k", b"v", 0).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn test_record_lengths_are_varints() -> Result<()> {
+    // A 20-byte key and an 8-byte value take 39 bytes, where fixed 4-byte lengths would take 45
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Set, &[b'k'; 20], &[b'v'; 8], 0)?;
+    assert_eq!(buffer.len(), 8 + 1 + 1 + 20 + 1 + 8);
+    let mut buffer = Vec::new();
+    encode_batch_records(&mut buffer, RecordKind::Set, &[b'k'; 20], &[b'v'; 8], 0)?;
+    assert_eq!(buffer.len(), BATCH_HEADER_LEN + 39);
+
+    for len in [0usize, 1, 127, 128, 16383, 16384] {
+        let (key, value) = (vec![b'k'; len], vec![b'v'; len]);
+        let mut buffer = Vec::new();
+        write_record(&mut buffer, RecordKind::Set, &key, &value, 0)?;
+        let varint_len = record::encode_var_u32(len as u32).len();
+        assert_eq!(buffer.len(), 8 + 1 + 2 * (varint_len + len), "length {len}");
+        let decoded = read_record(&mut Cursor::new(buffer))?.expect("record");
+        assert_eq!((decoded.key, decoded.value), (key, value), "length {len}");
+        assert_eq!((decoded.key_length, decoded.value_length), (len as u32, len as u32));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_varint_roundtrip() -> Result<()> {
+    let cases = [
+        (0, 1),
+        (1, 1),
+        (127, 1),
+        (128, 2),
+        (16383, 2),
+        (16384, 3),
+        ((1 << 21) - 1, 3),
+        (1 << 21, 4),
+        ((1 << 28) - 1, 4),
+        (1 << 28, 5),
+        (u32::MAX - 1, 5),
+        (u32::MAX, 5),
+    ];
+    for (value, len) in cases {
+        let encoded = record::encode_var_u32(value);
+        assert_eq!(encoded.len(), len, "{value}");
+        let mut cursor = 0;
+        assert_eq!(record::decode_var_u32(&encoded, &mut cursor)?, value);
+        assert_eq!(cursor, len);
+    }
+    assert_eq!(record::encode_var_u32(u32::MAX), [0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+    Ok(())
+}
+
+#[test]
+fn test_varint_rejects_overflow_and_truncation() {
+    let decode = |bytes: &[u8]| record::decode_var_u32(bytes, &mut 0).unwrap_err().kind();
+    // Longer than 5 bytes, and 5 bytes past u32::MAX
+    assert_eq!(decode(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]), io::ErrorKind::InvalidData);
+    assert_eq!(decode(&[0x80, 0x80, 0x80, 0x80, 0x10]), io::ErrorKind::InvalidData);
+    assert_eq!(decode(&[]), io::ErrorKind::UnexpectedEof);
+    assert_eq!(decode(&[0x80, 0x80]), io::ErrorKind::UnexpectedEof);
+
+    // The same inside a record whose checksum holds
+    let framed = |payload: &[u8]| {
+        let mut buffer = (payload.len() as u32).to_le_bytes().to_vec();
+        buffer.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        buffer.extend_from_slice(payload);
+        read_record(&mut Cursor::new(buffer)).unwrap_err().kind()
+    };
+    assert_eq!(framed(&[1, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]), io::ErrorKind::InvalidData);
+    assert_eq!(framed(&[1, 0x00, 0x80]), io::ErrorKind::UnexpectedEof);
+    // A value length near u32::MAX over a short payload
+    assert_eq!(framed(&[1, 0x00, 0xFE, 0xFF, 0xFF, 0xFF, 0x0F, b'v']), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_record_merge_roundtrip() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::Merge, b"counter", &1u64.to_le_bytes(), 3)?;
+    let record = read_record(&mut Cursor::new(&buffer))?.expect("merge record");
+    assert_eq!(record.kind, RecordKind::Merge);
+    assert_eq!((record.key.as_slice(), record.seqno), (&b"counter"[..], 3));
+    assert_eq!(record.value, 1u64.to_le_bytes());
+    assert_eq!(record.expires_at, None);
+    Ok(())
+}
+
+#[test]
+fn test_record_range_delete_roundtrip() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_record(&mut buffer, RecordKind::RangeDelete, b"user:100", b"user:200", 9)?;
+    let record = read_record(&mut Cursor::new(&buffer))?.expect("range delete record");
+    assert_eq!(record.kind, RecordKind::RangeDelete);
+    assert_eq!((record.key.as_slice(), record.value.as_slice()), (&b"user:100"[..], &b"user:200"[..]));
+    assert_eq!((record.seqno, record.expires_at), (9, None));
+
+    // The WAL replays it as a range delete, which is not a key and value
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("wal.log");
+    let mut log = Vec::new();
+    write_record(&mut log, RecordKind::Set, b"user:150", b"value", 0)?;
+    write_record(&mut log, RecordKind::RangeDelete, b"user:100", b"user:200", 0)?;
+    std::fs::write(&path, &log)?;
+    let (entries, _) = Wal::recover(&path, RecoveryMode::default())?;
+    assert!(matches!(&entries[0], WalEntry::Write(key, Value::Bytes(_)) if key == "user:150"));
+    assert!(matches!(&entries[1], WalEntry::RangeDelete { start, end } if start == "user:100" && end == "user:200"));
+    let wal = Wal::open(&path)?;
+    assert_eq!(wal.replay().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+/// One value of every typed variant, with the edge cases of each.
+fn typed_values() -> Vec<Value> {
+    vec![
+        Value::Int(0),
+        Value::Int(i64::MIN),
+        Value::Int(i64::MAX),
+        Value::Float(1.5),
+        Value::Float(-0.0),
+        Value::Float(f64::NAN),
+        Value::Float(f64::INFINITY),
+        Value::Float(f64::NEG_INFINITY),
+        Value::Str(String::new()),
+        Value::Str("snail 🐌".to_string()),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(vec![0, 0xff, 0x80]),
+        Value::Bool(false),
+        Value::Bool(true),
+    ]
+}
+
+#[test]
+fn test_typed_value_encoding_roundtrip() -> Result<()> {
+    for value in typed_values() {
+        let encoded = value.encode()?;
+        assert_eq!(encoded.len(), 1 + value.byte_len());
+        let decoded = Value::decode(&encoded)?;
+        assert_eq!(decoded, value, "{value:?}");
+        if let (Value::Float(written), Value::Float(read)) = (&value, &decoded) {
+            assert_eq!(written.to_bits(), read.to_bits());
+        }
+    }
+    assert!(matches!(Value::from_bytes(b"raw".to_vec()), Value::Bytes(bytes) if bytes == b"raw"));
+    assert_eq!(Value::Str("abc".to_string()).as_option(), Some(b"abc".to_vec()));
+    assert_eq!(Value::Int(-2).as_option(), Some((-2i64).to_le_bytes().to_vec()));
+
+    assert_eq!(Value::Deleted.encode().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(Value::merge_operand(b"x".to_vec()).encode().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    for invalid in [&[][..], &[0], &[9, 1], &[1, 0, 0], &[2; 10], &[3, 0xff], &[5], &[5, 2]] {
+        assert_eq!(Value::decode(invalid).unwrap_err().kind(), io::ErrorKind::InvalidData, "{invalid:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_typed_value_comparisons() {
+    assert!(Value::Int(1) < Value::Int(2));
+    assert!(Value::Str("a".to_string()) < Value::Str("b".to_string()));
+    assert!(Value::Bool(false) < Value::Bool(true));
+    assert!(Value::Float(-1.0) < Value::Float(f64::NAN));
+    assert_eq!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+    assert_ne!(Value::Float(0.0), Value::Float(-0.0));
+    assert_eq!(Value::Deleted, Value::tombstone());
+
+    // values of different variants are never equal and have no order
+    assert_ne!(Value::Int(1), Value::Float(1.0));
+    assert_ne!(Value::Str("a".to_string()), Value::Bytes(b"a".to_vec()));
+    assert_eq!(Value::Int(1).partial_cmp(&Value::Bool(true)), None);
+    assert_eq!(Value::Bytes(Vec::new()).partial_cmp(&Value::Deleted), None);
+}
+
+#[test]
+fn test_typed_values_in_sstable_and_wal() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let values = typed_values();
+    let entries: Vec<_> = values.iter().enumerate().map(|(i, value)| (format!("key{i:02}"), value.clone())).collect();
+    let path = temp_dir.path().join("typed.sst");
+    SsTable::create(&path, entries.clone())?;
+    for table in [SsTable::open(&path)?, SsTable::load(&path)?] {
+        for (key, value) in &entries {
+            assert_eq!(table.get(key)?.as_ref(), Some(value), "{key}");
+        }
+        let read: Vec<_> = table.iter().map(|item| item.map(|(_, value)| value)).collect::<io::Result<_>>()?;
+        assert_eq!(read, values);
+    }
+
+    // raw bytes are written as before, typed values have a record kind of their own
+    let path = temp_dir.path().join("wal.log");
+    let mut wal = Wal::open(&path)?;
+    for (key, value) in &entries {
+        wal.append_value(key, value)?;
+    }
+    wal.append_value("deleted", &Value::Deleted)?;
+    let err = wal.append_value("m", &Value::merge_operand(b"x".to_vec())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    wal.force_flush()?;
+    let replayed = wal.replay()?;
+    assert_eq!(replayed.len(), entries.len() + 1);
+    for ((key, value), (read_key, read)) in entries.iter().zip(&replayed) {
+        assert_eq!((key, value), (read_key, read));
+    }
+    assert_eq!(replayed[entries.len()], ("deleted".to_string(), Value::Deleted));
+
+    let mut file = std::fs::File::open(&path)?;
+    let mut kinds = Vec::new();
+    while let Some(record) = read_record(&mut file)? {
+        kinds.push(record.kind);
+    }
+    assert_eq!(kinds.iter().filter(|kind| **kind == RecordKind::Set).count(), 2);
+    assert_eq!(kinds.iter().filter(|kind| **kind == RecordKind::Typed).count(), entries.len() - 2);
+    Ok(())
+}
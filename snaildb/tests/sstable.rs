@@ -0,0 +1,2840 @@
+use snaildb::storage::sstable::{
+    BlockCache, CompactionFilter, Comparator, Compression, CorruptFooter, FilterDecision, KeyRange, MergeOptions,
+    PrefixExtractor, RangeTombstone, RecordMeta, ReverseBytewiseComparator, SsTableOptions, SsTableWriter, Stats,
+    VerifyError, VerifyOptions, DEFAULT_BLOCK_SIZE, FORMAT_VERSION, overlapping_tables,
+};
+use snaildb::storage::SsTable;
+use snaildb::utils::{AppendOperator, RecordError, RecordLimits, U64AddOperator, Value, ValueCompression};
+use anyhow::Result;
+use tempfile::TempDir;
+use std::fs::File;
+use std::path::Path;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Wraps a reader and counts how many bytes and seeks go through it.
+struct CountingReader<R> {
+    inner: R,
+    bytes_read: usize,
+    seeks: usize,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, bytes_read: 0, seeks: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.seeks += 1;
+        self.inner.seek(pos)
+    }
+}
+
+fn sample_entries(count: usize) -> Vec<(String, Value)> {
+    (0..count)
+        .map(|i| {
+            let key = format!("key:{:06}", i);
+            if i % 10 == 0 {
+                (key, Value::tombstone())
+            } else {
+                (key, Value::from_bytes(format!("value:{}", i).into_bytes()))
+            }
+        })
+        .collect()
+}
+
+/// Opens the table with every read backend this build supports: loaded into memory,
+/// lazily opened and, with the `mmap` feature, memory mapped.
+fn open_all(path: &Path) -> io::Result<Vec<SsTable>> {
+    Ok(vec![
+        SsTable::load(path)?,
+        SsTable::open(path)?,
+        #[cfg(feature = "mmap")]
+        SsTable::open_mmap(path)?,
+    ])
+}
+
+#[test]
+fn test_sstable_multi_block_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(5_000);
+
+    let created = SsTable::create(&path, entries.clone())?;
+    assert!(created.block_count() > 1);
+
+    let mut tables = open_all(&path)?;
+    tables.push(SsTable::load_metadata(&path)?);
+    tables.push(created);
+
+    for table in &tables {
+        assert_eq!(table.block_count(), tables[0].block_count());
+        for (key, value) in &entries {
+            let found = table.get(key)?.expect("key should be present");
+            assert_eq!(found.as_option(), value.as_option());
+        }
+        assert!(table.get("key:999999")?.is_none());
+        assert!(table.get("a-before-everything")?.is_none());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_lookup_reads_single_block() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    SsTable::create(&path, sample_entries(5_000))?;
+
+    let table = SsTable::load_metadata(&path)?;
+    let file_len = std::fs::metadata(&path)?.len() as usize;
+    assert!(table.block_count() > 10);
+
+    for key in ["key:000000", "key:002500", "key:004999", "key:001234"] {
+        let mut reader = CountingReader::new(File::open(&path)?);
+        let value = table.get_from(&mut reader, key)?;
+        assert!(value.is_some());
+        assert_eq!(reader.seeks, 1);
+        // one block is at most the target size plus the record that overflowed it
+        assert!(reader.bytes_read <= DEFAULT_BLOCK_SIZE + 64);
+        assert!(reader.bytes_read < file_len / 10);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_rejects_layout_without_magic() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("old.sst");
+
+    // Layout written before the block index existed:
+    // [entry_count:4][bloom_size:4][bloom][records][min_key_len:4][min_key][max_key_len:4][max_key][footer_offset:8]
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    snaildb::utils::write_record(&mut bytes, snaildb::utils::RecordKind::Set, b"a", b"1", 0)?;
+    let footer_offset = bytes.len() as u64;
+    for key in ["a", "a"] {
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+    }
+    bytes.extend_from_slice(&footer_offset.to_le_bytes());
+    std::fs::write(&path, bytes)?;
+
+    let err = SsTable::load_metadata(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("not an sstable"));
+    Ok(())
+}
+
+#[test]
+fn test_sstable_rejects_truncated_file() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    SsTable::create(&path, sample_entries(1_000))?;
+    let bytes = std::fs::read(&path)?;
+
+    for len in [0, 3, 8, 21, bytes.len() / 2, bytes.len() - 1] {
+        std::fs::write(&path, &bytes[..len])?;
+        let err = SsTable::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "truncated to {len} bytes");
+        assert!(err.to_string().contains("not an sstable"), "{err}");
+        // a mapping shorter than the trailer must not panic either
+        #[cfg(feature = "mmap")]
+        {
+            let err = SsTable::open_mmap(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "mapped {len} bytes");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_rejects_corrupt_footer() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    SsTable::create(&path, sample_entries(1_000))?;
+    let bytes = std::fs::read(&path)?;
+    let trailer = bytes.len() - 18;
+    let footer_offset = u64::from_le_bytes(bytes[trailer..trailer + 8].try_into().unwrap()) as usize;
+    let corrupt_footer = |bytes: &[u8]| -> Result<String> {
+        std::fs::write(&path, bytes)?;
+        let err = SsTable::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let corrupt = err.get_ref().and_then(|inner| inner.downcast_ref::<CorruptFooter>());
+        assert!(corrupt.is_some(), "not a corrupt footer error: {err}");
+        assert!(SsTable::load(&path).is_err());
+        Ok(err.to_string())
+    };
+
+    // truncated anywhere inside the footer or trailer
+    for len in [footer_offset + 1, footer_offset + 7, trailer - 4, trailer, trailer + 9, bytes.len() - 1] {
+        corrupt_footer(&bytes[..len])?;
+    }
+    // shorter than the trailer
+    for len in [0, 5, 8, 17] {
+        corrupt_footer(&bytes[..len])?;
+    }
+
+    // a flipped bit in the keys, the section handles, or the crc itself fails the checksum
+    for pos in [footer_offset + 5, trailer - 20, trailer - 2] {
+        let mut damaged = bytes.clone();
+        damaged[pos] ^= 0x01;
+        let message = corrupt_footer(&damaged)?;
+        assert!(message.contains("crc mismatch"), "{message}");
+    }
+
+    // footer offsets pointing into the header or past the footer
+    for offset in [0u64, 3, 4, (trailer - 4) as u64, trailer as u64, u64::MAX] {
+        let mut damaged = bytes.clone();
+        damaged[trailer..trailer + 8].copy_from_slice(&offset.to_le_bytes());
+        let message = corrupt_footer(&damaged)?;
+        assert!(message.contains(&format!("footer offset {offset}")), "{message}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_rejects_bad_magic() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("random.sst");
+    std::fs::write(&path, random_bytes(3, 10_000))?;
+    let err = SsTable::load(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("not an sstable"), "{err}");
+
+    SsTable::create(&path, sample_entries(10))?;
+    let mut bytes = std::fs::read(&path)?;
+    *bytes.last_mut().unwrap() ^= 0xFF;
+    std::fs::write(&path, bytes)?;
+    let err = SsTable::open(&path).unwrap_err();
+    assert!(err.to_string().contains("not an sstable"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_sstable_rejects_future_version() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    SsTable::create(&path, sample_entries(10))?;
+
+    // the version sits right before the 8-byte magic
+    let mut bytes = std::fs::read(&path)?;
+    let version_pos = bytes.len() - 10;
+    assert_eq!(&bytes[version_pos..version_pos + 2], &FORMAT_VERSION.to_le_bytes());
+    bytes[version_pos..version_pos + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    std::fs::write(&path, bytes)?;
+
+    let err = SsTable::open(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let message = err.to_string();
+    assert!(message.contains(&format!("version {}", FORMAT_VERSION + 1)), "{message}");
+    assert!(message.contains(&format!("supported version {FORMAT_VERSION}")), "{message}");
+    Ok(())
+}
+
+#[test]
+fn test_sstable_bloom_filter_false_positive_rate() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    // Only even keys are written, so every odd key is inside [min_key, max_key] but absent
+    let entries: Vec<_> = (0..20_000)
+        .step_by(2)
+        .map(|i| (format!("key:{:06}", i), Value::from_bytes(b"v".to_vec())))
+        .collect();
+    SsTable::create(&path, entries.clone())?;
+    let table = SsTable::load_metadata(&path)?;
+
+    for (key, _) in &entries {
+        assert!(table.might_contain_key(key));
+    }
+    let false_positives = (1..20_000)
+        .step_by(2)
+        .filter(|i| table.might_contain_key(format!("key:{:06}", i)))
+        .count();
+    // 10 bits per key gives ~1% in theory, leave generous headroom
+    assert!(false_positives < 300, "too many false positives: {false_positives}");
+    Ok(())
+}
+
+#[test]
+fn test_sstable_bloom_filter_includes_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    SsTable::create(&path, sample_entries(1_000))?;
+    let table = SsTable::load_metadata(&path)?;
+
+    // every tenth key is a tombstone, the filter must not hide it
+    for i in (0..1_000).step_by(10) {
+        let key = format!("key:{:06}", i);
+        assert!(table.might_contain_key(&key));
+        assert!(matches!(table.get(&key)?, Some(Value::Deleted)));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_without_bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let options = SsTableOptions::default().with_bloom_bits_per_key(0);
+    SsTable::create_with_options(&path, sample_entries(100), &options)?;
+
+    let table = SsTable::load_metadata(&path)?;
+    assert!(table.metadata.bloom_filter.is_empty());
+    // falls back to the key range check
+    assert!(table.might_contain_key("key:000050x"));
+    assert!(!table.might_contain_key("key:000100"));
+    assert!(!table.might_contain_key("a"));
+    assert!(table.get("key:000050x")?.is_none());
+    assert!(table.get("key:000051")?.is_some());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_open_matches_load() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(3_000);
+    SsTable::create(&path, entries.clone())?;
+
+    let mut tables = open_all(&path)?;
+    let eager = tables.remove(0);
+    assert!(eager.is_loaded());
+
+    let probes: Vec<_> = entries
+        .iter()
+        .map(|(key, _)| key.clone())
+        .chain(["key:".to_string(), "key:0015005".to_string(), "zzz".to_string()])
+        .collect();
+    for lazy in &tables {
+        assert!(!lazy.is_loaded());
+        for key in &probes {
+            let lazy_value = lazy.get(key)?.map(|v| v.as_option());
+            let eager_value = eager.get(key)?.map(|v| v.as_option());
+            assert_eq!(lazy_value, eager_value, "mismatch for {key}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_iter_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(2_500);
+    let created = SsTable::create(&path, entries.clone())?;
+
+    let expected: Vec<_> = entries.iter().map(|(k, v)| (k.clone().into_bytes(), v.as_option())).collect();
+    for table in std::iter::once(created).chain(open_all(&path)?) {
+        let iterated = table.iter().collect::<io::Result<Vec<_>>>()?;
+        let actual: Vec<_> = iterated.iter().map(|(k, v)| (k.clone(), v.as_option())).collect();
+        assert_eq!(actual, expected);
+        // tombstones are visible to the iterator
+        assert!(matches!(iterated[0].1, Value::Deleted));
+
+        // re-creating a table from the iterator yields the same contents
+        let copy_path = temp_dir.path().join("copy.sst");
+        let copy = SsTable::create(&copy_path, iterated)?;
+        let copied: Vec<_> = copy
+            .iter()
+            .map(|item| item.map(|(k, v)| (k, v.as_option())))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(copied, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_iter_double_ended() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(2_000);
+    SsTable::create(&path, entries.clone())?;
+    for table in open_all(&path)? {
+        assert!(table.block_count() > 2);
+
+        let reversed = table.iter().rev().collect::<io::Result<Vec<_>>>()?;
+        let keys: Vec<_> = reversed.into_iter().map(|(k, _)| k).collect();
+        let expected: Vec<_> = entries.iter().rev().map(|(k, _)| k.clone().into_bytes()).collect();
+        assert_eq!(keys, expected);
+
+        // consuming from both ends meets in the middle without losing or repeating entries
+        let mut iter = table.iter();
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        while let Some(item) = iter.next() {
+            front.push(item?.0);
+            match iter.next_back() {
+                Some(item) => back.push(item?.0),
+                None => break,
+            }
+        }
+        back.reverse();
+        front.extend(back);
+        let all: Vec<_> = entries.iter().map(|(k, _)| k.clone().into_bytes()).collect();
+        assert_eq!(front, all);
+    }
+    Ok(())
+}
+
+fn collect_keys(iter: snaildb::storage::sstable::Iter<'_>) -> io::Result<Vec<String>> {
+    iter.map(|item| item.map(|(key, _)| String::from_utf8(key).expect("utf-8 key"))).collect()
+}
+
+#[test]
+fn test_sstable_range_bounds() -> Result<()> {
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(3_000);
+    SsTable::create(&path, entries.clone())?;
+    let keys: Vec<String> = entries.iter().map(|(k, _)| k.clone()).collect();
+
+    for table in open_all(&path)? {
+        assert_eq!(collect_keys(table.range::<str>(Unbounded, Unbounded))?, keys);
+        assert_eq!(
+            collect_keys(table.range(Included("key:001000"), Excluded("key:001010")))?,
+            keys[1000..1010].to_vec()
+        );
+        assert_eq!(
+            collect_keys(table.range(Excluded("key:001000"), Included("key:001010")))?,
+            keys[1001..=1010].to_vec()
+        );
+        // bounds between existing keys
+        assert_eq!(
+            collect_keys(table.range(Included("key:0019995"), Unbounded))?,
+            keys[2000..].to_vec()
+        );
+        assert_eq!(collect_keys(table.range(Unbounded, Excluded("key:000003")))?, keys[..3].to_vec());
+        // ranges that span many blocks
+        assert_eq!(
+            collect_keys(table.range(Included("key:000100"), Included("key:002900")))?,
+            keys[100..=2900].to_vec()
+        );
+        // tombstones are included
+        let (_, first) = table.range(Included("key:000010"), Unbounded).next().unwrap()?;
+        assert!(matches!(first, Value::Deleted));
+
+        // empty cases
+        assert!(table.range(Excluded("key:002999"), Unbounded).next().is_none());
+        assert!(table.range(Included("zzz"), Unbounded).next().is_none());
+        assert!(table.range(Unbounded, Excluded("key:000000")).next().is_none());
+        assert!(table.range(Included("key:002000"), Excluded("key:001000")).next().is_none());
+        assert!(table.range(Included("key:001000"), Excluded("key:001000")).next().is_none());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_range_rev() -> Result<()> {
+    use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(3_000);
+    SsTable::create(&path, entries.clone())?;
+
+    let bounds: [(Bound<&str>, Bound<&str>); 8] = [
+        (Unbounded, Unbounded),
+        (Included("key:001000"), Excluded("key:001010")),
+        // key:002000 and key:000990 are tombstones
+        (Unbounded, Included("key:002000")),
+        (Included("key:000100"), Included("key:000990")),
+        (Excluded("key:000100"), Excluded("key:000990")),
+        (Included("key:0019995"), Unbounded),
+        (Included("key:002999"), Included("key:002999")),
+        (Included("key:002000"), Excluded("key:001000")),
+    ];
+    for table in open_all(&path)? {
+        for (start, end) in bounds {
+            let mut forward = collect_keys(table.range(start, end))?;
+            forward.reverse();
+            let backward = table
+                .range(start, end)
+                .rev()
+                .map(|item| item.map(|(key, _)| String::from_utf8(key).expect("utf-8 key")))
+                .collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(backward, forward, "{start:?}..{end:?}");
+        }
+
+        // a reverse scan ending on a tombstone yields it first
+        let (key, value) = table.range(Unbounded, Included("key:002000")).next_back().unwrap()?;
+        assert_eq!(key, b"key:002000");
+        assert!(matches!(value, Value::Deleted));
+        let (key, _) = table.range(Unbounded, Excluded("key:002000")).next_back().unwrap()?;
+        assert_eq!(key, b"key:001999");
+        let latest: Vec<_> = table
+            .iter()
+            .rev()
+            .take(3)
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(latest, [b"key:002999", b"key:002998", b"key:002997"]);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_writer_streams_large_table() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("large.sst");
+    let count = 300_000;
+    let key_of = |i: usize| format!("user:{:08}", i);
+    let value_of = |i: usize| {
+        if i.is_multiple_of(7) {
+            Value::tombstone()
+        } else {
+            Value::from_bytes(format!("payload-{}", i * 31).into_bytes())
+        }
+    };
+
+    let mut writer = SsTableWriter::new(&path, &SsTableOptions::default())?;
+    for i in 0..count {
+        writer.add(key_of(i), &value_of(i))?;
+    }
+    assert_eq!(writer.len(), count);
+    let written = writer.finish()?;
+    assert!(!written.is_loaded());
+
+    let loaded = SsTable::load(&path)?;
+    let mut read_back = 0;
+    for (i, item) in loaded.iter().enumerate() {
+        let (key, value) = item?;
+        assert_eq!(key, key_of(i).as_bytes());
+        assert_eq!(value.as_option(), value_of(i).as_option());
+        read_back += 1;
+    }
+    assert_eq!(read_back, count);
+    assert_eq!(written.get(key_of(123_456))?.and_then(|v| v.as_option()), value_of(123_456).as_option());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_writer_rejects_unsorted_keys() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut writer = SsTableWriter::new(temp_dir.path().join("a.sst"), &SsTableOptions::default())?;
+    writer.add("b", &Value::from_bytes(b"1".to_vec()))?;
+
+    let err = writer.add("b", &Value::from_bytes(b"2".to_vec())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("duplicate key"));
+
+    let err = writer.add("a", &Value::tombstone()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("out-of-order key"));
+
+    // rejected keys leave the writer usable
+    writer.add("c", &Value::from_bytes(b"3".to_vec()))?;
+    let table = writer.finish()?;
+    assert_eq!(collect_keys(table.iter())?, vec!["b".to_string(), "c".to_string()]);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_create_validates_input() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let value = || Value::from_bytes(b"v".to_vec());
+
+    let err = SsTable::create(&path, Vec::<(String, Value)>::new()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!path.exists());
+
+    let duplicate = vec![("a".to_string(), value()), ("b".to_string(), value()), ("b".to_string(), value())];
+    let err = SsTable::create(&path, duplicate).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("duplicate key \"b\""), "{err}");
+    assert!(!path.exists());
+
+    let descending: Vec<_> = ["c", "b", "a"].iter().map(|k| (k.to_string(), value())).collect();
+    let err = SsTable::create(&path, descending).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("out-of-order key \"b\" after \"c\""), "{err}");
+    assert!(!path.exists());
+
+    let entries = sample_entries(100);
+    SsTable::create(&path, entries.clone())?;
+    let expected: Vec<_> = entries.into_iter().map(|(k, v)| (k, v.as_option())).collect();
+    assert_eq!(collect_entries(&SsTable::load(&path)?)?, expected);
+
+    // a file whose records are out of order is rejected by `load` (swap the first two records)
+    let mut bytes = std::fs::read(&path)?;
+    let first_len = 8 + u32::from_le_bytes(bytes[5..9].try_into()?) as usize;
+    let second_len = 8 + u32::from_le_bytes(bytes[5 + first_len..9 + first_len].try_into()?) as usize;
+    bytes[5..5 + first_len + second_len].rotate_left(first_len);
+    std::fs::write(&path, bytes)?;
+    let err = SsTable::load(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("out of order"), "{err}");
+    Ok(())
+}
+
+/// Deterministic xorshift bytes, effectively incompressible.
+fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn json_entries(count: usize) -> Vec<(String, Value)> {
+    (0..count)
+        .map(|i| {
+            let json = format!(
+                r#"{{"id":{i},"name":"user number {i}","email":"user{i}@example.com","active":true,"tags":["alpha","beta","gamma"]}}"#
+            );
+            (format!("doc:{:06}", i), Value::from_bytes(json.into_bytes()))
+        })
+        .collect()
+}
+
+fn available_codecs() -> Vec<Compression> {
+    [Compression::None, Compression::Lz4, Compression::Zstd]
+        .into_iter()
+        .filter(|codec| codec.is_available())
+        .collect()
+}
+
+#[test]
+fn test_sstable_compression_roundtrip_incompressible() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let entries: Vec<_> = (0..500)
+        .map(|i| (format!("blob:{:04}", i), Value::from_bytes(random_bytes(i as u64 + 1, 300))))
+        .collect();
+
+    for codec in available_codecs() {
+        let path = temp_dir.path().join(format!("{}.sst", codec.name()));
+        let options = SsTableOptions::default().with_compression(codec);
+        SsTable::create_with_options(&path, entries.clone(), &options)?;
+        for table in open_all(&path)? {
+            for (key, value) in &entries {
+                assert_eq!(table.get(key)?.and_then(|v| v.as_option()), value.as_option());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_compression_shrinks_compressible_data() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let entries = json_entries(2_000);
+    let plain_path = temp_dir.path().join("plain.sst");
+    SsTable::create(&plain_path, entries.clone())?;
+    let plain_size = std::fs::metadata(&plain_path)?.len();
+
+    for codec in available_codecs().into_iter().filter(|c| *c != Compression::None) {
+        let path = temp_dir.path().join(format!("{}.sst", codec.name()));
+        let options = SsTableOptions::default().with_compression(codec);
+        SsTable::create_with_options(&path, entries.clone(), &options)?;
+        let size = std::fs::metadata(&path)?.len();
+        assert!(size * 2 < plain_size, "{} did not compress: {size} vs {plain_size}", codec.name());
+
+        let table = SsTable::open(&path)?;
+        let read_back = table.iter().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(read_back.len(), entries.len());
+        for ((key, value), (expected_key, expected_value)) in read_back.iter().zip(&entries) {
+            assert_eq!(key, expected_key.as_bytes());
+            assert_eq!(value.as_option(), expected_value.as_option());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_record_limits() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let limits = RecordLimits::default().with_max_key_len(8).with_max_value_len(16);
+    let options = SsTableOptions::default().with_record_limits(limits);
+
+    // Keys and values exactly at the limits are written, typed values counted by their bytes
+    let path = temp_dir.path().join("at_limit.sst");
+    let entries = vec![
+        ("a".repeat(8), Value::from_bytes(vec![1; 16])),
+        ("b".repeat(8), Value::Str("s".repeat(16))),
+        ("c".repeat(8), Value::tombstone()),
+    ];
+    let table = SsTable::create_with_options(&path, entries.clone(), &options)?;
+    for (key, value) in &entries {
+        assert_eq!(table.get(key)?.as_ref(), Some(value), "{key}");
+    }
+
+    // One byte over fails and leaves no table behind
+    let over = [
+        (("a".repeat(9), Value::from_bytes(vec![1])), RecordError::KeyTooLarge { len: 9, max: 8 }),
+        (("a".to_string(), Value::from_bytes(vec![1; 17])), RecordError::ValueTooLarge { len: 17, max: 16 }),
+        (("a".to_string(), Value::Str("s".repeat(17))), RecordError::ValueTooLarge { len: 17, max: 16 }),
+    ];
+    for (entry, expected) in over {
+        let path = temp_dir.path().join("over.sst");
+        let err = SsTable::create_with_options(&path, [entry], &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.get_ref().and_then(|err| err.downcast_ref()), Some(&expected));
+        assert!(!path.exists());
+    }
+
+    // The defaults take keys up to 64 KiB
+    let path = temp_dir.path().join("default.sst");
+    let key = "k".repeat(RecordLimits::DEFAULT_MAX_KEY_LEN);
+    SsTable::create(&path, [(key.clone(), Value::from_bytes(b"v".to_vec()))])?;
+    let err = SsTable::create(&path, [(key + "k", Value::from_bytes(b"v".to_vec()))]).unwrap_err();
+    assert!(matches!(err.get_ref().and_then(|err| err.downcast_ref()), Some(RecordError::KeyTooLarge { .. })));
+    Ok(())
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+#[test]
+fn test_sstable_value_compression() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let large = |i: usize| format!("{i:06}").repeat(200).into_bytes();
+    let entries: Vec<(String, Value)> = (0..200)
+        .map(|i| match i % 3 {
+            0 => (format!("key:{i:06}"), Value::from_bytes(large(i))),
+            1 => (format!("key:{i:06}"), Value::from_bytes(format!("small:{i}").into_bytes())),
+            _ => (format!("key:{i:06}"), Value::tombstone()),
+        })
+        .collect();
+    let plain_path = temp_dir.path().join("plain.sst");
+    SsTable::create(&plain_path, entries.clone())?;
+    let plain_size = std::fs::metadata(&plain_path)?.len();
+
+    for codec in [Compression::Lz4, Compression::Zstd].into_iter().filter(|codec| codec.is_available()) {
+        for prefix_compression in [false, true] {
+            let path = temp_dir.path().join(format!("{codec:?}-{prefix_compression}.sst"));
+            let options = SsTableOptions::default()
+                .with_value_compression(ValueCompression::new(codec, 64))
+                .with_prefix_compression(prefix_compression);
+            SsTable::create_with_options(&path, entries.clone(), &options)?;
+            assert!(std::fs::metadata(&path)?.len() < plain_size / 2, "{codec:?}");
+
+            let table = SsTable::open(&path)?;
+            for (key, value) in &entries {
+                assert_eq!(table.get(key)?.as_ref(), Some(value), "{key}");
+            }
+            let read: Vec<_> = table.iter().collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(read.len(), entries.len());
+            for ((key, value), (expected_key, expected_value)) in read.iter().zip(&entries) {
+                assert_eq!((key.as_slice(), value), (expected_key.as_bytes(), expected_value));
+            }
+            assert_eq!(SsTable::load(&path)?.get("key:000003")?, Some(Value::from_bytes(large(3))));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "lz4"))]
+#[test]
+fn test_sstable_compression_missing_codec_feature() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("lz4.sst");
+    SsTable::create(&path, sample_entries(10))?;
+
+    // the first block starts right after the 4-byte header, flag it as lz4
+    let mut bytes = std::fs::read(&path)?;
+    bytes[4] = 1;
+    std::fs::write(&path, bytes)?;
+
+    let table = SsTable::open(&path)?;
+    let err = table.get("key:000001").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("lz4"), "{err}");
+
+    let options = SsTableOptions::default().with_compression(Compression::Lz4);
+    let err = SsTable::create_with_options(temp_dir.path().join("new.sst"), sample_entries(10), &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let options = SsTableOptions::default().with_value_compression(ValueCompression::new(Compression::Lz4, 0));
+    let err = SsTable::create_with_options(temp_dir.path().join("new.sst"), sample_entries(10), &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+fn set(key: &str, value: &str) -> (String, Value) {
+    (key.to_string(), Value::from_bytes(value.as_bytes().to_vec()))
+}
+
+fn delete(key: &str) -> (String, Value) {
+    (key.to_string(), Value::tombstone())
+}
+
+fn collect_entries(table: &SsTable) -> io::Result<Vec<(String, Option<Vec<u8>>)>> {
+    table
+        .iter()
+        .map(|item| item.map(|(key, value)| (String::from_utf8(key).expect("utf-8 key"), value.as_option())))
+        .collect()
+}
+
+#[test]
+fn test_sstable_merge_overlapping_ranges() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let old = SsTable::create(dir.join("old.sst"), (0..2_000).map(|i| set(&format!("key:{:05}", i), "old")))?;
+    let new = SsTable::create(
+        dir.join("new.sst"),
+        (1_000..3_000).step_by(2).map(|i| set(&format!("key:{:05}", i), "new")),
+    )?;
+
+    let merged = SsTable::merge(dir.join("merged.sst"), &[old, new])?;
+    let entries = collect_entries(&merged)?;
+    assert_eq!(entries.len(), 2_500);
+    for (key, value) in &entries {
+        let i: usize = key["key:".len()..].parse()?;
+        let expected: &[u8] = if i >= 1_000 && i.is_multiple_of(2) { b"new" } else { b"old" };
+        assert_eq!(value.as_deref(), Some(expected), "{key}");
+    }
+    assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_disjoint_ranges() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let first = sample_entries(1_500);
+    let second: Vec<_> = (0..1_500).map(|i| set(&format!("other:{:06}", i), "v")).collect();
+    let inputs = [
+        SsTable::create(dir.join("b.sst"), second.clone())?,
+        SsTable::open(SsTable::create(dir.join("a.sst"), first.clone())?.path())?,
+    ];
+
+    let merged = SsTable::merge(dir.join("merged.sst"), &inputs)?;
+    let mut expected: Vec<_> = first.into_iter().chain(second).map(|(k, v)| (k, v.as_option())).collect();
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(collect_entries(&merged)?, expected);
+    assert_eq!(collect_entries(&SsTable::load(merged.path())?)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_shadowed_deletes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let oldest = SsTable::create(dir.join("1.sst"), vec![set("a", "1"), set("b", "1"), set("c", "1")])?;
+    let middle = SsTable::create(dir.join("2.sst"), vec![delete("a"), set("b", "2"), delete("d")])?;
+    let newest = SsTable::create(dir.join("3.sst"), vec![set("a", "3"), delete("b")])?;
+    let inputs = [oldest, middle, newest];
+
+    let merged = SsTable::merge(dir.join("merged.sst"), &inputs)?;
+    assert_eq!(
+        collect_entries(&merged)?,
+        vec![
+            ("a".to_string(), Some(b"3".to_vec())),
+            ("b".to_string(), None),
+            ("c".to_string(), Some(b"1".to_vec())),
+            ("d".to_string(), None),
+        ]
+    );
+    assert!(matches!(merged.get("b")?, Some(Value::Deleted)));
+
+    // at the bottom level the tombstones can go
+    let options = MergeOptions::default().with_drop_tombstones(true);
+    let compacted = SsTable::merge_with_options(dir.join("bottom.sst"), &inputs, &options)?.unwrap();
+    assert_eq!(
+        collect_entries(&compacted)?,
+        vec![("a".to_string(), Some(b"3".to_vec())), ("c".to_string(), Some(b"1".to_vec()))]
+    );
+    assert!(compacted.get("b")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_rejects_invalid_output() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let tombstones = SsTable::create(dir.join("tombstones.sst"), vec![delete("a"), delete("b")])?;
+    let inputs = [tombstones];
+
+    let err = SsTable::merge(dir.join("tombstones.sst"), &inputs).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(collect_entries(&inputs[0])?.len(), 2);
+
+    let err = SsTable::merge(dir.join("nothing.sst"), &[]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!dir.join("nothing.sst").exists());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_drop_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let old = SsTable::create(
+        dir.join("old.sst"),
+        (0..1_000).map(|i| set(&format!("key:{:04}", i), "old")),
+    )?;
+    // delete both ends and every third key
+    let new = SsTable::create(
+        dir.join("new.sst"),
+        (0..1_000)
+            .filter(|i: &usize| i.is_multiple_of(3) || *i > 990)
+            .map(|i| delete(&format!("key:{:04}", i))),
+    )?;
+    let inputs = [old, new];
+
+    let options = MergeOptions::default().with_drop_tombstones(true);
+    let merged = SsTable::merge_with_options(dir.join("merged.sst"), &inputs, &options)?.unwrap();
+    let keys = collect_keys(merged.iter())?;
+    let expected: Vec<_> = (0..=990).filter(|i: &usize| !i.is_multiple_of(3)).map(|i| format!("key:{:04}", i)).collect();
+    assert_eq!(keys, expected);
+    // the key range only covers the surviving entries
+    assert_eq!(merged.stats().min_key, b"key:0001");
+    assert_eq!(merged.stats().max_key, b"key:0989");
+    assert_eq!(merged.stats().tombstones, 0);
+    assert!(!merged.might_contain_key("key:0000"));
+    assert!(!merged.might_contain_key("key:0999"));
+    assert!(merged.get("key:0300")?.is_none());
+
+    // only tombstones survive the merge: no table is written
+    let tombstones = [SsTable::create(dir.join("tombstones.sst"), vec![delete("a"), delete("b")])?];
+    let output = dir.join("empty.sst");
+    assert!(SsTable::merge_with_options(&output, &tombstones, &options)?.is_none());
+    assert!(!output.exists());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_split() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let inputs = [
+        SsTable::create(dir.join("1.sst"), (0..20_000).map(|i| set(&format!("key:{:06}", i), "first")))?,
+        SsTable::create(
+            dir.join("2.sst"),
+            (0..20_000).step_by(3).map(|i| delete(&format!("key:{:06}", i))),
+        )?,
+        SsTable::create(
+            dir.join("3.sst"),
+            (10_000..30_000).step_by(2).map(|i| set(&format!("key:{:06}", i), "third")),
+        )?,
+    ];
+
+    for drop_tombstones in [false, true] {
+        let options = MergeOptions::default().with_drop_tombstones(drop_tombstones);
+        let single = SsTable::merge_with_options(dir.join("single.sst"), &inputs, &options)?.unwrap();
+        let expected = collect_entries(&single)?;
+
+        let target = 64 * 1024;
+        let options = options.with_target_file_size(target);
+        let outputs = SsTable::merge_split(dir.join("split.sst"), &inputs, &options)?;
+        assert!(outputs.len() > 3, "{} outputs", outputs.len());
+        let mut actual = Vec::new();
+        for (n, output) in outputs.iter().enumerate() {
+            assert_eq!(output.path(), dir.join(format!("split.{n}.sst")));
+            assert!(SsTable::verify(output.path())?.is_ok());
+            // a table is only finished once its data reaches the target
+            let size = output.stats().file_size;
+            if n + 1 < outputs.len() {
+                assert!(size >= target, "output {n} is only {size} bytes");
+            }
+            assert!(size < target + 2 * DEFAULT_BLOCK_SIZE as u64 + 16 * 1024, "output {n} is {size} bytes");
+            actual.extend(collect_entries(&SsTable::open(output.path())?)?);
+        }
+        assert_eq!(actual, expected);
+        assert!(outputs.windows(2).all(|pair| pair[0].stats().max_key < pair[1].stats().min_key));
+        assert_eq!(outputs[0].stats().min_key, single.stats().min_key);
+        assert_eq!(outputs[outputs.len() - 1].stats().max_key, single.stats().max_key);
+    }
+
+    // without a target size everything goes to one table
+    let outputs = SsTable::merge_split(dir.join("whole.sst"), &inputs, &MergeOptions::default())?;
+    assert_eq!(outputs.len(), 1);
+    assert_eq!(outputs[0].path(), dir.join("whole.0.sst"));
+
+    // nothing survives, nothing is written
+    let tombstones = [SsTable::create(dir.join("tombstones.sst"), vec![delete("a"), delete("b")])?];
+    let options = MergeOptions::default().with_drop_tombstones(true).with_target_file_size(1);
+    assert!(SsTable::merge_split(dir.join("empty.sst"), &tombstones, &options)?.is_empty());
+    assert!(!dir.join("empty.0.sst").exists());
+
+    // running into an input removes the outputs written before it
+    let input = [SsTable::create(dir.join("out.2.sst"), (0..100).map(|i| set(&format!("k{i:03}"), "v")))?];
+    let options = MergeOptions::default().with_target_file_size(1);
+    let err = SsTable::merge_split(dir.join("out.sst"), &input, &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!dir.join("out.0.sst").exists() && !dir.join("out.1.sst").exists());
+    assert_eq!(collect_entries(&input[0])?.len(), 100);
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_sstable_open_mmap() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(3_000);
+    SsTable::create(&path, entries.clone())?;
+
+    let table = SsTable::open_mmap(&path)?;
+    assert!(!table.is_loaded());
+    assert!(table.block_count() > 1);
+    for (key, value) in &entries {
+        assert_eq!(table.get(key)?.map(|v| v.as_option()), Some(value.as_option()));
+    }
+    assert!(table.get("key:0015005")?.is_none());
+    assert_eq!(table.iter().count(), entries.len());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_prefix_compression() -> Result<()> {
+    use std::ops::Bound::{Included, Unbounded};
+
+    let temp_dir = TempDir::new()?;
+    let mut entries: Vec<_> = (0..2_000u64)
+        .flat_map(|i| {
+            let id = format!("{:016x}-{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i);
+            ["profile", "settings", "sessions/ééé"]
+                .into_iter()
+                .map(move |field| (format!("user/{id}/{field}"), Value::from_bytes(b"v".to_vec())))
+        })
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries[5].1 = Value::tombstone();
+
+    let plain = temp_dir.path().join("plain.sst");
+    let prefixed = temp_dir.path().join("prefixed.sst");
+    SsTable::create(&plain, entries.clone())?;
+    let options = SsTableOptions::default().with_prefix_compression(true);
+    SsTable::create_with_options(&prefixed, entries.clone(), &options)?;
+
+    let plain_len = std::fs::metadata(&plain)?.len();
+    let prefixed_len = std::fs::metadata(&prefixed)?.len();
+    assert!(prefixed_len * 10 < plain_len * 7, "{prefixed_len} vs {plain_len}");
+
+    let expected: Vec<_> = entries.iter().map(|(k, v)| (k.clone(), v.as_option())).collect();
+    for table in open_all(&prefixed)? {
+        assert_eq!(collect_entries(&table)?, expected);
+        for (key, value) in &entries {
+            assert_eq!(table.get(key)?.map(|v| v.as_option()), Some(value.as_option()), "{key}");
+        }
+        assert!(table.get("user/")?.is_none());
+        assert!(table.get(format!("{}x", entries[100].0))?.is_none());
+        let from = &entries[1_234].0;
+        assert_eq!(collect_keys(table.range(Included(from), Unbounded))?.len(), entries.len() - 1_234);
+    }
+
+    for codec in available_codecs() {
+        let path = temp_dir.path().join(format!("prefixed-{}.sst", codec.name()));
+        let options = options.clone().with_compression(codec);
+        SsTable::create_with_options(&path, entries.clone(), &options)?;
+        assert_eq!(collect_entries(&SsTable::open(&path)?)?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_verify_reports_corruption() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    // ten records of exactly 14 bytes each: [length:4][crc32:4][kind][key_len][key:2][value_len][value]
+    let entries: Vec<_> = (0..10).map(|i| set(&format!("k{i}"), "v")).collect();
+    let record = |i: usize| 4 + 1 + 14 * i..4 + 1 + 14 * (i + 1);
+    SsTable::create(&path, entries)?;
+    let bytes = std::fs::read(&path)?;
+
+    let report = SsTable::verify(&path)?;
+    assert!(report.is_ok(), "{:?}", report.errors);
+    assert_eq!(report.entries_checked, 10);
+
+    // truncated: the footer is gone
+    std::fs::write(&path, &bytes[..bytes.len() - 5])?;
+    let report = SsTable::verify(&path)?;
+    assert!(matches!(report.errors[..], [VerifyError::Metadata(_)]), "{:?}", report.errors);
+
+    // reordered: swap the records of k1 and k2
+    let mut reordered = bytes.clone();
+    let (k1, k2) = (reordered[record(1)].to_vec(), reordered[record(2)].to_vec());
+    reordered[record(1)].copy_from_slice(&k2);
+    reordered[record(2)].copy_from_slice(&k1);
+    std::fs::write(&path, &reordered)?;
+    let report = SsTable::verify(&path)?;
+    assert_eq!(report.entries_checked, 10);
+    assert!(
+        matches!(&report.errors[..], [VerifyError::OutOfOrder { previous, key }] if previous == b"k2" && key == b"k1"),
+        "{:?}",
+        report.errors
+    );
+
+    // wrong entry count in the header
+    let mut miscounted = bytes.clone();
+    miscounted[..4].copy_from_slice(&12u32.to_le_bytes());
+    std::fs::write(&path, &miscounted)?;
+    let report = SsTable::verify(&path)?;
+    assert!(
+        matches!(report.errors[..], [VerifyError::EntryCount { header: 12, actual: 10 }]),
+        "{:?}",
+        report.errors
+    );
+
+    // a flipped bit fails the record checksum, and every problem is collected
+    let mut corrupted = miscounted.clone();
+    corrupted[record(4).end - 1] ^= 0x01;
+    std::fs::write(&path, &corrupted)?;
+    let report = SsTable::verify(&path)?;
+    assert!(matches!(report.errors[0], VerifyError::Block { block: 0, .. }), "{:?}", report.errors);
+    assert!(report.errors.len() > 1);
+    assert!(report.errors[0].to_string().contains("crc mismatch"));
+
+    let report = SsTable::verify_with_options(&path, &VerifyOptions::default().with_fail_fast(true))?;
+    assert_eq!(report.errors.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_stats() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(2_000);
+    let created = SsTable::create(&path, entries.clone())?;
+
+    let expected = Stats {
+        file_size: std::fs::metadata(&path)?.len(),
+        entries: 2_000,
+        tombstones: 200,
+        key_bytes: entries.iter().map(|(k, _)| k.len() as u64).sum(),
+        value_bytes: entries
+            .iter()
+            .filter_map(|(_, v)| v.as_option())
+            .map(|v| v.len() as u64)
+            .sum(),
+        min_key: b"key:000000".to_vec(),
+        max_key: b"key:001999".to_vec(),
+        min_seqno: 0,
+        max_seqno: 0,
+    };
+    assert_eq!(created.stats(), &expected);
+    for table in open_all(&path)? {
+        assert_eq!(table.stats(), &expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_stats_for_version_1_table() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(500);
+    let expected = SsTable::create(&path, entries)?.stats().clone();
+
+    // rewrite the file in the version 1 layout, which has no stats block
+    let bytes = std::fs::read(&path)?;
+    let u64_at = |pos: usize| u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+    let trailer = bytes.len() - 18;
+    let footer_offset = u64_at(trailer) as usize;
+    // six [offset:8][len:8] handles: filter, index, stats, properties, prefix filter and
+    // range deletions, followed by the footer crc
+    let handles = trailer - 4 - 96;
+    let stats_offset = u64_at(handles + 32) as usize;
+    let mut legacy = bytes[..stats_offset].to_vec();
+    legacy.extend_from_slice(&bytes[footer_offset..handles + 32]);
+    legacy.extend_from_slice(&(stats_offset as u64).to_le_bytes());
+    legacy.extend_from_slice(&1u16.to_le_bytes());
+    legacy.extend_from_slice(&bytes[bytes.len() - 8..]);
+    std::fs::write(&path, &legacy)?;
+
+    let table = SsTable::open(&path)?;
+    assert_eq!(table.stats(), &Stats { file_size: legacy.len() as u64, ..expected });
+    assert_eq!(table.iter().count(), 500);
+    // properties came after version 1
+    assert_eq!(table.format_version(), 1);
+    assert!(table.properties().is_empty());
+    assert!(table.created_at().is_none());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_reads_version_1_fixture() -> Result<()> {
+    // Twenty keys written in the version 1 layout, every fifth one deleted
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sstable-v1.sst");
+    for table in open_all(&fixture)? {
+        assert_eq!(table.format_version(), 1);
+        assert_eq!(table.iter().count(), 20);
+        assert_eq!(table.get("key:03")?, Some(Value::from_bytes(b"value:3".to_vec())));
+        assert_eq!(table.get("key:05")?, Some(Value::tombstone()));
+        assert_eq!(table.get("key:20")?, None);
+    }
+
+    // A table of a version that came later is refused, naming both versions
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("future.sst");
+    let mut bytes = std::fs::read(&fixture)?;
+    let version = bytes.len() - 10;
+    bytes[version..version + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    std::fs::write(&path, &bytes)?;
+    let err = SsTable::open(&path).unwrap_err();
+    let expected = format!("version {} is newer than the supported version {}", FORMAT_VERSION + 1, FORMAT_VERSION);
+    assert!(err.to_string().contains(&expected), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_get_reader_streams_values() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let large: Vec<u8> = (0..1_000_000u32).map(|i| (i % 253) as u8).collect();
+    let build = |path: &Path, options: &SsTableOptions| -> Result<()> {
+        let mut writer = SsTableWriter::new(path, options)?;
+        writer.add("a", &Value::from_bytes(b"first".to_vec()))?;
+        writer.add("b", &Value::tombstone())?;
+        writer.add("blob", &Value::from_bytes(large.clone()))?;
+        writer.add("count", &Value::Int(7))?;
+        writer.add_with_expiry("expired", b"token", 2_000)?;
+        writer.add_with_expiry("expiring", b"token", 4_000)?;
+        writer.add("hits", &Value::merge_operand(b"+1".to_vec()))?;
+        writer.add("ranged", &Value::from_bytes(b"gone".to_vec()))?;
+        writer.add_range_delete("ranged", "ranged\0", 1);
+        writer.finish()?;
+        Ok(())
+    };
+    build(&path, &SsTableOptions::default())?;
+
+    let read = |table: &SsTable, key: &str| -> Result<Option<Vec<u8>>> {
+        let Some(mut reader) = table.get_reader(key)? else {
+            return Ok(None);
+        };
+        let mut value = Vec::new();
+        reader.read_to_end(&mut value)?;
+        Ok(Some(value))
+    };
+    for table in open_all(&path)? {
+        let table = table.with_clock(|| 3_000);
+        assert_eq!(read(&table, "a")?, Some(b"first".to_vec()));
+        assert_eq!(read(&table, "blob")?, Some(large.clone()));
+        assert_eq!(read(&table, "count")?, Some(7i64.to_le_bytes().to_vec()));
+        assert_eq!(read(&table, "expiring")?, Some(b"token".to_vec()));
+        for key in ["b", "expired", "ranged", "missing", "0", "z"] {
+            assert_eq!(read(&table, key)?, None, "{key}");
+        }
+        assert_eq!(table.get_reader("hits").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // Partial reads and seeks of the large value of the lazily opened table
+    let table = SsTable::open(&path)?;
+    let mut reader = table.get_reader("blob")?.expect("a value");
+    assert_eq!(reader.len(), 1_000_000);
+    let mut part = [0u8; 16];
+    reader.read_exact(&mut part)?;
+    assert_eq!(part, large[..16]);
+    reader.seek(SeekFrom::Start(999_000))?;
+    reader.read_exact(&mut part)?;
+    assert_eq!(part, large[999_000..999_016]);
+    let mut copied = Vec::new();
+    reader.seek(SeekFrom::Start(0))?;
+    assert_eq!(io::copy(&mut reader, &mut copied)?, 1_000_000);
+    assert_eq!(copied, large);
+
+    // Values of compressed blocks are decompressed into memory first
+    #[cfg(feature = "lz4")]
+    {
+        let path = temp_dir.path().join("compressed.sst");
+        build(&path, &SsTableOptions::default().with_compression(Compression::Lz4))?;
+        let table = SsTable::open(&path)?;
+        assert_eq!(read(&table, "blob")?, Some(large.clone()));
+        assert_eq!(read(&table, "b")?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_properties() -> Result<()> {
+    use std::time::{Duration, SystemTime};
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let before = SystemTime::now() - Duration::from_millis(1);
+    let options = SsTableOptions::default()
+        .with_property("level", "2")
+        .with_property("origin", "compaction");
+    let created = SsTable::create_with_options(&path, sample_entries(100), &options)?;
+    let after = SystemTime::now();
+
+    for table in std::iter::once(created).chain(open_all(&path)?) {
+        assert_eq!(table.format_version(), FORMAT_VERSION);
+        let properties = table.properties();
+        assert_eq!(properties.get("level").map(String::as_str), Some("2"));
+        assert_eq!(properties.get("origin").map(String::as_str), Some("compaction"));
+        assert!(properties.contains_key("snaildb.created_at"));
+        let created_at = table.created_at().expect("creation time recorded");
+        assert!(before <= created_at && created_at <= after);
+    }
+
+    let reserved = SsTableOptions::default().with_property("snaildb.created_at", "0");
+    let err = SsTable::create_with_options(&path, sample_entries(10), &reserved).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("reserved"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_sstable_lazy_point_reads_read_one_block_each() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("large.sst");
+    let entries = sample_entries(200_000);
+    SsTable::create(&path, entries.clone())?;
+    let probes: Vec<_> = (0..200).map(|i| entries[i * 997].0.clone()).collect();
+
+    // Opening reads the footer, filter and index only, each get a single block through the cache
+    let cache = Arc::new(BlockCache::new(1 << 30));
+    let lazy = SsTable::open(&path)?.with_block_cache(Arc::clone(&cache));
+    assert!(!lazy.is_loaded());
+    let lazy_values = probes.iter().map(|key| lazy.get(key)).collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(cache.hits() + cache.misses(), probes.len() as u64);
+    assert!(cache.len() <= probes.len());
+    assert!(cache.len() * 5 < lazy.block_count(), "{} of {} blocks read", cache.len(), lazy.block_count());
+
+    let eager = SsTable::load(&path)?;
+    assert!(eager.is_loaded());
+    let eager_values = probes.iter().map(|key| eager.get(key)).collect::<io::Result<Vec<_>>>()?;
+    let as_options = |values: Vec<Option<Value>>| {
+        values.into_iter().map(|v| v.map(|v| v.as_option())).collect::<Vec<_>>()
+    };
+    assert_eq!(as_options(lazy_values), as_options(eager_values));
+    Ok(())
+}
+
+#[test]
+#[ignore = "compares wall-clock times, run with --ignored"]
+fn test_sstable_lazy_point_reads_beat_full_load() -> Result<()> {
+    use std::time::Instant;
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("large.sst");
+    let entries = sample_entries(200_000);
+    SsTable::create(&path, entries.clone())?;
+    let probes: Vec<_> = (0..200).map(|i| entries[i * 997].0.clone()).collect();
+
+    let start = Instant::now();
+    let lazy = SsTable::open(&path)?;
+    let lazy_values = probes.iter().map(|key| lazy.get(key)).collect::<io::Result<Vec<_>>>()?;
+    let lazy_time = start.elapsed();
+
+    let start = Instant::now();
+    let eager = SsTable::load(&path)?;
+    let eager_values = probes.iter().map(|key| eager.get(key)).collect::<io::Result<Vec<_>>>()?;
+    let eager_time = start.elapsed();
+
+    let as_options = |values: Vec<Option<Value>>| {
+        values.into_iter().map(|v| v.map(|v| v.as_option())).collect::<Vec<_>>()
+    };
+    assert_eq!(as_options(lazy_values), as_options(eager_values));
+    // opening reads the footer, filter and index only, each get decodes a single block
+    assert!(
+        lazy_time * 2 < eager_time,
+        "open + {} gets took {lazy_time:?}, load + gets took {eager_time:?}",
+        probes.len()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_sstable_binary_keys() -> Result<()> {
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let temp_dir = TempDir::new()?;
+    // big-endian integers sort numerically, and 0x00, 0xFF and invalid UTF-8 all appear in keys
+    let mut keys: Vec<Vec<u8>> = (0..3_000u64).map(|i| (i * 0x0101_0101).to_be_bytes().to_vec()).collect();
+    keys.extend([vec![0x00], vec![0x00, 0x00], vec![0xFF], vec![0xFF, 0xFF, 0x00], vec![0xC3, 0x28]]);
+    keys.sort();
+    keys.dedup();
+    let entries: Vec<_> = keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let value = if i % 7 == 0 { Value::tombstone() } else { Value::from_bytes(key.clone()) };
+            (key.clone(), value)
+        })
+        .collect();
+
+    let plain = temp_dir.path().join("plain.sst");
+    let prefixed = temp_dir.path().join("prefixed.sst");
+    SsTable::create(&plain, entries.clone())?;
+    let options = SsTableOptions::default().with_prefix_compression(true);
+    SsTable::create_with_options(&prefixed, entries.clone(), &options)?;
+
+    for table in open_all(&plain)?.into_iter().chain(open_all(&prefixed)?) {
+        assert!(table.block_count() > 1);
+        assert!(SsTable::verify(table.path())?.is_ok());
+        assert_eq!(table.stats().min_key, [0x00]);
+        assert_eq!(table.stats().max_key, [0xFF, 0xFF, 0x00]);
+
+        let iterated = table.iter().map(|item| item.map(|(key, _)| key)).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(iterated, keys);
+        let reversed = table.iter().rev().map(|item| item.map(|(key, _)| key)).collect::<io::Result<Vec<_>>>()?;
+        assert!(reversed.iter().rev().eq(keys.iter()));
+
+        for (key, value) in &entries {
+            assert_eq!(table.get(key)?.map(|v| v.as_option()), Some(value.as_option()), "{key:?}");
+        }
+        assert!(table.get([0x00, 0x01])?.is_none());
+        assert!(table.get([0xFF, 0xFF])?.is_none());
+
+        let start: &[u8] = &[0x00, 0x00];
+        let end: &[u8] = &[0xFF];
+        let expected: Vec<_> = keys.iter().filter(|key| key.as_slice() > start && key.as_slice() < end).collect();
+        let ranged = table
+            .range(Excluded(start), Excluded(end))
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert!(ranged.iter().eq(expected));
+        assert_eq!(table.range(Included(end), Unbounded).count(), 2);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_might_contain_prefix() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut entries = Vec::new();
+    for tenant in ["tenant1", "tenant3", "tenant7"] {
+        for i in 0..200 {
+            entries.push(set(&format!("{tenant}/item:{i:04}"), "value"));
+        }
+    }
+    // tenant5 only holds tombstones
+    entries.push(delete("tenant5/item:0000"));
+    entries.push(delete("tenant5/item:0001"));
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let plain = temp_dir.path().join("plain.sst");
+    let delimited = temp_dir.path().join("delimited.sst");
+    let fixed = temp_dir.path().join("fixed.sst");
+    SsTable::create(&plain, entries.clone())?;
+    let options = SsTableOptions::default().with_prefix_extractor(PrefixExtractor::Delimiter(b'/'));
+    SsTable::create_with_options(&delimited, entries.clone(), &options)?;
+    let options = SsTableOptions::default().with_prefix_extractor(PrefixExtractor::FixedLength(8));
+    SsTable::create_with_options(&fixed, entries.clone(), &options)?;
+
+    for path in [&plain, &delimited, &fixed] {
+        let filtered = path != &plain;
+        for table in open_all(path)? {
+            assert!(SsTable::verify(table.path())?.is_ok());
+
+            // before min_key, after max_key
+            assert!(!table.might_contain_prefix("tenant0/"));
+            assert!(!table.might_contain_prefix("apple"));
+            assert!(!table.might_contain_prefix("tenant8"));
+            assert!(!table.might_contain_prefix("zzz"));
+            // straddling the range: min_key starts with the prefix, or the range lies inside it
+            assert!(table.might_contain_prefix("tenant1/"));
+            assert!(table.might_contain_prefix("tenant"));
+            assert!(table.might_contain_prefix(""));
+            assert!(table.might_contain_prefix("tenant7/item:0199"));
+            assert!(table.might_contain_prefix("tenant3/item:01"));
+            // tombstones count, so a delete in this table can shadow older ones
+            assert!(table.might_contain_prefix("tenant5/"));
+            assert!(table.might_contain_prefix("tenant5/item:"));
+
+            // inside the range but absent, only the prefix filter can tell
+            assert_eq!(table.might_contain_prefix("tenant4/"), !filtered);
+            assert_eq!(table.might_contain_prefix("tenant2/item:0001"), !filtered);
+            // too short to have a prefix of its own, so only the range applies
+            assert!(table.might_contain_prefix("tenant4"));
+
+            // the key filter is unaffected
+            assert!(table.might_contain_key("tenant5/item:0000"));
+            assert!(table.might_contain_key("tenant3/item:0150"));
+            assert!(!table.might_contain_key("tenant0/item:0000"));
+        }
+    }
+
+    let options = SsTableOptions::default().with_prefix_extractor(PrefixExtractor::FixedLength(0));
+    let err = SsTable::create_with_options(temp_dir.path().join("zero.sst"), entries, &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_block_cache() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new()?;
+    let entries = sample_entries(5_000);
+    let other: Vec<_> = entries.iter().map(|(key, _)| set(key, "other")).collect();
+    SsTable::create(temp_dir.path().join("a.sst"), entries.clone())?;
+    SsTable::create(temp_dir.path().join("b.sst"), other.clone())?;
+
+    let cache = Arc::new(BlockCache::new(2 << 20));
+    let a = SsTable::open(temp_dir.path().join("a.sst"))?.with_block_cache(Arc::clone(&cache));
+    let b = SsTable::open(temp_dir.path().join("b.sst"))?.with_block_cache(Arc::clone(&cache));
+    assert!(a.block_count() > 4);
+    let probes: Vec<_> = (0..entries.len()).step_by(37).collect();
+
+    // a cold cache reads every block once, a warm one never touches the file
+    let mut reader = CountingReader::new(File::open(a.path())?);
+    for &i in &probes {
+        let (key, value) = &entries[i];
+        assert_eq!(a.get_from(&mut reader, key)?.map(|v| v.as_option()), Some(value.as_option()));
+    }
+    assert!(reader.bytes_read > 0);
+    assert_eq!(cache.misses() + cache.hits(), probes.len() as u64);
+    assert_eq!(cache.misses(), a.block_count() as u64);
+    let misses = cache.misses();
+
+    let mut reader = CountingReader::new(File::open(a.path())?);
+    for &i in &probes {
+        let (key, value) = &entries[i];
+        assert_eq!(a.get_from(&mut reader, key)?.map(|v| v.as_option()), Some(value.as_option()));
+        assert!(a.get_from(&mut reader, format!("{key}x"))?.is_none());
+    }
+    assert_eq!((reader.bytes_read, reader.seeks), (0, 0));
+    assert_eq!(cache.misses(), misses);
+    assert_eq!(cache.hits() + cache.misses(), 3 * probes.len() as u64);
+
+    // tables sharing the cache keep their blocks apart
+    for &i in &probes {
+        let (key, value) = &other[i];
+        assert_eq!(b.get(key)?.map(|v| v.as_option()), Some(value.as_option()));
+        assert_eq!(a.get(key)?.map(|v| v.as_option()), Some(entries[i].1.as_option()));
+    }
+    assert_eq!(cache.len(), a.block_count() + b.block_count());
+    assert!(cache.usage() <= cache.capacity());
+
+    // a small cache evicts least recently used blocks to stay within its capacity
+    let small = Arc::new(BlockCache::new(3 * DEFAULT_BLOCK_SIZE));
+    let table = SsTable::open(temp_dir.path().join("a.sst"))?.with_block_cache(Arc::clone(&small));
+    for (key, value) in &entries {
+        assert_eq!(table.get(key)?.map(|v| v.as_option()), Some(value.as_option()));
+        assert!(small.usage() <= small.capacity());
+    }
+    assert!(small.len() < table.block_count());
+    // the most recently used block is still cached
+    let hits = small.hits();
+    table.get(&entries[entries.len() - 1].0)?;
+    assert_eq!(small.hits(), hits + 1);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_multi_get() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries = sample_entries(4_000);
+    SsTable::create(&path, entries)?;
+
+    // hits, tombstones (every tenth key), misses inside and outside the key range, and duplicates
+    let noise = random_bytes(42, 600);
+    let keys: Vec<String> = noise
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let n = usize::from(u16::from_le_bytes([pair[0], pair[1]])) % 5_000;
+            match i % 10 {
+                0 => format!("key:{n:06}x"),
+                1 => "aaa".to_string(),
+                _ => format!("key:{n:06}"),
+            }
+        })
+        .collect();
+    assert!(keys.iter().filter(|key| *key == "aaa").count() > 1);
+
+    let cache = Arc::new(BlockCache::new(1 << 20));
+    let cached = SsTable::open(&path)?.with_block_cache(Arc::clone(&cache));
+    for table in open_all(&path)?.into_iter().chain([cached]) {
+        let expected = keys
+            .iter()
+            .map(|key| table.get(key).map(|value| value.map(|v| v.as_option())))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert!(expected.iter().any(|value| matches!(value, Some(Some(_)))));
+        assert!(expected.iter().any(|value| matches!(value, Some(None))));
+        assert!(expected.iter().any(Option::is_none));
+
+        let actual: Vec<_> = table.multi_get(&keys)?.into_iter().map(|value| value.map(|v| v.as_option())).collect();
+        assert_eq!(actual, expected);
+        assert!(table.multi_get::<&str>(&[])?.is_empty());
+        let single = table.multi_get(&["key:000123", "key:000123"])?;
+        assert_eq!(single.iter().filter(|value| value.as_ref().and_then(Value::as_option).is_some()).count(), 2);
+    }
+    // the cached table answered the multi_get from blocks its gets had already cached
+    assert!(cache.hits() > 0);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_create_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let path = dir.join("table.sst");
+    let temp_path = dir.join("table.sst.tmp");
+
+    // a create failing after several blocks were written never creates the final path
+    let mut entries = sample_entries(3_000);
+    entries.push(set("aaa", "out of order"));
+    let err = SsTable::create(&path, entries.clone()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!path.exists());
+    assert!(!temp_path.exists());
+
+    // nor does it clobber a table already there
+    SsTable::create(&path, vec![set("a", "1")])?;
+    SsTable::create(&path, entries).unwrap_err();
+    assert_eq!(collect_entries(&SsTable::open(&path)?)?, vec![("a".to_string(), Some(b"1".to_vec()))]);
+
+    // a writer that never finishes, as after a crash, leaves only its temporary file
+    let crashed = dir.join("crashed.sst");
+    let mut writer = SsTableWriter::new(&crashed, &SsTableOptions::default())?;
+    for (key, value) in sample_entries(2_000) {
+        writer.add(&key, &value)?;
+    }
+    std::mem::forget(writer);
+    assert!(!crashed.exists());
+    assert_eq!(SsTable::temp_files(dir)?, vec![dir.join("crashed.sst.tmp")]);
+    assert!(SsTable::open(dir.join("crashed.sst.tmp")).is_err());
+    assert_eq!(SsTable::remove_temp_files(dir)?, vec![dir.join("crashed.sst.tmp")]);
+    assert!(SsTable::temp_files(dir)?.is_empty());
+
+    // a writer dropped before finishing cleans up after itself
+    let mut writer = SsTableWriter::new(dir.join("dropped.sst"), &SsTableOptions::default())?;
+    writer.add("a", &Value::from_bytes(b"1".to_vec()))?;
+    drop(writer);
+    assert!(SsTable::temp_files(dir)?.is_empty());
+    assert!(!dir.join("dropped.sst").exists());
+
+    let finished = SsTable::create(dir.join("finished.sst"), vec![set("a", "1")])?;
+    assert_eq!(finished.path(), dir.join("finished.sst"));
+    assert!(SsTable::temp_files(dir)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_buffered_io_with_many_small_entries() -> Result<()> {
+    use std::time::{Duration, Instant};
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("large.sst");
+    let count = 1_000_000;
+    let key_of = |i: usize| format!("k{i:07}");
+
+    let start = Instant::now();
+    let created = SsTable::create(&path, (0..count).map(|i| (key_of(i), Value::from_bytes(vec![i as u8]))))?;
+    let loaded = SsTable::load(&path)?;
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(60), "create + load of {count} entries took {elapsed:?}");
+    assert_eq!(loaded.iter().count(), count);
+    assert_eq!(loaded.get(key_of(765_432))?.and_then(|v| v.as_option()), Some(vec![765_432usize as u8]));
+
+    // the recorded offsets still match the bytes on disk: the handles tile the file from the
+    // first section up to the footer, and the footer ends at the crc before the trailer
+    let bytes = std::fs::read(&path)?;
+    let u64_at = |pos: usize| u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap()) as usize;
+    let trailer = bytes.len() - 18;
+    let footer_offset = u64_at(trailer);
+    let handles = trailer - 4 - 80;
+    let mut end = u64_at(handles);
+    for section in 0..5 {
+        assert_eq!(u64_at(handles + 16 * section), end, "section {section}");
+        end += u64_at(handles + 16 * section + 8);
+    }
+    assert_eq!(end, footer_offset);
+    let min_len = u32::from_le_bytes(bytes[footer_offset..footer_offset + 4].try_into().unwrap()) as usize;
+    assert_eq!(&bytes[footer_offset + 4..footer_offset + 4 + min_len], b"k0000000");
+    assert_eq!(u32::from_le_bytes(bytes[..4].try_into().unwrap()), count as u32);
+    assert_eq!(created.stats().file_size, bytes.len() as u64);
+    assert!(SsTable::verify(&path)?.is_ok());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_approximate_key_at_and_offset_of() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let count = 10_000;
+    SsTable::create(&path, (0..count).map(|i| set(&format!("key:{i:06}"), "value")))?;
+    let index_of = |key: &[u8]| -> usize { std::str::from_utf8(&key[4..]).unwrap().parse().unwrap() };
+
+    for table in open_all(&path)? {
+        assert_eq!(table.approximate_key_at(0.0).unwrap(), b"key:000000");
+        assert_eq!(table.approximate_key_at(-3.0).unwrap(), b"key:000000");
+        assert_eq!(table.approximate_key_at(1.0).unwrap(), b"key:009999");
+        assert_eq!(table.approximate_key_at(7.5).unwrap(), b"key:009999");
+        assert!(table.approximate_key_at(f64::NAN).is_none());
+
+        let keys: Vec<_> = (0..=200).map(|i| table.approximate_key_at(i as f64 / 200.0).unwrap()).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+        // keys are uniform, so the position by count and by bytes agree to within a block
+        for (i, key) in keys.iter().enumerate() {
+            let expected = i * (count - 1) / 200;
+            assert!(index_of(key).abs_diff(expected) < 300, "{} for {expected}", key.escape_ascii());
+        }
+
+        let data_start = table.approximate_offset_of(b"");
+        let data_end = table.approximate_offset_of(b"zzz");
+        assert_eq!(data_start, 4);
+        assert!(data_end > data_start && data_end < table.stats().file_size);
+        let offsets: Vec<_> = (0..count).step_by(50).map(|i| table.approximate_offset_of(format!("key:{i:06}"))).collect();
+        assert!(offsets.windows(2).all(|pair| pair[0] <= pair[1]));
+        let half = table.approximate_offset_of("key:005000") - data_start;
+        let quarter = table.approximate_offset_of("key:002500") - data_start;
+        let total = data_end - data_start;
+        assert!(half.abs_diff(total / 2) < total / 20, "{half} of {total}");
+        assert!(quarter.abs_diff(total / 4) < total / 20, "{quarter} of {total}");
+
+        assert_eq!(table.approximate_size_of(None, None), total);
+        assert_eq!(table.approximate_size_of(None, Some(b"key:005000")), half);
+        let ends: [Option<&[u8]>; 4] = [None, Some(b"key:002500"), Some(b"key:005000"), None];
+        let sizes: u64 = ends.windows(2).map(|pair| table.approximate_size_of(pair[0], pair[1])).sum();
+        assert_eq!(sizes, total);
+        assert_eq!(table.approximate_size_of(Some(b"key:005000"), Some(b"key:002500")), 0);
+        assert_eq!(table.approximate_size_of(Some(b"zzz"), None), 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_dump() -> Result<()> {
+    use snaildb::storage::sstable::DumpFormat;
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let mut entries: Vec<(Vec<u8>, Value)> = sample_entries(1_000)
+        .into_iter()
+        .map(|(key, value)| (key.into_bytes(), value))
+        .collect();
+    entries.push((b"quote\"d".to_vec(), Value::from_bytes(b"line\nbreak\\ \t \x01 caf\xc3\xa9".to_vec())));
+    entries.push((vec![0xFF, 0x00, 0xFE], Value::from_bytes(vec![0xC3, 0x28])));
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    SsTable::create(&path, entries.clone())?;
+
+    for table in open_all(&path)? {
+        let mut out = Vec::new();
+        table.dump(&mut out, DumpFormat::Json)?;
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), entries.len() + 1);
+        for (line, (key, value)) in lines.iter().zip(&entries) {
+            if key == &[0xFF, 0x00, 0xFE] {
+                assert_eq!(line["key"], "/wD+");
+                assert_eq!(line["key_base64"], true);
+                assert_eq!(line["value"], "wyg=");
+                assert_eq!(line["value_base64"], true);
+                continue;
+            }
+            assert_eq!(line["key"].as_str().unwrap().as_bytes(), key.as_slice());
+            assert!(line.get("key_base64").is_none());
+            match value {
+                Value::Bytes(bytes) => {
+                    assert_eq!(line["kind"], "set");
+                    assert_eq!(line["value"].as_str().unwrap().as_bytes(), bytes.as_slice());
+                }
+                Value::Deleted => {
+                    assert_eq!(line["kind"], "delete");
+                    assert!(line["value"].is_null());
+                }
+                _ => unreachable!("only raw bytes and tombstones were written"),
+            }
+        }
+        let summary = &lines[entries.len()];
+        assert_eq!(summary["summary"], true);
+        assert_eq!(summary["entries"], entries.len());
+        assert_eq!(summary["min_key"], "key:000000");
+        assert_eq!(summary["max_key"], "/wD+");
+        assert_eq!(summary["max_key_base64"], true);
+
+        let mut out = Vec::new();
+        table.dump(&mut out, DumpFormat::Raw)?;
+        let raw = String::from_utf8(out)?;
+        assert!(raw.starts_with("block 0 offset=0x4 len="), "{raw}");
+        assert_eq!(raw.lines().filter(|line| line.starts_with("block ")).count(), table.block_count());
+        assert_eq!(raw.lines().filter(|line| line.starts_with("  0x")).count(), entries.len());
+        assert!(raw.contains("  0x000000 size="));
+        assert!(raw.contains(r#"delete key="key:000010" value_len=0"#), "{raw}");
+    }
+
+    // a corrupt block stops the JSON dump but the raw dump reports it and carries on
+    let mut out = Vec::new();
+    SsTable::open(&path)?.dump(&mut out, DumpFormat::Raw)?;
+    let raw = String::from_utf8(out)?;
+    let block_1 = raw.lines().find(|line| line.starts_with("block 1 ")).unwrap();
+    let field = |name: &str| block_1.split(' ').find_map(|part| part.strip_prefix(name)).unwrap();
+    let offset = usize::from_str_radix(field("offset=0x"), 16)?;
+    let len: usize = field("len=").parse()?;
+    let mut bytes = std::fs::read(&path)?;
+    bytes[offset + len / 2] ^= 0x40;
+    std::fs::write(&path, &bytes)?;
+    let table = SsTable::open(&path)?;
+    assert!(table.dump(io::sink(), DumpFormat::Json).is_err());
+    let mut out = Vec::new();
+    table.dump(&mut out, DumpFormat::Raw)?;
+    let raw = String::from_utf8(out)?;
+    let error_line = raw.lines().position(|line| line.contains("error after")).expect("error reported");
+    let block_2 = raw.lines().position(|line| line.starts_with("block 2 ")).expect("later blocks dumped");
+    assert!(error_line < block_2, "{raw}");
+    Ok(())
+}
+
+#[cfg(feature = "import")]
+#[test]
+fn test_sstable_import_ndjson() -> Result<()> {
+    use snaildb::storage::sstable::{DumpFormat, ImportOptions};
+
+    let temp_dir = TempDir::new()?;
+    let entries = sample_entries(5_000);
+    let mut ndjson = String::new();
+    for (key, value) in &entries {
+        match value {
+            Value::Bytes(bytes) => ndjson.push_str(&format!(
+                "{{\"key\":\"{key}\",\"value\":\"{}\"}}\n",
+                String::from_utf8_lossy(bytes)
+            )),
+            Value::Deleted => ndjson.push_str(&format!("{{\"key\":\"{key}\",\"deleted\":true}}\n")),
+            _ => unreachable!("only raw bytes and tombstones were written"),
+        }
+    }
+    let check = |table: &SsTable| -> Result<()> {
+        assert_eq!(table.stats().entries, entries.len() as u64);
+        for (key, value) in &entries {
+            assert_eq!(table.get(key)?.map(|v| v.as_option()), Some(value.as_option()), "{key}");
+        }
+        Ok(())
+    };
+
+    // presorted input streams straight through the writer
+    let path = temp_dir.path().join("sorted.sst");
+    SsTable::import_ndjson(ndjson.as_bytes(), &path, &ImportOptions::default())?;
+    check(&SsTable::load(&path)?)?;
+
+    // shuffled input is rejected without a sort budget and sorted in runs with one
+    let mut lines: Vec<&str> = ndjson.lines().collect();
+    lines.swap(10, 4_000);
+    lines.reverse();
+    let shuffled = lines.join("\n");
+    let path = temp_dir.path().join("shuffled.sst");
+    let err = SsTable::import_ndjson(shuffled.as_bytes(), &path, &ImportOptions::default()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().starts_with("line 2: out-of-order key"), "{err}");
+    assert!(!path.exists());
+    let options = ImportOptions::default().with_sort_memory_budget(16 * 1024);
+    check(&SsTable::import_ndjson(shuffled.as_bytes(), &path, &options)?)?;
+    assert!(SsTable::temp_files(temp_dir.path())?.is_empty(), "runs removed");
+
+    // with a sort budget a repeated key keeps the value read last
+    let repeated = format!("{shuffled}\n{{\"key\":\"key:000001\",\"value\":\"again\"}}\n");
+    let path = temp_dir.path().join("repeated.sst");
+    let table = SsTable::import_ndjson(repeated.as_bytes(), &path, &options)?;
+    assert_eq!(table.get("key:000001")?.and_then(|v| v.as_option()), Some(b"again".to_vec()));
+
+    // a dump imports back into the same table, binary keys included
+    let mut binary = entries.iter().map(|(key, value)| (key.clone().into_bytes(), value.clone())).collect::<Vec<_>>();
+    binary.push((vec![0xFF, 0x00], Value::from_bytes(vec![0xC3, 0x28])));
+    let source = SsTable::create(temp_dir.path().join("source.sst"), binary.clone())?;
+    let mut dump = Vec::new();
+    source.dump(&mut dump, DumpFormat::Json)?;
+    let path = temp_dir.path().join("dumped.sst");
+    let table = SsTable::import_ndjson(dump.as_slice(), &path, &ImportOptions::default())?;
+    let imported: Vec<_> = table
+        .iter()
+        .map(|item| item.map(|(key, value)| (key, value.as_option())))
+        .collect::<io::Result<_>>()?;
+    let expected: Vec<_> = binary.into_iter().map(|(key, value)| (key, value.as_option())).collect();
+    assert_eq!(imported, expected);
+
+    // malformed lines name their line number
+    for (input, message) in [
+        ("{\"key\":\"a\",\"value\":\"1\"}\nnot json\n", "line 2: invalid JSON"),
+        ("{\"key\":\"a\",\"value\":\"1\"}\n\n[1]\n", "line 3: expected a JSON object"),
+        ("{\"value\":\"1\"}\n", "line 1: missing \"key\""),
+        ("{\"key\":\"a\"}\n", "line 1: missing \"value\""),
+        ("{\"key\":\"a\",\"value\":1}\n", "line 1: \"value\" must be a string"),
+        ("{\"key\":\"a\",\"deleted\":\"yes\"}\n", "line 1: \"deleted\" must be a boolean"),
+        ("{\"key\":\"%%\",\"key_base64\":true,\"value\":\"1\"}\n", "line 1: \"key\" is not valid base64"),
+    ] {
+        let path = temp_dir.path().join("bad.sst");
+        let err = SsTable::import_ndjson(input.as_bytes(), &path, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with(message), "{err}");
+        assert!(!path.exists());
+    }
+    assert!(SsTable::temp_files(temp_dir.path())?.is_empty());
+    Ok(())
+}
+
+#[cfg(feature = "import")]
+#[test]
+fn test_sstable_dump_imports_typed_values() -> Result<()> {
+    use snaildb::storage::sstable::{DumpFormat, ImportOptions};
+
+    let temp_dir = TempDir::new()?;
+    let entries = vec![
+        ("a", Value::Int(-7)),
+        ("b", Value::Float(0.1)),
+        ("c", Value::Float(f64::NAN)),
+        ("d", Value::Float(f64::NEG_INFINITY)),
+        ("e", Value::Str("say \"hi\"".to_string())),
+        ("f", Value::Str(String::new())),
+        ("g", Value::Bool(true)),
+        ("h", Value::Bytes(Vec::new())),
+    ];
+    let source = SsTable::create(temp_dir.path().join("source.sst"), entries.clone())?;
+    let mut dump = Vec::new();
+    source.dump(&mut dump, DumpFormat::Json)?;
+    let lines: Vec<serde_json::Value> = dump
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice)
+        .collect::<Result<_, _>>()?;
+    assert_eq!((&lines[0]["type"], &lines[0]["value"]), (&serde_json::json!("int"), &serde_json::json!(-7)));
+    assert_eq!(lines[2]["value"], "NaN");
+    assert!(lines[7].get("type").is_none());
+
+    let table = SsTable::import_ndjson(dump.as_slice(), temp_dir.path().join("dumped.sst"), &ImportOptions::default())?;
+    let imported: Vec<_> = table.iter().collect::<io::Result<_>>()?;
+    let expected: Vec<_> = entries.into_iter().map(|(key, value)| (key.as_bytes().to_vec(), value)).collect();
+    assert_eq!(imported, expected);
+
+    for (input, message) in [
+        ("{\"key\":\"a\",\"type\":\"int\",\"value\":\"1\"}\n", "line 1: \"value\" must be an integer"),
+        ("{\"key\":\"a\",\"type\":\"date\",\"value\":1}\n", "line 1: \"type\" must be"),
+        ("{\"key\":\"a\",\"type\":\"int\",\"value\":1,\"expires_at\":5}\n", "line 1: only raw bytes"),
+    ] {
+        let err = SsTable::import_ndjson(input.as_bytes(), temp_dir.path().join("bad.sst"), &ImportOptions::default())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with(message), "{err}");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "import")]
+#[test]
+fn test_sstable_import_csv() -> Result<()> {
+    use snaildb::storage::sstable::ImportOptions;
+
+    let temp_dir = TempDir::new()?;
+    let entries = sample_entries(3_000);
+    let mut csv = String::from("id,key,value,deleted\n");
+    for (i, (key, value)) in entries.iter().enumerate().rev() {
+        match value {
+            Value::Bytes(bytes) => csv.push_str(&format!("{i},{key},{},false\n", String::from_utf8_lossy(bytes))),
+            Value::Deleted => csv.push_str(&format!("{i},{key},,true\r\n")),
+            _ => unreachable!("only raw bytes and tombstones were written"),
+        }
+    }
+    csv.push_str("9999,\"quoted,key\",\"say \"\"hi\"\"\nover two lines\",0\n");
+    let path = temp_dir.path().join("table.sst");
+    let options = ImportOptions::default().with_sort_memory_budget(32 * 1024);
+    let table = SsTable::import_csv(csv.as_bytes(), &path, &options)?;
+    assert_eq!(table.stats().entries, entries.len() as u64 + 1);
+    for (key, value) in &entries {
+        assert_eq!(table.get(key)?.map(|v| v.as_option()), Some(value.as_option()), "{key}");
+    }
+    assert_eq!(table.get("quoted,key")?.and_then(|v| v.as_option()), Some(b"say \"hi\"\nover two lines".to_vec()));
+
+    // the header must name the columns, and rows must match it
+    for (input, message) in [
+        ("name,value\na,1\n", "line 1: CSV header must name a key and a value column"),
+        ("key,value\na,1\nb\n", "line 3: expected 2 fields, found 1"),
+        ("key,value,deleted\na,1,maybe\n", "line 2: invalid deleted flag"),
+        ("key,value\na,1\n\"b,2\n", "line 3: unterminated quoted field"),
+        ("key,value\na,1\nb,2\"x\"\n", "line 3: unexpected quote"),
+        ("key,value\nb,1\na,2\n", "line 3: out-of-order key"),
+    ] {
+        let err = SsTable::import_csv(input.as_bytes(), temp_dir.path().join("bad.sst"), &ImportOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().starts_with(message), "{err}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_entries_expire() -> Result<()> {
+    use snaildb::storage::sstable::DumpFormat;
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let mut writer = SsTableWriter::new(&path, &SsTableOptions::default())?;
+    for i in 0..1_000 {
+        let key = format!("session:{i:04}");
+        match i % 3 {
+            0 => writer.add(&key, &Value::from_bytes(b"forever".to_vec()))?,
+            1 => writer.add_with_expiry(&key, b"token", 2_000)?,
+            _ => writer.add_with_expiry(&key, b"token", 4_000)?,
+        }
+    }
+    writer.finish()?;
+
+    let value_at = |table: &SsTable, key: &str| -> Result<Option<Option<Vec<u8>>>> {
+        Ok(table.get(key)?.map(|value| value.as_option()))
+    };
+    for table in open_all(&path)? {
+        let table = table.with_clock(|| 1_999);
+        assert_eq!(value_at(&table, "session:0001")?, Some(Some(b"token".to_vec())));
+        let table = table.with_clock(|| 2_000);
+        // expired entries read as tombstones, entries without a ttl never expire
+        assert_eq!(value_at(&table, "session:0000")?, Some(Some(b"forever".to_vec())));
+        assert_eq!(value_at(&table, "session:0001")?, Some(None));
+        assert_eq!(value_at(&table, "session:0002")?, Some(Some(b"token".to_vec())));
+        assert!(table.might_contain_key("session:0001"));
+        let values = table.multi_get(&["session:0004", "session:0005"])?;
+        assert!(matches!(values[0], Some(Value::Deleted)));
+        assert!(matches!(values[1], Some(Value::Bytes(_))));
+        let live = table.iter().filter(|item| matches!(item, Ok((_, Value::Bytes(_))))).count();
+        assert_eq!(live, 667);
+        let table = table.with_clock(|| u64::MAX);
+        let live = table.iter().rev().filter(|item| matches!(item, Ok((_, Value::Bytes(_))))).count();
+        assert_eq!(live, 334);
+    }
+
+    // the dump shows the expiry whether or not it passed
+    let mut out = Vec::new();
+    SsTable::open(&path)?.with_clock(|| u64::MAX).dump(&mut out, DumpFormat::Json)?;
+    let dump = String::from_utf8(out)?;
+    assert!(dump.contains(r#"{"key":"session:0001","kind":"set","value":"token","expires_at":2000}"#), "{dump}");
+    assert!(dump.contains(r#"{"key":"session:0000","kind":"set","value":"forever"}"#));
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_drops_expired_entries() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let older = SsTable::create(
+        temp_dir.path().join("older.sst"),
+        [("a", Value::from_bytes(b"old".to_vec())), ("b", Value::from_bytes(b"old".to_vec()))],
+    )?;
+    let mut writer = SsTableWriter::new(temp_dir.path().join("newer.sst"), &SsTableOptions::default())?;
+    writer.add_with_expiry("a", "new", 2_000)?;
+    writer.add_with_expiry("b", "new", 4_000)?;
+    writer.add_with_expiry("c", "new", 2_000)?;
+    let newer = writer.finish()?;
+    let inputs = [older, newer];
+    type Contents = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+    let contents = |table: &SsTable| -> Result<Contents> {
+        Ok(table
+            .iter()
+            .map(|item| item.map(|(key, value)| (key, value.as_option())))
+            .collect::<io::Result<_>>()?)
+    };
+
+    // unexpired entries keep their expiry through the merge
+    let options = MergeOptions::default().with_clock(|| 1_000);
+    let merged = SsTable::merge_with_options(temp_dir.path().join("early.sst"), &inputs, &options)?.unwrap();
+    let merged = merged.with_clock(|| 3_000);
+    assert_eq!(
+        contents(&merged)?,
+        vec![(b"a".to_vec(), None), (b"b".to_vec(), Some(b"new".to_vec())), (b"c".to_vec(), None)]
+    );
+
+    // expired entries lose their value and still shadow the older one
+    let options = MergeOptions::default().with_clock(|| 3_000);
+    let merged = SsTable::merge_with_options(temp_dir.path().join("late.sst"), &inputs, &options)?.unwrap();
+    let merged = merged.with_clock(|| 0);
+    assert_eq!(
+        contents(&merged)?,
+        vec![(b"a".to_vec(), None), (b"b".to_vec(), Some(b"new".to_vec())), (b"c".to_vec(), None)]
+    );
+    assert_eq!(merged.stats().tombstones, 2);
+
+    // dropping tombstones drops them entirely, without bringing back the older value
+    let options = options.with_drop_tombstones(true);
+    let merged = SsTable::merge_with_options(temp_dir.path().join("dropped.sst"), &inputs, &options)?.unwrap();
+    let merged = merged.with_clock(|| 0);
+    assert_eq!(contents(&merged)?, vec![(b"b".to_vec(), Some(b"new".to_vec()))]);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_seqnos() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries: Vec<(String, Value, u64)> = sample_entries(2_000)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (key, value))| (key, value, 100 + (i as u64 * 7) % 1_000))
+        .collect();
+    SsTable::create(&path, entries.clone())?;
+    for table in open_all(&path)? {
+        assert_eq!((table.stats().min_seqno, table.stats().max_seqno), (100, 1_099));
+        for (key, value, seqno) in entries.iter().step_by(37) {
+            let (found, found_seqno) = table.get_with_seqno(key)?.expect("key present");
+            assert_eq!((found.as_option(), found_seqno), (value.as_option(), *seqno));
+        }
+        assert!(table.get_with_seqno("missing")?.is_none());
+    }
+
+    // pairs are written with sequence number 0
+    let table = SsTable::create(temp_dir.path().join("pairs.sst"), [("a", Value::from_bytes(b"1".to_vec()))])?;
+    assert!(matches!(table.get_with_seqno("a")?, Some((Value::Bytes(_), 0))));
+    assert_eq!((table.stats().min_seqno, table.stats().max_seqno), (0, 0));
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_prefers_higher_seqno() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let set = |value: &str| Value::from_bytes(value.as_bytes().to_vec());
+    // the "older" input holds the newer version of b and the tombstone of c
+    let older = SsTable::create(
+        temp_dir.path().join("older.sst"),
+        [("a", set("older"), 1), ("b", set("older"), 9), ("c", Value::tombstone(), 8), ("d", set("older"), 5)],
+    )?;
+    let newer = SsTable::create(
+        temp_dir.path().join("newer.sst"),
+        [("a", set("newer"), 2), ("b", set("newer"), 3), ("c", set("newer"), 4), ("d", set("newer"), 5)],
+    )?;
+
+    for inputs in [[older, newer], {
+        // the order of the inputs only breaks ties between equal sequence numbers
+        let older = SsTable::open(temp_dir.path().join("older.sst"))?;
+        let newer = SsTable::open(temp_dir.path().join("newer.sst"))?;
+        [newer, older]
+    }] {
+        let output = temp_dir.path().join("merged.sst");
+        let merged = SsTable::merge(&output, &inputs)?;
+        let winner = |key: &str| -> Result<(Option<Vec<u8>>, u64)> {
+            let (value, seqno) = merged.get_with_seqno(key)?.expect("key present");
+            Ok((value.as_option(), seqno))
+        };
+        assert_eq!(winner("a")?, (Some(b"newer".to_vec()), 2));
+        assert_eq!(winner("b")?, (Some(b"older".to_vec()), 9));
+        assert_eq!(winner("c")?, (None, 8));
+        let tied = if inputs[1].path().ends_with("newer.sst") { "newer" } else { "older" };
+        assert_eq!(winner("d")?, (Some(tied.as_bytes().to_vec()), 5));
+        assert_eq!((merged.stats().min_seqno, merged.stats().max_seqno), (2, 9));
+
+        // a winning tombstone with the higher seqno drops the value it shadows
+        let options = MergeOptions::default().with_drop_tombstones(true);
+        let dropped = SsTable::merge_with_options(temp_dir.path().join("dropped.sst"), &inputs, &options)?.unwrap();
+        assert!(dropped.get("c")?.is_none());
+        drop(merged);
+        std::fs::remove_file(&output)?;
+        std::fs::remove_file(temp_dir.path().join("dropped.sst"))?;
+    }
+    Ok(())
+}
+
+/// Strips values starting with `deleted:`, upgrades `v1:` values to `v2:` and records every call.
+#[derive(Default)]
+struct SchemaFilter {
+    seen: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl CompactionFilter for SchemaFilter {
+    fn filter(&mut self, key: &[u8], value: &Value) -> FilterDecision {
+        self.seen.push((key.to_vec(), value.as_option()));
+        match value.as_option() {
+            Some(bytes) if bytes.starts_with(b"deleted:") => FilterDecision::Remove,
+            Some(bytes) if bytes.starts_with(b"v1:") => FilterDecision::ChangeValue([b"v2:", &bytes[3..]].concat()),
+            _ => FilterDecision::Keep,
+        }
+    }
+}
+
+#[test]
+fn test_sstable_merge_with_compaction_filter() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let set = |value: &str| Value::from_bytes(value.as_bytes().to_vec());
+    let inputs = vec![
+        SsTable::create(
+            temp_dir.path().join("oldest.sst"),
+            [("a", set("v1:a")), ("b", set("v1:old")), ("c", set("v1:c")), ("d", set("v1:d"))],
+        )?,
+        SsTable::create(
+            temp_dir.path().join("middle.sst"),
+            [("b", set("deleted:b")), ("c", Value::tombstone()), ("e", set("e"))],
+        )?,
+        SsTable::create(temp_dir.path().join("newest.sst"), [("d", set("deleted:d")), ("e", set("v1:e"))])?,
+    ];
+
+    let mut filter = SchemaFilter::default();
+    let output = temp_dir.path().join("merged.sst");
+    let merged = SsTable::merge_with_filter(&output, &inputs, &MergeOptions::default(), Some(&mut filter))?.unwrap();
+    // once per surviving key with the winning value, never with a shadowed one
+    let some = |value: &str| Some(value.as_bytes().to_vec());
+    assert_eq!(
+        filter.seen,
+        vec![
+            (b"a".to_vec(), some("v1:a")),
+            (b"b".to_vec(), some("deleted:b")),
+            (b"c".to_vec(), None),
+            (b"d".to_vec(), some("deleted:d")),
+            (b"e".to_vec(), some("v1:e")),
+        ]
+    );
+    let contents: Vec<(Vec<u8>, Option<Vec<u8>>)> =
+        merged.iter().map(|item| item.map(|(key, value)| (key, value.as_option()))).collect::<io::Result<_>>()?;
+    // removed keys stay tombstones, so the older v1 values of b and d do not come back
+    assert_eq!(
+        contents,
+        vec![
+            (b"a".to_vec(), some("v2:a")),
+            (b"b".to_vec(), None),
+            (b"c".to_vec(), None),
+            (b"d".to_vec(), None),
+            (b"e".to_vec(), some("v2:e")),
+        ]
+    );
+
+    // dropping tombstones leaves removed keys and shadowed tombstones out without calling the filter
+    let mut filter = SchemaFilter::default();
+    let options = MergeOptions::default().with_drop_tombstones(true);
+    let dropped = temp_dir.path().join("dropped.sst");
+    let merged = SsTable::merge_with_filter(&dropped, &inputs, &options, Some(&mut filter))?.unwrap();
+    assert_eq!(filter.seen.iter().map(|(key, _)| key.as_slice()).collect::<Vec<_>>(), [b"a", b"b", b"d", b"e"]);
+    let keys: Vec<Vec<u8>> = merged.iter().map(|item| item.map(|(key, _)| key)).collect::<io::Result<_>>()?;
+    assert_eq!(keys, [b"a".to_vec(), b"e".to_vec()]);
+
+    // without a filter the merge is the same as merge_with_options
+    let unfiltered = SsTable::merge_with_filter(temp_dir.path().join("plain.sst"), &inputs, &options, None)?.unwrap();
+    assert_eq!(unfiltered.stats().entries, 4);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_overlapping_tables() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key = |n: u8| format!("k{n:03}").into_bytes();
+    let mut random = random_bytes(11, 4_096).into_iter();
+    let mut tables = Vec::new();
+    for i in 0..16 {
+        let mut keys: Vec<u8> = (0..1 + random.next().unwrap() % 8).map(|_| random.next().unwrap()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let entries = keys.iter().map(|&n| (key(n), Value::from_bytes(vec![n])));
+        tables.push(SsTable::create(temp_dir.path().join(format!("{i}.sst")), entries)?);
+    }
+
+    // a table overlaps a range when some key of the universe lies in both
+    let bounds = |table: &SsTable| -> Result<(Vec<u8>, Vec<u8>)> {
+        let keys: Vec<Vec<u8>> = table.iter().map(|item| item.map(|(key, _)| key)).collect::<io::Result<_>>()?;
+        Ok((keys[0].clone(), keys[keys.len() - 1].clone()))
+    };
+    let in_both = |(min, max): &(Vec<u8>, Vec<u8>), start: &[u8], end: &[u8]| {
+        (0..=u8::MAX).map(key).any(|k| *min <= k && k <= *max && start <= k.as_slice() && k.as_slice() <= end)
+    };
+    let table_bounds: Vec<_> = tables.iter().map(bounds).collect::<Result<_>>()?;
+    for (table, bounds) in tables.iter().zip(&table_bounds) {
+        assert_eq!(table.key_range(), (bounds.0.as_slice(), bounds.1.as_slice()));
+        for (other, other_bounds) in tables.iter().zip(&table_bounds) {
+            assert_eq!(table.overlaps(other), in_both(bounds, &other_bounds.0, &other_bounds.1));
+        }
+    }
+    for _ in 0..500 {
+        let (start, end) = (key(random.next().unwrap()), key(random.next().unwrap()));
+        let found: Vec<&Path> = overlapping_tables(&tables, &start, &end).iter().map(|table| table.path()).collect();
+        let expected: Vec<&Path> = tables
+            .iter()
+            .zip(&table_bounds)
+            .filter(|(_, bounds)| in_both(bounds, &start, &end))
+            .map(|(table, _)| table.path())
+            .collect();
+        assert_eq!(found, expected, "range {start:?}..={end:?}");
+    }
+
+    // ranges touching a table at one boundary key overlap it, reversed ranges overlap nothing
+    let (min_key, max_key) = tables[0].key_range();
+    assert_eq!(overlapping_tables(&tables[..1], b"", min_key).len(), 1);
+    assert_eq!(overlapping_tables(&tables[..1], max_key, b"l").len(), 1);
+    assert_eq!(overlapping_tables(&tables[..1], max_key, min_key).len(), usize::from(min_key == max_key));
+    assert!(overlapping_tables(&tables, b"l", b"k").is_empty());
+    assert!(overlapping_tables(&[], b"a", b"z").is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_sstable_partitioned_index() -> Result<()> {
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let entries: Vec<(String, Value)> = (0..20_000)
+        .map(|i| (format!("key:{:06}", i * 2), Value::from_bytes(random_bytes(i as u64 + 1, 100))))
+        .collect();
+    let options = SsTableOptions::default().with_partitioned_index(256);
+    let created = SsTable::create_with_options(&path, entries.clone(), &options)?;
+    assert!(created.index_partition_count() > 10, "{} partitions", created.index_partition_count());
+    assert_eq!(created.properties().get("snaildb.index_type").map(String::as_str), Some("partitioned"));
+    let flat_path = temp_dir.path().join("flat.sst");
+    let flat = SsTable::create(&flat_path, entries.clone())?;
+    assert_eq!(flat.index_partition_count(), 0);
+    assert!(flat.properties().get("snaildb.index_type").is_none());
+    assert_eq!(created.block_count(), flat.block_count());
+
+    // every key, including the first and last of each block and partition, and the gaps around them
+    let mut tables = vec![created];
+    tables.extend(open_all(&path)?);
+    for table in &tables {
+        assert_eq!(table.stats(), &Stats { file_size: table.stats().file_size, ..flat.stats().clone() });
+        for (i, (key, value)) in entries.iter().enumerate() {
+            assert_eq!(table.get(key)?.and_then(|v| v.as_option()), value.as_option(), "{key}");
+            let gap = format!("key:{:06}", i * 2 + 1);
+            assert!(table.get(&gap)?.is_none());
+        }
+        assert!(table.get("key:")?.is_none());
+        assert!(table.get("key:999999")?.is_none());
+
+        let keys: Vec<&str> = entries.iter().step_by(7).map(|(key, _)| key.as_str()).collect();
+        let expected: Vec<_> = entries.iter().step_by(7).map(|(_, value)| value.as_option()).collect();
+        let values: Vec<_> =
+            table.multi_get(&keys)?.into_iter().map(|value| value.and_then(|v| v.as_option())).collect();
+        assert_eq!(values, expected);
+        assert_eq!(table.iter().count(), entries.len());
+        let (last_key, _) = table.iter().next_back().expect("table is not empty")?;
+        assert_eq!(last_key, b"key:039998");
+        let range: Vec<Vec<u8>> = table
+            .range(Bound::Included("key:010001"), Bound::Excluded("key:010101"))
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(range.len(), 50);
+        assert_eq!(table.approximate_key_at(0.5).map(|key| key > b"key:015000".to_vec()), Some(true));
+        assert!(table.approximate_offset_of("key:020000") < table.approximate_offset_of("key:030000"));
+    }
+    assert!(SsTable::verify(&path)?.is_ok());
+
+    // a lookup reads one index partition and one block, both cached
+    let cache = Arc::new(BlockCache::new(1 << 20));
+    let table = SsTable::open(&path)?.with_block_cache(Arc::clone(&cache));
+    assert!(table.get(&entries[12_345].0)?.is_some());
+    assert_eq!((cache.len(), cache.misses(), cache.hits()), (2, 2, 0));
+    // a key in another partition
+    assert!(table.get(&entries[0].0)?.is_some());
+    assert_eq!((cache.len(), cache.misses()), (4, 4));
+    // the last partition read is kept, the cache still serves the earlier one
+    assert!(table.get(&entries[12_345].0)?.is_some());
+    assert_eq!((cache.len(), cache.misses(), cache.hits()), (4, 4, 2));
+    Ok(())
+}
+
+#[test]
+fn test_sstable_concurrent_gets() -> Result<()> {
+    use std::sync::Arc;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let partitioned_path = temp_dir.path().join("partitioned.sst");
+    let entries = sample_entries(10_000);
+    SsTable::create(&path, entries.clone())?;
+    let options = SsTableOptions::default().with_partitioned_index(128);
+    SsTable::create_with_options(&partitioned_path, entries.clone(), &options)?;
+
+    // present and missing keys, answered once on a single thread
+    let probes: Vec<String> = random_bytes(5, 4_000)
+        .chunks(2)
+        .map(|pair| {
+            let n = usize::from(u16::from_le_bytes([pair[0], pair[1]])) % 12_000;
+            format!("key:{n:06}")
+        })
+        .collect();
+    let baseline_table = SsTable::load(&path)?;
+    let baseline: Vec<Option<Option<Vec<u8>>>> = probes
+        .iter()
+        .map(|key| baseline_table.get(key).map(|value| value.map(|v| v.as_option())))
+        .collect::<io::Result<_>>()?;
+    assert!(baseline.iter().any(Option::is_none) && baseline.iter().any(Option::is_some));
+
+    let cache = Arc::new(BlockCache::new(64 * DEFAULT_BLOCK_SIZE));
+    let tables = vec![
+        SsTable::open(&path)?,
+        SsTable::open(&path)?.with_block_cache(Arc::clone(&cache)),
+        SsTable::open(&partitioned_path)?,
+        SsTable::open(&partitioned_path)?.with_block_cache(Arc::clone(&cache)),
+        #[cfg(feature = "mmap")]
+        SsTable::open_mmap(&path)?,
+    ];
+    for table in &tables {
+        assert_send_sync(table);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..12)
+                .map(|thread| {
+                    let (probes, baseline) = (&probes, &baseline);
+                    scope.spawn(move || -> io::Result<()> {
+                        // each thread walks a share of the probes from its own offset and stride
+                        for step in 0..probes.len() / 4 {
+                            let i = (thread * 997 + step * (2 * thread + 1)) % probes.len();
+                            let value = table.get(&probes[i])?.map(|v| v.as_option());
+                            assert_eq!(value, baseline[i], "thread {thread} key {}", probes[i]);
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| worker.join().expect("worker panicked"))
+        })?;
+    }
+    assert!(cache.hits() > 0);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_reverse_comparator() -> Result<()> {
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let reverse: Arc<dyn Comparator> = Arc::new(ReverseBytewiseComparator);
+    let options = SsTableOptions::default().with_comparator(Arc::clone(&reverse));
+    let mut entries = sample_entries(3_000);
+    entries.reverse();
+
+    // an ascending input is out of order for the reverse comparator
+    let err = SsTable::create_with_options(dir.join("bad.sst"), sample_entries(10), &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let path = dir.join("reverse.sst");
+    let partitioned_path = dir.join("partitioned.sst");
+    let created = SsTable::create_with_options(&path, entries.clone(), &options)?;
+    let partitioned_options = options.clone().with_partitioned_index(128).with_prefix_compression(true);
+    SsTable::create_with_options(&partitioned_path, entries.clone(), &partitioned_options)?;
+    assert_eq!(created.comparator().name(), ReverseBytewiseComparator.name());
+    assert_eq!(created.key_range(), (&b"key:002999"[..], &b"key:000000"[..]));
+
+    // the comparator is recorded, so the default byte-wise order is refused
+    assert_eq!(SsTable::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(SsTable::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    let report = SsTable::verify(&path)?;
+    assert!(matches!(report.errors.as_slice(), [VerifyError::Metadata(_)]), "{:?}", report.errors);
+    let verify_options = VerifyOptions::default().with_comparator(Arc::clone(&reverse));
+    assert!(SsTable::verify_with_options(&path, &verify_options)?.is_ok());
+    let plain = SsTable::create(dir.join("plain.sst"), sample_entries(10))?;
+    let err = SsTable::open_with_comparator(plain.path(), Arc::clone(&reverse)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let tables = vec![
+        created,
+        SsTable::open_with_comparator(&path, Arc::clone(&reverse))?,
+        SsTable::load_with_comparator(&path, Arc::clone(&reverse))?,
+        SsTable::open_with_comparator(&partitioned_path, Arc::clone(&reverse))?,
+        #[cfg(feature = "mmap")]
+        SsTable::open_mmap_with_comparator(&path, Arc::clone(&reverse))?,
+    ];
+    let keys = ["key:000001", "key:001234", "key:002999", "key:000000", "key:003000", "a", "z"];
+    for table in &tables {
+        for (key, value) in &entries {
+            assert_eq!(table.get(key)?.map(|v| v.as_option()), Some(value.as_option()), "{key}");
+        }
+        assert!(table.get("key:003000")?.is_none());
+        assert_eq!(collect_keys(table.iter())?, entries.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>());
+
+        // bounds follow the comparator, so the start key is the larger one
+        let range = table.range(Bound::Included("key:002000"), Bound::Excluded("key:001000"));
+        let expected: Vec<String> = (1_001..=2_000).rev().map(|i| format!("key:{i:06}")).collect();
+        assert_eq!(collect_keys(range)?, expected);
+        assert!(collect_keys(table.range(Bound::Included("key:001000"), Bound::Included("key:002000")))?.is_empty());
+
+        let values = table.multi_get(&keys)?;
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value.map(|v| v.as_option()), table.get(key)?.map(|v| v.as_option()), "{key}");
+        }
+        assert!(table.might_contain_key("key:000042") && !table.might_contain_key("key:003000"));
+    }
+
+    // merging keeps the order and lets the newest input win
+    let update: Vec<_> = (0..3_000).step_by(3).rev().map(|i| set(&format!("key:{i:06}"), "new")).collect();
+    let newer = SsTable::create_with_options(dir.join("newer.sst"), update, &options)?;
+    let merged = SsTable::merge(dir.join("merged.sst"), &[tables.into_iter().next().expect("table"), newer])?;
+    assert_eq!(merged.comparator().name(), ReverseBytewiseComparator.name());
+    let merged_entries = collect_entries(&merged)?;
+    assert_eq!(merged_entries.len(), 3_000);
+    assert!(merged_entries.windows(2).all(|pair| pair[0].0 > pair[1].0));
+    for (key, value) in &merged_entries {
+        let i: usize = key["key:".len()..].parse()?;
+        if i.is_multiple_of(3) {
+            assert_eq!(value.as_deref(), Some(&b"new"[..]), "{key}");
+        }
+    }
+    let err = SsTable::merge(dir.join("mixed.sst"), &[merged, plain]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_accessors() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let path = dir.join("table.sst");
+    let entries = sample_entries(2_500);
+    let created = SsTable::create(&path, entries.clone())?;
+
+    for table in std::iter::once(created).chain(open_all(&path)?) {
+        assert_eq!(table.len(), 2_500);
+        assert!(!table.is_empty());
+        assert_eq!(table.min_key(), b"key:000000");
+        assert_eq!(table.max_key(), b"key:002499");
+        assert_eq!(table.file_size(), std::fs::metadata(&path)?.len());
+        let stored = table.entries().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(stored.len(), entries.len());
+        for (entry, (key, value)) in stored.iter().zip(&entries) {
+            assert_eq!(entry.key(), key.as_bytes());
+            assert_eq!(entry.value().as_option(), value.as_option());
+            assert_eq!((entry.expires_at(), entry.seqno()), (None, 0));
+        }
+    }
+
+    // entries are yielded as written, expired ones included
+    let expiring = dir.join("expiring.sst");
+    let mut writer = SsTableWriter::new(&expiring, &SsTableOptions::default())?;
+    writer.add_with_seqno("a", &Value::from_bytes(b"1".to_vec()), 7)?;
+    writer.add_with_expiry("b", "2", 1)?;
+    let table = writer.finish()?;
+    let stored = table.entries().collect::<io::Result<Vec<_>>>()?;
+    assert_eq!((stored[0].key(), stored[0].seqno(), stored[0].expires_at()), (&b"a"[..], 7, None));
+    assert_eq!(stored[1].value().as_option(), Some(b"2".to_vec()));
+    assert_eq!(stored[1].expires_at(), Some(1));
+
+    // a level kept sorted by key range
+    let mut level: Vec<SsTable> = [("m", "p"), ("a", "c"), ("m", "n"), ("d", "z")]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (min, max))| SsTable::create(dir.join(format!("{i}.sst")), [set(min, "v"), set(max, "v")]))
+        .collect::<io::Result<_>>()?;
+    level.sort_by_key(|table| KeyRange::from(table));
+    let ranges: Vec<KeyRange> = level.iter().map(KeyRange::from).collect();
+    let bounds: Vec<(&[u8], &[u8])> = ranges.iter().map(|range| (range.min_key(), range.max_key())).collect();
+    assert_eq!(bounds, [(&b"a"[..], &b"c"[..]), (b"d", b"z"), (b"m", b"n"), (b"m", b"p")]);
+    assert!(ranges[2] < ranges[3] && ranges[0] < ranges[1]);
+    Ok(())
+}
+
+/// Appends the operands of a key to its value.
+fn append(_key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+    let mut value = existing.unwrap_or_default().to_vec();
+    operands.iter().for_each(|operand| value.extend_from_slice(operand));
+    value
+}
+
+fn operands(operands: &[&str]) -> Value {
+    Value::Merge(operands.iter().map(|operand| operand.as_bytes().to_vec()).collect())
+}
+
+#[test]
+fn test_sstable_merge_collapses_operands() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let old = SsTable::create(
+        dir.join("old.sst"),
+        vec![
+            ("based", Value::from_bytes(b"base".to_vec()), 1),
+            ("deleted", Value::Deleted, 2),
+            ("stacked", operands(&["a"]), 3),
+        ],
+    )?;
+    let new = SsTable::create(
+        dir.join("new.sst"),
+        vec![
+            ("based", operands(&["1", "2"]), 4),
+            ("deleted", operands(&["x"]), 5),
+            ("stacked", operands(&["b", "c"]), 6),
+        ],
+    )?;
+    // Operands are stored as written, oldest first
+    assert!(matches!(new.get("based")?, Some(Value::Merge(ops)) if ops == [b"1".to_vec(), b"2".to_vec()]));
+    let inputs = [old, new];
+
+    // Operands reaching a value or a tombstone are combined into a value, the others stacked
+    let options = MergeOptions::default().with_merge_function(append);
+    let merged = SsTable::merge_with_options(dir.join("merged.sst"), &inputs, &options)?.unwrap();
+    assert_eq!(merged.len(), 3);
+    assert!(matches!(merged.get("based")?, Some(Value::Bytes(value)) if value == b"base12"));
+    assert!(matches!(merged.get("deleted")?, Some(Value::Bytes(value)) if value == b"x"));
+    assert!(matches!(merged.get("stacked")?, Some(Value::Merge(ops)) if ops == [b"a", b"b", b"c"]));
+    assert_eq!(merged.get_with_seqno("stacked")?.unwrap().1, 6);
+
+    // With nothing older, the stacked operands are combined too
+    let bottom = MergeOptions::default().with_merge_function(append).with_drop_tombstones(true);
+    let merged = SsTable::merge_with_options(dir.join("bottom.sst"), &inputs, &bottom)?.unwrap();
+    assert!(matches!(merged.get("stacked")?, Some(Value::Bytes(value)) if value == b"abc"));
+    assert_eq!(merged.stats().tombstones, 0);
+
+    // Without a merge function operands only stack onto operands
+    let error = SsTable::merge_with_options(dir.join("failed.sst"), &inputs, &MergeOptions::default()).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    let stacked = [SsTable::create(dir.join("a.sst"), vec![("key", operands(&["a"]))])?,
+        SsTable::create(dir.join("b.sst"), vec![("key", operands(&["b"]))])?];
+    let merged = SsTable::merge_with_options(dir.join("stacked.sst"), &stacked, &MergeOptions::default())?.unwrap();
+    assert!(matches!(merged.get("key")?, Some(Value::Merge(ops)) if ops == [b"a", b"b"]));
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_with_merge_operator() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let delta = |n: u64| n.to_le_bytes().to_vec();
+    let old = SsTable::create(
+        dir.join("old.sst"),
+        vec![
+            ("based", Value::from_bytes(delta(100)), 1),
+            ("broken", Value::from_bytes(b"not a counter".to_vec()), 2),
+            ("stacked", Value::Merge(vec![delta(1), delta(2)]), 3),
+        ],
+    )?;
+    let new = SsTable::create(
+        dir.join("new.sst"),
+        vec![("based", Value::Merge(vec![delta(5)]), 4), ("stacked", Value::Merge(vec![delta(3), delta(4)]), 5)],
+    )?;
+    let inputs = [old, new];
+
+    // Operands over a value are combined with it, the others into a single operand
+    let options = MergeOptions::default().with_merge_operator(Arc::new(U64AddOperator));
+    let merged = SsTable::merge_with_options(dir.join("merged.sst"), &inputs, &options)?.unwrap();
+    assert_eq!(merged.get("based")?, Some(Value::from_bytes(delta(105))));
+    assert_eq!(merged.get("stacked")?, Some(Value::Merge(vec![delta(10)])));
+    let bottom = options.clone().with_drop_tombstones(true);
+    let merged = SsTable::merge_with_options(dir.join("bottom.sst"), &inputs, &bottom)?.unwrap();
+    assert_eq!(merged.get("stacked")?, Some(Value::from_bytes(delta(10))));
+
+    // An append operator keeps the order of the operands
+    let options = MergeOptions::default().with_merge_operator(Arc::new(AppendOperator));
+    let merged = SsTable::merge_with_options(dir.join("appended.sst"), &inputs, &options)?.unwrap();
+    let expected: Vec<u8> = [delta(1), delta(2), delta(3), delta(4)].concat();
+    assert_eq!(merged.get("stacked")?, Some(Value::Merge(vec![expected])));
+
+    // Operands the operator cannot combine with the value fail the merge
+    let broken = SsTable::create(dir.join("broken.sst"), vec![("broken", Value::Merge(vec![delta(1)]), 6)])?;
+    let inputs = [SsTable::open(dir.join("old.sst"))?, broken];
+    let options = MergeOptions::default().with_merge_operator(Arc::new(U64AddOperator));
+    let err = SsTable::merge_with_options(dir.join("failed.sst"), &inputs, &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("\"broken\""), "{err}");
+    Ok(())
+}
+
+/// Writes a table holding `keys`, each with its sequence number and itself as the value, and
+/// the range tombstones `ranges` as `(start, end, seqno)`.
+fn table_with_range_deletes(path: &Path, keys: &[(&str, u64)], ranges: &[(&str, &str, u64)]) -> io::Result<SsTable> {
+    let mut writer = SsTableWriter::new(path, &SsTableOptions::default())?;
+    for &(key, seqno) in keys {
+        writer.add_with_seqno(key, &Value::from_bytes(key.as_bytes().to_vec()), seqno)?;
+    }
+    for &(start, end, seqno) in ranges {
+        writer.add_range_delete(start, end, seqno);
+    }
+    writer.finish()
+}
+
+#[test]
+fn test_sstable_range_tombstone_boundaries() -> Result<()> {
+    use std::ops::Bound;
+
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let keys = [("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 5), ("f", 20)];
+    // Empty and reversed ranges delete nothing and are left out
+    let ranges = [("f", "g", 10), ("b", "d", 10), ("e", "e", 10), ("e", "a", 10)];
+    let created = table_with_range_deletes(&path, &keys, &ranges)?;
+    assert_eq!(created.format_version(), FORMAT_VERSION);
+    assert!(SsTable::verify(&path)?.is_ok());
+
+    for table in std::iter::once(created).chain(open_all(&path)?) {
+        assert_eq!(table.range_tombstones(), [RangeTombstone::new("b", "d", 10), RangeTombstone::new("f", "g", 10)]);
+        // The end of a range counts in the key range of the table
+        assert_eq!(table.key_range(), (&b"a"[..], &b"g"[..]));
+        let present = |key: &str| matches!(table.get(key), Ok(Some(Value::Bytes(value))) if value == key.as_bytes());
+        let deleted = |key: &str| matches!(table.get(key), Ok(Some(Value::Deleted)));
+
+        // The start is deleted, the end is not, and neither is a key written after the tombstone
+        assert!(present("a"));
+        assert!(deleted("b"));
+        assert!(deleted("c"));
+        assert!(present("d"));
+        assert!(present("e"));
+        assert!(present("f"));
+        // Keys without an entry inside a range read as tombstones, to hide older tables
+        assert!(deleted("bb"));
+        assert!(deleted("ff"));
+        assert!(table.might_contain_key("bb"));
+        assert!(table.get("g")?.is_none());
+        assert!(!table.might_contain_key("g"));
+        assert!(matches!(table.get_with_seqno("c")?, Some((Value::Deleted, 10))));
+        assert!(matches!(table.get_with_seqno("f")?, Some((Value::Bytes(_), 20))));
+        let found = table.multi_get(&["a", "b", "d", "bb", "g"])?;
+        assert!(matches!(found.as_slice(), [Some(Value::Bytes(_)), Some(Value::Deleted), Some(Value::Bytes(_)),
+            Some(Value::Deleted), None]));
+
+        // Scans skip the deleted keys, raw entries keep them
+        assert_eq!(collect_keys(table.iter())?, ["a", "d", "e", "f"]);
+        assert_eq!(collect_keys(table.range::<str>(Bound::Included("b"), Bound::Included("d")))?, ["d"]);
+        let reversed: Vec<_> = table.iter().rev().map(|item| item.map(|(key, _)| key)).collect::<io::Result<_>>()?;
+        assert_eq!(reversed, [b"f".to_vec(), b"e".to_vec(), b"d".to_vec(), b"a".to_vec()]);
+        assert_eq!(table.entries().count(), 6);
+    }
+
+    // A table may hold nothing but range tombstones
+    let only = temp_dir.path().join("only.sst");
+    let table = table_with_range_deletes(&only, &[], &[("m", "p", 1)])?;
+    assert!(table.is_empty());
+    assert_eq!(table.key_range(), (&b"m"[..], &b"p"[..]));
+    assert!(SsTable::verify(&only)?.is_ok());
+    for table in open_all(&only)? {
+        assert!(matches!(table.get("m")?, Some(Value::Deleted)));
+        assert!(table.get("p")?.is_none());
+        assert_eq!(table.iter().count(), 0);
+    }
+    let empty = table_with_range_deletes(&temp_dir.path().join("empty.sst"), &[], &[("m", "m", 1)]);
+    assert_eq!(empty.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_drops_range_deleted_entries() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path();
+    let old = SsTable::create(
+        dir.join("old.sst"),
+        vec![
+            ("a", Value::from_bytes(b"old".to_vec()), 1),
+            ("b", Value::from_bytes(b"old".to_vec()), 2),
+            ("c", Value::from_bytes(b"old".to_vec()), 3),
+            ("d", Value::from_bytes(b"old".to_vec()), 4),
+            ("m", Value::from_bytes(b"base".to_vec()), 5),
+        ],
+    )?;
+    let mut writer = SsTableWriter::new(dir.join("new.sst"), &SsTableOptions::default())?;
+    writer.add_with_seqno("c", &Value::from_bytes(b"new".to_vec()), 11)?;
+    writer.add_with_seqno("m", &operands(&["x"]), 13)?;
+    writer.add_range_delete("b", "d", 10);
+    writer.add_range_delete("m", "n", 12);
+    let inputs = [old, writer.finish()?];
+
+    // Deleted entries are dropped, operands stack onto a range tombstone as onto a tombstone
+    let options = MergeOptions::default().with_merge_function(append);
+    let merged = SsTable::merge_with_options(dir.join("merged.sst"), &inputs, &options)?.unwrap();
+    let keys: Vec<_> = merged
+        .entries()
+        .map(|entry| entry.map(|entry| entry.key().to_vec()))
+        .collect::<io::Result<_>>()?;
+    assert_eq!(keys, [b"a".to_vec(), b"c".to_vec(), b"d".to_vec(), b"m".to_vec()]);
+    assert!(matches!(merged.get("c")?, Some(Value::Bytes(value)) if value == b"new"));
+    assert!(matches!(merged.get("m")?, Some(Value::Bytes(value)) if value == b"x"));
+    // The range tombstones are kept for the older tables
+    assert_eq!(merged.range_tombstones().len(), 2);
+    assert!(matches!(merged.get("b")?, Some(Value::Deleted)));
+
+    // At the bottom they are dropped with the entries they delete
+    let bottom = options.clone().with_drop_tombstones(true);
+    let merged = SsTable::merge_with_options(dir.join("bottom.sst"), &inputs, &bottom)?.unwrap();
+    assert!(merged.range_tombstones().is_empty());
+    assert_eq!(collect_keys(merged.iter())?, ["a", "c", "d", "m"]);
+    assert!(merged.get("b")?.is_none());
+
+    // Range tombstones alone survive a merge, but not one at the bottom
+    let only = [table_with_range_deletes(&dir.join("only.sst"), &[], &[("x", "z", 1)])?];
+    let merged = SsTable::merge_with_options(dir.join("kept.sst"), &only, &options)?.unwrap();
+    assert_eq!(merged.range_tombstones(), [RangeTombstone::new("x", "z", 1)]);
+    assert!(SsTable::merge_with_options(dir.join("dropped.sst"), &only, &bottom)?.is_none());
+    assert_eq!(SsTable::merge_split(dir.join("split.sst"), &only, &options)?.len(), 1);
+    assert!(SsTable::merge_split(dir.join("split_bottom.sst"), &only, &bottom)?.is_empty());
+
+    // Without sequence numbers a range tombstone deletes the entries of older inputs only
+    let unsequenced = [
+        SsTable::create(dir.join("first.sst"), vec![set("k", "old")])?,
+        table_with_range_deletes(&dir.join("second.sst"), &[("k", 0), ("l", 0)], &[("k", "m", 0)])?,
+    ];
+    let merged = SsTable::merge_with_options(dir.join("unsequenced.sst"), &unsequenced, &options)?.unwrap();
+    assert!(matches!(merged.get("k")?, Some(Value::Bytes(value)) if value == b"k"));
+    assert_eq!(collect_keys(merged.iter())?, ["k", "l"]);
+    Ok(())
+}
+
+#[test]
+fn test_sstable_write_timestamps_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    let options = SsTableOptions::default().with_write_clock(|| 1_700_000_000_000);
+    let entries = vec![("a", Value::from_bytes(b"1".to_vec()), 3), ("b", Value::tombstone(), 4)];
+    let created = SsTable::create_with_options(&path, entries, &options)?;
+
+    for table in std::iter::once(created).chain(open_all(&path)?) {
+        let (value, meta) = table.get_with_metadata("a")?.expect("a is present");
+        assert_eq!(value.as_option(), Some(b"1".to_vec()));
+        assert_eq!(meta, RecordMeta { seqno: 3, written_at: Some(1_700_000_000_000), expires_at: None });
+        let (value, meta) = table.get_with_metadata("b")?.expect("b is deleted");
+        assert!(matches!(value, Value::Deleted));
+        assert_eq!((meta.seqno, meta.written_at), (4, Some(1_700_000_000_000)));
+        assert!(table.get_with_metadata("c")?.is_none());
+        assert!(table.entries().all(|entry| entry.unwrap().written_at() == Some(1_700_000_000_000)));
+    }
+
+    let mut writer = SsTableWriter::new(temp_dir.path().join("expiring.sst"), &options)?;
+    writer.add_with_expiry("session", "token", 2_000_000_000_000)?;
+    let table = writer.finish()?;
+    let (_, meta) = table.get_with_metadata("session")?.expect("session is present");
+    assert_eq!(meta.written_at, Some(1_700_000_000_000));
+    assert_eq!(meta.expires_at, Some(2_000_000_000_000));
+    Ok(())
+}
+
+#[test]
+fn test_sstable_merge_keeps_write_timestamps() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let older = SsTable::create_with_options(
+        temp_dir.path().join("older.sst"),
+        vec![("a", Value::from_bytes(b"old".to_vec()), 1), ("b", Value::from_bytes(b"old".to_vec()), 2)],
+        &SsTableOptions::default().with_write_clock(|| 1_000),
+    )?;
+    let newer = SsTable::create_with_options(
+        temp_dir.path().join("newer.sst"),
+        vec![("b", Value::from_bytes(b"new".to_vec()), 3)],
+        &SsTableOptions::default().with_write_clock(|| 2_000),
+    )?;
+    // the output has a write clock of its own, which the merged entries ignore
+    let options = MergeOptions::default().with_table_options(SsTableOptions::default().with_write_clock(|| 9_000));
+    let merged = SsTable::merge_with_options(temp_dir.path().join("merged.sst"), &[older, newer], &options)?
+        .expect("merge has entries");
+
+    for table in std::iter::once(merged).chain(open_all(&temp_dir.path().join("merged.sst"))?) {
+        assert_eq!(table.get_with_metadata("a")?.expect("a is present").1.written_at, Some(1_000));
+        let (value, meta) = table.get_with_metadata("b")?.expect("b is present");
+        assert_eq!(value.as_option(), Some(b"new".to_vec()));
+        assert_eq!((meta.seqno, meta.written_at), (3, Some(2_000)));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sstable_without_write_timestamps() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("table.sst");
+    SsTable::create(&path, sample_entries(10))?;
+
+    // tables written before timestamps existed hold the same records, under version 9. The
+    // footer crc covers the version, so it is computed again
+    let mut bytes = std::fs::read(&path)?;
+    let trailer = bytes.len() - 18;
+    bytes[trailer + 8..trailer + 10].copy_from_slice(&9u16.to_le_bytes());
+    let footer_offset = u64::from_le_bytes(bytes[trailer..trailer + 8].try_into()?) as usize;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&bytes[footer_offset..trailer - 4]);
+    hasher.update(&bytes[trailer..trailer + 10]);
+    let crc = hasher.finalize();
+    bytes[trailer - 4..trailer].copy_from_slice(&crc.to_le_bytes());
+    std::fs::write(&path, bytes)?;
+
+    for table in open_all(&path)? {
+        assert_eq!(table.format_version(), 9);
+        let (key, _) = &sample_entries(10)[0];
+        let (_, meta) = table.get_with_metadata(key)?.expect("key is present");
+        assert_eq!(meta, RecordMeta::default());
+        assert!(table.entries().all(|entry| entry.unwrap().written_at().is_none()));
+    }
+
+    // a merge without inputs timestamps keeps them unstamped, whatever its own write clock
+    let options = MergeOptions::default().with_table_options(SsTableOptions::default().with_write_clock(|| 9_000));
+    let merged = SsTable::merge_with_options(temp_dir.path().join("merged.sst"), &[SsTable::open(&path)?], &options)?
+        .expect("merge has entries");
+    assert!(merged.entries().all(|entry| entry.unwrap().written_at().is_none()));
+    Ok(())
+}
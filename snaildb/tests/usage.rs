@@ -1,6 +1,14 @@
-use snaildb::SnailDb;
+use snaildb::{CasResult, Db, RestoreOptions, SnailDb, WriteBatch, WriteStallOptions, DEFAULT_CF};
+use snaildb::storage::{CompactionStrategy, LeveledOptions, Scan, SizeTieredOptions, SsTable};
+use snaildb::utils::{AppendOperator, RecordKind, U64AddOperator, Value, write_record};
+use snaildb::wal::RecoveryMode;
 use anyhow::Result;
 use tempfile::TempDir;
+use std::io;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 #[test]
 fn test_basic_operations() -> Result<()> {
@@ -32,21 +40,2225 @@ fn test_basic_operations() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_reopen_reads_what_was_written() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+
+    // Values and tombstones in two tables and the WAL, newest first
+    let mut db = Db::open(&db_path)?;
+    db.put("a", b"1")?;
+    db.put("b", b"1")?;
+    db.put("c", b"1")?;
+    db.flush_memtable()?;
+    db.put("a", b"2")?;
+    db.delete("b")?;
+    db.flush_memtable()?;
+    db.put("c", b"3")?;
+    db.delete("a")?;
+    db.put("d", b"3")?;
+    let expected = [("a", None), ("b", None), ("c", Some(b"3".to_vec())), ("d", Some(b"3".to_vec())), ("e", None)];
+    for (key, value) in &expected {
+        assert_eq!(&db.get(key)?, value, "{key}");
+    }
+
+    // Dropping the database syncs the WAL before the next open replays it
+    drop(db);
+    let mut db = Db::open(&db_path)?;
+    assert_eq!(db.sstables.len(), 2);
+    for (key, value) in &expected {
+        assert_eq!(&db.get(key)?, value, "{key}");
+    }
+
+    // And so does closing it
+    db.put("e", b"4")?;
+    db.close()?;
+    let db = Db::open(&db_path)?;
+    for (key, value) in &expected[..4] {
+        assert_eq!(&db.get(key)?, value, "{key}");
+    }
+    assert_eq!(db.get("e")?, Some(b"4".to_vec()));
+    db.close()?;
+    Ok(())
+}
+
 #[test]
 fn test_custom_flush_threshold() -> Result<()> {
     let temp_dir = TempDir::new()?;
-    let db_path = temp_dir.path().join("test_db");
-    
-    let mut db = SnailDb::open(&db_path)?
-        .with_flush_threshold(256 * 1024 * 1024); // Flush memtable after 256 MiB (for testing)
-    
-    // Verify it works
-    db.put("test", b"value")?;
-    assert_eq!(db.get("test")?, Some(b"value".to_vec()));
-    
+    let db_path = temp_dir.path().join("test_db");
+    
+    let mut db = SnailDb::open(&db_path)?
+        .with_flush_threshold(256 * 1024 * 1024); // Flush memtable after 256 MiB (for testing)
+    
+    // Verify it works
+    db.put("test", b"value")?;
+    assert_eq!(db.get("test")?, Some(b"value".to_vec()));
+    
+    Ok(())
+}
+
+#[test]
+fn test_memtable_flushes_at_threshold() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    // An entry takes its key and value and 40 bytes of overhead
+    let mut db = SnailDb::open(&db_path)?.with_flush_threshold(100);
+
+    db.put("k1", [b'v'; 10])?;
+    assert_eq!(db.memtable.size_bytes(), 52);
+    assert!(db.sstables.is_empty());
+    db.put("k2", [b'v'; 10])?;
+    assert_eq!(db.sstables.len(), 1);
+    assert!(db.memtable.is_empty() && db.frozen_memtable.is_none());
+    assert_eq!(db.memtable.size_bytes(), 0);
+
+    // The flushed entries are read from the table, and no longer replayed from the WAL
+    assert_eq!(db.get("k1")?, Some(vec![b'v'; 10]));
+    db.put("k3", b"v")?;
+    drop(db);
+    let db = SnailDb::open(&db_path)?;
+    assert_eq!(db.memtable.len(), 1);
+    assert_eq!(db.get("k2")?, Some(vec![b'v'; 10]));
+    assert_eq!(db.get("k3")?, Some(b"v".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_reads_during_memtable_flush() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open_with_merge_operator(&db_path, Arc::new(AppendOperator))?;
+    db.put("old", b"table")?;
+    db.put("ranged", b"table")?;
+    db.flush_memtable()?;
+
+    db.put("a", b"frozen")?;
+    db.put("b", b"frozen")?;
+    db.put("log", b"x")?;
+    db.delete("old")?;
+    db.delete_range("r", "s")?;
+    db.freeze_memtable()?;
+    assert!(db.memtable.is_empty());
+    assert_eq!(db.frozen_memtable.as_ref().map(|frozen| frozen.memtable.len()), Some(4));
+
+    // Writes go to the new memtable, reads see it over the frozen one over the tables
+    db.put("a", b"new")?;
+    db.delete("b")?;
+    db.merge("log", b"y")?;
+    db.put("c", b"new")?;
+    let expected = [
+        ("a", Some(b"new".to_vec())),
+        ("b", None),
+        ("c", Some(b"new".to_vec())),
+        ("log", Some(b"xy".to_vec())),
+        ("old", None),
+        ("ranged", None),
+    ];
+    for (key, value) in &expected {
+        assert_eq!(&db.get(key)?, value, "{key}");
+    }
+
+    // Flushing the frozen memtable leaves the new one, and the WAL its writes
+    db.flush_frozen_memtable()?;
+    assert!(db.frozen_memtable.is_none());
+    assert_eq!(db.sstables.len(), 2);
+    assert_eq!(db.memtable.len(), 4);
+    for (key, value) in &expected {
+        assert_eq!(&db.get(key)?, value, "{key}");
+    }
+    drop(db);
+    let db = SnailDb::open_with_merge_operator(&db_path, Arc::new(AppendOperator))?;
+    assert_eq!(db.memtable.len(), 4);
+    for (key, value) in &expected {
+        assert_eq!(&db.get(key)?, value, "{key}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tombstones_survive_memtable_flush() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open(&db_path)?;
+    db.put("gone", b"value")?;
+    db.put("kept", b"value")?;
+    db.flush_memtable()?;
+    db.delete("gone")?;
+    assert_eq!(db.memtable.iter().collect::<Vec<_>>(), [("gone".to_string(), Value::Deleted, 3)]);
+    db.flush_memtable()?;
+
+    // The newer table holds the tombstone, which hides the value of the older one
+    assert_eq!(db.sstables[0].get("gone")?, Some(Value::Deleted));
+    assert_eq!(db.sstables[1].get("gone")?, Some(Value::from_bytes(b"value".to_vec())));
+    assert_eq!(db.get("gone")?, None);
+    drop(db);
+    let db = SnailDb::open(&db_path)?;
+    assert!(db.memtable.is_empty());
+    assert_eq!(db.get("gone")?, None);
+    assert_eq!(db.get("kept")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+/// Returns the SSTable files in `dir`, sorted.
+fn sstable_files(dir: &std::path::Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".sst") {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn test_size_tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open(&db_path)?;
+    let mut expected = std::collections::BTreeMap::new();
+
+    // Every flush writes a table of about the same size, rewriting and deleting earlier keys
+    let mut flush = |db: &mut SnailDb, round: usize| -> Result<()> {
+        for i in 0..20 {
+            let key = format!("key{:03}", (round * 7 + i) % 50);
+            if i % 6 == 5 {
+                db.delete(&key)?;
+                expected.remove(&key);
+            } else {
+                let value = format!("{key}@{round}").into_bytes();
+                db.put(&key, value.clone())?;
+                expected.insert(key, value);
+            }
+        }
+        db.flush_memtable()?;
+        db.wait_for_compactions()
+    };
+    for round in 0..3 {
+        flush(&mut db, round)?;
+    }
+    assert_eq!(db.sstables.len(), 3);
+    let before = sstable_files(&db_path)?;
+
+    // The fourth table fills the bucket, merged into one table named after the newest input
+    flush(&mut db, 3)?;
+    assert_eq!(db.sstables.len(), 1);
+    let files = sstable_files(&db_path)?;
+    assert_eq!(files.len(), 1);
+    assert!(before.iter().all(|name| !files.contains(name)), "{files:?}");
+    let first_round = files[0].clone();
+
+    // The merged table shares the bucket of the small tables, so three more flushes fill it
+    for round in 4..7 {
+        flush(&mut db, round)?;
+    }
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(sstable_files(&db_path)?.len(), 1);
+    assert_ne!(sstable_files(&db_path)?[0], first_round);
+
+    // A bucket of tables too different in size waits for tables like its own
+    let mut db = db.with_compaction(SizeTieredOptions::default().with_min_table_size(0));
+    for round in 7..10 {
+        flush(&mut db, round)?;
+    }
+    assert_eq!(db.sstables.len(), 4);
+    flush(&mut db, 10)?;
+    assert_eq!(db.sstables.len(), 2);
+
+    // No live data was lost or brought back, before and after reopening
+    let check = |db: &SnailDb| -> Result<()> {
+        for i in 0..50 {
+            let key = format!("key{i:03}");
+            assert_eq!(db.get(&key)?, expected.get(&key).cloned(), "{key}");
+        }
+        Ok(())
+    };
+    check(&db)?;
+    let files = sstable_files(&db_path)?;
+    drop(db);
+    let db = SnailDb::open(&db_path)?;
+    assert_eq!(sstable_files(&db_path)?, files);
+    check(&db)?;
+    Ok(())
+}
+
+/// Checks that the tables of each level from 1 on are sorted and hold disjoint key ranges, and
+/// that a read of each key consults the one table of the level whose range holds it.
+fn check_levels(db: &SnailDb, keys: &[String]) {
+    for (i, level) in db.levels.iter().enumerate() {
+        for pair in level.windows(2) {
+            assert!(pair[0].max_key() < pair[1].min_key(), "level {} overlaps", i + 1);
+        }
+    }
+    for key in keys {
+        let consulted: Vec<_> = db.tables_for_key(key).map(|table| table.path().to_path_buf()).collect();
+        assert!(consulted.len() <= db.sstables.len() + db.levels.len(), "{key}");
+        for level in &db.levels {
+            let holding: Vec<_> = level
+                .iter()
+                .filter(|table| table.min_key() <= key.as_bytes() && key.as_bytes() <= table.max_key())
+                .collect();
+            assert!(holding.len() <= 1, "{key}");
+            assert!(holding.iter().all(|table| consulted.contains(&table.path().to_path_buf())), "{key}");
+        }
+    }
+}
+
+#[test]
+fn test_leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let options = LeveledOptions::default()
+        .with_l0_compaction_trigger(2)
+        .with_base_level_size(4 * 1024)
+        .with_level_size_multiplier(2)
+        .with_target_file_size(1024)
+        .with_max_levels(5);
+    let mut db = SnailDb::open(&db_path)?.with_compaction(options);
+    let keys: Vec<String> = (0..300).map(|i| format!("key{i:03}")).collect();
+    let mut expected = std::collections::BTreeMap::new();
+
+    let mut state = 7u64;
+    for round in 0..40 {
+        for _ in 0..40 {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let key = &keys[(state >> 33) as usize % keys.len()];
+            if (state >> 20).is_multiple_of(7) {
+                db.delete(key)?;
+                expected.remove(key);
+            } else {
+                let value = format!("{key} written in round {round}").into_bytes();
+                db.put(key, value.clone())?;
+                expected.insert(key.clone(), value);
+            }
+        }
+        db.flush_memtable()?;
+        db.wait_for_compactions()?;
+        assert!(db.sstables.len() < 2);
+        check_levels(&db, &keys);
+    }
+    assert!(db.levels.len() >= 3, "{} levels", db.levels.len());
+    assert!(db.levels.iter().any(|level| level.len() > 1));
+    for key in &keys {
+        assert_eq!(db.get(key)?, expected.get(key).cloned(), "{key}");
+    }
+
+    // The manifest brings the levels back, and the inputs of the compactions are gone
+    let names = |db: &SnailDb| -> Vec<Vec<String>> {
+        db.levels
+            .iter()
+            .map(|level| level.iter().map(|table| table.path().display().to_string()).collect())
+            .collect()
+    };
+    let before = names(&db);
+    let live = db.sstables.len() + db.levels.iter().map(Vec::len).sum::<usize>();
+    drop(db);
+    assert_eq!(sstable_files(&db_path)?.len(), live);
+    let db = SnailDb::open(&db_path)?.with_compaction(options);
+    assert_eq!(names(&db), before);
+    check_levels(&db, &keys);
+    for key in &keys {
+        assert_eq!(db.get(key)?, expected.get(key).cloned(), "{key}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_leveled_compaction_drops_tombstones_at_the_bottom() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    // Level 1 holds nothing under `drain`, and everything under `keep`
+    let drain = LeveledOptions::default()
+        .with_l0_compaction_trigger(1)
+        .with_base_level_size(1)
+        .with_level_size_multiplier(1 << 30)
+        .with_max_levels(3);
+    let keep = drain.with_base_level_size(1 << 30);
+    let tombstones = |level: &Vec<Arc<SsTable>>| level.iter().map(|table| table.stats().tombstones).sum::<u64>();
+
+    let mut db = SnailDb::open(&db_path)?.with_compaction(drain);
+    for i in 0..50 {
+        db.put(format!("key{i:02}"), b"value")?;
+    }
+    db.flush_memtable()?;
+    db.wait_for_compactions()?;
+    assert!(db.sstables.is_empty() && db.levels[0].is_empty());
+    assert_eq!(db.levels[1].iter().map(|table| table.len()).sum::<u64>(), 50);
+
+    // Compacted into level 1, above the values of level 2, the tombstones are kept
+    db.compaction = keep.into();
+    for i in 0..10 {
+        db.delete(format!("key{i:02}"))?;
+    }
+    db.flush_memtable()?;
+    db.wait_for_compactions()?;
+    assert!(db.sstables.is_empty());
+    assert_eq!(tombstones(&db.levels[0]), 10);
+    assert_eq!(db.levels[1].iter().map(|table| table.len()).sum::<u64>(), 50);
+    assert_eq!(db.get("key05")?, None);
+
+    // Compacted into the bottom level, they are dropped with the values they shadow
+    db.compaction = drain.into();
+    assert!(db.compact()?);
+    while db.compact()? {}
+    assert!(db.levels[0].is_empty());
+    assert_eq!(tombstones(&db.levels[1]), 0);
+    assert_eq!(db.levels[1].iter().map(|table| table.len()).sum::<u64>(), 40);
+    assert_eq!(db.get("key05")?, None);
+    assert_eq!(db.get("key15")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+/// Returns the name of the manifest `CURRENT` names.
+fn current_manifest(dir: &std::path::Path) -> Result<String> {
+    Ok(std::fs::read_to_string(dir.join("CURRENT"))?.trim_end().to_string())
+}
+
+#[test]
+fn test_manifest_recovers_from_a_crash_before_a_commit() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open(&db_path)?;
+    db.put("a", b"flushed".to_vec())?;
+    db.flush_memtable()?;
+    db.put("b", b"in the wal".to_vec())?;
+    let live = sstable_files(&db_path)?;
+    drop(db);
+
+    // A crash after a flush or a compaction wrote its tables and before it committed them
+    let stale = |key: &str| (key.to_string(), Value::from_bytes(b"never committed".to_vec()));
+    SsTable::create(db_path.join("sst-99999999999999.sst"), vec![stale("a"), stale("b"), stale("c")])?;
+    SsTable::create(db_path.join("lvl-0.0.sst"), vec![stale("a"), stale("d")])?;
+
+    // Reported, and left on disk under SkipCorrupt
+    let db = SnailDb::open_with_recovery_mode(&db_path, RecoveryMode::SkipCorrupt)?;
+    let mut orphaned: Vec<String> =
+        db.orphaned_files.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+    orphaned.sort();
+    assert_eq!(orphaned, ["lvl-0.0.sst", "sst-99999999999999.sst"]);
+    assert_eq!(db.get("c")?, None);
+    assert_eq!(sstable_files(&db_path)?.len(), live.len() + 2);
+    drop(db);
+
+    let db = SnailDb::open(&db_path)?;
+    assert_eq!(db.orphaned_files.len(), 2);
+    assert_eq!(sstable_files(&db_path)?, live);
+    assert_eq!(db.get("a")?, Some(b"flushed".to_vec()));
+    assert_eq!(db.get("b")?, Some(b"in the wal".to_vec()));
+    assert_eq!(db.get("c")?, None);
+    assert_eq!(db.get("d")?, None);
+
+    // A commit cut short is ignored like one never written
+    let manifest = db_path.join(current_manifest(&db_path)?);
+    drop(db);
+    let mut batch = Vec::new();
+    snaildb::utils::record::encode_write_batch(&mut batch, [(RecordKind::Set, &b"Rsst-0.sst"[..], &b""[..])])?;
+    let mut file = std::fs::OpenOptions::new().append(true).open(&manifest)?;
+    std::io::Write::write_all(&mut file, &batch[..batch.len() - 3])?;
+    drop(file);
+    let db = SnailDb::open(&db_path)?;
+    assert_eq!(db.get("a")?, Some(b"flushed".to_vec()));
+    assert_eq!(sstable_files(&db_path)?, live);
+    Ok(())
+}
+
+#[test]
+fn test_manifest_survives_a_crash_during_rotation() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open(&db_path)?;
+    for round in 0..3 {
+        db.put(format!("key{round}"), format!("value {round}").into_bytes())?;
+        db.flush_memtable()?;
+    }
+    let live = sstable_files(&db_path)?;
+    db.close()?;
+    let current = current_manifest(&db_path)?;
+    let number: u64 = current.trim_start_matches("MANIFEST-").parse()?;
+    let check = |db: &SnailDb| -> Result<()> {
+        for round in 0..3 {
+            assert_eq!(db.get(&format!("key{round}"))?, Some(format!("value {round}").into_bytes()));
+        }
+        Ok(())
+    };
+
+    // A crash while the next manifest was written, before CURRENT was switched to it
+    let next = format!("MANIFEST-{:06}", number + 1);
+    let bytes = std::fs::read(db_path.join(&current))?;
+    std::fs::write(db_path.join(&next), &bytes[..bytes.len() / 2])?;
+    std::fs::write(db_path.join("CURRENT.tmp"), format!("{next}\n"))?;
+    let db = SnailDb::open(&db_path)?;
+    check(&db)?;
+    assert_eq!(sstable_files(&db_path)?, live);
+    db.close()?;
+    let mut manifests = Vec::new();
+    for entry in std::fs::read_dir(&db_path)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("MANIFEST") {
+            manifests.push(name);
+        }
+    }
+    assert_eq!(manifests, [current_manifest(&db_path)?]);
+    assert!(!db_path.join("CURRENT.tmp").exists());
+
+    // A crash after CURRENT was switched, before the former manifest was removed
+    let current = current_manifest(&db_path)?;
+    let number: u64 = current.trim_start_matches("MANIFEST-").parse()?;
+    let next = format!("MANIFEST-{:06}", number + 1);
+    std::fs::copy(db_path.join(&current), db_path.join(&next))?;
+    std::fs::write(db_path.join("CURRENT"), format!("{next}\n"))?;
+    let db = SnailDb::open(&db_path)?;
+    check(&db)?;
+    assert_eq!(sstable_files(&db_path)?, live);
+    assert!(!db_path.join(&current).exists());
+    Ok(())
+}
+
+/// Copies the files of the database at `from` to `to`, as a crash would leave them.
+fn copy_db(from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        match std::fs::copy(entry.path(), to.join(entry.file_name())) {
+            // Gone since it was listed, a temporary file the compaction thread renamed
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            copied => {
+                copied?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a random workload and "crashes" at random points by copying the files of the
+/// database while its WAL writer is still going. Each copy must recover the writes up to
+/// its last sequence number, no less than those acknowledged by a sync or flush, and resume
+/// numbering after it.
+fn crash_and_recover(strategy: snaildb::storage::CompactionStrategy, seed: u64) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open(&db_path)?.with_flush_threshold(2048).with_compaction(strategy);
+    let keys: Vec<String> = (0..40).map(|i| format!("key{i:02}")).collect();
+    // The contents after each number of writes
+    let mut states = vec![std::collections::BTreeMap::<String, Vec<u8>>::new()];
+    let mut acknowledged = 0;
+    let mut state = seed;
+    let mut next = move || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as usize
+    };
+
+    for crash in 0..12 {
+        for _ in 0..next() % 80 {
+            let mut contents = states.last().expect("a state").clone();
+            let key = &keys[next() % keys.len()];
+            match next() % 20 {
+                0 => {
+                    db.flush_memtable()?;
+                    acknowledged = db.last_seqno();
+                    continue;
+                }
+                1 => {
+                    db.wal.force_flush()?;
+                    acknowledged = db.last_seqno();
+                    continue;
+                }
+                2..=4 => {
+                    db.delete(key)?;
+                    contents.remove(key);
+                }
+                5 => {
+                    let start = next() % (keys.len() - 1);
+                    let end = (start + 1 + next() % 5).min(keys.len() - 1);
+                    let (start, end) = (&keys[start], &keys[end]);
+                    db.delete_range(start, end)?;
+                    contents.retain(|other, _| other < start || other >= end);
+                }
+                _ => {
+                    let value = format!("{key} after {} writes", states.len()).into_bytes();
+                    db.put(key, value.clone())?;
+                    contents.insert(key.clone(), value);
+                }
+            }
+            states.push(contents);
+            assert_eq!(db.last_seqno() as usize, states.len() - 1);
+        }
+
+        let crash_path = temp_dir.path().join(format!("crash{crash}"));
+        copy_db(&db_path, &crash_path)?;
+        let mut recovered = SnailDb::open(&crash_path)?;
+        let seqno = recovered.last_seqno();
+        assert!(acknowledged <= seqno && (seqno as usize) < states.len(), "{acknowledged} <= {seqno}");
+        for key in &keys {
+            assert_eq!(recovered.get(key)?, states[seqno as usize].get(key).cloned(), "{key} at seqno {seqno}");
+        }
+        // Numbering resumes after the recovered writes
+        recovered.put("after the crash", b"written".to_vec())?;
+        assert_eq!(recovered.last_seqno(), seqno + 1);
+        recovered.close()?;
+        let reopened = SnailDb::open(&crash_path)?;
+        assert_eq!(reopened.last_seqno(), seqno + 1);
+        assert_eq!(reopened.get("after the crash")?, Some(b"written".to_vec()));
+        assert!(reopened.orphaned_files.is_empty(), "{:?}", reopened.orphaned_files);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_crash_recovery_keeps_acknowledged_writes() -> Result<()> {
+    crash_and_recover(SizeTieredOptions::default().with_min_threshold(3).into(), 1)?;
+    let leveled = LeveledOptions::default()
+        .with_l0_compaction_trigger(2)
+        .with_base_level_size(4 * 1024)
+        .with_target_file_size(1024);
+    crash_and_recover(leveled.into(), 2)
+}
+
+#[test]
+fn test_snapshot_reads_the_values_before_it() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    db.put("kept", b"old".to_vec())?;
+    db.put("deleted", b"old".to_vec())?;
+    db.put("range deleted", b"old".to_vec())?;
+    let snapshot = db.snapshot();
+    assert_eq!(snapshot.seqno(), 3);
+
+    db.put("kept", b"new".to_vec())?;
+    db.delete("deleted")?;
+    db.delete_range("range", "range z")?;
+    db.put("written after", b"new".to_vec())?;
+    for flushed in [false, true] {
+        assert_eq!(db.get_with_snapshot("kept", &snapshot)?, Some(b"old".to_vec()), "flushed: {flushed}");
+        assert_eq!(db.get_with_snapshot("deleted", &snapshot)?, Some(b"old".to_vec()));
+        assert_eq!(db.get_with_snapshot("range deleted", &snapshot)?, Some(b"old".to_vec()));
+        assert_eq!(db.get_with_snapshot("written after", &snapshot)?, None);
+        assert_eq!(db.get("kept")?, Some(b"new".to_vec()));
+        assert_eq!(db.get("deleted")?, None);
+        assert_eq!(db.get("range deleted")?, None);
+        assert_eq!(db.get("written after")?, Some(b"new".to_vec()));
+        db.flush_memtable()?;
+    }
+    // A snapshot of the flushed tables sees the last writes
+    let latest = db.snapshot();
+    assert_eq!(db.get_with_snapshot("kept", &latest)?, Some(b"new".to_vec()));
+    assert_eq!(db.get_with_snapshot("range deleted", &latest)?, None);
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_scans_the_keys_before_it() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    for key in ["a", "b", "c"] {
+        db.put(key, b"old".to_vec())?;
+    }
+    db.flush_memtable()?;
+    db.put("d", b"old".to_vec())?;
+    let snapshot = db.snapshot();
+
+    db.put("a", b"new".to_vec())?;
+    db.delete("b")?;
+    db.delete_range("c", "e")?;
+    db.put("bb", b"new".to_vec())?;
+    db.put("d", b"new".to_vec())?;
+    let old: Vec<_> = ["a", "b", "c", "d"].into_iter().map(|key| (key.to_string(), b"old".to_vec())).collect();
+    for flushed in [false, true] {
+        let scanned = db.scan_with_snapshot("a".."e", &snapshot).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(scanned, old, "flushed: {flushed}");
+        let scanned = db.scan_rev_with_snapshot("a".."e", &snapshot).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(scanned, old.iter().rev().cloned().collect::<Vec<_>>());
+        let scanned = db.scan_with_snapshot("b".."c", &snapshot).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(scanned, [("b".to_string(), b"old".to_vec())]);
+        let latest = db.scan("a".."e").collect::<io::Result<Vec<_>>>()?;
+        let new: Vec<_> = ["a", "bb", "d"].into_iter().map(|key| (key.to_string(), b"new".to_vec())).collect();
+        assert_eq!(latest, new);
+        db.flush_memtable()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_compaction_keeps_the_versions_of_a_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?.with_compaction(SizeTieredOptions::default().with_min_threshold(2));
+    db.put("key", b"old".to_vec())?;
+    db.flush_memtable()?;
+    let snapshot = db.snapshot();
+    db.put("key", b"new".to_vec())?;
+    db.flush_memtable()?;
+
+    // The two tables would merge into one holding only the new value
+    assert_eq!(db.sstables.len(), 2);
+    assert_eq!(db.get_with_snapshot("key", &snapshot)?, Some(b"old".to_vec()));
+    assert_eq!(db.get("key")?, Some(b"new".to_vec()));
+
+    drop(snapshot);
+    assert!(db.compact()?);
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(db.get("key")?, Some(b"new".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_leaves_level_0_bounded_under_leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let leveled = LeveledOptions::default().with_l0_compaction_trigger(2);
+    let stall = WriteStallOptions::default().with_l0_thresholds(3, 5);
+    let mut db = SnailDb::open(temp_dir.path())?.with_compaction(leveled).with_write_stall(stall);
+    db.put("key", b"old".to_vec())?;
+    db.flush_memtable()?;
+    db.put("other", b"old".to_vec())?;
+    db.flush_memtable()?;
+    db.wait_for_compactions()?;
+    assert_eq!((db.sstables.len(), db.levels[0].len()), (0, 1));
+    let snapshot = db.snapshot();
+
+    // Merging level 0 into level 1 would drop the old value, so level 0 is merged into itself
+    let mut most = 0;
+    for round in 0..20 {
+        db.put("key", format!("new {round}").into_bytes())?;
+        db.put(format!("key{round:02}"), b"value".to_vec())?;
+        db.flush_memtable()?;
+        most = most.max(db.sstables.len());
+    }
+    db.wait_for_compactions()?;
+    assert!(most < 5, "{most} tables");
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(db.levels[0].len(), 1);
+    assert_eq!(db.get_with_snapshot("key", &snapshot)?, Some(b"old".to_vec()));
+    assert_eq!(db.get_with_snapshot("key19", &snapshot)?, None);
+    assert_eq!(db.get("key")?, Some(b"new 19".to_vec()));
+    assert_eq!(db.get("key19")?, Some(b"value".to_vec()));
+
+    drop(snapshot);
+    db.put("after", b"value".to_vec())?;
+    db.flush_memtable()?;
+    db.wait_for_compactions()?;
+    assert!(db.sstables.is_empty());
+    assert_eq!(db.get("key")?, Some(b"new 19".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_stacks_merge_operands() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open_with_merge_operator(temp_dir.path(), Arc::new(U64AddOperator))?;
+    db.merge("counter", 1u64.to_le_bytes().to_vec())?;
+    db.flush_memtable()?;
+    db.merge("counter", 2u64.to_le_bytes().to_vec())?;
+    let snapshot = db.snapshot();
+    db.merge("counter", 4u64.to_le_bytes().to_vec())?;
+    assert_eq!(db.get_with_snapshot("counter", &snapshot)?, Some(3u64.to_le_bytes().to_vec()));
+    db.flush_memtable()?;
+    assert_eq!(db.get_with_snapshot("counter", &snapshot)?, Some(3u64.to_le_bytes().to_vec()));
+    assert_eq!(db.get("counter")?, Some(7u64.to_le_bytes().to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_write_batch_applies_its_writes_in_order() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    db.put("deleted", b"before".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.put("key", b"first".to_vec()).put("key", b"second".to_vec()).delete("deleted");
+    assert_eq!(batch.len(), 3);
+    assert!(batch.approximate_size() >= "keyfirstkeyseconddeleted".len());
+    db.write(&batch)?;
+    assert_eq!(db.get("key")?, Some(b"second".to_vec()));
+    assert_eq!(db.get("deleted")?, None);
+    assert_eq!(db.last_seqno(), 4);
+
+    // A batch is reusable once cleared
+    batch.clear();
+    assert!(batch.is_empty());
+    assert_eq!(batch.approximate_size(), 0);
+    db.write(&batch)?;
+    assert_eq!(db.last_seqno(), 4);
+    batch.merge("counter", 1u64.to_le_bytes().to_vec());
+    assert!(db.write(&batch).is_err(), "merging without a merge operator");
+    assert_eq!(db.last_seqno(), 4);
+    db.close()?;
+
+    let reopened = SnailDb::open(temp_dir.path())?;
+    assert_eq!(reopened.get("key")?, Some(b"second".to_vec()));
+    assert_eq!(reopened.get("deleted")?, None);
+    assert_eq!(reopened.last_seqno(), 4);
+    Ok(())
+}
+
+#[test]
+fn test_write_batch_torn_at_the_wal_tail_applies_nothing() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    db.put("before", b"kept".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.put("a", b"1".to_vec()).put("b", b"2".to_vec()).put("c", b"3".to_vec());
+    db.write(&batch)?;
+    db.close()?;
+
+    // A crash in the middle of writing the batch leaves part of its last record
+    let wal_path = temp_dir.path().join("wal.log");
+    let len = std::fs::metadata(&wal_path)?.len();
+    std::fs::OpenOptions::new().write(true).open(&wal_path)?.set_len(len - 3)?;
+    let db = SnailDb::open(temp_dir.path())?;
+    assert_eq!(db.get("before")?, Some(b"kept".to_vec()));
+    for key in ["a", "b", "c"] {
+        assert_eq!(db.get(key)?, None, "{key}");
+    }
+    assert_eq!(db.last_seqno(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_write_batch_interleaves_with_single_writes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open_with_merge_operator(temp_dir.path(), Arc::new(U64AddOperator))?;
+    let mut batch = WriteBatch::new();
+    for round in 0..10u64 {
+        db.put(format!("single {round}"), round.to_le_bytes().to_vec())?;
+        batch.clear();
+        batch.merge("counter", 1u64.to_le_bytes().to_vec()).put(format!("batched {round}"), b"batched".to_vec());
+        db.write(&batch)?;
+        db.merge("counter", 10u64.to_le_bytes().to_vec())?;
+        if round == 5 {
+            db.flush_memtable()?;
+        }
+    }
+    assert_eq!(db.last_seqno(), 40);
+    db.close()?;
+
+    let db = SnailDb::open_with_merge_operator(temp_dir.path(), Arc::new(U64AddOperator))?;
+    assert_eq!(db.last_seqno(), 40);
+    assert_eq!(db.get("counter")?, Some(110u64.to_le_bytes().to_vec()));
+    for round in 0..10u64 {
+        assert_eq!(db.get(&format!("single {round}"))?, Some(round.to_le_bytes().to_vec()));
+        assert_eq!(db.get(&format!("batched {round}"))?, Some(b"batched".to_vec()));
+    }
+    Ok(())
+}
+
+/// Returns the keys a scan yields.
+fn scanned_keys(scan: Scan<'_>) -> Result<Vec<String>> {
+    Ok(scan.map(|item| item.map(|(key, _)| key)).collect::<std::io::Result<_>>()?)
+}
+
+#[test]
+fn test_scan_merges_the_memtables_and_tables() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open_with_merge_operator(temp_dir.path(), Arc::new(U64AddOperator))?;
+    // The same key in three sources, the newest a tombstone
+    db.put("gone", b"oldest".to_vec())?;
+    db.put("kept", b"oldest".to_vec())?;
+    db.merge("counter", 1u64.to_le_bytes().to_vec())?;
+    db.flush_memtable()?;
+    db.put("gone", b"older".to_vec())?;
+    db.put("range 1", b"older".to_vec())?;
+    db.merge("counter", 2u64.to_le_bytes().to_vec())?;
+    db.flush_memtable()?;
+    db.delete("gone")?;
+    db.delete_range("range", "range z")?;
+    db.put("range 2", b"after".to_vec())?;
+    db.merge("counter", 4u64.to_le_bytes().to_vec())?;
+    db.freeze_memtable()?;
+    db.put("kept", b"newest".to_vec())?;
+
+    let scanned: Vec<(String, Vec<u8>)> = db.scan(..).collect::<std::io::Result<_>>()?;
+    assert_eq!(
+        scanned,
+        vec![
+            ("counter".to_string(), 7u64.to_le_bytes().to_vec()),
+            ("kept".to_string(), b"newest".to_vec()),
+            ("range 2".to_string(), b"after".to_vec()),
+        ]
+    );
+    for (key, value) in &scanned {
+        assert_eq!(db.get(key)?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_scan_bounds() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    for key in ["a", "b", "c"] {
+        db.put(key, b"table".to_vec())?;
+    }
+    db.flush_memtable()?;
+    for key in ["d", "e"] {
+        db.put(key, b"memtable".to_vec())?;
+    }
+
+    assert_eq!(scanned_keys(db.scan("b".."d"))?, ["b", "c"]);
+    assert_eq!(scanned_keys(db.scan("b"..="d"))?, ["b", "c", "d"]);
+    assert_eq!(scanned_keys(db.scan((Bound::Excluded("c"), Bound::Unbounded)))?, ["d", "e"]);
+    assert_eq!(scanned_keys(db.scan(.."b"))?, ["a"]);
+    assert_eq!(scanned_keys(db.scan("c"..="c"))?, ["c"]);
+    assert!(scanned_keys(db.scan("c".."c"))?.is_empty());
+    assert!(scanned_keys(db.scan("d".."b"))?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_scan_skips_the_tables_out_of_its_range() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    for i in 0..100 {
+        db.put(format!("x{i:03}"), vec![b'x'; 100])?;
+    }
+    db.flush_memtable()?;
+    for i in 0..100 {
+        db.put(format!("a{i:03}"), vec![b'a'; 100])?;
+    }
+    db.flush_memtable()?;
+
+    // Damage the data blocks of the table of `x` keys
+    let damaged = db.sstables.iter().find(|table| table.min_key() == b"x000").expect("the x table");
+    let len = std::fs::metadata(damaged.path())?.len();
+    let mut file = std::fs::OpenOptions::new().write(true).open(damaged.path())?;
+    std::io::Write::write_all(&mut file, &vec![0xFF; (len / 2) as usize])?;
+    drop(file);
+
+    assert_eq!(scanned_keys(db.scan("a".."b"))?.len(), 100);
+    assert_eq!(scanned_keys(db.scan_prefix("a0"))?.len(), 100);
+    assert!(db.scan("x".."y").any(|item| item.is_err()));
+    Ok(())
+}
+
+#[test]
+fn test_scan_rev_reads_the_newest_versions_backward() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // Compaction is off until the end, so each flush leaves a table, and so is the flush of the
+    // frozen memtable by the next write
+    let stall = WriteStallOptions::default().with_frozen_thresholds(0, 0);
+    let mut db = SnailDb::open(temp_dir.path())?
+        .with_compaction(SizeTieredOptions::default().with_min_threshold(0))
+        .with_write_stall(stall);
+    for i in 0..100 {
+        db.put(format!("item:{i:03}"), b"first".to_vec())?;
+        if i % 25 == 24 {
+            db.flush_memtable()?;
+        }
+    }
+    // Newer versions of some keys in newer sources, and keys around the prefix
+    for i in (0..100).step_by(3) {
+        db.put(format!("item:{i:03}"), b"second".to_vec())?;
+    }
+    db.delete("item:099")?;
+    db.put("a", b"before".to_vec())?;
+    db.put("z", b"after".to_vec())?;
+    db.freeze_memtable()?;
+    db.put("item:096", b"third".to_vec())?;
+
+    let recent: Vec<(String, Vec<u8>)> = db.scan_rev("item:".."item;").take(3).collect::<std::io::Result<_>>()?;
+    assert_eq!(
+        recent,
+        vec![
+            ("item:098".to_string(), b"first".to_vec()),
+            ("item:097".to_string(), b"first".to_vec()),
+            ("item:096".to_string(), b"third".to_vec()),
+        ]
+    );
+    let mut forward: Vec<(String, Vec<u8>)> = db.scan("item:".."item;").collect::<std::io::Result<_>>()?;
+    forward.reverse();
+    let backward: Vec<(String, Vec<u8>)> = db.scan_rev("item:".."item;").collect::<std::io::Result<_>>()?;
+    assert_eq!(backward.len(), 99);
+    assert_eq!(backward, forward);
+
+    // An unbounded end starts from the largest key
+    assert_eq!(scanned_keys(db.scan_rev("item:098"..))?, ["z", "item:098"]);
+    assert_eq!(scanned_keys(db.scan_rev(..="a"))?, ["a"]);
+
+    // A scan dropped early holds up no compaction
+    assert_eq!(db.sstables.len(), 4);
+    assert_eq!(db.scan_rev(..).take(1).count(), 1);
+    db.compaction = SizeTieredOptions::default().with_min_threshold(2).into();
+    db.flush_memtable()?;
+    db.wait_for_compactions()?;
+    assert_eq!(db.sstables.len(), 1);
+    let compacted: Vec<(String, Vec<u8>)> = db.scan_rev("item:".."item;").collect::<std::io::Result<_>>()?;
+    assert_eq!(compacted, backward);
+    Ok(())
+}
+
+#[test]
+fn test_scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    let keys = [
+        "a", "a\u{D7FF}", "a\u{D7FF}x", "a\u{E000}", "a\u{10FFFF}", "a\u{10FFFF}x", "b", "\u{10FFFF}", "\u{10FFFF}z",
+    ];
+    for (i, key) in keys.iter().enumerate() {
+        db.put(*key, b"value".to_vec())?;
+        if i % 3 == 2 {
+            db.flush_memtable()?;
+        }
+    }
+    db.delete("a\u{D7FF}x")?;
+
+    assert_eq!(scanned_keys(db.scan_prefix("a\u{D7FF}"))?, ["a\u{D7FF}"]);
+    // Prefixes ending with the largest character have no next one to end their range with
+    assert_eq!(scanned_keys(db.scan_prefix("a\u{10FFFF}"))?, ["a\u{10FFFF}", "a\u{10FFFF}x"]);
+    assert_eq!(scanned_keys(db.scan_prefix("\u{10FFFF}"))?, ["\u{10FFFF}", "\u{10FFFF}z"]);
+    assert_eq!(scanned_keys(db.scan_prefix("\u{10FFFF}\u{10FFFF}"))?, Vec::<String>::new());
+    // An empty prefix scans every key, and one longer than any key none
+    let all: Vec<&str> = keys.iter().copied().filter(|key| *key != "a\u{D7FF}x").collect();
+    assert_eq!(scanned_keys(db.scan_prefix(""))?, all);
+    assert!(scanned_keys(db.scan_prefix("a\u{10FFFF}x and more"))?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_multi_get_matches_get() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?
+        .with_flush_threshold(1024)
+        .with_compaction(SizeTieredOptions::default().with_min_threshold(3));
+    let mut state = 7u64;
+    let mut next = move || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as usize
+    };
+    for i in 0..600 {
+        let key = format!("key{:03}", next() % 200);
+        match next() % 10 {
+            0..=1 => db.delete(key)?,
+            2 if i % 50 == 0 => db.delete_range(key, format!("key{:03}", next() % 200))?,
+            _ => db.put(key, format!("value {i}").into_bytes())?,
+        }
+    }
+    db.freeze_memtable()?;
+    db.put("key000", b"newest".to_vec())?;
+
+    // Hits, misses, tombstones and keys given twice
+    let keys: Vec<String> = (0..150).map(|_| format!("key{:03}", next() % 250)).collect();
+    let keys: Vec<&str> = keys.iter().map(String::as_str).chain(["key000", "key000"]).collect();
+    let values = db.multi_get(&keys);
+    assert_eq!(values.len(), keys.len());
+    let mut found = 0;
+    for (key, value) in keys.iter().zip(values) {
+        let value = value?;
+        found += usize::from(value.is_some());
+        assert_eq!(value, db.get(key)?, "{key}");
+    }
+    assert!(found > 0 && found < keys.len(), "{found} of {} found", keys.len());
+    Ok(())
+}
+
+#[test]
+fn test_multi_get_looks_up_a_key_in_one_table_per_level() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let leveled = LeveledOptions::default()
+        .with_l0_compaction_trigger(2)
+        .with_base_level_size(4 * 1024)
+        .with_target_file_size(1024);
+    let mut db = SnailDb::open(temp_dir.path())?.with_compaction(leveled);
+    for round in 0..10 {
+        for i in 0..60 {
+            db.put(format!("key{:03}", (i * 7 + round * 13) % 300), format!("value {round}").into_bytes())?;
+        }
+        db.flush_memtable()?;
+        db.wait_for_compactions()?;
+    }
+    assert!(db.levels.iter().any(|level| level.len() > 2));
+
+    // As many bloom filters asked as by a get of each key
+    let keys: Vec<String> = (0..320).step_by(3).map(|i| format!("key{i:03}")).collect();
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let bloom_checks = |db: &SnailDb| db.stats().bloom_hits + db.stats().bloom_skips;
+    let before = bloom_checks(&db);
+    let values = db.multi_get(&keys);
+    let by_multi_get = bloom_checks(&db) - before;
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value?, db.get(key)?, "{key}");
+    }
+    assert_eq!(by_multi_get, bloom_checks(&db) - before - by_multi_get);
+    Ok(())
+}
+
+#[test]
+fn test_multi_get_fails_the_keys_of_a_damaged_table_only() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    for i in 0..100 {
+        db.put(format!("x{i:03}"), vec![b'x'; 100])?;
+    }
+    db.flush_memtable()?;
+    for i in 0..100 {
+        db.put(format!("a{i:03}"), vec![b'a'; 100])?;
+    }
+    db.flush_memtable()?;
+    db.put("x001", b"in the memtable".to_vec())?;
+    let damaged = db.sstables.iter().find(|table| table.min_key() == b"x000").expect("the x table");
+    let len = std::fs::metadata(damaged.path())?.len();
+    let mut file = std::fs::OpenOptions::new().write(true).open(damaged.path())?;
+    std::io::Write::write_all(&mut file, &vec![0xFF; (len / 2) as usize])?;
+    drop(file);
+
+    let values = db.multi_get(&["a001", "x000", "x001", "a002"]);
+    assert_eq!(values[0].as_ref().ok(), Some(&Some(vec![b'a'; 100])));
+    assert!(values[1].is_err());
+    assert_eq!(values[2].as_ref().ok(), Some(&Some(b"in the memtable".to_vec())));
+    assert_eq!(values[3].as_ref().ok(), Some(&Some(vec![b'a'; 100])));
+    Ok(())
+}
+
+#[test]
+fn test_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    assert_eq!(db.compare_and_swap("key", None, Some(b"first".to_vec()))?, CasResult::Swapped);
+    assert_eq!(
+        db.compare_and_swap("key", None, Some(b"second".to_vec()))?,
+        CasResult::Mismatch { current: Some(b"first".to_vec()) }
+    );
+    // A mismatch writes nothing
+    assert_eq!(db.last_seqno(), 1);
+    assert_eq!(db.compare_and_swap("key", Some(b"first"), Some(b"second".to_vec()))?, CasResult::Swapped);
+    assert_eq!(db.compare_and_swap("key", Some(b"second"), None)?, CasResult::Swapped);
+    assert_eq!(db.get("key")?, None);
+
+    // A tombstoned or range deleted key is absent
+    assert_eq!(db.compare_and_swap("key", Some(b"second"), None)?, CasResult::Mismatch { current: None });
+    assert_eq!(db.compare_and_swap("key", None, Some(b"third".to_vec()))?, CasResult::Swapped);
+    db.flush_memtable()?;
+    db.delete_range("k", "l")?;
+    assert_eq!(db.compare_and_swap("key", None, Some(b"fourth".to_vec()))?, CasResult::Swapped);
+    assert_eq!(db.get("key")?, Some(b"fourth".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_compare_and_swap_loops_lose_no_increment() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db = Arc::new(std::sync::Mutex::new(SnailDb::open(temp_dir.path())?.with_flush_threshold(512)));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        // Read without holding the lock, so the other threads can write in between
+                        let current = db.lock().unwrap().get("counter")?;
+                        let count = current.as_deref().map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+                        let new = (count + 1).to_le_bytes().to_vec();
+                        match db.lock().unwrap().compare_and_swap("counter", current.as_deref(), Some(new))? {
+                            CasResult::Swapped => break,
+                            CasResult::Mismatch { .. } => std::thread::yield_now(),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("a CAS thread")?;
+    }
+    let db = db.lock().unwrap();
+    assert_eq!(db.get("counter")?, Some(200u64.to_le_bytes().to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_ttl_expires_at_the_boundary() -> Result<()> {
+    static NOW: AtomicU64 = AtomicU64::new(1_000);
+    fn clock() -> u64 {
+        NOW.load(Ordering::SeqCst)
+    }
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?.with_clock(clock);
+    db.put_with_ttl("session", b"token".to_vec(), Duration::from_millis(100))?;
+    db.put("user", b"alice".to_vec())?;
+
+    // In the memtable, replayed from the WAL, then in a table
+    for stage in ["memtable", "reopened", "flushed"] {
+        if stage == "reopened" {
+            db.close()?;
+            db = SnailDb::open(temp_dir.path())?.with_clock(clock);
+        } else if stage == "flushed" {
+            db.flush_memtable()?;
+        }
+        NOW.store(1_099, Ordering::SeqCst);
+        assert_eq!(db.get("session")?, Some(b"token".to_vec()), "{stage}");
+        assert_eq!(db.ttl("session")?, Some(Duration::from_millis(1)), "{stage}");
+        assert_eq!(db.ttl("user")?, None, "{stage}");
+        assert_eq!(scanned_keys(db.scan(..))?, ["session", "user"], "{stage}");
+
+        NOW.store(1_100, Ordering::SeqCst);
+        assert_eq!(db.get("session")?, None, "{stage}");
+        assert_eq!(db.ttl("session")?, None, "{stage}");
+        assert_eq!(scanned_keys(db.scan(..))?, ["user"], "{stage}");
+        assert_eq!(scanned_keys(db.scan_rev(..))?, ["user"], "{stage}");
+        let found: Vec<Option<Vec<u8>>> = db.multi_get(&["session", "user"]).into_iter().collect::<io::Result<_>>()?;
+        assert_eq!(found, [None, Some(b"alice".to_vec())], "{stage}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_expired_value_shadows_older_value() -> Result<()> {
+    static NOW: AtomicU64 = AtomicU64::new(1_000);
+    fn clock() -> u64 {
+        NOW.load(Ordering::SeqCst)
+    }
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?.with_clock(clock);
+    db.put("key", b"old".to_vec())?;
+    db.flush_memtable()?;
+    db.put_with_ttl("key", b"new".to_vec(), Duration::from_millis(10))?;
+    assert_eq!(db.get("key")?, Some(b"new".to_vec()));
+
+    NOW.store(1_010, Ordering::SeqCst);
+    for flushed in [false, true] {
+        assert_eq!(db.get("key")?, None, "flushed: {flushed}");
+        assert_eq!(scanned_keys(db.scan(..))?, Vec::<String>::new(), "flushed: {flushed}");
+        db.flush_memtable()?;
+    }
+
+    // Deleting the expired key is still a write
+    let seqno = db.last_seqno();
+    db.delete("key")?;
+    assert_eq!(db.last_seqno(), seqno + 1);
+    assert_eq!(db.get("key")?, None);
+    db.put("key", b"again".to_vec())?;
+    assert_eq!(db.get("key")?, Some(b"again".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_compaction_drops_expired_rows() -> Result<()> {
+    static NOW: AtomicU64 = AtomicU64::new(1_000);
+    fn clock() -> u64 {
+        NOW.load(Ordering::SeqCst)
+    }
+    let temp_dir = TempDir::new()?;
+    let leveled = LeveledOptions::default().with_l0_compaction_trigger(2);
+    let mut db = SnailDb::open(temp_dir.path())?.with_compaction(leveled).with_clock(clock);
+    for i in 0..50 {
+        db.put_with_ttl(format!("expiring{i:02}"), b"soon gone".to_vec(), Duration::from_millis(10))?;
+        db.put_with_ttl(format!("lasting{i:02}"), b"kept".to_vec(), Duration::from_secs(3600))?;
+    }
+    db.flush_memtable()?;
+    assert_eq!(db.sstables[0].entries().count(), 100);
+
+    NOW.store(2_000, Ordering::SeqCst);
+    db.put("other", b"value".to_vec())?;
+    db.flush_memtable()?;
+    db.wait_for_compactions()?;
+    assert!(db.sstables.is_empty());
+    let mut stored = Vec::new();
+    for table in db.levels.iter().flatten() {
+        for entry in table.entries() {
+            stored.push(String::from_utf8(entry?.key().to_vec())?);
+        }
+    }
+    let mut expected: Vec<String> = (0..50).map(|i| format!("lasting{i:02}")).collect();
+    expected.push("other".to_string());
+    assert_eq!(stored, expected);
+    assert_eq!(db.ttl("lasting00")?, Some(Duration::from_millis(3_600_000 - 1_000)));
+    Ok(())
+}
+
+#[test]
+fn test_column_families_keep_keys_apart() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    let users = db.create_cf("users")?;
+    assert!(db.create_cf("users").is_err());
+    assert_eq!(db.cf("users"), Some(users.clone()));
+    assert!(db.cf("missing").is_none());
+    let default = db.cf(DEFAULT_CF).expect("the default family");
+
+    db.put("key", b"default value".to_vec())?;
+    db.put_cf(&users, "key", b"users value".to_vec())?;
+    db.put_cf(&users, "other", b"only in users".to_vec())?;
+    for round in 0..2 {
+        assert_eq!(db.get("key")?, Some(b"default value".to_vec()), "round {round}");
+        assert_eq!(db.get_cf(&default, "key")?, Some(b"default value".to_vec()));
+        assert_eq!(db.get_cf(&users, "key")?, Some(b"users value".to_vec()));
+        assert_eq!(db.get("other")?, None);
+        assert_eq!(scanned_keys(db.scan_cf(&users, ..)?)?, vec!["key", "other"]);
+        db.flush_memtable()?;
+    }
+    assert!(db.sstables.iter().all(|table| table.get("other").ok().flatten().is_none()));
+
+    db.delete_cf(&users, "key")?;
+    assert_eq!(db.get_cf(&users, "key")?, None);
+    assert_eq!(db.get("key")?, Some(b"default value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_column_families_take_every_write() -> Result<()> {
+    static NOW: AtomicU64 = AtomicU64::new(1_000);
+    fn clock() -> u64 {
+        NOW.load(Ordering::SeqCst)
+    }
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("db");
+    let mut db = SnailDb::open_with_merge_operator(&db_path, Arc::new(AppendOperator))?.with_clock(clock);
+    let events = db.create_cf("events")?;
+    for i in 0..6 {
+        db.put_cf(&events, format!("key{i}"), b"value".to_vec())?;
+    }
+    db.merge_cf(&events, "key0", b"+1".to_vec())?;
+    db.merge_cf(&events, "merged", b"a".to_vec())?;
+    db.delete_range_cf(&events, "key2", "key4")?;
+    db.put_with_ttl_cf(&events, "session", b"token".to_vec(), Duration::from_millis(100))?;
+    db.put("key3", b"default".to_vec())?;
+    assert!(SnailDb::open(temp_dir.path().join("plain"))?.merge_cf(&events, "key0", b"+1".to_vec()).is_err());
+
+    let check = |db: &SnailDb, events: &snaildb::CfHandle| -> Result<()> {
+        let keys = ["key0", "key1", "key2", "key3", "key5", "merged", "session"];
+        let values = db.multi_get_cf(events, &keys)?.into_iter().collect::<io::Result<Vec<_>>>()?;
+        let expected = [Some(&b"value+1"[..]), Some(b"value"), None, None, Some(b"value"), Some(b"a"), Some(b"token")];
+        assert_eq!(values, expected.map(|value| value.map(<[u8]>::to_vec)));
+        assert_eq!(db.get("key3")?, Some(b"default".to_vec()));
+        assert_eq!(db.ttl_cf(events, "session")?, Some(Duration::from_millis(100)));
+        assert_eq!((db.ttl_cf(events, "key1")?, db.ttl("session")?), (None, None));
+        assert_eq!(scanned_keys(db.scan_rev_cf(events, ..)?)?, ["session", "merged", "key5", "key4", "key1", "key0"]);
+        assert_eq!(scanned_keys(db.scan_prefix_cf(events, "key")?)?, ["key0", "key1", "key4", "key5"]);
+        Ok(())
+    };
+    check(&db, &events)?;
+    db.wal.force_flush()?;
+    let copy = temp_dir.path().join("copy");
+    copy_db(&db_path, &copy)?;
+    db.flush_memtable()?;
+    check(&db, &events)?;
+
+    // Replayed from the WAL, each write goes back to the family
+    let recovered = SnailDb::open_with_merge_operator(&copy, Arc::new(AppendOperator))?.with_clock(clock);
+    let events = recovered.cf("events").expect("a recovered family");
+    check(&recovered, &events)?;
+    NOW.store(1_100, Ordering::SeqCst);
+    assert_eq!(recovered.get_cf(&events, "session")?, None);
+    assert_eq!(recovered.ttl_cf(&events, "session")?, None);
+    Ok(())
+}
+
+#[test]
+fn test_drop_cf_removes_its_tables() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    let kept = db.create_cf("kept")?;
+    let dropped = db.create_cf("dropped")?;
+    db.put("key", b"default".to_vec())?;
+    db.put_cf(&kept, "key", b"kept".to_vec())?;
+    db.put_cf(&dropped, "key", b"dropped".to_vec())?;
+    db.flush_memtable()?;
+    let files = |dir: &std::path::Path| -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    };
+    let prefix = |name: &str| name.split("sst-").next().unwrap_or_default().to_string();
+    let tables: Vec<String> = files(temp_dir.path())?.into_iter().filter(|name| name.ends_with(".sst")).collect();
+    assert_eq!(tables.len(), 3);
+
+    db.drop_cf(&dropped)?;
+    assert!(db.drop_cf(&db.cf(DEFAULT_CF).expect("the default family")).is_err());
+    assert!(db.get_cf(&dropped, "key").is_err());
+    assert!(db.put_cf(&dropped, "key", b"again".to_vec()).is_err());
+    assert!(db.cf("dropped").is_none());
+    let left: Vec<String> = files(temp_dir.path())?.into_iter().filter(|name| name.ends_with(".sst")).collect();
+    assert_eq!(left.len(), 2);
+    let mut prefixes: Vec<String> = left.iter().map(|name| prefix(name)).collect();
+    prefixes.sort();
+    assert_eq!(prefixes, vec!["", "cf1-"]);
+    db.close()?;
+
+    let db = SnailDb::open(temp_dir.path())?;
+    assert!(db.cf("dropped").is_none());
+    let kept = db.cf("kept").expect("a family kept over a reopen");
+    assert_eq!(db.get_cf(&kept, "key")?, Some(b"kept".to_vec()));
+    assert_eq!(db.get("key")?, Some(b"default".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_column_families_recover_after_a_crash() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("db");
+    let mut db = SnailDb::open(&db_path)?.with_flush_threshold(4096);
+    let logs = db.create_cf("logs")?;
+    let dropped = db.create_cf("dropped")?;
+    for i in 0..200 {
+        db.put(format!("key{i:03}"), format!("default{i}").into_bytes())?;
+        db.put_cf(&logs, format!("key{i:03}"), format!("logs{i}").into_bytes())?;
+    }
+    db.put_cf(&dropped, "key000", b"gone".to_vec())?;
+    db.drop_cf(&dropped)?;
+    db.put_cf(&logs, "unflushed", b"in the WAL".to_vec())?;
+    db.wal.force_flush()?;
+    assert!(!db.sstables.is_empty());
+
+    let copy = temp_dir.path().join("copy");
+    copy_db(&db_path, &copy)?;
+    let recovered = SnailDb::open(&copy)?;
+    let logs = recovered.cf("logs").expect("a recovered family");
+    assert!(recovered.cf("dropped").is_none());
+    for i in 0..200 {
+        let key = format!("key{i:03}");
+        assert_eq!(recovered.get(&key)?, Some(format!("default{i}").into_bytes()));
+        assert_eq!(recovered.get_cf(&logs, &key)?, Some(format!("logs{i}").into_bytes()));
+    }
+    assert_eq!(recovered.get_cf(&logs, "unflushed")?, Some(b"in the WAL".to_vec()));
+    assert_eq!(recovered.last_seqno(), db.last_seqno());
+    Ok(())
+}
+
+#[test]
+fn test_stats_count_a_workload() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    let empty = db.stats();
+    assert_eq!(empty.sstable_count(), 0);
+    assert_eq!((empty.gets, empty.puts, empty.last_seqno), (0, 0, 0));
+
+    for i in 0..100 {
+        db.put(format!("key{i:03}"), format!("value{i}").into_bytes())?;
+    }
+    assert!(db.stats().memtable_bytes > 0);
+    db.flush_memtable()?;
+    for i in 0..100 {
+        assert!(db.get(&format!("key{i:03}"))?.is_some());
+    }
+    assert_eq!(db.get("zzz")?, None);
+    for i in 0..10 {
+        db.delete(format!("key{i:03}"))?;
+    }
+
+    let stats = db.stats();
+    assert_eq!(stats.sstables_per_level, vec![1]);
+    assert_eq!(stats.sstable_bytes, db.sstables[0].file_size());
+    assert!(stats.sstable_bytes > 0);
+    assert_eq!(stats.disk_bytes(), stats.sstable_bytes + stats.wal_bytes);
+    assert_eq!((stats.gets, stats.puts, stats.deletes), (101, 100, 10));
+    assert_eq!((stats.bloom_hits, stats.bloom_skips), (100, 1));
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.block_cache_hit_rate(), None);
+    assert_eq!(stats.last_seqno, 110);
+    let summary = stats.to_string();
+    assert!(summary.contains("sstables: 1 (L0: 1)"), "{summary}");
+    assert!(summary.contains("operations: 101 gets, 100 puts, 10 deletes"), "{summary}");
+    Ok(())
+}
+
+#[test]
+fn test_stats_count_compactions_and_cache_lookups() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let tiers = SizeTieredOptions::default().with_min_threshold(2);
+    let mut db = SnailDb::open(temp_dir.path())?.with_compaction(tiers);
+    for round in 0..2 {
+        for i in 0..50 {
+            db.put(format!("key{i:02}"), format!("value{round}").into_bytes())?;
+        }
+        db.flush_memtable()?;
+    }
+    db.wait_for_compactions()?;
+    let stats = db.stats();
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.compacted_bytes > stats.sstable_bytes);
+    assert_eq!(stats.sstables_per_level, vec![1]);
+    db.close()?;
+
+    let cache = Arc::new(snaildb::storage::sstable::BlockCache::new(1 << 20));
+    let db = SnailDb::open(temp_dir.path())?.with_block_cache(Arc::clone(&cache));
+    for _ in 0..2 {
+        assert_eq!(db.get("key07")?, Some(b"value1".to_vec()));
+    }
+    let stats = db.stats();
+    assert_eq!((stats.block_cache_hits, stats.block_cache_misses), (cache.hits(), cache.misses()));
+    assert_eq!(stats.block_cache_hit_rate(), Some(0.5));
+    assert_eq!(stats.compactions, 0);
+    Ok(())
+}
+
+#[test]
+fn test_checkpoint_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db = SnailDb::open(temp_dir.path().join("db"))?.with_flush_threshold(4096);
+    let db = Arc::new(std::sync::Mutex::new(db));
+    let writer = {
+        let db = Arc::clone(&db);
+        std::thread::spawn(move || -> Result<()> {
+            for i in 0..2000 {
+                db.lock().expect("the database lock").put(format!("key{i:04}"), format!("value{i}").into_bytes())?;
+            }
+            Ok(())
+        })
+    };
+    while db.lock().expect("the database lock").last_seqno() < 500 {
+        std::thread::yield_now();
+    }
+    let checkpoint_dir = temp_dir.path().join("checkpoint");
+    let summary = db.lock().expect("the database lock").checkpoint(&checkpoint_dir)?;
+    writer.join().expect("the writer thread")?;
+    assert!(summary.last_seqno >= 500);
+    assert!(summary.files >= 3);
+    assert!(summary.bytes > 0);
+
+    let copy = SnailDb::open(&checkpoint_dir)?;
+    assert_eq!(copy.last_seqno(), summary.last_seqno);
+    // A fresh database numbers its writes from 1, so the checkpoint holds the first keys only
+    for i in 0..2000u64 {
+        let value = copy.get(&format!("key{i:04}"))?;
+        assert_eq!(value.is_some(), i < summary.last_seqno, "key{i:04}");
+    }
+    assert_eq!(scanned_keys(copy.scan(..))?.len() as u64, summary.last_seqno);
+    let db = db.lock().expect("the database lock");
+    assert_eq!(db.get("key1999")?, Some(b"value1999".to_vec()));
+
+    assert!(SnailDb::open(temp_dir.path().join("other"))?.checkpoint(&checkpoint_dir).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_restore_round_trips_a_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path().join("db"))?.with_flush_threshold(2048);
+    for i in 0..300 {
+        db.put(format!("key{i:03}"), format!("value{i}").into_bytes())?;
+    }
+    db.delete("key007")?;
+    let backup = temp_dir.path().join("backup");
+    let summary = db.checkpoint(&backup)?;
+
+    let target = temp_dir.path().join("restored");
+    SnailDb::restore(&backup, &target, &RestoreOptions::default())?;
+    let restored = SnailDb::open(&target)?;
+    assert_eq!(restored.last_seqno(), summary.last_seqno);
+    for i in 0..300 {
+        let expected = (i != 7).then(|| format!("value{i}").into_bytes());
+        assert_eq!(restored.get(&format!("key{i:03}"))?, expected);
+    }
+    restored.close()?;
+
+    let refused = SnailDb::restore(&backup, &target, &RestoreOptions::default());
+    assert!(refused.unwrap_err().to_string().contains("not empty"));
+    SnailDb::restore(&backup, &target, &RestoreOptions::default().with_overwrite(true))?;
+    assert_eq!(SnailDb::open(&target)?.get("key299")?, Some(b"value299".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_restore_replays_the_wal_from_its_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("db");
+    let mut db = SnailDb::open_with_merge_operator(&db_path, Arc::new(U64AddOperator))?;
+    db.merge("counter", 1u64.to_le_bytes().to_vec())?;
+    db.freeze_memtable()?;
+    db.merge("counter", 2u64.to_le_bytes().to_vec())?;
+    db.put("later", b"value".to_vec())?;
+    // Flushes the first merge, leaving the WAL to skip it from its checkpoint
+    db.freeze_memtable()?;
+    db.wal.force_flush()?;
+    assert!(db_path.join("wal.log.checkpoint").exists());
+
+    let backup = temp_dir.path().join("backup");
+    copy_db(&db_path, &backup)?;
+    let target = temp_dir.path().join("restored");
+    SnailDb::restore(&backup, &target, &RestoreOptions::default())?;
+    let restored = SnailDb::open_with_merge_operator(&target, Arc::new(U64AddOperator))?;
+    assert_eq!(restored.get("counter")?, Some(3u64.to_le_bytes().to_vec()));
+    assert_eq!(restored.get("later")?, Some(b"value".to_vec()));
+    assert_eq!(restored.last_seqno(), db.last_seqno());
+    Ok(())
+}
+
+#[test]
+fn test_restore_of_a_damaged_backup_leaves_the_target() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path().join("db"))?;
+    for i in 0..100 {
+        db.put(format!("key{i:03}"), vec![b'v'; 64])?;
+    }
+    let backup = temp_dir.path().join("backup");
+    db.checkpoint(&backup)?;
+    let target = temp_dir.path().join("target");
+    std::fs::create_dir_all(&target)?;
+    std::fs::write(target.join("keep.txt"), b"untouched")?;
+    let overwrite = RestoreOptions::default().with_overwrite(true);
+
+    let table = db.sstables[0].path().file_name().expect("a table file name").to_owned();
+    let bytes = std::fs::read(backup.join(&table))?;
+    let mut damaged = bytes.clone();
+    damaged[40] ^= 0xFF;
+    std::fs::remove_file(backup.join(&table))?;
+    std::fs::write(backup.join(&table), &damaged)?;
+    let err = SnailDb::restore(&backup, &target, &overwrite).unwrap_err();
+    assert!(err.to_string().contains("damaged"), "{err}");
+    SnailDb::restore(&backup, temp_dir.path().join("unverified"), &overwrite.with_verify_checksums(false))?;
+
+    std::fs::remove_file(backup.join(&table))?;
+    let err = SnailDb::restore(&backup, &target, &overwrite).unwrap_err();
+    assert!(err.to_string().contains("missing sstable"), "{err}");
+    assert_eq!(std::fs::read(target.join("keep.txt"))?, b"untouched");
+    assert_eq!(std::fs::read_dir(&target)?.count(), 1);
+    assert!(!temp_dir.path().join("target.restoring").exists());
+    assert_eq!(db.get("key050")?, Some(vec![b'v'; 64]));
+    Ok(())
+}
+
+#[test]
+fn test_destroy_removes_only_database_files() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("db");
+    let mut db = SnailDb::open(&db_path)?;
+    for i in 0..100 {
+        db.put(format!("key{i:03}"), b"value".to_vec())?;
+    }
+    db.flush_memtable()?;
+    db.put("unflushed", b"value".to_vec())?;
+    std::fs::write(db_path.join("notes.txt"), b"not the database's")?;
+    std::fs::write(db_path.join("wallpaper.log"), b"not a WAL")?;
+    std::fs::write(db_path.join("wal-old.log"), b"not a segment")?;
+    std::fs::write(db_path.join("wal-7.log"), b"a segment")?;
+    std::fs::write(db_path.join("wal.log.checkpoint"), b"a checkpoint")?;
+
+    let err = SnailDb::destroy(&db_path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(SnailDb::open(&db_path).is_err());
+    assert_eq!(db.get("key000")?, Some(b"value".to_vec()));
+    db.close()?;
+
+    let report = SnailDb::destroy(&db_path)?;
+    let mut kept = report.kept.clone();
+    kept.sort();
+    assert_eq!(kept, ["notes.txt", "wal-old.log", "wallpaper.log"].map(|name| db_path.join(name)));
+    assert!(report.removed.contains(&db_path.join("LOCK")));
+    assert!(report.removed.contains(&db_path.join("CURRENT")));
+    assert!(report.removed.contains(&db_path.join("wal.log")));
+    assert!(report.removed.contains(&db_path.join("wal-7.log")));
+    assert!(report.removed.contains(&db_path.join("wal.log.checkpoint")));
+    assert!(report.removed.iter().any(|path| path.extension().is_some_and(|ext| ext == "sst")));
+    let left: Vec<_> = std::fs::read_dir(&db_path)?.map(|entry| entry.map(|entry| entry.file_name())).collect();
+    assert_eq!(left.len(), 3);
+
+    for name in ["notes.txt", "wal-old.log", "wallpaper.log"] {
+        std::fs::remove_file(db_path.join(name))?;
+    }
+    let db = SnailDb::open(&db_path)?;
+    assert_eq!(db.get("key000")?, None);
+    db.close()?;
+    SnailDb::destroy(&db_path)?;
+    assert!(!db_path.exists());
+    Ok(())
+}
+
+#[test]
+fn test_destroy_of_a_missing_or_broken_database() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let missing = temp_dir.path().join("never-created");
+    assert_eq!(SnailDb::destroy(&missing)?, Default::default());
+    assert!(!missing.exists());
+
+    // A crash while the database was created left a manifest CURRENT does not name yet
+    let broken = temp_dir.path().join("broken");
+    std::fs::create_dir_all(&broken)?;
+    std::fs::write(broken.join("MANIFEST-000001"), b"torn")?;
+    std::fs::write(broken.join("sst-1.sst.tmp"), b"torn")?;
+    std::fs::write(broken.join("CURRENT"), b"MANIFEST-000009\n")?;
+    assert!(SnailDb::open(&broken).is_err());
+    let report = SnailDb::destroy(&broken)?;
+    // The three files, along with the LOCK and READ_LOCK the failed open created
+    assert_eq!(report.removed.len(), 5);
+    assert!(report.kept.is_empty());
+    assert!(!broken.exists());
+    Ok(())
+}
+
+#[test]
+fn test_read_only_open_alongside_a_writer() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("db");
+    let mut db = SnailDb::open(&db_path)?.with_flush_threshold(4096);
+    let logs = db.create_cf("logs")?;
+    for i in 0..300 {
+        db.put(format!("key{i:03}"), format!("value{i}").into_bytes())?;
+    }
+    db.put_cf(&logs, "entry", b"logged".to_vec())?;
+    db.delete("key000")?;
+    db.wal.force_flush()?;
+    assert!(!db.sstables.is_empty());
+    let files = |dir: &std::path::Path| -> Result<Vec<(std::ffi::OsString, u64)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            files.push((entry.file_name(), entry.metadata()?.len()));
+        }
+        files.sort();
+        Ok(files)
+    };
+    let before = files(&db_path)?;
+
+    let reader = SnailDb::open_read_only(&db_path)?;
+    assert_eq!(files(&db_path)?, before);
+    assert_eq!(reader.last_seqno(), db.last_seqno());
+    assert_eq!(reader.get("key000")?, None);
+    assert_eq!(reader.get("key299")?, Some(b"value299".to_vec()));
+    assert_eq!(scanned_keys(reader.scan(..))?.len(), 299);
+    let reader_logs = reader.cf("logs").expect("the family of the writer");
+    assert_eq!(reader.get_cf(&reader_logs, "entry")?, Some(b"logged".to_vec()));
+    let snapshot = reader.snapshot();
+
+    // The reader keeps the view it opened with
+    db.put("key300", b"value300".to_vec())?;
+    db.put("key001", b"rewritten".to_vec())?;
+    db.flush_memtable()?;
+    assert_eq!(reader.get("key300")?, None);
+    assert_eq!(reader.get("key001")?, Some(b"value1".to_vec()));
+    assert_eq!(reader.get_with_snapshot("key001", &snapshot)?, Some(b"value1".to_vec()));
+    let reopened = SnailDb::open_read_only(&db_path)?;
+    assert_eq!(reopened.get("key001")?, Some(b"rewritten".to_vec()));
+    assert_eq!(reopened.get("key300")?, Some(b"value300".to_vec()));
+
+    // Neither the writer nor the readers let the database be destroyed
+    drop(db);
+    let err = SnailDb::destroy(&db_path).expect_err("destroyed under a reader");
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(before.iter().all(|(name, _)| db_path.join(name).exists()));
+    drop((reader, reopened));
+    SnailDb::destroy(&db_path)?;
+    assert!(!db_path.exists());
+
+    let missing = temp_dir.path().join("missing");
+    assert!(SnailDb::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+#[test]
+fn test_flush_returns_tables() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut flushes = Vec::new();
+    for run in ["a", "b"] {
+        let mut db = Db::open(temp_dir.path().join(run))?;
+        // Nothing to flush yet
+        assert!(db.flush()?.is_empty());
+        for i in 0..20 {
+            db.put(format!("key{i:02}"), format!("value{i}").as_bytes())?;
+        }
+        db.delete("key05")?;
+        let mut flushed = db.flush()?;
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].family, 0);
+        assert_eq!(flushed[0].stats.entries, 20);
+        assert_eq!(flushed[0].stats.tombstones, 1);
+        assert_eq!(flushed[0].stats.min_key, b"key00");
+        assert_eq!(flushed[0].stats.max_key, b"key19");
+        assert_eq!(flushed[0].stats.max_seqno, 21);
+        assert!(db.memtable.is_empty());
+        assert_eq!(db.sstables.len(), 1);
+        // The memtable is empty again
+        assert!(db.flush()?.is_empty());
+        assert_eq!(db.sstables.len(), 1);
+        flushes.push(flushed.remove(0).stats);
+    }
+    // The same writes flush to the same table, named after the time it was written
+    assert_eq!(flushes[0], flushes[1]);
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_flushes_write_the_memtable_once() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db = Arc::new(std::sync::Mutex::new(Db::open(temp_dir.path())?));
+    for i in 0..50 {
+        db.lock().unwrap().put(format!("key{i:02}"), b"value")?;
+    }
+    let flushers: Vec<_> = (0..2)
+        .map(|_| {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || db.lock().unwrap().flush().map(|tables| tables.len()))
+        })
+        .collect();
+    let mut flushed: Vec<usize> = flushers.into_iter().map(|flusher| flusher.join().unwrap()).collect::<Result<_>>()?;
+    flushed.sort();
+    assert_eq!(flushed, [0, 1]);
+
+    let db = db.lock().unwrap();
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(db.get("key49")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_flush_wal_survives_crash() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("db");
+    let crashed = temp_dir.path().join("crashed");
+    let mut db = Db::open(&db_path)?;
+    for i in 0..10 {
+        db.put(format!("key{i}"), b"synced")?;
+    }
+    db.flush_wal(true)?;
+    // What the disk holds once the sync returns, before the writes after it are synced
+    copy_db(&db_path, &crashed)?;
+    db.put("later", b"lost")?;
+    db.flush_wal(false)?;
+    assert!(db.sstables.is_empty());
+    assert!(std::fs::metadata(db_path.join("wal.log"))?.len() > std::fs::metadata(crashed.join("wal.log"))?.len());
+    drop(db);
+
+    let db = Db::open(&crashed)?;
+    for i in 0..10 {
+        assert_eq!(db.get(&format!("key{i}"))?, Some(b"synced".to_vec()));
+    }
+    assert_eq!(db.get("later")?, None);
+    assert_eq!(db.last_seqno(), 10);
+    Ok(())
+}
+
+#[test]
+fn test_compact_range_reclaims_deleted_keys() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = Db::open(temp_dir.path())?;
+    assert_eq!(db.compact_range(None, None)?, Default::default());
+    for i in 0..200 {
+        db.put(format!("key{i:03}"), vec![b'v'; 200])?;
+    }
+    db.flush()?;
+    for i in 0..190 {
+        db.delete(format!("key{i:03}"))?;
+    }
+    db.flush()?;
+    assert_eq!(db.sstables.len(), 2);
+
+    let summary = db.compact_range(None, None)?;
+    assert_eq!(summary.input_tables, 2);
+    assert_eq!(summary.output_tables, 1);
+    assert!(summary.bytes_after * 5 < summary.bytes_before, "{summary:?}");
+    assert_eq!(db.sstables.len(), 1);
+    // The tombstones went along with the values they deleted
+    assert_eq!(db.sstables[0].stats().tombstones, 0);
+    assert_eq!(db.sstables[0].stats().entries, 10);
+    assert_eq!(db.stats().sstable_bytes, summary.bytes_after);
+    assert_eq!(db.get("key000")?, None);
+    assert_eq!(db.get("key195")?, Some(vec![b'v'; 200]));
+    drop(db);
+
+    let db = Db::open(temp_dir.path())?;
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(scanned_keys(db.scan(..))?.len(), 10);
+    assert_eq!(db.get("key189")?, None);
+    Ok(())
+}
+
+#[test]
+fn test_compact_range_widens_to_split_tables() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = Db::open(temp_dir.path())?.with_compaction(SizeTieredOptions::default().with_min_threshold(10));
+    // Oldest first: key00..key30, key25..key60, key70..key99, then deletes of key80..key90
+    let tables: [(std::ops::Range<u32>, Option<&[u8]>); 4] =
+        [(0..31, Some(b"a")), (25..61, Some(b"b")), (70..100, Some(b"c")), (80..91, None)];
+    for (keys, value) in tables {
+        for i in keys {
+            match value {
+                Some(value) => db.put(format!("key{i:02}"), value)?,
+                None => db.delete(format!("key{i:02}"))?,
+            }
+        }
+        db.flush()?;
+    }
+    assert_eq!(db.sstables.len(), 4);
+
+    // The bounds split the second table, which shares keys with the first
+    let summary = db.compact_range(Some("key45"), Some("key50"))?;
+    assert_eq!(summary.input_tables, 2);
+    assert_eq!(db.sstables.len(), 3);
+    let expected = |i: u32| match i {
+        0..25 => Some(b"a".to_vec()),
+        25..61 => Some(b"b".to_vec()),
+        70..80 | 91..100 => Some(b"c".to_vec()),
+        _ => None,
+    };
+    for i in 0..100 {
+        assert_eq!(db.get(&format!("key{i:02}"))?, expected(i), "key{i:02}");
+    }
+    // The deletes were left out, and still shadow the values below them
+    assert_eq!(db.sstables.iter().map(|table| table.stats().tombstones).sum::<u64>(), 11);
+    drop(db);
+
+    let mut db = Db::open(temp_dir.path())?.with_compaction(LeveledOptions::default().with_l0_compaction_trigger(10));
+    for i in 0..100 {
+        assert_eq!(db.get(&format!("key{i:02}"))?, expected(i), "key{i:02}");
+    }
+    let summary = db.compact_range(Some("key85"), None)?;
+    assert_eq!(summary.input_tables, 2);
+    assert_eq!(db.levels[0].len(), summary.output_tables);
+    db.compact_range(None, None)?;
+    assert!(db.sstables.is_empty());
+    assert_eq!(db.levels[0].len(), 1);
+    for i in 0..100 {
+        assert_eq!(db.get(&format!("key{i:02}"))?, expected(i), "key{i:02}");
+    }
+    Ok(())
+}
+
+/// Appends operands like `AppendOperator`, but holds up its first merge off the thread that
+/// created it, one the compaction thread runs, until it is released.
+#[derive(Debug)]
+struct GatedOperator {
+    reader: std::thread::ThreadId,
+    gate: std::sync::Mutex<Option<(std::sync::mpsc::Sender<()>, std::sync::mpsc::Receiver<()>)>>,
+}
+
+impl GatedOperator {
+    /// Returns the operator, the receiver told when the merge is held up and the sender that releases it.
+    fn new() -> (Arc<Self>, std::sync::mpsc::Receiver<()>, std::sync::mpsc::Sender<()>) {
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let (release, wait_release) = std::sync::mpsc::channel();
+        let gate = std::sync::Mutex::new(Some((started, wait_release)));
+        (Arc::new(Self { reader: std::thread::current().id(), gate }), wait_started, release)
+    }
+}
+
+impl snaildb::utils::MergeOperator for GatedOperator {
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        if std::thread::current().id() != self.reader {
+            let gate = self.gate.lock().unwrap().take();
+            if let Some((started, release)) = gate {
+                let _ = started.send(());
+                let _ = release.recv();
+            }
+        }
+        AppendOperator.full_merge(key, existing, operands)
+    }
+}
+
+/// Opens a database whose compaction of its first two tables is held up in its merge, and
+/// returns it with the sender that releases the merge.
+fn open_with_held_up_compaction(path: &std::path::Path) -> Result<(SnailDb, std::sync::mpsc::Sender<()>)> {
+    let (operator, started, release) = GatedOperator::new();
+    let tiers = SizeTieredOptions::default().with_min_threshold(2);
+    let mut db = SnailDb::open_with_merge_operator(path, operator)?.with_compaction(tiers);
+    db.put("counter", b"0".to_vec())?;
+    db.flush_memtable()?;
+    db.merge("counter", b"1".to_vec())?;
+    db.flush_memtable()?;
+    started.recv_timeout(Duration::from_secs(10))?;
+    Ok((db, release))
+}
+
+#[test]
+fn test_writes_proceed_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (mut db, release) = open_with_held_up_compaction(temp_dir.path())?;
+
+    // None of these would return if they waited for the merge
+    for i in 0..200 {
+        db.put(format!("key{i:03}"), b"value".to_vec())?;
+    }
+    db.delete("key000")?;
+    db.flush_memtable()?;
+    assert_eq!(db.get("counter")?, Some(b"01".to_vec()));
+    assert_eq!(db.get("key199")?, Some(b"value".to_vec()));
+    assert_eq!(db.sstables.len(), 3);
+    assert_eq!(db.stats().compactions, 0);
+
+    release.send(())?;
+    db.wait_for_compactions()?;
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(db.stats().compactions, 2);
+    assert_eq!(db.get("counter")?, Some(b"01".to_vec()));
+    assert_eq!(db.get("key000")?, None);
+    assert_eq!(db.get("key199")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_drop_during_compaction_leaves_a_consistent_directory() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (mut db, release) = open_with_held_up_compaction(temp_dir.path())?;
+    db.put("later", b"written".to_vec())?;
+    // Released once the drop below waits for the merge
+    let releaser = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        release.send(())
+    });
+    drop(db);
+    releaser.join().unwrap()?;
+
+    // The output was never installed: the inputs are still live and the output is removed
+    let mut db = SnailDb::open_with_merge_operator(temp_dir.path(), Arc::new(AppendOperator))?;
+    assert_eq!(db.orphaned_files.len(), 1);
+    assert!(db.orphaned_files[0].extension().is_some_and(|ext| ext == "compacted"), "{:?}", db.orphaned_files);
+    assert!(!db.orphaned_files[0].exists());
+    assert_eq!(db.sstables.len(), 2);
+    assert_eq!(db.get("counter")?, Some(b"01".to_vec()));
+    assert_eq!(db.get("later")?, Some(b"written".to_vec()));
+
+    // Compacting again picks up where it stopped
+    db.compaction = SizeTieredOptions::default().with_min_threshold(2).into();
+    assert!(db.compact()?);
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(db.get("counter")?, Some(b"01".to_vec()));
+    db.close()?;
+    let db = SnailDb::open(temp_dir.path())?;
+    assert!(db.orphaned_files.is_empty(), "{:?}", db.orphaned_files);
+    assert_eq!(db.get("later")?, Some(b"written".to_vec()));
+    Ok(())
+}
+
+/// Appends operands like `AppendOperator`, taking `delay` over each merge off the thread that
+/// created it, one the compaction thread runs.
+#[derive(Debug)]
+struct SlowOperator {
+    reader: std::thread::ThreadId,
+    delay: Duration,
+}
+
+impl snaildb::utils::MergeOperator for SlowOperator {
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        if std::thread::current().id() != self.reader {
+            std::thread::sleep(self.delay);
+        }
+        AppendOperator.full_merge(key, existing, operands)
+    }
+}
+
+#[test]
+fn test_write_stall_bounds_level_0() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let operator = Arc::new(SlowOperator { reader: std::thread::current().id(), delay: Duration::from_millis(30) });
+    let stall = WriteStallOptions::default().with_l0_thresholds(3, 5);
+    let tiers = SizeTieredOptions::default().with_min_threshold(2);
+    let mut db =
+        SnailDb::open_with_merge_operator(temp_dir.path(), operator)?.with_compaction(tiers).with_write_stall(stall);
+    db.put("counter", b"0".to_vec())?;
+    let mut most = 0;
+    for round in 0..30 {
+        db.merge("counter", b"1".to_vec())?;
+        db.put(format!("key{round:02}"), b"value".to_vec())?;
+        db.flush_memtable()?;
+        most = most.max(db.sstables.len());
+    }
+    // Each compaction takes longer than the flushes of a round, which waited for them
+    assert!(most <= 5, "{most} tables");
+    let stalled = db.stats().write_stall_micros;
+    assert!(stalled > 0);
+    assert!(db.stats().to_string().contains(&format!("write stalls: {stalled} us")));
+
+    // Once compactions catch up writes go through
+    db.wait_for_compactions()?;
+    assert_eq!(db.sstables.len(), 1);
+    db.put("after", b"value".to_vec())?;
+    assert_eq!(db.stats().write_stall_micros, stalled);
+    assert_eq!(db.get("counter")?, Some(format!("0{}", "1".repeat(30)).into_bytes()));
+    assert_eq!(db.get("key29")?, Some(b"value".to_vec()));
+
+    // A memtable left frozen is flushed by the next write past the stop threshold
+    let mut db = db.with_write_stall(stall.with_frozen_thresholds(1, 1));
+    db.put("frozen", b"value".to_vec())?;
+    db.freeze_memtable()?;
+    assert!(db.frozen_memtable.is_some());
+    db.put("next", b"value".to_vec())?;
+    assert!(db.frozen_memtable.is_none());
+    assert_eq!(db.get("frozen")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_write_stall_counts_a_freeze_once_for_all_column_families() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = SnailDb::open(temp_dir.path())?;
+    let families = [db.create_cf("a")?, db.create_cf("b")?, db.create_cf("c")?];
+    db.put("key", b"value".to_vec())?;
+    db.put_cf(&families[0], "key", b"value".to_vec())?;
+    db.freeze_memtable()?;
+    assert!(db.frozen_memtable.is_some());
+
+    // It is counted once: past a slowdown of 1 writes are delayed, with the stop turned off
+    let mut db = db.with_write_stall(WriteStallOptions::default().with_frozen_thresholds(1, 0));
+    for i in 0..5 {
+        db.put(format!("key{i:02}"), b"value".to_vec())?;
+        db.put_cf(&families[2], format!("key{i:02}"), b"value".to_vec())?;
+    }
+    assert!(db.stats().write_stall_micros > 0);
+    assert!(db.frozen_memtable.is_some());
+    assert_eq!(db.get_cf(&families[0], "key")?, Some(b"value".to_vec()));
+
+    // Under the default thresholds the next write flushes it, those of every family along
+    let stalled = db.stats().write_stall_micros;
+    let files = db.sstables.len();
+    let mut db = db.with_write_stall(WriteStallOptions::default());
+    db.put("flushing", b"value".to_vec())?;
+    assert!(db.stats().write_stall_micros > stalled);
+    assert!(db.frozen_memtable.is_none());
+    assert_eq!(db.sstables.len(), files + 1);
+    assert_eq!(db.get_cf(&families[0], "key")?, Some(b"value".to_vec()));
+    assert_eq!(db.get("flushing")?, Some(b"value".to_vec()));
+
+    // Thresholds a single frozen memtable never reaches are refused
+    let refused = std::panic::catch_unwind(|| WriteStallOptions::default().with_frozen_thresholds(2, 4));
+    assert!(refused.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_close_handle_interrupts_a_stalled_write() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (db, release) = open_with_held_up_compaction(temp_dir.path())?;
+    let mut db = db.with_write_stall(WriteStallOptions::default().with_l0_thresholds(2, 2));
+    let handle = db.close_handle();
+    let closer = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        handle.close();
+    });
+    // Held up until the handle closes the database, as the compaction it waits for is stuck
+    let err = db.put("stalled", b"value".to_vec()).unwrap_err();
+    assert!(err.to_string().contains("closing"), "{err}");
+    closer.join().unwrap();
+    assert!(db.stats().write_stall_micros >= 50_000);
+    assert!(db.delete("counter").is_err());
+
+    release.send(())?;
+    db.close()?;
+    let db = SnailDb::open_with_merge_operator(temp_dir.path(), Arc::new(AppendOperator))?;
+    assert_eq!(db.sstables.len(), 1);
+    assert_eq!(db.get("stalled")?, None);
+    assert_eq!(db.get("counter")?, Some(b"01".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_pinned_scan_outlives_the_compaction_of_its_tables() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = Db::open(temp_dir.path())?.with_compaction(SizeTieredOptions::default().with_min_threshold(10));
+    for i in 0..600 {
+        db.put(format!("key{i:03}"), b"old")?;
+    }
+    db.flush()?;
+    for i in 0..300 {
+        db.put(format!("key{i:03}"), b"new")?;
+    }
+    db.flush()?;
+    let files: Vec<_> = db.sstables.iter().map(|table| table.path().to_path_buf()).collect();
+    assert_eq!(files.len(), 2);
+
+    let mut scan = db.scan_pinned(..);
+    let mut scanned = scan.by_ref().take(100).collect::<io::Result<Vec<_>>>()?;
+    // Written after the scan started, so it does not see them
+    db.put("key000", b"later")?;
+    db.put("key999", b"later")?;
+    let summary = db.compact_range(None, None)?;
+    assert_eq!(summary.input_tables, 2);
+    assert!(db.sstables.iter().all(|table| !files.iter().any(|file| file == table.path())));
+    assert!(files.iter().all(|file| file.exists()));
+    assert_eq!(db.purge_obsolete_files()?, 0);
+
+    scanned.extend(scan.collect::<io::Result<Vec<_>>>()?);
+    assert_eq!(scanned.len(), 600);
+    for (i, (key, value)) in scanned.iter().enumerate() {
+        assert_eq!(key, &format!("key{i:03}"));
+        assert_eq!(value, if i < 300 { b"new" } else { b"old" });
+    }
+
+    // The scan is gone, and the files of the tables compacted along with it
+    assert_eq!(db.purge_obsolete_files()?, 2);
+    assert!(files.iter().all(|file| !file.exists()));
+    assert_eq!(db.get("key000")?, Some(b"later".to_vec()));
+    assert_eq!(db.get("key450")?, Some(b"old".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_settings_reach_the_tables_a_pinned_scan_reads() -> Result<()> {
+    static NOW: AtomicU64 = AtomicU64::new(1_000);
+    fn clock() -> u64 {
+        NOW.load(Ordering::SeqCst)
+    }
+    let temp_dir = TempDir::new()?;
+    let compaction = SizeTieredOptions::default().with_min_threshold(10);
+    let mut db = Db::open(temp_dir.path())?.with_compaction(compaction).with_clock(clock);
+    db.put_with_ttl("session", b"token".to_vec(), Duration::from_millis(100))?;
+    db.put("user", b"alice".to_vec())?;
+    db.flush()?;
+    let file = db.sstables[0].path().to_path_buf();
+
+    // The tables the scan reads are copied for the database, which reads them with the settings
+    let scan = db.scan_pinned(..);
+    let cache = Arc::new(snaildb::storage::sstable::BlockCache::new(1 << 20));
+    let db = db.with_clock(snaildb::storage::sstable::system_clock).with_block_cache(Arc::clone(&cache));
+    assert_eq!(db.get("session")?, None);
+    assert_eq!(db.get("user")?, Some(b"alice".to_vec()));
+    assert!(!cache.is_empty());
+    let scanned = scan.collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(scanned.len(), 2, "the session read with the former clock");
+
+    // The file of a table compacted away is kept while a scan reads the table it was copied from
+    let scan = db.scan_pinned(..);
+    let mut db = db.with_clock(clock);
+    db.put("other", b"value".to_vec())?;
+    db.flush()?;
+    db.compact_range(None, None)?;
+    assert!(file.exists());
+    assert_eq!(db.purge_obsolete_files()?, 0);
+    drop(scan);
+    assert_eq!(db.purge_obsolete_files()?, 1);
+    assert!(!file.exists());
+    assert_eq!(db.get("user")?, Some(b"alice".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_approximate_size_of_key_ranges() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut db = Db::open(temp_dir.path())?.with_compaction(SizeTieredOptions::default().with_min_threshold(10));
+    assert_eq!(db.approximate_size(None, None), 0);
+    // Four tables, each holding every fourth key
+    for table in 0..4 {
+        for i in (table..4000).step_by(4) {
+            db.put(format!("key{i:05}"), vec![b'v'; 100])?;
+        }
+        db.flush()?;
+    }
+    assert_eq!(db.sstables.len(), 4);
+    let file_bytes: u64 = db.sstables.iter().map(|table| table.file_size()).sum();
+    let whole = db.approximate_size(None, None);
+    assert!(whole <= file_bytes && whole * 10 >= file_bytes * 9, "{whole} of {file_bytes}");
+
+    let bounds = [None, Some("key01000"), Some("key02000"), Some("key03000"), None];
+    let parts: Vec<u64> = bounds.windows(2).map(|ends| db.approximate_size(ends[0], ends[1])).collect();
+    assert_eq!(parts.iter().sum::<u64>(), whole);
+    for part in &parts {
+        assert!(part * 5 >= whole && part * 3 <= whole, "{part} of {whole}");
+    }
+    assert_eq!(db.approximate_size(Some("key02000"), Some("key01000")), 0);
+    assert_eq!(db.approximate_size(Some("zzz"), None), 0);
+
+    // The memtable counts the bytes of the keys and values of the range
+    for i in 0..10 {
+        db.put(format!("zzz{i}"), vec![b'v'; 100])?;
+    }
+    assert_eq!(db.approximate_size(Some("zzz"), None), 10 * 104);
+    assert_eq!(db.approximate_size(None, None), whole + 10 * 104);
     Ok(())
 }
 
+#[test]
+fn test_size_tiered_pick() {
+    let options = SizeTieredOptions::default().with_min_table_size(10);
+    // newest first: the newest full bucket is picked
+    assert_eq!(options.pick(&[100, 100, 100]), None);
+    assert_eq!(options.pick(&[100, 90, 110, 100, 1000]), Some(0..4));
+    assert_eq!(options.pick(&[1000, 100, 90, 110, 100]), Some(1..5));
+    assert_eq!(options.pick(&[1000, 100, 100, 100, 4000, 100]), None);
+    // tables below the minimum size share a bucket
+    assert_eq!(options.pick(&[1, 9, 2, 5, 100]), Some(0..4));
+    // at most max_threshold tables, the newest of the bucket, are merged
+    assert_eq!(options.with_max_threshold(5).pick(&[100; 8]), Some(0..5));
+    assert_eq!(options.with_min_threshold(1).pick(&[100; 8]), None);
+}
+
 #[test]
 fn test_working_with_strings() -> Result<()> {
     let temp_dir = TempDir::new()?;
@@ -104,7 +2316,7 @@ fn test_multiple_operations() -> Result<()> {
     
     // Put multiple values
     for i in 0..10 {
-        db.put(&format!("key:{}", i), format!("value:{}", i).as_bytes())?;
+        db.put(format!("key:{}", i), format!("value:{}", i).as_bytes())?;
     }
     
     // Retrieve all values
@@ -134,3 +2346,226 @@ fn test_multiple_operations() -> Result<()> {
     
     Ok(())
 }
+
+#[test]
+fn test_open_under_each_recovery_mode() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    std::fs::create_dir_all(&db_path)?;
+    let mut log = Vec::new();
+    let mut offsets = Vec::new();
+    for (seqno, key) in (1..).zip(["a", "b", "c"]) {
+        offsets.push(log.len());
+        write_record(&mut log, RecordKind::Set, key.as_bytes(), b"value", seqno)?;
+    }
+    // A damaged byte in the middle record and a table that is not one
+    log[offsets[2] - 1] ^= 0xff;
+    std::fs::write(db_path.join("wal.log"), &log)?;
+    std::fs::write(db_path.join("sst-1.sst"), b"not a table")?;
+
+    assert!(SnailDb::open_with_recovery_mode(&db_path, RecoveryMode::Strict).is_err());
+    assert!(SnailDb::open(&db_path).is_err());
+    let db = SnailDb::open_with_recovery_mode(&db_path, RecoveryMode::SkipCorrupt)?;
+    assert_eq!(db.get("a")?, Some(b"value".to_vec()));
+    assert_eq!(db.get("b")?, None);
+    assert_eq!(db.get("c")?, Some(b"value".to_vec()));
+    assert_eq!(db.recovery_report.records_replayed, 2);
+    assert_eq!(db.recovery_report.bytes_skipped, (offsets[2] - offsets[1] + b"not a table".len()) as u64);
+    assert_eq!(db.recovery_report.segments_affected, [db_path.join("wal.log"), db_path.join("sst-1.sst")]);
+    Ok(())
+}
+
+/// Adds up little-endian u64 operands onto the value of a counter.
+fn add_counter(_key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+    let read = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().expect("8-byte counter"));
+    let total = operands.iter().fold(existing.map_or(0, read), |total, operand| total + read(operand));
+    total.to_le_bytes().to_vec()
+}
+
+fn counter(db: &SnailDb, key: &str) -> Result<Option<u64>> {
+    Ok(db.get(key)?.map(|bytes| u64::from_le_bytes(bytes.try_into().expect("8-byte counter"))))
+}
+
+#[test]
+fn test_merge_counter_survives_wal_replay() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    {
+        let mut db = SnailDb::open_with_merge_function(&db_path, add_counter)?;
+        for _ in 0..3 {
+            db.merge("hits", 1u64.to_le_bytes())?;
+        }
+        db.put("base", 10u64.to_le_bytes())?;
+        db.merge("base", 5u64.to_le_bytes())?;
+        assert_eq!(counter(&db, "hits")?, Some(3));
+        assert_eq!(counter(&db, "base")?, Some(15));
+        db.wal.force_flush()?;
+    }
+
+    // The operands are replayed from the WAL
+    let mut db = SnailDb::open_with_merge_function(&db_path, add_counter)?;
+    assert_eq!(counter(&db, "hits")?, Some(3));
+    assert_eq!(counter(&db, "base")?, Some(15));
+
+    // And stack onto the value flushed to an SSTable, and onto tombstones as onto nothing
+    db.flush_memtable()?;
+    db.merge("hits", 2u64.to_le_bytes())?;
+    db.delete("base")?;
+    db.merge("base", 7u64.to_le_bytes())?;
+    assert_eq!(counter(&db, "hits")?, Some(5));
+    assert_eq!(counter(&db, "base")?, Some(7));
+    db.wal.force_flush()?;
+    drop(db);
+
+    // Replaying operands over a value needs the merge function
+    let error = SnailDb::open(&db_path).unwrap_err();
+    assert!(format!("{error:#}").contains("merge function"), "{error:#}");
+    Ok(())
+}
+
+#[test]
+fn test_merge_with_builtin_operators() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    {
+        let mut db = SnailDb::open_with_merge_operator(&db_path, Arc::new(U64AddOperator))?;
+        db.put("hits", 10u64.to_le_bytes())?;
+        db.flush_memtable()?;
+        for delta in [1u64, 2, 3] {
+            db.merge("hits", delta.to_le_bytes())?;
+            db.merge("fresh", delta.to_le_bytes())?;
+        }
+        assert_eq!(counter(&db, "hits")?, Some(16));
+        assert_eq!(counter(&db, "fresh")?, Some(6));
+        db.wal.force_flush()?;
+    }
+    let db = SnailDb::open_with_merge_operator(&db_path, Arc::new(U64AddOperator))?;
+    assert_eq!(counter(&db, "hits")?, Some(16));
+    assert_eq!(counter(&db, "fresh")?, Some(6));
+
+    let mut log = SnailDb::open_with_merge_operator(temp_dir.path().join("log"), Arc::new(AppendOperator))?;
+    log.merge("events", "a,")?;
+    log.flush_memtable()?;
+    log.merge("events", "b,")?;
+    log.merge("events", "c")?;
+    assert_eq!(log.get("events")?, Some(b"a,b,c".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_merge_operand_stacks_with_and_without_base() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open_with_merge_function(&db_path, add_counter)?;
+    db.put("with_base", 100u64.to_le_bytes())?;
+    db.flush_memtable()?;
+
+    // Operands spread over the memtable and two tables, newest first down to the base
+    db.merge("with_base", 1u64.to_le_bytes())?;
+    db.merge("without_base", 1u64.to_le_bytes())?;
+    db.flush_memtable()?;
+    db.merge("with_base", 10u64.to_le_bytes())?;
+    db.merge("without_base", 10u64.to_le_bytes())?;
+    assert_eq!(counter(&db, "with_base")?, Some(111));
+    assert_eq!(counter(&db, "without_base")?, Some(11));
+    assert_eq!(counter(&db, "missing")?, None);
+
+    // Without a merge function there is no merging
+    let mut plain = SnailDb::open(temp_dir.path().join("plain"))?;
+    assert!(plain.merge("hits", 1u64.to_le_bytes()).is_err());
+    assert_eq!(plain.get("hits")?, None);
+    Ok(())
+}
+
+#[test]
+fn test_delete_range_survives_replay_and_flush() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    {
+        let mut db = SnailDb::open(&db_path)?;
+        for key in ["a", "b", "c", "d"] {
+            db.put(key, "flushed")?;
+        }
+        db.flush_memtable()?;
+        db.put("bb", "memtable")?;
+        // The start of the range is deleted, the end is not
+        db.delete_range("b", "d")?;
+        db.put("c", "after")?;
+        // An empty range deletes nothing
+        db.delete_range("d", "d")?;
+        db.delete_range("d", "a")?;
+        db.wal.force_flush()?;
+    }
+
+    let mut db = SnailDb::open(&db_path)?;
+    for _ in 0..2 {
+        assert_eq!(db.get("a")?, Some(b"flushed".to_vec()));
+        assert_eq!(db.get("b")?, None);
+        assert_eq!(db.get("bb")?, None);
+        assert_eq!(db.get("c")?, Some(b"after".to_vec()));
+        assert_eq!(db.get("d")?, Some(b"flushed".to_vec()));
+        // The range tombstone is flushed with the memtable and keeps hiding the older table
+        db.flush_memtable()?;
+    }
+    assert_eq!(db.sstables.len(), 2);
+    assert_eq!(db.sstables[0].range_tombstones().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_scan_skips_range_deleted_keys() -> Result<()> {
+    let leveled = LeveledOptions::default()
+        .with_l0_compaction_trigger(2)
+        .with_base_level_size(8 * 1024)
+        .with_target_file_size(2 * 1024);
+    let size_tiered = SizeTieredOptions::default().with_min_threshold(0);
+    let strategies: [CompactionStrategy; 2] = [size_tiered.into(), leveled.into()];
+    for strategy in strategies {
+        let temp_dir = TempDir::new()?;
+        let mut db = SnailDb::open(temp_dir.path())?.with_flush_threshold(4096).with_compaction(strategy);
+        for i in 0..300 {
+            for tenant in 0..3 {
+                db.put(format!("tenant:{tenant}:{i:03}"), vec![b'v'; 20])?;
+            }
+        }
+        assert!(db.sstables.len() + db.levels.iter().map(Vec::len).sum::<usize>() > 1);
+        db.delete_range("tenant:1:", "tenant:1;")?;
+        let expected: Vec<String> = ["0", "2"]
+            .iter()
+            .flat_map(|tenant| (0..300).map(move |i| format!("tenant:{tenant}:{i:03}")))
+            .collect();
+        assert_eq!(scanned_keys(db.scan(..))?, expected, "{:?}", db.compaction);
+        assert_eq!(scanned_keys(db.scan_rev(..))?, expected.iter().rev().cloned().collect::<Vec<_>>());
+        assert!(scanned_keys(db.scan_prefix("tenant:1:"))?.is_empty());
+
+        // The range tombstone keeps hiding the keys once flushed and compacted, and once reopened
+        db.flush_memtable()?;
+        while db.compact()? {}
+        assert_eq!(scanned_keys(db.scan(..))?, expected);
+        db.close()?;
+        let db = SnailDb::open(temp_dir.path())?;
+        assert_eq!(scanned_keys(db.scan(..))?, expected);
+        assert_eq!(db.get("tenant:1:000")?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_merge_stops_at_range_delete() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let mut db = SnailDb::open_with_merge_function(&db_path, add_counter)?;
+    db.put("hits", 10u64.to_le_bytes())?;
+    db.flush_memtable()?;
+    db.delete_range("hits", "hitz")?;
+    db.merge("hits", 1u64.to_le_bytes())?;
+    assert_eq!(counter(&db, "hits")?, Some(1));
+
+    // The value they made is flushed with the range tombstone, later operands stack onto it
+    db.flush_memtable()?;
+    db.merge("hits", 2u64.to_le_bytes())?;
+    assert_eq!(counter(&db, "hits")?, Some(3));
+    db.flush_memtable()?;
+    assert_eq!(counter(&db, "hits")?, Some(3));
+    Ok(())
+}
@@ -1,10 +1,20 @@
-use snaildb::wal::Wal;
+use snaildb::utils::{
+    FormatVersion, RecordError, RecordKind, RecordLimits, encode_numbered_write_batch, encode_write_batch, read_record,
+    write_record,
+};
+use snaildb::wal::{
+    self, ArchivePolicy, FileSync, LogSync, SegmentOptions, SyncPolicy, Wal, WalWriter, WalWriterOptions,
+};
 use anyhow::Result;
 use tempfile::TempDir;
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::thread;
 use std::time::Duration;
 
+/// Length of the header a WAL file of the latest format starts with, see `FormatVersion`.
+const FILE_HEADER_LEN: u64 = 10;
+
 #[test]
 fn test_wal_open() -> Result<()> {
     let temp_dir = TempDir::new()?;
@@ -71,10 +81,16 @@ fn test_failure_very_large_key() -> Result<()> {
     let db_path = temp_dir.path().join("test_db");
     let mut db = Wal::open(&db_path)?;
     
-    // Very large key (1MB)
-    let large_key = "x".repeat(1024 * 1024);
+    // The largest key allowed
+    let large_key = "x".repeat(RecordLimits::DEFAULT_MAX_KEY_LEN);
     db.append_set(&large_key, b"value")?;
     db.append_delete(&large_key)?;
+
+    // A longer one (1MB) is refused right away
+    let err = db.append_set(&"x".repeat(1024 * 1024), b"value").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let expected = RecordError::KeyTooLarge { len: 1024 * 1024, max: RecordLimits::DEFAULT_MAX_KEY_LEN };
+    assert_eq!(err.get_ref().and_then(|err| err.downcast_ref()), Some(&expected));
     Ok(())
 }
 
@@ -549,4 +565,1720 @@ fn test_mpsc_handler_concurrent_writes_and_flush() -> Result<()> {
     assert_eq!(entries.len(), 4);
     
     Ok(())
-}
\ No newline at end of file
+}
+/// Reads every record of the log at `path` as (kind, key, value).
+fn read_log(path: &std::path::Path) -> Result<Vec<(RecordKind, String, Vec<u8>)>> {
+    let mut records = Vec::new();
+    for record in wal::replay(path)? {
+        let record = record?;
+        records.push((record.kind, String::from_utf8(record.key)?, record.value));
+    }
+    Ok(records)
+}
+
+#[test]
+fn test_wal_writer_shutdown_waits_for_queued_records() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+
+    for i in 0..5_000 {
+        if i % 7 == 0 {
+            handle.write(RecordKind::Delete, &format!("key_{}", i), &[])?;
+        } else {
+            handle.write(RecordKind::Set, &format!("key_{}", i), format!("value_{}", i).as_bytes())?;
+        }
+    }
+    handle.shutdown()?;
+    // The writer is gone, so later commands fail instead of being lost silently
+    writer.join()?;
+    assert!(handle.write(RecordKind::Set, "late", b"value").is_err());
+
+    let records = read_log(&db_path)?;
+    assert_eq!(records.len(), 5_000);
+    for (i, (kind, key, value)) in records.iter().enumerate() {
+        assert_eq!(key, &format!("key_{}", i));
+        if i % 7 == 0 {
+            assert_eq!((*kind, value.as_slice()), (RecordKind::Delete, &[][..]));
+        } else {
+            assert_eq!((*kind, value.clone()), (RecordKind::Set, format!("value_{}", i).into_bytes()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_wal_writer_keeps_order_across_handles() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+
+    handle.write(RecordKind::Set, "before_reset", b"gone")?;
+    handle.reset()?;
+    let workers: Vec<_> = (0..4)
+        .map(|t| {
+            let handle = handle.clone();
+            thread::spawn(move || -> std::io::Result<()> {
+                for i in 0..500 {
+                    handle.write(RecordKind::Set, &format!("{}:{:04}", t, i), b"v")?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().expect("worker panicked")?;
+    }
+    // Dropping the last handle shuts the writer down like `Shutdown`
+    drop(handle);
+    writer.join()?;
+
+    let records = read_log(&db_path)?;
+    assert_eq!(records.len(), 2_000);
+    for t in 0..4 {
+        let keys: Vec<&String> = records
+            .iter()
+            .map(|(_, key, _)| key)
+            .filter(|key| key.starts_with(&format!("{}:", t)))
+            .collect();
+        let expected: Vec<String> = (0..500).map(|i| format!("{}:{:04}", t, i)).collect();
+        assert_eq!(keys, expected.iter().collect::<Vec<_>>());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_wal_replay_after_flush() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+
+    // An empty log replays nothing
+    drop(std::fs::File::create(&db_path)?);
+    let mut empty = wal::replay(&db_path)?;
+    assert!(empty.next().is_none());
+    assert_eq!((empty.valid_len(), empty.has_torn_tail()), (0, false));
+
+    let written = vec![
+        (RecordKind::Set, "key1".to_string(), b"value1".to_vec()),
+        (RecordKind::Delete, "key2".to_string(), Vec::new()),
+        (RecordKind::Set, "key3".to_string(), vec![0, 1, 2]),
+        (RecordKind::Set, "key1".to_string(), b"value4".to_vec()),
+    ];
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    for (kind, key, value) in &written {
+        handle.write(*kind, key, value)?;
+    }
+    handle.flush()?;
+    drop(handle);
+    writer.join()?;
+
+    // The log ends exactly at a record boundary
+    let log_len = std::fs::metadata(&db_path)?.len();
+    let mut replay = wal::replay(&db_path)?;
+    let replayed = replay
+        .by_ref()
+        .map(|record| record.map(|record| (record.kind, String::from_utf8(record.key).expect("utf-8"), record.value)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(replayed, written);
+    assert_eq!((replay.valid_len(), replay.has_torn_tail()), (log_len, false));
+
+    // A record cut short by a crash ends the replay at the last whole record
+    let mut partial = Vec::new();
+    write_record(&mut partial, RecordKind::Set, b"key5", b"value5", 0)?;
+    let mut file = std::fs::OpenOptions::new().append(true).open(&db_path)?;
+    file.write_all(&partial[..partial.len() - 3])?;
+    drop(file);
+    let mut replay = wal::replay(&db_path)?;
+    assert_eq!(replay.by_ref().collect::<std::io::Result<Vec<_>>>()?.len(), written.len());
+    assert_eq!((replay.valid_len(), replay.has_torn_tail()), (log_len, true));
+    Ok(())
+}
+
+#[test]
+fn test_wal_segments_rotate_and_replay_in_order() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path().join("wal");
+    let record = |i: usize| (format!("key_{:04}", i), format!("value_{:04}", i).into_bytes());
+    let mut encoded = Vec::new();
+    let (key, value) = record(0);
+    write_record(&mut encoded, RecordKind::Set, key.as_bytes(), &value, 1)?;
+
+    // Each hundred records fills a segment exactly, waiting on the flushes keeps them in
+    // separate batches
+    let options = SegmentOptions::default().with_max_segment_size(FILE_HEADER_LEN + 100 * encoded.len() as u64);
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    for i in 0..300 {
+        let (key, value) = record(i);
+        handle.write(RecordKind::Set, &key, &value)?;
+        if i % 100 == 99 {
+            handle.flush()?;
+        }
+    }
+    handle.shutdown()?;
+    writer.join()?;
+
+    let found = wal::segments(&dir)?;
+    assert_eq!(found.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), [1, 2, 3]);
+    for (seq, path) in &found {
+        // No record is split across segments, so each one replays whole on its own
+        let mut replay = wal::replay(path)?;
+        let keys = replay.by_ref().map(|r| r.map(|r| r.key)).collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(keys.len(), 100, "segment {}", seq);
+        assert!(!replay.has_torn_tail());
+        assert_eq!(keys[0], record((*seq as usize - 1) * 100).0.into_bytes());
+    }
+    let replayed = wal::replay_dir(&dir)?.map(|r| r.map(|r| (r.key, r.value))).collect::<std::io::Result<Vec<_>>>()?;
+    let expected: Vec<_> = (0..300).map(|i| (record(i).0.into_bytes(), record(i).1)).collect();
+    assert_eq!(replayed, expected);
+
+    // Reopening appends to the newest segment, and the older ones can be deleted
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    handle.write(RecordKind::Delete, "key_0300", &[])?;
+    handle.delete_segments_before(2)?;
+    drop(handle);
+    writer.join()?;
+    assert!(!wal::segment_path(&dir, 1).exists());
+    let keys = wal::replay_dir(&dir)?.map(|r| r.map(|r| r.key)).collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(keys.len(), 201);
+    assert_eq!(keys[0], b"key_0100");
+    assert_eq!(keys[200], b"key_0300");
+
+    // A partial record is only a torn tail in the last segment
+    let middle = wal::segment_path(&dir, 2);
+    let len = std::fs::metadata(&middle)?.len();
+    std::fs::OpenOptions::new().write(true).open(&middle)?.set_len(len - 2)?;
+    let results: Vec<_> = wal::replay_dir(&dir)?.collect();
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 99);
+    assert!(results.last().expect("an item").is_err());
+    Ok(())
+}
+
+/// Encodes `count` records keyed `key_<i>` the way the WAL writes them, returning the log and
+/// the offset of each record.
+fn encode_log(count: usize) -> Result<(Vec<u8>, Vec<usize>)> {
+    let mut log = Vec::new();
+    let mut offsets = Vec::new();
+    for i in 0..count {
+        offsets.push(log.len());
+        let (key, value) = (format!("key_{}", i), format!("value_{}", i));
+        write_record(&mut log, RecordKind::Set, key.as_bytes(), value.as_bytes(), i as u64 + 1)?;
+    }
+    Ok((log, offsets))
+}
+
+fn replay_keys(replay: &mut wal::WalReplay) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for record in replay {
+        keys.push(String::from_utf8(record?.key)?);
+    }
+    Ok(keys)
+}
+
+#[test]
+fn test_wal_record_limits() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let key_limit = RecordLimits::DEFAULT_MAX_KEY_LEN;
+    let long_key = "k".repeat(key_limit + 1);
+
+    // A key over the default limit fails its write, and takes no sequence number
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    let err = handle.write(RecordKind::Set, &long_key, b"value").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let expected = RecordError::KeyTooLarge { len: key_limit + 1, max: key_limit };
+    assert_eq!(err.get_ref().and_then(|err| err.downcast_ref()), Some(&expected));
+    assert_eq!(handle.write(RecordKind::Set, "first", b"value")?, 1);
+    handle.shutdown()?;
+    writer.join()?;
+
+    // Limits of its own let the writer take the key, and refuse a batch with a value over them
+    let limits = RecordLimits::default().with_max_key_len(2 * key_limit).with_max_value_len(8);
+    let options = WalWriterOptions::default().with_record_limits(limits);
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    assert_eq!(handle.write(RecordKind::Set, &long_key, b"value")?, 2);
+    let batch = vec![(RecordKind::Set, "a".to_string(), b"1".to_vec()), (RecordKind::Set, "b".to_string(), vec![0; 9])];
+    let err = handle.write_batch(batch).unwrap_err();
+    assert_eq!(err.get_ref().and_then(|err| err.downcast_ref()), Some(&RecordError::ValueTooLarge { len: 9, max: 8 }));
+    handle.shutdown()?;
+    writer.join()?;
+    let mut replay = wal::replay_with_options(&db_path, &wal::ReplayOptions::default().with_record_limits(limits))?;
+    assert_eq!(replay_keys(&mut replay)?, ["first".to_string(), long_key]);
+
+    // A damaged length claiming gigabytes is a torn tail, not a reason to allocate them
+    let whole = std::fs::metadata(&db_path)?.len();
+    let mut file = std::fs::OpenOptions::new().append(true).open(&db_path)?;
+    file.write_all(&0xFFFF_FFF0u32.to_le_bytes())?;
+    file.write_all(&[0xAB; 32])?;
+    drop(file);
+    let mut replay = wal::replay(&db_path)?;
+    assert_eq!(replay_keys(&mut replay)?.len(), 2);
+    assert!(replay.has_torn_tail());
+    assert_eq!(replay.valid_len(), whole);
+    Ok(())
+}
+
+#[test]
+fn test_wal_replay_drops_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (log, offsets) = encode_log(4)?;
+    let whole = offsets[3] as u64;
+
+    // The last record cut short anywhere, from inside its header to its last byte
+    for cut in [offsets[3] + 2, offsets[3] + 6, offsets[3] + 11, log.len() - 1] {
+        std::fs::write(&db_path, &log[..cut])?;
+        let mut replay = wal::replay(&db_path)?;
+        assert_eq!(replay_keys(&mut replay)?, ["key_0", "key_1", "key_2"], "cut at {}", cut);
+        assert!(replay.has_torn_tail());
+        assert_eq!(replay.valid_len(), whole);
+    }
+
+    // The last record written whole but with garbage in place of its payload
+    let mut garbled = log.clone();
+    for byte in &mut garbled[offsets[3] + 8..] {
+        *byte = 0;
+    }
+    std::fs::write(&db_path, &garbled)?;
+    let options = wal::ReplayOptions::default().with_truncate_torn_tail(true);
+    let mut replay = wal::replay_with_options(&db_path, &options)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0", "key_1", "key_2"]);
+    assert!(replay.has_torn_tail());
+    assert_eq!(std::fs::metadata(&db_path)?.len(), whole);
+
+    // Once truncated the log replays cleanly
+    let mut replay = wal::replay(&db_path)?;
+    assert_eq!(replay_keys(&mut replay)?.len(), 3);
+    assert!(!replay.has_torn_tail());
+    Ok(())
+}
+
+#[test]
+fn test_wal_replay_reports_corruption_before_valid_records() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (mut log, offsets) = encode_log(4)?;
+
+    // Flip a byte in the key of the second record, the two after it are still valid
+    log[offsets[1] + 10] ^= 0xFF;
+    std::fs::write(&db_path, &log)?;
+    let options = wal::ReplayOptions::default().with_truncate_torn_tail(true);
+    let mut replay = wal::replay_with_options(&db_path, &options)?;
+    assert_eq!(String::from_utf8(replay.next().expect("first record")?.key)?, "key_0");
+    let Some(Err(err)) = replay.next() else {
+        panic!("expected the corrupt record to fail");
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("corrupt"), "{}", err);
+    assert!(replay.next().is_none());
+    assert!(!replay.has_torn_tail());
+    // Nothing is truncated on corruption
+    assert_eq!(std::fs::metadata(&db_path)?.len(), log.len() as u64);
+    Ok(())
+}
+
+/// Encodes a batch of `count` SET records keyed `<prefix>_<i>`.
+fn encode_batch(prefix: &str, count: usize) -> Result<Vec<u8>> {
+    let keys: Vec<String> = (0..count).map(|i| format!("{}_{}", prefix, i)).collect();
+    let mut batch = Vec::new();
+    encode_write_batch(&mut batch, keys.iter().map(|key| (RecordKind::Set, key.as_bytes(), b"value".as_slice())))?;
+    Ok(batch)
+}
+
+#[test]
+fn test_wal_replay_drops_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (single, _) = encode_log(1)?;
+    let batch = encode_batch("batch", 3)?;
+    // The header comes first and takes 21 bytes, the records follow
+    let header_len = 21;
+    let first_record = read_record(&mut &batch[header_len..])?.expect("a record after the header");
+    let second_record = header_len + 8 + first_record.length as usize;
+
+    for (torn, cut) in [("after the header", header_len), ("in the middle of the records", second_record + 5)] {
+        let mut log = single.clone();
+        log.extend_from_slice(&batch[..cut]);
+        std::fs::write(&db_path, &log)?;
+        let mut replay = wal::replay(&db_path)?;
+        assert_eq!(replay_keys(&mut replay)?, ["key_0"], "batch torn {}", torn);
+        assert!(replay.has_torn_tail(), "batch torn {}", torn);
+        assert_eq!(replay.valid_len(), single.len() as u64, "batch torn {}", torn);
+    }
+
+    // A whole batch followed by a torn one: the first is replayed whole, the second dropped
+    let mut log = single.clone();
+    log.extend_from_slice(&batch);
+    log.extend_from_slice(&encode_batch("torn", 2)?[..header_len + 3]);
+    std::fs::write(&db_path, &log)?;
+    let options = wal::ReplayOptions::default().with_truncate_torn_tail(true);
+    let mut replay = wal::replay_with_options(&db_path, &options)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0", "batch_0", "batch_1", "batch_2"]);
+    assert!(replay.has_torn_tail());
+    assert_eq!(std::fs::metadata(&db_path)?.len(), (single.len() + batch.len()) as u64);
+
+    // A damaged batch followed by a valid record is corruption
+    let mut log = batch.clone();
+    log[second_record + 10] ^= 0xFF;
+    log.extend_from_slice(&single);
+    std::fs::write(&db_path, &log)?;
+    let mut replay = wal::replay(&db_path)?;
+    let Some(Err(err)) = replay.next() else {
+        panic!("expected the damaged batch to fail");
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn test_wal_writer_appends_batches() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    handle.write(RecordKind::Set, "before", b"1")?;
+    handle.write_batch(vec![
+        (RecordKind::Set, "order:1".to_string(), b"row".to_vec()),
+        (RecordKind::Set, "index:a".to_string(), b"order:1".to_vec()),
+        (RecordKind::Delete, "index:b".to_string(), Vec::new()),
+    ])?;
+    handle.write_batch(Vec::new())?;
+    handle.write(RecordKind::Set, "after", b"2")?;
+    handle.shutdown()?;
+    writer.join()?;
+
+    let mut replay = wal::replay(&db_path)?;
+    let records = replay.by_ref().collect::<std::io::Result<Vec<_>>>()?;
+    let keys: Vec<&[u8]> = records.iter().map(|record| record.key.as_slice()).collect();
+    assert_eq!(keys, [b"before".as_slice(), b"order:1", b"index:a", b"index:b", b"after"]);
+    assert_eq!(records[3].kind, RecordKind::Delete);
+    assert!(!replay.has_torn_tail());
+    assert_eq!(replay.valid_len(), std::fs::metadata(&db_path)?.len());
+    Ok(())
+}
+
+/// Counts the syncs of a log, each taking a millisecond like a disk would.
+#[derive(Debug, Default)]
+struct CountingSync {
+    syncs: std::sync::atomic::AtomicUsize,
+}
+
+impl LogSync for CountingSync {
+    fn sync(&self, file: &std::fs::File) -> std::io::Result<()> {
+        self.syncs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(1));
+        FileSync.sync(file)
+    }
+}
+
+#[test]
+fn test_wal_writer_group_commit_coalesces_syncs() -> Result<()> {
+    const WRITERS: usize = 16;
+    const COMMITS: usize = 20;
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let syncer = std::sync::Arc::new(CountingSync::default());
+    let options = WalWriterOptions::default().with_syncer(syncer.clone());
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+
+    let start = std::sync::Arc::new(std::sync::Barrier::new(WRITERS));
+    let threads: Vec<_> = (0..WRITERS)
+        .map(|writer| {
+            let handle = handle.clone();
+            let start = start.clone();
+            thread::spawn(move || -> std::io::Result<()> {
+                start.wait();
+                for commit in 0..COMMITS {
+                    handle.write(RecordKind::Set, &format!("writer_{}_{}", writer, commit), b"value")?;
+                    handle.flush()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("writer thread panicked")?;
+    }
+    let syncs = syncer.syncs.load(std::sync::atomic::Ordering::SeqCst);
+    assert!(syncs > 0);
+    assert!(syncs * 4 <= WRITERS * COMMITS, "{} syncs for {} commits", syncs, WRITERS * COMMITS);
+
+    // Every acknowledged record is in the log
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(read_log(&db_path)?.len(), WRITERS * COMMITS);
+    Ok(())
+}
+
+/// Runs ten writes, a flush, two more writes and a shutdown through a writer with `policy`,
+/// returning the number of syncs.
+fn count_syncs(policy: SyncPolicy) -> Result<usize> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let syncer = std::sync::Arc::new(CountingSync::default());
+    let options = WalWriterOptions::default().with_syncer(syncer.clone()).with_sync_policy(policy);
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    for i in 0..10 {
+        handle.write(RecordKind::Set, &format!("key_{}", i), b"value")?;
+    }
+    handle.flush()?;
+    handle.write(RecordKind::Set, "key_10", b"value")?;
+    handle.write(RecordKind::Set, "key_11", b"value")?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(read_log(&db_path)?.len(), 12);
+    Ok(syncer.syncs.load(std::sync::atomic::Ordering::SeqCst))
+}
+
+#[test]
+fn test_wal_writer_sync_policies() -> Result<()> {
+    // One sync per record, the flush finds nothing left to sync, and one on shutdown
+    assert_eq!(count_syncs(SyncPolicy::Always)?, 13);
+    // After the 4th and 8th records, on the flush and on shutdown
+    assert_eq!(count_syncs(SyncPolicy::EveryN(4))?, 4);
+    // Only the flush and the shutdown sync
+    assert_eq!(count_syncs(SyncPolicy::Never)?, 2);
+    assert_eq!(count_syncs(SyncPolicy::Interval(Duration::from_secs(3600)))?, 2);
+    Ok(())
+}
+
+#[test]
+fn test_wal_writer_interval_policy_syncs_on_a_timer() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    for (policy, synced) in [(SyncPolicy::Interval(Duration::from_millis(5)), true), (SyncPolicy::Never, false)] {
+        let db_path = temp_dir.path().join(format!("{:?}", policy));
+        let syncer = std::sync::Arc::new(CountingSync::default());
+        let options = WalWriterOptions::default().with_syncer(syncer.clone()).with_sync_policy(policy);
+        let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+        handle.write(RecordKind::Set, "key", b"value")?;
+
+        // Without a flush the timer syncs the write, or nothing does
+        thread::sleep(Duration::from_millis(100));
+        let syncs = syncer.syncs.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(syncs > 0, synced, "{:?} synced {} times", policy, syncs);
+        handle.shutdown()?;
+        writer.join()?;
+    }
+    Ok(())
+}
+
+/// Applies the replay of a WAL over `state`, the last write winning.
+fn apply_replay(
+    state: &mut std::collections::BTreeMap<Vec<u8>, Vec<u8>>,
+    replay: wal::WalReplay,
+) -> Result<usize> {
+    let mut applied = 0;
+    for record in replay {
+        let record = record?;
+        match record.kind {
+            RecordKind::Delete => state.remove(&record.key),
+            _ => state.insert(record.key, record.value),
+        };
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+#[test]
+fn test_wal_checkpoint_after_flush() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let from_checkpoint = wal::ReplayOptions::default().with_from_checkpoint(true);
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    handle.write(RecordKind::Set, "k1", b"a")?;
+    handle.write(RecordKind::Set, "k2", b"b")?;
+    handle.write(RecordKind::Delete, "k1", b"")?;
+    handle.write(RecordKind::Set, "k3", b"c")?;
+    let flushed_at = handle.position()?;
+
+    // The memtable holding those four records is flushed, its contents persisted elsewhere
+    let mut flushed = std::collections::BTreeMap::new();
+    apply_replay(&mut flushed, wal::replay(&db_path)?)?;
+    handle.write(RecordKind::Set, "k2", b"d")?;
+    handle.write(RecordKind::Set, "k1", b"e")?;
+    handle.flush()?;
+
+    // A crash before the checkpoint replays the flushed records again, which changes nothing
+    let mut recovered = flushed.clone();
+    assert_eq!(apply_replay(&mut recovered, wal::replay_with_options(&db_path, &from_checkpoint)?)?, 6);
+    let expected: std::collections::BTreeMap<Vec<u8>, Vec<u8>> = [
+        (b"k1".to_vec(), b"e".to_vec()),
+        (b"k2".to_vec(), b"d".to_vec()),
+        (b"k3".to_vec(), b"c".to_vec()),
+    ]
+    .into();
+    assert_eq!(recovered, expected);
+
+    // After the checkpoint only the records written since the flush are replayed
+    handle.checkpoint(flushed_at)?;
+    assert_eq!(wal::read_checkpoint(wal::file_checkpoint_path(&db_path))?, Some(flushed_at));
+    let mut recovered = flushed.clone();
+    assert_eq!(apply_replay(&mut recovered, wal::replay_with_options(&db_path, &from_checkpoint)?)?, 2);
+    assert_eq!(recovered, expected);
+
+    // A checkpoint at the end empties the log but for the last sequence number, later records
+    // are replayed from its start
+    let end = handle.position()?;
+    handle.checkpoint(end)?;
+    let mut replay = wal::replay(&db_path)?;
+    assert!(replay_keys(&mut replay)?.is_empty());
+    assert_eq!(replay.last_seqno(), 6);
+    assert_eq!(handle.write(RecordKind::Set, "k4", b"f")?, 7);
+    handle.flush()?;
+    let mut replay = wal::replay_with_options(&db_path, &from_checkpoint)?;
+    assert_eq!(replay_keys(&mut replay)?, ["k4"]);
+
+    // Checkpoints past the end are refused
+    let past = wal::WalPosition { offset: 1 << 20, ..end };
+    assert_eq!(handle.checkpoint(past).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    handle.shutdown()?;
+    writer.join()?;
+    Ok(())
+}
+
+#[test]
+fn test_wal_checkpoint_at_segment_boundary() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path().join("wal");
+    let (encoded, _) = encode_log(1)?;
+    let options = SegmentOptions::default().with_max_segment_size(FILE_HEADER_LEN + 10 * encoded.len() as u64);
+    let from_checkpoint = wal::ReplayOptions::default().with_from_checkpoint(true);
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+
+    // Ten records fill the first segment exactly
+    for i in 0..10 {
+        handle.write(RecordKind::Set, &format!("key_{}", i), b"value_0")?;
+    }
+    let boundary = handle.position()?;
+    assert_eq!(boundary, wal::WalPosition { segment: 1, offset: FILE_HEADER_LEN + 10 * encoded.len() as u64 });
+    for i in 10..15 {
+        handle.write(RecordKind::Set, &format!("key_{}", i), b"value_0")?;
+    }
+    handle.flush()?;
+    assert_eq!(wal::segments(&dir)?.len(), 2);
+
+    // The checkpoint at the end of the first segment removes all of it
+    handle.checkpoint(boundary)?;
+    assert_eq!(wal::segments(&dir)?.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), [2]);
+    let mut replay = wal::replay_dir_with_options(&dir, &from_checkpoint)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_10", "key_11", "key_12", "key_13", "key_14"]);
+
+    // A checkpoint in the middle of the active segment keeps it
+    let mut replay = wal::replay_dir(&dir)?;
+    replay.next().expect("a record")?;
+    handle.checkpoint(wal::WalPosition { segment: 2, offset: replay.valid_len() })?;
+    assert_eq!(wal::segments(&dir)?.len(), 1);
+    let mut replay = wal::replay_dir_with_options(&dir, &from_checkpoint)?;
+    assert_eq!(replay_keys(&mut replay)?.len(), 4);
+    handle.shutdown()?;
+    writer.join()?;
+
+    // Reopening resumes the active segment, the checkpoint still applies
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    handle.write(RecordKind::Set, "key_15", b"value_0")?;
+    handle.shutdown()?;
+    writer.join()?;
+    let mut replay = wal::replay_dir_with_options(&dir, &from_checkpoint)?;
+    assert_eq!(replay_keys(&mut replay)?.len(), 5);
+    Ok(())
+}
+
+/// Fails every sync, like a disk that went away.
+#[derive(Debug)]
+struct FailingSync;
+
+impl LogSync for FailingSync {
+    fn sync(&self, _file: &std::fs::File) -> std::io::Result<()> {
+        Err(std::io::Error::other("injected sync failure"))
+    }
+}
+
+#[test]
+fn test_wal_writer_flush_reports_sync_errors() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let options = WalWriterOptions::default().with_syncer(std::sync::Arc::new(FailingSync));
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    handle.write(RecordKind::Set, "key", b"value")?;
+    let err = handle.flush().unwrap_err();
+    assert!(err.to_string().contains("injected sync failure"), "{}", err);
+    let err = handle.shutdown().unwrap_err();
+    assert!(err.to_string().contains("injected sync failure"), "{}", err);
+    writer.join()?;
+    Ok(())
+}
+
+#[test]
+fn test_wal_writer_reports_background_write_errors_on_next_flush() -> Result<()> {
+    // Every write to /dev/full fails as if the disk were full
+    let full = std::path::Path::new("/dev/full");
+    if !full.exists() {
+        return Ok(());
+    }
+    let options = WalWriterOptions::default().with_sync_policy(SyncPolicy::Never);
+    let (writer, handle) = WalWriter::open_with_options(full, &options)?;
+    handle.write(RecordKind::Set, "key", b"value")?;
+
+    // The write fails in the background with nobody waiting on it, the next flush reports it
+    thread::sleep(Duration::from_millis(50));
+    let err = handle.flush().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::StorageFull, "{}", err);
+    // Reported once, nothing else was written since
+    handle.flush()?;
+    handle.write(RecordKind::Set, "key", b"value")?;
+    assert_eq!(handle.shutdown().unwrap_err().kind(), std::io::ErrorKind::StorageFull);
+    writer.join()?;
+    Ok(())
+}
+
+/// Writes `count` records keyed `<prefix>_<i>` through `handle`, flushing each one so they
+/// fill segments one by one.
+fn write_keys(handle: &wal::WalHandle, prefix: &str, count: usize) -> Result<()> {
+    for i in 0..count {
+        handle.write(RecordKind::Set, &format!("{}_{}", prefix, i), b"value")?;
+        handle.flush()?;
+    }
+    Ok(())
+}
+
+fn segment_seqs(dir: &std::path::Path) -> Result<Vec<u64>> {
+    Ok(wal::segments(dir)?.into_iter().map(|(seq, _)| seq).collect())
+}
+
+#[test]
+fn test_wal_reset_with_archive_policies() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let archive = temp_dir.path().join("archive");
+    let from_checkpoint = wal::ReplayOptions::default().with_from_checkpoint(true);
+    let (encoded, _) = encode_log(1)?;
+    let policies = [ArchivePolicy::Delete, ArchivePolicy::MoveTo(archive.clone()), ArchivePolicy::Keep];
+    for (i, policy) in policies.into_iter().enumerate() {
+        let dir = temp_dir.path().join(format!("wal_{}", i));
+        let options = SegmentOptions::default()
+            .with_max_segment_size(FILE_HEADER_LEN + 4 * encoded.len() as u64)
+            .with_archive_policy(policy.clone());
+        let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+        // Three segments, the last one half full
+        write_keys(&handle, "old", 10)?;
+        assert_eq!(segment_seqs(&dir)?, [1, 2, 3]);
+        handle.reset()?;
+        write_keys(&handle, "new", 2)?;
+        handle.shutdown()?;
+        writer.join()?;
+
+        match &policy {
+            ArchivePolicy::Delete => {
+                assert_eq!(segment_seqs(&dir)?, [3]);
+            }
+            ArchivePolicy::MoveTo(archive) => {
+                assert_eq!(segment_seqs(&dir)?, [4]);
+                assert_eq!(segment_seqs(archive)?, [1, 2, 3]);
+                let mut replay = wal::replay_archived(archive)?;
+                assert_eq!(replay_keys(&mut replay)?.len(), 10);
+            }
+            ArchivePolicy::Keep => {
+                assert_eq!(segment_seqs(&dir)?, [1, 2, 3, 4]);
+                assert_eq!(
+                    wal::read_checkpoint(wal::dir_checkpoint_path(&dir))?,
+                    Some(wal::WalPosition { segment: 4, offset: 0 })
+                );
+                let mut replay = wal::replay_archived(&dir)?;
+                assert_eq!(replay_keys(&mut replay)?.len(), 10);
+            }
+        }
+
+        // Recovery only sees the records written since the reset
+        let mut replay = wal::replay_dir_with_options(&dir, &from_checkpoint)?;
+        assert_eq!(replay_keys(&mut replay)?, ["new_0", "new_1"], "{:?}", policy);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_wal_preallocated_segments() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path().join("wal");
+    let mut record = Vec::new();
+    write_record(&mut record, RecordKind::Set, b"first_0", b"value", 1)?;
+    let max_size = FILE_HEADER_LEN + 4 * record.len() as u64;
+    let options = SegmentOptions::default().with_max_segment_size(max_size).with_preallocate(true);
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    write_keys(&handle, "first", 2)?;
+    handle.shutdown()?;
+    writer.join()?;
+
+    // The file is full size, the zeros after the records are the end of the log
+    let path = dir.join("wal-1.log");
+    assert_eq!(std::fs::metadata(&path)?.len(), max_size);
+    let mut replay = wal::replay(&path)?;
+    assert_eq!(replay_keys(&mut replay)?, ["first_0", "first_1"]);
+    assert!(!replay.has_torn_tail());
+    assert_eq!(replay.valid_len(), FILE_HEADER_LEN + 2 * record.len() as u64);
+
+    // Reopening appends after the last record and rolls over into another full size segment
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    write_keys(&handle, "again", 3)?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(segment_seqs(&dir)?, [1, 2]);
+    assert_eq!(std::fs::metadata(dir.join("wal-2.log"))?.len(), max_size);
+    let mut replay = wal::replay_dir(&dir)?;
+    assert_eq!(replay_keys(&mut replay)?, ["first_0", "first_1", "again_0", "again_1", "again_2"]);
+    assert!(!replay.has_torn_tail());
+    Ok(())
+}
+
+#[test]
+fn test_wal_recycles_deleted_segments() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path().join("wal");
+    let mut record = Vec::new();
+    write_record(&mut record, RecordKind::Set, b"old_0", b"value", 1)?;
+    let options = SegmentOptions::default()
+        .with_max_segment_size(FILE_HEADER_LEN + 2 * record.len() as u64)
+        .with_recycle_segments(true);
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    write_keys(&handle, "old", 5)?;
+    assert_eq!(segment_seqs(&dir)?, [1, 2, 3]);
+    handle.delete_segments_before(3)?;
+    handle.flush()?;
+
+    // The deleted segments are kept aside and become the next ones
+    let spare = |dir: &std::path::Path| -> Result<usize> {
+        let names = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        Ok(names.iter().filter(|entry| entry.file_name().to_string_lossy().starts_with("recycle-")).count())
+    };
+    assert_eq!(spare(&dir)?, 2);
+    write_keys(&handle, "new", 3)?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(segment_seqs(&dir)?, [3, 4]);
+    assert_eq!(spare(&dir)?, 1);
+
+    // None of the old records of a reused file come back
+    let mut replay = wal::replay_dir(&dir)?;
+    assert_eq!(replay_keys(&mut replay)?, ["old_4", "new_0", "new_1", "new_2"]);
+    assert!(!replay.has_torn_tail());
+    Ok(())
+}
+
+#[test]
+fn test_wal_reader_yields_offsets_and_resumes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("wal.log");
+    let (log, offsets) = encode_log(4)?;
+    std::fs::write(&path, &log)?;
+
+    let mut reader = wal::WalReader::open(&path)?;
+    let mut read = Vec::new();
+    for entry in reader.by_ref() {
+        let (offset, record) = entry?;
+        read.push((offset as usize, String::from_utf8(record.key)?));
+    }
+    let expected: Vec<_> = offsets.iter().enumerate().map(|(i, offset)| (*offset, format!("key_{}", i))).collect();
+    assert_eq!(read, expected);
+    assert_eq!(reader.offset(), log.len() as u64);
+
+    // Resuming at a record boundary picks up from that record
+    let mut reader = wal::WalReader::open(&path)?;
+    reader.seek_to(offsets[2] as u64)?;
+    let keys = reader.map(|entry| Ok(String::from_utf8(entry?.1.key)?)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["key_2", "key_3"]);
+
+    // Resuming at the end yields nothing until more records are appended
+    let mut reader = wal::WalReader::open(&path)?;
+    reader.seek_to(log.len() as u64)?;
+    assert!(reader.next().is_none());
+    let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+    write_record(&mut file, RecordKind::Delete, b"key_4", b"", 0)?;
+    reader.seek_to(log.len() as u64)?;
+    let (offset, record) = reader.next().expect("appended record")?;
+    assert_eq!((offset, record.key.as_slice()), (log.len() as u64, b"key_4".as_slice()));
+    Ok(())
+}
+
+#[test]
+fn test_wal_reader_rejects_bogus_offsets() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("wal.log");
+    let (log, offsets) = encode_log(3)?;
+    std::fs::write(&path, &log)?;
+
+    let mut reader = wal::WalReader::open(&path)?;
+    for offset in [1, offsets[1] as u64 + 3, log.len() as u64 - 2] {
+        let err = reader.seek_to(offset).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "offset {}: {}", offset, err);
+    }
+    let err = reader.seek_to(log.len() as u64 + 1).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // A failed seek leaves the reader where it was
+    let (offset, record) = reader.next().expect("first record")?;
+    assert_eq!((offset, record.key.as_slice()), (0, b"key_0".as_slice()));
+    Ok(())
+}
+
+#[test]
+fn test_wal_reader_gives_batch_records_the_batch_offset() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("wal.log");
+    let (mut log, _) = encode_log(1)?;
+    let batch_offset = log.len() as u64;
+    log.extend(encode_batch("batch", 3)?);
+    std::fs::write(&path, &log)?;
+
+    let mut reader = wal::WalReader::open(&path)?;
+    let offsets = reader.by_ref().map(|entry| Ok(entry?.0)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(offsets, [0, batch_offset, batch_offset, batch_offset]);
+    reader.seek_to(batch_offset)?;
+    assert_eq!(reader.count(), 3);
+    Ok(())
+}
+
+fn replay_seqnos(replay: wal::WalReplay) -> Result<Vec<(String, u64)>> {
+    let mut seqnos = Vec::new();
+    for record in replay {
+        let record = record?;
+        seqnos.push((String::from_utf8(record.key)?, record.seqno));
+    }
+    Ok(seqnos)
+}
+
+#[test]
+fn test_wal_format_versions() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let expected = [("key_0", 1), ("key_1", 2), ("key_0", 3), ("key_2", 4), ("key_3", 5)];
+    let expected: Vec<_> = expected.iter().map(|(key, seqno)| (key.to_string(), *seqno)).collect();
+
+    // A log of version 1 has no header, three records and a batch of two
+    let fixture = include_bytes!("fixtures/wal-v1.log");
+    let v1_path = temp_dir.path().join("v1.log");
+    std::fs::write(&v1_path, fixture)?;
+    assert_eq!(replay_seqnos(wal::replay(&v1_path)?)?, expected);
+    assert_eq!(wal::WalReader::open(&v1_path)?.count(), 5);
+    let mut cursor = io::Cursor::new(&fixture[..]);
+    assert_eq!(read_record(&mut cursor)?.map(|record| record.key), Some(b"key_0".to_vec()));
+
+    // Appending to it keeps its records as they are, without a header in the middle
+    let (writer, handle) = WalWriter::open(&v1_path)?;
+    assert_eq!(handle.write(RecordKind::Set, "key_4", b"value_4")?, 6);
+    handle.shutdown()?;
+    writer.join()?;
+    let appended = std::fs::read(&v1_path)?;
+    assert_eq!(&appended[..fixture.len()], fixture);
+    let mut record = Vec::new();
+    write_record(&mut record, RecordKind::Set, b"key_4", b"value_4", 6)?;
+    assert_eq!(&appended[fixture.len()..], record);
+
+    // A new log starts with the header of the latest version, unless told to write version 1
+    for (version, header_len) in [(FormatVersion::LATEST, FILE_HEADER_LEN), (FormatVersion::V1, 0)] {
+        let path = temp_dir.path().join(format!("new-{:?}.log", version));
+        let options = WalWriterOptions::default().with_format_version(version);
+        let (writer, handle) = WalWriter::open_with_options(&path, &options)?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+        handle.write(RecordKind::Set, "key_0", b"value_0")?;
+        handle.shutdown()?;
+        writer.join()?;
+        let bytes = std::fs::read(&path)?;
+        let mut first = Vec::new();
+        write_record(&mut first, RecordKind::Set, b"key_0", b"value_0", 1)?;
+        assert_eq!(bytes.len() as u64, header_len + first.len() as u64, "{:?}", version);
+        assert_eq!(&bytes[header_len as usize..], first);
+        let mut replay = wal::replay(&path)?;
+        assert_eq!(replay_keys(&mut replay)?, ["key_0"]);
+        assert_eq!(replay.valid_len(), bytes.len() as u64);
+        let mut cursor = io::Cursor::new(&bytes);
+        assert_eq!(read_record(&mut cursor)?.map(|record| record.seqno), Some(1));
+    }
+
+    // A log of a version that came later is refused, naming both versions, and is not truncated
+    let mut future = Vec::new();
+    let payload = [0x43, 3];
+    future.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    future.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    future.extend_from_slice(&payload);
+    future.extend_from_slice(fixture);
+    let future_path = temp_dir.path().join("future.log");
+    std::fs::write(&future_path, &future)?;
+    let check = |err: io::Error| {
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported, "{}", err);
+        assert!(err.to_string().contains("version 3 is newer than the supported version 2"), "{}", err);
+    };
+    check(wal::replay(&future_path)?.next().expect("an error").unwrap_err());
+    check(wal::WalReader::open(&future_path)?.next().expect("an error").unwrap_err());
+    check(read_record(&mut io::Cursor::new(&future)).unwrap_err());
+    assert!(WalWriter::open(&future_path).is_err());
+    assert_eq!(std::fs::read(&future_path)?, future);
+    Ok(())
+}
+
+#[test]
+fn test_wal_sequence_numbers_continue_after_restart() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    assert_eq!(handle.last_sequence(), 0);
+    assert_eq!(handle.write(RecordKind::Set, "a", b"1")?, 1);
+    assert_eq!(handle.write(RecordKind::Delete, "b", b"")?, 2);
+    assert_eq!(handle.last_sequence(), 2);
+    handle.shutdown()?;
+    writer.join()?;
+
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    assert_eq!(handle.last_sequence(), 2);
+    assert_eq!(handle.write(RecordKind::Set, "c", b"3")?, 3);
+    // An emptied log still remembers where the numbers stopped
+    handle.reset()?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert!(read_log(&db_path)?.is_empty());
+
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    assert_eq!(handle.last_sequence(), 3);
+    assert_eq!(handle.write(RecordKind::Set, "d", b"4")?, 4);
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(replay_seqnos(wal::replay(&db_path)?)?, [("d".to_string(), 4)]);
+
+    // Segmented logs recover the number from every segment
+    let dir = temp_dir.path().join("wal");
+    let options = SegmentOptions::default().with_max_segment_size(64);
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    write_keys(&handle, "key", 5)?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert!(segment_seqs(&dir)?.len() > 1);
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    assert_eq!(handle.write(RecordKind::Set, "key_5", b"value")?, 6);
+    handle.shutdown()?;
+    writer.join()?;
+    let seqnos: Vec<u64> = replay_seqnos(wal::replay_dir(&dir)?)?.into_iter().map(|(_, seqno)| seqno).collect();
+    assert_eq!(seqnos, [1, 2, 3, 4, 5, 6]);
+    Ok(())
+}
+
+#[test]
+fn test_wal_numbers_batch_records() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    handle.write(RecordKind::Set, "before", b"1")?;
+    let batch = vec![
+        (RecordKind::Set, "batch_0".to_string(), b"a".to_vec()),
+        (RecordKind::Delete, "batch_1".to_string(), Vec::new()),
+        (RecordKind::Set, "batch_2".to_string(), b"c".to_vec()),
+    ];
+    assert_eq!(handle.write_batch(batch)?, 2..5);
+    assert_eq!(handle.write_batch(Vec::new())?, 5..5);
+    assert_eq!(handle.write(RecordKind::Set, "after", b"2")?, 5);
+    handle.shutdown()?;
+    writer.join()?;
+
+    let expected = [("before", 1), ("batch_0", 2), ("batch_1", 3), ("batch_2", 4), ("after", 5)];
+    let expected: Vec<_> = expected.iter().map(|(key, seqno)| (key.to_string(), *seqno)).collect();
+    assert_eq!(replay_seqnos(wal::replay(&db_path)?)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_wal_sequence_numbers_follow_log_order_across_handles() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    let workers: Vec<_> = (0..4)
+        .map(|t| {
+            let handle = handle.clone();
+            thread::spawn(move || -> std::io::Result<Vec<(String, u64)>> {
+                let mut written = Vec::new();
+                for i in 0..250 {
+                    let key = format!("{}:{:04}", t, i);
+                    let seqno = if i % 10 == 0 {
+                        handle.write_batch(vec![(RecordKind::Set, key.clone(), b"v".to_vec())])?.start
+                    } else {
+                        handle.write(RecordKind::Set, &key, b"v")?
+                    };
+                    written.push((key, seqno));
+                }
+                Ok(written)
+            })
+        })
+        .collect();
+    let mut written = Vec::new();
+    for worker in workers {
+        let mine = worker.join().expect("worker panicked")?;
+        // Each writer sees its own numbers increase
+        assert!(mine.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        written.extend(mine);
+    }
+    assert_eq!(handle.last_sequence(), 1_000);
+    handle.shutdown()?;
+    writer.join()?;
+
+    // The log holds the records in sequence number order, each with the number returned
+    let replayed = replay_seqnos(wal::replay(&db_path)?)?;
+    let seqnos: Vec<u64> = replayed.iter().map(|(_, seqno)| *seqno).collect();
+    assert_eq!(seqnos, (1..=1_000).collect::<Vec<_>>());
+    written.sort_by_key(|(_, seqno)| *seqno);
+    assert_eq!(replayed, written);
+    Ok(())
+}
+
+#[test]
+fn test_wal_reset_interrupted_before_rename_keeps_old_log() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    write_keys(&handle, "old", 3)?;
+    handle.shutdown()?;
+    writer.join()?;
+
+    // A crash during a reset, after the new log was written but before it replaced the old one
+    let new_log = temp_dir.path().join("test_db.new");
+    std::fs::write(&new_log, encode_log(1)?.0)?;
+    let mut replay = wal::replay(&db_path)?;
+    assert_eq!(replay_keys(&mut replay)?, ["old_0", "old_1", "old_2"]);
+    assert!(!replay.has_torn_tail());
+
+    // Opening the log drops the unfinished new one and carries on with the old
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    assert!(!new_log.exists());
+    assert_eq!(handle.last_sequence(), 3);
+    write_keys(&handle, "more", 1)?;
+    handle.reset()?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert!(!new_log.exists());
+    let mut replay = wal::replay(&db_path)?;
+    assert!(replay_keys(&mut replay)?.is_empty());
+    assert_eq!(replay.last_seqno(), 4);
+    Ok(())
+}
+
+#[test]
+fn test_wal_reset_up_to_keeps_newer_records() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    write_keys(&handle, "key", 5)?;
+    handle.reset_up_to(3)?;
+    assert_eq!(handle.write(RecordKind::Set, "key_5", b"value")?, 6);
+    handle.shutdown()?;
+    writer.join()?;
+    let kept: Vec<_> = [("key_3", 4), ("key_4", 5), ("key_5", 6)].iter().map(|(k, s)| (k.to_string(), *s)).collect();
+    assert_eq!(replay_seqnos(wal::replay(&db_path)?)?, kept);
+
+    // Records in older segments are carried over as well
+    let dir = temp_dir.path().join("wal");
+    let options = SegmentOptions::default().with_max_segment_size(64);
+    let (writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    write_keys(&handle, "key", 5)?;
+    assert!(segment_seqs(&dir)?.len() > 2);
+    handle.reset_up_to(2)?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(segment_seqs(&dir)?.len(), 1);
+    let seqnos: Vec<u64> = replay_seqnos(wal::replay_dir(&dir)?)?.into_iter().map(|(_, seqno)| seqno).collect();
+    assert_eq!(seqnos, [3, 4, 5]);
+    let (_writer, handle) = WalWriter::open_dir(&dir, &options)?;
+    assert_eq!(handle.last_sequence(), 5);
+    Ok(())
+}
+
+#[test]
+fn test_wal_metrics_count_a_known_workload() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("test_db");
+    let started = std::time::Instant::now();
+    let options = WalWriterOptions::default().with_sync_policy(SyncPolicy::Never);
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    assert_eq!(handle.metrics(), wal::WalMetricsSnapshot::default());
+
+    // Ten records flushed one by one, then a batch of five flushed at once, after the file header
+    let mut expected_bytes = vec![0; FILE_HEADER_LEN as usize];
+    for i in 0..10u64 {
+        let key = format!("key_{}", i);
+        handle.write(RecordKind::Set, &key, b"value")?;
+        handle.flush()?;
+        write_record(&mut expected_bytes, RecordKind::Set, key.as_bytes(), b"value", i + 1)?;
+    }
+    let batch: Vec<_> = (0..5).map(|i| (RecordKind::Set, format!("batch_{}", i), b"value".to_vec())).collect();
+    let seqnos = handle.write_batch(batch.clone())?;
+    handle.flush()?;
+    let records = batch.iter().map(|(kind, key, value)| (*kind, key.as_bytes(), value.as_slice()));
+    encode_numbered_write_batch(&mut expected_bytes, seqnos.start, records)?;
+
+    let metrics = handle.metrics();
+    assert_eq!(metrics.records_appended, 15);
+    assert_eq!(metrics.batches_appended, 11);
+    assert_eq!(metrics.bytes_written, expected_bytes.len() as u64);
+    assert_eq!(metrics.log_size, std::fs::metadata(&db_path)?.len());
+    assert_eq!(metrics.log_size, expected_bytes.len() as u64);
+    assert_eq!(metrics.fsyncs, 11);
+    assert!(metrics.fsync_time_max > Duration::ZERO);
+    assert!(metrics.fsync_time_max <= metrics.fsync_time);
+    assert!(metrics.fsync_time <= started.elapsed());
+    assert!((1..=2).contains(&metrics.queue_depth_max), "{:?}", metrics);
+
+    // A reset shrinks the log, and queued commands raise the high-water mark
+    for i in 0..100 {
+        handle.write(RecordKind::Set, &format!("more_{}", i), b"value")?;
+    }
+    handle.reset()?;
+    handle.flush()?;
+    let metrics = handle.metrics();
+    assert_eq!(metrics.records_appended, 115);
+    assert_eq!(metrics.log_size, std::fs::metadata(&db_path)?.len());
+    assert!(metrics.log_size < expected_bytes.len() as u64);
+    assert!(metrics.queue_depth_max >= 1);
+    handle.shutdown()?;
+    writer.join()?;
+    Ok(())
+}
+
+#[test]
+fn test_wal_repair_drops_a_corrupt_middle_record() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let (writer, handle) = WalWriter::open(&db_path)?;
+    write_keys(&handle, "key", 4)?;
+    let persisted = handle.position()?;
+    write_keys(&handle, "key_4", 1)?;
+    handle.checkpoint(persisted)?;
+    handle.shutdown()?;
+    writer.join()?;
+    let offsets = wal::WalReader::open(&db_path)?.map(|entry| Ok(entry?.0)).collect::<Result<Vec<_>>>()?;
+
+    // Flip a byte in the payload of the third record, recovery refuses the log
+    let original = std::fs::read(&db_path)?;
+    let mut damaged = original.clone();
+    damaged[offsets[3] as usize - 1] ^= 0xff;
+    std::fs::write(&db_path, &damaged)?;
+    assert!(wal::replay(&db_path)?.any(|record| record.is_err()));
+
+    let report = wal::repair(&db_path, &wal::RepairOptions::default())?;
+    let backup = temp_dir.path().join("wal.log.bak");
+    assert_eq!(
+        report,
+        wal::RepairReport {
+            records_kept: 4,
+            records_dropped: 1,
+            skipped: std::iter::once(offsets[2]..offsets[3]).collect(),
+            backup: Some(backup.clone()),
+        }
+    );
+    assert_eq!(std::fs::read(&backup)?, damaged);
+    let mut replay = wal::replay(&db_path)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0", "key_1", "key_3", "key_4_0"]);
+    assert!(!replay.has_torn_tail());
+
+    // The checkpoint still points at the first record not persisted
+    let from_checkpoint = wal::ReplayOptions::default().with_from_checkpoint(true);
+    let mut replay = wal::replay_with_options(&db_path, &from_checkpoint)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_4_0"]);
+    Ok(())
+}
+
+#[test]
+fn test_wal_repair_resynchronizes_after_damaged_framing() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let (log, offsets) = encode_log(5)?;
+
+    // An intact log is left alone
+    std::fs::write(&db_path, &log)?;
+    let report = wal::repair(&db_path, &wal::RepairOptions::default())?;
+    assert_eq!(report, wal::RepairReport { records_kept: 5, ..Default::default() });
+    assert!(!temp_dir.path().join("wal.log.bak").exists());
+
+    // A length pointing past the end hides where the next record starts, the scan finds it
+    let mut damaged = log.clone();
+    damaged[offsets[1]..offsets[1] + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&db_path, &damaged)?;
+    let report = wal::repair(&db_path, &wal::RepairOptions::default())?;
+    assert_eq!(report.records_kept, 4);
+    assert_eq!(report.skipped, std::iter::once(offsets[1] as u64..offsets[2] as u64).collect::<Vec<_>>());
+    let mut replay = wal::replay(&db_path)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0", "key_2", "key_3", "key_4"]);
+
+    // Or everything after it is dropped
+    std::fs::write(&db_path, &damaged)?;
+    let options = wal::RepairOptions::default().with_stop_at_first_corruption(true);
+    let report = wal::repair(&db_path, &options)?;
+    assert_eq!((report.records_kept, report.records_dropped), (1, 1));
+    assert_eq!(report.skipped, std::iter::once(offsets[1] as u64..log.len() as u64).collect::<Vec<_>>());
+    let mut replay = wal::replay(&db_path)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0"]);
+    Ok(())
+}
+
+#[test]
+fn test_wal_replay_under_each_recovery_mode() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    // The second record claims to run past the end, hiding where the third one starts
+    let (mut log, offsets) = encode_log(5)?;
+    log[offsets[1]..offsets[1] + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&db_path, &log)?;
+    let with_mode = |mode| wal::ReplayOptions::default().with_recovery_mode(mode);
+
+    let mut replay = wal::replay_with_options(&db_path, &with_mode(wal::RecoveryMode::Strict))?;
+    let err = replay_keys(&mut replay).unwrap_err();
+    assert!(err.to_string().contains("torn tail"), "{err}");
+
+    // Taken for a torn tail, everything after the first record is dropped
+    let mut replay = wal::replay_with_options(&db_path, &with_mode(wal::RecoveryMode::TolerateTornTail))?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0"]);
+    assert!(replay.has_torn_tail());
+    let report = wal::RecoveryReport {
+        records_replayed: 1,
+        bytes_skipped: (log.len() - offsets[1]) as u64,
+        segments_affected: vec![db_path.clone()],
+    };
+    assert_eq!(replay.report(), &report);
+
+    // Only the damaged record is dropped
+    let mut replay = wal::replay_with_options(&db_path, &with_mode(wal::RecoveryMode::SkipCorrupt))?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0", "key_2", "key_3", "key_4"]);
+    assert!(!replay.has_torn_tail());
+    assert_eq!(replay.last_seqno(), 5);
+    let report = wal::RecoveryReport {
+        records_replayed: 4,
+        bytes_skipped: (offsets[2] - offsets[1]) as u64,
+        segments_affected: vec![db_path.clone()],
+    };
+    assert_eq!(replay.report(), &report);
+    Ok(())
+}
+
+#[test]
+fn test_wal_skip_corrupt_moves_past_damaged_segments() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path().join("wal");
+    std::fs::create_dir_all(&dir)?;
+    let (log, offsets) = encode_log(3)?;
+    // Nothing valid follows the damage in the first segment, the second one is intact
+    let first = wal::segment_path(&dir, 1);
+    std::fs::write(&first, &log[..offsets[2] - 1])?;
+    std::fs::write(wal::segment_path(&dir, 2), &log[offsets[2]..])?;
+
+    assert!(replay_keys(&mut wal::replay_dir(&dir)?).is_err());
+    let options = wal::ReplayOptions::default().with_recovery_mode(wal::RecoveryMode::SkipCorrupt);
+    let mut replay = wal::replay_dir_with_options(&dir, &options)?;
+    assert_eq!(replay_keys(&mut replay)?, ["key_0", "key_2"]);
+    assert_eq!(replay.report().bytes_skipped, (offsets[2] - 1 - offsets[1]) as u64);
+    assert_eq!(replay.report().segments_affected, [first]);
+
+    // The writer recovers the last sequence number under the same mode
+    assert!(WalWriter::open_dir(&dir, &SegmentOptions::default()).is_err());
+    let writer_options = WalWriterOptions::default().with_recovery_mode(wal::RecoveryMode::SkipCorrupt);
+    let (writer, handle) = WalWriter::open_dir_with_options(&dir, &SegmentOptions::default(), &writer_options)?;
+    assert_eq!(handle.last_sequence(), 3);
+    handle.shutdown()?;
+    writer.join()?;
+    Ok(())
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn test_wal_compresses_large_values() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let large = "snail".repeat(20_000).into_bytes();
+    let options = WalWriterOptions::default().with_compression_threshold(1024);
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    handle.write(RecordKind::Set, "small", b"value")?;
+    handle.write(RecordKind::Set, "large", &large)?;
+    handle.write_batch(vec![
+        (RecordKind::Set, "batch_large".to_string(), large.clone()),
+        (RecordKind::Delete, "small".to_string(), Vec::new()),
+    ])?;
+    handle.shutdown()?;
+    writer.join()?;
+
+    // The small value is stored as it is, the large ones take a fraction of their size
+    let mut small = Vec::new();
+    write_record(&mut small, RecordKind::Set, b"small", b"value", 1)?;
+    let log = std::fs::read(&db_path)?;
+    assert!(log[FILE_HEADER_LEN as usize..].starts_with(&small));
+    assert!(log.len() < large.len() / 10, "{} bytes", log.len());
+
+    let records = wal::replay(&db_path)?.collect::<std::io::Result<Vec<_>>>()?;
+    let values: Vec<_> = records.iter().map(|record| (record.key.as_slice(), record.value.as_slice())).collect();
+    assert_eq!(
+        values,
+        [(&b"small"[..], &b"value"[..]), (b"large", &large), (b"batch_large", &large), (b"small", b"")]
+    );
+
+    // A log written without compression reads the same, and appending compressed records to it is fine
+    let plain = temp_dir.path().join("plain.log");
+    let (writer, handle) = WalWriter::open(&plain)?;
+    handle.write(RecordKind::Set, "large", &large)?;
+    handle.shutdown()?;
+    writer.join()?;
+    let (writer, handle) = WalWriter::open_with_options(&plain, &options)?;
+    handle.write(RecordKind::Set, "large", &large)?;
+    handle.shutdown()?;
+    writer.join()?;
+    let records = wal::replay(&plain)?.collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 2);
+    assert!(records.iter().all(|record| record.value == large));
+    Ok(())
+}
+
+#[cfg(not(feature = "lz4"))]
+#[test]
+fn test_wal_compressed_records_need_lz4() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let options = WalWriterOptions::default().with_compression_threshold(1024);
+    let err = WalWriter::open_with_options(&db_path, &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // A record with the compressed flag, 0x20 in the kind byte, with a valid checksum
+    let (mut log, offsets) = encode_log(2)?;
+    let payload = offsets[1] + 8;
+    log[payload] |= 0x20;
+    let crc = crc32fast::hash(&log[payload..]);
+    log[offsets[1] + 4..payload].copy_from_slice(&crc.to_le_bytes());
+    std::fs::write(&db_path, &log)?;
+
+    // Being the last record it would pass for a torn tail, if the error did not say otherwise
+    for mode in [wal::RecoveryMode::TolerateTornTail, wal::RecoveryMode::SkipCorrupt] {
+        let options = wal::ReplayOptions::default().with_recovery_mode(mode).with_truncate_torn_tail(true);
+        let mut replay = wal::replay_with_options(&db_path, &options)?;
+        assert_eq!(String::from_utf8(replay.next().unwrap()?.key)?, "key_0");
+        let err = replay.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("lz4"), "{err}");
+        assert!(!replay.has_torn_tail());
+    }
+    let err = wal::repair(&db_path, &wal::RepairOptions::default()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert_eq!(std::fs::read(&db_path)?, log);
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_wal_encrypted_round_trip() -> Result<()> {
+    use snaildb::utils::EncryptionKey;
+
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let key = EncryptionKey::new([7; 32]);
+    let options = WalWriterOptions::default().with_encryption_key(key.clone());
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    handle.write(RecordKind::Set, "secret_key", b"secret_value")?;
+    handle.write_batch(vec![
+        (RecordKind::Set, "batch_key".to_string(), b"batch_value".to_vec()),
+        (RecordKind::Delete, "secret_key".to_string(), Vec::new()),
+    ])?;
+    handle.shutdown()?;
+    writer.join()?;
+
+    // Nothing of the keys and values is left in plaintext
+    let log = std::fs::read(&db_path)?;
+    for plaintext in [&b"secret"[..], b"batch"] {
+        assert!(!log.windows(plaintext.len()).any(|window| window == plaintext));
+    }
+
+    let options = wal::ReplayOptions::default().with_encryption_key(key.clone());
+    let replay = wal::replay_with_options(&db_path, &options)?;
+    assert_eq!(replay_seqnos(replay)?, [
+        ("secret_key".to_string(), 1),
+        ("batch_key".to_string(), 2),
+        ("secret_key".to_string(), 3),
+    ]);
+    let reader = wal::WalReader::open(&db_path)?.with_encryption_key(key.clone());
+    let values = reader.map(|entry| Ok(entry?.1.value)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(values, [b"secret_value".to_vec(), b"batch_value".to_vec(), Vec::new()]);
+
+    // A restart reads the log with the key to continue the sequence numbers
+    let options = WalWriterOptions::default().with_encryption_key(key);
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    assert_eq!(handle.write(RecordKind::Set, "after_restart", b"value")?, 4);
+    handle.shutdown()?;
+    writer.join()?;
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_wal_encrypted_replay_rejects_wrong_key() -> Result<()> {
+    use snaildb::utils::EncryptionKey;
+
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let options = WalWriterOptions::default().with_encryption_key(EncryptionKey::new([7; 32]));
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    write_keys(&handle, "key", 2)?;
+    handle.shutdown()?;
+    writer.join()?;
+    let log = std::fs::read(&db_path)?;
+
+    // Neither a wrong key nor no key passes for damage, whatever the recovery mode
+    let wrong = wal::ReplayOptions::default().with_encryption_key(EncryptionKey::new([8; 32]));
+    for options in [
+        wrong.clone(),
+        wrong.clone().with_recovery_mode(wal::RecoveryMode::SkipCorrupt).with_truncate_torn_tail(true),
+        wal::ReplayOptions::default(),
+    ] {
+        let mut replay = wal::replay_with_options(&db_path, &options)?;
+        let err = replay.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(replay.next().is_none());
+        assert!(!replay.has_torn_tail());
+    }
+    let err = replay_keys(&mut wal::replay_with_options(&db_path, &wrong)?).unwrap_err();
+    assert!(err.to_string().contains("authentication"), "{err}");
+    assert!(WalWriter::open(&db_path).is_err());
+    let err = wal::repair(&db_path, &wal::RepairOptions::default()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(std::fs::read(&db_path)?, log);
+    Ok(())
+}
+
+#[cfg(not(feature = "encryption"))]
+#[test]
+fn test_wal_encryption_needs_feature() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key = snaildb::utils::EncryptionKey::new([7; 32]);
+    let options = WalWriterOptions::default().with_encryption_key(key);
+    let err = WalWriter::open_with_options(temp_dir.path().join("wal.log"), &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("encryption"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_wal_subscribers_get_durable_records_in_order() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let options = WalWriterOptions::default().with_sync_policy(SyncPolicy::Never);
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    handle.write(RecordKind::Set, "before", b"value")?;
+    let events = handle.subscribe();
+
+    // Written but not synced yet, so not sent
+    let other = handle.clone();
+    other.write(RecordKind::Set, "first", b"1")?;
+    handle.write_batch(vec![
+        (RecordKind::Set, "second".to_string(), b"2".to_vec()),
+        (RecordKind::Delete, "first".to_string(), Vec::new()),
+    ])?;
+    handle.position()?;
+    assert_eq!(events.try_recv(), Err(std::sync::mpsc::TryRecvError::Empty));
+
+    other.flush()?;
+    let event = |seqno, kind, key: &str, value: &[u8]| wal::WalEvent {
+        seqno,
+        kind,
+        key: key.to_string(),
+        value: value.to_vec(),
+    };
+    assert_eq!(events.try_iter().collect::<Vec<_>>(), [
+        event(2, RecordKind::Set, "first", b"1"),
+        event(3, RecordKind::Set, "second", b"2"),
+        event(4, RecordKind::Delete, "first", b""),
+    ]);
+
+    // Events of concurrent writers come in sequence number order
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let handle = handle.clone();
+            thread::spawn(move || -> std::io::Result<()> {
+                for i in 0..25 {
+                    handle.write(RecordKind::Set, &format!("t{t}_{i}"), b"value")?;
+                }
+                handle.flush()
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    let seqnos: Vec<_> = events.try_iter().map(|event| event.seqno).collect();
+    assert_eq!(seqnos, (5..105).collect::<Vec<_>>());
+
+    // Stopping the writer ends the subscription
+    handle.shutdown()?;
+    writer.join()?;
+    assert!(events.recv().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_wal_slow_subscribers_follow_the_policy() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = WalWriterOptions::default()
+        .with_subscriber_capacity(2)
+        .with_slow_subscriber_policy(wal::SlowSubscriberPolicy::Disconnect);
+    let (writer, handle) = WalWriter::open_with_options(temp_dir.path().join("disconnect.log"), &options)?;
+    let (fast, slow) = (handle.subscribe(), handle.subscribe());
+    let reader = thread::spawn(move || fast.iter().map(|event| event.seqno).take(5).collect::<Vec<_>>());
+    // One flush per write, so the fast reader keeps up
+    write_keys(&handle, "key", 5)?;
+    assert_eq!(reader.join().unwrap(), [1, 2, 3, 4, 5]);
+    // The slow one got what fit, then was disconnected
+    assert_eq!(slow.iter().map(|event| event.seqno).collect::<Vec<_>>(), [1, 2]);
+    handle.shutdown()?;
+    writer.join()?;
+
+    // Backpressure waits for a subscriber that keeps reading, and gives up on one that does not
+    let options = WalWriterOptions::default()
+        .with_subscriber_capacity(1)
+        .with_slow_subscriber_policy(wal::SlowSubscriberPolicy::Backpressure(Duration::from_millis(200)));
+    let (writer, handle) = WalWriter::open_with_options(temp_dir.path().join("backpressure.log"), &options)?;
+    let (lagging, stuck) = (handle.subscribe(), handle.subscribe());
+    let reader = thread::spawn(move || {
+        lagging
+            .iter()
+            .map(|event| {
+                thread::sleep(Duration::from_millis(5));
+                event.seqno
+            })
+            .collect::<Vec<_>>()
+    });
+    for i in 0..10 {
+        handle.write(RecordKind::Set, &format!("key_{i}"), b"value")?;
+    }
+    handle.flush()?;
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(reader.join().unwrap(), (1..=10).collect::<Vec<_>>());
+    assert_eq!(stuck.iter().map(|event| event.seqno).collect::<Vec<_>>(), [1]);
+    Ok(())
+}
+
+/// Keeps what a log file holds at each of its syncs, optionally holding the first sync until
+/// released so commands queue up behind it.
+#[derive(Debug)]
+struct SnapshotSync {
+    path: PathBuf,
+    snapshots: std::sync::Mutex<Vec<Vec<u8>>>,
+    /// signaled when the held sync starts, and waited on to finish it
+    hold: std::sync::Mutex<Option<(std::sync::mpsc::Sender<()>, std::sync::mpsc::Receiver<()>)>>,
+}
+
+impl SnapshotSync {
+    fn new(path: PathBuf) -> Self {
+        SnapshotSync { path, snapshots: Default::default(), hold: Default::default() }
+    }
+
+    /// The sequence numbers of the records the log held at each sync, the last `count` ones.
+    fn synced_seqnos(&self, dir: &std::path::Path, count: usize) -> Result<Vec<Vec<u64>>> {
+        let snapshot_path = dir.join("snapshot.log");
+        let mut synced = Vec::new();
+        let snapshots = self.snapshots.lock().unwrap();
+        for snapshot in snapshots.iter().skip(snapshots.len().saturating_sub(count)) {
+            std::fs::write(&snapshot_path, snapshot)?;
+            let mut replay = wal::replay(&snapshot_path)?;
+            let seqnos = replay.by_ref().map(|record| record.map(|record| record.seqno)).collect::<Result<_, _>>()?;
+            // Only whole records
+            assert_eq!(replay.valid_len(), snapshot.len() as u64);
+            synced.push(seqnos);
+        }
+        Ok(synced)
+    }
+}
+
+impl LogSync for SnapshotSync {
+    fn sync(&self, file: &std::fs::File) -> std::io::Result<()> {
+        self.snapshots.lock().unwrap().push(std::fs::read(&self.path)?);
+        if let Some((started, release)) = self.hold.lock().unwrap().take() {
+            let _ = started.send(());
+            let _ = release.recv();
+        }
+        FileSync.sync(file)
+    }
+}
+
+#[test]
+fn test_wal_barrier_syncs_before_later_writes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let syncer = std::sync::Arc::new(SnapshotSync::new(db_path.clone()));
+    let options = WalWriterOptions::default()
+        .with_sync_policy(SyncPolicy::Never)
+        .with_syncer(syncer.clone());
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    let (started, wait_started) = std::sync::mpsc::channel();
+    let (release, wait_release) = std::sync::mpsc::channel();
+    *syncer.hold.lock().unwrap() = Some((started, wait_release));
+
+    // Hold the writer in a sync while commands queue up: writes, a barrier, then another write
+    handle.write(RecordKind::Set, "a", b"1")?;
+    let flusher = handle.clone();
+    let flush = thread::spawn(move || flusher.flush());
+    wait_started.recv()?;
+    for key in ["b1", "b2", "b3"] {
+        handle.write(RecordKind::Set, key, b"1")?;
+    }
+    let barrier_handle = handle.clone();
+    let barrier = thread::spawn(move || barrier_handle.barrier());
+    // At most two commands were ever queued before, so four means the barrier is in
+    while handle.metrics().queue_depth_max < 4 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    handle.write(RecordKind::Set, "c", b"1")?;
+    release.send(())?;
+    flush.join().unwrap()?;
+    barrier.join().unwrap()?;
+
+    // The barrier synced the writes before it alone, even with one queued right behind it
+    assert_eq!(syncer.synced_seqnos(temp_dir.path(), 2)?, [vec![1], vec![1, 2, 3, 4]]);
+    handle.shutdown()?;
+    writer.join()?;
+    assert_eq!(syncer.synced_seqnos(temp_dir.path(), 1)?.pop(), Some(vec![1, 2, 3, 4, 5]));
+    Ok(())
+}
+
+#[test]
+fn test_wal_barrier_orders_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let db_path = temp_dir.path().join("wal.log");
+    let syncer = std::sync::Arc::new(SnapshotSync::new(db_path.clone()));
+    let options = WalWriterOptions::default()
+        .with_sync_policy(SyncPolicy::Never)
+        .with_syncer(syncer.clone());
+    let (writer, handle) = WalWriter::open_with_options(&db_path, &options)?;
+    let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let (handle, stop) = (handle.clone(), stop.clone());
+            thread::spawn(move || -> std::io::Result<()> {
+                for i in 0.. {
+                    if stop.load(std::sync::atomic::Ordering::SeqCst) {
+                        break;
+                    }
+                    if i % 3 == 0 {
+                        let records = (0..3).map(|j| (RecordKind::Set, format!("t{t}_{i}_{j}"), b"v".to_vec()));
+                        handle.write_batch(records.collect())?;
+                    } else {
+                        handle.write(RecordKind::Set, &format!("t{t}_{i}"), b"v")?;
+                    }
+                    thread::sleep(Duration::from_micros(20));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for _ in 0..20 {
+        thread::sleep(Duration::from_millis(2));
+        let sent_before = handle.last_sequence();
+        handle.barrier()?;
+        let sent_after = handle.last_sequence();
+        // When the barrier resolves, its sync held every record sent before the call, and, as
+        // nothing behind it is written first, exactly the records numbered up to some point
+        let synced = syncer.synced_seqnos(temp_dir.path(), 1)?.pop().unwrap_or_default();
+        let last = synced.len() as u64;
+        assert_eq!(synced, (1..=last).collect::<Vec<_>>());
+        assert!((sent_before..=sent_after).contains(&last), "{sent_before} <= {last} <= {sent_after}");
+    }
+    stop.store(true, std::sync::atomic::Ordering::SeqCst);
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    handle.shutdown()?;
+    writer.join()?;
+    Ok(())
+}